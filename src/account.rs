@@ -16,27 +16,53 @@
 //!
 //! # Accounts
 //!
-//! Accounts compatible with BIP32, BIP39, BIP44, BIP49, BIP84
+//! Accounts compatible with BIP32, BIP39, BIP44, BIP49, BIP84, BIP86
 //!
 
+use std::convert::TryFrom;
+use std::num::NonZeroU32;
+use std::ops::Range;
+use std::str::FromStr;
 use std::sync::Arc;
 
-use bitcoin::{Address, blockdata::{
-    opcodes::all,
-    transaction::{SigHashType, TxOut},
-}, blockdata::script::Builder, network::constants::Network, OutPoint, PublicKey, Script, Transaction, util::bip143, util::bip32::{ChildNumber, ExtendedPrivKey}, PrivateKey};
-use bitcoin_hashes::{Hash, hash160};
+use bitcoin::{Address, Amount, CompressedPublicKey, EcdsaSighashType, FeeRate, Network, NetworkKind, OutPoint, PrivateKey, Psbt, PublicKey,
+              ScriptBuf, TapSighashType, Transaction, TxOut, Txid, ecdsa, taproot,
+              Script, bip32::{ChildNumber, DerivationPath, Fingerprint, KeySource, Xpriv, Xpub}, script::{Builder, PushBytesBuf}};
 
-use context::SecpContext;
+use backup;
+use coinselect;
+use context::{AnySignature, SecpContext};
+use sighash::TxSighashes;
 use error::WalletError;
-use bitcoin::util::bip32::ExtendedPubKey;
-use crate::mnemonic::Mnemonic;
-use secp256k1::rand::{thread_rng, Rng};
+use events::{EventBus, WalletEvent};
+use history::{self, HistoryEntry};
+use crate::mnemonic::{self, Mnemonic};
+use keystore::{self, Kdf};
+use labels::Labels;
+use message;
+use signer::Signer;
+use store;
+use sweep::{self, UtxoSource};
+use utxo::UtxoSet;
+use secret::SecretBytes;
+use zeroize::Zeroize;
+use secp256k1::rand::{thread_rng, RngCore};
+#[cfg(not(target_arch = "wasm32"))]
 use std::time::{SystemTime, UNIX_EPOCH};
-use crypto::hmac::Hmac;
-use crypto::sha2::Sha512;
-use crypto::pbkdf2::pbkdf2;
-use std::collections::HashMap;
+// std has no clock on wasm32-unknown-unknown
+#[cfg(target_arch = "wasm32")]
+use web_time::{SystemTime, UNIX_EPOCH};
+use ring::pbkdf2;
+use std::cmp::max;
+use std::collections::{HashMap, HashSet};
+
+/// purpose, coin type and index of an account in the m / purpose' / coin_type' / account' hierarchy
+pub type AccountId = (u32, u32, u32);
+
+/// sub account of the external chain, keys to receive payments
+pub const EXTERNAL: u32 = 0;
+/// sub account of the internal chain, keys to receive change
+pub const INTERNAL: u32 = 1;
 
 /// chose your security level
 #[derive(Copy, Clone)]
@@ -48,10 +74,13 @@ pub enum MasterKeyEntropy {
 
 /// A masterAccount is the root of an account hierarchy
 pub struct MasterAccount {
-    master_public: ExtendedPubKey,
+    master_public: Xpub,
+    fingerprint: Fingerprint,
     encrypted: Vec<u8>,
     accounts: HashMap<(u32, u32), Account>,
-    birth: u64
+    archived: HashSet<AccountId>,
+    birth: u64,
+    events: Option<EventBus>
 }
 
 impl MasterAccount {
@@ -59,53 +88,130 @@ impl MasterAccount {
     /// the information that leads to private key is stored encrypted with passphrase
     /// and the optional pd_passphrase (pd for plausible deniability)
     pub fn new (entropy: MasterKeyEntropy, network: Network, passphrase: &str, pd_passphrase: Option<&str>) -> Result<MasterAccount, WalletError> {
-        let context = SecpContext::new();
-        let mut random = vec!(0u8; entropy as usize);
+        let mut random = SecretBytes::new(vec!(0u8; entropy as usize));
         let mut rng = thread_rng();
         rng.fill_bytes(random.as_mut_slice());
-        let mnemonic = Mnemonic::new(&random)?;
-        let encrypted = mnemonic.encrypt(passphrase)?;
-        let seed = Seed::new(&mnemonic, pd_passphrase);
-        let master_key = context.master_private_key(network, &seed)?;
-        let public_master_key = context.extended_public_from_private(&master_key);
-        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
-        Ok(MasterAccount { master_public: public_master_key, encrypted, accounts: HashMap::new(), birth: now})
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_err(|_| WalletError::Unsupported("clock is before 1970"))?.as_secs();
+        Self::from_entropy(random.as_slice(), now, network, passphrase, pd_passphrase)
+    }
+
+    /// create a master account from entropy and birth given by the caller,
+    /// for hosts like wasm32-unknown-unknown where the crate can not read a random source or clock
+    pub fn from_entropy(entropy: &[u8], birth: u64, network: Network, passphrase: &str, pd_passphrase: Option<&str>) -> Result<MasterAccount, WalletError> {
+        let mnemonic = Mnemonic::new(entropy)?;
+        Self::from_mnemonic(&mnemonic, birth, network, passphrase, pd_passphrase)
     }
 
     /// Restore from encrypted store
-    pub fn from_encrypted(encrypted: &[u8], public_master_key: ExtendedPubKey, birth: u64) -> MasterAccount {
+    pub fn from_encrypted(encrypted: &[u8], public_master_key: Xpub, birth: u64) -> MasterAccount {
         let encrypted = encrypted.to_vec();
-        MasterAccount { master_public: public_master_key, encrypted, accounts: HashMap::new(), birth }
+        let fingerprint = public_master_key.fingerprint();
+        MasterAccount { master_public: public_master_key, fingerprint, encrypted, accounts: HashMap::new(), archived: HashSet::new(), birth, events: None }
+    }
+
+    /// this should only be used to restore previously stored state
+    pub fn new_from_storage(master_public: Xpub, fingerprint: Fingerprint, encrypted: &[u8], birth: u64) -> MasterAccount {
+        MasterAccount { master_public, fingerprint, encrypted: encrypted.to_vec(), accounts: HashMap::new(), archived: HashSet::new(), birth, events: None }
     }
 
     /// A watch only master. You will not be able to sign with this.
-    pub fn watch_only(public_master_key: ExtendedPubKey, birth: u64) -> MasterAccount {
-        MasterAccount { master_public: public_master_key, encrypted: Vec::new(), accounts: HashMap::new(), birth }
+    pub fn watch_only(public_master_key: Xpub, birth: u64) -> MasterAccount {
+        let fingerprint = public_master_key.fingerprint();
+        MasterAccount { master_public: public_master_key, fingerprint, encrypted: Vec::new(), accounts: HashMap::new(), archived: HashSet::new(), birth, events: None }
+    }
+
+    /// A watch only master of a single account given by its extended public key at
+    /// m / purpose' / coin_type' / account' and the fingerprint of the master key it was derived from.
+    /// Receive (sub account 0) and change (sub account 1) keys are derived without private keys.
+    pub fn watch_only_account(fingerprint: Fingerprint, account_public: Xpub, address_type: AccountAddressType, network: Network, look_ahead: u32, birth: u64) -> Result<MasterAccount, WalletError> {
+        if NetworkKind::from(network) != account_public.network {
+            return Err(WalletError::Network);
+        }
+        let account_number = match account_public.child_number {
+            ChildNumber::Hardened { index } if account_public.depth == 3 => index,
+            _ => return Err(WalletError::Unsupported("not an account level extended public key"))
+        };
+        let mut master = MasterAccount { master_public: account_public, fingerprint, encrypted: Vec::new(), accounts: HashMap::new(), archived: HashSet::new(), birth, events: None };
+        for sub_account_number in 0..2 {
+            let account = Account::new_watch_only(&account_public, address_type, account_number, sub_account_number, look_ahead, network)?;
+            master.add_account(account);
+        }
+        Ok(master)
     }
 
     /// Restore from mnemonic
     pub fn from_mnemonic(mnemonic: &Mnemonic, birth: u64, network: Network, passphrase: &str, pd_passphrase: Option<&str>) -> Result<MasterAccount, WalletError> {
         let context = SecpContext::new();
         let encrypted = mnemonic.encrypt(passphrase)?;
-        let seed = Seed::new(&mnemonic, pd_passphrase);
+        let seed = Seed::new(mnemonic, pd_passphrase);
         let master_key = context.master_private_key(network, &seed)?;
         let public_master_key = context.extended_public_from_private(&master_key);
-        Ok(MasterAccount { master_public: public_master_key, encrypted, accounts: HashMap::new(), birth })
+        let fingerprint = public_master_key.fingerprint();
+        Ok(MasterAccount { master_public: public_master_key, fingerprint, encrypted, accounts: HashMap::new(), archived: HashSet::new(), birth, events: None })
+    }
+
+    /// the master of the same seed with an other pd_passphrase, an independent wallet without accounts yet.
+    /// Every pd_passphrase is a valid wallet, a wrong one can not be told from a hidden one.
+    pub fn with_pd_passphrase(&self, passphrase: &str, pd_passphrase: Option<&str>, network: Network) -> Result<MasterAccount, WalletError> {
+        if self.is_watch_only() {
+            return Err(WalletError::Unsupported("watch only master has no seed"));
+        }
+        if NetworkKind::from(network) != self.master_public.network {
+            return Err(WalletError::Network);
+        }
+        let unlocker = Unlocker::new(&self.encrypted, passphrase, pd_passphrase, network, None)?;
+        let master_public = unlocker.context.extended_public_from_private(&unlocker.master_private);
+        Ok(MasterAccount::from_encrypted(&self.encrypted, master_public, self.birth))
     }
 
     /// get the mnemonic (human readable) representation of the master key
     pub fn mnemonic (&self, passphrase: &str) -> Result<Mnemonic, WalletError> {
-        Ok(Mnemonic::decrypt(&self.encrypted, passphrase)?)
+        Mnemonic::decrypt(&self.encrypted, passphrase)
     }
 
-    pub fn master_public (&self) ->&ExtendedPubKey {
+    pub fn master_public (&self) ->&Xpub {
         &self.master_public
     }
 
+    /// fingerprint of the master key, origin of key paths
+    pub fn fingerprint(&self) -> Fingerprint {
+        self.fingerprint
+    }
+
     pub fn encrypted(&self) -> &Vec<u8> {
         &self.encrypted
     }
 
+    /// re-encrypt a legacy keystore into the current format
+    /// returns true if the keystore was migrated, the new one should be stored
+    pub fn migrate_keystore(&mut self, passphrase: &str) -> Result<bool, WalletError> {
+        if self.is_watch_only() || !keystore::is_legacy(&self.encrypted) {
+            return Ok(false);
+        }
+        self.encrypted = keystore::migrate(&self.encrypted, passphrase, keystore::Cipher::Aes256Gcm, keystore::Kdf::default())?;
+        Ok(true)
+    }
+
+    /// re-encrypt the seed with a new passphrase, old must decrypt it
+    /// the new keystore replaces the old only once it decrypts to the same seed, the master should be stored again
+    pub fn change_passphrase(&mut self, old: &str, new: &str) -> Result<(), WalletError> {
+        if self.is_watch_only() {
+            return Err(WalletError::Unsupported("watch only master has no passphrase"));
+        }
+        let mnemonic = Mnemonic::decrypt(&self.encrypted, old)?;
+        let encrypted = mnemonic.encrypt(new)?;
+        if Mnemonic::decrypt(&encrypted, new)? != mnemonic {
+            return Err(WalletError::Unsupported("re-encrypted seed does not decrypt"));
+        }
+        self.encrypted = encrypted;
+        Ok(())
+    }
+
+    /// true if this master holds no private key material
+    pub fn is_watch_only(&self) -> bool {
+        self.encrypted.is_empty()
+    }
+
     pub fn birth (&self) -> u64 {
         self.birth
     }
@@ -122,82 +228,439 @@ impl MasterAccount {
         &self.accounts
     }
 
-    pub fn get_scripts<'a>(&'a self) -> impl Iterator<Item=(Script, KeyDerivation)> + 'a {
+    /// network of the accounts, none if there is no account yet
+    /// keys only tell main from test networks, accounts also tell testnet, signet and regtest apart
+    pub fn network(&self) -> Option<Network> {
+        self.accounts.values().next().map(|a| a.network)
+    }
+
+    pub fn get_scripts<'a>(&'a self) -> impl Iterator<Item=(ScriptBuf, KeyDerivation)> + 'a {
         self.accounts.iter().flat_map(
             |((an, sub), a)|
                 a.get_scripts().map(move |(kix, s, tweak)|
                     (s, KeyDerivation{ account: *an, sub: *sub, kix, tweak})))
     }
 
+    /// usage of an address of the master, none if the script is not of its keys
+    pub fn address_status(&self, script_pubkey: &Script) -> Option<AddressStatus> {
+        self.get_key_for_script(script_pubkey).map(|(account, key)| account.address_status(key.index))
+    }
+
+    /// find the account and key that controls a script
+    pub fn get_key_for_script(&self, script_pubkey: &Script) -> Option<(&Account, &InstantiatedKey)> {
+        self.accounts.values().find_map(|a|
+            a.instantiated.iter().find(|i| i.script_pubkey.as_script() == script_pubkey).map(|i| (a, i)))
+    }
+
+    /// fingerprint of the master and full derivation path of an instantiated key
+    /// none for keys with a tweak, their path alone does not lead to them
+    pub fn key_origin(&self, account: (u32, u32), kix: u32) -> Option<KeySource> {
+        let a = self.accounts.get(&account)?;
+        a.get_key(kix).filter(|k| k.tweak.is_none()).map(|_| (self.fingerprint, a.key_path(kix)))
+    }
+
+    /// all instantiated keys an external signer can derive, with their origin
+    pub fn origins<'a>(&'a self) -> impl Iterator<Item=(&'a InstantiatedKey, KeySource)> + 'a {
+        self.accounts.values().flat_map(move |a|
+            a.instantiated.iter().filter(|k| k.tweak.is_none()).map(move |k| (k, (self.fingerprint, a.key_path(k.index)))))
+    }
+
+    /// a new key of an account with its origin, published to the event bus if set
+    pub fn next_key(&mut self, account: (u32, u32)) -> Result<(InstantiatedKey, KeySource), WalletError> {
+        let fingerprint = self.fingerprint;
+        let a = self.accounts.get_mut(&account).ok_or(WalletError::UnknownAccount { account: account.0, sub_account: account.1 })?;
+        let key = a.next_key()?.clone();
+        let path = a.key_path(key.index);
+        if let Some(ref events) = self.events {
+            let derivation = KeyDerivation { account: account.0, sub: account.1, kix: key.index, tweak: key.tweak.clone() };
+            events.publish(WalletEvent::AddressGenerated { derivation, script_pubkey: key.script_pubkey.clone() });
+        }
+        Ok((key, (fingerprint, path)))
+    }
+
+    /// publish keys handed out by `next_key` to the bus
+    pub fn set_events(&mut self, events: EventBus) {
+        self.events = Some(events);
+    }
+
+    /// a single encrypted file with the seed, accounts, address cursors and labels, see `backup::export`
+    pub fn export_backup(&self, labels: &Labels, passphrase: &str, kdf: Kdf) -> Result<Vec<u8>, WalletError> {
+        backup::export(self, labels, passphrase, kdf)
+    }
+
+    /// restore a master and its labels from a backup file
+    pub fn import_backup(backup: &[u8], passphrase: &str) -> Result<(MasterAccount, Labels), WalletError> {
+        backup::import(backup, passphrase)
+    }
+
+    /// public data of the wallet as JSON, to restore alongside the mnemonic, see `backup::export_public`
+    pub fn export_public_backup(&self, labels: &Labels, history: &[store::HistoryEntry]) -> Result<String, WalletError> {
+        backup::export_public(self, labels, history)
+    }
+
+    /// restore a master, its labels and history from mnemonic and a public backup
+    pub fn import_public_backup(backup: &str, mnemonic: &Mnemonic, passphrase: &str, pd_passphrase: Option<&str>) -> Result<(MasterAccount, Labels, Vec<store::HistoryEntry>), WalletError> {
+        backup::import_public(backup, mnemonic, passphrase, pd_passphrase)
+    }
+
     pub fn add_account(&mut self, account: Account) {
         self.accounts.insert((account.account_number, account.sub_account_number), account);
     }
 
-    pub fn sign<R>(&self, transaction: &mut Transaction, hash_type: SigHashType, resolver: &R, unlocker: &mut Unlocker) -> Result<usize, WalletError>
+    /// create an account of the chosen address type and add it to this master
+    /// keys are derived on m / purpose' / coin_type' / account' / sub_account
+    /// purpose is 44 for P2PKH, 49 for P2SHWPKH, 84 for P2WPKH (bech32 addresses) and 86 for P2TR (bech32m addresses)
+    pub fn new_account(&mut self, unlocker: &mut Unlocker, address_type: AccountAddressType, account_number: u32, sub_account_number: u32, look_ahead: u32) -> Result<&mut Account, WalletError> {
+        self.check_unlocker(unlocker)?;
+        let account = Account::new(unlocker, address_type, account_number, sub_account_number, look_ahead)?;
+        self.add_account(account);
+        Ok(self.accounts.get_mut(&(account_number, sub_account_number)).unwrap())
+    }
+
+    /// create the external and internal chain of an account, see `next_receive_address` and `next_change_address`
+    pub fn new_keychains(&mut self, unlocker: &mut Unlocker, address_type: AccountAddressType, account_number: u32, look_ahead: u32) -> Result<(), WalletError> {
+        self.new_account(unlocker, address_type, account_number, EXTERNAL, look_ahead)?;
+        self.new_account(unlocker, address_type, account_number, INTERNAL, look_ahead)?;
+        Ok(())
+    }
+
+    /// next address of the external chain of an account
+    pub fn next_receive_address(&mut self, account_number: u32) -> Result<Address, WalletError> {
+        Ok(self.next_key((account_number, EXTERNAL))?.0.address)
+    }
+
+    /// next address of the internal chain of an account, its cursor is independent of the external chain
+    pub fn next_change_address(&mut self, account_number: u32) -> Result<Address, WalletError> {
+        Ok(self.next_key((account_number, INTERNAL))?.0.address)
+    }
+
+    /// the internal chain change goes to if not chosen, that of the lowest account number
+    pub fn default_change(&self) -> Option<(u32, u32)> {
+        self.accounts.keys().filter(|(_, sub)| *sub == INTERNAL).min().cloned()
+    }
+
+    /// create the external and internal chain of the account of an address type at a coin type and index,
+    /// keys on m / purpose' / coin_type' / index' / sub_account. Returns the account number in this master,
+    /// the index unless an account of another purpose or coin type has that number.
+    pub fn open_account(&mut self, unlocker: &mut Unlocker, address_type: AccountAddressType, coin_type: u32, index: u32, look_ahead: u32) -> Result<u32, WalletError> {
+        if self.account_number((address_type.as_u32(), coin_type, index)).is_some() {
+            return Err(WalletError::Unsupported("account exists"));
+        }
+        if coin_type == unlocker.coin_type() && !self.accounts.keys().any(|(n, _)| *n == index) {
+            self.new_keychains(unlocker, address_type, index, look_ahead)?;
+            return Ok(index);
+        }
+        let number = match self.accounts.keys().map(|(n, _)| *n).max() {
+            Some(n) => n.checked_add(1).ok_or(WalletError::Unsupported("no free account number"))?,
+            None => 0
+        };
+        for sub in &[EXTERNAL, INTERNAL] {
+            let template = format!("m/{}'/{}'/{}'/{}/*", address_type.as_u32(), coin_type, index, sub);
+            self.new_custom_account(unlocker, address_type, &template, number, *sub, look_ahead)?;
+        }
+        Ok(number)
+    }
+
+    /// the account number in this master of an account
+    pub fn account_number(&self, id: AccountId) -> Option<u32> {
+        self.accounts.values().find(|a| a.id() == Some(id)).map(|a| a.account_number)
+    }
+
+    /// ids of the accounts in order, archived ones included
+    pub fn account_ids(&self) -> Vec<AccountId> {
+        let mut ids = self.accounts.values().filter_map(|a| a.id()).collect::<Vec<_>>();
+        ids.sort();
+        ids.dedup();
+        ids
+    }
+
+    /// ids of the accounts not archived, in order
+    pub fn active_account_ids(&self) -> Vec<AccountId> {
+        self.account_ids().into_iter().filter(|id| !self.archived.contains(id)).collect()
+    }
+
+    /// archive an account or bring it back. Archived accounts are left out of the active ones,
+    /// their keys still match incoming payments and sign.
+    pub fn archive_account(&mut self, id: AccountId, archived: bool) -> Result<(), WalletError> {
+        if self.account_number(id).is_none() {
+            return Err(WalletError::Unsupported("no such account"));
+        }
+        if archived {
+            self.archived.insert(id);
+        } else {
+            self.archived.remove(&id);
+        }
+        Ok(())
+    }
+
+    pub fn is_archived(&self, id: AccountId) -> bool {
+        self.archived.contains(&id)
+    }
+
+    /// create an account of keys on a path template like m/0/x or m/1852'/1815'/0'/0/*, as nonstandard legacy wallets used
+    /// account and sub account number only identify the account in this master
+    pub fn new_custom_account(&mut self, unlocker: &mut Unlocker, address_type: AccountAddressType, template: &str, account_number: u32, sub_account_number: u32, look_ahead: u32) -> Result<&mut Account, WalletError> {
+        self.check_unlocker(unlocker)?;
+        let account = Account::new_custom(unlocker, address_type, template, account_number, sub_account_number, look_ahead)?;
+        self.add_account(account);
+        Ok(self.accounts.get_mut(&(account_number, sub_account_number)).unwrap())
+    }
+
+    fn check_unlocker(&self, unlocker: &Unlocker) -> Result<(), WalletError> {
+        if self.is_watch_only() {
+            return Err(WalletError::Unsupported("watch only master can not create accounts"));
+        }
+        if NetworkKind::from(unlocker.network) != self.master_public.network || self.network().is_some_and(|n| n != unlocker.network) {
+            return Err(WalletError::Network);
+        }
+        if unlocker.context.extended_public_from_private(&unlocker.master_private) != self.master_public {
+            return Err(WalletError::Passphrase);
+        }
+        Ok(())
+    }
+
+    pub fn sign<R>(&self, transaction: &mut Transaction, hash_type: EcdsaSighashType, resolver: &R, unlocker: &mut Unlocker) -> Result<usize, WalletError>
         where R: Fn(&OutPoint) -> Option<TxOut> {
+        if self.is_watch_only() {
+            return Err(WalletError::Unsupported("watch only master can not sign"));
+        }
+        // an unlocker of an other pd_passphrase has other keys
+        if Unlocker::fingerprint(unlocker) != self.fingerprint {
+            return Err(WalletError::Passphrase);
+        }
         let mut n_signatures = 0;
         for (_, a) in self.accounts.iter() {
             n_signatures += a.sign(transaction, hash_type, resolver, unlocker)?;
         }
         Ok(n_signatures)
     }
+
+    /// replace a transaction signaling opt-in RBF (BIP125) with one paying at a higher fee rate.
+    /// inputs stay the same, the fee is taken from the last output paying to an internal chain, or else to any account,
+    /// change that would become dust is given up to the fee. All inputs must be spent by accounts of the master.
+    pub fn bump_fee<R>(&self, original: &Transaction, fee_rate: FeeRate, resolver: &R, unlocker: &mut Unlocker) -> Result<Transaction, WalletError>
+        where R: Fn(&OutPoint) -> Option<TxOut> {
+        if !original.is_explicitly_rbf() {
+            return Err(WalletError::NotReplaceable(original.compute_txid()));
+        }
+        let mut input_value = 0;
+        for input in original.input.iter() {
+            input_value += resolver(&input.previous_output).ok_or(WalletError::Unsupported("unknown spent output"))?.value.to_sat();
+        }
+        let output_value = original.output.iter().map(|o| o.value.to_sat()).sum::<u64>();
+        let original_fee = input_value.checked_sub(output_value).ok_or(WalletError::Unsupported("outputs exceed inputs"))?;
+        let chain = |o: &TxOut| self.get_key_for_script(&o.script_pubkey).map(|(a, _)| a.sub_account_number());
+        let change = original.output.iter().rposition(|o| chain(o) == Some(INTERNAL))
+            .or_else(|| original.output.iter().rposition(|o| chain(o).is_some()))
+            .ok_or(WalletError::Unsupported("no change output to reduce"))?;
+
+        // the original is signed already so its weight is what the replacement will have,
+        // allowing for a byte longer DER signature on each input
+        let weight = original.weight().to_wu() + 4 * original.input.len() as u64;
+        // BIP125 also requires paying for the relay of the replacement
+        let fee = max(coinselect::fee(fee_rate, weight), original_fee + weight.div_ceil(4));
+
+        let mut replacement = original.clone();
+        for input in replacement.input.iter_mut() {
+            input.script_sig = ScriptBuf::new();
+            input.witness.clear();
+        }
+        let reduced = replacement.output[change].value.to_sat().checked_sub(fee - original_fee)
+            .ok_or(WalletError::InsufficientFunds)?;
+        if reduced < replacement.output[change].script_pubkey.minimal_non_dust().to_sat() {
+            if replacement.output.len() == 1 {
+                return Err(WalletError::InsufficientFunds);
+            }
+            replacement.output.remove(change);
+        } else {
+            replacement.output[change].value = Amount::from_sat(reduced);
+        }
+        if self.sign(&mut replacement, EcdsaSighashType::All, resolver, unlocker)? != replacement.input.len() {
+            return Err(WalletError::Unsupported("can only bump fee of transactions spending coins of the master"));
+        }
+        Ok(replacement)
+    }
+
+    /// add signatures of a signer holding the master key, e.g. a hardware wallet of a watch only master
+    /// returns the number of signatures added
+    pub fn sign_psbt(&self, psbt: &mut Psbt, signer: &mut dyn Signer) -> Result<usize, WalletError> {
+        if signer.fingerprint()? != self.fingerprint {
+            return Err(WalletError::Unsupported("signer holds an other master key"));
+        }
+        signer.sign_psbt(self, psbt)
+    }
 }
 
 /// calculator of private keys
 pub struct Unlocker {
-    master_private: ExtendedPrivKey,
+    master_private: Xpriv,
     network: Network,
+    coin_type: u32,
     context: SecpContext,
-    cached: HashMap<AccountAddressType,
-        (ExtendedPrivKey, HashMap<u32,
-            (ExtendedPrivKey, HashMap<u32,
-                (ExtendedPrivKey, HashMap<u32, ExtendedPrivKey>)>)>)>,
+    cached: HashMap<AccountAddressType, (Xpriv, HashMap<u32, CoinTypeCache>)>,
+    fingerprint: Fingerprint,
+    /// sub account keys of a hot unlocker, it has no other keys
+    hot: Option<HashMap<(AccountAddressType, u32, u32), Xpriv>>
 }
 
+impl Drop for Unlocker {
+    fn drop(&mut self) {
+        self.master_private.private_key.non_secure_erase();
+        for (purpose, by_coin_type) in self.cached.values_mut() {
+            purpose.private_key.non_secure_erase();
+            for (coin_type, by_account) in by_coin_type.values_mut() {
+                coin_type.private_key.non_secure_erase();
+                for (account, subs) in by_account.values_mut() {
+                    account.private_key.non_secure_erase();
+                    for sub in subs.values_mut() {
+                        sub.private_key.non_secure_erase();
+                    }
+                }
+            }
+        }
+        if let Some(ref mut hot) = self.hot {
+            for key in hot.values_mut() {
+                key.private_key.non_secure_erase();
+            }
+        }
+    }
+}
+
+/// cached account keys of a coin type and their sub account keys
+type CoinTypeCache = (Xpriv, HashMap<u32, (Xpriv, HashMap<u32, Xpriv>)>);
+
 impl Unlocker {
     /// decrypt encrypted seed of a master account
     /// check result if master_public is provided
-    pub fn new (encrypted: &[u8], passphrase: &str, pd_passphrase: Option<&str>, network: Network, master_public: Option<&ExtendedPubKey>) -> Result<Unlocker, WalletError>{
+    pub fn new (encrypted: &[u8], passphrase: &str, pd_passphrase: Option<&str>, network: Network, master_public: Option<&Xpub>) -> Result<Unlocker, WalletError>{
         let mnemonic = Mnemonic::decrypt (encrypted, passphrase)?;
         let context = SecpContext::new();
         let master_private = context.master_private_key(network, &Seed::new(&mnemonic, pd_passphrase))?;
         if let Some(master_public) = master_public {
-            if network != master_public.network {
+            if NetworkKind::from(network) != master_public.network {
                 return Err(WalletError::Network);
             }
             if context.extended_public_from_private(&master_private) != *master_public {
                 return Err(WalletError::Passphrase);
             }
         }
-        Ok(Unlocker{master_private, network, context, cached: HashMap::new()})
+        let fingerprint = context.extended_public_from_private(&master_private).fingerprint();
+        Ok(Unlocker{master_private, network, coin_type: coin_type(network), context, cached: HashMap::new(), fingerprint, hot: None})
     }
 
-    pub fn master_private (&self) -> &ExtendedPrivKey {
+    /// an unlocker of only the sub account keys of some accounts of the master, e.g. of its receive and change chains.
+    /// It has no master key, other keys fail with WalletError::Locked.
+    pub fn hot(&mut self, master: &MasterAccount, accounts: &[(u32, u32)]) -> Result<Unlocker, WalletError> {
+        let mut hot = HashMap::new();
+        for number in accounts {
+            let account = master.get(*number).ok_or(WalletError::UnknownAccount { account: number.0, sub_account: number.1 })?;
+            if account.path().is_some() {
+                return Err(WalletError::Unsupported("custom accounts can not be hot"));
+            }
+            let key = self.sub_account_key(account.address_type(), account.account_number(), account.sub_account_number())?;
+            hot.insert((account.address_type(), account.account_number(), account.sub_account_number()), key);
+        }
+        let mut master_private = self.master_private;
+        master_private.private_key.non_secure_erase();
+        Ok(Unlocker{master_private, network: self.network, coin_type: self.coin_type, context: SecpContext::new(), cached: HashMap::new(), fingerprint: self.fingerprint, hot: Some(hot)})
+    }
+
+    /// true if this unlocker has sub account keys only
+    pub fn is_hot(&self) -> bool {
+        self.hot.is_some()
+    }
+
+    /// derive accounts on an other coin type than the BIP44 one of the network, e.g. for a custom network
+    pub fn with_coin_type(mut self, coin_type: u32) -> Unlocker {
+        self.coin_type = coin_type;
+        self
+    }
+
+    pub fn coin_type(&self) -> u32 {
+        self.coin_type
+    }
+
+    /// mix fresh randomness into ECDSA nonces of signatures with these keys, see `SecpContext::set_extra_entropy`
+    pub fn with_extra_entropy(mut self, extra_entropy: bool) -> Unlocker {
+        self.context.set_extra_entropy(extra_entropy);
+        self
+    }
+
+    /// the context keys are used with
+    pub fn context(&self) -> &SecpContext {
+        &self.context
+    }
+
+    /// the master key, erased in a hot unlocker
+    pub fn master_private (&self) -> &Xpriv {
         &self.master_private
     }
 
-    pub fn sub_account_key(&mut self, address_type: AccountAddressType, account: u32, sub_account: u32) -> Result<ExtendedPrivKey, WalletError> {
+    /// fingerprint of the master key
+    pub fn fingerprint(&self) -> Fingerprint {
+        self.fingerprint
+    }
+
+    /// the key at any path of the master key, not cached
+    pub fn derive(&self, path: &DerivationPath) -> Result<Xpriv, WalletError> {
+        if self.hot.is_some() {
+            return Err(WalletError::Locked);
+        }
+        let mut key = self.master_private;
+        for child in path {
+            key = self.context.private_child(&key, *child).map_err(|e| match e {
+                WalletError::KeyDerivation(source) => WalletError::Derivation { path: path.clone(), source },
+                e => e
+            })?;
+        }
+        Ok(key)
+    }
+
+    /// the account key m / purpose' / coin_type' / account'
+    /// its extended public key is what a watch only master needs
+    pub fn account_key(&mut self, address_type: AccountAddressType, account: u32) -> Result<Xpriv, WalletError> {
+        Ok(self.account_entry(address_type, account)?.0)
+    }
+
+    pub fn sub_account_key(&mut self, address_type: AccountAddressType, account: u32, sub_account: u32) -> Result<Xpriv, WalletError> {
+        if let Some(ref hot) = self.hot {
+            return hot.get(&(address_type, account, sub_account)).cloned().ok_or(WalletError::Locked);
+        }
+        let account_key = self.account_entry(address_type, account)?.0;
+        self.context.private_child(&account_key, ChildNumber::Normal { index: sub_account })
+    }
+
+    fn account_entry(&mut self, address_type: AccountAddressType, account: u32) -> Result<&mut (Xpriv, HashMap<u32, Xpriv>), WalletError> {
+        if self.hot.is_some() {
+            return Err(WalletError::Locked);
+        }
         let by_purpose = self.cached.entry(address_type).or_insert(
             (
                 self.context.private_child(&self.master_private, ChildNumber::Hardened { index: address_type.as_u32() })?
                 , HashMap::new()));
-        let coin_type = match self.network {
-            Network::Bitcoin => 0,
-            Network::Testnet => 1,
-            Network::Regtest => 1
-        };
+        let coin_type = self.coin_type;
         let by_coin_type = by_purpose.1.entry(coin_type).or_insert(
             (
                 self.context.private_child(&by_purpose.0, ChildNumber::Hardened { index: coin_type })?
                 ,HashMap::new()));
         let by_account = by_coin_type.1.entry(account).or_insert(
             (self.context.private_child(&by_coin_type.0, ChildNumber::Hardened { index: account })?, HashMap::new()));
-        Ok(self.context.private_child(&by_account.0, ChildNumber::Normal { index: sub_account })?)
+        Ok(by_account)
     }
 
     pub fn unlock (&mut self, address_type: AccountAddressType, account: u32, sub_account: u32, index: u32, tweak: Option<Vec<u8>>) -> Result<PrivateKey, WalletError> {
         let sub_account_key = self.sub_account_key(address_type, account, sub_account)?;
-        let mut key = self.context.private_child(&sub_account_key, ChildNumber::Normal { index: index })?.private_key;
+        let key = self.context.private_child(&sub_account_key, ChildNumber::Normal { index })?.to_priv();
+        self.tweaked(key, tweak)
+    }
+
+    /// the private key at a path, tweaked if tweak is given
+    pub fn unlock_path(&mut self, path: &DerivationPath, tweak: Option<Vec<u8>>) -> Result<PrivateKey, WalletError> {
+        let key = self.derive(path)?.to_priv();
+        self.tweaked(key, tweak)
+    }
+
+    fn tweaked(&self, mut key: PrivateKey, tweak: Option<Vec<u8>>) -> Result<PrivateKey, WalletError> {
         if let Some(tweak) = tweak {
             self.context.tweak_add(&mut key, tweak.as_slice())?;
         }
@@ -205,9 +668,42 @@ impl Unlocker {
     }
 }
 
+/// path of the chain of keys of a template like m/0/x or m/0/*, the last step may be omitted
+pub fn chain_path(template: &str) -> Result<DerivationPath, WalletError> {
+    let chain = template.strip_suffix("/x").or_else(|| template.strip_suffix("/*")).unwrap_or(template);
+    DerivationPath::from_str(chain).map_err(|_| WalletError::Unsupported("invalid derivation path template"))
+}
+
+/// BIP44 coin type of a network
+pub(crate) fn coin_type(network: Network) -> u32 {
+    match network {
+        Network::Bitcoin => 0,
+        _ => 1
+    }
+}
+
+/// output scripts of keys in range of a sub account's extended public key, all derived with one context
+/// scripts are indexed by key and ready to match compact block filters, e.g. during a rescan
+pub fn derive_scripts(context: &SecpContext, address_type: AccountAddressType, sub_account_public: &Xpub, range: Range<u32>) -> Result<Vec<(u32, ScriptBuf)>, WalletError> {
+    let first = range.start;
+    let publics = context.public_children(sub_account_public, range)?;
+    let mut scripts = Vec::with_capacity(publics.len());
+    for (i, public) in publics.iter().enumerate() {
+        let script = match address_type {
+            AccountAddressType::P2PKH => ScriptBuf::new_p2pkh(&public.pubkey_hash()),
+            AccountAddressType::P2SHWPKH => ScriptBuf::new_p2sh(&ScriptBuf::new_p2wpkh(&InstantiatedKey::compressed(public)?.wpubkey_hash()).script_hash()),
+            AccountAddressType::P2WPKH => ScriptBuf::new_p2wpkh(&InstantiatedKey::compressed(public)?.wpubkey_hash()),
+            AccountAddressType::P2TR => context.p2tr_address(public, Network::Bitcoin).script_pubkey(),
+            AccountAddressType::P2WSH(_) => return Err(WalletError::Unsupported("P2WSH scripts can not be derived from keys alone"))
+        };
+        scripts.push((first + i as u32, script));
+    }
+    Ok(scripts)
+}
+
 /// Key derivation detail information
 /// coordinates of a key as defined in BIP32 and BIP44
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct KeyDerivation {
     /// m / purpose' / coin_type' / account' / sub / kix
     pub account: u32,
@@ -219,8 +715,19 @@ pub struct KeyDerivation {
     pub tweak: Option<Vec<u8>>
 }
 
+/// usage of an address
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum AddressStatus {
+    /// never received funds
+    Fresh,
+    /// received funds from one transaction
+    Used,
+    /// received funds from several transactions, hurting the privacy of all of them
+    Reused
+}
+
 /// Address type an account is using
-#[derive(Copy, Clone, Hash, Eq, PartialEq)]
+#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq)]
 pub enum AccountAddressType {
     /// legacy pay to public key hash (BIP44)
     P2PKH,
@@ -228,8 +735,10 @@ pub enum AccountAddressType {
     P2SHWPKH,
     /// native segwit pay to public key hash in bech format (BIP84)
     P2WPKH,
+    /// taproot key path spend to a BIP86 tweaked key in bech32m format
+    P2TR,
     /// native segwit pay to script
-    /// do not use 44, 49 or 84 for this parameter, to avoid confusion with above types
+    /// do not use 44, 49, 84 or 86 for this parameter, to avoid confusion with above types
     /// Only supports scripts that can be spent with following witness:
     /// <signature> <scriptCode>
     P2WSH(u32),
@@ -241,6 +750,7 @@ impl AccountAddressType {
             AccountAddressType::P2PKH => 44,
             AccountAddressType::P2SHWPKH => 49,
             AccountAddressType::P2WPKH => 84,
+            AccountAddressType::P2TR => 86,
             AccountAddressType::P2WSH(n) => *n
         }
     }
@@ -250,6 +760,7 @@ impl AccountAddressType {
             44 => AccountAddressType::P2PKH,
             49 => AccountAddressType::P2SHWPKH,
             84 => AccountAddressType::P2WPKH,
+            86 => AccountAddressType::P2TR,
             n => AccountAddressType::P2WSH(n)
         }
     }
@@ -260,11 +771,16 @@ pub struct Account {
     account_number: u32,
     sub_account_number: u32,
     context: Arc<SecpContext>,
-    master_public: ExtendedPubKey,
+    master_public: Xpub,
     instantiated: Vec<InstantiatedKey>,
     next: u32,
     look_ahead: u32,
     network: Network,
+    coin_type: u32,
+    /// path of the chain of keys if it is not m / purpose' / coin_type' / account' / sub_account
+    path: Option<DerivationPath>,
+    /// transactions that paid to a key, by key index
+    receipts: HashMap<u32, HashSet<Txid>>
 }
 
 impl Account {
@@ -274,21 +790,86 @@ impl Account {
         let pubic_key = context.extended_public_from_private(&master_private);
         let mut sub = Account {
             address_type, account_number, sub_account_number, context,
-            master_public: pubic_key, instantiated: Vec::new(), next: 0, look_ahead, network: pubic_key.network
+            master_public: pubic_key, instantiated: Vec::new(), next: 0, look_ahead, network: unlocker.network,
+            coin_type: unlocker.coin_type, path: None, receipts: HashMap::new()
         };
         sub.do_look_ahead(0)?;
         Ok(sub)
     }
 
+    /// an account of keys on a path template like m/0/x or m/44'/0'/0'/0/*
+    /// keys are the non-hardened children of the path before the last step
+    pub fn new_custom (unlocker: &mut Unlocker, address_type: AccountAddressType, template: &str, account_number: u32, sub_account_number: u32, look_ahead: u32) -> Result<Account, WalletError> {
+        let path = chain_path(template)?;
+        let context = Arc::new(SecpContext::new());
+        let master_public = context.extended_public_from_private(&unlocker.derive(&path)?);
+        let mut sub = Account {
+            address_type, account_number, sub_account_number, context,
+            master_public, instantiated: Vec::new(), next: 0, look_ahead, network: unlocker.network,
+            coin_type: unlocker.coin_type, path: Some(path), receipts: HashMap::new()
+        };
+        sub.do_look_ahead(0)?;
+        Ok(sub)
+    }
+
+    /// a watch only account derived from the extended public key at m / purpose' / coin_type' / account'
+    pub fn new_watch_only (account_public: &Xpub, address_type: AccountAddressType, account_number: u32, sub_account_number: u32, look_ahead: u32, network: Network) -> Result<Account, WalletError> {
+        let context = Arc::new(SecpContext::new());
+        let master_public = context.public_child(account_public, ChildNumber::Normal { index: sub_account_number })?;
+        let mut sub = Account {
+            address_type, account_number, sub_account_number, context,
+            master_public, instantiated: Vec::new(), next: 0, look_ahead, network, coin_type: coin_type(network), path: None, receipts: HashMap::new()
+        };
+        sub.do_look_ahead(0)?;
+        Ok(sub)
+    }
+
+    #[allow(clippy::too_many_arguments)]
     pub fn new_from_storage(address_type: AccountAddressType, account_number: u32, sub_account_number: u32,
-                            master_public: ExtendedPubKey, instantiated: Vec<InstantiatedKey>,
+                            master_public: Xpub, instantiated: Vec<InstantiatedKey>,
                             next: u32, look_ahead: u32, network: Network) -> Account {
         let context = Arc::new(SecpContext::new());
         Account {
-            address_type, account_number, sub_account_number, context, master_public, instantiated, next, look_ahead, network
+            address_type, account_number, sub_account_number, context, master_public, instantiated, next, look_ahead, network,
+            coin_type: coin_type(network), path: None, receipts: HashMap::new()
+        }
+    }
+
+    /// restore the chain path of a custom account
+    pub(crate) fn with_path(mut self, path: Option<DerivationPath>) -> Account {
+        self.path = path;
+        self
+    }
+
+    /// restore the transactions that paid to keys
+    pub(crate) fn with_receipts(mut self, receipts: HashMap<u32, HashSet<Txid>>) -> Account {
+        self.receipts = receipts;
+        self
+    }
+
+    /// purpose, coin type and index of the account, none for custom accounts off the m / purpose' / coin_type' / account' hierarchy
+    pub fn id(&self) -> Option<AccountId> {
+        match self.path {
+            None => Some((self.address_type.as_u32(), self.coin_type, self.account_number)),
+            Some(ref path) => match path.as_ref() {
+                [ChildNumber::Hardened { index: purpose }, ChildNumber::Hardened { index: coin_type }, ChildNumber::Hardened { index: account }, ChildNumber::Normal { .. }] =>
+                    Some((*purpose, *coin_type, *account)),
+                _ => None
+            }
         }
     }
 
+    /// path of the chain of keys of a custom account, none for the standard m / purpose' / coin_type' / account' / sub_account
+    pub fn path(&self) -> Option<&DerivationPath> {
+        self.path.as_ref()
+    }
+
+    /// the coin type of key paths if it is not the BIP44 one of the network
+    pub fn with_coin_type(mut self, coin_type: u32) -> Account {
+        self.coin_type = coin_type;
+        self
+    }
+
     pub fn address_type (&self) -> AccountAddressType {
         self.address_type
     }
@@ -301,7 +882,7 @@ impl Account {
         self.sub_account_number
     }
 
-    pub fn master_public(&self) -> &ExtendedPubKey {
+    pub fn master_public(&self) -> &Xpub {
         &self.master_public
     }
 
@@ -317,12 +898,80 @@ impl Account {
         self.network
     }
 
+    pub fn coin_type(&self) -> u32 {
+        self.coin_type
+    }
+
     pub fn instantiated(&self) -> &Vec<InstantiatedKey> {
         &self.instantiated
     }
 
+    /// transactions of this account and its other sub accounts, e.g. change, with their net effect
+    pub fn history(&self, utxos: &UtxoSet) -> Vec<HistoryEntry> {
+        history::account_history(utxos, self.address_type, self.account_number)
+    }
+
+    /// sign a transaction paying all coins of an external private key in WIF to the next key of this account,
+    /// the coins are looked up at the source
+    pub fn sweep_wif(&mut self, wif: &str, fee_rate: FeeRate, source: &mut dyn UtxoSource) -> Result<Transaction, WalletError> {
+        let network = self.network;
+        let destination = self.next_key()?.script_pubkey.clone();
+        sweep::sweep(wif, network, fee_rate, source, destination)
+    }
+
+    /// BIP32 path of a key of this account
+    /// m / purpose' / coin_type' / account' / sub / kix
+    pub fn key_path(&self, kix: u32) -> DerivationPath {
+        if let Some(ref path) = self.path {
+            return path.child(ChildNumber::Normal { index: kix });
+        }
+        DerivationPath::from(vec!(
+            ChildNumber::Hardened { index: self.address_type.as_u32() },
+            ChildNumber::Hardened { index: self.coin_type },
+            ChildNumber::Hardened { index: self.account_number },
+            ChildNumber::Normal { index: self.sub_account_number },
+            ChildNumber::Normal { index: kix }))
+    }
+
+    /// output scripts of a range of keys without instantiating them, see `derive_scripts`
+    pub fn derive_scripts(&self, range: Range<u32>) -> Result<Vec<(u32, ScriptBuf)>, WalletError> {
+        derive_scripts(&self.context, self.address_type, &self.master_public, range)
+    }
+
+    /// note that a transaction paid to key kix, a transaction is counted once however many outputs pay to the key
+    pub fn record_receipt(&mut self, kix: u32, txid: Txid) {
+        self.receipts.entry(kix).or_default().insert(txid);
+    }
+
+    /// whether key kix received funds, and from how many transactions
+    pub fn address_status(&self, kix: u32) -> AddressStatus {
+        match self.receipts.get(&kix).map(|r| r.len()).unwrap_or(0) {
+            0 => AddressStatus::Fresh,
+            1 => AddressStatus::Used,
+            _ => AddressStatus::Reused
+        }
+    }
+
+    /// transactions that paid to keys, by key index
+    pub fn receipts(&self) -> &HashMap<u32, HashSet<Txid>> {
+        &self.receipts
+    }
+
+    /// true if the account is the internal chain of change keys
+    pub fn is_internal(&self) -> bool {
+        self.sub_account_number == INTERNAL
+    }
+
+    /// private key of key kix, tweaked if tweak is given
+    pub fn unlock(&self, unlocker: &mut Unlocker, kix: u32, tweak: Option<Vec<u8>>) -> Result<PrivateKey, WalletError> {
+        match self.path {
+            Some(ref path) => unlocker.unlock_path(&path.child(ChildNumber::Normal { index: kix }), tweak),
+            None => unlocker.unlock(self.address_type, self.account_number, self.sub_account_number, kix, tweak)
+        }
+    }
+
     /// look ahead from last seen
-    pub fn do_look_ahead(&mut self, seen: u32) -> Result<Vec<(u32, Script)>, WalletError> {
+    pub fn do_look_ahead(&mut self, seen: u32) -> Result<Vec<(u32, ScriptBuf)>, WalletError> {
         use std::cmp::max;
 
         let have = self.instantiated.len() as u32;
@@ -343,12 +992,28 @@ impl Account {
         Ok(&self.instantiated[len])
     }
 
-    /// create a new key
+    /// create a new key, keys that received funds meanwhile are skipped
     pub fn next_key(&mut self) -> Result<&InstantiatedKey, WalletError> {
+        while self.receipts.contains_key(&self.next) {
+            self.next += 1;
+            self.instantiate_more()?;
+        }
         self.instantiate_more()?;
         let key = &self.instantiated[self.next as usize];
         self.next += 1;
-        Ok(&key)
+        Ok(key)
+    }
+
+    /// the key next_key would create, the account does not change
+    pub fn peek_key(&self) -> Result<InstantiatedKey, WalletError> {
+        let mut next = self.next;
+        while self.receipts.contains_key(&next) {
+            next += 1;
+        }
+        match self.instantiated.get(next as usize) {
+            Some(key) => Ok(key.clone()),
+            None => InstantiatedKey::new_from_extended_key(self.address_type, self.network, next, &self.master_public, self.context.clone())
+        }
     }
 
     /// get a previously instantiated key
@@ -356,7 +1021,7 @@ impl Account {
         self.instantiated.get(kix as usize)
     }
 
-    pub fn add_script_key(&mut self, pk: PublicKey, script_code: Script, tweak: Option<&[u8]>) -> Result<u32, WalletError> {
+    pub fn add_script_key(&mut self, pk: PublicKey, script_code: ScriptBuf, tweak: Option<&[u8]>) -> Result<u32, WalletError> {
         match self.address_type {
             AccountAddressType::P2WSH(_) => {}
             _ => return Err(WalletError::Unsupported("add_script_key can only be used for P2WSH accounts"))
@@ -372,82 +1037,100 @@ impl Account {
         self.next as usize
     }
 
+    /// move the next index forward, keys up to it and the look ahead are instantiated
+    pub fn set_next(&mut self, next: u32) -> Result<(), WalletError> {
+        if next > self.next {
+            self.next = next;
+            self.do_look_ahead(next)?;
+        }
+        Ok(())
+    }
+
     // get all pubkey scripts of this account
-    pub fn get_scripts<'a>(&'a self) -> impl Iterator<Item=(u32, Script, Option<Vec<u8>>)> + 'a {
+    pub fn get_scripts<'a>(&'a self) -> impl Iterator<Item=(u32, ScriptBuf, Option<Vec<u8>>)> + 'a {
         self.instantiated.iter().enumerate().map(|(kix, i)| (kix as u32, i.script_pubkey.clone(), i.tweak.clone()))
     }
 
-    /// sign a transaction with keys in this account works for types except P2WSH
-    pub fn sign<R>(&self, transaction: &mut Transaction, hash_type: SigHashType, resolver: R, unlocker: &mut Unlocker) -> Result<usize, WalletError>
+    /// sign a transaction with keys in this account
+    /// signature hashes of all inputs are computed first from shared midstates, then signed at once, see `SecpContext::sign_all`
+    pub fn sign<R>(&self, transaction: &mut Transaction, hash_type: EcdsaSighashType, resolver: R, unlocker: &mut Unlocker) -> Result<usize, WalletError>
         where R: Fn(&OutPoint) -> Option<TxOut> {
-        let mut signed = 0;
         let txclone = transaction.clone();
-        let mut bip143hasher: Option<bip143::SighashComponents> = None;
-        for (ix, input) in transaction.input.iter_mut().enumerate() {
+        // taproot signatures commit to all spent outputs
+        let prevouts = txclone.input.iter().map(|i| resolver(&i.previous_output)).collect::<Option<Vec<_>>>();
+        let mut sighashes = TxSighashes::new(&txclone, prevouts);
+        let mut work = Vec::new();
+        let mut signing = Vec::new();
+        for (ix, input) in txclone.input.iter().enumerate() {
             if let Some(spend) = resolver(&input.previous_output) {
                 if let Some(instantiated) =
                 self.instantiated.iter().find(|i| i.script_pubkey == spend.script_pubkey) {
-                    let pk = unlocker.unlock(self.address_type, self.account_number, self.sub_account_number, instantiated.index, instantiated.tweak.clone())?;
-                    match self.address_type {
-                        AccountAddressType::P2PKH => {
-                            let sighash = txclone.signature_hash(ix, &instantiated.address.script_pubkey(), hash_type.as_u32());
-                            let mut signature = self.context.sign(&sighash[..], &pk)?.serialize_der();
-                            signature.push(hash_type.as_u32() as u8);
-                            input.script_sig = Builder::new()
-                                .push_slice(signature.as_slice())
-                                .push_slice(instantiated.public.to_bytes().as_slice()).into_script();
-                            signed += 1;
-                        }
-                        AccountAddressType::P2WPKH => {
-                            if hash_type.as_u32() & SigHashType::All.as_u32() == 0 {
-                                return Err(WalletError::Unsupported("can only sig all inputs for now"));
-                            }
-                            input.script_sig = Script::new();
-                            let hasher = bip143hasher.unwrap_or(bip143::SighashComponents::new(&txclone));
-                            let sighash = hasher.sighash_all(&txclone.input[ix], &instantiated.script_code, spend.value);
-                            bip143hasher = Some(hasher);
-                            let mut signature = self.context.sign(&sighash[..], &pk)?.serialize_der();
-                            signature.push(hash_type.as_u32() as u8);
-                            input.witness.push(signature);
-                            input.witness.push(instantiated.public.to_bytes());
-                            signed += 1;
-                        }
-                        AccountAddressType::P2SHWPKH => {
-                            if hash_type.as_u32() & SigHashType::All.as_u32() == 0 {
-                                return Err(WalletError::Unsupported("can only sig all inputs for now"));
-                            }
-                            input.script_sig = Builder::new().push_slice(&Builder::new()
-                                .push_int(0)
-                                .push_slice(&hash160::Hash::hash(instantiated.public.to_bytes().as_slice())[..])
-                                .into_script()[..]).into_script();
-                            let hasher = bip143hasher.unwrap_or(bip143::SighashComponents::new(&txclone));
-                            let sighash = hasher.sighash_all(&txclone.input[ix], &instantiated.script_code, spend.value);
-                            bip143hasher = Some(hasher);
-                            let mut signature = self.context.sign(&sighash[..], &pk)?.serialize_der();
-                            signature.push(hash_type.as_u32() as u8);
-                            input.witness.push(signature);
-                            input.witness.push(instantiated.public.to_bytes());
-                            signed += 1;
-                        }
-                        AccountAddressType::P2WSH(_) => {
-                            if hash_type.as_u32() & SigHashType::All.as_u32() == 0 {
-                                return Err(WalletError::Unsupported("can only sig all inputs for now"));
-                            }
-                            input.script_sig = Script::new();
-                            let hasher = bip143hasher.unwrap_or(bip143::SighashComponents::new(&txclone));
-                            let sighash = hasher.sighash_all(&txclone.input[ix], &instantiated.script_code, spend.value);
-                            bip143hasher = Some(hasher);
-                            let mut signature = self.context.sign(&sighash[..], &pk)?.serialize_der();
-                            signature.push(hash_type.as_u32() as u8);
-                            input.witness.push(signature);
-                            input.witness.push(instantiated.script_code.to_bytes());
-                            signed += 1;
-                        }
-                    }
+                    let pk = self.unlock(unlocker, instantiated.index, instantiated.tweak.clone())?;
+                    work.push((sighashes.signing_hash(ix, self.address_type, &instantiated.script_code, &spend, hash_type)?, pk));
+                    signing.push((ix, instantiated));
                 }
             }
         }
-        Ok(signed)
+        let signatures = unlocker.context.sign_all(&work)?;
+        for ((ix, instantiated), signature) in signing.iter().zip(signatures) {
+            let input = &mut transaction.input[*ix];
+            match (self.address_type, signature) {
+                (AccountAddressType::P2PKH, AnySignature::Ecdsa(signature)) => {
+                    let signature = ecdsa::Signature { signature, sighash_type: hash_type };
+                    input.script_sig = Builder::new()
+                        .push_slice(signature.serialize())
+                        .push_key(&instantiated.public).into_script();
+                }
+                (AccountAddressType::P2WPKH, AnySignature::Ecdsa(signature)) => {
+                    input.script_sig = ScriptBuf::new();
+                    input.witness.push(ecdsa::Signature { signature, sighash_type: hash_type }.serialize());
+                    input.witness.push(instantiated.public.to_bytes());
+                }
+                (AccountAddressType::P2SHWPKH, AnySignature::Ecdsa(signature)) => {
+                    let redeem_script = ScriptBuf::new_p2wpkh(&instantiated.public.wpubkey_hash().expect("compressed key"));
+                    input.script_sig = Builder::new()
+                        .push_slice(PushBytesBuf::try_from(redeem_script.into_bytes()).expect("short script"))
+                        .into_script();
+                    input.witness.push(ecdsa::Signature { signature, sighash_type: hash_type }.serialize());
+                    input.witness.push(instantiated.public.to_bytes());
+                }
+                (AccountAddressType::P2WSH(_), AnySignature::Ecdsa(signature)) => {
+                    input.script_sig = ScriptBuf::new();
+                    input.witness.push(ecdsa::Signature { signature, sighash_type: hash_type }.serialize());
+                    input.witness.push(instantiated.script_code.to_bytes());
+                }
+                (AccountAddressType::P2TR, AnySignature::Schnorr(signature)) => {
+                    input.script_sig = ScriptBuf::new();
+                    input.witness.push(taproot::Signature { signature, sighash_type: tap_sighash_type(hash_type) }.to_vec());
+                }
+                _ => unreachable!("taproot keys sign with schnorr, all others with ecdsa")
+            }
+        }
+        Ok(signing.len())
+    }
+
+    /// sign a message to prove control of the address of a key
+    /// BIP322 signatures, legacy signmessage signatures for P2PKH keys
+    pub fn sign_message(&self, kix: u32, message: &str, unlocker: &mut Unlocker) -> Result<String, WalletError> {
+        message::sign(self, kix, message, unlocker)
+    }
+
+    /// verify a signed message of the address of a key
+    pub fn verify_message(&self, kix: u32, message: &str, signature: &str) -> Result<bool, WalletError> {
+        let key = self.get_key(kix).ok_or(WalletError::Unsupported("unknown key"))?;
+        message::verify(&key.address, message, signature)
+    }
+}
+
+/// taproot sighash type of the same coverage, ALL is signed as DEFAULT for shorter signatures
+pub(crate) fn tap_sighash_type(hash_type: EcdsaSighashType) -> TapSighashType {
+    match hash_type {
+        EcdsaSighashType::All => TapSighashType::Default,
+        EcdsaSighashType::None => TapSighashType::None,
+        EcdsaSighashType::Single => TapSighashType::Single,
+        EcdsaSighashType::AllPlusAnyoneCanPay => TapSighashType::AllPlusAnyoneCanPay,
+        EcdsaSighashType::NonePlusAnyoneCanPay => TapSighashType::NonePlusAnyoneCanPay,
+        EcdsaSighashType::SinglePlusAnyoneCanPay => TapSighashType::SinglePlusAnyoneCanPay
     }
 }
 
@@ -456,42 +1139,47 @@ impl Account {
 pub struct InstantiatedKey {
     pub index: u32,
     pub public: PublicKey,
-    pub script_code: Script,
+    pub script_code: ScriptBuf,
+    #[serde(with = "address_serde")]
     pub address: Address,
-    pub script_pubkey: Script,
+    pub script_pubkey: ScriptBuf,
     pub tweak: Option<Vec<u8>>
 }
 
+/// addresses are stored in their string form and trusted to be of the right network on load
+mod address_serde {
+    use bitcoin::Address;
+    use bitcoin::address::NetworkUnchecked;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(address: &Address, serializer: S) -> Result<S::Ok, S::Error> {
+        address.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Address, D::Error> {
+        Ok(Address::<NetworkUnchecked>::deserialize(deserializer)?.assume_checked())
+    }
+}
+
 
 impl InstantiatedKey {
-    pub fn new_from_extended_key(address_type: AccountAddressType, network: Network, index: u32, ek: &ExtendedPubKey, context: Arc<SecpContext>) -> Result<InstantiatedKey, WalletError> {
-        return Self::new(address_type, network, context.public_child(&ek, ChildNumber::Normal {index})?.public_key, None, index, None, context);
+    pub fn new_from_extended_key(address_type: AccountAddressType, network: Network, index: u32, ek: &Xpub, context: Arc<SecpContext>) -> Result<InstantiatedKey, WalletError> {
+        Self::new(address_type, network, PublicKey::new(context.public_child(ek, ChildNumber::Normal {index})?.public_key), None, index, None, context)
     }
 
-    pub fn new(address_type: AccountAddressType, network: Network, mut public: PublicKey, tweak: Option<&[u8]>, index: u32, script_code: Option<Script>, context: Arc<SecpContext>) -> Result<InstantiatedKey, WalletError> {
+    pub fn new(address_type: AccountAddressType, network: Network, mut public: PublicKey, tweak: Option<&[u8]>, index: u32, script_code: Option<ScriptBuf>, context: Arc<SecpContext>) -> Result<InstantiatedKey, WalletError> {
         if let Some(tweak) = tweak {
             context.tweak_exp_add(&mut public, tweak)?;
         }
         let (script_code, address) = match address_type {
-            AccountAddressType::P2PKH => (Script::new(), Address::p2pkh(&public, network)),
+            AccountAddressType::P2PKH => (ScriptBuf::new(), Address::p2pkh(public, network)),
             AccountAddressType::P2SHWPKH => (
-                Builder::new()
-                    .push_opcode(all::OP_DUP)
-                    .push_opcode(all::OP_HASH160)
-                    .push_slice(&hash160::Hash::hash(public.to_bytes().as_slice())[..])
-                    .push_opcode(all::OP_EQUALVERIFY)
-                    .push_opcode(all::OP_CHECKSIG)
-                    .into_script(),
-                Address::p2shwpkh(&public, network)),
+                ScriptBuf::new_p2pkh(&public.pubkey_hash()),
+                Address::p2shwpkh(&Self::compressed(&public)?, network)),
             AccountAddressType::P2WPKH => (
-                Builder::new()
-                    .push_opcode(all::OP_DUP)
-                    .push_opcode(all::OP_HASH160)
-                    .push_slice(&hash160::Hash::hash(public.to_bytes().as_slice())[..])
-                    .push_opcode(all::OP_EQUALVERIFY)
-                    .push_opcode(all::OP_CHECKSIG)
-                    .into_script(),
-                Address::p2wpkh(&public, network)),
+                ScriptBuf::new_p2pkh(&public.pubkey_hash()),
+                Address::p2wpkh(&Self::compressed(&public)?, network)),
+            AccountAddressType::P2TR => (ScriptBuf::new(), context.p2tr_address(&public, network)),
             AccountAddressType::P2WSH(_) => {
                 if let Some(ref script_code) = script_code {
                     (script_code.clone(), Address::p2wsh(script_code, network))
@@ -503,20 +1191,28 @@ impl InstantiatedKey {
         let script_pubkey = address.script_pubkey();
         Ok(InstantiatedKey { index, public, script_code, address, script_pubkey, tweak: tweak.map(|t|t.to_vec()) })
     }
+
+    fn compressed(public: &PublicKey) -> Result<CompressedPublicKey, WalletError> {
+        CompressedPublicKey::try_from(*public).map_err(|_| WalletError::Unsupported("segwit needs compressed keys"))
+    }
 }
 
 /// seed of the master key
-pub struct Seed(pub Vec<u8>);
+pub struct Seed(pub SecretBytes);
 
 impl Seed {
     /// create a seed from mnemonic
     /// with optional passphrase for plausible deniability see BIP39
     pub fn new(mnemonic: &Mnemonic, pd_passphrase: Option<&str>) -> Seed {
-        let mut mac = Hmac::new(Sha512::new(), mnemonic.to_string().as_bytes());
-        let mut output = [0u8; 64];
-        let passphrase = "mnemonic".to_owned() + pd_passphrase.unwrap_or("");
-        pbkdf2(&mut mac, passphrase.as_bytes(), 2048, &mut output);
-        Seed(output.to_vec())
+        let mut phrase = mnemonic.to_string();
+        let mut words = mnemonic::nfkd(&phrase);
+        phrase.zeroize();
+        let mut output = SecretBytes::new(vec!(0u8; 64));
+        let mut passphrase = mnemonic::nfkd(&("mnemonic".to_owned() + pd_passphrase.unwrap_or("")));
+        pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA512, NonZeroU32::new(2048).expect("non zero"), passphrase.as_bytes(), words.as_bytes(), output.as_mut_slice());
+        words.zeroize();
+        passphrase.zeroize();
+        Seed(output)
     }
 }
 
@@ -524,18 +1220,25 @@ impl Seed {
 mod test {
     use std::collections::HashMap;
 
-    use bitcoin::blockdata::opcodes::all;
-    use bitcoin::blockdata::script::Builder;
-    use bitcoin::blockdata::transaction::{OutPoint, TxIn, TxOut};
-    use bitcoin_hashes::sha256d;
-
+    use bitcoin::opcodes::all;
+    use bitcoin::script::Builder;
+    use bitcoin::{Amount, OutPoint, Sequence, TxIn, TxOut, Txid, Witness};
+    use bitcoin::absolute::LockTime;
+    use bitcoin::transaction::Version;
+    use bitcoin::sighash::{Prevouts, SighashCache};
+    use bitcoin_hashes::Hash;
+    use bitcoin::{Block, BlockHash, CompactTarget, TxMerkleNode};
+    use bitcoin::block::Header;
+
+    use builder::TxBuilder;
+    use coins::Coins;
     use super::*;
 
     use std::fs::File;
     use std::path::PathBuf;
     use std::io::Read;
-    use bitcoin::network::constants::Network;
-    use bitcoin::util::bip32::ChildNumber;
+    use bitcoin::Network;
+    use bitcoin::bip32::ChildNumber;
 
     use serde_json::{Value};
     use hex::decode;
@@ -555,49 +1258,49 @@ mod test {
         let input_transaction = Transaction {
             input: vec![
                 TxIn {
-                    previous_output: OutPoint { txid: sha256d::Hash::default(), vout: 0 },
-                    sequence: 0,
-                    witness: Vec::new(),
-                    script_sig: Script::new(),
+                    previous_output: OutPoint { txid: Txid::all_zeros(), vout: 0 },
+                    sequence: Sequence(0),
+                    witness: Witness::new(),
+                    script_sig: ScriptBuf::new(),
                 }
             ],
             output: vec![
                 TxOut {
                     script_pubkey: source.script_pubkey(),
-                    value: 5000000000,
+                    value: Amount::from_sat(5000000000),
                 }
             ],
-            lock_time: 0xffffffff,
-            version: 1,
+            lock_time: LockTime::from_consensus(0xffffffff),
+            version: Version(1),
         };
-        let txid = input_transaction.txid();
+        let txid = input_transaction.compute_txid();
 
         let mut spending_transaction = Transaction {
             input: vec![
                 TxIn {
                     previous_output: OutPoint { txid, vout: 0 },
-                    sequence: 0,
-                    witness: Vec::new(),
-                    script_sig: Script::new(),
+                    sequence: Sequence(0),
+                    witness: Witness::new(),
+                    script_sig: ScriptBuf::new(),
                 }
             ],
             output: vec![
                 TxOut {
                     script_pubkey: target.script_pubkey(),
-                    value: 5000000000,
+                    value: Amount::from_sat(5000000000),
                 }
             ],
-            lock_time: 0xffffffff,
-            version: 1,
+            lock_time: LockTime::from_consensus(0xffffffff),
+            version: Version(1),
         };
 
         let mut spent = HashMap::new();
-        spent.insert(input_transaction.txid(), input_transaction.clone());
+        spent.insert(input_transaction.compute_txid(), input_transaction.clone());
 
-        assert_eq!(master.sign(&mut spending_transaction, SigHashType::All,
+        assert_eq!(master.sign(&mut spending_transaction, EcdsaSighashType::All,
                                &(|_| Some(input_transaction.output[0].clone())), &mut unlocker).unwrap(), 1);
 
-        spending_transaction.verify(&spent).unwrap();
+        spending_transaction.verify(|point| spent.get(&point.txid).map(|t| t.output[point.vout as usize].clone())).unwrap();
     }
 
     #[test]
@@ -614,49 +1317,49 @@ mod test {
         let input_transaction = Transaction {
             input: vec![
                 TxIn {
-                    previous_output: OutPoint { txid: sha256d::Hash::default(), vout: 0 },
-                    sequence: 0,
-                    witness: Vec::new(),
-                    script_sig: Script::new(),
+                    previous_output: OutPoint { txid: Txid::all_zeros(), vout: 0 },
+                    sequence: Sequence(0),
+                    witness: Witness::new(),
+                    script_sig: ScriptBuf::new(),
                 }
             ],
             output: vec![
                 TxOut {
                     script_pubkey: source.script_pubkey(),
-                    value: 5000000000,
+                    value: Amount::from_sat(5000000000),
                 }
             ],
-            lock_time: 0x11000000,
-            version: 1,
+            lock_time: LockTime::from_consensus(0x11000000),
+            version: Version(1),
         };
-        let txid = input_transaction.txid();
+        let txid = input_transaction.compute_txid();
 
         let mut spending_transaction = Transaction {
             input: vec![
                 TxIn {
                     previous_output: OutPoint { txid, vout: 0 },
-                    sequence: 0,
-                    witness: Vec::new(),
-                    script_sig: Script::new(),
+                    sequence: Sequence(0),
+                    witness: Witness::new(),
+                    script_sig: ScriptBuf::new(),
                 }
             ],
             output: vec![
                 TxOut {
                     script_pubkey: target.script_pubkey(),
-                    value: 5000000000,
+                    value: Amount::from_sat(5000000000),
                 }
             ],
-            lock_time: 0x11000000,
-            version: 1,
+            lock_time: LockTime::from_consensus(0x11000000),
+            version: Version(1),
         };
 
         let mut spent = HashMap::new();
         spent.insert(txid, input_transaction.clone());
 
-        assert_eq!(master.sign(&mut spending_transaction, SigHashType::All,
+        assert_eq!(master.sign(&mut spending_transaction, EcdsaSighashType::All,
                                &(|_| Some(input_transaction.output[0].clone())), &mut unlocker).unwrap(), 1);
 
-        spending_transaction.verify(&spent).unwrap();
+        spending_transaction.verify(|point| spent.get(&point.txid).map(|t| t.output[point.vout as usize].clone())).unwrap();
     }
 
     #[test]
@@ -673,50 +1376,50 @@ mod test {
         let input_transaction = Transaction {
             input: vec![
                 TxIn {
-                    previous_output: OutPoint { txid: sha256d::Hash::default(), vout: 0 },
-                    sequence: 0,
-                    witness: Vec::new(),
-                    script_sig: Script::new(),
+                    previous_output: OutPoint { txid: Txid::all_zeros(), vout: 0 },
+                    sequence: Sequence(0),
+                    witness: Witness::new(),
+                    script_sig: ScriptBuf::new(),
                 }
             ],
             output: vec![
                 TxOut {
                     script_pubkey: source.script_pubkey(),
-                    value: 5000000000,
+                    value: Amount::from_sat(5000000000),
                 }
             ],
-            lock_time: 0x11000000,
-            version: 1,
+            lock_time: LockTime::from_consensus(0x11000000),
+            version: Version(1),
         };
 
-        let txid = input_transaction.txid();
+        let txid = input_transaction.compute_txid();
 
         let mut spending_transaction = Transaction {
             input: vec![
                 TxIn {
                     previous_output: OutPoint { txid, vout: 0 },
-                    sequence: 0,
-                    witness: Vec::new(),
-                    script_sig: Script::new(),
+                    sequence: Sequence(0),
+                    witness: Witness::new(),
+                    script_sig: ScriptBuf::new(),
                 }
             ],
             output: vec![
                 TxOut {
                     script_pubkey: target.script_pubkey(),
-                    value: 5000000000,
+                    value: Amount::from_sat(5000000000),
                 }
             ],
-            lock_time: 0x11000000,
-            version: 1,
+            lock_time: LockTime::from_consensus(0x11000000),
+            version: Version(1),
         };
 
         let mut spent = HashMap::new();
         spent.insert(txid, input_transaction.clone());
 
-        assert_eq!(master.sign(&mut spending_transaction, SigHashType::All,
+        assert_eq!(master.sign(&mut spending_transaction, EcdsaSighashType::All,
                                &(|_| Some(input_transaction.output[0].clone())), &mut unlocker).unwrap(), 1);
 
-        spending_transaction.verify(&spent).unwrap();
+        spending_transaction.verify(|point| spent.get(&point.txid).map(|t| t.output[point.vout as usize].clone())).unwrap();
     }
 
     #[test]
@@ -741,7 +1444,7 @@ mod test {
         {
             let account = master.get_mut((1, 0)).unwrap();
             let script_code = Builder::new()
-                .push_slice(pk.to_bytes().as_slice())
+                .push_key(&pk)
                 .push_opcode(all::OP_CHECKSIG)
                 .into_script();
             account.add_script_key(pk, script_code, Some(&[0x01; 32])).unwrap();
@@ -755,50 +1458,50 @@ mod test {
         let input_transaction = Transaction {
             input: vec![
                 TxIn {
-                    previous_output: OutPoint { txid: sha256d::Hash::default(), vout: 0 },
-                    sequence: 0,
-                    witness: Vec::new(),
-                    script_sig: Script::new(),
+                    previous_output: OutPoint { txid: Txid::all_zeros(), vout: 0 },
+                    sequence: Sequence(0),
+                    witness: Witness::new(),
+                    script_sig: ScriptBuf::new(),
                 }
             ],
             output: vec![
                 TxOut {
                     script_pubkey: source.script_pubkey(),
-                    value: 5000000000,
+                    value: Amount::from_sat(5000000000),
                 }
             ],
-            lock_time: 0xffffffff,
-            version: 1,
+            lock_time: LockTime::from_consensus(0xffffffff),
+            version: Version(1),
         };
-        let txid = input_transaction.txid();
+        let txid = input_transaction.compute_txid();
 
         let mut spending_transaction = Transaction {
             input: vec![
                 TxIn {
                     previous_output: OutPoint { txid, vout: 0 },
-                    sequence: 0,
-                    witness: Vec::new(),
-                    script_sig: Script::new(),
+                    sequence: Sequence(0),
+                    witness: Witness::new(),
+                    script_sig: ScriptBuf::new(),
                 }
             ],
             output: vec![
                 TxOut {
                     script_pubkey: target.script_pubkey(),
-                    value: 5000000000,
+                    value: Amount::from_sat(5000000000),
                 }
             ],
-            lock_time: 0xffffffff,
-            version: 1,
+            lock_time: LockTime::from_consensus(0xffffffff),
+            version: Version(1),
         };
 
         let mut spent = HashMap::new();
-        spent.insert(input_transaction.txid(), input_transaction.clone());
+        spent.insert(input_transaction.compute_txid(), input_transaction.clone());
 
         assert_eq!(master.sign(
-            &mut spending_transaction, SigHashType::All,
+            &mut spending_transaction, EcdsaSighashType::All,
             &(|_| Some(input_transaction.output[0].clone())), &mut unlocker).unwrap(), 1);
 
-        spending_transaction.verify(&spent).unwrap();
+        spending_transaction.verify(|point| spent.get(&point.txid).map(|t| t.output[point.vout as usize].clone())).unwrap();
     }
 
     #[test]
@@ -817,6 +1520,367 @@ mod test {
         assert_eq!(account.get_key(0).unwrap().address.to_string(), "bc1qlz2h9scgalmqj43d36f58dcxrrl7udu999gcp2");
     }
 
+    #[test]
+    fn test_bip49 () {
+        // test vector of BIP49
+        let words = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+        let mnemonic = Mnemonic::from_str(words).unwrap();
+        let mut master = MasterAccount::from_mnemonic(&mnemonic, 0, Network::Testnet, PASSPHRASE, None).unwrap();
+        let mut unlocker = Unlocker::new(master.encrypted(), PASSPHRASE, None, Network::Testnet, None).unwrap();
+        let account = Account::new(&mut unlocker, AccountAddressType::P2SHWPKH, 0, 0, 10).unwrap();
+        // m/49'/1'/0'/0/0
+        let key = account.get_key(0).unwrap().clone();
+        assert_eq!(key.address.to_string(), "2Mww8dCYPUpKHofjgcXcBCEGmniw9CoaiD2");
+        master.add_account(account);
+
+        // sweep a coin another wallet paid to the address
+        let funding = Transaction {
+            input: vec![
+                TxIn {
+                    previous_output: OutPoint { txid: Txid::all_zeros(), vout: 0 },
+                    sequence: Sequence::MAX,
+                    witness: Witness::new(),
+                    script_sig: ScriptBuf::new(),
+                }
+            ],
+            output: vec![
+                TxOut { script_pubkey: key.script_pubkey.clone(), value: Amount::from_sat(100000) }
+            ],
+            lock_time: LockTime::ZERO,
+            version: Version::TWO,
+        };
+        let mut sweep = Transaction {
+            input: vec![
+                TxIn {
+                    previous_output: OutPoint { txid: funding.compute_txid(), vout: 0 },
+                    sequence: Sequence::MAX,
+                    witness: Witness::new(),
+                    script_sig: ScriptBuf::new(),
+                }
+            ],
+            output: vec![
+                TxOut { script_pubkey: ScriptBuf::new_op_return([0u8; 4]), value: Amount::from_sat(99000) }
+            ],
+            lock_time: LockTime::ZERO,
+            version: Version::TWO,
+        };
+        assert_eq!(master.sign(&mut sweep, EcdsaSighashType::All, &(|_| Some(funding.output[0].clone())), &mut unlocker).unwrap(), 1);
+        // the script_sig only pushes the redeemScript 0 <20-byte-key-hash>
+        let redeem_script = ScriptBuf::new_p2wpkh(&key.public.wpubkey_hash().unwrap());
+        assert_eq!(sweep.input[0].script_sig.as_bytes()[1..], redeem_script.as_bytes()[..]);
+        assert_eq!(ScriptBuf::new_p2sh(&redeem_script.script_hash()), key.script_pubkey);
+        assert_eq!(sweep.input[0].witness.len(), 2);
+        sweep.verify(|point| if point.txid == funding.compute_txid() { Some(funding.output[0].clone()) } else { None }).unwrap();
+    }
+
+    #[test]
+    fn test_tr () {
+        // test vector of BIP86
+        let words = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+        let mnemonic = Mnemonic::from_str(words).unwrap();
+        let master = MasterAccount::from_mnemonic(&mnemonic, 0, Network::Bitcoin, PASSPHRASE, None).unwrap();
+        // the same master from the entropy of the mnemonic
+        assert_eq!(MasterAccount::from_entropy(&[0u8; 16], 0, Network::Bitcoin, PASSPHRASE, None).unwrap().master_public(), master.master_public());
+        let mut unlocker = Unlocker::new(master.encrypted(), PASSPHRASE, None, Network::Bitcoin, None).unwrap();
+        let account = Account::new(&mut unlocker, AccountAddressType::P2TR, 0, 0, 10).unwrap();
+        // m/86'/0'/0'/0/0
+        assert_eq!(account.get_key(0).unwrap().address.to_string(), "bc1p5cyxnuxmeuwuvkwfem96lqzszd02n6xdcjrs20cac6yqjjwudpxqkedrcr");
+        assert_eq!(account.get_key(1).unwrap().address.to_string(), "bc1p4qhjn9zdvkux4e44uhx8tc55attvtyu358kutcqkudyccelu0was9fqzwh");
+
+        let spent = TxOut { script_pubkey: account.get_key(0).unwrap().script_pubkey.clone(), value: Amount::from_sat(100000) };
+        let mut spending_transaction = Transaction {
+            input: vec![
+                TxIn {
+                    previous_output: OutPoint { txid: Txid::all_zeros(), vout: 0 },
+                    sequence: Sequence::MAX,
+                    witness: Witness::new(),
+                    script_sig: ScriptBuf::new(),
+                }
+            ],
+            output: vec![
+                TxOut {
+                    script_pubkey: account.get_key(1).unwrap().script_pubkey.clone(),
+                    value: Amount::from_sat(99000),
+                }
+            ],
+            lock_time: LockTime::ZERO,
+            version: Version::TWO,
+        };
+        assert_eq!(account.sign(&mut spending_transaction, EcdsaSighashType::All, |_| Some(spent.clone()), &mut unlocker).unwrap(), 1);
+        let witness = spending_transaction.input[0].witness.to_vec();
+        assert_eq!(witness.len(), 1);
+        assert_eq!(witness[0].len(), 64);
+
+        let sighash = SighashCache::new(&spending_transaction)
+            .taproot_key_spend_signature_hash(0, &Prevouts::All(std::slice::from_ref(&spent)), TapSighashType::Default).unwrap();
+        let output_key = secp256k1::XOnlyPublicKey::from_slice(&spent.script_pubkey.as_bytes()[2..]).unwrap();
+        secp256k1::Secp256k1::verification_only().verify_schnorr(
+            &secp256k1::schnorr::Signature::from_slice(&witness[0]).unwrap(),
+            &secp256k1::Message::from_digest(sighash.to_byte_array()), &output_key).unwrap();
+
+        // signing needs all spent outputs
+        let mut foreign = spending_transaction.input[0].clone();
+        foreign.previous_output.vout = 1;
+        spending_transaction.input.push(foreign);
+        assert!(account.sign(&mut spending_transaction, EcdsaSighashType::All,
+                             |p| if p.vout == 0 { Some(spent.clone()) } else { None }, &mut unlocker).is_err());
+    }
+
+    #[test]
+    fn test_bump_fee () {
+        let mut master = MasterAccount::new(MasterKeyEntropy::Low, Network::Bitcoin, PASSPHRASE, None).unwrap();
+        let mut unlocker = Unlocker::new(master.encrypted(), PASSPHRASE, None, Network::Bitcoin, None).unwrap();
+        let receive = master.new_account(&mut unlocker, AccountAddressType::P2WPKH, 0, 0, 10).unwrap().next_key().unwrap().script_pubkey.clone();
+        let returned = master.new_account(&mut unlocker, AccountAddressType::P2WPKH, 0, 1, 10).unwrap().next_key().unwrap().script_pubkey.clone();
+        // coins of the receive and of the change chain
+        let funding = Transaction {
+            input: vec![TxIn { previous_output: OutPoint { txid: Txid::all_zeros(), vout: 0 }, sequence: Sequence::MAX, witness: Witness::new(), script_sig: ScriptBuf::new() }],
+            output: vec![
+                TxOut { script_pubkey: receive, value: Amount::from_sat(60000) },
+                TxOut { script_pubkey: returned, value: Amount::from_sat(40000) }
+            ],
+            lock_time: LockTime::ZERO,
+            version: Version::ONE,
+        };
+        let block = Block {
+            header: Header {
+                version: bitcoin::block::Version::ONE,
+                prev_blockhash: BlockHash::all_zeros(),
+                merkle_root: TxMerkleNode::all_zeros(),
+                time: 0,
+                bits: CompactTarget::from_consensus(0),
+                nonce: 0
+            },
+            txdata: vec![funding.clone()]
+        };
+        let mut coins = Coins::new();
+        coins.process(&mut master, &block).unwrap();
+        let resolver = |point: &OutPoint| coins.owned().get(point).map(|c| c.output.clone());
+
+        let recipient = ScriptBuf::new_op_return([0u8; 8]);
+        let mut original = TxBuilder::new().add_recipient(recipient.clone(), 80000).fee_rate(2)
+            .finish_signed(&mut master, &coins, &mut unlocker).unwrap();
+        assert_eq!(original.input.len(), 2);
+        let change = original.output.iter().find(|o| o.script_pubkey != recipient).unwrap().clone();
+        assert_eq!(master.get_key_for_script(&change.script_pubkey).unwrap().0.sub_account_number(), INTERNAL);
+
+        let fee_rate = FeeRate::from_sat_per_vb(10).unwrap();
+        let replacement = master.bump_fee(&original, fee_rate, &resolver, &mut unlocker).unwrap();
+        assert_eq!(replacement.input.iter().map(|i| i.previous_output).collect::<Vec<_>>(), original.input.iter().map(|i| i.previous_output).collect::<Vec<_>>());
+        assert!(replacement.output.iter().any(|o| o.script_pubkey == recipient && o.value == Amount::from_sat(80000)));
+        let reduced = replacement.output.iter().find(|o| o.script_pubkey == change.script_pubkey).unwrap();
+        assert!(reduced.value < change.value);
+        let fee = 100000 - replacement.output.iter().map(|o| o.value.to_sat()).sum::<u64>();
+        assert!(fee >= coinselect::fee(fee_rate, replacement.weight().to_wu()));
+        replacement.verify(resolver).unwrap();
+
+        // change too small for the fee
+        let fee_rate = FeeRate::from_sat_per_vb(1000).unwrap();
+        assert!(master.bump_fee(&original, fee_rate, &resolver, &mut unlocker).is_err());
+        // not replaceable
+        for input in original.input.iter_mut() {
+            input.sequence = Sequence::MAX;
+        }
+        assert!(master.bump_fee(&original, FeeRate::from_sat_per_vb(10).unwrap(), &resolver, &mut unlocker).is_err());
+    }
+
+    #[test]
+    fn test_new_account () {
+        let words = "announce damage viable ticket engage curious yellow ten clock finish burden orient faculty rigid smile host offer affair suffer slogan mercy another switch park";
+        let mnemonic = Mnemonic::from_str(words).unwrap();
+        let mut master = MasterAccount::from_mnemonic(&mnemonic, 0, Network::Bitcoin, PASSPHRASE, None).unwrap();
+        let mut unlocker = Unlocker::new(master.encrypted(), PASSPHRASE, None, Network::Bitcoin, None).unwrap();
+        {
+            let account = master.new_account(&mut unlocker, AccountAddressType::P2WPKH, 0, 0, 10).unwrap();
+            assert!(account.address_type() == AccountAddressType::P2WPKH);
+            // this should be address of m/84'/0'/0'/0/0
+            assert_eq!(account.next_key().unwrap().address.to_string(), "bc1qlz2h9scgalmqj43d36f58dcxrrl7udu999gcp2");
+        }
+        assert_eq!(master.get((0, 0)).unwrap().used(), 1);
+        let (key, (fingerprint, path)) = master.next_key((0, 0)).unwrap();
+        assert_eq!(fingerprint, master.fingerprint());
+        assert_eq!(path.to_string(), "84'/0'/0'/0/1");
+        assert_eq!(master.key_origin((0, 0), key.index), Some((fingerprint, path)));
+        assert_eq!(master.origins().count(), master.get((0, 0)).unwrap().instantiated().len());
+        assert!(master.next_key((1, 0)).is_err());
+
+        let mut other = Unlocker::new(master.encrypted(), PASSPHRASE, Some("other"), Network::Bitcoin, None).unwrap();
+        assert!(master.new_account(&mut other, AccountAddressType::P2WPKH, 1, 0, 10).is_err());
+        let mut testnet = Unlocker::new(master.encrypted(), PASSPHRASE, None, Network::Testnet, None).unwrap();
+        assert!(master.new_account(&mut testnet, AccountAddressType::P2WPKH, 1, 0, 10).is_err());
+    }
+
+    #[test]
+    fn test_change_passphrase () {
+        let mut master = MasterAccount::new(MasterKeyEntropy::Low, Network::Bitcoin, PASSPHRASE, None).unwrap();
+        let mnemonic = master.mnemonic(PASSPHRASE).unwrap();
+        let encrypted = master.encrypted().clone();
+        assert!(master.change_passphrase("wrong", "new passphrase").is_err());
+        assert_eq!(master.encrypted(), &encrypted);
+        master.change_passphrase(PASSPHRASE, "new passphrase").unwrap();
+        assert!(master.mnemonic(PASSPHRASE).is_err());
+        assert_eq!(master.mnemonic("new passphrase").unwrap(), mnemonic);
+        Unlocker::new(master.encrypted(), "new passphrase", None, Network::Bitcoin, Some(master.master_public())).unwrap();
+        assert!(MasterAccount::watch_only(*master.master_public(), 0).change_passphrase("new passphrase", PASSPHRASE).is_err());
+    }
+
+    #[test]
+    fn test_keychains () {
+        let mut master = MasterAccount::new(MasterKeyEntropy::Low, Network::Bitcoin, PASSPHRASE, None).unwrap();
+        let mut unlocker = Unlocker::new(master.encrypted(), PASSPHRASE, None, Network::Bitcoin, None).unwrap();
+        assert!(master.next_receive_address(0).is_err());
+        assert_eq!(master.default_change(), None);
+        master.new_keychains(&mut unlocker, AccountAddressType::P2WPKH, 0, 10).unwrap();
+        master.new_keychains(&mut unlocker, AccountAddressType::P2TR, 1, 10).unwrap();
+        assert_eq!(master.default_change(), Some((0, INTERNAL)));
+        assert!(!master.get((0, EXTERNAL)).unwrap().is_internal());
+        assert!(master.get((0, INTERNAL)).unwrap().is_internal());
+
+        let first = master.next_receive_address(0).unwrap();
+        let second = master.next_receive_address(0).unwrap();
+        let change = master.next_change_address(0).unwrap();
+        assert_ne!(first, second);
+        assert_eq!(master.get((0, EXTERNAL)).unwrap().next(), 2);
+        assert_eq!(master.get((0, INTERNAL)).unwrap().next(), 1);
+        assert_eq!(master.get((0, INTERNAL)).unwrap().get_key(0).unwrap().address, change);
+        assert_eq!(master.key_origin((0, INTERNAL), 0).unwrap().1.to_string(), "84'/0'/0'/1/0");
+
+        // keys that received funds are not handed out
+        let account = master.get_mut((0, EXTERNAL)).unwrap();
+        assert_eq!(account.address_status(2), AddressStatus::Fresh);
+        account.record_receipt(2, Txid::all_zeros());
+        account.record_receipt(2, Txid::all_zeros());
+        assert_eq!(account.address_status(2), AddressStatus::Used);
+        account.record_receipt(2, Txid::from_byte_array([1u8; 32]));
+        assert_eq!(account.address_status(2), AddressStatus::Reused);
+        let third = account.get_key(2).unwrap().address.clone();
+        assert_ne!(master.next_receive_address(0).unwrap(), third);
+        assert_eq!(master.get((0, EXTERNAL)).unwrap().next(), 4);
+        assert_eq!(master.address_status(&third.script_pubkey()), Some(AddressStatus::Reused));
+    }
+
+    #[test]
+    fn test_open_account () {
+        let mut master = MasterAccount::new(MasterKeyEntropy::Low, Network::Bitcoin, PASSPHRASE, None).unwrap();
+        let mut unlocker = Unlocker::new(master.encrypted(), PASSPHRASE, None, Network::Bitcoin, None).unwrap();
+        assert_eq!(master.open_account(&mut unlocker, AccountAddressType::P2WPKH, 0, 0, 10).unwrap(), 0);
+        assert!(master.open_account(&mut unlocker, AccountAddressType::P2WPKH, 0, 0, 10).is_err());
+        // the same index of another purpose or coin type gets a free account number
+        assert_eq!(master.open_account(&mut unlocker, AccountAddressType::P2TR, 0, 0, 10).unwrap(), 1);
+        assert_eq!(master.open_account(&mut unlocker, AccountAddressType::P2WPKH, 1, 0, 10).unwrap(), 2);
+        assert_eq!(master.key_origin((1, INTERNAL), 0).unwrap().1.to_string(), "86'/0'/0'/1/0");
+        assert_eq!(master.get((2, EXTERNAL)).unwrap().address_type(), AccountAddressType::P2WPKH);
+        assert_eq!(master.account_ids(), vec!((84, 0, 0), (84, 1, 0), (86, 0, 0)));
+        assert_eq!(master.account_number((86, 0, 0)), Some(1));
+
+        master.archive_account((84, 1, 0), true).unwrap();
+        assert!(master.is_archived((84, 1, 0)));
+        assert_eq!(master.active_account_ids(), vec!((84, 0, 0), (86, 0, 0)));
+        assert_eq!(master.account_ids().len(), 3);
+        assert!(master.archive_account((44, 0, 0), true).is_err());
+        master.archive_account((84, 1, 0), false).unwrap();
+        assert_eq!(master.active_account_ids().len(), 3);
+    }
+
+    #[test]
+    fn test_custom_path () {
+        let mut master = MasterAccount::new(MasterKeyEntropy::Low, Network::Bitcoin, PASSPHRASE, None).unwrap();
+        let mut unlocker = Unlocker::new(master.encrypted(), PASSPHRASE, None, Network::Bitcoin, None).unwrap();
+        let expected = unlocker.derive(&DerivationPath::from_str("m/0/3").unwrap()).unwrap().to_priv();
+        let script_pubkey = {
+            let account = master.new_custom_account(&mut unlocker, AccountAddressType::P2PKH, "m/0/x", 7, 0, 5).unwrap();
+            assert_eq!(account.key_path(3).to_string(), "0/3");
+            assert_eq!(account.get_key(3).unwrap().public, SecpContext::new().public_from_private(&expected));
+            account.get_key(3).unwrap().script_pubkey.clone()
+        };
+        assert_eq!(master.key_origin((7, 0), 3).unwrap().1.to_string(), "0/3");
+        assert!(::descriptor::account_descriptor(master.fingerprint(), master.get((7, 0)).unwrap()).unwrap()
+            .starts_with(&format!("pkh([{}/0]", master.fingerprint())));
+        assert!(master.new_custom_account(&mut unlocker, AccountAddressType::P2WPKH, "m/0'/x'", 8, 0, 5).is_err());
+
+        let spent = TxOut { script_pubkey, value: Amount::from_sat(100000) };
+        let mut transaction = Transaction {
+            version: Version::TWO, lock_time: LockTime::ZERO,
+            input: vec!(TxIn { previous_output: OutPoint { txid: Txid::all_zeros(), vout: 0 }, script_sig: ScriptBuf::new(), sequence: Sequence::MAX, witness: Witness::new() }),
+            output: vec!(TxOut { value: Amount::from_sat(90000), script_pubkey: spent.script_pubkey.clone() })
+        };
+        assert_eq!(master.sign(&mut transaction, EcdsaSighashType::All, &(|_| Some(spent.clone())), &mut unlocker).unwrap(), 1);
+        assert!(TxSighashes::new(&transaction, None).verify(&SecpContext::new(), 0, &spent).unwrap());
+    }
+
+    #[test]
+    fn test_derive_scripts () {
+        let mut master = MasterAccount::new(MasterKeyEntropy::Low, Network::Testnet, PASSPHRASE, None).unwrap();
+        let mut unlocker = Unlocker::new(master.encrypted(), PASSPHRASE, None, Network::Testnet, None).unwrap();
+        for (n, address_type) in [AccountAddressType::P2PKH, AccountAddressType::P2SHWPKH, AccountAddressType::P2WPKH, AccountAddressType::P2TR].iter().enumerate() {
+            let account = master.new_account(&mut unlocker, *address_type, n as u32, 0, 20).unwrap();
+            let scripts = account.derive_scripts(5..15).unwrap();
+            assert_eq!(scripts.len(), 10);
+            for (kix, script) in scripts {
+                assert_eq!(account.get_key(kix).unwrap().script_pubkey, script);
+            }
+        }
+        let multisig = Account::new(&mut unlocker, AccountAddressType::P2WSH(4711), 9, 0, 0).unwrap();
+        assert!(multisig.derive_scripts(0..10).is_err());
+    }
+
+    #[test]
+    fn test_signet_regtest () {
+        let words = "announce damage viable ticket engage curious yellow ten clock finish burden orient faculty rigid smile host offer affair suffer slogan mercy another switch park";
+        let mnemonic = Mnemonic::from_str(words).unwrap();
+        for (network, prefix) in [(Network::Signet, "tb1q"), (Network::Regtest, "bcrt1q")].iter() {
+            let mut master = MasterAccount::from_mnemonic(&mnemonic, 0, *network, PASSPHRASE, None).unwrap();
+            let mut unlocker = Unlocker::new(master.encrypted(), PASSPHRASE, None, *network, Some(master.master_public())).unwrap();
+            {
+                let account = master.new_account(&mut unlocker, AccountAddressType::P2WPKH, 0, 0, 10).unwrap();
+                assert!(account.next_key().unwrap().address.to_string().starts_with(prefix));
+                assert_eq!(account.key_path(0).to_string(), "84'/1'/0'/0/0");
+            }
+            assert_eq!(master.network(), Some(*network));
+            // same keys on every test network, but accounts must not mix them
+            let mut testnet = Unlocker::new(master.encrypted(), PASSPHRASE, None, Network::Testnet, Some(master.master_public())).unwrap();
+            match master.new_account(&mut testnet, AccountAddressType::P2WPKH, 0, 1, 10) {
+                Err(WalletError::Network) => {},
+                _ => panic!("accounts of different networks should not mix")
+            }
+            assert!(Unlocker::new(master.encrypted(), PASSPHRASE, None, Network::Bitcoin, Some(master.master_public())).is_err());
+        }
+    }
+
+    #[test]
+    fn test_watch_only_account () {
+        let words = "announce damage viable ticket engage curious yellow ten clock finish burden orient faculty rigid smile host offer affair suffer slogan mercy another switch park";
+        let mnemonic = Mnemonic::from_str(words).unwrap();
+        let master = MasterAccount::from_mnemonic(&mnemonic, 0, Network::Bitcoin, PASSPHRASE, None).unwrap();
+        let mut unlocker = Unlocker::new(master.encrypted(), PASSPHRASE, None, Network::Bitcoin, None).unwrap();
+        let context = SecpContext::new();
+        let account_public = context.extended_public_from_private(&unlocker.account_key(AccountAddressType::P2WPKH, 0).unwrap());
+
+        let mut watch_only = MasterAccount::watch_only_account(
+            master.fingerprint(), account_public, AccountAddressType::P2WPKH, Network::Bitcoin, 10, 0).unwrap();
+        assert!(watch_only.is_watch_only());
+        assert_eq!(watch_only.fingerprint(), master.fingerprint());
+        {
+            let receive = watch_only.get_mut((0, 0)).unwrap();
+            // this should be address of m/84'/0'/0'/0/0
+            assert_eq!(receive.next_key().unwrap().address.to_string(), "bc1qlz2h9scgalmqj43d36f58dcxrrl7udu999gcp2");
+            assert_eq!(receive.key_path(0).to_string(), "84'/0'/0'/0/0");
+        }
+        assert_eq!(watch_only.get((0, 1)).unwrap().key_path(0).to_string(), "84'/0'/0'/1/0");
+
+        let mut transaction = Transaction { version: bitcoin::transaction::Version::TWO, lock_time: bitcoin::absolute::LockTime::ZERO, input: Vec::new(), output: Vec::new() };
+        match watch_only.sign(&mut transaction, EcdsaSighashType::All, &(|_| None), &mut unlocker) {
+            Err(WalletError::Unsupported(_)) => {},
+            _ => panic!("watch only master should not sign")
+        }
+        match watch_only.new_account(&mut unlocker, AccountAddressType::P2WPKH, 1, 0, 10) {
+            Err(WalletError::Unsupported(_)) => {},
+            _ => panic!("watch only master should not create accounts")
+        }
+        assert!(MasterAccount::watch_only_account(master.fingerprint(), account_public, AccountAddressType::P2WPKH, Network::Testnet, 10, 0).is_err());
+        assert!(MasterAccount::watch_only_account(master.fingerprint(), *master.master_public(), AccountAddressType::P2WPKH, Network::Bitcoin, 10, 0).is_err());
+    }
+
     #[test]
     fn bip32_tests () {
         let context = super::SecpContext::new();
@@ -829,12 +1893,12 @@ mod test {
         let json: Value = serde_json::from_str(&data).unwrap();
         let tests = json.as_array().unwrap();
         for test in tests {
-            let seed = Seed(decode(test["seed"].as_str().unwrap()).unwrap());
+            let seed = Seed(SecretBytes::new(decode(test["seed"].as_str().unwrap()).unwrap()));
             let master_private = context.master_private_key(Network::Bitcoin, &seed).unwrap();
             assert_eq!(test["private"].as_str().unwrap(), master_private.to_string());
             assert_eq!(test["public"].as_str().unwrap(), context.extended_public_from_private(&master_private).to_string());
             for d in test["derived"].as_array().unwrap() {
-                let mut key = master_private.clone();
+                let mut key = master_private;
                 for l in d ["locator"].as_array().unwrap() {
                     let sequence = l ["sequence"].as_u64().unwrap();
                     let private = l ["private"].as_bool().unwrap();
@@ -843,7 +1907,7 @@ mod test {
                     } else {
                         ChildNumber::Normal{index:sequence as u32}
                     };
-                    key = context.private_child(&key.clone(), child).unwrap();
+                    key = context.private_child(&key, child).unwrap();
                 }
                 assert_eq!(d ["private"].as_str().unwrap(), key.to_string());
                 assert_eq!(d ["public"].as_str().unwrap(), context.extended_public_from_private(&key).to_string());
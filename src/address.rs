@@ -0,0 +1,112 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Address validation
+//!
+//! Check addresses entered by users the way the wallet does before it sends to them
+//!
+
+use std::str::FromStr;
+
+use bitcoin::{Address, AddressType, Network, WitnessVersion};
+use bitcoin::address::NetworkUnchecked;
+
+use error::WalletError;
+
+/// kind of a valid address
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AddressKind {
+    P2pkh,
+    P2sh,
+    P2wpkh,
+    P2wsh,
+    P2tr,
+    /// pay to anchor
+    P2a,
+    /// a segwit program of a version or length without meaning yet, valid to send to but anyone can spend it today
+    FutureWitness(WitnessVersion)
+}
+
+/// an address valid for the network
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ValidAddress {
+    pub address: Address,
+    pub kind: AddressKind
+}
+
+impl ValidAddress {
+    /// true if the address uses a witness version or program length without consensus meaning
+    pub fn is_future(&self) -> bool {
+        matches!(self.kind, AddressKind::FutureWitness(_))
+    }
+}
+
+/// validate an address for the network: its base58 or bech32/bech32m checksum, witness version
+/// and program length are checked by parsing, then its network.
+/// Fails with InvalidAddress for malformed addresses and Network for addresses of an other network.
+pub fn validate(address: &str, network: Network) -> Result<ValidAddress, WalletError> {
+    let address = Address::<NetworkUnchecked>::from_str(address).map_err(|e| WalletError::InvalidAddress(e.to_string()))?
+        .require_network(network).map_err(|_| WalletError::Network)?;
+    let kind = match address.address_type() {
+        Some(AddressType::P2pkh) => AddressKind::P2pkh,
+        Some(AddressType::P2sh) => AddressKind::P2sh,
+        Some(AddressType::P2wpkh) => AddressKind::P2wpkh,
+        Some(AddressType::P2wsh) => AddressKind::P2wsh,
+        Some(AddressType::P2tr) => AddressKind::P2tr,
+        Some(AddressType::P2a) => AddressKind::P2a,
+        _ => match address.witness_program() {
+            Some(program) => AddressKind::FutureWitness(program.version()),
+            None => return Err(WalletError::InvalidAddress("unknown address type".to_string()))
+        }
+    };
+    Ok(ValidAddress { address, kind })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        let valid = |a: &str| validate(a, Network::Bitcoin).unwrap().kind;
+        assert_eq!(valid("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa"), AddressKind::P2pkh);
+        assert_eq!(valid("3J98t1WpEZ73CNmQviecrnyiWrnqRhWNLy"), AddressKind::P2sh);
+        assert_eq!(valid("BC1QW508D6QEJXTDG4Y5R3ZARVARY0C5XW7KV8F3T4"), AddressKind::P2wpkh);
+        assert_eq!(valid("bc1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3qccfmv3"), AddressKind::P2wsh);
+        assert_eq!(valid("bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqzk5jj0"), AddressKind::P2tr);
+        // BIP350 test vectors of future witness versions
+        assert_eq!(valid("bc1pw508d6qejxtdg4y5r3zarvary0c5xw7kw508d6qejxtdg4y5r3zarvary0c5xw7kt5nd6y"), AddressKind::FutureWitness(WitnessVersion::V1));
+        assert_eq!(valid("BC1SW50QGDZ25J"), AddressKind::FutureWitness(WitnessVersion::V16));
+        assert!(validate("bc1zw508d6qejxtdg4y5r3zarvaryvaxxpcs", Network::Bitcoin).unwrap().is_future());
+        assert!(!validate("bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqzk5jj0", Network::Bitcoin).unwrap().is_future());
+
+        let invalid = |a: &str| matches!(validate(a, Network::Bitcoin), Err(WalletError::InvalidAddress(_)));
+        // bech32 checksum of a version 1 program
+        assert!(invalid("bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqh2y7hd"));
+        // bech32m checksum of a version 0 program
+        assert!(invalid("bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kemeawh"));
+        // invalid program lengths
+        assert!(invalid("bc1pw5dgrnzv"));
+        assert!(invalid("BC1QR508D6QEJXTDG4Y5R3ZARVARYV98GJ9P"));
+        // base58 checksum
+        assert!(invalid("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNb"));
+        match validate("tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx", Network::Bitcoin) {
+            Err(WalletError::Network) => {},
+            other => panic!("expected a network error, got {:?}", other)
+        }
+        assert_eq!(validate("tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx", Network::Testnet).unwrap().kind, AddressKind::P2wpkh);
+    }
+}
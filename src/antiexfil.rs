@@ -0,0 +1,284 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Anti-exfil
+//!
+//! Sign-to-contract ECDSA signing, the host contributes randomness to every nonce
+//!
+//! The host commits to random data, the signer answers with the point of its nonce,
+//! then the host reveals the data and the signer signs with its nonce tweaked by the data.
+//! A compromised signer can not choose nonces that leak its key, the host verifies the tweak was applied.
+//!
+
+use bitcoin::{EcdsaSighashType, PrivateKey, Psbt, PublicKey, ecdsa};
+use bitcoin::sighash::SighashCache;
+use bitcoin_hashes::{sha256, Hash, HashEngine};
+use secp256k1::{Scalar, Secp256k1, SecretKey, constants::CURVE_ORDER, ecdsa::Signature};
+use secp256k1::rand::{thread_rng, RngCore};
+
+use account::{AccountAddressType, MasterAccount, Unlocker};
+use context::SecpContext;
+use error::WalletError;
+use signer::Signer;
+
+/// the host's commitment to its random data
+pub fn host_commitment(host_data: &[u8; 32]) -> [u8; 32] {
+    tagged_hash("s2c/ecdsa/data", &[host_data])
+}
+
+/// the signer's nonce point for a host commitment, it is opened once the host revealed its data
+pub fn signer_commit(key: &PrivateKey, digest: &[u8; 32], commitment: &[u8; 32]) -> Result<PublicKey, WalletError> {
+    let nonce = nonce(key, digest, commitment)?;
+    Ok(PublicKey::new(secp256k1::PublicKey::from_secret_key(&Secp256k1::signing_only(), &nonce)))
+}
+
+/// ECDSA signature with the committed nonce tweaked by the host's data
+pub fn sign(key: &PrivateKey, digest: &[u8; 32], host_data: &[u8; 32]) -> Result<Signature, WalletError> {
+    let secp = Secp256k1::signing_only();
+    let original = nonce(key, digest, &host_commitment(host_data))?;
+    let opening = secp256k1::PublicKey::from_secret_key(&secp, &original);
+    let nonce = original.add_tweak(&tweak(&opening, host_data)?)?;
+    let r = SecretKey::from_slice(&reduce(x_coordinate(&secp256k1::PublicKey::from_secret_key(&secp, &nonce))))?;
+    let mut sum = key.inner.mul_tweak(&Scalar::from(r))?;
+    let z = reduce(*digest);
+    if z != [0u8; 32] {
+        sum = sum.add_tweak(&Scalar::from_be_bytes(z).expect("reduced"))?;
+    }
+    let s = sum.mul_tweak(&Scalar::from(invert(&nonce)?))?;
+    let mut compact = [0u8; 64];
+    compact[..32].copy_from_slice(&r.secret_bytes());
+    compact[32..].copy_from_slice(&s.secret_bytes());
+    let mut signature = Signature::from_compact(&compact)?;
+    signature.normalize_s();
+    Ok(signature)
+}
+
+/// true if the signature is valid and its nonce is the opening tweaked with the host's data
+pub fn verify(context: &SecpContext, digest: &[u8; 32], signature: &Signature, public: &PublicKey, host_data: &[u8; 32], opening: &PublicKey) -> bool {
+    if !context.verify(digest, signature, public) {
+        return false;
+    }
+    let mut point = *opening;
+    match tweak(&opening.inner, host_data) {
+        Ok(tweak) if context.tweak_exp_add(&mut point, &tweak.to_be_bytes()).is_ok() =>
+            reduce(x_coordinate(&point.inner))[..] == signature.serialize_compact()[..32],
+        _ => false
+    }
+}
+
+/// sign the ECDSA inputs of the master with the anti-exfil protocol of the signer
+/// signatures are added only if all of them verify, taproot inputs are left for `Signer::sign_psbt`
+pub fn sign_psbt(signer: &mut dyn Signer, master: &MasterAccount, psbt: &mut Psbt) -> Result<usize, WalletError> {
+    if signer.fingerprint()? != master.fingerprint() {
+        return Err(WalletError::Unsupported("signer holds an other master key"));
+    }
+    let mut expected = Vec::new();
+    let mut hasher = SighashCache::new(&psbt.unsigned_tx);
+    for ix in 0..psbt.inputs.len() {
+        if let Ok(spent) = psbt.spend_utxo(ix) {
+            if let Some((account, key)) = master.get_key_for_script(&spent.script_pubkey) {
+                if account.address_type() != AccountAddressType::P2TR {
+                    let (message, _) = psbt.sighash_ecdsa(ix, &mut hasher)?;
+                    let mut host_data = [0u8; 32];
+                    thread_rng().fill_bytes(&mut host_data);
+                    expected.push((ix, key.public, *message.as_ref(), host_data));
+                }
+            }
+        }
+    }
+    let commitments = expected.iter().map(|(ix, _, _, data)| (*ix, host_commitment(data))).collect::<Vec<_>>();
+    let openings = signer.commit_nonces(master, psbt, &commitments)?;
+    let mut signed = psbt.clone();
+    signer.sign_psbt_anti_exfil(master, &mut signed, &expected.iter().map(|(ix, _, _, data)| (*ix, *data)).collect::<Vec<_>>())?;
+    let context = SecpContext::new();
+    for (ix, public, digest, host_data) in expected.iter() {
+        let opening = openings.iter().find(|(i, _)| i == ix).map(|(_, o)| o)
+            .ok_or_else(|| WalletError::Signer(format!("no nonce commitment for input {}", ix)))?;
+        let signature = signed.inputs[*ix].partial_sigs.get(public)
+            .ok_or_else(|| WalletError::Signer(format!("input {} is not signed", ix)))?;
+        if !verify(&context, digest, &signature.signature, public, host_data, opening) {
+            return Err(WalletError::Signer(format!("signature of input {} does not use the committed nonce", ix)));
+        }
+    }
+    for (ix, public, _, _) in expected.iter() {
+        let signature = signed.inputs[*ix].partial_sigs[public];
+        psbt.inputs[*ix].partial_sigs.insert(*public, signature);
+    }
+    Ok(expected.len())
+}
+
+/// nonce openings of an unlocker for ECDSA inputs it controls
+pub(crate) fn commit_nonces(unlocker: &mut Unlocker, master: &MasterAccount, psbt: &Psbt, commitments: &[(usize, [u8; 32])]) -> Result<Vec<(usize, PublicKey)>, WalletError> {
+    let mut openings = Vec::new();
+    for (ix, commitment) in commitments {
+        let (key, digest, _, _) = input_key(unlocker, master, psbt, *ix)?;
+        openings.push((*ix, signer_commit(&key, &digest, commitment)?));
+    }
+    Ok(openings)
+}
+
+/// sign ECDSA inputs of an unlocker with nonces tweaked by the host's data
+pub(crate) fn sign_inputs(unlocker: &mut Unlocker, master: &MasterAccount, psbt: &mut Psbt, host_data: &[(usize, [u8; 32])]) -> Result<usize, WalletError> {
+    for (ix, data) in host_data {
+        let (key, digest, sighash_type, public) = input_key(unlocker, master, psbt, *ix)?;
+        let signature = sign(&key, &digest, data)?;
+        psbt.inputs[*ix].partial_sigs.insert(public, ecdsa::Signature { signature, sighash_type });
+    }
+    Ok(host_data.len())
+}
+
+fn input_key(unlocker: &mut Unlocker, master: &MasterAccount, psbt: &Psbt, ix: usize) -> Result<(PrivateKey, [u8; 32], EcdsaSighashType, PublicKey), WalletError> {
+    let spent = psbt.spend_utxo(ix).map_err(|_| WalletError::Unsupported("unknown spent output"))?;
+    let (account, key) = master.get_key_for_script(&spent.script_pubkey).ok_or(WalletError::Unsupported("input of an other wallet"))?;
+    if account.address_type() == AccountAddressType::P2TR {
+        return Err(WalletError::Unsupported("anti-exfil signing of taproot inputs"));
+    }
+    let (message, sighash_type) = psbt.sighash_ecdsa(ix, &mut SighashCache::new(&psbt.unsigned_tx))?;
+    Ok((account.unlock(unlocker, key.index, key.tweak.clone())?, *message.as_ref(), sighash_type, key.public))
+}
+
+/// deterministic nonce of key, message and the host's commitment
+fn nonce(key: &PrivateKey, digest: &[u8; 32], commitment: &[u8; 32]) -> Result<SecretKey, WalletError> {
+    Ok(SecretKey::from_slice(&reduce(tagged_hash("s2c/ecdsa/nonce", &[&key.inner.secret_bytes(), digest, commitment])))?)
+}
+
+/// the tweak of a nonce point committing to the host's data
+fn tweak(opening: &secp256k1::PublicKey, host_data: &[u8; 32]) -> Result<Scalar, WalletError> {
+    Scalar::from_be_bytes(reduce(tagged_hash("s2c/ecdsa/point", &[&opening.serialize(), host_data])))
+        .map_err(|_| WalletError::Signer("invalid nonce tweak".to_string()))
+}
+
+fn x_coordinate(point: &secp256k1::PublicKey) -> [u8; 32] {
+    let mut x = [0u8; 32];
+    x.copy_from_slice(&point.serialize()[1..33]);
+    x
+}
+
+fn tagged_hash(tag: &str, data: &[&[u8]]) -> [u8; 32] {
+    let tag = sha256::Hash::hash(tag.as_bytes());
+    let mut engine = sha256::Hash::engine();
+    engine.input(tag.as_ref());
+    engine.input(tag.as_ref());
+    for d in data {
+        engine.input(d);
+    }
+    sha256::Hash::from_engine(engine).to_byte_array()
+}
+
+/// modulo the curve order, a 256 bit number is less than twice of it
+fn reduce(mut n: [u8; 32]) -> [u8; 32] {
+    if n >= CURVE_ORDER {
+        let mut borrow = 0i16;
+        for i in (0..32).rev() {
+            let d = n[i] as i16 - CURVE_ORDER[i] as i16 - borrow;
+            borrow = if d < 0 { 1 } else { 0 };
+            n[i] = (d + 256 * borrow) as u8;
+        }
+    }
+    n
+}
+
+/// multiplicative inverse modulo the curve order, k^(n-2)
+fn invert(k: &SecretKey) -> Result<SecretKey, WalletError> {
+    let mut exponent = CURVE_ORDER;
+    exponent[31] -= 2;
+    let mut result: Option<SecretKey> = None;
+    for byte in exponent.iter() {
+        for bit in (0..8).rev() {
+            if let Some(r) = result {
+                result = Some(r.mul_tweak(&Scalar::from(r))?);
+            }
+            if byte & (1 << bit) != 0 {
+                result = Some(match result {
+                    Some(r) => r.mul_tweak(&Scalar::from(*k))?,
+                    None => *k
+                });
+            }
+        }
+    }
+    Ok(result.expect("exponent is not zero"))
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{Amount, Network, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Txid, Witness};
+    use bitcoin::absolute::LockTime;
+    use bitcoin::bip32::Fingerprint;
+    use bitcoin::transaction::Version;
+
+    use account::MasterKeyEntropy;
+    use psbt;
+    use super::*;
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+
+    /// a signer that ignores the host's data
+    struct Leaky(Unlocker);
+
+    impl Signer for Leaky {
+        fn fingerprint(&mut self) -> Result<Fingerprint, WalletError> {
+            Ok(self.0.fingerprint())
+        }
+
+        fn sign_psbt(&mut self, master: &MasterAccount, psbt: &mut Psbt) -> Result<usize, WalletError> {
+            self.0.sign_psbt(master, psbt)
+        }
+
+        fn commit_nonces(&mut self, master: &MasterAccount, psbt: &Psbt, commitments: &[(usize, [u8; 32])]) -> Result<Vec<(usize, PublicKey)>, WalletError> {
+            self.0.commit_nonces(master, psbt, commitments)
+        }
+
+        fn sign_psbt_anti_exfil(&mut self, master: &MasterAccount, psbt: &mut Psbt, _host_data: &[(usize, [u8; 32])]) -> Result<usize, WalletError> {
+            psbt::sign(master, psbt, &mut self.0)
+        }
+    }
+
+    #[test]
+    fn test_anti_exfil() {
+        let key = PrivateKey::from_slice(&[7u8; 32], Network::Bitcoin).unwrap();
+        let context = SecpContext::new();
+        let public = context.public_from_private(&key);
+        let digest = [42u8; 32];
+        let host_data = [3u8; 32];
+        let opening = signer_commit(&key, &digest, &host_commitment(&host_data)).unwrap();
+        let signature = sign(&key, &digest, &host_data).unwrap();
+        assert!(verify(&context, &digest, &signature, &public, &host_data, &opening));
+        assert!(!verify(&context, &digest, &signature, &public, &[4u8; 32], &opening));
+        assert!(!verify(&context, &digest, &context.sign(&digest, &key).unwrap(), &public, &host_data, &opening));
+        assert_eq!(invert(&invert(&key.inner).unwrap()).unwrap(), key.inner);
+
+        let mut master = MasterAccount::new(MasterKeyEntropy::Low, Network::Bitcoin, PASSPHRASE, None).unwrap();
+        let mut unlocker = Unlocker::new(master.encrypted(), PASSPHRASE, None, Network::Bitcoin, None).unwrap();
+        let spent = TxOut { value: Amount::from_sat(10000), script_pubkey: master.new_account(&mut unlocker, AccountAddressType::P2WPKH, 0, 0, 10).unwrap().get_key(0).unwrap().script_pubkey.clone() };
+        let mut psbt = Psbt::from_unsigned_tx(Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec!(TxIn { previous_output: OutPoint { txid: Txid::all_zeros(), vout: 0 }, script_sig: ScriptBuf::new(), sequence: Sequence::MAX, witness: Witness::new() }),
+            output: vec!(TxOut { value: Amount::from_sat(9000), script_pubkey: ScriptBuf::new_op_return([0u8; 4]) })
+        }).unwrap();
+        psbt.inputs[0].witness_utxo = Some(spent);
+        let mut leaky = psbt.clone();
+        assert_eq!(sign_psbt(&mut unlocker, &master, &mut psbt).unwrap(), 1);
+        assert_eq!(psbt.inputs[0].partial_sigs.len(), 1);
+
+        let mut device = Leaky(unlocker);
+        match sign_psbt(&mut device, &master, &mut leaky) {
+            Err(WalletError::Signer(_)) => {},
+            _ => panic!("nonces not committed to must be detected")
+        }
+        assert!(leaky.inputs[0].partial_sigs.is_empty());
+    }
+}
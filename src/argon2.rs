@@ -0,0 +1,405 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Argon2id
+//!
+//! Memory hard key derivation of RFC 9106, version 0x13
+//!
+use error::WalletError;
+use secret::SecretBytes;
+use zeroize::Zeroize;
+
+const VERSION: u32 = 0x13;
+const ARGON2ID: u32 = 2;
+const BLOCK_WORDS: usize = 128;
+const SYNC_POINTS: usize = 4;
+
+type Block = [u64; BLOCK_WORDS];
+
+/// cost parameters of Argon2id
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct Params {
+    /// memory in KiB
+    pub memory: u32,
+    /// number of passes over the memory
+    pub iterations: u32,
+    /// number of lanes
+    pub parallelism: u32
+}
+
+impl Default for Params {
+    /// the minimum recommended by OWASP: 19 MiB, 2 passes, 1 lane
+    fn default() -> Params {
+        Params { memory: 19 * 1024, iterations: 2, parallelism: 1 }
+    }
+}
+
+/// derive length bytes from password and salt
+pub fn argon2id(password: &[u8], salt: &[u8], params: &Params, length: usize) -> Result<SecretBytes, WalletError> {
+    hash(password, salt, &[], &[], params, length)
+}
+
+fn hash(password: &[u8], salt: &[u8], secret: &[u8], associated: &[u8], params: &Params, length: usize) -> Result<SecretBytes, WalletError> {
+    let lanes = params.parallelism as usize;
+    if lanes == 0 || lanes > 0xff_ffff || params.iterations == 0 || length < 4 || salt.len() < 8 ||
+        (params.memory as usize) < 8 * lanes {
+        return Err(WalletError::Unsupported("invalid Argon2id parameters"));
+    }
+    let mut h0 = Blake2b::new(64);
+    for n in &[lanes as u32, length as u32, params.memory, params.iterations, VERSION, ARGON2ID] {
+        h0.input(&n.to_le_bytes());
+    }
+    for data in &[password, salt, secret, associated] {
+        h0.input(&(data.len() as u32).to_le_bytes());
+        h0.input(data);
+    }
+    let mut seed = [0u8; 72];
+    h0.result(&mut seed[..64]);
+
+    let segment_length = params.memory as usize / (lanes * SYNC_POINTS);
+    let lane_length = segment_length * SYNC_POINTS;
+    let mut memory = vec!([0u64; BLOCK_WORDS]; lane_length * lanes);
+    for lane in 0..lanes {
+        seed[68..].copy_from_slice(&(lane as u32).to_le_bytes());
+        for column in 0..2 {
+            seed[64..68].copy_from_slice(&(column as u32).to_le_bytes());
+            memory[lane * lane_length + column] = from_bytes(&variable_hash(&seed, 1024));
+        }
+    }
+
+    let context = Context { lanes, lane_length, segment_length, passes: params.iterations as usize, blocks: lane_length * lanes };
+    for pass in 0..context.passes {
+        for slice in 0..SYNC_POINTS {
+            for lane in 0..lanes {
+                fill_segment(&context, &mut memory, pass, lane, slice);
+            }
+        }
+    }
+
+    let mut last = memory[lane_length - 1];
+    for lane in 1..lanes {
+        xor(&mut last, &memory[lane * lane_length + lane_length - 1]);
+    }
+    memory.zeroize();
+    seed.zeroize();
+    let mut bytes = to_bytes(&last);
+    last.zeroize();
+    let result = variable_hash(&bytes, length);
+    bytes.zeroize();
+    Ok(SecretBytes::new(result))
+}
+
+struct Context {
+    lanes: usize,
+    lane_length: usize,
+    segment_length: usize,
+    passes: usize,
+    blocks: usize
+}
+
+fn fill_segment(context: &Context, memory: &mut [Block], pass: usize, lane: usize, slice: usize) {
+    let data_independent = pass == 0 && slice < SYNC_POINTS / 2;
+    let mut input = [0u64; BLOCK_WORDS];
+    let mut addresses = [0u64; BLOCK_WORDS];
+    if data_independent {
+        input[..6].copy_from_slice(&[pass as u64, lane as u64, slice as u64, context.blocks as u64, context.passes as u64, ARGON2ID as u64]);
+    }
+    let mut start = 0;
+    if pass == 0 && slice == 0 {
+        start = 2;
+        if data_independent {
+            next_addresses(&mut input, &mut addresses);
+        }
+    }
+    let lane_start = lane * context.lane_length;
+    for index in start..context.segment_length {
+        let current = lane_start + slice * context.segment_length + index;
+        // the previous block of the first block of a lane is its last
+        let previous = if current == lane_start { lane_start + context.lane_length - 1 } else { current - 1 };
+        let random = if data_independent {
+            if index % BLOCK_WORDS == 0 {
+                next_addresses(&mut input, &mut addresses);
+            }
+            addresses[index % BLOCK_WORDS]
+        } else {
+            memory[previous][0]
+        };
+        let reference_lane = if pass == 0 && slice == 0 { lane } else { ((random >> 32) as usize) % context.lanes };
+        let reference = reference_index(context, pass, slice, index, random & 0xffff_ffff, reference_lane == lane);
+        let (prev, refd) = (memory[previous], memory[reference_lane * context.lane_length + reference]);
+        let block = &mut memory[current];
+        compress(&prev, &refd, block, pass != 0);
+    }
+}
+
+fn reference_index(context: &Context, pass: usize, slice: usize, index: usize, random: u64, same_lane: bool) -> usize {
+    let area = if pass == 0 {
+        if slice == 0 {
+            index - 1
+        } else if same_lane {
+            slice * context.segment_length + index - 1
+        } else {
+            slice * context.segment_length - if index == 0 { 1 } else { 0 }
+        }
+    } else if same_lane {
+        context.lane_length - context.segment_length + index - 1
+    } else {
+        context.lane_length - context.segment_length - if index == 0 { 1 } else { 0 }
+    } as u64;
+    let relative = (random * random) >> 32;
+    let relative = area - 1 - ((area * relative) >> 32);
+    let start = if pass != 0 && slice != SYNC_POINTS - 1 { (slice + 1) * context.segment_length } else { 0 };
+    (start + relative as usize) % context.lane_length
+}
+
+fn next_addresses(input: &mut Block, addresses: &mut Block) {
+    input[6] += 1;
+    let zero = [0u64; BLOCK_WORDS];
+    compress(&zero, input, addresses, false);
+    let first = *addresses;
+    compress(&zero, &first, addresses, false);
+}
+
+// the compression function G, xored into next for passes after the first
+fn compress(previous: &Block, reference: &Block, next: &mut Block, with_xor: bool) {
+    let mut r = *reference;
+    xor(&mut r, previous);
+    let mut result = r;
+    if with_xor {
+        xor(&mut result, next);
+    }
+    for row in 0..8 {
+        let base = 16 * row;
+        let mut v = [0usize; 16];
+        for (i, v) in v.iter_mut().enumerate() {
+            *v = base + i;
+        }
+        permute(&mut r, &v);
+    }
+    for column in 0..8 {
+        let mut v = [0usize; 16];
+        for (i, v) in v.iter_mut().enumerate() {
+            *v = 2 * column + 16 * (i / 2) + i % 2;
+        }
+        permute(&mut r, &v);
+    }
+    xor(&mut result, &r);
+    *next = result;
+}
+
+// the BLAKE2b round with multiplication applied to 16 words of a block
+fn permute(block: &mut Block, v: &[usize; 16]) {
+    let mut gb = |a: usize, b: usize, c: usize, d: usize| {
+        let (a, b, c, d) = (v[a], v[b], v[c], v[d]);
+        block[a] = fbla(block[a], block[b]);
+        block[d] = (block[d] ^ block[a]).rotate_right(32);
+        block[c] = fbla(block[c], block[d]);
+        block[b] = (block[b] ^ block[c]).rotate_right(24);
+        block[a] = fbla(block[a], block[b]);
+        block[d] = (block[d] ^ block[a]).rotate_right(16);
+        block[c] = fbla(block[c], block[d]);
+        block[b] = (block[b] ^ block[c]).rotate_right(63);
+    };
+    gb(0, 4, 8, 12);
+    gb(1, 5, 9, 13);
+    gb(2, 6, 10, 14);
+    gb(3, 7, 11, 15);
+    gb(0, 5, 10, 15);
+    gb(1, 6, 11, 12);
+    gb(2, 7, 8, 13);
+    gb(3, 4, 9, 14);
+}
+
+fn fbla(x: u64, y: u64) -> u64 {
+    x.wrapping_add(y).wrapping_add(2u64.wrapping_mul((x & 0xffff_ffff) * (y & 0xffff_ffff)))
+}
+
+// H' of RFC 9106, a BLAKE2b based hash of variable length
+fn variable_hash(input: &[u8], length: usize) -> Vec<u8> {
+    let blake = |data: &[&[u8]], length: usize| {
+        let mut hasher = Blake2b::new(length);
+        for d in data {
+            hasher.input(d);
+        }
+        let mut out = vec!(0u8; length);
+        hasher.result(&mut out);
+        out
+    };
+    let prefix = (length as u32).to_le_bytes();
+    if length <= 64 {
+        return blake(&[&prefix, input], length);
+    }
+    let mut result = Vec::with_capacity(length);
+    let mut v = blake(&[&prefix, input], 64);
+    result.extend(&v[..32]);
+    while length - result.len() > 64 {
+        v = blake(&[&v], 64);
+        result.extend(&v[..32]);
+    }
+    let remaining = length - result.len();
+    result.extend(blake(&[&v], remaining));
+    result
+}
+
+const IV: [u64; 8] = [
+    0x6a09_e667_f3bc_c908, 0xbb67_ae85_84ca_a73b, 0x3c6e_f372_fe94_f82b, 0xa54f_f53a_5f1d_36f1,
+    0x510e_527f_ade6_82d1, 0x9b05_688c_2b3e_6c1f, 0x1f83_d9ab_fb41_bd6b, 0x5be0_cd19_137e_2179
+];
+
+const SIGMA: [[usize; 16]; 10] = [
+    [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15],
+    [14, 10, 4, 8, 9, 15, 13, 6, 1, 12, 0, 2, 11, 7, 5, 3],
+    [11, 8, 12, 0, 5, 2, 15, 13, 10, 14, 3, 6, 7, 1, 9, 4],
+    [7, 9, 3, 1, 13, 12, 11, 14, 2, 6, 5, 10, 4, 0, 15, 8],
+    [9, 0, 5, 7, 2, 4, 10, 15, 14, 1, 11, 12, 6, 8, 3, 13],
+    [2, 12, 6, 10, 0, 11, 8, 3, 4, 13, 7, 5, 15, 14, 1, 9],
+    [12, 5, 1, 15, 14, 13, 4, 10, 0, 7, 6, 3, 9, 2, 8, 11],
+    [13, 11, 7, 14, 12, 1, 3, 9, 5, 0, 15, 4, 8, 6, 2, 10],
+    [6, 15, 14, 9, 11, 3, 0, 8, 12, 2, 13, 7, 1, 4, 10, 5],
+    [10, 2, 8, 4, 7, 6, 1, 5, 15, 11, 9, 14, 3, 12, 13, 0]
+];
+
+// BLAKE2b of RFC 7693 without key, of 1 to 64 bytes output
+struct Blake2b {
+    h: [u64; 8],
+    counter: u128,
+    buffer: [u8; 128],
+    filled: usize,
+    length: usize
+}
+
+impl Blake2b {
+    fn new(length: usize) -> Blake2b {
+        let mut h = IV;
+        h[0] ^= 0x0101_0000 ^ length as u64;
+        Blake2b { h, counter: 0, buffer: [0u8; 128], filled: 0, length }
+    }
+
+    fn input(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            // the last block is compressed in result, so a full buffer waits for more data
+            if self.filled == 128 {
+                self.counter += 128;
+                self.compress(false);
+                self.filled = 0;
+            }
+            let n = (128 - self.filled).min(data.len());
+            self.buffer[self.filled..self.filled + n].copy_from_slice(&data[..n]);
+            self.filled += n;
+            data = &data[n..];
+        }
+    }
+
+    fn result(&mut self, out: &mut [u8]) {
+        self.counter += self.filled as u128;
+        self.buffer[self.filled..].iter_mut().for_each(|b| *b = 0);
+        self.compress(true);
+        for (i, byte) in out.iter_mut().take(self.length).enumerate() {
+            *byte = (self.h[i / 8] >> (8 * (i % 8))) as u8;
+        }
+    }
+
+    fn compress(&mut self, last: bool) {
+        let mut m = [0u64; 16];
+        for (word, chunk) in m.iter_mut().zip(self.buffer.chunks(8)) {
+            let mut le = [0u8; 8];
+            le.copy_from_slice(chunk);
+            *word = u64::from_le_bytes(le);
+        }
+        let mut v = [0u64; 16];
+        v[..8].copy_from_slice(&self.h);
+        v[8..].copy_from_slice(&IV);
+        v[12] ^= self.counter as u64;
+        v[13] ^= (self.counter >> 64) as u64;
+        if last {
+            v[14] = !v[14];
+        }
+        let mut g = |a: usize, b: usize, c: usize, d: usize, x: u64, y: u64| {
+            v[a] = v[a].wrapping_add(v[b]).wrapping_add(x);
+            v[d] = (v[d] ^ v[a]).rotate_right(32);
+            v[c] = v[c].wrapping_add(v[d]);
+            v[b] = (v[b] ^ v[c]).rotate_right(24);
+            v[a] = v[a].wrapping_add(v[b]).wrapping_add(y);
+            v[d] = (v[d] ^ v[a]).rotate_right(16);
+            v[c] = v[c].wrapping_add(v[d]);
+            v[b] = (v[b] ^ v[c]).rotate_right(63);
+        };
+        for s in SIGMA.iter().cycle().take(12) {
+            g(0, 4, 8, 12, m[s[0]], m[s[1]]);
+            g(1, 5, 9, 13, m[s[2]], m[s[3]]);
+            g(2, 6, 10, 14, m[s[4]], m[s[5]]);
+            g(3, 7, 11, 15, m[s[6]], m[s[7]]);
+            g(0, 5, 10, 15, m[s[8]], m[s[9]]);
+            g(1, 6, 11, 12, m[s[10]], m[s[11]]);
+            g(2, 7, 8, 13, m[s[12]], m[s[13]]);
+            g(3, 4, 9, 14, m[s[14]], m[s[15]]);
+        }
+        for (i, h) in self.h.iter_mut().enumerate() {
+            *h ^= v[i] ^ v[i + 8];
+        }
+        m.zeroize();
+        v.zeroize();
+    }
+}
+
+impl Drop for Blake2b {
+    fn drop(&mut self) {
+        self.h.zeroize();
+        self.buffer.zeroize();
+    }
+}
+
+fn xor(block: &mut Block, other: &Block) {
+    for (a, b) in block.iter_mut().zip(other.iter()) {
+        *a ^= b;
+    }
+}
+
+fn from_bytes(bytes: &[u8]) -> Block {
+    let mut block = [0u64; BLOCK_WORDS];
+    for (word, chunk) in block.iter_mut().zip(bytes.chunks(8)) {
+        let mut le = [0u8; 8];
+        le.copy_from_slice(chunk);
+        *word = u64::from_le_bytes(le);
+    }
+    block
+}
+
+fn to_bytes(block: &Block) -> Vec<u8> {
+    block.iter().flat_map(|w| w.to_le_bytes().to_vec()).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use hex::decode;
+    use super::*;
+
+    #[test]
+    fn test_argon2id() {
+        // RFC 9106 test vector
+        let params = Params { memory: 32, iterations: 3, parallelism: 4 };
+        assert_eq!(hash(&[1u8; 32], &[2u8; 16], &[3u8; 8], &[4u8; 12], &params, 32).unwrap().as_slice(),
+                   decode("0d640df58d78766c08c037a34a8b53c9d01ef0452d75b65eb52520e96b01e659").unwrap().as_slice());
+        assert!(argon2id(b"password", b"short", &params, 32).is_err());
+        // RFC 7693 BLAKE2b-512 of "abc"
+        let mut abc = [0u8; 64];
+        let mut hasher = Blake2b::new(64);
+        hasher.input(b"abc");
+        hasher.result(&mut abc);
+        assert_eq!(abc.to_vec(), decode("ba80a53f981c4d0d6a2797b69f12f6e94c212f14685ac4b74b12bb6fdbffa2d17d87c5392aab792dc252d5de4533cc9518d38aa8dbf1925ab92386edd4009923").unwrap());
+        assert!(argon2id(b"password", b"somesalt", &Params { memory: 16, iterations: 1, parallelism: 4 }, 32).is_err());
+    }
+}
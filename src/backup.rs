@@ -0,0 +1,179 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Wallet backup
+//!
+//! An encrypted file to move a wallet between devices, or its public data to keep in the cloud
+//!
+
+use std::io;
+
+use bitcoin::{Network, NetworkKind};
+use bitcoin::bip32::{DerivationPath, Fingerprint};
+
+use account::{MasterAccount, Unlocker};
+use descriptor;
+use error::WalletError;
+use keystore::{self, Cipher, Kdf};
+use labels::Labels;
+use mnemonic::Mnemonic;
+use secret::SecretBytes;
+use store::{AccountRecord, HistoryEntry, MasterRecord};
+
+/// version of the backup format, newer versions are rejected on import
+pub const BACKUP_VERSION: u32 = 1;
+
+/// content of a backup file before encryption
+/// the seed stays encrypted with the passphrase of the master inside
+#[derive(Serialize, Deserialize)]
+struct BackupRecord {
+    version: u32,
+    /// encrypted seed, accounts and their address cursors
+    master: MasterRecord,
+    /// BIP329 JSON lines
+    labels: String
+}
+
+/// the backup of a master account and its labels, encrypted as a keystore with passphrase.
+/// Accounts and labels are in a defined order, the same wallet backs up to the same plain text.
+pub fn export(master: &MasterAccount, labels: &Labels, passphrase: &str, kdf: Kdf) -> Result<Vec<u8>, WalletError> {
+    let record = BackupRecord { version: BACKUP_VERSION, master: MasterRecord::new(master), labels: labels.export() };
+    let json = SecretBytes::new(serde_json::to_vec(&record).map_err(io::Error::from)?);
+    keystore::encrypt(&json, passphrase, Cipher::Aes256Gcm, kdf)
+}
+
+/// restore master account and labels of a backup
+pub fn import(backup: &[u8], passphrase: &str) -> Result<(MasterAccount, Labels), WalletError> {
+    let decrypted = keystore::decrypt(backup, passphrase)?;
+    let record = serde_json::from_slice::<BackupRecord>(&decrypted).map_err(io::Error::from)?;
+    if record.version > BACKUP_VERSION {
+        return Err(WalletError::Unsupported("backup of a newer version"));
+    }
+    let mut labels = Labels::new();
+    labels.import(&record.labels)?;
+    Ok((record.master.restore(), labels))
+}
+
+/// public data of a wallet, safe to keep unencrypted, e.g. in cloud storage
+/// together with the mnemonic it restores the full wallet state
+#[derive(Serialize, Deserialize)]
+struct PublicBackupRecord {
+    version: u32,
+    fingerprint: Fingerprint,
+    birth: u64,
+    network: Network,
+    /// output descriptors of the accounts, for other wallets
+    descriptors: Vec<String>,
+    /// account keys and their address cursors
+    accounts: Vec<AccountRecord>,
+    /// BIP329 JSON lines
+    labels: String,
+    history: Vec<HistoryEntry>
+}
+
+/// JSON with the descriptors, accounts, labels and transaction history of a master, without the seed
+pub fn export_public(master: &MasterAccount, labels: &Labels, history: &[HistoryEntry]) -> Result<String, WalletError> {
+    let mut accounts = master.accounts().values().collect::<Vec<_>>();
+    accounts.sort_by_key(|a| (a.account_number(), a.sub_account_number()));
+    let network = master.network().unwrap_or(match master.master_public().network {
+        NetworkKind::Main => Network::Bitcoin,
+        NetworkKind::Test => Network::Testnet
+    });
+    let record = PublicBackupRecord {
+        version: BACKUP_VERSION, fingerprint: master.fingerprint(), birth: master.birth(), network,
+        descriptors: accounts.iter().filter_map(|a| descriptor::account_descriptor(master.fingerprint(), a)).collect(),
+        accounts: accounts.into_iter().map(AccountRecord::new).collect(),
+        labels: labels.export(),
+        history: history.to_vec()
+    };
+    Ok(serde_json::to_string_pretty(&record).map_err(io::Error::from)?)
+}
+
+/// restore a master from its mnemonic and a public backup, the seed is encrypted with passphrase.
+/// Fails with WalletError::Passphrase if the mnemonic and pd_passphrase are not those of the backed up wallet.
+pub fn import_public(backup: &str, mnemonic: &Mnemonic, passphrase: &str, pd_passphrase: Option<&str>) -> Result<(MasterAccount, Labels, Vec<HistoryEntry>), WalletError> {
+    let record = serde_json::from_str::<PublicBackupRecord>(backup).map_err(io::Error::from)?;
+    if record.version > BACKUP_VERSION {
+        return Err(WalletError::Unsupported("backup of a newer version"));
+    }
+    let mut master = MasterAccount::from_mnemonic(mnemonic, record.birth, record.network, passphrase, pd_passphrase)?;
+    if master.fingerprint() != record.fingerprint {
+        return Err(WalletError::Passphrase);
+    }
+    let unlocker = Unlocker::new(master.encrypted(), passphrase, pd_passphrase, record.network, Some(master.master_public()))?;
+    for account in record.accounts {
+        let account = account.restore();
+        // the account key must derive from the seed
+        let path = account.key_path(0);
+        let parent = DerivationPath::from(path[..path.len() - 1].to_vec());
+        let key = unlocker.derive(&parent)?;
+        if unlocker.context().extended_public_from_private(&key) != *account.master_public() {
+            return Err(WalletError::Passphrase);
+        }
+        master.add_account(account);
+    }
+    let mut labels = Labels::new();
+    labels.import(&record.labels)?;
+    Ok((master, labels, record.history))
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin_hashes::Hash;
+
+    use account::{AccountAddressType, MasterKeyEntropy, Unlocker};
+    use argon2;
+    use labels::LabelType;
+    use super::*;
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+
+    #[test]
+    fn test_backup() {
+        let kdf = Kdf::Argon2id(argon2::Params { memory: 64, iterations: 1, parallelism: 1 });
+        let mut master = MasterAccount::new(MasterKeyEntropy::Low, Network::Testnet, PASSPHRASE, None).unwrap();
+        let mut unlocker = Unlocker::new(master.encrypted(), PASSPHRASE, None, Network::Testnet, None).unwrap();
+        let account = master.new_account(&mut unlocker, AccountAddressType::P2WPKH, 0, 0, 10).unwrap();
+        account.next_key().unwrap();
+        let address = account.next_key().unwrap().address.clone();
+        master.new_custom_account(&mut unlocker, AccountAddressType::P2TR, "m/86'/1'/7'/0", 7, 0, 5).unwrap();
+        let mut labels = Labels::new();
+        labels.set(LabelType::Addr, address.to_string(), "donations");
+
+        let backup = master.export_backup(&labels, "backup passphrase", kdf).unwrap();
+        assert!(MasterAccount::import_backup(&backup, PASSPHRASE).is_err());
+        let (restored, restored_labels) = MasterAccount::import_backup(&backup, "backup passphrase").unwrap();
+        assert_eq!(restored_labels, labels);
+        assert_eq!(restored.master_public(), master.master_public());
+        assert_eq!(restored.encrypted(), master.encrypted());
+        assert_eq!(restored.get((0, 0)).unwrap().next(), 2);
+        assert_eq!(restored.get((7, 0)).unwrap().path(), master.get((7, 0)).unwrap().path());
+        // the seed decrypts with the passphrase of the master
+        assert!(Unlocker::new(restored.encrypted(), PASSPHRASE, None, Network::Testnet, None).is_ok());
+
+        let history = vec!((bitcoin::Txid::from_byte_array([1u8; 32]), 100));
+        let public = export_public(&master, &labels, &history).unwrap();
+        assert!(!public.contains("encrypted"));
+        assert!(public.contains("wpkh(["));
+        let mnemonic = master.mnemonic(PASSPHRASE).unwrap();
+        let (restored, restored_labels, restored_history) = MasterAccount::import_public_backup(&public, &mnemonic, "new passphrase", None).unwrap();
+        assert_eq!(restored.fingerprint(), master.fingerprint());
+        assert_eq!(restored.get((0, 0)).unwrap().next(), 2);
+        assert_eq!(restored.get((7, 0)).unwrap().get_key(4).unwrap().script_pubkey, master.get((7, 0)).unwrap().get_key(4).unwrap().script_pubkey);
+        assert_eq!((restored_labels, restored_history), (labels, history));
+        assert!(MasterAccount::import_public_backup(&public, &mnemonic, "new passphrase", Some("other")).is_err());
+    }
+}
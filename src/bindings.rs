@@ -0,0 +1,346 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Bindings
+//!
+//! The object model of uniffi/bitcoin_wallet.udl for generated Kotlin and Swift wrappers
+//!
+//! Objects are shared as `Arc` and lock their state, arguments and results are strings and numbers.
+//! An app crate generates the scaffolding with `uniffi::include_scaffolding!("bitcoin_wallet")`
+//! over these types.
+//!
+
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use bitcoin::{consensus, Address, Block};
+use bitcoin::address::NetworkUnchecked;
+
+use account::{self, AccountAddressType, MasterKeyEntropy, Unlocker};
+use builder;
+use coins::Coins;
+use descriptor;
+use error::WalletError;
+use mnemonic::Mnemonic;
+use psbt;
+
+/// errors of the bindings, details are in the message
+#[derive(Debug)]
+pub enum BindingError {
+    Passphrase,
+    Network,
+    InvalidArgument(String),
+    InsufficientFunds,
+    Wallet(String)
+}
+
+impl fmt::Display for BindingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BindingError::Passphrase => write!(f, "wrong passphrase"),
+            BindingError::Network => write!(f, "wrong network"),
+            BindingError::InvalidArgument(s) => write!(f, "invalid argument: {}", s),
+            BindingError::InsufficientFunds => write!(f, "insufficient funds"),
+            BindingError::Wallet(s) => write!(f, "{}", s)
+        }
+    }
+}
+
+impl std::error::Error for BindingError {}
+
+impl From<WalletError> for BindingError {
+    fn from(err: WalletError) -> BindingError {
+        match err {
+            WalletError::Passphrase => BindingError::Passphrase,
+            WalletError::Network => BindingError::Network,
+            WalletError::InsufficientFunds => BindingError::InsufficientFunds,
+            err => BindingError::Wallet(err.to_string())
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Network {
+    Bitcoin,
+    Testnet,
+    Signet,
+    Regtest
+}
+
+impl From<Network> for bitcoin::Network {
+    fn from(network: Network) -> bitcoin::Network {
+        match network {
+            Network::Bitcoin => bitcoin::Network::Bitcoin,
+            Network::Testnet => bitcoin::Network::Testnet,
+            Network::Signet => bitcoin::Network::Signet,
+            Network::Regtest => bitcoin::Network::Regtest
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AddressType {
+    P2pkh,
+    P2shwpkh,
+    P2wpkh,
+    P2tr
+}
+
+impl From<AddressType> for AccountAddressType {
+    fn from(address_type: AddressType) -> AccountAddressType {
+        match address_type {
+            AddressType::P2pkh => AccountAddressType::P2PKH,
+            AddressType::P2shwpkh => AccountAddressType::P2SHWPKH,
+            AddressType::P2wpkh => AccountAddressType::P2WPKH,
+            AddressType::P2tr => AccountAddressType::P2TR
+        }
+    }
+}
+
+struct Wallet {
+    master: account::MasterAccount,
+    coins: Coins
+}
+
+/// a master account together with its coins
+pub struct MasterAccount {
+    network: bitcoin::Network,
+    wallet: Mutex<Wallet>
+}
+
+impl MasterAccount {
+    /// a new random master account
+    pub fn create(network: Network, passphrase: String) -> Result<Arc<MasterAccount>, BindingError> {
+        let master = account::MasterAccount::new(MasterKeyEntropy::Recommended, network.into(), &passphrase, None)?;
+        Ok(MasterAccount::wrap(master, network))
+    }
+
+    /// restore from a BIP39 mnemonic, birth is the unix time of first use
+    pub fn restore(mnemonic: String, birth: u64, network: Network, passphrase: String) -> Result<Arc<MasterAccount>, BindingError> {
+        let mnemonic = Mnemonic::from_str(&mnemonic)?;
+        let master = account::MasterAccount::from_mnemonic(&mnemonic, birth, network.into(), &passphrase, None)?;
+        Ok(MasterAccount::wrap(master, network))
+    }
+
+    fn wrap(master: account::MasterAccount, network: Network) -> Arc<MasterAccount> {
+        Arc::new(MasterAccount { network: network.into(), wallet: Mutex::new(Wallet { master, coins: Coins::new() }) })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Wallet> {
+        self.wallet.lock().expect("wallet lock poisoned")
+    }
+
+    fn unlocker(&self, wallet: &Wallet, passphrase: &str) -> Result<Unlocker, BindingError> {
+        Ok(Unlocker::new(wallet.master.encrypted(), passphrase, None, self.network, Some(wallet.master.master_public()))?)
+    }
+
+    pub fn fingerprint(&self) -> String {
+        self.lock().master.fingerprint().to_string()
+    }
+
+    pub fn birth(&self) -> u64 {
+        self.lock().master.birth()
+    }
+
+    pub fn mnemonic(&self, passphrase: String) -> Result<String, BindingError> {
+        Ok(self.lock().master.mnemonic(&passphrase)?.to_string())
+    }
+
+    pub fn new_account(self: Arc<Self>, passphrase: String, address_type: AddressType, account_number: u32, sub_account_number: u32,
+                       look_ahead: u32) -> Result<Arc<Account>, BindingError> {
+        {
+            let mut wallet = self.lock();
+            let mut unlocker = self.unlocker(&wallet, &passphrase)?;
+            wallet.master.new_account(&mut unlocker, address_type.into(), account_number, sub_account_number, look_ahead)?;
+        }
+        Ok(Arc::new(Account { master: self, account: (account_number, sub_account_number) }))
+    }
+
+    pub fn get_account(self: Arc<Self>, account_number: u32, sub_account_number: u32) -> Option<Arc<Account>> {
+        let account = (account_number, sub_account_number);
+        self.lock().master.get(account)?;
+        Some(Arc::new(Account { master: self, account }))
+    }
+
+    /// add coins of a serialized block
+    pub fn process_block(&self, block: Vec<u8>) -> Result<(), BindingError> {
+        let block: Block = consensus::deserialize(&block).map_err(|e| BindingError::InvalidArgument(e.to_string()))?;
+        let mut wallet = self.lock();
+        let Wallet { ref mut master, ref mut coins } = *wallet;
+        coins.process(master, &block)?;
+        Ok(())
+    }
+
+    /// satoshis of owned coins
+    pub fn balance(&self) -> u64 {
+        self.lock().coins.owned().values().map(|c| c.output.value.to_sat()).sum()
+    }
+
+    /// sign a base64 PSBT, returns it with the signatures added
+    pub fn sign_psbt(&self, passphrase: String, psbt: String) -> Result<String, BindingError> {
+        let mut psbt = psbt::from_base64(&psbt)?;
+        let wallet = self.lock();
+        let mut unlocker = self.unlocker(&wallet, &passphrase)?;
+        psbt::update(&wallet.master, &wallet.coins, &mut psbt);
+        psbt::sign(&wallet.master, &mut psbt, &mut unlocker)?;
+        Ok(psbt::to_base64(&psbt))
+    }
+}
+
+/// an account of a master account
+pub struct Account {
+    master: Arc<MasterAccount>,
+    account: (u32, u32)
+}
+
+impl Account {
+    fn with<T, F: FnOnce(&mut account::Account) -> T>(&self, f: F) -> T {
+        let mut wallet = self.master.lock();
+        f(wallet.master.get_mut(self.account).expect("accounts are not removed"))
+    }
+
+    pub fn address_type(&self) -> AddressType {
+        match self.with(|a| a.address_type()) {
+            AccountAddressType::P2PKH => AddressType::P2pkh,
+            AccountAddressType::P2SHWPKH => AddressType::P2shwpkh,
+            AccountAddressType::P2TR => AddressType::P2tr,
+            _ => AddressType::P2wpkh
+        }
+    }
+
+    pub fn account_number(&self) -> u32 {
+        self.account.0
+    }
+
+    pub fn sub_account_number(&self) -> u32 {
+        self.account.1
+    }
+
+    pub fn next_address(&self) -> Result<String, BindingError> {
+        Ok(self.with(|a| a.next_key().map(|k| k.address.to_string()))?)
+    }
+
+    pub fn address(&self, kix: u32) -> Option<String> {
+        self.with(|a| a.get_key(kix).map(|k| k.address.to_string()))
+    }
+
+    /// output descriptor of the account's keys
+    pub fn descriptor(&self) -> Option<String> {
+        let wallet = self.master.lock();
+        descriptor::account_descriptor(wallet.master.fingerprint(), wallet.master.get(self.account)?)
+    }
+}
+
+struct Payment {
+    /// addresses, amounts and whether they pay the fee
+    recipients: Vec<(Address<NetworkUnchecked>, u64, bool)>,
+    fee_rate: u64,
+    change: Option<(u32, u32)>
+}
+
+/// collects a payment, then funds it from coins of a master account
+pub struct TxBuilder {
+    payment: Mutex<Payment>
+}
+
+impl Default for TxBuilder {
+    fn default() -> TxBuilder {
+        TxBuilder::new()
+    }
+}
+
+impl TxBuilder {
+    pub fn new() -> TxBuilder {
+        TxBuilder { payment: Mutex::new(Payment { recipients: Vec::new(), fee_rate: 1, change: None }) }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Payment> {
+        self.payment.lock().expect("builder lock poisoned")
+    }
+
+    pub fn add_recipient(&self, address: String, amount: u64) -> Result<(), BindingError> {
+        let address = Address::<NetworkUnchecked>::from_str(&address).map_err(|e| BindingError::InvalidArgument(e.to_string()))?;
+        self.lock().recipients.push((address, amount, false));
+        Ok(())
+    }
+
+    /// the last added recipient pays the fee from its amount
+    pub fn subtract_fee_from_amount(&self) {
+        if let Some(last) = self.lock().recipients.last_mut() {
+            last.2 = true;
+        }
+    }
+
+    pub fn fee_rate(&self, sat_per_vb: u64) {
+        self.lock().fee_rate = sat_per_vb;
+    }
+
+    pub fn change_account(&self, account_number: u32, sub_account_number: u32) {
+        self.lock().change = Some((account_number, sub_account_number));
+    }
+
+    /// an unsigned base64 PSBT paying the recipients
+    pub fn finish(&self, master: Arc<MasterAccount>) -> Result<String, BindingError> {
+        let payment = self.lock();
+        let mut builder = builder::TxBuilder::new().fee_rate(payment.fee_rate);
+        for (address, amount, subtract_fee) in payment.recipients.iter() {
+            let address = address.clone().require_network(master.network).map_err(|_| BindingError::Network)?;
+            builder = builder.add_recipient(address.script_pubkey(), *amount);
+            if *subtract_fee {
+                builder = builder.subtract_fee_from_amount();
+            }
+        }
+        if let Some((account, sub_account)) = payment.change {
+            builder = builder.change_account(account, sub_account);
+        }
+        let mut wallet = master.lock();
+        let Wallet { ref mut master, ref coins } = *wallet;
+        Ok(psbt::to_base64(&builder.finish(master, coins)?))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+
+    #[test]
+    fn test_bindings() {
+        let words = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+        let master = MasterAccount::restore(words.to_string(), 0, Network::Bitcoin, PASSPHRASE.to_string()).unwrap();
+        assert!(master.clone().get_account(0, 0).is_none());
+        let account = master.clone().new_account(PASSPHRASE.to_string(), AddressType::P2wpkh, 0, 0, 10).unwrap();
+        // m/84'/0'/0'/0/0 of BIP84
+        assert_eq!(account.next_address().unwrap(), "bc1qcr8te4kr609gcawutmrza0j4xv80jy8z306fyu");
+        assert_eq!(account.address_type(), AddressType::P2wpkh);
+        assert!(account.descriptor().unwrap().starts_with(&format!("wpkh([{}/84'/0'/0'/0]", master.fingerprint())));
+        assert_eq!(master.mnemonic(PASSPHRASE.to_string()).unwrap(), words);
+        match master.clone().new_account("wrong".to_string(), AddressType::P2tr, 1, 0, 10) {
+            Err(BindingError::Passphrase) => {}
+            _ => panic!("expected wrong passphrase")
+        }
+
+        let builder = TxBuilder::new();
+        builder.add_recipient("bc1qlz2h9scgalmqj43d36f58dcxrrl7udu999gcp2".to_string(), 10000).unwrap();
+        builder.change_account(0, 0);
+        assert!(builder.add_recipient("not an address".to_string(), 1).is_err());
+        // no coins yet
+        assert_eq!(master.balance(), 0);
+        assert!(builder.finish(master).is_err());
+    }
+}
@@ -0,0 +1,168 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # BIP38
+//!
+//! Passphrase protected private keys, e.g. of paper wallets, not built for wasm32 as its ciphers are of rust-crypto
+//!
+
+use bitcoin::{base58, Address, Network, NetworkKind, PrivateKey};
+use bitcoin_hashes::{sha256d, Hash};
+use crypto::aessafe::{AesSafe256Decryptor, AesSafe256Encryptor};
+use crypto::scrypt::{scrypt, ScryptParams};
+use crypto::symmetriccipher::{BlockDecryptor, BlockEncryptor};
+use secp256k1::{PublicKey, Scalar, Secp256k1, SecretKey};
+use zeroize::Zeroize;
+
+use error::WalletError;
+
+const NON_EC_MULTIPLIED: [u8; 2] = [0x01, 0x42];
+const EC_MULTIPLIED: [u8; 2] = [0x01, 0x43];
+const COMPRESSED: u8 = 0x20;
+const LOT_AND_SEQUENCE: u8 = 0x04;
+
+/// first four bytes of the double SHA256 of the P2PKH address of the key
+fn address_hash(public: &PublicKey, compressed: bool, network: NetworkKind) -> [u8; 4] {
+    let public = bitcoin::PublicKey { inner: *public, compressed };
+    let address = Address::p2pkh(public, network).to_string();
+    let mut hash = [0u8; 4];
+    hash.copy_from_slice(&sha256d::Hash::hash(address.as_bytes())[0..4]);
+    hash
+}
+
+fn derive(password: &[u8], salt: &[u8], log_n: u8, r: u32, p: u32, length: usize) -> Vec<u8> {
+    let mut output = vec!(0u8; length);
+    scrypt(password, salt, &ScryptParams::new(log_n, r, p), &mut output);
+    output
+}
+
+/// XOR 16 bytes with mask then encrypt with AES-256
+fn encrypt_half(half: &[u8], mask: &[u8], key: &[u8], output: &mut [u8]) {
+    let mut block = [0u8; 16];
+    for i in 0..16 {
+        block[i] = half[i] ^ mask[i];
+    }
+    AesSafe256Encryptor::new(key).encrypt_block(&block, output);
+    block.zeroize();
+}
+
+fn decrypt_half(encrypted: &[u8], mask: &[u8], key: &[u8], output: &mut [u8]) {
+    AesSafe256Decryptor::new(key).decrypt_block(encrypted, output);
+    for i in 0..16 {
+        output[i] ^= mask[i];
+    }
+}
+
+/// encrypt a private key with a passphrase, the non EC multiplied form starting with 6P
+pub fn encrypt(key: &PrivateKey, passphrase: &str) -> Result<String, WalletError> {
+    let secp = Secp256k1::signing_only();
+    let salt = address_hash(&key.inner.public_key(&secp), key.compressed, key.network);
+    let mut derived = derive(passphrase.as_bytes(), &salt, 14, 8, 8, 64);
+    let mut secret = key.inner.secret_bytes();
+    let mut data = Vec::with_capacity(39);
+    data.extend_from_slice(&NON_EC_MULTIPLIED);
+    data.push(0xc0 | if key.compressed { COMPRESSED } else { 0 });
+    data.extend_from_slice(&salt);
+    let mut encrypted = [0u8; 32];
+    encrypt_half(&secret[0..16], &derived[0..16], &derived[32..64], &mut encrypted[0..16]);
+    encrypt_half(&secret[16..32], &derived[16..32], &derived[32..64], &mut encrypted[16..32]);
+    data.extend_from_slice(&encrypted);
+    secret.zeroize();
+    derived.zeroize();
+    Ok(base58::encode_check(&data))
+}
+
+/// decrypt a key encrypted with or without EC multiplication, addresses of network are checked
+pub fn decrypt(encrypted: &str, passphrase: &str, network: Network) -> Result<PrivateKey, WalletError> {
+    let data = base58::decode_check(encrypted).map_err(|_| WalletError::Unsupported("invalid BIP38 encoding"))?;
+    if data.len() != 39 {
+        return Err(WalletError::Unsupported("invalid BIP38 length"));
+    }
+    let flag = data[2];
+    let compressed = flag & COMPRESSED != 0;
+    let salt = &data[3..7];
+    let network = NetworkKind::from(network);
+    let secp = Secp256k1::new();
+
+    let secret = if data[0..2] == NON_EC_MULTIPLIED {
+        let mut derived = derive(passphrase.as_bytes(), salt, 14, 8, 8, 64);
+        let mut secret = [0u8; 32];
+        decrypt_half(&data[7..23], &derived[0..16], &derived[32..64], &mut secret[0..16]);
+        decrypt_half(&data[23..39], &derived[16..32], &derived[32..64], &mut secret[16..32]);
+        derived.zeroize();
+        let key = SecretKey::from_slice(&secret).map_err(|_| WalletError::Passphrase);
+        secret.zeroize();
+        key?
+    } else if data[0..2] == EC_MULTIPLIED {
+        let owner_entropy = &data[7..15];
+        let mut pass_factor = if flag & LOT_AND_SEQUENCE != 0 {
+            let mut pre_factor = derive(passphrase.as_bytes(), &owner_entropy[0..4], 14, 8, 8, 32);
+            pre_factor.extend_from_slice(owner_entropy);
+            let pass_factor = sha256d::Hash::hash(&pre_factor).to_byte_array().to_vec();
+            pre_factor.zeroize();
+            pass_factor
+        } else {
+            derive(passphrase.as_bytes(), owner_entropy, 14, 8, 8, 32)
+        };
+        let pass_factor_key = SecretKey::from_slice(&pass_factor).map_err(|_| WalletError::Passphrase)?;
+        pass_factor.zeroize();
+        let pass_point = PublicKey::from_secret_key(&secp, &pass_factor_key).serialize();
+        let mut derived = derive(&pass_point, &data[3..15], 10, 1, 1, 64);
+        // encrypted part 2 holds the second half of encrypted part 1 and the end of seed b
+        let mut part2 = [0u8; 16];
+        decrypt_half(&data[23..39], &derived[16..32], &derived[32..64], &mut part2);
+        let mut part1 = [0u8; 16];
+        part1[0..8].copy_from_slice(&data[15..23]);
+        part1[8..16].copy_from_slice(&part2[0..8]);
+        let mut seed_b = [0u8; 24];
+        decrypt_half(&part1, &derived[0..16], &derived[32..64], &mut seed_b[0..16]);
+        seed_b[16..24].copy_from_slice(&part2[8..16]);
+        derived.zeroize();
+        let factor_b = sha256d::Hash::hash(&seed_b).to_byte_array();
+        seed_b.zeroize();
+        let factor_b = Scalar::from_be_bytes(factor_b).map_err(|_| WalletError::Passphrase)?;
+        pass_factor_key.mul_tweak(&factor_b).map_err(|_| WalletError::Passphrase)?
+    } else {
+        return Err(WalletError::Unsupported("unknown BIP38 prefix"));
+    };
+
+    if address_hash(&secret.public_key(&secp), compressed, network) != salt {
+        return Err(WalletError::Passphrase);
+    }
+    Ok(PrivateKey { compressed, network, inner: secret })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_bip38() {
+        // test vectors of BIP38
+        let key = decrypt("6PRVWUbkzzsbcVac2qwfssoUJAN1Xhrg6bNk8J7Nzm5H7kxEbn2Nh2ZoGg", "TestingOneTwoThree", Network::Bitcoin).unwrap();
+        assert_eq!(key.to_wif(), "5KN7MzqK5wt2TP1fQCYyHBtDrXdJuXbUzm4A9rKAteGu3Qi5CVR");
+        assert_eq!(encrypt(&key, "TestingOneTwoThree").unwrap(), "6PRVWUbkzzsbcVac2qwfssoUJAN1Xhrg6bNk8J7Nzm5H7kxEbn2Nh2ZoGg");
+
+        let key = PrivateKey::from_wif("L44B5gGEpqEDRS9vVPz7QT35jcBG2r3CZwSwQ4fCewXAhAhqGVpP").unwrap();
+        let encrypted = encrypt(&key, "TestingOneTwoThree").unwrap();
+        assert_eq!(encrypted, "6PYNKZ1EAgYgmQfmNVamxyXVWHzK5s6DGhwP4J5o44cvXdoY7sRzhtpUeo");
+        assert_eq!(decrypt(&encrypted, "TestingOneTwoThree", Network::Bitcoin).unwrap(), key);
+        assert!(decrypt(&encrypted, "wrong", Network::Bitcoin).is_err());
+
+        let key = decrypt("6PgNBNNzDkKdhkT6uJntUXwwzQV8Rr2tZcbkDcuC9DZRsS6AtHts4Ypo1j", "MOLON LABE", Network::Bitcoin).unwrap();
+        assert_eq!(key.to_wif(), "5JLdxTtcTHcfYcmJsNVy1v2PMDx432JPoYcBTVVRHpPaxUrdtf8");
+    }
+}
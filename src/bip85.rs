@@ -0,0 +1,124 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # BIP85
+//!
+//! Deterministic entropy for other wallets derived from the master key
+//!
+use bitcoin::{Network, PrivateKey};
+use bitcoin::bip32::{ChainCode, ChildNumber, Fingerprint, Xpriv};
+use ring::hmac;
+use secp256k1::SecretKey;
+use zeroize::Zeroize;
+
+use context::SecpContext;
+use error::WalletError;
+use secret::SecretBytes;
+use mnemonic::{Language, Mnemonic};
+
+/// purpose of BIP85 derivations
+pub const PURPOSE: u32 = 83696968;
+
+const APP_BIP39: u32 = 39;
+const APP_WIF: u32 = 2;
+const APP_XPRV: u32 = 32;
+const APP_HEX: u32 = 128169;
+
+/// 64 bytes of entropy derived at m / 83696968' / path' where all elements of path are hardened
+pub fn entropy(master: &Xpriv, path: &[u32]) -> Result<SecretBytes, WalletError> {
+    let context = SecpContext::new();
+    let mut key = context.private_child(master, ChildNumber::Hardened { index: PURPOSE })?;
+    for index in path {
+        key = context.private_child(&key, ChildNumber::from_hardened_idx(*index)?)?;
+    }
+    let mut secret = key.private_key.secret_bytes();
+    let tag = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA512, b"bip-entropy-from-k"), &secret);
+    secret.zeroize();
+    key.private_key.non_secure_erase();
+    Ok(SecretBytes::from_slice(tag.as_ref()))
+}
+
+/// a BIP39 mnemonic of 12, 18 or 24 words
+pub fn mnemonic(master: &Xpriv, language: Language, words: u32, index: u32) -> Result<Mnemonic, WalletError> {
+    let length = match words {
+        12 => 16,
+        18 => 24,
+        24 => 32,
+        _ => return Err(WalletError::Unsupported("BIP85 mnemonics have 12, 18 or 24 words"))
+    };
+    let code = match language {
+        Language::English => 0,
+        Language::Japanese => 1,
+        Language::Korean => 2,
+        Language::Spanish => 3,
+        Language::ChineseSimplified => 4,
+        Language::ChineseTraditional => 5,
+        Language::French => 6,
+        Language::Italian => 7,
+        Language::Czech => 8,
+        Language::Portuguese => 9
+    };
+    Mnemonic::new_in(&entropy(master, &[APP_BIP39, code, words, index])?[..length], language)
+}
+
+/// a compressed private key as used by Bitcoin Core's HD seed
+pub fn wif(master: &Xpriv, network: Network, index: u32) -> Result<PrivateKey, WalletError> {
+    Ok(PrivateKey::new(SecretKey::from_slice(&entropy(master, &[APP_WIF, index])?[..32])?, network))
+}
+
+/// an extended master private key
+pub fn xprv(master: &Xpriv, index: u32) -> Result<Xpriv, WalletError> {
+    let entropy = entropy(master, &[APP_XPRV, index])?;
+    let mut chain_code = [0u8; 32];
+    chain_code.copy_from_slice(&entropy[..32]);
+    Ok(Xpriv {
+        network: master.network,
+        depth: 0,
+        parent_fingerprint: Fingerprint::default(),
+        child_number: ChildNumber::Normal { index: 0 },
+        private_key: SecretKey::from_slice(&entropy[32..])?,
+        chain_code: ChainCode::from(chain_code)
+    })
+}
+
+/// 16 to 64 bytes of entropy
+pub fn hex(master: &Xpriv, length: u32, index: u32) -> Result<SecretBytes, WalletError> {
+    if !(16..=64).contains(&length) {
+        return Err(WalletError::Unsupported("BIP85 hex entropy is 16 to 64 bytes"));
+    }
+    Ok(SecretBytes::from_slice(&entropy(master, &[APP_HEX, length, index])?[..length as usize]))
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+    use hex::decode;
+    use super::*;
+
+    #[test]
+    fn test_bip85() {
+        let master = Xpriv::from_str("xprv9s21ZrQH143K2LBWUUQRFXhucrQqBpKdRRxNVq2zBqsx8HVqFk2uYo8kmbaLLHRdqtQpUm98uKfu3vca1LqdGhUtyoFnCNkfmXRyPXLjbKb").unwrap();
+        assert_eq!(entropy(&master, &[0, 0]).unwrap().to_vec(),
+                   decode("efecfbccffea313214232d29e71563d941229afb4338c21f9517c41aaa0d16f00b83d2a09ef747e7a64e8e2bd5a14869e693da66ce94ac2da570ab7ee48618f7").unwrap());
+        assert_eq!(mnemonic(&master, Language::English, 12, 0).unwrap().to_string(), "girl mad pet galaxy egg matter matrix prison refuse sense ordinary nose");
+        assert_eq!(wif(&master, Network::Bitcoin, 0).unwrap().to_string(), "Kzyv4uF39d4Jrw2W7UryTHwZr1zQVNk4dAFyqE6BuMrMh1Za7uhp");
+        assert_eq!(hex(&master, 64, 0).unwrap().to_vec(),
+                   decode("492db4698cf3b73a5a24998aa3e9d7fa96275d85724a91e71aa2d645442f878555d078fd1f1f67e368976f04137b1f7a0d19232136ca50c44614af72b5582a5c").unwrap());
+        assert_ne!(xprv(&master, 0).unwrap(), xprv(&master, 1).unwrap());
+        assert!(mnemonic(&master, Language::English, 15, 0).is_err());
+        assert!(hex(&master, 8, 0).is_err());
+    }
+}
@@ -0,0 +1,502 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Bitcoin Core
+//!
+//! Chain data from a Bitcoin Core node over JSON-RPC, the wallet keeps the keys
+//!
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt::Display;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use bitcoin::{consensus, Amount, Block, BlockHash, Denomination, FeeRate, Network, OutPoint, Script, ScriptBuf, Transaction, TxOut, Txid};
+use bitcoin::base64::{Engine, engine::general_purpose::STANDARD};
+use bitcoin::hex::FromHex;
+use serde_json::{json, Value};
+
+use account::MasterAccount;
+use broadcast::Broadcaster;
+use chain::{ChainSource, Confirmation};
+use coins::Coins;
+use descriptor;
+use error::WalletError;
+use fees::{self, FeeEstimator};
+use proxy::{self, Socks5Proxy};
+use sweep::UtxoSource;
+
+/// an unspent output the node knows of
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Unspent {
+    pub point: OutPoint,
+    pub output: TxOut,
+    /// height of the block confirming it, none if unconfirmed
+    pub height: Option<u32>
+}
+
+/// default JSON-RPC port of bitcoind on a network
+pub fn rpc_port(network: Network) -> u16 {
+    match network {
+        Network::Bitcoin => 8332,
+        Network::Testnet => 18332,
+        Network::Testnet4 => 48332,
+        Network::Signet => 38332,
+        Network::Regtest => 18443
+    }
+}
+
+/// the .cookie file bitcoind writes into its data directory, a sub directory for test networks
+pub fn cookie_path<P: AsRef<Path>>(data_dir: P, network: Network) -> PathBuf {
+    let mut path = data_dir.as_ref().to_path_buf();
+    match network {
+        Network::Bitcoin => {},
+        Network::Testnet => path.push("testnet3"),
+        Network::Testnet4 => path.push("testnet4"),
+        Network::Signet => path.push("signet"),
+        Network::Regtest => path.push("regtest")
+    }
+    path.push(".cookie");
+    path
+}
+
+/// a client of the JSON-RPC interface of bitcoind
+pub struct Client {
+    address: String,
+    path: String,
+    authorization: String,
+    proxy: Option<Socks5Proxy>,
+    next_id: u64
+}
+
+impl Client {
+    /// a client of the node listening at address, authenticated with rpcuser and rpcpassword
+    pub fn new(address: &str, user: &str, password: &str) -> Client {
+        Client {
+            address: address.to_string(), path: "/".to_string(),
+            authorization: STANDARD.encode(format!("{}:{}", user, password)), proxy: None, next_id: 0
+        }
+    }
+
+    /// authenticate with the .cookie file of the node's data directory
+    pub fn from_cookie<P: AsRef<Path>>(address: &str, cookie: P) -> Result<Client, WalletError> {
+        let cookie = fs::read_to_string(cookie)?;
+        let (user, password) = cookie.trim().split_once(':').ok_or_else(|| WalletError::Rpc("invalid cookie file".to_string()))?;
+        Ok(Client::new(address, user, password))
+    }
+
+    /// a client of a node of the network on this machine, listening on the default port
+    pub fn local<P: AsRef<Path>>(data_dir: P, network: Network) -> Result<Client, WalletError> {
+        Client::from_cookie(&format!("127.0.0.1:{}", rpc_port(network)), cookie_path(data_dir, network))
+    }
+
+    /// direct wallet calls to a wallet of the node loaded with several
+    pub fn with_wallet(mut self, wallet: &str) -> Client {
+        self.path = format!("/wallet/{}", wallet);
+        self
+    }
+
+    /// connect to the node through a SOCKS5 proxy, e.g. to its onion service
+    pub fn with_proxy(mut self, proxy: Socks5Proxy) -> Client {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    /// call a method and wait for its result
+    pub fn request(&mut self, method: &str, params: Vec<Value>) -> Result<Value, WalletError> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let body = json!({"jsonrpc": "1.0", "id": id, "method": method, "params": params}).to_string();
+        let mut stream = proxy::connect(&self.address, self.proxy.as_ref())?;
+        write!(stream, "POST {} HTTP/1.1\r\nHost: {}\r\nAuthorization: Basic {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+               self.path, self.address, self.authorization, body.len(), body)?;
+        stream.flush()?;
+
+        let mut reader = BufReader::new(stream);
+        let mut status = String::new();
+        reader.read_line(&mut status)?;
+        let code = status.split_whitespace().nth(1).and_then(|c| c.parse::<u16>().ok()).ok_or_else(|| unexpected("status"))?;
+        if code == 401 {
+            return Err(WalletError::Rpc("unauthorized".to_string()));
+        }
+        let mut length = None;
+        loop {
+            let mut header = String::new();
+            if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                if name.trim().eq_ignore_ascii_case("content-length") {
+                    length = value.trim().parse::<usize>().ok();
+                }
+            }
+        }
+        let mut content = Vec::new();
+        match length {
+            Some(length) => {
+                content.resize(length, 0);
+                reader.read_exact(&mut content)?;
+            }
+            None => { reader.read_to_end(&mut content)?; }
+        }
+        // errors come with status 500 and a JSON body
+        let mut response: Value = serde_json::from_slice(&content).map_err(|_| WalletError::Rpc(format!("HTTP status {}", code)))?;
+        if !response["error"].is_null() {
+            return Err(WalletError::Rpc(response["error"]["message"].as_str().map(|s| s.to_string()).unwrap_or_else(|| response["error"].to_string())));
+        }
+        Ok(response["result"].take())
+    }
+
+    pub fn get_block_count(&mut self) -> Result<u32, WalletError> {
+        self.request("getblockcount", Vec::new())?.as_u64().map(|h| h as u32).ok_or_else(|| unexpected("block count"))
+    }
+
+    pub fn get_block_hash(&mut self, height: u32) -> Result<BlockHash, WalletError> {
+        BlockHash::from_str(self.request("getblockhash", vec!(json!(height)))?.as_str().ok_or_else(|| unexpected("block hash"))?).map_err(invalid)
+    }
+
+    /// a block, checked against its hash and merkle root
+    pub fn get_block(&mut self, block_hash: &BlockHash) -> Result<Block, WalletError> {
+        let raw = self.request("getblock", vec!(json!(block_hash.to_string()), json!(0)))?;
+        let block: Block = deserialize_hex(raw.as_str().ok_or_else(|| unexpected("block"))?)?;
+        if block.block_hash() != *block_hash || !block.check_merkle_root() {
+            return Err(WalletError::Rpc(format!("invalid block {}", block_hash)));
+        }
+        Ok(block)
+    }
+
+    /// fee rate for confirmation within blocks, None if the node has not seen enough transactions yet
+    pub fn estimate_smart_fee(&mut self, blocks: u32) -> Result<Option<FeeRate>, WalletError> {
+        let estimate = self.request("estimatesmartfee", vec!(json!(blocks)))?;
+        Ok(estimate["feerate"].as_f64().and_then(fees::from_btc_per_kvb))
+    }
+
+    /// send a transaction to the network
+    pub fn send_raw_transaction(&mut self, transaction: &Transaction) -> Result<Txid, WalletError> {
+        let txid = self.request("sendrawtransaction", vec!(json!(consensus::encode::serialize_hex(transaction))))?;
+        Txid::from_str(txid.as_str().ok_or_else(|| unexpected("txid"))?).map_err(invalid)
+    }
+
+    /// a transaction of the wallet of the node, or one in its mempool or indexed with -txindex
+    pub fn get_transaction(&mut self, txid: &Txid) -> Result<Transaction, WalletError> {
+        let raw = match self.request("gettransaction", vec!(json!(txid.to_string()), json!(true))) {
+            Ok(wallet) => wallet["hex"].clone(),
+            Err(WalletError::Rpc(_)) => self.request("getrawtransaction", vec!(json!(txid.to_string())))?,
+            Err(e) => return Err(e)
+        };
+        let transaction: Transaction = deserialize_hex(raw.as_str().ok_or_else(|| unexpected("transaction"))?)?;
+        if transaction.compute_txid() != *txid {
+            return Err(unexpected("transaction"));
+        }
+        Ok(transaction)
+    }
+
+    /// all transactions of the wallet of the node with height and hash of the block confirming them
+    pub fn list_transactions(&mut self) -> Result<Vec<(Transaction, Confirmation)>, WalletError> {
+        let since = self.request("listsinceblock", vec!(json!(""), json!(1), json!(true)))?;
+        let mut confirmed = HashMap::new();
+        for entry in since["transactions"].as_array().ok_or_else(|| unexpected("transactions"))? {
+            let txid = Txid::from_str(entry["txid"].as_str().ok_or_else(|| unexpected("txid"))?).map_err(invalid)?;
+            let block = match (entry["blockheight"].as_u64(), entry["blockhash"].as_str()) {
+                (Some(height), Some(block_hash)) => Some((height as u32, BlockHash::from_str(block_hash).map_err(invalid)?)),
+                _ => None
+            };
+            confirmed.insert(txid, block);
+        }
+        let mut transactions = confirmed.into_iter().map(|(txid, block)| Ok((self.get_transaction(&txid)?, block))).collect::<Result<Vec<_>, WalletError>>()?;
+        transactions.sort_by_key(|(transaction, block)| (block.is_none(), block.map(|(height, _)| height), transaction.compute_txid()));
+        Ok(transactions)
+    }
+
+    /// import the public descriptors of all accounts of the master into a watch only descriptor wallet of the node,
+    /// sub account 1 is imported as change. The node rescans from the birth of the master.
+    pub fn import_descriptors(&mut self, master: &MasterAccount) -> Result<(), WalletError> {
+        let requests = master.accounts().values().filter_map(|account|
+            descriptor::account_descriptor(master.fingerprint(), account).map(|desc| json!({
+                "desc": desc,
+                "timestamp": master.birth(),
+                "active": true,
+                "internal": account.sub_account_number() == 1,
+                "range": [0, account.instantiated().len().saturating_sub(1)]
+            }))).collect::<Vec<_>>();
+        let results = self.request("importdescriptors", vec!(Value::Array(requests)))?;
+        for result in results.as_array().ok_or_else(|| unexpected("import result"))? {
+            if result["success"].as_bool() != Some(true) {
+                return Err(WalletError::Rpc(format!("import failed: {}", result["error"])));
+            }
+        }
+        Ok(())
+    }
+
+    /// unspent outputs of the wallet of the node with at least min_conf confirmations
+    pub fn list_unspent(&mut self, min_conf: u32) -> Result<Vec<Unspent>, WalletError> {
+        let tip = self.get_block_count()?;
+        let unspent = self.request("listunspent", vec!(json!(min_conf)))?;
+        unspent.as_array().ok_or_else(|| unexpected("unspent"))?.iter().map(|u| {
+            let confirmations = u["confirmations"].as_u64().ok_or_else(|| unexpected("confirmations"))? as u32;
+            parse_unspent(u, if confirmations > 0 { Some(tip + 1 - confirmations) } else { None })
+        }).collect()
+    }
+
+    /// confirmed unspent outputs of the scripts of the master found in the UTXO set of the node,
+    /// needs no wallet at the node
+    pub fn scan_txout_set(&mut self, master: &MasterAccount) -> Result<Vec<Unspent>, WalletError> {
+        let scan = master.accounts().values().filter_map(|account|
+            descriptor::account_descriptor(master.fingerprint(), account).map(|desc| json!({
+                "desc": desc,
+                "range": account.instantiated().len().saturating_sub(1)
+            }))).collect::<Vec<_>>();
+        let result = self.request("scantxoutset", vec!(json!("start"), Value::Array(scan)))?;
+        if result["success"].as_bool() != Some(true) {
+            return Err(WalletError::Rpc("scan of UTXO set failed".to_string()));
+        }
+        result["unspents"].as_array().ok_or_else(|| unexpected("unspents"))?.iter()
+            .map(|u| parse_unspent(u, u["height"].as_u64().map(|h| h as u32))).collect()
+    }
+
+    /// find unspent coins of the master in the UTXO set, extending look ahead of accounts as
+    /// used keys are found, then process the blocks confirming them into coins.
+    /// returns the unspent outputs found
+    pub fn sync(&mut self, master: &mut MasterAccount, coins: &mut Coins) -> Result<Vec<Unspent>, WalletError> {
+        let unspent = loop {
+            let unspent = self.scan_txout_set(master)?;
+            let used = unspent.iter().map(|u| u.output.script_pubkey.clone()).collect::<HashSet<ScriptBuf>>();
+            let mut extended = false;
+            let found = master.get_scripts().filter(|(s, _)| used.contains(s)).map(|(_, d)| d).collect::<Vec<_>>();
+            for derivation in found {
+                if let Some(account) = master.get_mut((derivation.account, derivation.sub)) {
+                    extended |= !account.do_look_ahead(derivation.kix)?.is_empty();
+                }
+            }
+            if !extended {
+                break unspent;
+            }
+        };
+        let heights = unspent.iter()
+            .filter(|u| !coins.proofs().contains_key(&u.point.txid))
+            .filter_map(|u| u.height).collect::<BTreeSet<_>>();
+        for height in heights {
+            let block_hash = self.get_block_hash(height)?;
+            let block = self.get_block(&block_hash)?;
+            coins.process(master, &block)?;
+        }
+        Ok(unspent)
+    }
+}
+
+fn parse_unspent(u: &Value, height: Option<u32>) -> Result<Unspent, WalletError> {
+    let txid = Txid::from_str(u["txid"].as_str().ok_or_else(|| unexpected("txid"))?).map_err(invalid)?;
+    let vout = u["vout"].as_u64().ok_or_else(|| unexpected("vout"))? as u32;
+    let script_pubkey = ScriptBuf::from_hex(u["scriptPubKey"].as_str().ok_or_else(|| unexpected("scriptPubKey"))?).map_err(invalid)?;
+    let amount = u["amount"].as_f64().ok_or_else(|| unexpected("amount"))?;
+    let value = Amount::from_float_in(amount, Denomination::Bitcoin).map_err(invalid)?;
+    Ok(Unspent { point: OutPoint { txid, vout }, output: TxOut { value, script_pubkey }, height })
+}
+
+fn deserialize_hex<T: consensus::Decodable>(hex: &str) -> Result<T, WalletError> {
+    consensus::deserialize(&Vec::<u8>::from_hex(hex).map_err(invalid)?).map_err(invalid)
+}
+
+impl UtxoSource for Client {
+    /// confirmed unspent outputs in the UTXO set of the node
+    fn unspent(&mut self, script_pubkey: &Script) -> Result<Vec<(OutPoint, TxOut)>, WalletError> {
+        let scan = json!([{"desc": descriptor::with_checksum(&format!("raw({})", script_pubkey.to_hex_string()))?}]);
+        let result = self.request("scantxoutset", vec!(json!("start"), scan))?;
+        if result["success"].as_bool() != Some(true) {
+            return Err(WalletError::Rpc("scan of UTXO set failed".to_string()));
+        }
+        result["unspents"].as_array().ok_or_else(|| unexpected("unspents"))?.iter()
+            .map(|u| parse_unspent(u, None).map(|u| (u.point, u.output))).collect()
+    }
+}
+
+impl Broadcaster for Client {
+    fn broadcast(&mut self, transaction: &Transaction) -> Result<Txid, WalletError> {
+        self.send_raw_transaction(transaction)
+    }
+}
+
+impl FeeEstimator for Client {
+    fn estimate(&mut self, blocks: u32) -> Result<FeeRate, WalletError> {
+        self.estimate_smart_fee(blocks)?.ok_or_else(|| WalletError::Fees(format!("no estimate for {} blocks", blocks)))
+    }
+}
+
+impl ChainSource for Client {
+    /// transactions of the wallet of the node paying to or spending from the script,
+    /// the descriptors of the master need to be imported
+    fn get_history(&mut self, script_pubkey: &Script) -> Result<Vec<(Txid, Confirmation)>, WalletError> {
+        let mut paid = HashSet::new();
+        let mut history = Vec::new();
+        for (transaction, block) in self.list_transactions()? {
+            let txid = transaction.compute_txid();
+            let spends = transaction.input.iter().any(|i| paid.contains(&i.previous_output));
+            let mut pays = false;
+            for (vout, output) in transaction.output.iter().enumerate() {
+                if output.script_pubkey == *script_pubkey {
+                    paid.insert(OutPoint { txid, vout: vout as u32 });
+                    pays = true;
+                }
+            }
+            if spends || pays {
+                history.push((txid, block));
+            }
+        }
+        Ok(history)
+    }
+
+    fn get_utxos(&mut self, script_pubkey: &Script) -> Result<Vec<(OutPoint, TxOut)>, WalletError> {
+        self.unspent(script_pubkey)
+    }
+
+    fn get_tx(&mut self, txid: &Txid) -> Result<Transaction, WalletError> {
+        self.get_transaction(txid)
+    }
+
+    fn broadcast(&mut self, transaction: &Transaction) -> Result<Txid, WalletError> {
+        self.send_raw_transaction(transaction)
+    }
+
+    fn estimate_fee(&mut self, blocks: u32) -> Result<FeeRate, WalletError> {
+        self.estimate(blocks)
+    }
+
+    fn tip(&mut self) -> Result<(u32, BlockHash), WalletError> {
+        let height = self.get_block_count()?;
+        Ok((height, self.get_block_hash(height)?))
+    }
+}
+
+fn invalid<E: Display>(err: E) -> WalletError {
+    WalletError::Rpc(err.to_string())
+}
+
+fn unexpected(what: &str) -> WalletError {
+    WalletError::Rpc(format!("unexpected {} in response", what))
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::TcpListener;
+    use std::thread;
+
+    use bitcoin::{CompactTarget, Network, Sequence, TxIn, TxMerkleNode, Witness};
+    use bitcoin::absolute::LockTime;
+    use bitcoin::block::Header;
+    use bitcoin::transaction::Version;
+    use bitcoin_hashes::Hash;
+
+    use account::{AccountAddressType, MasterKeyEntropy, Unlocker};
+    use super::*;
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+
+    /// answers a number of requests from a chain of a single block
+    fn serve(listener: TcpListener, block: Block, requests: usize) -> thread::JoinHandle<Vec<String>> {
+        thread::spawn(move || {
+            let mut methods = Vec::new();
+            for stream in listener.incoming().take(requests) {
+                let stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut length = 0;
+                let mut authorized = false;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some(l) = line.strip_prefix("Content-Length: ") {
+                        length = l.trim().parse().unwrap();
+                    }
+                    authorized |= line.trim() == format!("Authorization: Basic {}", STANDARD.encode("user:password"));
+                }
+                let mut body = vec!(0u8; length);
+                reader.read_exact(&mut body).unwrap();
+                let request: Value = serde_json::from_slice(&body).unwrap();
+                let method = request["method"].as_str().unwrap().to_string();
+                let tx = &block.txdata[0];
+                let result = match method.as_str() {
+                    "getblockcount" => json!(1),
+                    "getblockhash" => json!(block.block_hash().to_string()),
+                    "getblock" => json!(consensus::encode::serialize_hex(&block)),
+                    "scantxoutset" => json!({"success": true, "unspents": [{
+                        "txid": tx.compute_txid().to_string(), "vout": 0, "scriptPubKey": tx.output[0].script_pubkey.to_hex_string(),
+                        "amount": 0.001, "height": 1}]}),
+                    "sendrawtransaction" => json!(tx.compute_txid().to_string()),
+                    _ => Value::Null
+                };
+                let response = if authorized {
+                    json!({"result": result, "error": null, "id": request["id"]}).to_string()
+                } else {
+                    json!({"result": null, "error": {"code": -1, "message": "not authorized"}, "id": request["id"]}).to_string()
+                };
+                let mut stream = stream;
+                write!(stream, "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}", response.len(), response).unwrap();
+                methods.push(method);
+            }
+            methods
+        })
+    }
+
+    #[test]
+    fn test_sync() {
+        let mut master = MasterAccount::new(MasterKeyEntropy::Low, Network::Bitcoin, PASSPHRASE, None).unwrap();
+        let mut unlocker = Unlocker::new(master.encrypted(), PASSPHRASE, None, Network::Bitcoin, None).unwrap();
+        // the last key of the look ahead window is used
+        let used = master.new_account(&mut unlocker, AccountAddressType::P2WPKH, 0, 0, 5).unwrap().get_key(4).unwrap().script_pubkey.clone();
+        let funding = Transaction {
+            version: Version::ONE,
+            lock_time: LockTime::ZERO,
+            input: vec!(TxIn { previous_output: OutPoint { txid: Txid::all_zeros(), vout: 0 }, script_sig: ScriptBuf::new(), sequence: Sequence::MAX, witness: Witness::new() }),
+            output: vec!(TxOut { value: Amount::from_sat(100000), script_pubkey: used })
+        };
+        let mut block = Block {
+            header: Header {
+                version: bitcoin::block::Version::ONE,
+                prev_blockhash: BlockHash::all_zeros(),
+                merkle_root: TxMerkleNode::all_zeros(),
+                time: 0,
+                bits: CompactTarget::from_consensus(0),
+                nonce: 0
+            },
+            txdata: vec!(funding.clone())
+        };
+        block.header.merkle_root = block.compute_merkle_root().unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = serve(listener, block, 6);
+        let mut client = Client::new(&address, "user", "password");
+        let mut coins = Coins::new();
+        let unspent = client.sync(&mut master, &mut coins).unwrap();
+        assert_eq!(unspent.len(), 1);
+        assert_eq!(unspent[0].output.value.to_sat(), 100000);
+        assert_eq!(unspent[0].height, Some(1));
+        // found use extended the look ahead
+        assert_eq!(master.get((0, 0)).unwrap().instantiated().len(), 9);
+        assert_eq!(client.send_raw_transaction(&funding).unwrap(), funding.compute_txid());
+        assert!(Client::new(&address, "user", "wrong").get_block_count().is_err());
+        assert_eq!(coins.owned().len(), 1);
+        assert_eq!(server.join().unwrap(), vec!("scantxoutset", "scantxoutset", "getblockhash", "getblock", "sendrawtransaction", "getblockcount"));
+
+        assert_eq!(rpc_port(Network::Regtest), 18443);
+        assert_eq!(cookie_path("/data", Network::Signet), PathBuf::from("/data/signet/.cookie"));
+        assert_eq!(cookie_path("/data", Network::Bitcoin), PathBuf::from("/data/.cookie"));
+    }
+}
@@ -0,0 +1,151 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Broadcast
+//!
+//! Submit transactions to the network through one or several endpoints
+//!
+
+use bitcoin::{Transaction, Txid};
+
+use error::WalletError;
+use events::{EventBus, WalletEvent};
+
+/// an endpoint that relays transactions to the network
+pub trait Broadcaster {
+    /// submit the transaction, returns its txid once the endpoint accepted it
+    fn broadcast(&mut self, transaction: &Transaction) -> Result<Txid, WalletError>;
+}
+
+/// submits a transaction to all of its endpoints
+pub struct FanOut {
+    endpoints: Vec<(String, Box<dyn Broadcaster>)>
+}
+
+impl FanOut {
+    /// a fan out without endpoints
+    pub fn new() -> FanOut {
+        FanOut { endpoints: Vec::new() }
+    }
+
+    /// add an endpoint, the name identifies it in the results
+    pub fn add(&mut self, name: &str, endpoint: Box<dyn Broadcaster>) {
+        self.endpoints.push((name.to_string(), endpoint));
+    }
+
+    /// names of the endpoints in the order they are tried
+    pub fn endpoints(&self) -> Vec<&str> {
+        self.endpoints.iter().map(|(name, _)| name.as_str()).collect()
+    }
+
+    /// submit to every endpoint, even if an earlier one failed, and report each result
+    pub fn broadcast_all(&mut self, transaction: &Transaction) -> Vec<(String, Result<Txid, WalletError>)> {
+        self.endpoints.iter_mut().map(|(name, endpoint)| (name.clone(), endpoint.broadcast(transaction))).collect()
+    }
+}
+
+/// publishes transactions its endpoint accepted to the event bus
+pub struct Announcing<B: Broadcaster> {
+    endpoint: B,
+    events: EventBus
+}
+
+impl<B: Broadcaster> Announcing<B> {
+    /// announce what the endpoint accepts on the bus
+    pub fn new(endpoint: B, events: EventBus) -> Announcing<B> {
+        Announcing { endpoint, events }
+    }
+}
+
+impl<B: Broadcaster> Broadcaster for Announcing<B> {
+    fn broadcast(&mut self, transaction: &Transaction) -> Result<Txid, WalletError> {
+        let txid = self.endpoint.broadcast(transaction)?;
+        self.events.publish(WalletEvent::TxBroadcast { txid });
+        Ok(txid)
+    }
+}
+
+impl Default for FanOut {
+    fn default() -> FanOut {
+        FanOut::new()
+    }
+}
+
+impl Broadcaster for FanOut {
+    /// succeeds if at least one endpoint accepted the transaction
+    fn broadcast(&mut self, transaction: &Transaction) -> Result<Txid, WalletError> {
+        let results = self.broadcast_all(transaction);
+        let mut failures = Vec::new();
+        let mut accepted = None;
+        for (name, result) in results {
+            match result {
+                Ok(txid) => accepted = Some(txid),
+                Err(err) => failures.push(format!("{}: {}", name, err))
+            }
+        }
+        match accepted {
+            Some(txid) => Ok(txid),
+            None if failures.is_empty() => Err(WalletError::Broadcast("no endpoints".to_string())),
+            None => Err(WalletError::Broadcast(failures.join(", ")))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::absolute::LockTime;
+    use bitcoin::transaction::Version;
+
+    use super::*;
+
+    struct Accepting;
+
+    impl Broadcaster for Accepting {
+        fn broadcast(&mut self, transaction: &Transaction) -> Result<Txid, WalletError> {
+            Ok(transaction.compute_txid())
+        }
+    }
+
+    struct Rejecting;
+
+    impl Broadcaster for Rejecting {
+        fn broadcast(&mut self, _: &Transaction) -> Result<Txid, WalletError> {
+            Err(WalletError::Rpc("bad-txns-inputs-missingorspent".to_string()))
+        }
+    }
+
+    #[test]
+    fn test_fan_out() {
+        let transaction = Transaction { version: Version::TWO, lock_time: LockTime::ZERO, input: vec!(), output: vec!() };
+        let mut fan_out = FanOut::new();
+        assert!(fan_out.broadcast(&transaction).is_err());
+
+        fan_out.add("node", Box::new(Rejecting));
+        match fan_out.broadcast(&transaction) {
+            Err(WalletError::Broadcast(s)) => assert!(s.starts_with("node: ")),
+            _ => panic!("no endpoint accepted")
+        }
+
+        fan_out.add("electrum", Box::new(Accepting));
+        assert_eq!(fan_out.endpoints(), vec!("node", "electrum"));
+        assert_eq!(fan_out.broadcast(&transaction).unwrap(), transaction.compute_txid());
+        let results = fan_out.broadcast_all(&transaction);
+        assert_eq!(results.len(), 2);
+        assert!(results[0].1.is_err());
+        assert_eq!(results[1].0, "electrum");
+        assert_eq!(*results[1].1.as_ref().unwrap(), transaction.compute_txid());
+    }
+}
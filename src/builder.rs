@@ -0,0 +1,1139 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Transaction builder
+//!
+//! Pay recipients with owned coins, fee and change are computed from the estimated weight
+//!
+
+use std::cmp::max;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+
+use bitcoin::{Address, Amount, EcdsaSighashType, FeeRate, OutPoint, Psbt, Script, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Witness};
+use bitcoin::absolute::LockTime;
+use bitcoin::script::PushBytesBuf;
+use bitcoin::key::{Keypair, TapTweak};
+use bitcoin::transaction::Version;
+use bitcoin_hashes::Hash;
+use secp256k1::Secp256k1;
+use secp256k1::rand::{thread_rng, Rng, seq::SliceRandom};
+
+use account::{AccountAddressType, AddressStatus, MasterAccount, Unlocker, INTERNAL};
+use coins::Coins;
+use coinselect::{self, BranchAndBound, Candidate, CoinSelector, Selection, Target};
+use dust;
+use error::WalletError;
+use fees::FeeEstimator;
+use psbt;
+use silentpayment::{self, InputKey, SilentPaymentAddress};
+
+/// the most data relayed in an OP_RETURN output by default
+pub const MAX_DATA: usize = 80;
+
+/// where change goes
+enum Change {
+    Script(ScriptBuf),
+    Account(u32, u32)
+}
+
+/// who pays the fee of a transaction
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum FeeDeduction {
+    /// the wallet pays the fee on top of the amounts
+    Wallet,
+    /// all recipients pay the fee in proportion to their amounts
+    Proportional,
+    /// recipients at these positions of the added recipients pay the fee in equal shares,
+    /// the first of them also pays what does not divide
+    Recipients(Vec<usize>)
+}
+
+/// what happens to change too small for an output
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DustPolicy {
+    /// change below the dust limit goes to the fee
+    Absorb,
+    /// change below the amount goes to the fee, not to an output that costs more to spend than it is worth.
+    /// The dust limit applies if it is higher.
+    AbsorbBelow(Amount),
+    /// fail instead of giving change below the dust limit to the fee
+    Refuse
+}
+
+/// order of the inputs and outputs of a transaction
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TxOrdering {
+    /// random, the position of an output does not tell change from payments
+    Shuffle,
+    /// BIP69 lexicographic order, the same for all wallets that follow it
+    Bip69,
+    /// inputs as selected, outputs as added with change last
+    Untouched
+}
+
+impl TxOrdering {
+    /// BIP69 sorts by txid in its displayed byte order, then by output index
+    pub fn sort_inputs(self, inputs: &mut [OutPoint]) {
+        match self {
+            TxOrdering::Shuffle => inputs.shuffle(&mut thread_rng()),
+            TxOrdering::Bip69 => inputs.sort_by_key(|p| {
+                let mut txid = p.txid.to_byte_array();
+                txid.reverse();
+                (txid, p.vout)
+            }),
+            TxOrdering::Untouched => {}
+        }
+    }
+
+    /// BIP69 sorts by amount, then by script
+    pub fn sort_outputs(self, outputs: &mut [TxOut]) {
+        match self {
+            TxOrdering::Shuffle => outputs.shuffle(&mut thread_rng()),
+            TxOrdering::Bip69 => outputs.sort_by(|a, b| a.value.cmp(&b.value).then_with(|| a.script_pubkey.as_bytes().cmp(b.script_pubkey.as_bytes()))),
+            TxOrdering::Untouched => {}
+        }
+    }
+}
+
+/// which address type change has if neither a change script nor account is chosen,
+/// change of another type than the payment tells which output is which
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ChangeMatching {
+    /// the internal chain of the account of the first required coin, or of the lowest account
+    Off,
+    /// the type of the first recipient the master has an internal chain of, else as Inputs
+    Payment,
+    /// the type most coins that may be spent have
+    Inputs
+}
+
+/// collects what a transaction should do, then funds and signs it
+pub struct TxBuilder {
+    recipients: Vec<TxOut>,
+    /// payload of an OP_RETURN output
+    data: Option<Vec<u8>>,
+    fee_rate: u64,
+    change: Option<Change>,
+    must_spend: Vec<OutPoint>,
+    unspendable: HashSet<OutPoint>,
+    manual_only: bool,
+    refuse_used_change: bool,
+    /// denomination and number of equal outputs to the wallet
+    equal_outputs: Option<(u64, usize)>,
+    lock_time: LockTime,
+    /// height of the chain tip for the anti fee sniping lock time
+    tip_height: Option<u32>,
+    anti_fee_sniping: bool,
+    /// nSequence of inputs other than the default that signals RBF
+    sequences: HashMap<OutPoint, Sequence>,
+    selector: Box<dyn CoinSelector>,
+    /// the script that receives all value of the spent coins left after recipients and fee
+    drain: Option<ScriptBuf>,
+    fee_deduction: FeeDeduction,
+    dust_policy: DustPolicy,
+    ordering: TxOrdering,
+    change_matching: ChangeMatching,
+    /// recipients paid to silent payment addresses, their scripts are known once inputs are
+    silent: Vec<(usize, SilentPaymentAddress)>
+}
+
+impl Default for TxBuilder {
+    fn default() -> TxBuilder {
+        TxBuilder::new()
+    }
+}
+
+impl TxBuilder {
+    /// a builder paying at 1 sat/vB, selecting coins with branch and bound
+    pub fn new() -> TxBuilder {
+        TxBuilder {
+            recipients: Vec::new(),
+            data: None,
+            fee_rate: 1,
+            change: None,
+            must_spend: Vec::new(),
+            unspendable: HashSet::new(),
+            manual_only: false,
+            refuse_used_change: false,
+            equal_outputs: None,
+            lock_time: LockTime::ZERO,
+            tip_height: None,
+            anti_fee_sniping: true,
+            sequences: HashMap::new(),
+            selector: Box::new(BranchAndBound::default()),
+            drain: None,
+            fee_deduction: FeeDeduction::Wallet,
+            dust_policy: DustPolicy::Absorb,
+            ordering: TxOrdering::Shuffle,
+            change_matching: ChangeMatching::Off,
+            silent: Vec::new()
+        }
+    }
+
+    /// pay amount satoshis to a script
+    pub fn add_recipient(mut self, script_pubkey: ScriptBuf, amount: u64) -> TxBuilder {
+        self.recipients.push(TxOut { value: Amount::from_sat(amount), script_pubkey });
+        self
+    }
+
+    /// pay each address its amount in satoshis, e.g. a batch of withdrawals
+    pub fn add_recipients<I: IntoIterator<Item=(Address, u64)>>(mut self, recipients: I) -> TxBuilder {
+        for (address, amount) in recipients {
+            self = self.add_recipient(address.script_pubkey(), amount);
+        }
+        self
+    }
+
+    /// deduct the fee from the amounts of recipients, the wallet pays it by default
+    pub fn fee_deduction(mut self, deduction: FeeDeduction) -> TxBuilder {
+        self.fee_deduction = deduction;
+        self
+    }
+
+    /// the last added recipient pays the fee from its amount, as with subtractfeefromamount of Bitcoin Core.
+    /// Recipients marked this way pay the fee in equal shares, change is not reduced.
+    pub fn subtract_fee_from_amount(mut self) -> TxBuilder {
+        if let Some(last) = self.recipients.len().checked_sub(1) {
+            match self.fee_deduction {
+                FeeDeduction::Recipients(ref mut payers) => if !payers.contains(&last) { payers.push(last) },
+                _ => self.fee_deduction = FeeDeduction::Recipients(vec!(last))
+            }
+        }
+        self
+    }
+
+    /// pay amount satoshis to a BIP352 silent payment address, needs finish_signed
+    pub fn add_silent_payment(mut self, address: &SilentPaymentAddress, amount: u64) -> TxBuilder {
+        self.silent.push((self.recipients.len(), *address));
+        self.add_recipient(address.placeholder(), amount)
+    }
+
+    /// attach up to 80 bytes in a zero value OP_RETURN output, a later call replaces the data
+    pub fn add_data(mut self, data: &[u8]) -> TxBuilder {
+        self.data = Some(data.to_vec());
+        self
+    }
+
+    /// fee rate in satoshi per virtual byte
+    pub fn fee_rate(mut self, sat_per_vb: u64) -> TxBuilder {
+        self.fee_rate = sat_per_vb;
+        self
+    }
+
+    /// pay the fee rate an estimator expects to confirm within blocks, e.g. fees::NORMAL
+    pub fn fee_estimate(mut self, estimator: &mut dyn FeeEstimator, blocks: u32) -> Result<TxBuilder, WalletError> {
+        self.fee_rate = estimator.estimate(blocks)?.to_sat_per_vb_ceil();
+        Ok(self)
+    }
+
+    /// pay change to a script
+    pub fn change_script(mut self, script_pubkey: ScriptBuf) -> TxBuilder {
+        self.change = Some(Change::Script(script_pubkey));
+        self
+    }
+
+    /// pay change to the next key of an account of the master account.
+    /// Without a change script or account change goes to the internal chain of the account of the first required coin,
+    /// or else of the lowest account with one.
+    pub fn change_account(mut self, account: u32, sub_account: u32) -> TxBuilder {
+        self.change = Some(Change::Account(account, sub_account));
+        self
+    }
+
+    /// pay change to an internal chain of the address type of the payment or of the inputs if the master has one,
+    /// unless a change script or account is chosen
+    pub fn change_matching(mut self, matching: ChangeMatching) -> TxBuilder {
+        self.change_matching = matching;
+        self
+    }
+
+    /// send everything: spend all spendable coins, or with manually_selected_only those added with add_utxo,
+    /// and pay what remains after recipients and fee to the script, without change
+    pub fn drain_to(mut self, script_pubkey: ScriptBuf) -> TxBuilder {
+        self.drain = Some(script_pubkey);
+        self
+    }
+
+    /// what to do with change below the dust limit, it goes to the fee by default
+    pub fn dust_policy(mut self, policy: DustPolicy) -> TxBuilder {
+        self.dust_policy = policy;
+        self
+    }
+
+    /// fail instead of paying change to a script of the master that already received funds
+    pub fn refuse_used_change(mut self) -> TxBuilder {
+        self.refuse_used_change = true;
+        self
+    }
+
+    /// also pay count outputs of denomination satoshis to fresh keys of the change account.
+    /// With the default ordering their position does not tell payments, equal outputs and change apart.
+    pub fn equal_outputs(mut self, denomination: u64, count: usize) -> TxBuilder {
+        self.equal_outputs = Some((denomination, count));
+        self
+    }
+
+    /// order of inputs and outputs, shuffled by default
+    pub fn ordering(mut self, ordering: TxOrdering) -> TxBuilder {
+        self.ordering = ordering;
+        self
+    }
+
+    /// spend this coin in any case, it must not be frozen
+    pub fn add_utxo(mut self, point: OutPoint) -> TxBuilder {
+        self.must_spend.push(point);
+        self
+    }
+
+    /// spend all these coins in any case
+    pub fn add_utxos<I: IntoIterator<Item=OutPoint>>(mut self, points: I) -> TxBuilder {
+        for point in points {
+            if !self.must_spend.contains(&point) {
+                self.must_spend.push(point);
+            }
+        }
+        self
+    }
+
+    /// never spend this coin
+    pub fn unspendable(mut self, point: OutPoint) -> TxBuilder {
+        self.unspendable.insert(point);
+        self
+    }
+
+    /// spend only coins added with add_utxo
+    pub fn manually_selected_only(mut self) -> TxBuilder {
+        self.manual_only = true;
+        self
+    }
+
+    /// the transaction is not valid before a height or time.
+    /// It is enforced only if an input has a sequence below the maximum, as inputs have by default.
+    pub fn lock_time(mut self, lock_time: LockTime) -> TxBuilder {
+        self.lock_time = lock_time;
+        self
+    }
+
+    /// height of the chain tip, transactions without an explicit lock time are not valid before it,
+    /// so miners gain nothing by reorganizing the tip to take their fee
+    pub fn tip_height(mut self, height: u32) -> TxBuilder {
+        self.tip_height = Some(height);
+        self
+    }
+
+    /// whether to set the lock time to the tip height, on by default. Turn it off for a lock time of zero.
+    pub fn anti_fee_sniping(mut self, enabled: bool) -> TxBuilder {
+        self.anti_fee_sniping = enabled;
+        self
+    }
+
+    /// spend this coin with an nSequence, e.g. Sequence::from_height to satisfy a CSV relative lock time.
+    /// Maturity of the coin is not checked, the transaction is not valid before.
+    pub fn input_sequence(mut self, point: OutPoint, sequence: Sequence) -> TxBuilder {
+        self.sequences.insert(point, sequence);
+        self.add_utxo(point)
+    }
+
+    /// strategy to select coins in addition to those added with add_utxo
+    pub fn coin_selector<S: CoinSelector + 'static>(mut self, selector: S) -> TxBuilder {
+        self.selector = Box::new(selector);
+        self
+    }
+
+    /// select coins and create an unsigned PSBT
+    pub fn finish(&self, master: &mut MasterAccount, coins: &Coins) -> Result<Psbt, WalletError> {
+        if !self.silent.is_empty() {
+            return Err(WalletError::Unsupported("silent payment outputs need the keys of the inputs, use finish_signed"));
+        }
+        self.build(master, coins, false)
+    }
+
+    /// a preview derives no keys, outputs to the master pay to the keys next_key would create
+    fn build(&self, master: &mut MasterAccount, coins: &Coins, preview: bool) -> Result<Psbt, WalletError> {
+        if self.recipients.is_empty() && self.equal_outputs.is_none() && self.data.is_none() && self.drain.is_none() {
+            return Err(WalletError::Unsupported("no recipients"));
+        }
+        let fee_rate = FeeRate::from_sat_per_vb(self.fee_rate).ok_or(WalletError::Unsupported("fee rate is too high"))?;
+        // keys of the master are derived once the transaction is built, the next key stands for them until then
+        let (mut change_script, change_account) = match (&self.drain, &self.change) {
+            (Some(drain), _) => (drain.clone(), None),
+            (None, Some(Change::Script(ref script))) => (script.clone(), None),
+            (None, Some(Change::Account(account, sub_account))) => (peek_script(master, (*account, *sub_account))?, Some((*account, *sub_account))),
+            (None, None) => {
+                let change = self.internal_chain(master, coins).ok_or(WalletError::Unsupported("no change script or account"))?;
+                (peek_script(master, change)?, Some(change))
+            }
+        };
+        if self.refuse_used_change && self.drain.is_none() && master.address_status(&change_script).is_some_and(|s| s != AddressStatus::Fresh) {
+            return Err(WalletError::Unsupported("change to a used address"));
+        }
+
+        let mut recipients = self.recipients.clone();
+        if let Some(ref data) = self.data {
+            if data.len() > MAX_DATA {
+                return Err(WalletError::Unsupported("OP_RETURN data is longer than 80 bytes"));
+            }
+            let data = PushBytesBuf::try_from(data.clone()).expect("short data");
+            recipients.push(TxOut { value: Amount::ZERO, script_pubkey: ScriptBuf::new_op_return(data) });
+        }
+        let mut equal = None;
+        if let Some((denomination, count)) = self.equal_outputs {
+            if !self.silent.is_empty() {
+                return Err(WalletError::Unsupported("equal outputs would move silent payment outputs"));
+            }
+            let account = match self.change {
+                Some(Change::Account(account, sub_account)) => Some((account, sub_account)),
+                _ => self.internal_chain(master, coins)
+            }.ok_or(WalletError::Unsupported("no account for equal outputs"))?;
+            let script_pubkey = peek_script(master, account)?;
+            if Amount::from_sat(denomination) < dust::dust_limit(&script_pubkey, dust::DUST_RELAY_FEE) {
+                return Err(WalletError::Dust(Amount::from_sat(denomination)));
+            }
+            equal = Some((account, recipients.len()..recipients.len() + count));
+            recipients.extend((0..count).map(|_| TxOut { value: Amount::from_sat(denomination), script_pubkey: script_pubkey.clone() }));
+        }
+
+        let mut required = Vec::new();
+        for point in self.must_spend.iter() {
+            if coins.is_frozen(point) {
+                return Err(WalletError::FrozenCoin(*point));
+            }
+            let coin = coins.owned().get(point).ok_or(WalletError::UnknownCoin(*point))?;
+            required.push(coinselect::candidate(master, point, coin).ok_or(WalletError::UnknownCoin(*point))?);
+        }
+        let others = if self.manual_only {
+            Vec::new()
+        } else {
+            coins.owned().iter()
+                .filter(|(point, _)| !self.unspendable.contains(point) && !self.must_spend.contains(point) && !coins.is_frozen(point))
+                .filter_map(|(point, coin)| coinselect::candidate(master, point, coin))
+                .collect()
+        };
+
+        if let Some(output) = recipients.iter().find(|o| dust::is_dust(o)) {
+            return Err(WalletError::Dust(output.value));
+        }
+        let dust_limit = dust::dust_limit(&change_script, dust::DUST_RELAY_FEE);
+        let target = Target {
+            amount: recipients.iter().map(|o| o.value.to_sat()).sum(),
+            fee_rate,
+            base_weight: coinselect::TRANSACTION_OVERHEAD_WEIGHT + recipients.iter().map(|o| coinselect::output_weight(&o.script_pubkey)).sum::<u64>(),
+            change_weight: coinselect::output_weight(&change_script),
+            min_change: match self.dust_policy {
+                DustPolicy::AbsorbBelow(threshold) => max(threshold, dust_limit),
+                _ => dust_limit
+            }.to_sat()
+        };
+        let deduct = self.fee_deduction != FeeDeduction::Wallet;
+        let selection = if self.drain.is_some() {
+            if deduct {
+                return Err(WalletError::Unsupported("fee deduction from recipients of a send everything transaction"));
+            }
+            TxBuilder::drain(&required, &others, &target)?
+        } else if deduct {
+            // select coins for the amounts alone, recipients pay the fee
+            self.select(&required, &others, &Target { fee_rate: FeeRate::ZERO, ..target })?
+        } else {
+            self.select(&required, &others, &target)?
+        };
+
+        let weight = target.base_weight + selection.coins.iter()
+            .filter_map(|(point, _)| required.iter().chain(others.iter()).find(|c| c.point == *point))
+            .map(|c| c.weight).sum::<u64>() + if selection.change > 0 { target.change_weight } else { 0 };
+        let fee = coinselect::fee(fee_rate, weight);
+        // surplus of the selection not worth a change output, it goes to the fee
+        let surplus = if deduct { selection.fee } else { selection.fee.saturating_sub(fee) };
+        if self.dust_policy == DustPolicy::Refuse && selection.change == 0 && surplus > 0 {
+            return Err(WalletError::Dust(Amount::from_sat(surplus)));
+        }
+
+        let mut outputs = recipients;
+        if deduct {
+            self.deduct_fee(&mut outputs[..self.recipients.len()], fee.saturating_sub(surplus))?;
+        }
+        let mut inputs = selection.coins.iter().map(|(point, _)| *point).collect::<Vec<_>>();
+        let all_final = inputs.iter().all(|point| self.sequences.get(point) == Some(&Sequence::MAX));
+        if self.lock_time != LockTime::ZERO && all_final {
+            return Err(WalletError::Unsupported("lock time is not enforced if all inputs have the final sequence"));
+        }
+
+        if !preview {
+            if let Some(account) = change_account.filter(|_| selection.change > 0) {
+                change_script = master.next_key(account)?.0.script_pubkey;
+            }
+            if let Some((account, range)) = equal {
+                for output in outputs[range].iter_mut() {
+                    output.script_pubkey = master.next_key(account)?.0.script_pubkey;
+                }
+            }
+        }
+        if selection.change > 0 {
+            outputs.push(TxOut { value: Amount::from_sat(selection.change), script_pubkey: change_script });
+        }
+        if self.silent.is_empty() {
+            // silent payment outputs are ordered once their scripts are known
+            self.ordering.sort_outputs(&mut outputs);
+        }
+        self.ordering.sort_inputs(&mut inputs);
+        let lock_time = match self.tip_height {
+            Some(height) if self.anti_fee_sniping && self.lock_time == LockTime::ZERO => anti_fee_sniping_lock_time(height)?,
+            _ => self.lock_time
+        };
+        let mut psbt = psbt::create(master, coins, &inputs, outputs, lock_time)?;
+        for input in psbt.unsigned_tx.input.iter_mut() {
+            if let Some(sequence) = self.sequences.get(&input.previous_output) {
+                input.sequence = *sequence;
+            }
+        }
+        if all_final {
+            // not enforced anyway
+            psbt.unsigned_tx.lock_time = LockTime::ZERO;
+        }
+        Ok(psbt)
+    }
+
+    /// reduce the amounts of the recipients paying the fee by their shares
+    fn deduct_fee(&self, recipients: &mut [TxOut], fee: u64) -> Result<(), WalletError> {
+        let mut shares = vec!(0u64; recipients.len());
+        match self.fee_deduction {
+            FeeDeduction::Wallet => return Ok(()),
+            FeeDeduction::Proportional => {
+                let total = recipients.iter().map(|o| o.value.to_sat() as u128).sum::<u128>();
+                if total == 0 {
+                    return Err(WalletError::Unsupported("no recipient amount to deduct the fee from"));
+                }
+                for (share, output) in shares.iter_mut().zip(recipients.iter()) {
+                    *share = (fee as u128 * output.value.to_sat() as u128 / total) as u64;
+                }
+                let rest = fee - shares.iter().sum::<u64>();
+                if let Some(first) = recipients.iter().position(|o| o.value.to_sat() > 0) {
+                    shares[first] += rest;
+                }
+            }
+            FeeDeduction::Recipients(ref payers) => {
+                if payers.is_empty() || payers.iter().any(|i| *i >= recipients.len()) {
+                    return Err(WalletError::Unsupported("fee paying recipient is not added"));
+                }
+                let payers = payers.iter().collect::<HashSet<_>>();
+                let share = fee / payers.len() as u64;
+                for i in payers.iter() {
+                    shares[**i] = share;
+                }
+                let first = payers.iter().min().expect("payers");
+                shares[**first] += fee - share * payers.len() as u64;
+            }
+        }
+        for (output, share) in recipients.iter_mut().zip(shares) {
+            let value = output.value.to_sat().checked_sub(share).ok_or(WalletError::InsufficientFunds)?;
+            output.value = Amount::from_sat(value);
+            if share > 0 && dust::is_dust(output) {
+                return Err(WalletError::Dust(output.value));
+            }
+        }
+        Ok(())
+    }
+
+    /// the internal chain of the account of the first required coin, or else the lowest account with one
+    fn internal_chain(&self, master: &MasterAccount, coins: &Coins) -> Option<(u32, u32)> {
+        let matching = match self.change_matching {
+            ChangeMatching::Off => None,
+            ChangeMatching::Payment => self.recipients.iter()
+                .find_map(|o| change_chain(master, |t| pays_to(t, &o.script_pubkey)))
+                .or_else(|| self.dominant_chain(master, coins)),
+            ChangeMatching::Inputs => self.dominant_chain(master, coins)
+        };
+        if matching.is_some() {
+            return matching;
+        }
+        self.must_spend.iter().filter_map(|point| coins.owned().get(point))
+            .map(|coin| (coin.derivation.account, INTERNAL))
+            .find(|change| master.get(*change).is_some())
+            .or_else(|| master.default_change())
+    }
+
+    /// the internal chain of the address type of most coins that may be spent
+    fn dominant_chain(&self, master: &MasterAccount, coins: &Coins) -> Option<(u32, u32)> {
+        let mut counts = HashMap::new();
+        for (point, coin) in coins.owned().iter() {
+            if self.must_spend.contains(point) || (!self.manual_only && !self.unspendable.contains(point) && !coins.is_frozen(point)) {
+                if let Some(account) = master.get((coin.derivation.account, coin.derivation.sub)) {
+                    *counts.entry(account.address_type()).or_insert(0usize) += 1;
+                }
+            }
+        }
+        let mut types = counts.into_iter().collect::<Vec<_>>();
+        types.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.as_u32().cmp(&b.0.as_u32())));
+        types.into_iter().find_map(|(address_type, _)| change_chain(master, |t| t == address_type))
+    }
+
+    /// select coins, create and sign the transaction with keys of the master account
+    pub fn finish_signed(&self, master: &mut MasterAccount, coins: &Coins, unlocker: &mut Unlocker) -> Result<Transaction, WalletError> {
+        let mut transaction = self.build(master, coins, false)?.unsigned_tx;
+        if !self.silent.is_empty() {
+            let inputs = self.input_keys(master, coins, &transaction, unlocker)?;
+            let scripts = silentpayment::sender_outputs(&inputs, &self.silent.iter().map(|(_, a)| *a).collect::<Vec<_>>())?;
+            for ((vout, _), script) in self.silent.iter().zip(scripts) {
+                transaction.output[*vout].script_pubkey = script;
+            }
+            self.ordering.sort_outputs(&mut transaction.output);
+        }
+        let resolver = |point: &OutPoint| coins.owned().get(point).map(|c| c.output.clone());
+        let signed = master.sign(&mut transaction, EcdsaSighashType::All, &resolver, unlocker)?;
+        if signed != transaction.input.len() {
+            return Err(WalletError::Unsupported("could not sign all inputs"));
+        }
+        Ok(transaction)
+    }
+
+    /// keys of the inputs silent payment outputs are derived from, P2WSH inputs are not eligible
+    fn input_keys(&self, master: &MasterAccount, coins: &Coins, transaction: &Transaction, unlocker: &mut Unlocker) -> Result<Vec<InputKey>, WalletError> {
+        let secp = Secp256k1::new();
+        let mut keys = Vec::new();
+        for input in transaction.input.iter() {
+            let d = &coins.owned().get(&input.previous_output).ok_or(WalletError::UnknownCoin(input.previous_output))?.derivation;
+            let account = master.get((d.account, d.sub)).ok_or(WalletError::Unsupported("no account of an input"))?;
+            let address_type = account.address_type();
+            let secret = account.unlock(unlocker, d.kix, d.tweak.clone())?.inner;
+            match address_type {
+                AccountAddressType::P2WSH(_) => {}
+                AccountAddressType::P2TR => keys.push(InputKey {
+                    point: input.previous_output,
+                    secret: Keypair::from_secret_key(&secp, &secret).tap_tweak(&secp, None).to_keypair().secret_key(),
+                    taproot: true
+                }),
+                _ => keys.push(InputKey { point: input.previous_output, secret, taproot: false })
+            }
+        }
+        Ok(keys)
+    }
+
+    /// the value a drain_to script receives, the most a send everything transaction pays to it
+    pub fn drain_amount(&self, master: &mut MasterAccount, coins: &Coins) -> Result<Amount, WalletError> {
+        let drain = self.drain.as_ref().ok_or(WalletError::Unsupported("no drain script"))?;
+        let psbt = self.build(master, coins, true)?;
+        Ok(psbt.unsigned_tx.output.iter().rev().find(|o| o.script_pubkey == *drain).map(|o| o.value).unwrap_or(Amount::ZERO))
+    }
+
+    /// spend all candidates, the change output receives the remainder
+    fn drain(required: &[Candidate], others: &[Candidate], target: &Target) -> Result<Selection, WalletError> {
+        let all = required.iter().chain(others.iter()).collect::<Vec<_>>();
+        let value = all.iter().map(|c| c.coin.output.value.to_sat()).sum::<u64>();
+        let fee = coinselect::fee(target.fee_rate, target.base_weight + target.change_weight + all.iter().map(|c| c.weight).sum::<u64>());
+        let change = value.checked_sub(target.amount + fee).ok_or(WalletError::InsufficientFunds)?;
+        if change < target.min_change {
+            return Err(WalletError::InsufficientFunds);
+        }
+        Ok(Selection { coins: all.iter().map(|c| (c.point, c.coin.clone())).collect(), fee, change })
+    }
+
+    /// required coins are paid for in the base weight, the selector adds what is missing
+    fn select(&self, required: &[Candidate], others: &[Candidate], target: &Target) -> Result<Selection, WalletError> {
+        let value = required.iter().map(|c| c.coin.output.value.to_sat()).sum::<u64>();
+        let weight = target.base_weight + required.iter().map(|c| c.weight).sum::<u64>();
+        let mut coins = required.iter().map(|c| (c.point, c.coin.clone())).collect::<Vec<_>>();
+        let fee = coinselect::fee(target.fee_rate, weight);
+        if !required.is_empty() && value >= target.amount + fee {
+            let fee_with_change = coinselect::fee(target.fee_rate, weight + target.change_weight);
+            if value >= target.amount + fee_with_change + target.min_change {
+                return Ok(Selection { coins, fee: fee_with_change, change: value - target.amount - fee_with_change });
+            }
+            return Ok(Selection { coins, fee: value - target.amount, change: 0 });
+        }
+        let rest = Target { amount: target.amount.saturating_sub(value), base_weight: weight, ..*target };
+        let mut selection = self.selector.select(others, &rest)?;
+        coins.append(&mut selection.coins);
+        // value of required coins exceeding the amount is not lost to the fee
+        let total = coins.iter().map(|(_, c)| c.output.value.to_sat()).sum::<u64>();
+        if selection.change > 0 {
+            Ok(Selection { coins, fee: selection.fee, change: total - target.amount - selection.fee })
+        } else {
+            Ok(Selection { coins, fee: total - target.amount, change: 0 })
+        }
+    }
+}
+
+/// the lock time of a transaction at the tip height as Bitcoin Core and Electrum set it,
+/// one in ten transactions up to 99 blocks earlier so that those signed late do not stand out
+pub fn anti_fee_sniping_lock_time(height: u32) -> Result<LockTime, WalletError> {
+    let mut rng = thread_rng();
+    let height = if rng.gen_range(0..10) == 0 { height.saturating_sub(rng.gen_range(0..100)) } else { height };
+    LockTime::from_height(height).map_err(|_| WalletError::Unsupported("not a block height"))
+}
+
+/// the script of the key next_key would create on an account
+fn peek_script(master: &MasterAccount, account: (u32, u32)) -> Result<ScriptBuf, WalletError> {
+    let a = master.get(account).ok_or(WalletError::UnknownAccount { account: account.0, sub_account: account.1 })?;
+    Ok(a.peek_key()?.script_pubkey)
+}
+
+/// the lowest internal chain of an address type
+fn change_chain<F: Fn(AccountAddressType) -> bool>(master: &MasterAccount, of_type: F) -> Option<(u32, u32)> {
+    master.accounts().iter().filter(|((_, sub), account)| *sub == INTERNAL && of_type(account.address_type()))
+        .map(|(id, _)| *id).min()
+}
+
+/// whether a script is of the kind an address type pays to, nested segwit is assumed for P2SH
+fn pays_to(address_type: AccountAddressType, script: &Script) -> bool {
+    match address_type {
+        AccountAddressType::P2PKH => script.is_p2pkh(),
+        AccountAddressType::P2SHWPKH => script.is_p2sh(),
+        AccountAddressType::P2WPKH => script.is_p2wpkh(),
+        AccountAddressType::P2TR => script.is_p2tr(),
+        AccountAddressType::P2WSH(_) => script.is_p2wsh()
+    }
+}
+
+/// spend own outputs of a stuck parent transaction to destination with a fee that lifts
+/// parent and child together to the package fee rate. parent_fee is the fee the parent pays,
+/// it is not computed here as inputs of incoming transactions are not known to the wallet.
+pub fn child_pays_for_parent(master: &MasterAccount, parent: &Transaction, parent_fee: Amount, package_fee_rate: FeeRate,
+                             destination: ScriptBuf, unlocker: &mut Unlocker) -> Result<Transaction, WalletError> {
+    let txid = parent.compute_txid();
+    let mut inputs = Vec::new();
+    let mut spent = Vec::new();
+    let mut value = 0u64;
+    for (vout, output) in parent.output.iter().enumerate() {
+        if let Some((account, key)) = master.get_key_for_script(&output.script_pubkey) {
+            inputs.push(TxIn {
+                previous_output: OutPoint { txid, vout: vout as u32 },
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                witness: Witness::new()
+            });
+            value += output.value.to_sat();
+            spent.push((account.address_type(), key.script_code.clone()));
+        }
+    }
+    if inputs.is_empty() {
+        return Err(WalletError::Unsupported("parent has no output of the master account"));
+    }
+    let weight = coinselect::estimate_weight(&spent.iter().map(|(t, s)| (*t, s.as_script())).collect::<Vec<_>>(), &[&destination]);
+    // the package pays for both, but the child should not pay less than its own weight needs
+    let package_fee = coinselect::fee(package_fee_rate, parent.weight().to_wu() + weight);
+    let fee = max(package_fee.saturating_sub(parent_fee.to_sat()), coinselect::fee(package_fee_rate, weight));
+    let remaining = value.checked_sub(fee).ok_or(WalletError::InsufficientFunds)?;
+    if remaining < destination.minimal_non_dust().to_sat() {
+        return Err(WalletError::InsufficientFunds);
+    }
+    let mut child = Transaction {
+        version: Version::TWO,
+        lock_time: LockTime::ZERO,
+        input: inputs,
+        output: vec!(TxOut { value: Amount::from_sat(remaining), script_pubkey: destination })
+    };
+    let resolver = |point: &OutPoint| if point.txid == txid { parent.output.get(point.vout as usize).cloned() } else { None };
+    master.sign(&mut child, EcdsaSighashType::All, &resolver, unlocker)?;
+    Ok(child)
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{Block, BlockHash, CompactTarget, Network, TxMerkleNode, Txid};
+    use bitcoin::block::Header;
+    use bitcoin_hashes::Hash;
+
+    use account::{AccountAddressType, MasterKeyEntropy};
+    use coinselect::LargestFirst;
+    use events::EventBus;
+    use super::*;
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+
+    /// a receive and a change account, coins of 100000, 50000 and 20000 satoshis in outputs 0 to 2 of the txid
+    fn funded() -> (MasterAccount, Unlocker, Coins, Txid) {
+        let mut master = MasterAccount::new(MasterKeyEntropy::Low, Network::Bitcoin, PASSPHRASE, None).unwrap();
+        let mut unlocker = Unlocker::new(master.encrypted(), PASSPHRASE, None, Network::Bitcoin, None).unwrap();
+        let receive = master.new_account(&mut unlocker, AccountAddressType::P2WPKH, 0, 0, 10).unwrap().next_key().unwrap().script_pubkey.clone();
+        master.new_account(&mut unlocker, AccountAddressType::P2WPKH, 0, 1, 10).unwrap();
+        let funding = Transaction {
+            version: Version::ONE,
+            lock_time: LockTime::ZERO,
+            input: vec!(TxIn {
+                previous_output: OutPoint { txid: Txid::all_zeros(), vout: 0 },
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::new()
+            }),
+            output: vec!(
+                TxOut { value: Amount::from_sat(100000), script_pubkey: receive.clone() },
+                TxOut { value: Amount::from_sat(50000), script_pubkey: receive.clone() },
+                TxOut { value: Amount::from_sat(20000), script_pubkey: receive })
+        };
+        let block = Block {
+            header: Header {
+                version: bitcoin::block::Version::ONE,
+                prev_blockhash: BlockHash::all_zeros(),
+                merkle_root: TxMerkleNode::all_zeros(),
+                time: 0,
+                bits: CompactTarget::from_consensus(0),
+                nonce: 0
+            },
+            txdata: vec!(funding.clone())
+        };
+        let mut coins = Coins::new();
+        coins.process(&mut master, &block).unwrap();
+        (master, unlocker, coins, funding.compute_txid())
+    }
+
+    #[test]
+    fn test_builder() {
+        let (mut master, mut unlocker, coins, txid) = funded();
+        let recipient = ScriptBuf::new_op_return([0u8; 8]);
+
+        let builder = TxBuilder::new()
+            .add_recipient(recipient.clone(), 30000)
+            .fee_rate(5)
+            .change_account(0, 1)
+            .coin_selector(LargestFirst);
+        let transaction = builder.finish_signed(&mut master, &coins, &mut unlocker).unwrap();
+        assert_eq!(transaction.input.len(), 1);
+        assert_eq!(transaction.input[0].previous_output, OutPoint { txid, vout: 0 });
+        assert_eq!(transaction.output.len(), 2);
+        let change = transaction.output.iter().find(|o| o.script_pubkey != recipient).unwrap();
+        assert!(master.get((0, 1)).unwrap().instantiated().iter().any(|k| k.script_pubkey == change.script_pubkey));
+        // the estimated fee covers the signed weight
+        let fee = 100000 - transaction.output.iter().map(|o| o.value.to_sat()).sum::<u64>();
+        assert!(fee >= coinselect::fee(FeeRate::from_sat_per_vb(5).unwrap(), transaction.weight().to_wu()));
+        assert!(fee <= coinselect::fee(FeeRate::from_sat_per_vb(5).unwrap(), transaction.weight().to_wu() + 4));
+        transaction.verify(|point| coins.owned().get(point).map(|c| c.output.clone())).unwrap();
+
+        // coin control
+        let psbt = TxBuilder::new()
+            .add_recipient(recipient.clone(), 30000)
+            .change_account(0, 1)
+            .add_utxo(OutPoint { txid, vout: 2 })
+            .unspendable(OutPoint { txid, vout: 0 })
+            .ordering(TxOrdering::Untouched)
+            .finish(&mut master, &coins).unwrap();
+        let inputs = psbt.unsigned_tx.input.iter().map(|i| i.previous_output.vout).collect::<Vec<_>>();
+        assert_eq!(inputs, vec!(2, 1));
+
+        assert!(TxBuilder::new()
+            .add_recipient(recipient.clone(), 30000)
+            .change_account(0, 1)
+            .add_utxo(OutPoint { txid, vout: 2 })
+            .manually_selected_only()
+            .finish(&mut master, &coins).is_err());
+    }
+
+    #[test]
+    fn test_frozen_coins() {
+        let (mut master, _, mut coins, txid) = funded();
+        let recipient = ScriptBuf::new_op_return([0u8; 8]);
+
+        // frozen coins are not selected and can not be pinned
+        coins.freeze(OutPoint { txid, vout: 0 });
+        let psbt = TxBuilder::new()
+            .add_recipient(recipient.clone(), 30000)
+            .change_account(0, 1)
+            .add_utxos(vec!(OutPoint { txid, vout: 2 }, OutPoint { txid, vout: 2 }))
+            .ordering(TxOrdering::Untouched)
+            .finish(&mut master, &coins).unwrap();
+        let inputs = psbt.unsigned_tx.input.iter().map(|i| i.previous_output.vout).collect::<Vec<_>>();
+        assert_eq!(inputs, vec!(2, 1));
+        assert!(TxBuilder::new()
+            .add_recipient(recipient, 30000)
+            .change_account(0, 1)
+            .add_utxo(OutPoint { txid, vout: 0 })
+            .finish(&mut master, &coins).is_err());
+        assert!(coins.unfreeze(&OutPoint { txid, vout: 0 }));
+    }
+
+    #[test]
+    fn test_default_change() {
+        let (mut master, _, coins, _) = funded();
+        let recipient = ScriptBuf::new_op_return([0u8; 8]);
+
+        // change goes to the internal chain unless chosen
+        let psbt = TxBuilder::new().add_recipient(recipient.clone(), 30000).finish(&mut master, &coins).unwrap();
+        assert!(master.get((0, 1)).unwrap().instantiated().iter().any(|k| psbt.unsigned_tx.output.iter().any(|o| o.script_pubkey == k.script_pubkey)));
+        let mut receive_only = MasterAccount::new(MasterKeyEntropy::Low, Network::Bitcoin, PASSPHRASE, None).unwrap();
+        let mut unlocker = Unlocker::new(receive_only.encrypted(), PASSPHRASE, None, Network::Bitcoin, None).unwrap();
+        receive_only.new_account(&mut unlocker, AccountAddressType::P2WPKH, 0, 0, 10).unwrap();
+        assert!(TxBuilder::new().add_recipient(recipient, 30000).finish(&mut receive_only, &coins).is_err());
+    }
+
+    #[test]
+    fn test_used_change() {
+        let (mut master, _, coins, _) = funded();
+        let recipient = ScriptBuf::new_op_return([0u8; 8]);
+
+        // the receive script already got funds
+        let used = master.get((0, 0)).unwrap().get_key(0).unwrap().script_pubkey.clone();
+        assert_eq!(master.address_status(&used), Some(AddressStatus::Used));
+        assert!(TxBuilder::new().add_recipient(recipient.clone(), 30000).change_script(used.clone()).finish(&mut master, &coins).is_ok());
+        assert!(TxBuilder::new().add_recipient(recipient, 30000).change_script(used).refuse_used_change().finish(&mut master, &coins).is_err());
+    }
+
+    #[test]
+    fn test_data() {
+        let (mut master, _, coins, _) = funded();
+
+        let psbt = TxBuilder::new().add_data(b"commitment").finish(&mut master, &coins).unwrap();
+        assert_eq!(psbt.unsigned_tx.output.len(), 2);
+        let data = psbt.unsigned_tx.output.iter().find(|o| o.script_pubkey.is_op_return()).unwrap();
+        assert_eq!(data.value, Amount::ZERO);
+        assert!(data.script_pubkey.as_bytes().ends_with(b"commitment"));
+        assert!(TxBuilder::new().add_data(&[0u8; 81]).finish(&mut master, &coins).is_err());
+    }
+
+    #[test]
+    fn test_lock_time() {
+        let (mut master, _, coins, txid) = funded();
+        let recipient = ScriptBuf::new_op_return([0u8; 8]);
+
+        let psbt = TxBuilder::new().add_recipient(recipient.clone(), 30000)
+            .lock_time(LockTime::from_height(800000).unwrap())
+            .input_sequence(OutPoint { txid, vout: 1 }, Sequence::from_height(144))
+            .finish(&mut master, &coins).unwrap();
+        assert_eq!(psbt.unsigned_tx.lock_time, LockTime::from_height(800000).unwrap());
+        let input = psbt.unsigned_tx.input.iter().find(|i| i.previous_output.vout == 1).unwrap();
+        assert_eq!(input.sequence.to_relative_lock_time(), Some(bitcoin::relative::LockTime::from_height(144)));
+        assert!(TxBuilder::new().add_recipient(recipient, 30000)
+            .lock_time(LockTime::from_height(800000).unwrap())
+            .input_sequence(OutPoint { txid, vout: 0 }, Sequence::MAX)
+            .manually_selected_only()
+            .finish(&mut master, &coins).is_err());
+    }
+
+    #[test]
+    fn test_anti_fee_sniping() {
+        let (mut master, _, coins, txid) = funded();
+        let recipient = ScriptBuf::new_op_return([0u8; 8]);
+
+        let mut lock_time = |builder: TxBuilder| builder.add_recipient(recipient.clone(), 30000).finish(&mut master, &coins).unwrap().unsigned_tx.lock_time;
+        for _ in 0..20 {
+            let height = lock_time(TxBuilder::new().tip_height(800000)).to_consensus_u32();
+            assert!((799901..=800000).contains(&height));
+        }
+        assert_eq!(lock_time(TxBuilder::new().tip_height(800000).anti_fee_sniping(false)), LockTime::ZERO);
+        assert_eq!(lock_time(TxBuilder::new()), LockTime::ZERO);
+        assert_eq!(lock_time(TxBuilder::new().tip_height(800000).lock_time(LockTime::from_height(700000).unwrap())), LockTime::from_height(700000).unwrap());
+        assert_eq!(lock_time(TxBuilder::new().tip_height(800000).input_sequence(OutPoint { txid, vout: 0 }, Sequence::MAX).manually_selected_only()), LockTime::ZERO);
+        assert!(anti_fee_sniping_lock_time(u32::MAX).is_err());
+    }
+
+    #[test]
+    fn test_equal_outputs() {
+        let (mut master, _, coins, _) = funded();
+        let recipient = ScriptBuf::new_op_return([0u8; 8]);
+
+        // equal outputs to distinct fresh keys
+        let psbt = TxBuilder::new().add_recipient(recipient.clone(), 30000).equal_outputs(20000, 3).fee_rate(2).finish(&mut master, &coins).unwrap();
+        let equal = psbt.unsigned_tx.output.iter().filter(|o| o.value == Amount::from_sat(20000)).collect::<Vec<_>>();
+        assert_eq!(equal.len(), 3);
+        assert!(equal.iter().all(|o| master.address_status(&o.script_pubkey) == Some(AddressStatus::Fresh)));
+        assert_eq!(equal.iter().map(|o| &o.script_pubkey).collect::<HashSet<_>>().len(), 3);
+        assert!(psbt.unsigned_tx.output.len() == 5 && psbt.unsigned_tx.output.iter().any(|o| o.script_pubkey == recipient));
+        assert!(TxBuilder::new().equal_outputs(10000, 2).finish(&mut master, &coins).is_ok());
+        assert!(TxBuilder::new().equal_outputs(100, 2).finish(&mut master, &coins).is_err());
+    }
+
+    #[test]
+    fn test_drain() {
+        let (mut master, mut unlocker, coins, txid) = funded();
+        let recipient = ScriptBuf::new_op_return([0u8; 8]);
+
+        // send everything at known weight, without change
+        let destination = master.get((0, 0)).unwrap().get_key(1).unwrap().script_pubkey.clone();
+        let builder = TxBuilder::new().drain_to(destination.clone()).fee_rate(3);
+        let max = builder.drain_amount(&mut master, &coins).unwrap();
+        let transaction = builder.finish_signed(&mut master, &coins, &mut unlocker).unwrap();
+        assert_eq!(transaction.input.len(), 3);
+        assert_eq!(transaction.output, vec!(TxOut { value: max, script_pubkey: destination.clone() }));
+        let fee = 170000 - max.to_sat();
+        assert!(fee >= coinselect::fee(FeeRate::from_sat_per_vb(3).unwrap(), transaction.weight().to_wu()));
+        assert!(fee <= coinselect::fee(FeeRate::from_sat_per_vb(3).unwrap(), transaction.weight().to_wu() + 12));
+        // of selected coins, next to a recipient
+        let psbt = TxBuilder::new().add_recipient(recipient.clone(), 30000).drain_to(destination.clone())
+            .add_utxos(vec!(OutPoint { txid, vout: 1 }, OutPoint { txid, vout: 2 })).manually_selected_only()
+            .finish(&mut master, &coins).unwrap();
+        assert_eq!(psbt.unsigned_tx.input.len(), 2);
+        assert_eq!(psbt.unsigned_tx.output.len(), 2);
+        assert!(psbt.unsigned_tx.output.iter().any(|o| o.script_pubkey == destination));
+        assert!(TxBuilder::new().add_recipient(recipient, 69900).drain_to(destination)
+            .add_utxos(vec!(OutPoint { txid, vout: 1 }, OutPoint { txid, vout: 2 })).manually_selected_only()
+            .finish(&mut master, &coins).is_err());
+    }
+
+    #[test]
+    fn test_fee_deduction() {
+        let (mut master, mut unlocker, coins, _) = funded();
+        let values = [100000, 50000, 20000];
+        let destination = master.get((0, 0)).unwrap().get_key(1).unwrap().script_pubkey.clone();
+
+        // a batch of withdrawals paying its fee
+        let addresses = (1u8..4).map(|i| Address::p2wsh(&ScriptBuf::from(vec!(i)), Network::Bitcoin)).collect::<Vec<_>>();
+        let batch = addresses.iter().cloned().zip(vec!(10000, 20000, 30000)).collect::<Vec<_>>();
+        let value = |t: &Transaction, a: &Address| t.output.iter().find(|o| o.script_pubkey == a.script_pubkey()).unwrap().value.to_sat();
+        let transaction = TxBuilder::new().add_recipients(batch.clone()).fee_rate(4).fee_deduction(FeeDeduction::Proportional)
+            .finish_signed(&mut master, &coins, &mut unlocker).unwrap();
+        let paid = addresses.iter().map(|a| value(&transaction, a)).collect::<Vec<_>>();
+        let fee = 60000 - paid.iter().sum::<u64>();
+        let inputs = transaction.input.iter().map(|i| values[i.previous_output.vout as usize]).sum::<u64>();
+        assert_eq!(inputs - transaction.output.iter().map(|o| o.value.to_sat()).sum::<u64>(), fee);
+        assert!(fee >= coinselect::fee(FeeRate::from_sat_per_vb(4).unwrap(), transaction.weight().to_wu()));
+        assert!(10000 - paid[0] <= 20000 - paid[1] && 20000 - paid[1] <= 30000 - paid[2]);
+        let transaction = TxBuilder::new().add_recipients(batch.clone()).fee_rate(4).fee_deduction(FeeDeduction::Recipients(vec!(1)))
+            .finish_signed(&mut master, &coins, &mut unlocker).unwrap();
+        assert_eq!((value(&transaction, &addresses[0]), value(&transaction, &addresses[2])), (10000, 30000));
+        let inputs = transaction.input.iter().map(|i| values[i.previous_output.vout as usize]).sum::<u64>();
+        assert_eq!(20000 - value(&transaction, &addresses[1]), inputs - transaction.output.iter().map(|o| o.value.to_sat()).sum::<u64>());
+        let transaction = TxBuilder::new().add_recipient(addresses[0].script_pubkey(), 10000).subtract_fee_from_amount()
+            .add_recipient(addresses[1].script_pubkey(), 20000).add_recipient(addresses[2].script_pubkey(), 30000).subtract_fee_from_amount()
+            .fee_rate(4).finish_signed(&mut master, &coins, &mut unlocker).unwrap();
+        assert_eq!(value(&transaction, &addresses[1]), 20000);
+        let inputs = transaction.input.iter().map(|i| values[i.previous_output.vout as usize]).sum::<u64>();
+        let fee = inputs - transaction.output.iter().map(|o| o.value.to_sat()).sum::<u64>();
+        assert_eq!((10000 - value(&transaction, &addresses[0]), 30000 - value(&transaction, &addresses[2])), (fee - fee / 2, fee / 2));
+        assert!(TxBuilder::new().add_recipients(batch.clone()).fee_deduction(FeeDeduction::Recipients(vec!(3))).finish(&mut master, &coins).is_err());
+        assert!(TxBuilder::new().add_recipients(batch).drain_to(destination).fee_deduction(FeeDeduction::Proportional).finish(&mut master, &coins).is_err());
+    }
+
+    #[test]
+    fn test_dust_policy() {
+        let (mut master, _, coins, txid) = funded();
+        let recipient = ScriptBuf::new_op_return([0u8; 8]);
+
+        // change below the dust limit
+        let rate = FeeRate::from_sat_per_vb(1).unwrap();
+        let empty = ScriptBuf::new();
+        let change = master.get((0, 1)).unwrap().get_key(0).unwrap().script_pubkey.clone();
+        let without_change = coinselect::fee(rate, coinselect::estimate_weight(&[(AccountAddressType::P2WPKH, &empty)], &[&recipient]));
+        let with_change = coinselect::fee(rate, coinselect::estimate_weight(&[(AccountAddressType::P2WPKH, &empty)], &[&recipient, &change]));
+        let pay = |amount: u64, policy: DustPolicy| TxBuilder::new().add_recipient(recipient.clone(), amount).change_script(change.clone())
+            .add_utxo(OutPoint { txid, vout: 0 }).manually_selected_only().dust_policy(policy).ordering(TxOrdering::Untouched);
+        let psbt = pay(100000 - without_change - 100, DustPolicy::Absorb).finish(&mut master, &coins).unwrap();
+        assert_eq!(psbt.unsigned_tx.output.len(), 1);
+        match pay(100000 - without_change - 100, DustPolicy::Refuse).finish(&mut master, &coins) {
+            Err(WalletError::Dust(surplus)) => assert_eq!(surplus, Amount::from_sat(100)),
+            other => panic!("expected dust, got {:?}", other.map(|p| p.unsigned_tx))
+        }
+        assert_eq!(pay(100000 - with_change - 3000, DustPolicy::Refuse).finish(&mut master, &coins).unwrap().unsigned_tx.output[1].value, Amount::from_sat(3000));
+        assert_eq!(pay(100000 - with_change - 3000, DustPolicy::AbsorbBelow(Amount::from_sat(5000))).finish(&mut master, &coins).unwrap().unsigned_tx.output.len(), 1);
+    }
+
+    #[test]
+    fn test_change_matching() {
+        let (mut master, mut unlocker, coins, _) = funded();
+
+        // change of the type of the payment or of most inputs
+        master.new_keychains(&mut unlocker, AccountAddressType::P2TR, 1, 10).unwrap();
+        let mut taproot = vec!(0x51, 0x20);
+        taproot.extend_from_slice(&[2u8; 32]);
+        let taproot = ScriptBuf::from(taproot);
+        let change_of = |matching: ChangeMatching, master: &mut MasterAccount| {
+            let psbt = TxBuilder::new().add_recipient(taproot.clone(), 30000).change_matching(matching)
+                .finish(master, &coins).unwrap();
+            psbt.unsigned_tx.output.iter().find(|o| o.script_pubkey != taproot).unwrap().script_pubkey.clone()
+        };
+        let change = change_of(ChangeMatching::Payment, &mut master);
+        assert!(change.is_p2tr() && master.get((1, 1)).unwrap().instantiated().iter().any(|k| k.script_pubkey == change));
+        assert!(change_of(ChangeMatching::Inputs, &mut master).is_p2wpkh());
+        assert!(change_of(ChangeMatching::Off, &mut master).is_p2wpkh());
+    }
+
+    #[test]
+    fn test_ordering() {
+        let (mut master, _, coins, txid) = funded();
+        let recipient = ScriptBuf::new_op_return([0u8; 8]);
+        let change = master.get((0, 1)).unwrap().get_key(0).unwrap().script_pubkey.clone();
+
+        // BIP69 sorts inputs by outpoint and outputs by value
+        let psbt = TxBuilder::new().add_recipient(recipient, 30000).change_script(change)
+            .add_utxos(vec!(OutPoint { txid, vout: 2 }, OutPoint { txid, vout: 1 })).manually_selected_only()
+            .ordering(TxOrdering::Bip69).finish(&mut master, &coins).unwrap();
+        let inputs = psbt.unsigned_tx.input.iter().map(|i| i.previous_output.vout).collect::<Vec<_>>();
+        assert_eq!(inputs, vec!(1, 2));
+        assert!(psbt.unsigned_tx.output.windows(2).all(|w| w[0].value <= w[1].value));
+    }
+
+    #[test]
+    fn test_dust_recipient() {
+        let (mut master, _, coins, _) = funded();
+        let destination = master.get((0, 0)).unwrap().get_key(1).unwrap().script_pubkey.clone();
+
+        // dust recipients are not relayed
+        assert!(TxBuilder::new().add_recipient(destination.clone(), 293).finish(&mut master, &coins).is_err());
+        assert!(TxBuilder::new().add_recipient(destination, 294).finish(&mut master, &coins).is_ok());
+    }
+
+    #[test]
+    fn test_change_keys() {
+        let (mut master, _, coins, txid) = funded();
+        let events = EventBus::new();
+        let generated = events.subscribe();
+        master.set_events(events);
+        let recipient = ScriptBuf::new_op_return([0u8; 8]);
+        let drain = ScriptBuf::new_op_return([1u8; 8]);
+
+        // failed builds, previews and transactions without change derive no key
+        assert!(TxBuilder::new().add_recipient(recipient.clone(), 1000000).finish(&mut master, &coins).is_err());
+        assert!(TxBuilder::new().add_recipient(recipient.clone(), 1000).equal_outputs(1000, 2).lock_time(LockTime::from_height(100).unwrap())
+            .input_sequence(OutPoint { txid, vout: 0 }, Sequence::MAX).manually_selected_only().finish(&mut master, &coins).is_err());
+        let everything = TxBuilder::new().add_recipient(recipient.clone(), 1000).equal_outputs(1000, 2).drain_to(drain);
+        assert!(everything.drain_amount(&mut master, &coins).unwrap() > Amount::from_sat(160000));
+        let psbt = TxBuilder::new().add_recipient(recipient.clone(), 19800).add_utxo(OutPoint { txid, vout: 2 }).manually_selected_only()
+            .finish(&mut master, &coins).unwrap();
+        assert_eq!(psbt.unsigned_tx.output.len(), 1);
+        assert_eq!(master.get((0, 1)).unwrap().used(), 0);
+        assert!(generated.try_recv().is_err());
+
+        // change and equal outputs pay to keys derived once the transaction is built
+        let psbt = TxBuilder::new().add_recipient(recipient, 30000).equal_outputs(10000, 2).ordering(TxOrdering::Untouched)
+            .finish(&mut master, &coins).unwrap();
+        let change = master.get((0, 1)).unwrap();
+        assert_eq!(change.used(), 3);
+        let outputs = &psbt.unsigned_tx.output;
+        assert_eq!(outputs.len(), 4);
+        assert_eq!(outputs[3].script_pubkey, change.get_key(0).unwrap().script_pubkey);
+        assert_eq!(outputs[1].script_pubkey, change.get_key(1).unwrap().script_pubkey);
+        assert_eq!(outputs[2].script_pubkey, change.get_key(2).unwrap().script_pubkey);
+        assert_eq!(generated.try_iter().count(), 3);
+    }
+
+    #[test]
+    fn test_cpfp() {
+        let mut master = MasterAccount::new(MasterKeyEntropy::Low, Network::Bitcoin, PASSPHRASE, None).unwrap();
+        let mut unlocker = Unlocker::new(master.encrypted(), PASSPHRASE, None, Network::Bitcoin, None).unwrap();
+        let receive = master.new_account(&mut unlocker, AccountAddressType::P2WPKH, 0, 0, 10).unwrap().next_key().unwrap().script_pubkey.clone();
+        let parent = Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec!(TxIn {
+                previous_output: OutPoint { txid: Txid::all_zeros(), vout: 0 },
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::new()
+            }),
+            output: vec!(
+                TxOut { value: Amount::from_sat(70000), script_pubkey: ScriptBuf::new_op_return([0u8; 8]) },
+                TxOut { value: Amount::from_sat(50000), script_pubkey: receive })
+        };
+        let fee_rate = FeeRate::from_sat_per_vb(20).unwrap();
+        let destination = ScriptBuf::new_op_return([1u8; 8]);
+        let child = child_pays_for_parent(&master, &parent, Amount::from_sat(100), fee_rate, destination.clone(), &mut unlocker).unwrap();
+        assert_eq!(child.input.len(), 1);
+        assert_eq!(child.input[0].previous_output.vout, 1);
+        let child_fee = 50000 - child.output[0].value.to_sat();
+        // the package reaches the fee rate
+        assert!(100 + child_fee >= coinselect::fee(fee_rate, parent.weight().to_wu() + child.weight().to_wu()));
+        child.verify(|point| parent.output.get(point.vout as usize).cloned()).unwrap();
+
+        assert!(child_pays_for_parent(&master, &parent, Amount::from_sat(100), FeeRate::from_sat_per_vb(1000).unwrap(), destination, &mut unlocker).is_err());
+    }
+}
@@ -0,0 +1,256 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Chain source
+//!
+//! One interface to Electrum servers, Bitcoin Core, filter serving peers or a custom backend
+//!
+
+use std::collections::{HashMap, HashSet};
+
+use bitcoin::{BlockHash, FeeRate, OutPoint, Script, ScriptBuf, Transaction, TxOut, Txid};
+
+use account::MasterAccount;
+use broadcast::Broadcaster;
+use discovery::UsageSource;
+use error::WalletError;
+use fees::FeeEstimator;
+use sweep::UtxoSource;
+use utxo::UtxoSet;
+
+/// height and hash of the block confirming a transaction, none if unconfirmed
+pub type Confirmation = Option<(u32, BlockHash)>;
+
+/// a backend that knows the chain and the mempool
+pub trait ChainSource {
+    /// transactions spending from or paying to a script and their confirmation
+    fn get_history(&mut self, script_pubkey: &Script) -> Result<Vec<(Txid, Confirmation)>, WalletError>;
+
+    /// unspent outputs of a script
+    fn get_utxos(&mut self, script_pubkey: &Script) -> Result<Vec<(OutPoint, TxOut)>, WalletError>;
+
+    fn get_tx(&mut self, txid: &Txid) -> Result<Transaction, WalletError>;
+
+    /// send a transaction to the network
+    fn broadcast(&mut self, transaction: &Transaction) -> Result<Txid, WalletError>;
+
+    /// fee rate expected to confirm within blocks
+    fn estimate_fee(&mut self, blocks: u32) -> Result<FeeRate, WalletError>;
+
+    /// height and hash of the best block
+    fn tip(&mut self) -> Result<(u32, BlockHash), WalletError>;
+}
+
+/// ask the source for the history of all scripts of the master, extending look ahead of accounts as
+/// used keys are found, then process transactions not yet known so into the UTXO set.
+/// returns the history, confirmed transactions in ascending height order, unconfirmed ones last
+pub fn sync<C: ChainSource + ?Sized>(source: &mut C, master: &mut MasterAccount, utxos: &mut UtxoSet) -> Result<Vec<(Txid, Confirmation)>, WalletError> {
+    let mut queried = HashSet::new();
+    let mut history = HashMap::new();
+    loop {
+        let scripts = master.get_scripts().filter(|(s, _)| !queried.contains(s)).collect::<Vec<_>>();
+        if scripts.is_empty() {
+            break;
+        }
+        for (script_pubkey, derivation) in scripts {
+            let entries = source.get_history(&script_pubkey)?;
+            if !entries.is_empty() {
+                if let Some(account) = master.get_mut((derivation.account, derivation.sub)) {
+                    account.do_look_ahead(derivation.kix)?;
+                }
+            }
+            history.extend(entries);
+            queried.insert(script_pubkey);
+        }
+    }
+
+    let mut history = history.into_iter().collect::<Vec<_>>();
+    history.sort_by_key(|(txid, confirmed)| (confirmed.is_none(), confirmed.map(|(height, _)| height), *txid));
+    for block in history.chunk_by(|(_, a), (_, b)| a == b) {
+        let confirmed = block[0].1;
+        let mut transactions = Vec::new();
+        for (txid, _) in block {
+            if utxos.transactions().get(txid).map(|record| record.confirmed != confirmed).unwrap_or(true) {
+                transactions.push(source.get_tx(txid)?);
+            }
+        }
+        // the history does not tell the order within a block, process parents first
+        for transaction in parents_first(transactions) {
+            match confirmed {
+                Some((height, block_hash)) => utxos.process_confirmed(master, &transaction, height, block_hash),
+                None => utxos.process_unconfirmed(master, &transaction)
+            }
+        }
+    }
+    Ok(history)
+}
+
+/// transactions ordered so that none spends an output of a later one
+fn parents_first(mut transactions: Vec<Transaction>) -> Vec<Transaction> {
+    let mut ordered = Vec::with_capacity(transactions.len());
+    while !transactions.is_empty() {
+        let txids = transactions.iter().map(|t| t.compute_txid()).collect::<HashSet<_>>();
+        let (ready, rest): (Vec<_>, Vec<_>) = transactions.into_iter()
+            .partition(|t| !t.input.iter().any(|i| txids.contains(&i.previous_output.txid)));
+        if ready.is_empty() {
+            ordered.extend(rest);
+            break;
+        }
+        ordered.extend(ready);
+        transactions = rest;
+    }
+    ordered
+}
+
+impl Broadcaster for Box<dyn ChainSource> {
+    fn broadcast(&mut self, transaction: &Transaction) -> Result<Txid, WalletError> {
+        ChainSource::broadcast(self.as_mut(), transaction)
+    }
+}
+
+impl UtxoSource for Box<dyn ChainSource> {
+    fn unspent(&mut self, script_pubkey: &Script) -> Result<Vec<(OutPoint, TxOut)>, WalletError> {
+        self.get_utxos(script_pubkey)
+    }
+}
+
+impl UsageSource for Box<dyn ChainSource> {
+    fn used(&mut self, scripts: &[ScriptBuf]) -> Result<Vec<bool>, WalletError> {
+        scripts.iter().map(|s| Ok(!self.get_history(s)?.is_empty())).collect()
+    }
+}
+
+impl FeeEstimator for Box<dyn ChainSource> {
+    fn estimate(&mut self, blocks: u32) -> Result<FeeRate, WalletError> {
+        self.estimate_fee(blocks)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{Amount, Network, Sequence, TxIn, Witness};
+    use bitcoin::absolute::LockTime;
+    use bitcoin::transaction::Version;
+    use bitcoin_hashes::Hash;
+
+    use account::{AccountAddressType, Unlocker};
+    use discovery;
+    use fees::FixedFee;
+    use super::*;
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+
+    /// confirmed transactions by height, then the mempool
+    #[derive(Default)]
+    struct Memory {
+        transactions: Vec<(Transaction, Confirmation)>
+    }
+
+    impl ChainSource for Memory {
+        fn get_history(&mut self, script_pubkey: &Script) -> Result<Vec<(Txid, Confirmation)>, WalletError> {
+            let mut paid = HashSet::new();
+            let mut history = Vec::new();
+            for (transaction, confirmed) in self.transactions.iter() {
+                let txid = transaction.compute_txid();
+                let spends = transaction.input.iter().any(|i| paid.contains(&i.previous_output));
+                let pays = transaction.output.iter().enumerate().filter(|(_, o)| o.script_pubkey == *script_pubkey)
+                    .map(|(vout, _)| paid.insert(OutPoint { txid, vout: vout as u32 })).count() > 0;
+                if spends || pays {
+                    history.push((txid, *confirmed));
+                }
+            }
+            Ok(history)
+        }
+
+        fn get_utxos(&mut self, _script_pubkey: &Script) -> Result<Vec<(OutPoint, TxOut)>, WalletError> {
+            Err(WalletError::Unsupported("utxos"))
+        }
+
+        fn get_tx(&mut self, txid: &Txid) -> Result<Transaction, WalletError> {
+            self.transactions.iter().map(|(t, _)| t).find(|t| t.compute_txid() == *txid).cloned().ok_or(WalletError::Unsupported("unknown transaction"))
+        }
+
+        fn broadcast(&mut self, transaction: &Transaction) -> Result<Txid, WalletError> {
+            self.transactions.push((transaction.clone(), None));
+            Ok(transaction.compute_txid())
+        }
+
+        fn estimate_fee(&mut self, blocks: u32) -> Result<FeeRate, WalletError> {
+            FixedFee(FeeRate::from_sat_per_vb(if blocks > 6 { 1 } else { 5 }).unwrap()).estimate(blocks)
+        }
+
+        fn tip(&mut self) -> Result<(u32, BlockHash), WalletError> {
+            Ok(self.transactions.iter().filter_map(|(_, confirmed)| *confirmed).max().unwrap_or((0, BlockHash::all_zeros())))
+        }
+    }
+
+    fn transaction(inputs: Vec<OutPoint>, outputs: Vec<(u64, ScriptBuf)>) -> Transaction {
+        Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: inputs.into_iter().map(|previous_output| TxIn { previous_output, script_sig: ScriptBuf::new(), sequence: Sequence::MAX, witness: Witness::new() }).collect(),
+            output: outputs.into_iter().map(|(value, script_pubkey)| TxOut { value: Amount::from_sat(value), script_pubkey }).collect()
+        }
+    }
+
+    #[test]
+    fn test_sync() {
+        let wallet = |look_ahead| {
+            let mut master = MasterAccount::from_entropy(&[7u8; 16], 0, Network::Bitcoin, PASSPHRASE, None).unwrap();
+            let mut unlocker = Unlocker::new(master.encrypted(), PASSPHRASE, None, Network::Bitcoin, None).unwrap();
+            master.new_account(&mut unlocker, AccountAddressType::P2WPKH, 0, 0, look_ahead).unwrap();
+            master
+        };
+        let ahead = wallet(10);
+        let receive = (0..5).map(|kix| ahead.get((0, 0)).unwrap().get_key(kix).unwrap().script_pubkey.clone()).collect::<Vec<_>>();
+        let mut master = wallet(2);
+        let other = ScriptBuf::new_op_return([0u8; 4]);
+
+        // a payment to a key beyond the look ahead window, found as the one before it is used,
+        // and a spend mined in the same block as the payment it spends
+        let funding = transaction(vec!(OutPoint::null()), vec!((50000, receive[1].clone()), (20000, receive[2].clone())));
+        let spend = transaction(vec!(OutPoint { txid: funding.compute_txid(), vout: 0 }), vec!((49000, other.clone())));
+        let late = transaction(vec!(OutPoint::null()), vec!((30000, receive[3].clone())));
+        let pending = transaction(vec!(OutPoint { txid: late.compute_txid(), vout: 0 }), vec!((10000, other), (19000, receive[4].clone())));
+        let block = BlockHash::from_byte_array([1u8; 32]);
+        let mut source = Memory::default();
+        source.transactions.push((funding.clone(), Some((100, block))));
+        source.transactions.push((spend.clone(), Some((100, block))));
+        source.transactions.push((late.clone(), Some((101, BlockHash::all_zeros()))));
+        source.transactions.push((pending.clone(), None));
+
+        let mut utxos = UtxoSet::new();
+        let history = sync(&mut source, &mut master, &mut utxos).unwrap();
+        assert_eq!(history.len(), 4);
+        assert_eq!(history[3], (pending.compute_txid(), None));
+        assert!(history[..2].iter().all(|(_, confirmed)| *confirmed == Some((100, block))));
+        assert_eq!(utxos.get(&OutPoint { txid: funding.compute_txid(), vout: 0 }).unwrap().spent_by, Some((spend.compute_txid(), Some(block))));
+        assert_eq!(utxos.get(&OutPoint { txid: funding.compute_txid(), vout: 1 }).unwrap().confirmed, Some((100, block)));
+        assert!(utxos.get(&OutPoint { txid: pending.compute_txid(), vout: 1 }).unwrap().confirmed.is_none());
+        assert_eq!(utxos.balance().confirmed, Amount::from_sat(20000));
+
+        // a second sync only fetches what changed
+        assert_eq!(sync(&mut source, &mut master, &mut utxos).unwrap(), history);
+
+        // any source serves the single purpose traits
+        let mut boxed: Box<dyn ChainSource> = Box::new(source);
+        assert_eq!(boxed.tip().unwrap(), (101, BlockHash::all_zeros()));
+        assert_eq!(boxed.estimate(2).unwrap(), FeeRate::from_sat_per_vb(5).unwrap());
+        assert_eq!(boxed.used(&[receive[3].clone(), receive[0].clone()]).unwrap(), vec!(true, false));
+        let found = discovery::discover_chain(&mut boxed, master.get_mut((0, 0)).unwrap(), 2).unwrap();
+        assert_eq!(found, Some(4));
+    }
+}
@@ -0,0 +1,126 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Checkpoints
+//!
+//! Hashes of the most recent blocks the wallet processed, to notice when the chain reorganizes
+//!
+
+use std::collections::VecDeque;
+
+use bitcoin::BlockHash;
+
+/// checkpoints kept by default, deeper reorgs are not expected
+pub const DEFAULT_CAPACITY: usize = 100;
+
+/// block hashes by height of the recent trunk, in ascending height order
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Checkpoints {
+    blocks: VecDeque<(u32, BlockHash)>,
+    capacity: usize
+}
+
+impl Default for Checkpoints {
+    fn default() -> Checkpoints {
+        Checkpoints::new(DEFAULT_CAPACITY)
+    }
+}
+
+impl Checkpoints {
+    /// remember at most capacity blocks
+    pub fn new(capacity: usize) -> Checkpoints {
+        Checkpoints { blocks: VecDeque::new(), capacity }
+    }
+
+    /// height and hash of the highest block
+    pub fn tip(&self) -> Option<(u32, BlockHash)> {
+        self.blocks.back().cloned()
+    }
+
+    /// hash of the block at height if remembered
+    pub fn get(&self, height: u32) -> Option<BlockHash> {
+        self.blocks.iter().find(|(h, _)| *h == height).map(|(_, hash)| *hash)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=&(u32, BlockHash)> {
+        self.blocks.iter()
+    }
+
+    /// a block at height with parent prev_blockhash joins the trunk. Returns the hashes of
+    /// blocks no longer in the trunk, highest first, these have to be unwound before the block is processed.
+    /// Blocks may be skipped, the parent is only checked if remembered.
+    pub fn connect(&mut self, height: u32, block_hash: BlockHash, prev_blockhash: BlockHash) -> Vec<BlockHash> {
+        if self.get(height) == Some(block_hash) {
+            return Vec::new();
+        }
+        let parent_lost = height > 0 && self.get(height - 1).map(|parent| parent != prev_blockhash).unwrap_or(false);
+        let disconnected = self.disconnect_from(if parent_lost { height - 1 } else { height });
+        self.blocks.push_back((height, block_hash));
+        while self.blocks.len() > self.capacity {
+            self.blocks.pop_front();
+        }
+        disconnected
+    }
+
+    /// the backend reports that the trunk forked at height, forget blocks above it.
+    /// Returns their hashes highest first.
+    pub fn disconnect_above(&mut self, height: u32) -> Vec<BlockHash> {
+        self.disconnect_from(height.saturating_add(1))
+    }
+
+    fn disconnect_from(&mut self, height: u32) -> Vec<BlockHash> {
+        let mut disconnected = Vec::new();
+        while let Some((h, hash)) = self.blocks.back().cloned() {
+            if h < height {
+                break;
+            }
+            self.blocks.pop_back();
+            disconnected.push(hash);
+        }
+        disconnected
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin_hashes::Hash;
+
+    use super::*;
+
+    fn hash(n: u8) -> BlockHash {
+        BlockHash::from_byte_array([n; 32])
+    }
+
+    #[test]
+    fn test_checkpoints() {
+        let mut checkpoints = Checkpoints::new(3);
+        assert!(checkpoints.connect(1, hash(1), hash(0)).is_empty());
+        assert!(checkpoints.connect(2, hash(2), hash(1)).is_empty());
+        assert!(checkpoints.connect(2, hash(2), hash(1)).is_empty());
+        // a block skipped
+        assert!(checkpoints.connect(4, hash(4), hash(3)).is_empty());
+        assert!(checkpoints.connect(5, hash(5), hash(4)).is_empty());
+        assert_eq!(checkpoints.get(1), None);
+        assert_eq!(checkpoints.tip(), Some((5, hash(5))));
+
+        // competing block at the tip
+        assert_eq!(checkpoints.connect(5, hash(15), hash(4)), vec!(hash(5)));
+        // a child of an unknown parent
+        assert_eq!(checkpoints.connect(6, hash(16), hash(25)), vec!(hash(15)));
+        assert_eq!(checkpoints.iter().cloned().collect::<Vec<_>>(), vec!((2, hash(2)), (4, hash(4)), (6, hash(16))));
+        assert_eq!(checkpoints.disconnect_above(3), vec!(hash(16), hash(4)));
+    }
+}
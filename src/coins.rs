@@ -18,12 +18,12 @@
 //!
 //!
 
-use bitcoin_hashes::sha256d;
-use bitcoin::{OutPoint, TxOut, Script};
+use bitcoin::{OutPoint, Transaction, TxOut, ScriptBuf, Txid, BlockHash};
 use bitcoin::Block;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use account::{MasterAccount, KeyDerivation};
 use proved::ProvedTransaction;
+use error::WalletError;
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 /// a coin is defined by the spendable output
@@ -39,34 +39,60 @@ pub struct Coins {
     /// coins owned
     owned: HashMap<OutPoint, Coin>,
     /// SPV proofs of transactions holding owned coins
-    proofs: HashMap<sha256d::Hash, ProvedTransaction>,
+    proofs: HashMap<Txid, ProvedTransaction>,
+    /// coins the user does not want to spend, e.g. tainted or sentimental ones
+    frozen: HashSet<OutPoint>
+}
+
+impl Default for Coins {
+    fn default() -> Coins {
+        Coins::new()
+    }
 }
 
 impl Coins {
     pub fn new () -> Coins {
-        Coins { owned: HashMap::new(), proofs: HashMap::new() }
+        Coins { owned: HashMap::new(), proofs: HashMap::new(), frozen: HashSet::new() }
     }
 
     /// this should only be used to restore previously computed state
     pub fn add_from_storage(&mut self, point: OutPoint, coin: Coin, proof: ProvedTransaction) {
         self.owned.insert(point, coin);
-        self.proofs.insert(proof.get_transaction().txid(), proof);
+        self.proofs.insert(proof.get_transaction().compute_txid(), proof);
     }
 
     pub fn owned(&self) -> &HashMap<OutPoint, Coin> {
         &self.owned
     }
 
-    pub fn proofs(&self) -> &HashMap<sha256d::Hash, ProvedTransaction> {
+    pub fn proofs(&self) -> &HashMap<Txid, ProvedTransaction> {
         &self.proofs
     }
 
+    /// never select this coin for spending until unfrozen
+    pub fn freeze(&mut self, point: OutPoint) {
+        self.frozen.insert(point);
+    }
+
+    /// returns false if the coin was not frozen
+    pub fn unfreeze(&mut self, point: &OutPoint) -> bool {
+        self.frozen.remove(point)
+    }
+
+    pub fn is_frozen(&self, point: &OutPoint) -> bool {
+        self.frozen.contains(point)
+    }
+
+    pub fn frozen(&self) -> &HashSet<OutPoint> {
+        &self.frozen
+    }
+
     /// unwind the tip of the trunk
-    pub fn unwind_tip(&mut self, block_hash: &sha256d::Hash) {
+    pub fn unwind_tip(&mut self, block_hash: &BlockHash) {
         // this means we might have lost control of coins at least temporarily
         let lost_coins = self.proofs.values()
             .filter_map(|t| if *t.get_block_hash() == *block_hash {
-                Some(t.get_transaction().txid())
+                Some(t.get_transaction().compute_txid())
             } else { None })
             .flat_map(|txid| self.owned.keys().filter(move |point| point.txid == txid)).cloned().collect::<Vec<OutPoint>>();
 
@@ -78,50 +104,70 @@ impl Coins {
 
     /// process a block to find own coins
     /// processing should be in ascending height order, it is fine to skip blocks  if you know
-    /// there is nothing in them you would care (this will be easy to tell with committed BIP158
-    /// filters, but we are not yet there)
-    pub fn process(&mut self, master_account: &mut MasterAccount, block: &Block) {
-        let mut scripts: HashMap<Script, KeyDerivation> = master_account.get_scripts().collect();
+    /// there is nothing in them you would care (BIP158 filters tell, see filters::scan)
+    pub fn process(&mut self, master_account: &mut MasterAccount, block: &Block) -> Result<(), WalletError> {
+        let mut scripts: HashMap<ScriptBuf, KeyDerivation> = master_account.get_scripts().collect();
 
         for (txnr, tx) in block.txdata.iter().enumerate() {
-            for input in tx.input.iter().skip(1) {
+            self.process_transaction(master_account, &mut scripts, tx, || ProvedTransaction::new(block, txnr))?;
+        }
+        Ok(())
+    }
+
+    /// process a transaction confirmed with an SPV proof
+    /// as with blocks, transactions should be processed in the order they were confirmed
+    pub fn process_proved(&mut self, master_account: &mut MasterAccount, proof: &ProvedTransaction) -> Result<(), WalletError> {
+        let mut scripts: HashMap<ScriptBuf, KeyDerivation> = master_account.get_scripts().collect();
+        let tx = proof.get_transaction();
+        self.process_transaction(master_account, &mut scripts, &tx, || proof.clone())
+    }
+
+    fn process_transaction<P>(&mut self, master_account: &mut MasterAccount, scripts: &mut HashMap<ScriptBuf, KeyDerivation>, tx: &Transaction, proof: P) -> Result<(), WalletError>
+        where P: Fn() -> ProvedTransaction {
+        if !tx.is_coinbase() {
+            for input in tx.input.iter() {
                 self.owned.remove(&input.previous_output);
-                if self.owned.iter().any(|(point,_)| point.txid == input.previous_output.txid) == false {
+                self.frozen.remove(&input.previous_output);
+                if !self.owned.iter().any(|(point,_)| point.txid == input.previous_output.txid) {
                     self.proofs.remove(&input.previous_output.txid);
                 }
             }
-            for (vout, output) in tx.output.iter().enumerate() {
-                let mut lookahead = Vec::new();
-                if let Some(d) = scripts.get(&output.script_pubkey) {
-                    let seen = d.kix;
-                    lookahead =
-                        master_account.get_mut((d.account, d.sub)).unwrap().do_look_ahead(seen).unwrap()
-                            .iter().map(move |(kix, s)| (s.clone(), KeyDerivation{ kix: *kix, account: d.account, sub: d.sub, tweak: d.tweak.clone()})).collect();
-                    self.owned.insert(OutPoint { txid: tx.txid(), vout: vout as u32 },
-                                      Coin { output: output.clone(), derivation: d.clone()});
-                    self.proofs.entry(tx.txid()).or_insert(ProvedTransaction::new(block, txnr));
-                }
-                for (s, d) in lookahead {
-                    scripts.insert(s.clone(), d);
-                }
+        }
+        for (vout, output) in tx.output.iter().enumerate() {
+            let mut lookahead = Vec::new();
+            if let Some(d) = scripts.get(&output.script_pubkey) {
+                let seen = d.kix;
+                let account = master_account.get_mut((d.account, d.sub)).ok_or(WalletError::Unsupported("script of an unknown account"))?;
+                account.record_receipt(seen, tx.compute_txid());
+                lookahead =
+                    account.do_look_ahead(seen)?
+                        .iter().map(move |(kix, s)| (s.clone(), KeyDerivation{ kix: *kix, account: d.account, sub: d.sub, tweak: d.tweak.clone()})).collect();
+                self.owned.insert(OutPoint { txid: tx.compute_txid(), vout: vout as u32 },
+                                  Coin { output: output.clone(), derivation: d.clone()});
+                self.proofs.entry(tx.compute_txid()).or_insert_with(&proof);
+            }
+            for (s, d) in lookahead {
+                scripts.insert(s.clone(), d);
             }
         }
+        Ok(())
     }
 
-    /// get random owned coins of sufficient amount that pass a filter
+    /// get random owned coins of sufficient amount that pass a filter, frozen coins are not considered
     pub fn get_coins<V> (&self,  minimum: u64, filter: V) -> Vec<(OutPoint, Coin)>
-        where V: Fn(&sha256d::Hash, &OutPoint, &Coin) -> bool {
+        where V: Fn(&BlockHash, &OutPoint, &Coin) -> bool {
         let mut sum = 0u64;
 
         self.owned.iter()
+            .filter(|(point, _)| !self.frozen.contains(point))
             .filter_map(|(point, details)| {
                 let details = details.clone();
-                if filter(self.proofs.get(&point.txid).unwrap().get_block_hash(), &point, &details) {
-                    Some((point.clone(), details))
+                if filter(self.proofs.get(&point.txid)?.get_block_hash(), point, &details) {
+                    Some((*point, details))
                 } else {
                     None
                 }
             }
-            ).take_while(move |(_,d)| {sum += d.output.value; sum < minimum}).collect()
+            ).take_while(move |(_,d)| {sum += d.output.value.to_sat(); sum < minimum}).collect()
     }
 }
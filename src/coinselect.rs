@@ -0,0 +1,363 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Coin selection
+//!
+//! Strategies to pick owned coins that fund a payment and its fee
+//!
+
+use std::cmp::Reverse;
+
+use bitcoin::{FeeRate, OutPoint, Script};
+use secp256k1::rand::{thread_rng, seq::SliceRandom};
+
+use account::{AccountAddressType, MasterAccount};
+use coins::Coin;
+use error::WalletError;
+
+/// weight of version, locktime, segwit marker and flag, input and output counts
+pub const TRANSACTION_OVERHEAD_WEIGHT: u64 = 4 * (4 + 4 + 1 + 1) + 2;
+
+/// maximum number of branches branch and bound explores before giving up
+const BNB_MAX_TRIES: usize = 100_000;
+
+/// a coin that might be selected with the weight an input spending it adds to a transaction
+#[derive(Clone, Debug)]
+pub struct Candidate {
+    pub point: OutPoint,
+    pub coin: Coin,
+    pub weight: u64
+}
+
+/// what a selection has to pay for
+#[derive(Clone, Copy, Debug)]
+pub struct Target {
+    /// amount paid to the recipients
+    pub amount: u64,
+    /// fee rate the transaction should achieve
+    pub fee_rate: FeeRate,
+    /// weight of the transaction without inputs and change
+    pub base_weight: u64,
+    /// weight of a change output
+    pub change_weight: u64,
+    /// the smallest change worth an output, surplus below goes to the fee
+    pub min_change: u64
+}
+
+/// result of a coin selection
+#[derive(Clone, Debug)]
+pub struct Selection {
+    /// selected coins
+    pub coins: Vec<(OutPoint, Coin)>,
+    /// fee of the funded transaction
+    pub fee: u64,
+    /// value of the change output, zero if no change output is needed
+    pub change: u64
+}
+
+/// a strategy to select coins
+pub trait CoinSelector {
+    /// select from the candidates to fund the target
+    fn select(&self, candidates: &[Candidate], target: &Target) -> Result<Selection, WalletError>;
+}
+
+/// spend largest coins first, this minimizes the number of inputs
+pub struct LargestFirst;
+
+impl CoinSelector for LargestFirst {
+    fn select(&self, candidates: &[Candidate], target: &Target) -> Result<Selection, WalletError> {
+        let mut sorted = candidates.to_vec();
+        sorted.sort_by_key(|c| Reverse(c.coin.output.value));
+        accumulate(sorted, target)
+    }
+}
+
+/// spend coins in random order until the target is met
+pub struct SingleRandomDraw;
+
+impl CoinSelector for SingleRandomDraw {
+    fn select(&self, candidates: &[Candidate], target: &Target) -> Result<Selection, WalletError> {
+        let mut shuffled = candidates.to_vec();
+        shuffled.shuffle(&mut thread_rng());
+        accumulate(shuffled, target)
+    }
+}
+
+/// search for a set of coins that funds the target without change
+/// falls back to the fallback strategy if there is no such set
+pub struct BranchAndBound<F: CoinSelector> {
+    pub fallback: F
+}
+
+impl Default for BranchAndBound<SingleRandomDraw> {
+    fn default() -> Self {
+        BranchAndBound { fallback: SingleRandomDraw }
+    }
+}
+
+impl<F: CoinSelector> CoinSelector for BranchAndBound<F> {
+    fn select(&self, candidates: &[Candidate], target: &Target) -> Result<Selection, WalletError> {
+        // only coins worth more than it costs to spend them are considered
+        let mut pool = candidates.iter()
+            .map(|c| (effective_value(c, target.fee_rate), c))
+            .filter(|(v, _)| *v > 0)
+            .collect::<Vec<_>>();
+        pool.sort_by_key(|(v, _)| Reverse(*v));
+
+        let low = (target.amount + fee(target.fee_rate, target.base_weight)) as i64;
+        let high = low + (fee(target.fee_rate, target.change_weight) + target.min_change) as i64;
+
+        let mut remaining = pool.iter().map(|(v, _)| *v).sum::<i64>();
+        if remaining < low {
+            return self.fallback.select(candidates, target);
+        }
+
+        // depth first search, left branch includes the coin
+        let mut included = vec!(false; pool.len());
+        let mut best: Option<(i64, Vec<bool>)> = None;
+        let mut value = 0i64;
+        let mut depth = 0usize;
+        for _ in 0..BNB_MAX_TRIES {
+            let backtrack = if value + remaining < low || value > high {
+                true
+            } else if value >= low {
+                if best.as_ref().map(|(w, _)| value - low < *w).unwrap_or(true) {
+                    best = Some((value - low, included.clone()));
+                }
+                true
+            } else {
+                false
+            };
+            if backtrack {
+                // walk back to the last included coin and exclude it
+                while depth > 0 && !included[depth - 1] {
+                    depth -= 1;
+                    remaining += pool[depth].0;
+                }
+                if depth == 0 {
+                    break;
+                }
+                included[depth - 1] = false;
+                value -= pool[depth - 1].0;
+            } else {
+                remaining -= pool[depth].0;
+                included[depth] = true;
+                value += pool[depth].0;
+                depth += 1;
+            }
+        }
+
+        if let Some((_, included)) = best {
+            let coins = pool.iter().zip(included.iter()).filter(|(_, i)| **i)
+                .map(|((_, c), _)| (c.point, c.coin.clone())).collect::<Vec<_>>();
+            let sum = coins.iter().map(|(_, c)| c.output.value.to_sat()).sum::<u64>();
+            Ok(Selection { coins, fee: sum - target.amount, change: 0 })
+        } else {
+            self.fallback.select(candidates, target)
+        }
+    }
+}
+
+/// select candidates in the given order until the target and fees are covered
+fn accumulate(candidates: Vec<Candidate>, target: &Target) -> Result<Selection, WalletError> {
+    let mut coins = Vec::new();
+    let mut weight = target.base_weight;
+    let mut sum = 0u64;
+    for candidate in candidates {
+        weight += candidate.weight;
+        sum += candidate.coin.output.value.to_sat();
+        coins.push((candidate.point, candidate.coin));
+        let fee_without_change = fee(target.fee_rate, weight);
+        if sum >= target.amount + fee_without_change {
+            let fee_with_change = fee(target.fee_rate, weight + target.change_weight);
+            if sum >= target.amount + fee_with_change + target.min_change {
+                return Ok(Selection { coins, fee: fee_with_change, change: sum - target.amount - fee_with_change });
+            }
+            return Ok(Selection { coins, fee: sum - target.amount, change: 0 });
+        }
+    }
+    Err(WalletError::InsufficientFunds)
+}
+
+/// value of a coin less the fee of spending it
+fn effective_value(candidate: &Candidate, fee_rate: FeeRate) -> i64 {
+    candidate.coin.output.value.to_sat() as i64 - fee(fee_rate, candidate.weight) as i64
+}
+
+/// fee for some weight at a fee rate rounded up to the next satoshi
+pub fn fee(fee_rate: FeeRate, weight: u64) -> u64 {
+    (fee_rate.to_sat_per_kwu() * weight).div_ceil(1000)
+}
+
+/// a candidate of an owned coin, none if the master account does not know its key
+pub fn candidate(master: &MasterAccount, point: &OutPoint, coin: &Coin) -> Option<Candidate> {
+    master.get_key_for_script(&coin.output.script_pubkey).map(|(account, key)| Candidate {
+        point: *point,
+        coin: coin.clone(),
+        weight: input_weight(account.address_type(), &key.script_code)
+    })
+}
+
+/// weight of an input spending an address type, script_code is only used for P2WSH
+pub fn input_weight(address_type: AccountAddressType, script_code: &Script) -> u64 {
+    // outpoint, sequence and script length
+    let base = 32 + 4 + 4 + 1;
+    // signature with sighash type and public key pushes
+    let signature = 1 + 72;
+    let key = 1 + 33;
+    match address_type {
+        AccountAddressType::P2PKH => 4 * (base + signature + key),
+        AccountAddressType::P2SHWPKH => 4 * (base + 23) + 1 + signature + key,
+        AccountAddressType::P2WPKH => 4 * base + 1 + signature + key,
+        // a single schnorr signature with default sighash type
+        AccountAddressType::P2TR => 4 * base + 1 + 1 + 64,
+        AccountAddressType::P2WSH(_) => 4 * base + 1 + signature + varint_len(script_code.len()) + script_code.len() as u64,
+    }
+}
+
+/// weight of an output paying to a script
+pub fn output_weight(script_pubkey: &Script) -> u64 {
+    4 * (8 + varint_len(script_pubkey.len()) + script_pubkey.len() as u64)
+}
+
+/// weight of a transaction spending inputs of address types, with script codes only used for P2WSH, to output scripts.
+/// Signatures count 72 bytes, the most a low S signature takes, so the estimate does not fall short.
+/// Without inputs it is the weight of a segwit transaction with the outputs, to display fees before coins are selected.
+pub fn estimate_weight(inputs: &[(AccountAddressType, &Script)], outputs: &[&Script]) -> u64 {
+    let legacy = !inputs.is_empty() && inputs.iter().all(|(address_type, _)| *address_type == AccountAddressType::P2PKH);
+    let counts = 4 * (varint_len(inputs.len()) + varint_len(outputs.len()) - 2);
+    let inputs_weight = inputs.iter().map(|(address_type, script_code)| match address_type {
+        // an empty witness next to segwit inputs
+        AccountAddressType::P2PKH if !legacy => input_weight(*address_type, script_code) + 1,
+        _ => input_weight(*address_type, script_code)
+    }).sum::<u64>();
+    let weight = TRANSACTION_OVERHEAD_WEIGHT + counts + inputs_weight + outputs.iter().map(|s| output_weight(s)).sum::<u64>();
+    // no segwit marker and flag
+    if legacy { weight - 2 } else { weight }
+}
+
+/// virtual size of `estimate_weight`
+pub fn estimate_vsize(inputs: &[(AccountAddressType, &Script)], outputs: &[&Script]) -> u64 {
+    estimate_weight(inputs, outputs).div_ceil(4)
+}
+
+/// bytes of the compact size encoding of a length
+fn varint_len(n: usize) -> u64 {
+    match n {
+        0..=0xfc => 1,
+        0xfd..=0xffff => 3,
+        _ => 5
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{Amount, ScriptBuf, TxOut, Txid};
+    use bitcoin_hashes::Hash;
+
+    use account::KeyDerivation;
+    use super::*;
+
+    fn candidates(values: &[u64]) -> Vec<Candidate> {
+        values.iter().enumerate().map(|(vout, value)| Candidate {
+            point: OutPoint { txid: Txid::all_zeros(), vout: vout as u32 },
+            coin: Coin {
+                output: TxOut { value: Amount::from_sat(*value), script_pubkey: ScriptBuf::new() },
+                derivation: KeyDerivation { account: 0, sub: 0, kix: vout as u32, tweak: None }
+            },
+            weight: 272
+        }).collect()
+    }
+
+    fn target(amount: u64) -> Target {
+        Target {
+            amount,
+            fee_rate: FeeRate::from_sat_per_vb(1).unwrap(),
+            base_weight: TRANSACTION_OVERHEAD_WEIGHT + 124,
+            change_weight: 124,
+            min_change: 546
+        }
+    }
+
+    fn total(selection: &Selection) -> u64 {
+        selection.coins.iter().map(|(_, c)| c.output.value.to_sat()).sum()
+    }
+
+    #[test]
+    fn test_estimate_weight() {
+        use bitcoin::{Sequence, Transaction, TxIn, Witness};
+        use bitcoin::absolute::LockTime;
+        use bitcoin::script::Builder;
+        use bitcoin::transaction::Version;
+
+        let signature = [1u8; 72];
+        let key = [2u8; 33];
+        let input = |script_sig: ScriptBuf, witness: &[&[u8]]| TxIn {
+            previous_output: OutPoint::null(), script_sig, sequence: Sequence::MAX, witness: Witness::from_slice(witness)
+        };
+        let p2pkh = || input(Builder::new().push_slice(signature).push_slice(key).into_script(), &[]);
+        let p2wpkh = || input(ScriptBuf::new(), &[&signature[..], &key[..]]);
+        let p2shwpkh = || input(Builder::new().push_slice([0u8; 22]).into_script(), &[&signature[..], &key[..]]);
+        let p2tr = || input(ScriptBuf::new(), &[&[3u8; 64][..]]);
+        let script_code = ScriptBuf::from(vec!(0x51u8; 300));
+        let p2wsh = || input(ScriptBuf::new(), &[&signature[..], script_code.as_bytes()]);
+        let outputs = [ScriptBuf::from(vec!(0u8; 22)), ScriptBuf::from(vec!(0u8; 34))];
+        let transaction = |input: Vec<TxIn>| Transaction {
+            version: Version::TWO, lock_time: LockTime::ZERO, input,
+            output: outputs.iter().map(|s| TxOut { value: Amount::ZERO, script_pubkey: s.clone() }).collect()
+        };
+        let outputs = outputs.iter().map(|s| s.as_script()).collect::<Vec<_>>();
+        let empty = ScriptBuf::new();
+        assert_eq!(estimate_weight(&[(AccountAddressType::P2PKH, &empty)], &outputs), transaction(vec!(p2pkh())).weight().to_wu());
+        let mixed = transaction(vec!(p2pkh(), p2shwpkh(), p2wpkh(), p2tr(), p2wsh()));
+        assert_eq!(estimate_weight(&[(AccountAddressType::P2PKH, &empty), (AccountAddressType::P2SHWPKH, &empty), (AccountAddressType::P2WPKH, &empty), (AccountAddressType::P2TR, &empty),
+                                     (AccountAddressType::P2WSH(0), &script_code)], &outputs), mixed.weight().to_wu());
+        assert_eq!(estimate_vsize(&[(AccountAddressType::P2WPKH, &empty)], &outputs), transaction(vec!(p2wpkh())).vsize() as u64);
+        let many = transaction((0..300).map(|_| p2wpkh()).collect());
+        assert_eq!(estimate_weight(&vec!((AccountAddressType::P2WPKH, empty.as_script()); 300), &outputs), many.weight().to_wu());
+    }
+
+    #[test]
+    fn test_largest_first() {
+        let selection = LargestFirst.select(&candidates(&[1000, 50000, 20000]), &target(30000)).unwrap();
+        assert_eq!(selection.coins.len(), 1);
+        assert_eq!(total(&selection), 50000);
+        assert_eq!(total(&selection), 30000 + selection.fee + selection.change);
+        assert!(selection.change > 0);
+        assert!(LargestFirst.select(&candidates(&[1000, 2000]), &target(30000)).is_err());
+    }
+
+    #[test]
+    fn test_single_random_draw() {
+        let selection = SingleRandomDraw.select(&candidates(&[10000, 10000, 10000, 10000]), &target(25000)).unwrap();
+        assert_eq!(selection.coins.len(), 3);
+        assert_eq!(total(&selection), 25000 + selection.fee + selection.change);
+    }
+
+    #[test]
+    fn test_branch_and_bound() {
+        let target = target(30000);
+        // 20000 and 10178 pay exactly amount and fee of a transaction with two inputs
+        let selection = BranchAndBound::default().select(&candidates(&[100000, 30, 20000, 10178, 7000]), &target).unwrap();
+        assert_eq!(selection.change, 0);
+        assert_eq!(selection.coins.len(), 2);
+        assert_eq!(total(&selection), 30000 + selection.fee);
+
+        // no changeless solution falls back
+        let selection = BranchAndBound { fallback: LargestFirst }.select(&candidates(&[100000]), &target).unwrap();
+        assert!(selection.change > 0);
+    }
+}
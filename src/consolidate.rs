@@ -0,0 +1,155 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Consolidation
+//!
+//! Sweep many small coins into one while fees are low
+//!
+
+use bitcoin::{Amount, FeeRate, OutPoint, Psbt, TxOut};
+use bitcoin::absolute::LockTime;
+
+use account::MasterAccount;
+use coins::Coins;
+use coinselect;
+use error::WalletError;
+use psbt;
+
+/// what to consolidate
+#[derive(Clone, Copy, Debug)]
+pub struct Policy {
+    /// consolidate only if the current fee rate is at most this
+    pub threshold: FeeRate,
+    /// fee rate expected when the coins would be spent otherwise
+    pub future_fee_rate: FeeRate,
+    /// coins of at most this value are swept
+    pub max_value: u64,
+    /// at most this many coins are swept in one transaction
+    pub max_inputs: usize
+}
+
+/// a proposed consolidation
+pub struct Plan {
+    /// unsigned transaction sweeping the coins to the next key of the destination account
+    pub psbt: Psbt,
+    /// coins swept
+    pub inputs: Vec<OutPoint>,
+    /// fee paid now
+    pub cost: u64,
+    /// fee the swept coins would cost at the future fee rate, less that of spending the consolidated output
+    pub savings: u64
+}
+
+impl Plan {
+    /// savings less cost, positive if consolidating pays off
+    pub fn net(&self) -> i64 {
+        self.savings as i64 - self.cost as i64
+    }
+}
+
+/// propose to sweep small coins of the master into one output to the account (account, sub_account).
+/// None if the fee rate is above the threshold of the policy, fewer than two coins qualify, or
+/// consolidating would cost more than it saves. Frozen coins and coins not worth their input are left alone.
+pub fn plan(master: &mut MasterAccount, coins: &Coins, fee_rate: FeeRate, policy: &Policy, destination: (u32, u32)) -> Result<Option<Plan>, WalletError> {
+    if fee_rate > policy.threshold {
+        return Ok(None);
+    }
+    let mut candidates = coins.owned().iter()
+        .filter(|(point, coin)| !coins.is_frozen(point) && coin.output.value.to_sat() <= policy.max_value)
+        .filter_map(|(point, coin)| coinselect::candidate(master, point, coin))
+        .filter(|c| c.coin.output.value.to_sat() > coinselect::fee(fee_rate, c.weight))
+        .collect::<Vec<_>>();
+    if candidates.len() < 2 {
+        return Ok(None);
+    }
+    // smallest first, they are the most expensive to spend later
+    candidates.sort_by_key(|c| (c.coin.output.value, c.point));
+    candidates.truncate(policy.max_inputs);
+
+    let account = master.get_mut(destination).ok_or(WalletError::UnknownAccount { account: destination.0, sub_account: destination.1 })?;
+    let address_type = account.address_type();
+    let key = account.next_key()?;
+    let script_pubkey = key.script_pubkey.clone();
+    let consolidated_weight = coinselect::input_weight(address_type, &key.script_code);
+
+    let input_weight = candidates.iter().map(|c| c.weight).sum::<u64>();
+    let weight = coinselect::estimate_weight(&[], &[&script_pubkey]) + input_weight;
+    let cost = coinselect::fee(fee_rate, weight);
+    let savings = coinselect::fee(policy.future_fee_rate, input_weight)
+        .saturating_sub(coinselect::fee(policy.future_fee_rate, consolidated_weight));
+    if savings <= cost {
+        return Ok(None);
+    }
+    let value = candidates.iter().map(|c| c.coin.output.value.to_sat()).sum::<u64>() - cost;
+    if value < script_pubkey.minimal_non_dust().to_sat() {
+        return Ok(None);
+    }
+    let inputs = candidates.iter().map(|c| c.point).collect::<Vec<_>>();
+    let psbt = psbt::create(master, coins, &inputs, vec!(TxOut { value: Amount::from_sat(value), script_pubkey }), LockTime::ZERO)?;
+    Ok(Some(Plan { psbt, inputs, cost, savings }))
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{Block, BlockHash, CompactTarget, Network, ScriptBuf, Sequence, Transaction, TxIn, TxMerkleNode, Txid, Witness};
+    use bitcoin::block::{Header, Version as BlockVersion};
+    use bitcoin::transaction::Version;
+    use bitcoin_hashes::Hash;
+
+    use account::{AccountAddressType, MasterKeyEntropy, Unlocker};
+    use super::*;
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+
+    #[test]
+    fn test_consolidation() {
+        let mut master = MasterAccount::new(MasterKeyEntropy::Low, Network::Bitcoin, PASSPHRASE, None).unwrap();
+        let mut unlocker = Unlocker::new(master.encrypted(), PASSPHRASE, None, Network::Bitcoin, None).unwrap();
+        master.new_account(&mut unlocker, AccountAddressType::P2WPKH, 0, 0, 10).unwrap();
+        master.new_account(&mut unlocker, AccountAddressType::P2WPKH, 0, 1, 10).unwrap();
+        let scripts = (0..5).map(|kix| master.get((0, 0)).unwrap().get_key(kix).unwrap().script_pubkey.clone()).collect::<Vec<_>>();
+        let funding = Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec!(TxIn { previous_output: OutPoint { txid: Txid::all_zeros(), vout: 0 }, script_sig: ScriptBuf::new(), sequence: Sequence::MAX, witness: Witness::new() }),
+            output: [2000u64, 3000, 4000, 500000, 100].iter().zip(scripts).map(|(value, script_pubkey)| TxOut { value: Amount::from_sat(*value), script_pubkey }).collect()
+        };
+        let txid = funding.compute_txid();
+        let block = Block {
+            header: Header { version: BlockVersion::ONE, prev_blockhash: BlockHash::all_zeros(), merkle_root: TxMerkleNode::all_zeros(), time: 0, bits: CompactTarget::from_consensus(0), nonce: 0 },
+            txdata: vec!(funding)
+        };
+        let mut coins = Coins::new();
+        coins.process(&mut master, &block).unwrap();
+        coins.freeze(OutPoint { txid, vout: 2 });
+
+        let policy = Policy {
+            threshold: FeeRate::from_sat_per_vb(3).unwrap(),
+            future_fee_rate: FeeRate::from_sat_per_vb(50).unwrap(),
+            max_value: 10000,
+            max_inputs: 100
+        };
+        assert!(plan(&mut master, &coins, FeeRate::from_sat_per_vb(10).unwrap(), &policy, (0, 1)).unwrap().is_none());
+
+        let fee_rate = FeeRate::from_sat_per_vb(2).unwrap();
+        let plan = plan(&mut master, &coins, fee_rate, &policy, (0, 1)).unwrap().unwrap();
+        // the frozen, the large and the dust coin stay
+        assert_eq!(plan.inputs, vec!(OutPoint { txid, vout: 0 }, OutPoint { txid, vout: 1 }));
+        assert_eq!(plan.psbt.unsigned_tx.output.len(), 1);
+        assert_eq!(plan.psbt.unsigned_tx.output[0].value.to_sat(), 5000 - plan.cost);
+        assert!(plan.net() > 0);
+    }
+}
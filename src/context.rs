@@ -16,59 +16,196 @@
 //!
 //! # Key derivation
 //!
+use std::ops::Range;
+
 use bitcoin::{
-    PublicKey, PrivateKey,
-    network::constants::Network
+    Address, PublicKey, PrivateKey,
+    Network
 };
-use bitcoin::util::bip32::{ExtendedPubKey, ExtendedPrivKey,ChildNumber};
-use secp256k1::{All,Secp256k1, Message, Signature};
+use bitcoin::bip32::{Xpub, Xpriv, ChildNumber};
+use bitcoin::key::TapTweak;
+use secp256k1::{All, Keypair, Secp256k1, Message, Scalar, XOnlyPublicKey, ecdsa::{RecoverableSignature, Signature}, schnorr};
+use secp256k1::rand::{thread_rng, RngCore};
 use error::WalletError;
 use account::Seed;
 
+/// a signature hash to sign, ECDSA or BIP340 for taproot key path spends
+#[derive(Clone, Copy, Debug)]
+pub enum SigningHash {
+    Ecdsa([u8; 32]),
+    Schnorr([u8; 32])
+}
+
+/// signature of a `SigningHash`
+#[derive(Clone, Copy, Debug)]
+pub enum AnySignature {
+    Ecdsa(Signature),
+    Schnorr(schnorr::Signature)
+}
+
 pub struct SecpContext {
-    secp: Secp256k1<All>
+    secp: Secp256k1<All>,
+    extra_entropy: bool
+}
+
+impl Default for SecpContext {
+    fn default() -> SecpContext {
+        SecpContext::new()
+    }
 }
 
 impl SecpContext {
     pub fn new() -> SecpContext {
         SecpContext {
-            secp: Secp256k1::new()
+            secp: Secp256k1::new(),
+            extra_entropy: false
         }
     }
 
+    /// mix fresh randomness into RFC6979 nonces of ECDSA signatures
+    /// protects against nonces leaking the key through a flawed RFC6979 implementation or fault attacks
+    pub fn set_extra_entropy(&mut self, extra_entropy: bool) {
+        self.extra_entropy = extra_entropy;
+    }
+
+    pub fn extra_entropy(&self) -> bool {
+        self.extra_entropy
+    }
+
     /// create a master private key from seed
-    pub fn master_private_key(&self, network: Network, seed: &Seed) -> Result<ExtendedPrivKey, WalletError> {
-        Ok(ExtendedPrivKey::new_master (network, &seed.0)?)
+    pub fn master_private_key(&self, network: Network, seed: &Seed) -> Result<Xpriv, WalletError> {
+        Ok(Xpriv::new_master (network, &seed.0)?)
     }
 
     /// get extended public key for a known private key
-    pub fn extended_public_from_private(&self, extended_private_key: &ExtendedPrivKey) -> ExtendedPubKey {
-        ExtendedPubKey::from_private(&self.secp, extended_private_key)
+    pub fn extended_public_from_private(&self, extended_private_key: &Xpriv) -> Xpub {
+        Xpub::from_priv(&self.secp, extended_private_key)
     }
 
-    pub fn private_child (&self, extended_private_key: &ExtendedPrivKey, child: ChildNumber) -> Result<ExtendedPrivKey, WalletError> {
-        Ok(extended_private_key.ckd_priv(&self.secp, child)?)
+    pub fn private_child (&self, extended_private_key: &Xpriv, child: ChildNumber) -> Result<Xpriv, WalletError> {
+        Ok(extended_private_key.derive_priv(&self.secp, &[child])?)
     }
 
-    pub fn public_child (&self, extended_public_key: &ExtendedPubKey, child: ChildNumber) -> Result<ExtendedPubKey, WalletError> {
+    pub fn public_child (&self, extended_public_key: &Xpub, child: ChildNumber) -> Result<Xpub, WalletError> {
         Ok(extended_public_key.ckd_pub(&self.secp, child)?)
     }
 
+    /// public keys of a contiguous range of non-hardened children, all derived with this context
+    pub fn public_children(&self, extended_public_key: &Xpub, range: Range<u32>) -> Result<Vec<PublicKey>, WalletError> {
+        range.map(|index| Ok(PublicKey::new(self.public_child(extended_public_key, ChildNumber::Normal { index })?.public_key))).collect()
+    }
+
     pub fn public_from_private(&self, private: &PrivateKey) -> PublicKey {
         PublicKey::from_private_key(&self.secp, private)
     }
 
+    /// ECDSA signature with a RFC6979 deterministic nonce, see set_extra_entropy
     pub fn sign(&self, digest: &[u8], key: &PrivateKey) -> Result<Signature, WalletError>{
-        Ok(self.secp.sign(&Message::from_slice(digest)?, &key.key))
+        let message = Message::from_digest_slice(digest)?;
+        if self.extra_entropy {
+            Ok(self.secp.sign_ecdsa_with_noncedata(&message, &key.inner, &Self::random()))
+        } else {
+            Ok(self.secp.sign_ecdsa(&message, &key.inner))
+        }
+    }
+
+    /// true if the ECDSA signature of the digest is by the key
+    pub fn verify(&self, digest: &[u8; 32], signature: &Signature, public: &PublicKey) -> bool {
+        self.secp.verify_ecdsa(&Message::from_digest(*digest), signature, &public.inner).is_ok()
+    }
+
+    /// true if the BIP340 signature of the digest is by the key
+    pub fn verify_schnorr(&self, digest: &[u8; 32], signature: &schnorr::Signature, public: &XOnlyPublicKey) -> bool {
+        self.secp.verify_schnorr(signature, &Message::from_digest(*digest), public).is_ok()
+    }
+
+    /// signature the public key can be recovered from, as used by legacy message signing
+    pub fn sign_recoverable(&self, digest: &[u8], key: &PrivateKey) -> Result<RecoverableSignature, WalletError> {
+        let message = Message::from_digest_slice(digest)?;
+        if self.extra_entropy {
+            Ok(self.secp.sign_ecdsa_recoverable_with_noncedata(&message, &key.inner, &Self::random()))
+        } else {
+            Ok(self.secp.sign_ecdsa_recoverable(&message, &key.inner))
+        }
+    }
+
+    fn random() -> [u8; 32] {
+        let mut data = [0u8; 32];
+        thread_rng().fill_bytes(&mut data);
+        data
+    }
+
+    /// BIP340 signature with the BIP86 tweaked key, as needed to spend a taproot output on the key path
+    pub fn sign_schnorr(&self, digest: &[u8], key: &PrivateKey) -> Result<schnorr::Signature, WalletError> {
+        let keypair = Keypair::from_secret_key(&self.secp, &key.inner).tap_tweak(&self.secp, None);
+        Ok(self.secp.sign_schnorr(&Message::from_digest_slice(digest)?, &keypair.to_keypair()))
+    }
+
+    /// signatures of signature hashes in the order given
+    /// spread across threads with the parallel feature, as consolidations may sign hundreds of inputs
+    pub fn sign_all(&self, work: &[(SigningHash, PrivateKey)]) -> Result<Vec<AnySignature>, WalletError> {
+        #[cfg(feature = "parallel")]
+        use rayon::prelude::*;
+        #[cfg(feature = "parallel")]
+        let work = work.par_iter();
+        #[cfg(not(feature = "parallel"))]
+        let work = work.iter();
+        work.map(|(hash, key)| match hash {
+            SigningHash::Ecdsa(digest) => Ok(AnySignature::Ecdsa(self.sign(digest, key)?)),
+            SigningHash::Schnorr(digest) => Ok(AnySignature::Schnorr(self.sign_schnorr(digest, key)?))
+        }).collect()
+    }
+
+    /// BIP86 pay to taproot address of an internal key without script tree
+    pub fn p2tr_address(&self, internal: &PublicKey, network: Network) -> Address {
+        Address::p2tr(&self.secp, XOnlyPublicKey::from(internal.inner), None, network)
     }
 
     pub fn tweak_add(&self, key: &mut PrivateKey, tweak: &[u8]) -> Result<(), WalletError> {
-        key.key.add_assign(tweak)?;
+        key.inner = key.inner.add_tweak(&Self::scalar(tweak)?)?;
         Ok(())
     }
 
     pub fn tweak_exp_add(&self, key: &mut PublicKey, tweak: &[u8]) -> Result<(), WalletError> {
-        key.key.add_exp_assign(&self.secp, tweak)?;
+        key.inner = key.inner.add_exp_tweak(&self.secp, &Self::scalar(tweak)?)?;
         Ok(())
     }
+
+    fn scalar(tweak: &[u8]) -> Result<Scalar, WalletError> {
+        let mut bytes = [0u8; 32];
+        if tweak.len() != bytes.len() {
+            return Err(WalletError::SecpError(secp256k1::Error::InvalidTweak));
+        }
+        bytes.copy_from_slice(tweak);
+        Scalar::from_be_bytes(bytes).map_err(|_| WalletError::SecpError(secp256k1::Error::InvalidTweak))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::Network;
+    use bitcoin_hashes::{sha256, Hash};
+
+    use super::*;
+
+    #[test]
+    fn test_nonces() {
+        let mut context = SecpContext::new();
+        let mut one = [0u8; 32];
+        one[31] = 1;
+        let key = PrivateKey::from_slice(&one, Network::Bitcoin).unwrap();
+        let public = context.public_from_private(&key);
+        let digest = sha256::Hash::hash(b"Satoshi Nakamoto").to_byte_array();
+        // RFC6979 test vector of key 1
+        let signature = context.sign(&digest, &key).unwrap();
+        assert_eq!(signature.serialize_compact().iter().map(|b| format!("{:02x}", b)).collect::<String>(),
+                   "934b1ea10a4b3c1757e2b0c017d0b6143ce3c9a7e6a4a49860d7a6ab210ee3d82442ce9d2b916064108014783e923ec36b49743e2ffa1c4496f01a512aafd9e5");
+        assert_eq!(context.sign(&digest, &key).unwrap(), signature);
+
+        context.set_extra_entropy(true);
+        let randomized = context.sign(&digest, &key).unwrap();
+        assert_ne!(randomized, signature);
+        assert_ne!(context.sign(&digest, &key).unwrap(), randomized);
+        assert!(context.verify(&digest, &randomized, &public));
+    }
 }
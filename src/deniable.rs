@@ -0,0 +1,156 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Plausible deniability
+//!
+//! Independent wallets of one seed, each of its own BIP39 passphrase
+//!
+
+use bitcoin::{Network, Script};
+use bitcoin::bip32::Fingerprint;
+
+use account::{MasterAccount, Unlocker};
+use error::WalletError;
+
+/// the wallets of a seed that were opened with their pd_passphrase.
+/// Their accounts are kept apart, a script belongs to at most one of them.
+pub struct DeniableWallets {
+    network: Network,
+    wallets: Vec<MasterAccount>
+}
+
+impl DeniableWallets {
+    /// wallets of the seed of master, master is the first of them
+    pub fn new(master: MasterAccount, network: Network) -> Result<DeniableWallets, WalletError> {
+        if master.is_watch_only() {
+            return Err(WalletError::Unsupported("watch only master has no seed"));
+        }
+        if master.network().is_some_and(|n| n != network) {
+            return Err(WalletError::Network);
+        }
+        Ok(DeniableWallets { network, wallets: vec!(master) })
+    }
+
+    pub fn network(&self) -> Network {
+        self.network
+    }
+
+    /// the wallet of a pd_passphrase, added if it was not opened before
+    pub fn open(&mut self, passphrase: &str, pd_passphrase: Option<&str>) -> Result<&mut MasterAccount, WalletError> {
+        let wallet = self.wallets[0].with_pd_passphrase(passphrase, pd_passphrase, self.network)?;
+        match self.wallets.iter().position(|w| w.fingerprint() == wallet.fingerprint()) {
+            Some(ix) => Ok(&mut self.wallets[ix]),
+            None => {
+                self.wallets.push(wallet);
+                Ok(self.wallets.last_mut().expect("just pushed"))
+            }
+        }
+    }
+
+    /// add a wallet restored from storage, it must be of the same seed and network
+    pub fn add(&mut self, wallet: MasterAccount) -> Result<(), WalletError> {
+        if wallet.encrypted() != self.wallets[0].encrypted() {
+            return Err(WalletError::Unsupported("wallet of an other seed"));
+        }
+        if wallet.network().is_some_and(|n| n != self.network) {
+            return Err(WalletError::Network);
+        }
+        if self.get(wallet.fingerprint()).is_some() {
+            return Err(WalletError::Unsupported("wallet is already open"));
+        }
+        if wallet.get_scripts().any(|(s, _)| self.owner(&s).is_some()) {
+            return Err(WalletError::Unsupported("wallet shares scripts with an other wallet"));
+        }
+        self.wallets.push(wallet);
+        Ok(())
+    }
+
+    /// forget a wallet, e.g. the hidden one before showing the others
+    pub fn close(&mut self, fingerprint: Fingerprint) -> Option<MasterAccount> {
+        let ix = self.wallets.iter().position(|w| w.fingerprint() == fingerprint)?;
+        if self.wallets.len() == 1 {
+            return None;
+        }
+        Some(self.wallets.remove(ix))
+    }
+
+    /// the open wallets
+    pub fn wallets(&self) -> impl Iterator<Item=&MasterAccount> {
+        self.wallets.iter()
+    }
+
+    pub fn get(&self, fingerprint: Fingerprint) -> Option<&MasterAccount> {
+        self.wallets.iter().find(|w| w.fingerprint() == fingerprint)
+    }
+
+    pub fn get_mut(&mut self, fingerprint: Fingerprint) -> Option<&mut MasterAccount> {
+        self.wallets.iter_mut().find(|w| w.fingerprint() == fingerprint)
+    }
+
+    /// the wallet a script belongs to
+    pub fn owner(&self, script_pubkey: &Script) -> Option<&MasterAccount> {
+        self.wallets.iter().find(|w| w.get_key_for_script(script_pubkey).is_some())
+    }
+
+    /// the unlocker of a wallet, fails with WalletError::Passphrase if the pd_passphrase is that of an other wallet
+    pub fn unlock(&self, fingerprint: Fingerprint, passphrase: &str, pd_passphrase: Option<&str>) -> Result<Unlocker, WalletError> {
+        let wallet = self.get(fingerprint).ok_or(WalletError::Unsupported("no such wallet"))?;
+        Unlocker::new(wallet.encrypted(), passphrase, pd_passphrase, self.network, Some(wallet.master_public()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{EcdsaSighashType, Transaction};
+    use bitcoin::absolute::LockTime;
+    use bitcoin::transaction::Version;
+
+    use account::{AccountAddressType, MasterKeyEntropy};
+    use super::*;
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+
+    #[test]
+    fn test_deniable() {
+        let master = MasterAccount::new(MasterKeyEntropy::Low, Network::Bitcoin, PASSPHRASE, None).unwrap();
+        let decoy = master.fingerprint();
+        let mut wallets = DeniableWallets::new(master, Network::Bitcoin).unwrap();
+        assert!(wallets.open("wrong", Some("hidden")).is_err());
+        let hidden = wallets.open(PASSPHRASE, Some("hidden")).unwrap().fingerprint();
+        assert_ne!(hidden, decoy);
+        assert_eq!(wallets.open(PASSPHRASE, Some("hidden")).unwrap().fingerprint(), hidden);
+        assert_eq!(wallets.open(PASSPHRASE, None).unwrap().fingerprint(), decoy);
+        assert_eq!(wallets.wallets().count(), 2);
+
+        let mut unlocker = wallets.unlock(hidden, PASSPHRASE, Some("hidden")).unwrap();
+        assert!(wallets.unlock(hidden, PASSPHRASE, None).is_err());
+        let script = wallets.get_mut(hidden).unwrap().new_account(&mut unlocker, AccountAddressType::P2WPKH, 0, 0, 10).unwrap()
+            .next_key().unwrap().script_pubkey.clone();
+        assert_eq!(wallets.owner(&script).unwrap().fingerprint(), hidden);
+        // the keys of one wallet do not sign for an other
+        let mut decoy_unlocker = wallets.unlock(decoy, PASSPHRASE, None).unwrap();
+        assert!(wallets.get_mut(decoy).unwrap().new_account(&mut unlocker, AccountAddressType::P2WPKH, 0, 0, 10).is_err());
+        let mut transaction = Transaction { version: Version::TWO, lock_time: LockTime::ZERO, input: Vec::new(), output: Vec::new() };
+        assert!(wallets.get(hidden).unwrap().sign(&mut transaction, EcdsaSighashType::All, &(|_| None), &mut decoy_unlocker).is_err());
+
+        let closed = wallets.close(hidden).unwrap();
+        assert!(wallets.owner(&script).is_none());
+        assert!(wallets.close(decoy).is_none());
+        wallets.add(closed).unwrap();
+        assert!(DeniableWallets::new(MasterAccount::new(MasterKeyEntropy::Low, Network::Bitcoin, PASSPHRASE, None).unwrap(), Network::Bitcoin).unwrap()
+            .add(wallets.close(hidden).unwrap()).is_err());
+    }
+}
@@ -0,0 +1,131 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Output descriptors
+//!
+//! Describe the scripts of accounts as output descriptors understood by Bitcoin Core and other wallets
+//!
+
+use bitcoin::bip32::{DerivationPath, Fingerprint};
+
+use account::{Account, AccountAddressType};
+use error::WalletError;
+
+const INPUT_CHARSET: &str = "0123456789()[],'/*abcdefgh@:$%{}IJKLMNOPQRSTUVWXYZ&+-.;<=>?!^_|~ijklmnopqrstuvwxyzABCDEFGH`#\"\\ ";
+const CHECKSUM_CHARSET: &[u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+/// BIP380 checksum of a descriptor
+pub fn checksum(descriptor: &str) -> Result<String, WalletError> {
+    let mut c = 1u64;
+    let mut cls = 0u64;
+    let mut cls_count = 0;
+    for ch in descriptor.chars() {
+        let pos = INPUT_CHARSET.find(ch).ok_or(WalletError::Unsupported("invalid character in descriptor"))? as u64;
+        c = polymod(c, pos & 31);
+        cls = cls * 3 + (pos >> 5);
+        cls_count += 1;
+        if cls_count == 3 {
+            c = polymod(c, cls);
+            cls = 0;
+            cls_count = 0;
+        }
+    }
+    if cls_count > 0 {
+        c = polymod(c, cls);
+    }
+    for _ in 0..8 {
+        c = polymod(c, 0);
+    }
+    c ^= 1;
+    Ok((0..8).map(|j| CHECKSUM_CHARSET[((c >> (5 * (7 - j))) & 31) as usize] as char).collect())
+}
+
+/// the descriptor followed by # and its checksum
+pub fn with_checksum(descriptor: &str) -> Result<String, WalletError> {
+    Ok(format!("{}#{}", descriptor, checksum(descriptor)?))
+}
+
+/// the descriptor without its checksum, which must be valid if present after a #
+pub fn strip_checksum(descriptor: &str) -> Result<&str, WalletError> {
+    match descriptor.rfind('#') {
+        Some(hash) => {
+            let (stripped, given) = (&descriptor[..hash], &descriptor[hash + 1..]);
+            if checksum(stripped)? != given {
+                return Err(WalletError::Unsupported("invalid descriptor checksum"));
+            }
+            Ok(stripped)
+        },
+        None => {
+            checksum(descriptor)?;
+            Ok(descriptor)
+        }
+    }
+}
+
+/// ranged descriptor of the keys of an account, with the key origin of the master key of fingerprint
+/// none for P2WSH accounts as their scripts are not derived from the keys alone
+pub fn account_descriptor(fingerprint: Fingerprint, account: &Account) -> Option<String> {
+    let path = account.key_path(0);
+    let origin = DerivationPath::from(path[..path.len() - 1].to_vec());
+    let key = format!("[{}/{}]{}/*", fingerprint, origin, account.master_public());
+    let descriptor = match account.address_type() {
+        AccountAddressType::P2PKH => format!("pkh({})", key),
+        AccountAddressType::P2SHWPKH => format!("sh(wpkh({}))", key),
+        AccountAddressType::P2WPKH => format!("wpkh({})", key),
+        AccountAddressType::P2TR => format!("tr({})", key),
+        AccountAddressType::P2WSH(_) => return None
+    };
+    with_checksum(&descriptor).ok()
+}
+
+fn polymod(c: u64, val: u64) -> u64 {
+    let c0 = c >> 35;
+    let mut c = ((c & 0x7ffffffff) << 5) ^ val;
+    for (bit, generator) in [0xf5dee51989u64, 0xa9fdca3312, 0x1bab10e32d, 0x3706b1677a, 0x644d626ffd].iter().enumerate() {
+        if c0 & (1 << bit) != 0 {
+            c ^= generator;
+        }
+    }
+    c
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::Network;
+
+    use account::{MasterAccount, MasterKeyEntropy, Unlocker};
+    use super::*;
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+
+    #[test]
+    fn test_descriptor() {
+        // example of BIP380
+        assert_eq!(with_checksum("raw(deadbeef)").unwrap(), "raw(deadbeef)#89f8spxm");
+        assert!(checksum("raw(deadbeef)\u{e9}").is_err());
+        assert_eq!(strip_checksum("raw(deadbeef)#89f8spxm").unwrap(), "raw(deadbeef)");
+        assert_eq!(strip_checksum("raw(deadbeef)").unwrap(), "raw(deadbeef)");
+        assert!(strip_checksum("raw(deadbeef)#89f8spxn").is_err());
+
+        let mut master = MasterAccount::new(MasterKeyEntropy::Low, Network::Bitcoin, PASSPHRASE, None).unwrap();
+        let mut unlocker = Unlocker::new(master.encrypted(), PASSPHRASE, None, Network::Bitcoin, None).unwrap();
+        let fingerprint = master.fingerprint();
+        let account = master.new_account(&mut unlocker, AccountAddressType::P2SHWPKH, 1, 0, 10).unwrap();
+        let descriptor = account_descriptor(fingerprint, account).unwrap();
+        assert!(descriptor.starts_with(&format!("sh(wpkh([{}/49'/0'/1'/0]xpub", fingerprint)));
+        assert!(descriptor.contains("/*))#"));
+    }
+}
@@ -0,0 +1,154 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Account discovery
+//!
+//! Find used accounts and addresses of a restored wallet with a gap limit as of BIP44
+//!
+use std::collections::HashSet;
+
+use bitcoin::ScriptBuf;
+
+use account::{Account, AccountAddressType, MasterAccount, Unlocker};
+use context::SecpContext;
+use error::WalletError;
+
+/// consecutive unused addresses that end the scan of a chain (BIP44)
+pub const DEFAULT_GAP_LIMIT: u32 = 20;
+
+/// receive and change sub accounts
+const CHAINS: [u32; 2] = [0, 1];
+
+/// a source that knows which scripts were used on chain, such as an Electrum server
+pub trait UsageSource {
+    /// for each script true if any transaction paid to it
+    fn used(&mut self, scripts: &[ScriptBuf]) -> Result<Vec<bool>, WalletError>;
+}
+
+/// scripts known to be used
+impl UsageSource for HashSet<ScriptBuf> {
+    fn used(&mut self, scripts: &[ScriptBuf]) -> Result<Vec<bool>, WalletError> {
+        Ok(scripts.iter().map(|s| self.contains(s)).collect())
+    }
+}
+
+/// discover accounts of an address type in order of account number until one without history,
+/// scanning receive and change chains up to gap_limit unused addresses.
+/// accounts with history, and account 0 in any case, are added to the master with
+/// gap_limit look ahead and their next index after the last used address.
+/// returns the numbers of accounts with history
+pub fn discover<S: UsageSource>(source: &mut S, master: &mut MasterAccount, unlocker: &mut Unlocker, address_type: AccountAddressType, gap_limit: u32) -> Result<Vec<u32>, WalletError> {
+    if master.is_watch_only() {
+        return Err(WalletError::Unsupported("watch only master can not create accounts"));
+    }
+    if SecpContext::new().extended_public_from_private(unlocker.master_private()) != *master.master_public() {
+        return Err(WalletError::Passphrase);
+    }
+    if gap_limit == 0 {
+        return Err(WalletError::Unsupported("gap limit must be positive"));
+    }
+    let mut found = Vec::new();
+    for account_number in 0.. {
+        let mut chains = Vec::new();
+        let mut used = false;
+        for sub_account_number in CHAINS.iter() {
+            let mut account = Account::new(unlocker, address_type, account_number, *sub_account_number, gap_limit)?;
+            used |= discover_chain(source, &mut account, gap_limit)?.is_some();
+            chains.push(account);
+        }
+        if used || account_number == 0 {
+            for account in chains {
+                master.add_account(account);
+            }
+        }
+        if !used {
+            break;
+        }
+        found.push(account_number);
+    }
+    Ok(found)
+}
+
+/// scan a chain until gap_limit consecutive unused addresses, move the next index of the account
+/// after the last used address. returns the index of the last used address
+pub fn discover_chain<S: UsageSource>(source: &mut S, account: &mut Account, gap_limit: u32) -> Result<Option<u32>, WalletError> {
+    let mut last_used = None;
+    let mut checked = 0;
+    loop {
+        let end = last_used.map_or(0, |u| u + 1) + gap_limit;
+        if checked >= end {
+            break;
+        }
+        account.do_look_ahead(end)?;
+        let scripts = (checked..end).map(|kix| account.get_key(kix).expect("instantiated by look ahead").script_pubkey.clone()).collect::<Vec<_>>();
+        for (i, used) in source.used(&scripts)?.into_iter().enumerate() {
+            if used {
+                last_used = Some(checked + i as u32);
+            }
+        }
+        checked = end;
+    }
+    if let Some(last) = last_used {
+        account.set_next(last + 1)?;
+    }
+    Ok(last_used)
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::Network;
+
+    use account::MasterKeyEntropy;
+    use super::*;
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+
+    #[test]
+    fn test_discover() {
+        let mut master = MasterAccount::new(MasterKeyEntropy::Low, Network::Bitcoin, PASSPHRASE, None).unwrap();
+        let mut unlocker = Unlocker::new(master.encrypted(), PASSPHRASE, None, Network::Bitcoin, None).unwrap();
+        let key = |unlocker: &mut Unlocker, account: u32, sub: u32, kix: u32|
+            Account::new(unlocker, AccountAddressType::P2WPKH, account, sub, kix + 1).unwrap().get_key(kix).unwrap().script_pubkey.clone();
+
+        let mut used = HashSet::new();
+        // receive 3 and 23 of account 0, 23 is the last of the gap after 3
+        used.insert(key(&mut unlocker, 0, 0, 3));
+        used.insert(key(&mut unlocker, 0, 0, 23));
+        // beyond the gap after 23
+        used.insert(key(&mut unlocker, 0, 0, 44));
+        // change of account 1 only
+        used.insert(key(&mut unlocker, 1, 1, 0));
+        // account 3 is not reached as account 2 is unused
+        used.insert(key(&mut unlocker, 3, 0, 0));
+
+        assert_eq!(discover(&mut used, &mut master, &mut unlocker, AccountAddressType::P2WPKH, DEFAULT_GAP_LIMIT).unwrap(), vec!(0, 1));
+        assert_eq!(master.accounts().len(), 4);
+        assert_eq!(master.get((0, 0)).unwrap().next(), 24);
+        assert_eq!(master.get((0, 1)).unwrap().next(), 0);
+        assert_eq!(master.get((1, 0)).unwrap().next(), 0);
+        assert_eq!(master.get((1, 1)).unwrap().next(), 1);
+        assert!(master.get((2, 0)).is_none());
+        // the look ahead covers the gap
+        assert!(master.get((0, 0)).unwrap().instantiated().len() >= 44);
+
+        let mut nothing = HashSet::new();
+        let mut fresh = MasterAccount::new(MasterKeyEntropy::Low, Network::Bitcoin, PASSPHRASE, None).unwrap();
+        let mut fresh_unlocker = Unlocker::new(fresh.encrypted(), PASSPHRASE, None, Network::Bitcoin, None).unwrap();
+        assert!(discover(&mut nothing, &mut fresh, &mut fresh_unlocker, AccountAddressType::P2WPKH, DEFAULT_GAP_LIMIT).unwrap().is_empty());
+        assert_eq!(fresh.accounts().len(), 2);
+        assert!(discover(&mut nothing, &mut fresh, &mut unlocker, AccountAddressType::P2WPKH, DEFAULT_GAP_LIMIT).is_err());
+    }
+}
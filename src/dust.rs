@@ -0,0 +1,81 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Dust
+//!
+//! Dust limits of output scripts, outputs worth less than the fee to spend them are not relayed
+//!
+
+use bitcoin::{Amount, FeeRate, PubkeyHash, Script, ScriptBuf, ScriptHash, TxOut, WPubkeyHash, WScriptHash, WitnessProgram, XOnlyPublicKey};
+use bitcoin::key::TweakedPublicKey;
+use bitcoin_hashes::Hash;
+
+use account::AccountAddressType;
+use coinselect;
+
+/// the dust relay fee rate of Bitcoin Core, 3 sat/vB
+pub const DUST_RELAY_FEE: FeeRate = FeeRate::from_sat_per_kwu(750);
+
+/// the value below which an output to the script is dust at a fee rate: what creating
+/// and later spending it costs, as Bitcoin Core computes it. OP_RETURN outputs are never dust.
+pub fn dust_limit(script_pubkey: &Script, fee_rate: FeeRate) -> Amount {
+    if script_pubkey.is_op_return() {
+        return Amount::ZERO;
+    }
+    // outpoint, script length, sequence and the signature script or a quarter of the witness
+    let spend = if script_pubkey.is_witness_program() { 32 + 4 + 1 + 107 / 4 + 4 } else { 32 + 4 + 1 + 107 + 4 };
+    let size = coinselect::output_weight(script_pubkey) / 4 + spend;
+    Amount::from_sat(size * fee_rate.to_sat_per_kwu() * 4 / 1000)
+}
+
+/// dust limit of outputs of an address type at a fee rate
+pub fn address_type_limit(address_type: AccountAddressType, fee_rate: FeeRate) -> Amount {
+    let script = match address_type {
+        AccountAddressType::P2PKH => ScriptBuf::new_p2pkh(&PubkeyHash::all_zeros()),
+        AccountAddressType::P2SHWPKH => ScriptBuf::new_p2sh(&ScriptHash::all_zeros()),
+        AccountAddressType::P2WPKH => ScriptBuf::new_p2wpkh(&WPubkeyHash::all_zeros()),
+        AccountAddressType::P2TR => ScriptBuf::new_witness_program(&WitnessProgram::p2tr_tweaked(TweakedPublicKey::dangerous_assume_tweaked(
+            XOnlyPublicKey::from_slice(&[1u8; 32]).expect("valid x coordinate")))),
+        AccountAddressType::P2WSH(_) => ScriptBuf::new_p2wsh(&WScriptHash::all_zeros())
+    };
+    dust_limit(&script, fee_rate)
+}
+
+/// true if the output is worth less than its dust limit at the dust relay fee
+pub fn is_dust(output: &TxOut) -> bool {
+    output.value < dust_limit(&output.script_pubkey, DUST_RELAY_FEE)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_dust() {
+        // the well known limits of Bitcoin Core
+        assert_eq!(address_type_limit(AccountAddressType::P2PKH, DUST_RELAY_FEE), Amount::from_sat(546));
+        assert_eq!(address_type_limit(AccountAddressType::P2SHWPKH, DUST_RELAY_FEE), Amount::from_sat(540));
+        assert_eq!(address_type_limit(AccountAddressType::P2WPKH, DUST_RELAY_FEE), Amount::from_sat(294));
+        assert_eq!(address_type_limit(AccountAddressType::P2WSH(0), DUST_RELAY_FEE), Amount::from_sat(330));
+        assert_eq!(address_type_limit(AccountAddressType::P2TR, DUST_RELAY_FEE), Amount::from_sat(330));
+        let script = ScriptBuf::new_p2wpkh(&WPubkeyHash::from_byte_array([1u8; 20]));
+        assert_eq!(dust_limit(&script, DUST_RELAY_FEE), script.minimal_non_dust());
+        assert_eq!(dust_limit(&script, FeeRate::from_sat_per_vb(10).unwrap()), Amount::from_sat(980));
+        assert_eq!(dust_limit(&ScriptBuf::new_op_return([0u8; 8]), DUST_RELAY_FEE), Amount::ZERO);
+        assert!(is_dust(&TxOut { value: Amount::from_sat(293), script_pubkey: script.clone() }));
+        assert!(!is_dust(&TxOut { value: Amount::from_sat(294), script_pubkey: script }));
+    }
+}
@@ -0,0 +1,565 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Electrum client
+//!
+//! Learn balance and history of a master account from an Electrum server
+//!
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::TryFrom;
+use std::fmt::Display;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use bitcoin::{consensus, Amount, BlockHash, FeeRate, OutPoint, Script, ScriptBuf, Transaction, TxOut, Txid};
+use bitcoin::block::Header;
+use bitcoin::hex::{DisplayHex, FromHex};
+use bitcoin_hashes::{sha256, sha256d, Hash};
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+use rustls::pki_types::ServerName;
+use serde_json::{json, Value};
+
+use account::MasterAccount;
+use broadcast::Broadcaster;
+use chain::{ChainSource, Confirmation};
+use coins::Coins;
+use error::WalletError;
+use fees::{self, FeeEstimator};
+use sweep::UtxoSource;
+use discovery::UsageSource;
+use proved::ProvedTransaction;
+use proxy::{self, Socks5Proxy};
+use watch::{MempoolEvent, MempoolWatcher};
+
+/// protocol version this client speaks
+pub(crate) const PROTOCOL_VERSION: &str = "1.4";
+
+pub use store::HistoryEntry;
+
+/// a client talking the Electrum protocol over a stream
+pub struct Client<S: Read + Write> {
+    stream: BufReader<S>,
+    next_id: u64,
+    notifications: VecDeque<Value>,
+    watched: HashMap<String, ScriptBuf>
+}
+
+impl Client<TcpStream> {
+    /// connect over plain TCP
+    pub fn connect(address: &str) -> Result<Client<TcpStream>, WalletError> {
+        Client::connect_with_proxy(address, None)
+    }
+
+    /// connect over plain TCP, through the proxy if given, e.g. to an onion server through Tor
+    pub fn connect_with_proxy(address: &str, proxy: Option<&Socks5Proxy>) -> Result<Client<TcpStream>, WalletError> {
+        Client::new(proxy::connect(address, proxy)?)
+    }
+}
+
+impl Client<StreamOwned<ClientConnection, TcpStream>> {
+    /// connect over SSL, the server certificate is checked against the webpki roots for domain
+    pub fn connect_ssl(address: &str, domain: &str) -> Result<Client<StreamOwned<ClientConnection, TcpStream>>, WalletError> {
+        Client::connect_ssl_with_proxy(address, domain, None)
+    }
+
+    /// connect over SSL, through the proxy if given
+    pub fn connect_ssl_with_proxy(address: &str, domain: &str, proxy: Option<&Socks5Proxy>) -> Result<Client<StreamOwned<ClientConnection, TcpStream>>, WalletError> {
+        let roots = RootCertStore { roots: webpki_roots::TLS_SERVER_ROOTS.to_vec() };
+        let config = ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions().map_err(invalid)?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let name = ServerName::try_from(domain.to_string()).map_err(invalid)?;
+        let connection = ClientConnection::new(Arc::new(config), name).map_err(invalid)?;
+        Client::new(StreamOwned::new(connection, proxy::connect(address, proxy)?))
+    }
+}
+
+impl<S: Read + Write> Client<S> {
+    /// start a session on a connected stream
+    pub fn new(stream: S) -> Result<Client<S>, WalletError> {
+        let mut client = Client { stream: BufReader::new(stream), next_id: 0, notifications: VecDeque::new(), watched: HashMap::new() };
+        client.request("server.version", vec!(json!("bitcoin-wallet"), json!(PROTOCOL_VERSION)))?;
+        Ok(client)
+    }
+
+    /// send a request and wait for its result, notifications received meanwhile are queued
+    pub fn request(&mut self, method: &str, params: Vec<Value>) -> Result<Value, WalletError> {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.stream.get_mut().write_all(request_line(id, method, params).as_bytes())?;
+        self.stream.get_mut().flush()?;
+        loop {
+            let mut line = String::new();
+            if self.stream.read_line(&mut line)? == 0 {
+                return Err(WalletError::Electrum("connection closed".to_string()));
+            }
+            let response: Value = serde_json::from_str(&line).map_err(invalid)?;
+            if response["id"].is_null() && response["method"].is_string() {
+                self.notifications.push_back(response);
+                continue;
+            }
+            if response["id"].as_u64() != Some(id) {
+                continue;
+            }
+            return result(response);
+        }
+    }
+
+    /// fee rate for confirmation within blocks, None if the server has no estimate
+    pub fn estimate_fee(&mut self, blocks: u32) -> Result<Option<FeeRate>, WalletError> {
+        let btc_per_kvb = self.request("blockchain.estimatefee", vec!(json!(blocks)))?.as_f64().ok_or_else(|| unexpected("fee"))?;
+        Ok(fees::from_btc_per_kvb(btc_per_kvb))
+    }
+
+    /// unspent outputs of a script, confirmed or not
+    pub fn list_unspent(&mut self, script_pubkey: &Script) -> Result<Vec<(OutPoint, TxOut)>, WalletError> {
+        let unspent = self.request("blockchain.scripthash.listunspent", vec!(json!(script_hash(script_pubkey))))?;
+        unspent.as_array().ok_or_else(|| unexpected("unspent"))?.iter().map(|u| {
+            let txid = Txid::from_str(u["tx_hash"].as_str().ok_or_else(|| unexpected("tx_hash"))?).map_err(invalid)?;
+            let vout = u["tx_pos"].as_u64().ok_or_else(|| unexpected("tx_pos"))? as u32;
+            let value = Amount::from_sat(u["value"].as_u64().ok_or_else(|| unexpected("value"))?);
+            Ok((OutPoint { txid, vout }, TxOut { value, script_pubkey: script_pubkey.to_owned() }))
+        }).collect()
+    }
+
+    /// subscribe to changes of a script, returns the status of its history
+    pub fn subscribe(&mut self, script_pubkey: &Script) -> Result<Option<String>, WalletError> {
+        let status = self.request("blockchain.scripthash.subscribe", vec!(json!(script_hash(script_pubkey))))?;
+        Ok(status.as_str().map(|s| s.to_string()))
+    }
+
+    /// transactions spending from or paying to a script
+    pub fn get_history(&mut self, script_pubkey: &Script) -> Result<Vec<HistoryEntry>, WalletError> {
+        history(&self.request("blockchain.scripthash.get_history", vec!(json!(script_hash(script_pubkey))))?)
+    }
+
+    pub fn get_transaction(&mut self, txid: &Txid) -> Result<Transaction, WalletError> {
+        transaction(&self.request("blockchain.transaction.get", vec!(json!(txid.to_string())))?, txid)
+    }
+
+    pub fn get_header(&mut self, height: u32) -> Result<Header, WalletError> {
+        header(&self.request("blockchain.block.header", vec!(json!(height)))?)
+    }
+
+    /// fetch a confirmed transaction with its SPV proof checked against the block header
+    pub fn get_proved(&mut self, txid: &Txid, height: u32) -> Result<(ProvedTransaction, usize), WalletError> {
+        let transaction = self.get_transaction(txid)?;
+        let merkle = self.request("blockchain.transaction.get_merkle", vec!(json!(txid.to_string()), json!(height)))?;
+        let header = self.get_header(height)?;
+        proved(transaction, &merkle, &header)
+    }
+
+    /// the next notification of the server, waits for one if none is queued
+    pub fn next_notification(&mut self) -> Result<Value, WalletError> {
+        loop {
+            if let Some(notification) = self.notifications.pop_front() {
+                return Ok(notification);
+            }
+            let mut line = String::new();
+            if self.stream.read_line(&mut line)? == 0 {
+                return Err(WalletError::Electrum("connection closed".to_string()));
+            }
+            let response: Value = serde_json::from_str(&line).map_err(invalid)?;
+            if response["method"].is_string() {
+                self.notifications.push_back(response);
+            }
+        }
+    }
+
+    /// subscribe to the scripts of the watcher not yet subscribed, then report payments already in the mempool
+    pub fn watch(&mut self, watcher: &mut MempoolWatcher) -> Result<Vec<MempoolEvent>, WalletError> {
+        let scripts = watcher.scripts().map(|s| (script_hash(s), s.to_owned()))
+            .filter(|(hash, _)| !self.watched.contains_key(hash)).collect::<Vec<_>>();
+        let mut events = Vec::new();
+        for (hash, script_pubkey) in scripts {
+            let status = self.subscribe(&script_pubkey)?;
+            self.watched.insert(hash, script_pubkey.clone());
+            if status.is_some() {
+                events.extend(self.watch_script(watcher, &script_pubkey)?);
+            }
+        }
+        Ok(events)
+    }
+
+    /// wait until a subscribed script changes, then report its new mempool payments and confirmations
+    pub fn wait_for_events(&mut self, watcher: &mut MempoolWatcher) -> Result<Vec<MempoolEvent>, WalletError> {
+        loop {
+            let notification = self.next_notification()?;
+            if notification["method"].as_str() != Some("blockchain.scripthash.subscribe") {
+                continue;
+            }
+            let script_pubkey = match notification["params"][0].as_str().and_then(|hash| self.watched.get(hash)) {
+                Some(script_pubkey) => script_pubkey.clone(),
+                None => continue
+            };
+            let events = self.watch_script(watcher, &script_pubkey)?;
+            if !events.is_empty() {
+                return Ok(events);
+            }
+        }
+    }
+
+    fn watch_script(&mut self, watcher: &mut MempoolWatcher, script_pubkey: &Script) -> Result<Vec<MempoolEvent>, WalletError> {
+        let mut events = Vec::new();
+        for (txid, height) in self.get_history(script_pubkey)? {
+            // unconfirmed transactions have height 0, or -1 if they spend unconfirmed outputs
+            let event = if height > 0 {
+                watcher.confirmed(&txid, height as u32)
+            } else if !watcher.is_pending(&txid) {
+                let transaction = self.get_transaction(&txid)?;
+                watcher.process(&transaction)
+            } else {
+                None
+            };
+            events.extend(event);
+        }
+        Ok(events)
+    }
+
+    /// send a transaction to the network
+    pub fn broadcast(&mut self, transaction: &Transaction) -> Result<Txid, WalletError> {
+        txid(&self.request("blockchain.transaction.broadcast", vec!(json!(consensus::encode::serialize_hex(transaction))))?)
+    }
+
+    /// subscribe to all scripts of the master account, extending look ahead of accounts as
+    /// used keys are found, then process confirmed transactions into coins.
+    /// returns the history of the master account including unconfirmed transactions
+    pub fn sync(&mut self, master: &mut MasterAccount, coins: &mut Coins) -> Result<Vec<HistoryEntry>, WalletError> {
+        let mut queried = HashSet::new();
+        let mut history = HashMap::new();
+        loop {
+            let scripts = master.get_scripts().filter(|(s, _)| !queried.contains(s)).collect::<Vec<_>>();
+            if scripts.is_empty() {
+                break;
+            }
+            for (script_pubkey, derivation) in scripts {
+                if self.subscribe(&script_pubkey)?.is_some() {
+                    history.extend(self.get_history(&script_pubkey)?);
+                    if let Some(account) = master.get_mut((derivation.account, derivation.sub)) {
+                        account.do_look_ahead(derivation.kix)?;
+                    }
+                }
+                queried.insert(script_pubkey);
+            }
+        }
+
+        let mut confirmed = Vec::new();
+        for (txid, height) in history.iter() {
+            if *height > 0 {
+                let (proved, position) = self.get_proved(txid, *height as u32)?;
+                confirmed.push((*height, position, proved));
+            }
+        }
+        process(master, coins, history, confirmed)
+    }
+}
+
+impl<S: Read + Write> Broadcaster for Client<S> {
+    fn broadcast(&mut self, transaction: &Transaction) -> Result<Txid, WalletError> {
+        Client::broadcast(self, transaction)
+    }
+}
+
+impl<S: Read + Write> UsageSource for Client<S> {
+    fn used(&mut self, scripts: &[ScriptBuf]) -> Result<Vec<bool>, WalletError> {
+        // a script has a status if and only if it has history
+        scripts.iter().map(|s| Ok(self.subscribe(s)?.is_some())).collect()
+    }
+}
+
+impl<S: Read + Write> ChainSource for Client<S> {
+    fn get_history(&mut self, script_pubkey: &Script) -> Result<Vec<(Txid, Confirmation)>, WalletError> {
+        let mut block_hashes = HashMap::new();
+        let mut history = Vec::new();
+        for (txid, height) in Client::get_history(self, script_pubkey)? {
+            if height <= 0 {
+                history.push((txid, None));
+                continue;
+            }
+            let height = height as u32;
+            let block_hash = match block_hashes.get(&height) {
+                Some(block_hash) => *block_hash,
+                None => {
+                    let block_hash = self.get_header(height)?.block_hash();
+                    block_hashes.insert(height, block_hash);
+                    block_hash
+                }
+            };
+            history.push((txid, Some((height, block_hash))));
+        }
+        Ok(history)
+    }
+
+    fn get_utxos(&mut self, script_pubkey: &Script) -> Result<Vec<(OutPoint, TxOut)>, WalletError> {
+        self.list_unspent(script_pubkey)
+    }
+
+    fn get_tx(&mut self, txid: &Txid) -> Result<Transaction, WalletError> {
+        self.get_transaction(txid)
+    }
+
+    fn broadcast(&mut self, transaction: &Transaction) -> Result<Txid, WalletError> {
+        Client::broadcast(self, transaction)
+    }
+
+    fn estimate_fee(&mut self, blocks: u32) -> Result<FeeRate, WalletError> {
+        self.estimate(blocks)
+    }
+
+    fn tip(&mut self) -> Result<(u32, BlockHash), WalletError> {
+        let tip = self.request("blockchain.headers.subscribe", Vec::new())?;
+        let height = tip["height"].as_u64().ok_or_else(|| unexpected("height"))? as u32;
+        let header: Header = deserialize_hex(tip["hex"].as_str().ok_or_else(|| unexpected("header"))?)?;
+        Ok((height, header.block_hash()))
+    }
+}
+
+/// the script hash Electrum servers index scripts by
+pub fn script_hash(script_pubkey: &Script) -> String {
+    let mut hash = sha256::Hash::hash(script_pubkey.as_bytes()).to_byte_array();
+    hash.reverse();
+    hash.to_lower_hex_string()
+}
+
+/// a request line of the JSON-RPC protocol
+pub(crate) fn request_line(id: u64, method: &str, params: Vec<Value>) -> String {
+    let mut line = json!({"jsonrpc": "2.0", "id": id, "method": method, "params": params}).to_string();
+    line.push('\n');
+    line
+}
+
+/// the result of a response to a request, or the error the server reported
+pub(crate) fn result(mut response: Value) -> Result<Value, WalletError> {
+    if !response["error"].is_null() {
+        return Err(WalletError::Electrum(response["error"].to_string()));
+    }
+    Ok(response["result"].take())
+}
+
+/// the history of a script as blockchain.scripthash.get_history reports it
+pub(crate) fn history(history: &Value) -> Result<Vec<HistoryEntry>, WalletError> {
+    history.as_array().ok_or_else(|| unexpected("history"))?.iter().map(|entry| {
+        let txid = Txid::from_str(entry["tx_hash"].as_str().ok_or_else(|| unexpected("tx_hash"))?).map_err(invalid)?;
+        let height = entry["height"].as_i64().ok_or_else(|| unexpected("height"))? as i32;
+        Ok((txid, height))
+    }).collect()
+}
+
+/// the raw transaction of blockchain.transaction.get, it must have the txid asked for
+pub(crate) fn transaction(raw: &Value, txid: &Txid) -> Result<Transaction, WalletError> {
+    let transaction: Transaction = deserialize_hex(raw.as_str().ok_or_else(|| unexpected("transaction"))?)?;
+    if transaction.compute_txid() != *txid {
+        return Err(unexpected("transaction"));
+    }
+    Ok(transaction)
+}
+
+pub(crate) fn header(raw: &Value) -> Result<Header, WalletError> {
+    deserialize_hex(raw.as_str().ok_or_else(|| unexpected("header"))?)
+}
+
+pub(crate) fn txid(txid: &Value) -> Result<Txid, WalletError> {
+    Txid::from_str(txid.as_str().ok_or_else(|| unexpected("txid"))?).map_err(invalid)
+}
+
+/// a transaction with the merkle branch of blockchain.transaction.get_merkle, checked against the block header
+pub(crate) fn proved(transaction: Transaction, merkle: &Value, header: &Header) -> Result<(ProvedTransaction, usize), WalletError> {
+    let position = merkle["pos"].as_u64().ok_or_else(|| unexpected("pos"))? as usize;
+    let branch = merkle["merkle"].as_array().ok_or_else(|| unexpected("merkle"))?.iter()
+        .map(|h| sha256d::Hash::from_str(h.as_str().unwrap_or_default()).map_err(invalid))
+        .collect::<Result<Vec<_>, _>>()?;
+    let txid = transaction.compute_txid();
+    let proved = ProvedTransaction::from_merkle_branch(transaction, header.block_hash(), position, &branch);
+    if proved.merkle_root() != header.merkle_root {
+        return Err(WalletError::Electrum(format!("invalid merkle proof for {}", txid)));
+    }
+    Ok((proved, position))
+}
+
+/// process confirmed transactions of a sync not yet known into coins in block order,
+/// returns the history sorted by height, unconfirmed transactions last
+pub(crate) fn process(master: &mut MasterAccount, coins: &mut Coins, history: HashMap<Txid, i32>, mut confirmed: Vec<(i32, usize, ProvedTransaction)>)
+    -> Result<Vec<HistoryEntry>, WalletError> {
+    let mut proofs: HashMap<BlockHash, HashSet<Txid>> = HashMap::new();
+    for proof in coins.proofs().values() {
+        proofs.entry(*proof.get_block_hash()).or_default().insert(proof.get_transaction().compute_txid());
+    }
+    confirmed.sort_by_key(|(height, position, _)| (*height, *position));
+    for (_, _, proved) in confirmed {
+        let known = proofs.get(proved.get_block_hash())
+            .map(|txids| txids.contains(&proved.get_transaction().compute_txid())).unwrap_or(false);
+        if !known {
+            coins.process_proved(master, &proved)?;
+        }
+    }
+
+    let mut history = history.into_iter().collect::<Vec<_>>();
+    history.sort_by_key(|(txid, height)| (if *height > 0 { *height } else { i32::MAX }, *txid));
+    Ok(history)
+}
+
+fn deserialize_hex<T: consensus::Decodable>(hex: &str) -> Result<T, WalletError> {
+    consensus::deserialize(&Vec::<u8>::from_hex(hex).map_err(invalid)?).map_err(invalid)
+}
+
+impl<S: Read + Write> UtxoSource for Client<S> {
+    fn unspent(&mut self, script_pubkey: &Script) -> Result<Vec<(OutPoint, TxOut)>, WalletError> {
+        self.list_unspent(script_pubkey)
+    }
+}
+
+impl<S: Read + Write> FeeEstimator for Client<S> {
+    fn estimate(&mut self, blocks: u32) -> Result<FeeRate, WalletError> {
+        self.estimate_fee(blocks)?.ok_or_else(|| WalletError::Fees(format!("no estimate for {} blocks", blocks)))
+    }
+}
+
+pub(crate) fn invalid<E: Display>(err: E) -> WalletError {
+    WalletError::Electrum(err.to_string())
+}
+
+pub(crate) fn unexpected(what: &str) -> WalletError {
+    WalletError::Electrum(format!("unexpected {} in response", what))
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::VecDeque;
+    use std::io;
+
+    use bitcoin::{Address, Amount, Block, CompactTarget, Network, OutPoint, ScriptBuf, Sequence, TxIn, TxMerkleNode, TxOut, Witness};
+    use bitcoin::absolute::LockTime;
+    use bitcoin::transaction::Version;
+
+    use account::{AccountAddressType, MasterKeyEntropy, Unlocker};
+    use super::*;
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+
+    /// answers requests from a single block chain
+    struct MockServer {
+        block: Block,
+        request: Vec<u8>,
+        response: VecDeque<u8>
+    }
+
+    impl MockServer {
+        fn answer(&self, request: &Value) -> Value {
+            let params = request["params"].as_array().unwrap();
+            let tx = &self.block.txdata[0];
+            let hashes = tx.output.iter().map(|o| script_hash(&o.script_pubkey)).collect::<Vec<_>>();
+            match request["method"].as_str().unwrap() {
+                "server.version" => json!(["mock", PROTOCOL_VERSION]),
+                "blockchain.scripthash.subscribe" =>
+                    if hashes.iter().any(|h| h == params[0].as_str().unwrap()) { json!("status") } else { Value::Null },
+                "blockchain.scripthash.get_history" => json!([{"tx_hash": tx.compute_txid().to_string(), "height": 1}]),
+                "blockchain.transaction.get" => json!(consensus::encode::serialize_hex(tx)),
+                "blockchain.transaction.get_merkle" => json!({"block_height": 1, "merkle": [], "pos": 0}),
+                "blockchain.block.header" => json!(consensus::encode::serialize_hex(&self.block.header)),
+                "blockchain.headers.subscribe" => json!({"height": 1, "hex": consensus::encode::serialize_hex(&self.block.header)}),
+                _ => Value::Null
+            }
+        }
+    }
+
+    impl Write for MockServer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.request.extend_from_slice(buf);
+            while let Some(end) = self.request.iter().position(|b| *b == b'\n') {
+                let line = self.request.drain(..=end).collect::<Vec<_>>();
+                let request: Value = serde_json::from_slice(&line).unwrap();
+                // a notification first, the client has to skip it
+                self.response.extend(json!({"jsonrpc": "2.0", "method": "blockchain.headers.subscribe", "params": []}).to_string().bytes());
+                self.response.push_back(b'\n');
+                let response = json!({"jsonrpc": "2.0", "id": request["id"], "result": self.answer(&request)});
+                self.response.extend(response.to_string().bytes());
+                self.response.push_back(b'\n');
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Read for MockServer {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.response.read(buf)
+        }
+    }
+
+    #[test]
+    fn test_script_hash() {
+        // example of the Electrum protocol documentation
+        let address = Address::from_str("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa").unwrap().assume_checked();
+        assert_eq!(script_hash(&address.script_pubkey()), "8b01df4e368ea28f8dc0423bcf7a4923e3a12d307c875e47a0cfbf90b5c39161");
+    }
+
+    #[test]
+    fn test_sync() {
+        let mut master = MasterAccount::new(MasterKeyEntropy::Low, Network::Bitcoin, PASSPHRASE, None).unwrap();
+        let mut unlocker = Unlocker::new(master.encrypted(), PASSPHRASE, None, Network::Bitcoin, None).unwrap();
+        // the last key of the look ahead window is used
+        let used = master.new_account(&mut unlocker, AccountAddressType::P2WPKH, 0, 0, 5).unwrap().get_key(4).unwrap().script_pubkey.clone();
+        let funding = Transaction {
+            version: Version::ONE,
+            lock_time: LockTime::ZERO,
+            input: vec!(TxIn {
+                previous_output: OutPoint { txid: Txid::all_zeros(), vout: 0 },
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::new()
+            }),
+            output: vec!(TxOut { value: Amount::from_sat(100000), script_pubkey: used })
+        };
+        let block = Block {
+            header: Header {
+                version: bitcoin::block::Version::ONE,
+                prev_blockhash: BlockHash::all_zeros(),
+                merkle_root: TxMerkleNode::from_raw_hash(funding.compute_txid().to_raw_hash()),
+                time: 0,
+                bits: CompactTarget::from_consensus(0),
+                nonce: 0
+            },
+            txdata: vec!(funding.clone())
+        };
+        let server = MockServer { block, request: Vec::new(), response: VecDeque::new() };
+        let mut client = Client::new(server).unwrap();
+        let mut coins = Coins::new();
+        let history = client.sync(&mut master, &mut coins).unwrap();
+        assert_eq!(history, vec!((funding.compute_txid(), 1)));
+        assert_eq!(coins.owned().len(), 1);
+        assert_eq!(coins.proofs().len(), 1);
+        // found use extended the look ahead
+        assert_eq!(master.get((0, 0)).unwrap().instantiated().len(), 9);
+
+        // a second sync does not add anything
+        assert_eq!(client.sync(&mut master, &mut coins).unwrap().len(), 1);
+        assert_eq!(coins.owned().len(), 1);
+
+        // as a chain source
+        let block_hash = client.get_header(1).unwrap().block_hash();
+        let script_pubkey = funding.output[0].script_pubkey.clone();
+        assert_eq!(ChainSource::get_history(&mut client, &script_pubkey).unwrap(), vec!((funding.compute_txid(), Some((1, block_hash)))));
+        assert_eq!(client.get_tx(&funding.compute_txid()).unwrap(), funding);
+        assert_eq!(client.tip().unwrap(), (1, block_hash));
+    }
+}
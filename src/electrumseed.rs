@@ -0,0 +1,156 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Electrum seeds
+//!
+//! Restore funds of Electrum v2 standard and segwit seeds
+//!
+
+use std::convert::TryFrom;
+use std::num::NonZeroU32;
+
+use bitcoin::{Address, CompressedPublicKey, FeeRate, Network, PrivateKey, ScriptBuf, Transaction};
+use bitcoin::bip32::{ChildNumber, Xpriv};
+use ring::{hmac, pbkdf2};
+use zeroize::Zeroize;
+
+use account::{AccountAddressType, Seed};
+use context::SecpContext;
+use error::WalletError;
+use secret::SecretBytes;
+use sweep::{self, UtxoSource};
+
+/// keys Electrum looks at beyond the last used one
+pub const GAP_LIMIT: u32 = 20;
+
+/// kinds of Electrum v2 seeds this wallet restores
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SeedType {
+    /// P2PKH addresses at m / change / index
+    Standard,
+    /// P2WPKH addresses at m / 0' / change / index
+    Segwit
+}
+
+/// lower case words separated by single spaces, seeds of other languages need NFKD normalization not done here
+fn normalize(mnemonic: &str) -> String {
+    mnemonic.split_whitespace().map(|w| w.to_lowercase()).collect::<Vec<_>>().join(" ")
+}
+
+/// the type of an Electrum v2 seed, None if it is not one or of an other type, e.g. 2FA
+pub fn seed_type(mnemonic: &str) -> Option<SeedType> {
+    let version = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA512, b"Seed version"), normalize(mnemonic).as_bytes());
+    let version = version.as_ref();
+    // hex prefixes 01 and 100
+    if version[0] == 0x01 {
+        Some(SeedType::Standard)
+    } else if version[0] == 0x10 && version[1] >> 4 == 0 {
+        Some(SeedType::Segwit)
+    } else {
+        None
+    }
+}
+
+/// the keys of an Electrum v2 seed
+pub struct ElectrumSeed {
+    account_key: Xpriv,
+    seed_type: SeedType,
+    network: Network,
+    context: SecpContext
+}
+
+impl ElectrumSeed {
+    /// decode the seed words with the optional seed extension Electrum calls passphrase
+    pub fn new(mnemonic: &str, passphrase: Option<&str>, network: Network) -> Result<ElectrumSeed, WalletError> {
+        let seed_type = seed_type(mnemonic).ok_or(WalletError::Mnemonic("not an Electrum standard or segwit seed"))?;
+        let mut words = normalize(mnemonic);
+        let mut salt = "electrum".to_owned() + &normalize(passphrase.unwrap_or(""));
+        let mut output = SecretBytes::new(vec!(0u8; 64));
+        pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA512, NonZeroU32::new(2048).expect("non zero"), salt.as_bytes(), words.as_bytes(), output.as_mut_slice());
+        words.zeroize();
+        salt.zeroize();
+        let context = SecpContext::new();
+        let master = context.master_private_key(network, &Seed(output))?;
+        let account_key = match seed_type {
+            SeedType::Standard => master,
+            SeedType::Segwit => context.private_child(&master, ChildNumber::Hardened { index: 0 })?
+        };
+        Ok(ElectrumSeed { account_key, seed_type, network, context })
+    }
+
+    pub fn seed_type(&self) -> SeedType {
+        self.seed_type
+    }
+
+    /// script type of the addresses
+    pub fn address_type(&self) -> AccountAddressType {
+        match self.seed_type {
+            SeedType::Standard => AccountAddressType::P2PKH,
+            SeedType::Segwit => AccountAddressType::P2WPKH
+        }
+    }
+
+    /// the key of a receive or change address
+    pub fn private_key(&self, change: bool, index: u32) -> Result<PrivateKey, WalletError> {
+        let chain = self.context.private_child(&self.account_key, ChildNumber::Normal { index: change as u32 })?;
+        Ok(self.context.private_child(&chain, ChildNumber::Normal { index })?.to_priv())
+    }
+
+    /// a receive or change address
+    pub fn address(&self, change: bool, index: u32) -> Result<Address, WalletError> {
+        let key = self.private_key(change, index)?;
+        let public = key.public_key(&secp256k1::Secp256k1::signing_only());
+        Ok(match self.seed_type {
+            SeedType::Standard => Address::p2pkh(public, self.network),
+            SeedType::Segwit => Address::p2wpkh(&CompressedPublicKey::try_from(public).map_err(|_| WalletError::Unsupported("uncompressed key"))?, self.network)
+        })
+    }
+
+    /// a signed transaction moving all coins of the first receive and change keys to destination,
+    /// gap receive keys and half as many change keys are looked up at the source
+    pub fn sweep(&self, gap: u32, fee_rate: FeeRate, source: &mut dyn UtxoSource, destination: ScriptBuf) -> Result<Transaction, WalletError> {
+        let mut keys = Vec::new();
+        for (change, count) in [(false, gap), (true, gap.div_ceil(2))] {
+            for index in 0..count {
+                keys.push((self.private_key(change, index)?, self.address_type()));
+            }
+        }
+        sweep::sweep_keys(&keys, fee_rate, source, destination)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_electrum_seed() {
+        let segwit = "bitter grass shiver impose acquire brush forget axis eager alone wine silver";
+        assert_eq!(seed_type(segwit), Some(SeedType::Segwit));
+        let seed = ElectrumSeed::new(segwit, None, Network::Bitcoin).unwrap();
+        assert_eq!(seed.address(false, 0).unwrap().to_string(), "bc1q3g5tmkmlvxryhh843v4dz026avatc0zzr6h3af");
+        assert_eq!(seed.address(true, 0).unwrap().to_string(), "bc1qdy94n2q5qcp0kg7v9yzwe6wvfkhnvyzje7nx2p");
+
+        let standard = "cycle rocket west magnet parrot shuffle foot correct salt library feed song";
+        assert_eq!(seed_type(standard), Some(SeedType::Standard));
+        let seed = ElectrumSeed::new(standard, None, Network::Bitcoin).unwrap();
+        assert_eq!(seed.address(false, 0).unwrap().to_string(), "1NNkttn1YvVGdqBW4PR6zvc3Zx3H5owKRf");
+        assert_eq!(seed.address(true, 0).unwrap().to_string(), "1KSezYMhAJMWqFbVFB2JshYg69UpmEXR4D");
+
+        // a BIP39 mnemonic
+        assert!(ElectrumSeed::new("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about", None, Network::Bitcoin).is_err());
+    }
+}
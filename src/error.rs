@@ -21,81 +21,152 @@
 
 
 use std::convert;
-use std::error::Error;
 use std::fmt;
 use std::io;
-use bitcoin::util::bip32;
+use bitcoin::{bip32, Amount, OutPoint, Txid};
+use bitcoin::bip158;
+use bitcoin::bip32::DerivationPath;
+use bitcoin::psbt;
+use bitcoin::sighash;
+use bitcoin::base64;
+#[cfg(not(target_arch = "wasm32"))]
 use crypto::symmetriccipher;
+use thiserror::Error;
 
 
-/// An error class to offer a unified error interface upstream
+/// An error class to offer a unified error interface upstream.
+/// New variants may be added, match with a wildcard arm.
+#[derive(Error)]
+#[non_exhaustive]
 pub enum WalletError {
     /// Unsupported
+    #[error("Unsupported: {0}")]
     Unsupported(&'static str),
     /// mnemonic related error
+    #[error("Mnemonic: {0}")]
     Mnemonic(&'static str),
+    /// SLIP-39 share related error
+    #[error("SLIP-39: {0}")]
+    Slip39(&'static str),
+    /// MuSig2 signing session error
+    #[error("MuSig2: {0}")]
+    MuSig(&'static str),
+    /// invalid payment URI
+    #[error("Payment URI: {0}")]
+    Uri(&'static str),
+    /// invalid BIP47 payment code or notification
+    #[error("BIP47 payment code: {0}")]
+    PaymentCode(&'static str),
     /// wrong passphrase
+    #[error("wrong passphrase")]
     Passphrase,
+    /// the key is cold, unlock with the passphrase to use it
+    #[error("locked, unlock with the passphrase")]
+    Locked,
     /// wrong network
+    #[error("wrong network")]
     Network,
+    /// networking is disabled, see the offline module
+    #[error("offline, networking is disabled")]
+    Offline,
+    /// not enough funds for a payment and its fee
+    #[error("insufficient funds")]
+    InsufficientFunds,
+    /// an output worth less than the fee to spend it
+    #[error("output of {0} is dust")]
+    Dust(Amount),
+    /// an address that does not parse
+    #[error("invalid address: {0}")]
+    InvalidAddress(String),
+    /// stored wallet state that does not restore
+    #[error("corrupt wallet store: {0}")]
+    StoreCorrupt(String),
+    /// the master has no account of this number and sub account number
+    #[error("no account {account}/{sub_account}")]
+    UnknownAccount { account: u32, sub_account: u32 },
+    /// a coin the wallet does not own
+    #[error("coin {0} is not owned")]
+    UnknownCoin(OutPoint),
+    /// a frozen coin was to be spent
+    #[error("coin {0} is frozen")]
+    FrozenCoin(OutPoint),
+    /// a transaction that does not signal BIP125 replaceability
+    #[error("transaction {0} does not signal replaceability")]
+    NotReplaceable(Txid),
+    /// a key of a path does not derive
+    #[error("can not derive m/{path}: {source}")]
+    Derivation { path: DerivationPath, #[source] source: bip32::Error },
     /// Network IO error
-    IO(io::Error),
+    #[error("IO error: {0}")]
+    IO(#[from] io::Error),
     /// key derivation error
-    KeyDerivation(bip32::Error),
+    #[error("BIP32 error: {0}")]
+    KeyDerivation(#[from] bip32::Error),
     /// sekp256k1 error
-    SecpError(secp256k1::Error),
-    /// cipher error
-    SymmetricCipherError(symmetriccipher::SymmetricCipherError)
+    #[error("Secp256k1 error: {0}")]
+    SecpError(#[from] secp256k1::Error),
+    /// cipher error of the legacy keystore or BIP38
+    #[cfg(not(target_arch = "wasm32"))]
+    #[error("Cipher error: {}", cipher_error(.0))]
+    SymmetricCipherError(symmetriccipher::SymmetricCipherError),
+    /// PSBT error
+    #[error("PSBT error: {0}")]
+    PSBT(#[from] psbt::Error),
+    /// PSBT signing error
+    #[error("PSBT signing error: {0}")]
+    PSBTSign(#[from] psbt::SignError),
+    /// taproot signature hash error, e.g. SIGHASH_SINGLE without an output at the index of the input
+    #[error("Taproot sighash error: {0}")]
+    TaprootSighash(#[from] sighash::TaprootError),
+    /// base64 decoding error
+    #[error("Base64 error: {0}")]
+    Base64(#[source] base64::DecodeError),
+    /// error reported by or talking to an Electrum server
+    #[error("Electrum error: {0}")]
+    Electrum(String),
+    /// error reported by or talking to an Esplora server
+    #[error("Esplora error: {0}")]
+    Esplora(String),
+    /// error reported by or talking to a Bitcoin Core node
+    #[error("Bitcoin Core RPC error: {0}")]
+    Rpc(String),
+    /// compact block filter error
+    #[error("Block filter error: {0}")]
+    BlockFilter(#[from] bip158::Error),
+    /// error talking to a peer of the bitcoin network
+    #[error("P2P error: {0}")]
+    P2P(String),
+    /// error reported by or talking to a hardware signer
+    #[error("Hardware signer error: {0}")]
+    Signer(String),
+    /// error reported by or talking to a fee estimation service
+    #[error("Fee estimation error: {0}")]
+    Fees(String),
+    /// no endpoint accepted a transaction
+    #[error("Broadcast error: {0}")]
+    Broadcast(String),
+    /// error talking to a SOCKS5 proxy
+    #[error("SOCKS5 proxy error: {0}")]
+    Proxy(String),
+    /// a payjoin endpoint failed or proposed an invalid transaction
+    #[error("Payjoin error: {0}")]
+    Payjoin(String),
+    /// a spending policy does not compile or can not be satisfied
+    #[error("Policy error: {0}")]
+    Policy(String),
+    /// a QR code payload is malformed or incomplete
+    #[error("QR code error: {0}")]
+    Qr(String),
+    /// error of the SQLite database of a wallet store
+    #[error("SQLite error: {0}")]
+    Sqlite(String)
 }
 
-impl Error for WalletError {
-    fn description(&self) -> &str {
-        match *self {
-            WalletError::Passphrase => "wrong passphrase",
-            WalletError::Network => "wrong network",
-            WalletError::Unsupported(ref s) => s,
-            WalletError::Mnemonic(ref s) => s,
-            WalletError::IO(ref err) => err.description(),
-            WalletError::KeyDerivation(ref err) => err.description(),
-            WalletError::SecpError(ref err) => err.description(),
-            WalletError::SymmetricCipherError(ref err) => match err {
-                &symmetriccipher::SymmetricCipherError::InvalidLength => "invalid length",
-                &symmetriccipher::SymmetricCipherError::InvalidPadding => "invalid padding"
-            }
-        }
-    }
-
-    fn cause(&self) -> Option<&dyn Error> {
-        match *self {
-            WalletError::Network => None,
-            WalletError::Passphrase => None,
-            WalletError::Unsupported(_) => None,
-            WalletError::Mnemonic(_) => None,
-            WalletError::IO(ref err) => Some(err),
-            WalletError::KeyDerivation(ref err) => Some(err),
-            WalletError::SecpError(ref err) => Some(err),
-            WalletError::SymmetricCipherError(_) => None
-        }
-    }
-}
-
-impl fmt::Display for WalletError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match *self {
-            // Both underlying errors already impl `Display`, so we defer to
-            // their implementations.
-            WalletError::Passphrase => write!(f, "wrong passphrase"),
-            WalletError::Network => write!(f, "wrong network"),
-            WalletError::Unsupported(ref s) => write!(f, "Unsupported: {}", s),
-            WalletError::Mnemonic(ref s) => write!(f, "Mnemonic: {}", s),
-            WalletError::IO(ref err) => write!(f, "IO error: {}", err),
-            WalletError::KeyDerivation(ref err) => write!(f, "BIP32 error: {}", err),
-            WalletError::SecpError(ref err) => write!(f, "Secp256k1 error: {}", err),
-            WalletError::SymmetricCipherError(ref err) => write!(f, "Cipher error: {}", match err {
-                &symmetriccipher::SymmetricCipherError::InvalidLength => "invalid length",
-                &symmetriccipher::SymmetricCipherError::InvalidPadding => "invalid padding"
-            })
-        }
+#[cfg(not(target_arch = "wasm32"))]
+fn cipher_error(err: &symmetriccipher::SymmetricCipherError) -> &'static str {
+    match *err {
+        symmetriccipher::SymmetricCipherError::InvalidLength => "invalid length",
+        symmetriccipher::SymmetricCipherError::InvalidPadding => "invalid padding"
     }
 }
 
@@ -109,32 +180,67 @@ impl convert::From<WalletError> for io::Error {
     fn from(err: WalletError) -> io::Error {
         match err {
             WalletError::IO(e) => e,
-            _ => io::Error::new(io::ErrorKind::Other, err.description())
+            _ => io::Error::other(err.to_string())
         }
     }
 }
 
-impl convert::From<io::Error> for WalletError {
-    fn from(err: io::Error) -> WalletError {
-        WalletError::IO(err)
+#[cfg(not(target_arch = "wasm32"))]
+impl convert::From<symmetriccipher::SymmetricCipherError> for WalletError {
+    fn from(err: symmetriccipher::SymmetricCipherError) -> WalletError {
+        WalletError::SymmetricCipherError(err)
     }
 }
 
-impl convert::From<bip32::Error> for WalletError {
-    fn from(err: bip32::Error) -> WalletError {
-        WalletError::KeyDerivation(err)
+impl convert::From<psbt::PsbtParseError> for WalletError {
+    fn from(err: psbt::PsbtParseError) -> WalletError {
+        match err {
+            psbt::PsbtParseError::PsbtEncoding(e) => WalletError::PSBT(e),
+            psbt::PsbtParseError::Base64Encoding(e) => WalletError::Base64(e),
+            _ => WalletError::Unsupported("unknown PSBT encoding error")
+        }
     }
 }
 
-impl convert::From<symmetriccipher::SymmetricCipherError> for WalletError {
-    fn from(err: symmetriccipher::SymmetricCipherError) -> WalletError {
-        WalletError::SymmetricCipherError(err)
-    }
-}
+#[cfg(test)]
+mod test {
+    use std::error::Error;
+    use std::str::FromStr;
+
+    use bitcoin::{Network, ScriptBuf};
 
+    use account::{MasterAccount, MasterKeyEntropy, Unlocker};
+    use coins::Coins;
+    use builder::TxBuilder;
+    use super::*;
 
-impl convert::From<secp256k1::Error> for WalletError {
-    fn from(err: secp256k1::Error) -> WalletError {
-        WalletError::SecpError(err)
+    const PASSPHRASE: &str = "correct horse battery staple";
+
+    #[test]
+    fn test_error() {
+        let err = WalletError::from(io::Error::other("broken pipe"));
+        assert_eq!(err.to_string(), "IO error: broken pipe");
+        assert_eq!(err.source().unwrap().to_string(), "broken pipe");
+        assert!(WalletError::Passphrase.source().is_none());
+
+        let mut master = MasterAccount::new(MasterKeyEntropy::Low, Network::Bitcoin, PASSPHRASE, None).unwrap();
+        match master.next_key((1, 0)) {
+            Err(WalletError::UnknownAccount { account: 1, sub_account: 0 }) => {},
+            _ => panic!("no such account")
+        }
+        let point = OutPoint::null();
+        match TxBuilder::new().add_utxo(point).add_recipient(ScriptBuf::new_op_return([0u8; 4]), 1000).change_script(ScriptBuf::new_op_return([1u8; 4]))
+            .fee_rate(1).finish(&mut master, &Coins::new()) {
+            Err(WalletError::UnknownCoin(p)) => assert_eq!(p, point),
+            Err(e) => panic!("{}", e),
+            Ok(_) => panic!("coin is not owned")
+        }
+        let unlocker = Unlocker::new(master.encrypted(), PASSPHRASE, None, Network::Bitcoin, None).unwrap();
+        let deep = DerivationPath::from_str(&format!("m{}", "/0".repeat(256))).unwrap();
+        match unlocker.derive(&deep) {
+            Err(WalletError::Derivation { ref path, .. }) => assert_eq!(*path, deep),
+            Err(e) => panic!("{}", e),
+            Ok(_) => panic!("derived beyond depth 255")
+        }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,414 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Esplora client
+//!
+//! History, unspent outputs, fees and broadcast through the Esplora REST API of blockstream.info or mempool.space
+//!
+
+use std::convert::TryFrom;
+use std::fmt::Display;
+use std::io::{Read, Write};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bitcoin::{consensus, Amount, BlockHash, FeeRate, OutPoint, Script, ScriptBuf, Transaction, TxOut, Txid};
+use bitcoin::hex::{DisplayHex, FromHex};
+use bitcoin_hashes::{sha256, Hash};
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+use rustls::pki_types::ServerName;
+use serde_json::Value;
+
+use broadcast::Broadcaster;
+use chain::{ChainSource, Confirmation};
+use discovery::UsageSource;
+use error::WalletError;
+use fees::{self, FeeEstimator};
+use proxy::{self, Socks5Proxy};
+use sweep::UtxoSource;
+
+/// the API of blockstream.info
+pub const BLOCKSTREAM: &str = "https://blockstream.info/api";
+
+/// the API of mempool.space
+pub const MEMPOOL_SPACE: &str = "https://mempool.space/api";
+
+/// confirmed transactions of a script per page of its history
+pub const CHAIN_PAGE: usize = 25;
+
+/// attempts of a request the server throttles before giving up
+const MAX_ATTEMPTS: u32 = 4;
+
+/// a client of an Esplora server, each request is a new HTTP connection
+pub struct Client {
+    tls: bool,
+    host: String,
+    target: String,
+    prefix: String,
+    proxy: Option<Socks5Proxy>,
+    interval: Duration,
+    last_request: Option<Instant>
+}
+
+impl Client {
+    /// the API at url, e.g. BLOCKSTREAM or http://127.0.0.1:3002 of an own instance
+    pub fn new(url: &str) -> Result<Client, WalletError> {
+        let (tls, rest) = match (url.strip_prefix("https://"), url.strip_prefix("http://")) {
+            (Some(rest), _) => (true, rest),
+            (_, Some(rest)) => (false, rest),
+            _ => return Err(WalletError::Esplora(format!("not an HTTP URL: {}", url)))
+        };
+        let (authority, prefix) = match rest.find('/') {
+            Some(ix) => (&rest[..ix], rest[ix..].trim_end_matches('/')),
+            None => (rest, "")
+        };
+        let host = authority.rsplit_once(':').map(|(host, _)| host).unwrap_or(authority);
+        let target = if authority.contains(':') { authority.to_string() } else { format!("{}:{}", host, if tls { 443 } else { 80 }) };
+        Ok(Client { tls, host: host.to_string(), target, prefix: prefix.to_string(), proxy: None, interval: Duration::ZERO, last_request: None })
+    }
+
+    /// connect through a SOCKS5 proxy, e.g. Tor
+    pub fn with_proxy(mut self, proxy: Socks5Proxy) -> Client {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    /// wait at least interval between requests, public instances throttle clients asking more often
+    pub fn with_rate_limit(mut self, interval: Duration) -> Client {
+        self.interval = interval;
+        self
+    }
+
+    /// GET, or POST if a body is given, a path of the API and return the body of the response.
+    /// Throttled requests are tried again after the delay the server asks for or a doubling backoff
+    pub fn request(&mut self, path: &str, body: Option<&str>) -> Result<String, WalletError> {
+        let mut backoff = self.interval.max(Duration::from_secs(1));
+        let mut attempt = 1;
+        loop {
+            if let Some(elapsed) = self.last_request.map(|last| last.elapsed()) {
+                if elapsed < self.interval {
+                    thread::sleep(self.interval - elapsed);
+                }
+            }
+            self.last_request = Some(Instant::now());
+            let (status, retry_after, content) = self.exchange(path, body)?;
+            match status {
+                200 => return Ok(content),
+                429 | 503 if attempt < MAX_ATTEMPTS => {
+                    thread::sleep(retry_after.unwrap_or(backoff));
+                    backoff *= 2;
+                    attempt += 1;
+                }
+                _ => return Err(WalletError::Esplora(format!("HTTP status {}: {}", status, content.trim())))
+            }
+        }
+    }
+
+    fn get_json(&mut self, path: &str) -> Result<Value, WalletError> {
+        serde_json::from_str(&self.request(path, None)?).map_err(invalid)
+    }
+
+    /// transactions spending from or paying to a script, confirmed ones in ascending height order, unconfirmed ones last.
+    /// Pages of confirmed transactions are fetched until the oldest
+    pub fn get_script_history(&mut self, script_pubkey: &Script) -> Result<Vec<(Txid, Confirmation)>, WalletError> {
+        let hash = script_hash(script_pubkey);
+        let mut page = self.get_json(&format!("/scripthash/{}/txs", hash))?;
+        let mut history = Vec::new();
+        loop {
+            let entries = page.as_array().ok_or_else(|| unexpected("transactions"))?.iter().map(|entry| {
+                let txid = parse_txid(&entry["txid"])?;
+                Ok((txid, parse_status(&entry["status"])?))
+            }).collect::<Result<Vec<_>, WalletError>>()?;
+            let confirmed = entries.iter().filter(|(_, c)| c.is_some()).map(|(txid, _)| *txid).collect::<Vec<_>>();
+            history.extend(entries);
+            match confirmed.last() {
+                Some(last) if confirmed.len() >= CHAIN_PAGE => page = self.get_json(&format!("/scripthash/{}/txs/chain/{}", hash, last))?,
+                _ => break
+            }
+        }
+        history.sort_by_key(|(txid, confirmed)| (confirmed.is_none(), confirmed.map(|(height, _)| height), *txid));
+        history.dedup();
+        Ok(history)
+    }
+
+    /// unspent outputs of a script, confirmed or not
+    pub fn list_unspent(&mut self, script_pubkey: &Script) -> Result<Vec<(OutPoint, TxOut)>, WalletError> {
+        let unspent = self.get_json(&format!("/scripthash/{}/utxo", script_hash(script_pubkey)))?;
+        unspent.as_array().ok_or_else(|| unexpected("unspent"))?.iter().map(|u| {
+            let txid = parse_txid(&u["txid"])?;
+            let vout = u["vout"].as_u64().ok_or_else(|| unexpected("vout"))? as u32;
+            let value = Amount::from_sat(u["value"].as_u64().ok_or_else(|| unexpected("value"))?);
+            Ok((OutPoint { txid, vout }, TxOut { value, script_pubkey: script_pubkey.to_owned() }))
+        }).collect()
+    }
+
+    pub fn get_transaction(&mut self, txid: &Txid) -> Result<Transaction, WalletError> {
+        let raw = self.request(&format!("/tx/{}/hex", txid), None)?;
+        let transaction: Transaction = consensus::deserialize(&Vec::<u8>::from_hex(raw.trim()).map_err(invalid)?).map_err(invalid)?;
+        if transaction.compute_txid() != *txid {
+            return Err(unexpected("transaction"));
+        }
+        Ok(transaction)
+    }
+
+    /// send a transaction to the network
+    pub fn broadcast(&mut self, transaction: &Transaction) -> Result<Txid, WalletError> {
+        let txid = self.request("/tx", Some(&consensus::encode::serialize_hex(transaction)))?;
+        Txid::from_str(txid.trim()).map_err(invalid)
+    }
+
+    /// fee rates of the server for confirmation windows in blocks, shortest first
+    pub fn fee_estimates(&mut self) -> Result<Vec<(u32, FeeRate)>, WalletError> {
+        let estimates = self.get_json("/fee-estimates")?;
+        let mut rates = estimates.as_object().ok_or_else(|| unexpected("fee estimates"))?.iter().filter_map(|(blocks, sat_per_vb)| {
+            let blocks = blocks.parse::<u32>().ok()?;
+            // 1 sat/vB = 0.00001 BTC/kvB
+            Some((blocks, fees::from_btc_per_kvb(sat_per_vb.as_f64()? / 100_000.0)?))
+        }).collect::<Vec<_>>();
+        rates.sort_by_key(|(blocks, _)| *blocks);
+        Ok(rates)
+    }
+}
+
+/// the rate of the longest window within blocks, or of the shortest if none is
+fn for_blocks(rates: &[(u32, FeeRate)], blocks: u32) -> Option<FeeRate> {
+    rates.iter().rev().find(|(window, _)| *window <= blocks).or(rates.first()).map(|(_, rate)| *rate)
+}
+
+impl Client {
+    fn exchange(&self, path: &str, body: Option<&str>) -> Result<(u16, Option<Duration>, String), WalletError> {
+        fn send<S: Read + Write>(mut stream: S, request: &str) -> Result<Vec<u8>, WalletError> {
+            stream.write_all(request.as_bytes())?;
+            stream.flush()?;
+            let mut response = Vec::new();
+            // servers may close without TLS close_notify
+            if let Err(e) = stream.read_to_end(&mut response) {
+                if e.kind() != std::io::ErrorKind::UnexpectedEof {
+                    return Err(e.into());
+                }
+            }
+            Ok(response)
+        }
+
+        // HTTP/1.0 responses are not chunked
+        let request = match body {
+            Some(body) => format!("POST {}{} HTTP/1.0\r\nHost: {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n{}",
+                                  self.prefix, path, self.host, body.len(), body),
+            None => format!("GET {}{} HTTP/1.0\r\nHost: {}\r\n\r\n", self.prefix, path, self.host)
+        };
+        let stream = proxy::connect(&self.target, self.proxy.as_ref())?;
+        let response = if self.tls {
+            let roots = RootCertStore { roots: webpki_roots::TLS_SERVER_ROOTS.to_vec() };
+            let config = ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions().map_err(invalid)?
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+            let name = ServerName::try_from(self.host.clone()).map_err(invalid)?;
+            let connection = ClientConnection::new(Arc::new(config), name).map_err(invalid)?;
+            send(StreamOwned::new(connection, stream), &request)?
+        } else {
+            send(stream, &request)?
+        };
+        let response = String::from_utf8_lossy(&response);
+        let (head, content) = response.split_once("\r\n\r\n").ok_or_else(|| unexpected("HTTP response"))?;
+        let mut lines = head.lines();
+        let status = lines.next().and_then(|l| l.split_whitespace().nth(1)).and_then(|c| c.parse::<u16>().ok()).ok_or_else(|| unexpected("status"))?;
+        let retry_after = lines.filter_map(|l| l.split_once(':'))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("retry-after"))
+            .and_then(|(_, seconds)| seconds.trim().parse::<u64>().ok()).map(Duration::from_secs);
+        Ok((status, retry_after, content.to_string()))
+    }
+}
+
+impl ChainSource for Client {
+    fn get_history(&mut self, script_pubkey: &Script) -> Result<Vec<(Txid, Confirmation)>, WalletError> {
+        self.get_script_history(script_pubkey)
+    }
+
+    fn get_utxos(&mut self, script_pubkey: &Script) -> Result<Vec<(OutPoint, TxOut)>, WalletError> {
+        self.list_unspent(script_pubkey)
+    }
+
+    fn get_tx(&mut self, txid: &Txid) -> Result<Transaction, WalletError> {
+        self.get_transaction(txid)
+    }
+
+    fn broadcast(&mut self, transaction: &Transaction) -> Result<Txid, WalletError> {
+        Client::broadcast(self, transaction)
+    }
+
+    fn estimate_fee(&mut self, blocks: u32) -> Result<FeeRate, WalletError> {
+        self.estimate(blocks)
+    }
+
+    fn tip(&mut self) -> Result<(u32, BlockHash), WalletError> {
+        let block_hash = BlockHash::from_str(self.request("/blocks/tip/hash", None)?.trim()).map_err(invalid)?;
+        let block = self.get_json(&format!("/block/{}", block_hash))?;
+        Ok((block["height"].as_u64().ok_or_else(|| unexpected("height"))? as u32, block_hash))
+    }
+}
+
+impl Broadcaster for Client {
+    fn broadcast(&mut self, transaction: &Transaction) -> Result<Txid, WalletError> {
+        Client::broadcast(self, transaction)
+    }
+}
+
+impl UtxoSource for Client {
+    fn unspent(&mut self, script_pubkey: &Script) -> Result<Vec<(OutPoint, TxOut)>, WalletError> {
+        self.list_unspent(script_pubkey)
+    }
+}
+
+impl UsageSource for Client {
+    fn used(&mut self, scripts: &[ScriptBuf]) -> Result<Vec<bool>, WalletError> {
+        scripts.iter().map(|s| {
+            let stats = self.get_json(&format!("/scripthash/{}", script_hash(s)))?;
+            Ok(stats["chain_stats"]["tx_count"].as_u64().unwrap_or(0) + stats["mempool_stats"]["tx_count"].as_u64().unwrap_or(0) > 0)
+        }).collect()
+    }
+}
+
+impl FeeEstimator for Client {
+    fn estimate(&mut self, blocks: u32) -> Result<FeeRate, WalletError> {
+        for_blocks(&self.fee_estimates()?, blocks).ok_or_else(|| WalletError::Fees(format!("no estimate for {} blocks", blocks)))
+    }
+
+    fn estimates(&mut self, windows: &[u32]) -> Result<Vec<(u32, FeeRate)>, WalletError> {
+        let rates = self.fee_estimates()?;
+        windows.iter().map(|blocks| Ok((*blocks, for_blocks(&rates, *blocks).ok_or_else(|| WalletError::Fees(format!("no estimate for {} blocks", blocks)))?))).collect()
+    }
+}
+
+/// the script hash Esplora indexes scripts by, as Electrum does
+pub fn script_hash(script_pubkey: &Script) -> String {
+    let mut hash = sha256::Hash::hash(script_pubkey.as_bytes()).to_byte_array();
+    hash.reverse();
+    hash.to_lower_hex_string()
+}
+
+fn parse_txid(txid: &Value) -> Result<Txid, WalletError> {
+    Txid::from_str(txid.as_str().ok_or_else(|| unexpected("txid"))?).map_err(invalid)
+}
+
+fn parse_status(status: &Value) -> Result<Confirmation, WalletError> {
+    if status["confirmed"].as_bool() != Some(true) {
+        return Ok(None);
+    }
+    let height = status["block_height"].as_u64().ok_or_else(|| unexpected("block_height"))? as u32;
+    let block_hash = BlockHash::from_str(status["block_hash"].as_str().ok_or_else(|| unexpected("block_hash"))?).map_err(invalid)?;
+    Ok(Some((height, block_hash)))
+}
+
+fn invalid<E: Display>(err: E) -> WalletError {
+    WalletError::Esplora(err.to_string())
+}
+
+fn unexpected(what: &str) -> WalletError {
+    WalletError::Esplora(format!("unexpected {} in response", what))
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+
+    use bitcoin::{ScriptBuf, WPubkeyHash};
+    use serde_json::json;
+
+    use super::*;
+
+    /// answers requests of paths with bodies, the first request is throttled
+    fn serve(listener: TcpListener, responses: Vec<(String, String)>) -> thread::JoinHandle<Vec<String>> {
+        thread::spawn(move || {
+            let mut paths = Vec::new();
+            for (n, stream) in listener.incoming().take(responses.len() + 2).enumerate() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let path = line.split_whitespace().nth(1).unwrap().to_string();
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    if header.trim().is_empty() {
+                        break;
+                    }
+                }
+                if n == 0 {
+                    write!(stream, "HTTP/1.0 429 Too Many Requests\r\nRetry-After: 0\r\n\r\nslow down").unwrap();
+                    continue;
+                }
+                let body = responses.iter().find(|(p, _)| *p == path).map(|(_, body)| body.clone());
+                match body {
+                    Some(body) => write!(stream, "HTTP/1.0 200 OK\r\n\r\n{}", body).unwrap(),
+                    None => write!(stream, "HTTP/1.0 404 Not Found\r\n\r\nnot found").unwrap()
+                }
+                paths.push(path);
+            }
+            paths
+        })
+    }
+
+    fn txid(n: usize) -> Txid {
+        let mut bytes = [0u8; 32];
+        bytes[..8].copy_from_slice(&(n as u64).to_le_bytes());
+        Txid::from_byte_array(bytes)
+    }
+
+    #[test]
+    fn test_esplora() {
+        assert!(Client::new("tcp://example.com").is_err());
+        let script_pubkey = ScriptBuf::new_p2wpkh(&WPubkeyHash::from_byte_array([4u8; 20]));
+        let hash = script_hash(&script_pubkey);
+        let block_hash = BlockHash::from_byte_array([1u8; 32]);
+        let entry = |n: usize, confirmed: bool| if confirmed {
+            json!({"txid": txid(n).to_string(), "status": {"confirmed": true, "block_height": 1000 - n, "block_hash": block_hash.to_string()}})
+        } else {
+            json!({"txid": txid(n).to_string(), "status": {"confirmed": false}})
+        };
+        // the mempool and a full page of confirmed transactions, newest first, then the rest
+        let mut first = vec!(entry(0, false));
+        first.extend((1..=CHAIN_PAGE).map(|n| entry(n, true)));
+        let rest = vec!(entry(CHAIN_PAGE + 1, true), entry(CHAIN_PAGE + 2, true));
+        let responses = vec!(
+            (format!("/api/scripthash/{}/txs", hash), Value::Array(first).to_string()),
+            (format!("/api/scripthash/{}/txs/chain/{}", hash, txid(CHAIN_PAGE)), Value::Array(rest).to_string()),
+            ("/api/fee-estimates".to_string(), json!({"1": 20.5, "6": 8.0, "144": 1.2}).to_string()),
+            ("/api/blocks/tip/hash".to_string(), block_hash.to_string()),
+            (format!("/api/block/{}", block_hash), json!({"id": block_hash.to_string(), "height": 1000}).to_string()));
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = serve(listener, responses);
+        let mut client = Client::new(&format!("http://{}/api/", address)).unwrap().with_rate_limit(Duration::from_millis(1));
+
+        let history = client.get_history(&script_pubkey).unwrap();
+        assert_eq!(history.len(), CHAIN_PAGE + 3);
+        assert_eq!(history[0], (txid(CHAIN_PAGE + 2), Some((998 - CHAIN_PAGE as u32, block_hash))));
+        assert_eq!(history.last(), Some(&(txid(0), None)));
+        let rates = client.estimates(&[1, 3, 1008]).unwrap();
+        assert_eq!(rates, vec!((1, FeeRate::from_sat_per_kwu(5125)), (3, FeeRate::from_sat_per_kwu(5125)), (1008, FeeRate::from_sat_per_kwu(300))));
+        assert_eq!(client.tip().unwrap(), (1000, block_hash));
+        assert!(client.get_utxos(&script_pubkey).is_err());
+
+        let paths = server.join().unwrap();
+        assert_eq!(paths[0], format!("/api/scripthash/{}/txs", hash));
+        assert_eq!(paths.len(), 6);
+    }
+}
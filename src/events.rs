@@ -0,0 +1,153 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Wallet events
+//!
+//! State changes of the wallet delivered to subscribers
+//!
+
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+use bitcoin::{BlockHash, ScriptBuf, TxOut, Txid};
+
+use account::KeyDerivation;
+use watch::MempoolEvent;
+
+/// a change of wallet state
+#[derive(Clone, Debug, PartialEq)]
+pub enum WalletEvent {
+    /// a transaction not spending wallet outputs pays to the wallet, vout, output and key of those outputs
+    PaymentReceived { txid: Txid, outputs: Vec<(u32, TxOut, KeyDerivation)> },
+    /// a wallet transaction was confirmed at height
+    PaymentConfirmed { txid: Txid, height: u32 },
+    /// an endpoint accepted a transaction
+    TxBroadcast { txid: Txid },
+    /// blocks were disconnected from the trunk, highest first
+    ReorgDetected { disconnected: Vec<BlockHash> },
+    /// `MasterAccount::next_key` handed out a new key for receiving or change
+    AddressGenerated { derivation: KeyDerivation, script_pubkey: ScriptBuf }
+}
+
+impl From<MempoolEvent> for WalletEvent {
+    fn from(event: MempoolEvent) -> WalletEvent {
+        match event {
+            MempoolEvent::Incoming(payment) => WalletEvent::PaymentReceived { txid: payment.txid(), outputs: payment.outputs },
+            MempoolEvent::Confirmed { txid, height } => WalletEvent::PaymentConfirmed { txid, height }
+        }
+    }
+}
+
+/// delivers events to all subscribers, clones publish to the same subscribers
+/// hand a clone to `UtxoSet::set_events`, `MasterAccount::set_events`, `MempoolWatcher::set_events`
+/// or `broadcast::Announcing` to have their changes published
+#[derive(Clone, Default)]
+pub struct EventBus {
+    subscribers: Arc<Mutex<Vec<Sender<WalletEvent>>>>
+}
+
+impl EventBus {
+    pub fn new() -> EventBus {
+        EventBus::default()
+    }
+
+    /// a receiver of all future events, it stops receiving once dropped
+    pub fn subscribe(&self) -> Receiver<WalletEvent> {
+        let (sender, receiver) = channel();
+        self.subscribers.lock().expect("event bus poisoned").push(sender);
+        receiver
+    }
+
+    /// deliver an event to all subscribers
+    pub fn publish(&self, event: WalletEvent) {
+        self.subscribers.lock().expect("event bus poisoned").retain(|s| s.send(event.clone()).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{Amount, Block, CompactTarget, Network, OutPoint, Sequence, Transaction, TxIn, TxMerkleNode, Witness};
+    use bitcoin::absolute::LockTime;
+    use bitcoin::block::{Header, Version as BlockVersion};
+    use bitcoin::transaction::Version;
+    use bitcoin_hashes::Hash;
+
+    use account::{AccountAddressType, MasterAccount, MasterKeyEntropy, Unlocker};
+    use broadcast::{Announcing, Broadcaster};
+    use error::WalletError;
+    use utxo::UtxoSet;
+    use super::*;
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+
+    struct Accepting;
+
+    impl Broadcaster for Accepting {
+        fn broadcast(&mut self, transaction: &Transaction) -> Result<Txid, WalletError> {
+            Ok(transaction.compute_txid())
+        }
+    }
+
+    #[test]
+    fn test_events() {
+        let bus = EventBus::new();
+        let events = bus.subscribe();
+        let mut master = MasterAccount::new(MasterKeyEntropy::Low, Network::Bitcoin, PASSPHRASE, None).unwrap();
+        let mut unlocker = Unlocker::new(master.encrypted(), PASSPHRASE, None, Network::Bitcoin, None).unwrap();
+        master.new_account(&mut unlocker, AccountAddressType::P2WPKH, 0, 0, 10).unwrap();
+        master.set_events(bus.clone());
+        let (key, _) = master.next_key((0, 0)).unwrap();
+        match events.try_recv().unwrap() {
+            WalletEvent::AddressGenerated { derivation, script_pubkey } => {
+                assert_eq!((derivation.account, derivation.sub, derivation.kix), (0, 0, 0));
+                assert_eq!(script_pubkey, key.script_pubkey);
+            }
+            _ => panic!("address generated")
+        }
+
+        let mut utxos = UtxoSet::new();
+        utxos.set_events(bus.clone());
+        let funding = Transaction {
+            version: Version::TWO, lock_time: LockTime::ZERO,
+            input: vec!(TxIn { previous_output: OutPoint { txid: Txid::all_zeros(), vout: 0 }, script_sig: ScriptBuf::new(), sequence: Sequence::MAX, witness: Witness::new() }),
+            output: vec!(TxOut { value: Amount::from_sat(50000), script_pubkey: key.script_pubkey.clone() })
+        };
+        let txid = funding.compute_txid();
+        utxos.process_unconfirmed(&mut master, &funding);
+        match events.try_recv().unwrap() {
+            WalletEvent::PaymentReceived { txid: received, outputs } => {
+                assert_eq!(received, txid);
+                assert_eq!(outputs[0].1.value, Amount::from_sat(50000));
+            }
+            _ => panic!("payment received")
+        }
+        let mut block = Block {
+            header: Header { version: BlockVersion::ONE, prev_blockhash: BlockHash::all_zeros(), merkle_root: TxMerkleNode::all_zeros(), time: 0, bits: CompactTarget::from_consensus(0), nonce: 0 },
+            txdata: vec!(funding.clone())
+        };
+        block.header.merkle_root = block.compute_merkle_root().unwrap();
+        utxos.process_block(&mut master, &block, 1);
+        assert_eq!(events.try_recv().unwrap(), WalletEvent::PaymentConfirmed { txid, height: 1 });
+        utxos.reorg(0);
+        assert_eq!(events.try_recv().unwrap(), WalletEvent::ReorgDetected { disconnected: vec!(block.block_hash()) });
+        assert!(utxos.reorg(0).is_empty());
+
+        let mut broadcaster = Announcing::new(Accepting, bus.clone());
+        broadcaster.broadcast(&funding).unwrap();
+        assert_eq!(events.try_recv().unwrap(), WalletEvent::TxBroadcast { txid });
+        assert!(events.try_recv().is_err());
+    }
+}
@@ -0,0 +1,188 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Fee estimation
+//!
+//! Fee rates for confirmation within a number of blocks from Electrum, Bitcoin Core or mempool.space
+//!
+
+use bitcoin::FeeRate;
+use serde_json::Value;
+
+use error::WalletError;
+#[cfg(all(feature = "mempool", not(feature = "offline")))]
+use proxy::{self, Socks5Proxy};
+
+/// confirmation within the next block
+pub const FAST: u32 = 1;
+/// confirmation within about an hour
+pub const NORMAL: u32 = 6;
+/// confirmation within about a day
+pub const ECONOMY: u32 = 144;
+
+/// source of fee rates
+pub trait FeeEstimator {
+    /// fee rate expected to confirm within blocks
+    fn estimate(&mut self, blocks: u32) -> Result<FeeRate, WalletError>;
+
+    /// fee rates for several confirmation windows at once
+    fn estimates(&mut self, windows: &[u32]) -> Result<Vec<(u32, FeeRate)>, WalletError> {
+        windows.iter().map(|blocks| Ok((*blocks, self.estimate(*blocks)?))).collect()
+    }
+}
+
+/// the same fee rate for any window, e.g. for tests or without network
+pub struct FixedFee(pub FeeRate);
+
+impl FeeEstimator for FixedFee {
+    fn estimate(&mut self, _blocks: u32) -> Result<FeeRate, WalletError> {
+        Ok(self.0)
+    }
+}
+
+/// a fee rate given in BTC per 1000 virtual bytes as Electrum and Bitcoin Core do,
+/// rounded up and at least 1 sat/vB. None for unknown (negative) rates.
+pub fn from_btc_per_kvb(btc_per_kvb: f64) -> Option<FeeRate> {
+    if !btc_per_kvb.is_finite() || btc_per_kvb < 0.0 {
+        return None;
+    }
+    // 1 BTC/kvB = 100000 sat/vB = 25000000 sat/kwu
+    let sat_per_kwu = (btc_per_kvb * 25_000_000.0).ceil() as u64;
+    Some(FeeRate::from_sat_per_kwu(sat_per_kwu.max(FeeRate::BROADCAST_MIN.to_sat_per_kwu())))
+}
+
+/// the recommended fees of the mempool.space API /api/v1/fees/recommended, in sat/vB
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Recommended {
+    pub fastest: u64,
+    pub half_hour: u64,
+    pub hour: u64,
+    pub economy: u64,
+    pub minimum: u64
+}
+
+impl Recommended {
+    /// parse the JSON response
+    pub fn from_json(json: &Value) -> Result<Recommended, WalletError> {
+        let field = |name: &str| json[name].as_u64().ok_or_else(|| WalletError::Fees(format!("no {} in response", name)));
+        Ok(Recommended {
+            fastest: field("fastestFee")?,
+            half_hour: field("halfHourFee")?,
+            hour: field("hourFee")?,
+            economy: field("economyFee")?,
+            minimum: field("minimumFee")?
+        })
+    }
+
+    /// the recommendation for confirmation within blocks
+    pub fn for_blocks(&self, blocks: u32) -> FeeRate {
+        let sat_per_vb = match blocks {
+            0..=1 => self.fastest,
+            2..=3 => self.half_hour,
+            4..=6 => self.hour,
+            _ => self.economy
+        };
+        FeeRate::from_sat_per_kwu(sat_per_vb.max(self.minimum).max(1).saturating_mul(250))
+    }
+}
+
+/// fee estimates of a mempool.space instance over HTTPS
+#[cfg(all(feature = "mempool", not(feature = "offline")))]
+pub struct MempoolSpace {
+    host: String,
+    proxy: Option<Socks5Proxy>
+}
+
+#[cfg(all(feature = "mempool", not(feature = "offline")))]
+impl MempoolSpace {
+    /// the instance at host, e.g. mempool.space
+    pub fn new(host: &str) -> MempoolSpace {
+        MempoolSpace { host: host.to_string(), proxy: None }
+    }
+
+    /// connect through a SOCKS5 proxy, e.g. Tor
+    pub fn with_proxy(mut self, proxy: Socks5Proxy) -> MempoolSpace {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    /// the current recommendations
+    pub fn recommended(&self) -> Result<Recommended, WalletError> {
+        use std::convert::TryFrom;
+        use std::io::{Read, Write};
+        use std::sync::Arc;
+        use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+        use rustls::pki_types::ServerName;
+
+        let invalid = |e: &dyn std::fmt::Display| WalletError::Fees(e.to_string());
+        let roots = RootCertStore { roots: webpki_roots::TLS_SERVER_ROOTS.to_vec() };
+        let config = ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions().map_err(|e| invalid(&e))?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let name = ServerName::try_from(self.host.clone()).map_err(|e| invalid(&e))?;
+        let connection = ClientConnection::new(Arc::new(config), name).map_err(|e| invalid(&e))?;
+        let mut stream = StreamOwned::new(connection, proxy::connect(&format!("{}:443", self.host), self.proxy.as_ref())?);
+        write!(stream, "GET /api/v1/fees/recommended HTTP/1.1\r\nHost: {}\r\nAccept: application/json\r\nConnection: close\r\n\r\n", self.host)?;
+        stream.flush()?;
+        let mut response = Vec::new();
+        // servers may close without TLS close_notify
+        if let Err(e) = stream.read_to_end(&mut response) {
+            if e.kind() != std::io::ErrorKind::UnexpectedEof {
+                return Err(e.into());
+            }
+        }
+        let response = String::from_utf8_lossy(&response);
+        let body = response.split_once("\r\n\r\n").map(|(_, body)| body).unwrap_or_default();
+        let json = serde_json::from_str(body).map_err(|e| invalid(&e))?;
+        Recommended::from_json(&json)
+    }
+}
+
+#[cfg(all(feature = "mempool", not(feature = "offline")))]
+impl FeeEstimator for MempoolSpace {
+    fn estimate(&mut self, blocks: u32) -> Result<FeeRate, WalletError> {
+        Ok(self.recommended()?.for_blocks(blocks))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+
+    fn sat_per_vb(rate: u64) -> FeeRate {
+        FeeRate::from_sat_per_vb(rate).unwrap()
+    }
+
+    #[test]
+    fn test_fees() {
+        assert_eq!(from_btc_per_kvb(0.00012345), Some(FeeRate::from_sat_per_kwu(3087)));
+        assert_eq!(from_btc_per_kvb(0.0), Some(FeeRate::BROADCAST_MIN));
+        assert_eq!(from_btc_per_kvb(-1.0), None);
+
+        let recommended = Recommended::from_json(&json!({"fastestFee": 30, "halfHourFee": 20, "hourFee": 10, "economyFee": 4, "minimumFee": 2})).unwrap();
+        assert_eq!(recommended.for_blocks(FAST), sat_per_vb(30));
+        assert_eq!(recommended.for_blocks(3), sat_per_vb(20));
+        assert_eq!(recommended.for_blocks(NORMAL), sat_per_vb(10));
+        assert_eq!(recommended.for_blocks(ECONOMY), sat_per_vb(4));
+        assert!(Recommended::from_json(&json!({"fastestFee": 30})).is_err());
+
+        let mut fixed = FixedFee(sat_per_vb(3));
+        assert_eq!(fixed.estimates(&[FAST, ECONOMY]).unwrap(), vec!((FAST, sat_per_vb(3)), (ECONOMY, sat_per_vb(3))));
+    }
+}
@@ -0,0 +1,327 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # C interface
+//!
+//! Create and restore wallets, derive addresses, sign PSBTs and encrypt seeds through a C ABI
+//!
+//! Wallets are opaque handles, strings are NUL terminated UTF-8. Functions return `WALLET_OK` or an
+//! error code and write results to out pointers. Release results with `wallet_free`, `wallet_string_free`
+//! and `wallet_bytes_free`. Build a shared or static library with e.g.
+//! `cargo rustc --release --features ffi --crate-type cdylib`
+//!
+
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+
+use bitcoin::Network;
+use zeroize::Zeroize;
+
+use account::{AccountAddressType, MasterAccount, MasterKeyEntropy, Unlocker};
+use error::WalletError;
+use keystore::{self, Cipher, Kdf};
+use mnemonic::Mnemonic;
+use psbt;
+
+pub const WALLET_OK: c_int = 0;
+/// null pointer, invalid UTF-8 or unknown network
+pub const WALLET_ERROR_ARGUMENT: c_int = 1;
+pub const WALLET_ERROR_PASSPHRASE: c_int = 2;
+pub const WALLET_ERROR_NETWORK: c_int = 3;
+/// malformed mnemonic or PSBT
+pub const WALLET_ERROR_FORMAT: c_int = 4;
+pub const WALLET_ERROR: c_int = 5;
+/// a bug in this library, the call panicked and was stopped at the C boundary
+pub const WALLET_ERROR_PANIC: c_int = 6;
+
+/// a master account and the network it is on
+pub struct Wallet {
+    master: MasterAccount,
+    network: Network
+}
+
+fn code(error: WalletError) -> c_int {
+    match error {
+        WalletError::Passphrase => WALLET_ERROR_PASSPHRASE,
+        WalletError::Network => WALLET_ERROR_NETWORK,
+        WalletError::Mnemonic(_) | WalletError::PSBT(_) | WalletError::Base64(_) => WALLET_ERROR_FORMAT,
+        _ => WALLET_ERROR
+    }
+}
+
+/// 0 bitcoin, 1 testnet, 2 signet, 3 regtest
+fn network(n: u32) -> Result<Network, c_int> {
+    match n {
+        0 => Ok(Network::Bitcoin),
+        1 => Ok(Network::Testnet),
+        2 => Ok(Network::Signet),
+        3 => Ok(Network::Regtest),
+        _ => Err(WALLET_ERROR_ARGUMENT)
+    }
+}
+
+unsafe fn string<'a>(s: *const c_char) -> Result<&'a str, c_int> {
+    if s.is_null() {
+        return Err(WALLET_ERROR_ARGUMENT);
+    }
+    CStr::from_ptr(s).to_str().map_err(|_| WALLET_ERROR_ARGUMENT)
+}
+
+unsafe fn put_string(out: *mut *mut c_char, s: String) -> Result<(), c_int> {
+    if out.is_null() {
+        return Err(WALLET_ERROR_ARGUMENT);
+    }
+    *out = CString::new(s).map_err(|_| WALLET_ERROR)?.into_raw();
+    Ok(())
+}
+
+unsafe fn put_bytes(out: *mut *mut u8, out_len: *mut usize, data: &[u8]) -> Result<(), c_int> {
+    if out.is_null() || out_len.is_null() {
+        return Err(WALLET_ERROR_ARGUMENT);
+    }
+    *out_len = data.len();
+    *out = Box::into_raw(data.to_vec().into_boxed_slice()) as *mut u8;
+    Ok(())
+}
+
+unsafe fn put_wallet(out: *mut *mut Wallet, wallet: Wallet) -> Result<(), c_int> {
+    if out.is_null() {
+        return Err(WALLET_ERROR_ARGUMENT);
+    }
+    *out = Box::into_raw(Box::new(wallet));
+    Ok(())
+}
+
+unsafe fn wallet<'a>(wallet: *mut Wallet) -> Result<&'a mut Wallet, c_int> {
+    wallet.as_mut().ok_or(WALLET_ERROR_ARGUMENT)
+}
+
+/// run the body of an exported function, a panic must not unwind into C
+fn status<F: FnOnce() -> Result<(), c_int>>(body: F) -> c_int {
+    match panic::catch_unwind(AssertUnwindSafe(body)) {
+        Ok(result) => result.err().unwrap_or(WALLET_OK),
+        Err(_) => WALLET_ERROR_PANIC
+    }
+}
+
+/// run the body of an exported function without result
+fn release<F: FnOnce()>(body: F) {
+    let _ = panic::catch_unwind(AssertUnwindSafe(body));
+}
+
+/// create a wallet with a new random 256 bit master key, encrypted with passphrase
+///
+/// # Safety
+/// passphrase must be a NUL terminated string, out a valid pointer
+#[no_mangle]
+pub unsafe extern "C" fn wallet_create(network_code: u32, passphrase: *const c_char, out: *mut *mut Wallet) -> c_int {
+    status(|| {
+        let network = network(network_code)?;
+        let master = MasterAccount::new(MasterKeyEntropy::Recommended, network, string(passphrase)?, None).map_err(code)?;
+        put_wallet(out, Wallet { master, network })
+    })
+}
+
+/// restore a wallet from its BIP39 mnemonic, birth is the unix time of its first use
+///
+/// # Safety
+/// mnemonic and passphrase must be NUL terminated strings, out a valid pointer
+#[no_mangle]
+pub unsafe extern "C" fn wallet_restore(mnemonic: *const c_char, birth: u64, network_code: u32, passphrase: *const c_char, out: *mut *mut Wallet) -> c_int {
+    status(|| {
+        let network = network(network_code)?;
+        let mnemonic = Mnemonic::from_str(string(mnemonic)?).map_err(code)?;
+        let master = MasterAccount::from_mnemonic(&mnemonic, birth, network, string(passphrase)?, None).map_err(code)?;
+        put_wallet(out, Wallet { master, network })
+    })
+}
+
+/// release a wallet
+///
+/// # Safety
+/// wallet must be null or a handle of wallet_create or wallet_restore not yet released
+#[no_mangle]
+pub unsafe extern "C" fn wallet_free(wallet: *mut Wallet) {
+    release(|| if !wallet.is_null() {
+        drop(Box::from_raw(wallet));
+    })
+}
+
+/// the mnemonic of the wallet
+///
+/// # Safety
+/// wallet must be a valid handle, passphrase a NUL terminated string, out a valid pointer
+#[no_mangle]
+pub unsafe extern "C" fn wallet_mnemonic(wallet_handle: *mut Wallet, passphrase: *const c_char, out: *mut *mut c_char) -> c_int {
+    status(|| {
+        let wallet = wallet(wallet_handle)?;
+        let mnemonic = wallet.master.mnemonic(string(passphrase)?).map_err(code)?;
+        put_string(out, mnemonic.to_string())
+    })
+}
+
+/// the address of key kix of an account, address_type is the BIP44 purpose (44, 49, 84 or 86),
+/// sub_account is 0 for receive and 1 for change. Accounts are created on first use.
+///
+/// # Safety
+/// wallet must be a valid handle, passphrase a NUL terminated string, out a valid pointer
+#[no_mangle]
+pub unsafe extern "C" fn wallet_address(wallet_handle: *mut Wallet, passphrase: *const c_char, address_type: u32, account: u32, sub_account: u32, kix: u32,
+                                        out: *mut *mut c_char) -> c_int {
+    status(|| {
+        let wallet = wallet(wallet_handle)?;
+        let address_type = match AccountAddressType::from_u32(address_type) {
+            AccountAddressType::P2WSH(_) => return Err(WALLET_ERROR_ARGUMENT),
+            address_type => address_type
+        };
+        if wallet.master.get((account, sub_account)).map(|a| a.address_type() != address_type).unwrap_or(false) {
+            return Err(WALLET_ERROR_ARGUMENT);
+        }
+        if wallet.master.get((account, sub_account)).is_none() {
+            let mut unlocker = Unlocker::new(wallet.master.encrypted(), string(passphrase)?, None, wallet.network, Some(wallet.master.master_public())).map_err(code)?;
+            wallet.master.new_account(&mut unlocker, address_type, account, sub_account, 0).map_err(code)?;
+        }
+        let account = wallet.master.get_mut((account, sub_account)).expect("account created above");
+        account.do_look_ahead(kix.checked_add(1).ok_or(WALLET_ERROR)?).map_err(code)?;
+        put_string(out, account.get_key(kix).ok_or(WALLET_ERROR)?.address.to_string())
+    })
+}
+
+/// sign inputs of a base64 PSBT with keys of the wallet's accounts, the PSBT must carry the spent outputs
+///
+/// # Safety
+/// wallet must be a valid handle, passphrase and psbt NUL terminated strings, out and signed valid pointers
+#[no_mangle]
+pub unsafe extern "C" fn wallet_sign_psbt(wallet_handle: *mut Wallet, passphrase: *const c_char, psbt: *const c_char,
+                                          out: *mut *mut c_char, signed: *mut usize) -> c_int {
+    status(|| {
+        let wallet = wallet(wallet_handle)?;
+        if signed.is_null() {
+            return Err(WALLET_ERROR_ARGUMENT);
+        }
+        let mut psbt = psbt::from_base64(string(psbt)?).map_err(code)?;
+        let mut unlocker = Unlocker::new(wallet.master.encrypted(), string(passphrase)?, None, wallet.network, Some(wallet.master.master_public())).map_err(code)?;
+        *signed = psbt::sign(&wallet.master, &mut psbt, &mut unlocker).map_err(code)?;
+        put_string(out, psbt::to_base64(&psbt))
+    })
+}
+
+/// encrypt a seed or other secret into a keystore
+///
+/// # Safety
+/// data must point to len readable bytes, passphrase must be a NUL terminated string, out and out_len valid pointers
+#[no_mangle]
+pub unsafe extern "C" fn wallet_encrypt_seed(data: *const u8, len: usize, passphrase: *const c_char, out: *mut *mut u8, out_len: *mut usize) -> c_int {
+    status(|| {
+        if data.is_null() {
+            return Err(WALLET_ERROR_ARGUMENT);
+        }
+        let encrypted = keystore::encrypt(slice::from_raw_parts(data, len), string(passphrase)?, Cipher::Aes256Gcm, Kdf::default()).map_err(code)?;
+        put_bytes(out, out_len, &encrypted)
+    })
+}
+
+/// decrypt a keystore of wallet_encrypt_seed
+///
+/// # Safety
+/// data must point to len readable bytes, passphrase must be a NUL terminated string, out and out_len valid pointers
+#[no_mangle]
+pub unsafe extern "C" fn wallet_decrypt_seed(data: *const u8, len: usize, passphrase: *const c_char, out: *mut *mut u8, out_len: *mut usize) -> c_int {
+    status(|| {
+        if data.is_null() {
+            return Err(WALLET_ERROR_ARGUMENT);
+        }
+        let decrypted = keystore::decrypt(slice::from_raw_parts(data, len), string(passphrase)?).map_err(code)?;
+        put_bytes(out, out_len, decrypted.as_slice())
+    })
+}
+
+/// release and wipe a string returned by this library
+///
+/// # Safety
+/// s must be null or a string returned by this library not yet released
+#[no_mangle]
+pub unsafe extern "C" fn wallet_string_free(s: *mut c_char) {
+    release(|| if !s.is_null() {
+        let mut bytes = CString::from_raw(s).into_bytes();
+        bytes.zeroize();
+    })
+}
+
+/// release and wipe bytes returned by this library
+///
+/// # Safety
+/// data must be null or bytes returned by this library with their length, not yet released
+#[no_mangle]
+pub unsafe extern "C" fn wallet_bytes_free(data: *mut u8, len: usize) {
+    release(|| if !data.is_null() {
+        let mut bytes = Box::from_raw(ptr::slice_from_raw_parts_mut(data, len));
+        bytes.zeroize();
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const PASSPHRASE: &[u8] = b"correct horse battery staple\0";
+    const WORDS: &[u8] = b"abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about\0";
+
+    unsafe fn take(s: *mut c_char) -> String {
+        let result = CStr::from_ptr(s).to_str().unwrap().to_string();
+        wallet_string_free(s);
+        result
+    }
+
+    #[test]
+    fn test_ffi() {
+        unsafe {
+            let passphrase = PASSPHRASE.as_ptr() as *const c_char;
+            let mut wallet = ptr::null_mut();
+            assert_eq!(wallet_restore(WORDS.as_ptr() as *const c_char, 0, 0, passphrase, &mut wallet), WALLET_OK);
+            let mut address = ptr::null_mut();
+            assert_eq!(wallet_address(wallet, passphrase, 84, 0, 0, 0, &mut address), WALLET_OK);
+            // m/84'/0'/0'/0/0 of BIP84
+            assert_eq!(take(address), "bc1qcr8te4kr609gcawutmrza0j4xv80jy8z306fyu");
+            assert_eq!(wallet_address(wallet, passphrase, 44, 0, 0, 0, &mut address), WALLET_ERROR_ARGUMENT);
+            assert_eq!(wallet_address(wallet, passphrase, 84, 0, 0, u32::MAX, &mut address), WALLET_ERROR);
+            let mut mnemonic = ptr::null_mut();
+            assert_eq!(wallet_mnemonic(wallet, b"wrong\0".as_ptr() as *const c_char, &mut mnemonic), WALLET_ERROR_PASSPHRASE);
+            assert_eq!(wallet_mnemonic(wallet, passphrase, &mut mnemonic), WALLET_OK);
+            assert_eq!(take(mnemonic).as_bytes(), &WORDS[..WORDS.len() - 1]);
+            let mut signed = 0;
+            let mut psbt = ptr::null_mut();
+            assert_eq!(wallet_sign_psbt(wallet, passphrase, b"cHNidP8=\0".as_ptr() as *const c_char, &mut psbt, &mut signed), WALLET_ERROR_FORMAT);
+            wallet_free(wallet);
+
+            assert_eq!(wallet_create(4, passphrase, &mut wallet), WALLET_ERROR_ARGUMENT);
+            assert_eq!(wallet_create(3, ptr::null(), &mut wallet), WALLET_ERROR_ARGUMENT);
+
+            let seed = [7u8; 64];
+            let (mut encrypted, mut encrypted_len) = (ptr::null_mut(), 0);
+            assert_eq!(wallet_encrypt_seed(seed.as_ptr(), seed.len(), passphrase, &mut encrypted, &mut encrypted_len), WALLET_OK);
+            let (mut decrypted, mut decrypted_len) = (ptr::null_mut(), 0);
+            assert_eq!(wallet_decrypt_seed(encrypted, encrypted_len, passphrase, &mut decrypted, &mut decrypted_len), WALLET_OK);
+            assert_eq!(slice::from_raw_parts(decrypted, decrypted_len), &seed[..]);
+            wallet_bytes_free(encrypted, encrypted_len);
+            wallet_bytes_free(decrypted, decrypted_len);
+        }
+        assert_eq!(status(|| panic!("bug")), WALLET_ERROR_PANIC);
+    }
+}
@@ -0,0 +1,349 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Compact block filters
+//!
+//! Scan the chain with BIP158 filters and process only blocks that might hold own coins
+//!
+
+use std::collections::{HashMap, HashSet};
+
+use bitcoin::{Block, BlockHash, FeeRate, OutPoint, Script, Transaction, TxOut, Txid};
+use bitcoin::bip158::BlockFilter;
+
+use account::MasterAccount;
+use broadcast::Broadcaster;
+use chain::{ChainSource, Confirmation};
+use coins::Coins;
+use error::WalletError;
+use fees::FeeEstimator;
+
+/// filter type of BIP158 basic filters
+pub const BASIC_FILTER: u8 = 0;
+
+/// maximum number of filters a peer serves for a request (BIP157)
+pub const MAX_FILTERS_PER_REQUEST: usize = 1000;
+
+/// a source of basic filters and blocks, such as a peer of the bitcoin network
+pub trait FilterSource {
+    /// block hashes and basic filters of blocks from start_height up to the block stop_hash
+    fn get_filters(&mut self, start_height: u32, stop_hash: &BlockHash) -> Result<Vec<(BlockHash, BlockFilter)>, WalletError>;
+
+    /// a full block
+    fn get_block(&mut self, block_hash: &BlockHash) -> Result<Block, WalletError>;
+}
+
+/// true if the filter of a block matches any script of the master account
+pub fn matches(filter: &BlockFilter, block_hash: &BlockHash, master: &MasterAccount) -> Result<bool, WalletError> {
+    let scripts = master.get_scripts().map(|(s, _)| s).collect::<Vec<_>>();
+    Ok(filter.match_any(block_hash, scripts.iter().map(|s| s.as_bytes()))?)
+}
+
+/// scan a chain of blocks, block_hashes[i] is the block at height start_height + i
+/// blocks whose filter matches are downloaded and processed in order,
+/// so keys found in the look ahead window are matched in later blocks.
+/// returns the hashes of processed blocks
+pub fn scan<S: FilterSource>(source: &mut S, master: &mut MasterAccount, coins: &mut Coins, start_height: u32, block_hashes: &[BlockHash]) -> Result<Vec<BlockHash>, WalletError> {
+    let mut processed = Vec::new();
+    for (batch, hashes) in block_hashes.chunks(MAX_FILTERS_PER_REQUEST).enumerate() {
+        let height = start_height + (batch * MAX_FILTERS_PER_REQUEST) as u32;
+        for (block_hash, filter) in get_checked_filters(source, height, hashes)? {
+            if matches(&filter, &block_hash, master)? {
+                let block = get_checked_block(source, &block_hash)?;
+                coins.process(master, &block)?;
+                processed.push(block_hash);
+            }
+        }
+    }
+    Ok(processed)
+}
+
+/// filters of blocks from height, checked to be those of the hashes
+fn get_checked_filters<S: FilterSource>(source: &mut S, height: u32, hashes: &[BlockHash]) -> Result<Vec<(BlockHash, BlockFilter)>, WalletError> {
+    let filters = source.get_filters(height, hashes.last().expect("chunks are not empty"))?;
+    if filters.len() != hashes.len() || filters.iter().zip(hashes.iter()).any(|((h, _), e)| h != e) {
+        return Err(WalletError::P2P("filters do not match the requested blocks".to_string()));
+    }
+    Ok(filters)
+}
+
+fn get_checked_block<S: FilterSource>(source: &mut S, block_hash: &BlockHash) -> Result<Block, WalletError> {
+    let block = source.get_block(block_hash)?;
+    if block.block_hash() != *block_hash || !block.check_merkle_root() {
+        return Err(WalletError::P2P(format!("invalid block {}", block_hash)));
+    }
+    Ok(block)
+}
+
+/// a chain source of the filters of known blocks, e.g. served by a peer.
+/// Blocks whose filter matches a script are downloaded once, transactions broadcast through it are
+/// unconfirmed until found in a block. Filters tell no fee rates, an other estimator may.
+pub struct FilterChain<S: FilterSource + Broadcaster> {
+    source: S,
+    start_height: u32,
+    block_hashes: Vec<BlockHash>,
+    filters: Option<Vec<BlockFilter>>,
+    blocks: HashMap<BlockHash, Block>,
+    mempool: Vec<Transaction>,
+    fees: Option<Box<dyn FeeEstimator>>
+}
+
+impl<S: FilterSource + Broadcaster> FilterChain<S> {
+    /// block_hashes[i] is the block at height start_height + i, as for scan
+    pub fn new(source: S, start_height: u32, block_hashes: Vec<BlockHash>) -> FilterChain<S> {
+        FilterChain { source, start_height, block_hashes, filters: None, blocks: HashMap::new(), mempool: Vec::new(), fees: None }
+    }
+
+    /// estimate fees with an other source
+    pub fn with_fees(mut self, fees: Box<dyn FeeEstimator>) -> FilterChain<S> {
+        self.fees = Some(fees);
+        self
+    }
+
+    /// blocks joined the chain, their filters are fetched with the next query
+    pub fn extend(&mut self, block_hashes: &[BlockHash]) {
+        self.block_hashes.extend_from_slice(block_hashes);
+        self.filters = None;
+    }
+
+    /// transactions paying to or spending from a script in blocks matching it, then in the mempool
+    fn transactions(&mut self, script_pubkey: &Script) -> Result<Vec<(Transaction, Confirmation)>, WalletError> {
+        if self.filters.is_none() {
+            let mut filters = Vec::with_capacity(self.block_hashes.len());
+            for (batch, hashes) in self.block_hashes.chunks(MAX_FILTERS_PER_REQUEST).enumerate() {
+                let height = self.start_height + (batch * MAX_FILTERS_PER_REQUEST) as u32;
+                filters.extend(get_checked_filters(&mut self.source, height, hashes)?.into_iter().map(|(_, filter)| filter));
+            }
+            self.filters = Some(filters);
+        }
+        let matching = self.block_hashes.iter().zip(self.filters.iter().flatten()).enumerate()
+            .filter_map(|(i, (block_hash, filter))| match filter.match_any(block_hash, std::iter::once(script_pubkey.as_bytes())) {
+                Ok(true) => Some(Ok((self.start_height + i as u32, *block_hash))),
+                Ok(false) => None,
+                Err(e) => Some(Err(WalletError::from(e)))
+            }).collect::<Result<Vec<_>, _>>()?;
+
+        let mut paid = HashSet::new();
+        let mut transactions = Vec::new();
+        let mut touches = |transaction: &Transaction| {
+            let txid = transaction.compute_txid();
+            let spends = transaction.input.iter().any(|i| paid.contains(&i.previous_output));
+            let mut pays = false;
+            for (vout, output) in transaction.output.iter().enumerate() {
+                if output.script_pubkey == *script_pubkey {
+                    paid.insert(OutPoint { txid, vout: vout as u32 });
+                    pays = true;
+                }
+            }
+            spends || pays
+        };
+        for (height, block_hash) in matching {
+            if !self.blocks.contains_key(&block_hash) {
+                let block = get_checked_block(&mut self.source, &block_hash)?;
+                let txids = block.txdata.iter().map(|t| t.compute_txid()).collect::<HashSet<_>>();
+                self.mempool.retain(|t| !txids.contains(&t.compute_txid()));
+                self.blocks.insert(block_hash, block);
+            }
+            for transaction in self.blocks[&block_hash].txdata.iter().filter(|t| touches(t)) {
+                transactions.push((transaction.clone(), Some((height, block_hash))));
+            }
+        }
+        for transaction in self.mempool.iter().filter(|t| touches(t)) {
+            transactions.push((transaction.clone(), None));
+        }
+        Ok(transactions)
+    }
+}
+
+impl<S: FilterSource + Broadcaster> ChainSource for FilterChain<S> {
+    fn get_history(&mut self, script_pubkey: &Script) -> Result<Vec<(Txid, Confirmation)>, WalletError> {
+        Ok(self.transactions(script_pubkey)?.into_iter().map(|(t, block)| (t.compute_txid(), block)).collect())
+    }
+
+    fn get_utxos(&mut self, script_pubkey: &Script) -> Result<Vec<(OutPoint, TxOut)>, WalletError> {
+        let transactions = self.transactions(script_pubkey)?;
+        let spent = transactions.iter().flat_map(|(t, _)| t.input.iter().map(|i| i.previous_output)).collect::<HashSet<_>>();
+        Ok(transactions.iter().flat_map(|(t, _)| {
+            let txid = t.compute_txid();
+            t.output.iter().enumerate().map(move |(vout, o)| (OutPoint { txid, vout: vout as u32 }, o.clone()))
+        }).filter(|(point, output)| output.script_pubkey == *script_pubkey && !spent.contains(point)).collect())
+    }
+
+    /// a transaction of a block downloaded before or broadcast through this
+    fn get_tx(&mut self, txid: &Txid) -> Result<Transaction, WalletError> {
+        self.blocks.values().flat_map(|b| b.txdata.iter()).chain(self.mempool.iter())
+            .find(|t| t.compute_txid() == *txid).cloned()
+            .ok_or_else(|| WalletError::P2P(format!("unknown transaction {}", txid)))
+    }
+
+    fn broadcast(&mut self, transaction: &Transaction) -> Result<Txid, WalletError> {
+        let txid = self.source.broadcast(transaction)?;
+        self.mempool.push(transaction.clone());
+        Ok(txid)
+    }
+
+    fn estimate_fee(&mut self, blocks: u32) -> Result<FeeRate, WalletError> {
+        match self.fees {
+            Some(ref mut fees) => fees.estimate(blocks),
+            None => Err(WalletError::Fees("no fee estimator".to_string()))
+        }
+    }
+
+    fn tip(&mut self) -> Result<(u32, BlockHash), WalletError> {
+        let block_hash = self.block_hashes.last().ok_or_else(|| WalletError::P2P("no blocks".to_string()))?;
+        Ok((self.start_height + self.block_hashes.len() as u32 - 1, *block_hash))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{Amount, CompactTarget, Network, ScriptBuf, Sequence, TxIn, TxMerkleNode, WPubkeyHash, Witness};
+    use bitcoin::absolute::LockTime;
+    use bitcoin::bip158;
+    use bitcoin::block::Header;
+    use bitcoin::transaction::Version;
+    use bitcoin_hashes::Hash;
+
+    use account::{Account, AccountAddressType, MasterKeyEntropy, Unlocker};
+    use fees::FixedFee;
+    use super::*;
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+
+    struct Chain {
+        blocks: HashMap<BlockHash, Block>,
+        order: Vec<BlockHash>,
+        downloaded: usize
+    }
+
+    impl FilterSource for Chain {
+        fn get_filters(&mut self, start_height: u32, stop_hash: &BlockHash) -> Result<Vec<(BlockHash, BlockFilter)>, WalletError> {
+            let stop = self.order.iter().position(|h| h == stop_hash).unwrap();
+            Ok(self.order[start_height as usize..=stop].iter().map(|h| {
+                let filter = BlockFilter::new_script_filter(&self.blocks[h], |point|
+                    self.blocks.values().flat_map(|b| b.txdata.iter()).find(|t| t.compute_txid() == point.txid)
+                        .map(|t| t.output[point.vout as usize].script_pubkey.clone())
+                        .ok_or(bip158::Error::UtxoMissing(*point))).unwrap();
+                (*h, filter)
+            }).collect())
+        }
+
+        fn get_block(&mut self, block_hash: &BlockHash) -> Result<Block, WalletError> {
+            self.downloaded += 1;
+            Ok(self.blocks[block_hash].clone())
+        }
+    }
+
+    impl Broadcaster for Chain {
+        fn broadcast(&mut self, transaction: &Transaction) -> Result<Txid, WalletError> {
+            Ok(transaction.compute_txid())
+        }
+    }
+
+    fn block(prev_blockhash: BlockHash, nonce: u32, txdata: Vec<Transaction>) -> Block {
+        let mut block = Block {
+            header: Header {
+                version: bitcoin::block::Version::ONE,
+                prev_blockhash,
+                merkle_root: TxMerkleNode::all_zeros(),
+                time: 0,
+                bits: CompactTarget::from_consensus(0),
+                nonce
+            },
+            txdata
+        };
+        block.header.merkle_root = block.compute_merkle_root().unwrap();
+        block
+    }
+
+    fn payment(previous_output: OutPoint, value: u64, script_pubkey: ScriptBuf) -> Transaction {
+        Transaction {
+            version: Version::ONE,
+            lock_time: LockTime::ZERO,
+            input: vec!(TxIn { previous_output, script_sig: ScriptBuf::new(), sequence: Sequence::MAX, witness: Witness::new() }),
+            output: vec!(TxOut { value: Amount::from_sat(value), script_pubkey })
+        }
+    }
+
+    #[test]
+    fn test_scan() {
+        let mut master = MasterAccount::new(MasterKeyEntropy::Low, Network::Bitcoin, PASSPHRASE, None).unwrap();
+        let mut unlocker = Unlocker::new(master.encrypted(), PASSPHRASE, None, Network::Bitcoin, None).unwrap();
+        // the last key of the look ahead window
+        let first = master.new_account(&mut unlocker, AccountAddressType::P2WPKH, 0, 0, 5).unwrap().get_key(4).unwrap().script_pubkey.clone();
+        // a key of the same account beyond the window
+        let beyond = Account::new(&mut unlocker, AccountAddressType::P2WPKH, 0, 0, 10).unwrap().get_key(7).unwrap().script_pubkey.clone();
+        let foreign = ScriptBuf::new_op_return([1u8; 4]);
+
+        let funding = payment(OutPoint { txid: Txid::all_zeros(), vout: 0 }, 100000, first);
+        let blocks = vec!(
+            block(BlockHash::all_zeros(), 0, vec!(payment(OutPoint::null(), 1, foreign.clone()))),
+            block(BlockHash::all_zeros(), 1, vec!(funding.clone())),
+            block(BlockHash::all_zeros(), 2, vec!(payment(OutPoint::null(), 2, foreign))),
+            // only known after the look ahead window moved with the funding
+            block(BlockHash::all_zeros(), 3, vec!(payment(OutPoint { txid: Txid::all_zeros(), vout: 1 }, 5000, beyond))),
+            // spends the funding, filters do not cover inputs of the coinbase
+            block(BlockHash::all_zeros(), 4, vec!(
+                payment(OutPoint::null(), 3, ScriptBuf::new_op_return([2u8; 4])),
+                payment(OutPoint { txid: funding.compute_txid(), vout: 0 }, 90000, ScriptBuf::new_op_return([3u8; 4])))));
+        let order = blocks.iter().map(|b| b.block_hash()).collect::<Vec<_>>();
+        let mut chain = Chain { blocks: blocks.into_iter().map(|b| (b.block_hash(), b)).collect(), order: order.clone(), downloaded: 0 };
+
+        let mut coins = Coins::new();
+        let processed = scan(&mut chain, &mut master, &mut coins, 0, &order).unwrap();
+        assert_eq!(processed, vec!(order[1], order[3], order[4]));
+        assert_eq!(chain.downloaded, 3);
+        assert_eq!(coins.owned().len(), 1);
+        assert_eq!(coins.owned().values().next().unwrap().output.value.to_sat(), 5000);
+
+        assert!(scan(&mut chain, &mut master, &mut coins, 1, &order[2..]).is_err());
+    }
+
+    #[test]
+    fn test_filter_chain() {
+        let script_pubkey = ScriptBuf::new_p2wpkh(&WPubkeyHash::from_byte_array([4u8; 20]));
+        let other = ScriptBuf::new_p2wpkh(&WPubkeyHash::from_byte_array([9u8; 20]));
+        let coinbase = |n| payment(OutPoint::null(), n, other.clone());
+        let first = block(BlockHash::all_zeros(), 0, vec!(coinbase(1)));
+        let funding = payment(OutPoint { txid: first.txdata[0].compute_txid(), vout: 0 }, 50000, script_pubkey.clone());
+        let spend = payment(OutPoint { txid: funding.compute_txid(), vout: 0 }, 40000, other.clone());
+        let blocks = vec!(
+            first,
+            block(BlockHash::all_zeros(), 1, vec!(coinbase(2), funding.clone())),
+            block(BlockHash::all_zeros(), 2, vec!(coinbase(3), spend.clone())),
+            block(BlockHash::all_zeros(), 3, vec!(coinbase(4))));
+        let order = blocks.iter().map(|b| b.block_hash()).collect::<Vec<_>>();
+        let chain = Chain { blocks: blocks.into_iter().map(|b| (b.block_hash(), b)).collect(), order: order.clone(), downloaded: 0 };
+
+        let mut source = FilterChain::new(chain, 0, order.clone());
+        let history = vec!((funding.compute_txid(), Some((1, order[1]))), (spend.compute_txid(), Some((2, order[2]))));
+        assert_eq!(source.get_history(&script_pubkey).unwrap(), history);
+        assert!(source.get_utxos(&script_pubkey).unwrap().is_empty());
+        assert_eq!(source.get_tx(&spend.compute_txid()).unwrap(), spend);
+        assert_eq!(source.tip().unwrap(), (3, order[3]));
+        assert!(source.estimate_fee(6).is_err());
+
+        // broadcast transactions are unconfirmed, matching blocks are downloaded once
+        let again = payment(OutPoint { txid: spend.compute_txid(), vout: 0 }, 30000, script_pubkey.clone());
+        ChainSource::broadcast(&mut source, &again).unwrap();
+        assert_eq!(source.get_history(&script_pubkey).unwrap().last(), Some(&(again.compute_txid(), None)));
+        assert_eq!(source.get_utxos(&script_pubkey).unwrap(), vec!((OutPoint { txid: again.compute_txid(), vout: 0 }, again.output[0].clone())));
+        assert_eq!(source.source.downloaded, 2);
+
+        let mut source = source.with_fees(Box::new(FixedFee(FeeRate::from_sat_per_vb(3).unwrap())));
+        assert_eq!(source.estimate_fee(6).unwrap(), FeeRate::from_sat_per_vb(3).unwrap());
+    }
+}
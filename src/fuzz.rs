@@ -0,0 +1,146 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Fuzz targets
+//!
+//! Decoders of untrusted input taking arbitrary bytes, for cargo-fuzz targets in fuzz/
+//!
+//! Each target fails with an error on malformed input and panics only if a decoded value
+//! does not survive a round trip through its encoding.
+//!
+
+use std::str;
+
+use bitcoin::Network;
+use bitcoin::psbt::Psbt;
+
+use descriptor;
+use keystore::{self, Kdf};
+use mnemonic::Mnemonic;
+use psbt;
+use uri::PaymentRequest;
+
+/// passphrase keystores are decrypted with
+pub const PASSPHRASE: &str = "fuzz";
+
+/// a mnemonic in any language
+pub fn mnemonic(data: &[u8]) {
+    if let Some(mnemonic) = str::from_utf8(data).ok().and_then(|s| Mnemonic::from_str(s).ok()) {
+        assert_eq!(Mnemonic::from_str(&mnemonic.to_string()).expect("encoded mnemonic decodes"), mnemonic);
+    }
+}
+
+/// a keystore of the current or legacy format, those of expensive key derivations are not decrypted
+pub fn keystore(data: &[u8]) {
+    let cheap = match keystore::kdf(data) {
+        Ok(Kdf::Pbkdf2Sha256(iterations)) => iterations <= 1000,
+        Ok(Kdf::Argon2id(params)) => params.memory <= 1024 && params.iterations <= 4 && params.parallelism <= 4,
+        // legacy, or rejected before any key derivation
+        Err(_) => true
+    };
+    if cheap {
+        let _ = keystore::decrypt(data, PASSPHRASE);
+    }
+}
+
+/// a binary or base64 PSBT
+pub fn psbt(data: &[u8]) {
+    if let Ok(decoded) = Psbt::deserialize(data) {
+        assert_eq!(Psbt::deserialize(&decoded.serialize()).expect("encoded PSBT decodes"), decoded);
+    }
+    if let Some(decoded) = str::from_utf8(data).ok().and_then(|s| psbt::from_base64(s).ok()) {
+        assert_eq!(psbt::from_base64(&psbt::to_base64(&decoded)).expect("encoded PSBT decodes"), decoded);
+    }
+}
+
+/// a descriptor with or without checksum
+pub fn descriptor(data: &[u8]) {
+    if let Some(stripped) = str::from_utf8(data).ok().and_then(|s| descriptor::strip_checksum(s).ok()) {
+        let with = descriptor::with_checksum(stripped).expect("descriptor without checksum has a checksum");
+        assert_eq!(descriptor::strip_checksum(&with).expect("descriptor with its checksum"), stripped);
+    }
+}
+
+/// a BIP21 URI of any network
+pub fn uri(data: &[u8]) {
+    if let Ok(s) = str::from_utf8(data) {
+        for network in &[Network::Bitcoin, Network::Testnet, Network::Regtest] {
+            if let Ok(request) = PaymentRequest::parse(s, *network) {
+                assert_eq!(PaymentRequest::parse(&request.to_uri(), *network).expect("encoded URI decodes"), request);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use secp256k1::rand::{thread_rng, Rng, RngCore};
+
+    use super::*;
+
+    // valid inputs with some bytes flipped, and random bytes
+    fn inputs(valid: &[&[u8]]) -> Vec<Vec<u8>> {
+        let mut rng = thread_rng();
+        let mut inputs = Vec::new();
+        for input in valid {
+            inputs.push(input.to_vec());
+            for _ in 0..200 {
+                let mut mutated = input.to_vec();
+                for _ in 0..rng.gen_range(1..4) {
+                    let at = rng.gen_range(0..mutated.len());
+                    match rng.gen_range(0..3) {
+                        0 => mutated[at] = rng.gen(),
+                        1 => { mutated.remove(at); },
+                        _ => mutated.truncate(at)
+                    }
+                    if mutated.is_empty() {
+                        break;
+                    }
+                }
+                inputs.push(mutated);
+            }
+        }
+        for _ in 0..200 {
+            let mut random = vec!(0u8; rng.gen_range(0..100));
+            rng.fill_bytes(&mut random);
+            inputs.push(random);
+        }
+        inputs
+    }
+
+    #[test]
+    fn test_fuzz() {
+        let words = "announce damage viable ticket engage curious yellow ten clock finish burden orient faculty rigid smile host offer affair suffer slogan mercy another switch park";
+        for input in inputs(&[words.as_bytes()]) {
+            mnemonic(&input);
+        }
+        let encrypted = keystore::encrypt(b"secret", PASSPHRASE, keystore::Cipher::Aes256Gcm, Kdf::Pbkdf2Sha256(10)).unwrap();
+        for input in inputs(&[&encrypted]) {
+            keystore(&input);
+        }
+        let base64 = "cHNidP8BAHUCAAAAASaBcTce3/KF6Tet7qSze3gADAVmy7OtZGQXE8pCFxv2AAAAAAD+////AtPf9QUAAAAAGXapFNDFmQPFusKGh2DpD9UhpGZap2UgiKwA4fUFAAAAABepFDVF5uM7gyxHBQ8k0+65PJwDlIvHh7MuEwAAAQD9pQEBAAAAAAECiaPHHqtNIOA3G7ukzGmPopXJRjr6Ljl/hTPMti+VZ+UBAAAAFxYAFL4Y0VKpsBIDna89p95PUzSe7LmF/////4b4qkOnHf8USIk6UwpyN+9rRgi7st0tAXHmOuxqSJC0AQAAABcWABT+Pp7xp0XpdNkCxDVZQ6vLNL1TU/////8CAMLrCwAAAAAZdqkUhc/xCX/Z4Ai7NK9wnGIZeziXikiIrHL++E4sAAAAF6kUM5cluiHv1irHU6m80GfWx6ajnQWHAkcwRAIgJxK+IuAnDzlPVoMR3HyppolwuAJf3TskAinwf4pfOiQCIAGLONfc0xTnNMkna9b7QPZzMlvEuqFEyADS8vAtsnZcASED0uFWdJQbrUqZY3LLh+GFbTZSYG2YVi/jnF6efkE/IQUCSDBFAiEA0SuFLYXc2WHS9fSrZgZU327tzHlMDDPOXMMJ/7X85Y0CIGczio4OFyXBl/saiK9Z9R5E5CVbIBZ8hoQDHAXR8lkqASECI7cr7vCWXRC+B3jv7NYfysb3mk6haTkzgHNEZPhPKrMAAAAAAAAA";
+        for input in inputs(&[base64.as_bytes(), &psbt::from_base64(base64).unwrap().serialize()]) {
+            psbt(&input);
+        }
+        let descriptor = descriptor::with_checksum("wpkh([d34db33f/84'/0'/0']xpub6CatWdiZiodmUeTDp8LT5or8nmbKNcuyvz7WyksVFkKB4RHwCD3XyuvPEbvqAQY3rAPshWcMLoP2fMFMKHPJ4ZeZXYVUhLv1VMrjPC7PW6V/0/*)").unwrap();
+        for input in inputs(&[descriptor.as_bytes()]) {
+            super::descriptor(&input);
+        }
+        for input in inputs(&[b"bitcoin:175tWpb8K1S7NmH4Zx6rewF9WQrcZv245W?amount=20.3&label=Luke-Jr"]) {
+            uri(&input);
+        }
+    }
+}
@@ -0,0 +1,160 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # History
+//!
+//! Transactions of an account with their net effect on the wallet
+//!
+
+use bitcoin::{Amount, OutPoint, SignedAmount, Txid};
+
+use account::AccountAddressType;
+use utxo::{Utxo, UtxoSet};
+
+/// what a transaction did to the wallet
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Direction {
+    /// paid to the wallet without spending its coins
+    Received,
+    /// paid from the wallet to others
+    Sent,
+    /// spent coins of the wallet to outputs of the wallet only
+    SelfTransfer
+}
+
+/// a transaction of the history
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HistoryEntry {
+    pub txid: Txid,
+    /// height of the confirming block, None if unconfirmed
+    pub height: Option<u32>,
+    /// time of the confirming block
+    pub timestamp: Option<u32>,
+    /// known if all inputs are coins of the wallet
+    pub fee: Option<Amount>,
+    /// paid to the account
+    pub received: Amount,
+    /// spent from the account
+    pub sent: Amount,
+    pub direction: Direction
+}
+
+impl HistoryEntry {
+    /// received less sent
+    pub fn net(&self) -> SignedAmount {
+        SignedAmount::from_sat(self.received.to_sat() as i64 - self.sent.to_sat() as i64)
+    }
+}
+
+/// history of the account of address type and number over all its sub accounts, e.g. change.
+/// Confirmed transactions come in ascending height order, unconfirmed ones last.
+pub fn account_history(utxos: &UtxoSet, address_type: AccountAddressType, account_number: u32) -> Vec<HistoryEntry> {
+    let of_account = |utxo: &Utxo| utxo.address_type == address_type && utxo.derivation.account == account_number;
+    let mut history = Vec::new();
+    for (txid, record) in utxos.transactions() {
+        let transaction = &record.transaction;
+        let outputs = (0..transaction.output.len()).map(|vout| utxos.get(&OutPoint { txid: *txid, vout: vout as u32 })).collect::<Vec<_>>();
+        let inputs = if transaction.is_coinbase() {
+            Vec::new()
+        } else {
+            transaction.input.iter().map(|input| utxos.get(&input.previous_output)).collect::<Vec<_>>()
+        };
+        let received = outputs.iter().flatten().filter(|u| of_account(u)).map(|u| u.output.value).sum::<Amount>();
+        let sent = inputs.iter().flatten().filter(|u| of_account(u)).map(|u| u.output.value).sum::<Amount>();
+        if received == Amount::ZERO && sent == Amount::ZERO {
+            continue;
+        }
+        let fee = if !inputs.is_empty() && inputs.iter().all(|u| u.is_some()) {
+            let value_in = inputs.iter().flatten().map(|u| u.output.value).sum::<Amount>();
+            value_in.checked_sub(transaction.output.iter().map(|o| o.value).sum())
+        } else {
+            None
+        };
+        let direction = if sent == Amount::ZERO {
+            Direction::Received
+        } else if outputs.iter().all(|u| u.is_some()) {
+            Direction::SelfTransfer
+        } else {
+            Direction::Sent
+        };
+        history.push(HistoryEntry {
+            txid: *txid, height: record.confirmed.map(|(height, _)| height), timestamp: record.timestamp, fee, received, sent, direction
+        });
+    }
+    history.sort_by_key(|entry| (entry.height.is_none(), entry.height, entry.txid));
+    history
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{Block, BlockHash, CompactTarget, Network, ScriptBuf, Sequence, Transaction, TxIn, TxMerkleNode, TxOut, Witness};
+    use bitcoin::absolute::LockTime;
+    use bitcoin::block::{Header, Version as BlockVersion};
+    use bitcoin::transaction::Version;
+    use bitcoin_hashes::Hash;
+
+    use account::{MasterAccount, MasterKeyEntropy, Unlocker};
+    use super::*;
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+
+    fn transaction(inputs: Vec<OutPoint>, outputs: Vec<TxOut>) -> Transaction {
+        Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: inputs.into_iter().map(|previous_output| TxIn { previous_output, script_sig: ScriptBuf::new(), sequence: Sequence::MAX, witness: Witness::new() }).collect(),
+            output: outputs
+        }
+    }
+
+    #[test]
+    fn test_history() {
+        let mut master = MasterAccount::new(MasterKeyEntropy::Low, Network::Bitcoin, PASSPHRASE, None).unwrap();
+        let mut unlocker = Unlocker::new(master.encrypted(), PASSPHRASE, None, Network::Bitcoin, None).unwrap();
+        let receive = master.new_account(&mut unlocker, AccountAddressType::P2WPKH, 0, 0, 10).unwrap().next_key().unwrap().script_pubkey.clone();
+        let change = master.new_account(&mut unlocker, AccountAddressType::P2WPKH, 0, 1, 10).unwrap().next_key().unwrap().script_pubkey.clone();
+        let mut utxos = UtxoSet::new();
+
+        let funding = transaction(vec!(OutPoint { txid: Txid::all_zeros(), vout: 0 }), vec!(TxOut { value: Amount::from_sat(50000), script_pubkey: receive }));
+        let funded = OutPoint { txid: funding.compute_txid(), vout: 0 };
+        let block = Block {
+            header: Header { version: BlockVersion::ONE, prev_blockhash: BlockHash::all_zeros(), merkle_root: TxMerkleNode::all_zeros(), time: 1234567, bits: CompactTarget::from_consensus(0), nonce: 0 },
+            txdata: vec!(funding.clone())
+        };
+        utxos.process_block(&mut master, &block, 100);
+        let payment = transaction(vec!(funded), vec!(
+            TxOut { value: Amount::from_sat(20000), script_pubkey: ScriptBuf::new_op_return([0u8; 4]) },
+            TxOut { value: Amount::from_sat(29000), script_pubkey: change.clone() }));
+        utxos.process_unconfirmed(&mut master, &payment);
+        let consolidation = transaction(vec!(OutPoint { txid: payment.compute_txid(), vout: 1 }), vec!(TxOut { value: Amount::from_sat(28500), script_pubkey: change }));
+        utxos.process_unconfirmed(&mut master, &consolidation);
+
+        let history = master.get((0, 0)).unwrap().history(&utxos);
+        assert_eq!(history.len(), 3);
+        assert_eq!(history[0], HistoryEntry {
+            txid: funding.compute_txid(), height: Some(100), timestamp: Some(1234567), fee: None,
+            received: Amount::from_sat(50000), sent: Amount::ZERO, direction: Direction::Received
+        });
+        let payment = history.iter().find(|e| e.txid == payment.compute_txid()).unwrap();
+        assert_eq!(payment.direction, Direction::Sent);
+        assert_eq!(payment.fee, Some(Amount::from_sat(1000)));
+        assert_eq!(payment.net(), SignedAmount::from_sat(-21000));
+        let consolidation = history.iter().find(|e| e.txid == consolidation.compute_txid()).unwrap();
+        assert_eq!(consolidation.direction, Direction::SelfTransfer);
+        assert_eq!(consolidation.net(), SignedAmount::from_sat(-500));
+        assert!(master.get((1, 0)).is_none());
+    }
+}
@@ -0,0 +1,169 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Hot and cold keys
+//!
+//! Sign small payments with hot keys, the others only after unlocking the cold keys with the passphrase
+//!
+
+use std::time::Duration;
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::Instant;
+// std has no clock on wasm32-unknown-unknown
+#[cfg(target_arch = "wasm32")]
+use web_time::Instant;
+
+use bitcoin::{Amount, EcdsaSighashType, Network, OutPoint, Transaction, TxOut};
+
+use account::{MasterAccount, Unlocker};
+use error::WalletError;
+
+/// the sub account keys of hot accounts stay decrypted, e.g. those of the receive and change chains of an account.
+/// Payments from hot accounts up to the spending limit are signed with them,
+/// everything else needs the passphrase, the cold keys it decrypts lock again after a timeout.
+pub struct HotColdKeys {
+    network: Network,
+    hot: Unlocker,
+    spending_limit: Amount,
+    timeout: Duration,
+    unlocked: Option<(Unlocker, Instant)>
+}
+
+impl HotColdKeys {
+    /// decrypt the master once to keep the keys of the hot accounts
+    pub fn new(master: &MasterAccount, passphrase: &str, pd_passphrase: Option<&str>, network: Network, hot: &[(u32, u32)],
+               spending_limit: Amount, timeout: Duration) -> Result<HotColdKeys, WalletError> {
+        let mut unlocker = Unlocker::new(master.encrypted(), passphrase, pd_passphrase, network, Some(master.master_public()))?;
+        let hot = unlocker.hot(master, hot)?;
+        Ok(HotColdKeys { network, hot, spending_limit, timeout, unlocked: None })
+    }
+
+    pub fn spending_limit(&self) -> Amount {
+        self.spending_limit
+    }
+
+    /// decrypt all keys until the timeout
+    pub fn unlock(&mut self, master: &MasterAccount, passphrase: &str, pd_passphrase: Option<&str>) -> Result<(), WalletError> {
+        let unlocker = Unlocker::new(master.encrypted(), passphrase, pd_passphrase, self.network, Some(master.master_public()))?;
+        self.unlocked = Some((unlocker, Instant::now()));
+        Ok(())
+    }
+
+    /// forget the cold keys before the timeout
+    pub fn lock(&mut self) {
+        self.unlocked = None;
+    }
+
+    pub fn is_unlocked(&mut self) -> bool {
+        if self.unlocked.as_ref().is_some_and(|(_, since)| since.elapsed() >= self.timeout) {
+            self.unlocked = None;
+        }
+        self.unlocked.is_some()
+    }
+
+    /// value that leaves the wallet with a transaction, payments and fee
+    pub fn spent<R>(master: &MasterAccount, transaction: &Transaction, resolver: R) -> Amount
+        where R: Fn(&OutPoint) -> Option<TxOut> {
+        let inputs = transaction.input.iter().filter_map(|i| resolver(&i.previous_output))
+            .filter(|o| master.get_key_for_script(&o.script_pubkey).is_some())
+            .map(|o| o.value).sum::<Amount>();
+        let change = transaction.output.iter().filter(|o| master.get_key_for_script(&o.script_pubkey).is_some())
+            .map(|o| o.value).sum::<Amount>();
+        inputs.checked_sub(change).unwrap_or(Amount::ZERO)
+    }
+
+    /// sign with the cold keys if unlocked, otherwise with the hot keys if the transaction spends only coins of hot accounts
+    /// and at most the spending limit. Fails with WalletError::Locked if it needs the cold keys.
+    pub fn sign<R>(&mut self, master: &MasterAccount, transaction: &mut Transaction, hash_type: EcdsaSighashType, resolver: R) -> Result<usize, WalletError>
+        where R: Fn(&OutPoint) -> Option<TxOut> {
+        if self.is_unlocked() {
+            let (ref mut unlocker, _) = *self.unlocked.as_mut().expect("unlocked");
+            return master.sign(transaction, hash_type, &resolver, unlocker);
+        }
+        if HotColdKeys::spent(master, transaction, &resolver) > self.spending_limit {
+            return Err(WalletError::Locked);
+        }
+        for input in &transaction.input {
+            if let Some(spent) = resolver(&input.previous_output) {
+                if let Some((account, _)) = master.get_key_for_script(&spent.script_pubkey) {
+                    if account.path().is_some() || self.hot.sub_account_key(account.address_type(), account.account_number(), account.sub_account_number()).is_err() {
+                        return Err(WalletError::Locked);
+                    }
+                }
+            }
+        }
+        master.sign(transaction, hash_type, &resolver, &mut self.hot)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{ScriptBuf, Sequence, TxIn, Txid, Witness};
+    use bitcoin::absolute::LockTime;
+    use bitcoin::transaction::Version;
+    use bitcoin_hashes::Hash;
+
+    use account::{AccountAddressType, MasterKeyEntropy};
+    use super::*;
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+
+    #[test]
+    fn test_hotcold() {
+        let mut master = MasterAccount::new(MasterKeyEntropy::Low, Network::Bitcoin, PASSPHRASE, None).unwrap();
+        let mut unlocker = Unlocker::new(master.encrypted(), PASSPHRASE, None, Network::Bitcoin, None).unwrap();
+        master.new_keychains(&mut unlocker, AccountAddressType::P2WPKH, 0, 10).unwrap();
+        master.new_keychains(&mut unlocker, AccountAddressType::P2WPKH, 1, 10).unwrap();
+        let mut keys = HotColdKeys::new(&master, PASSPHRASE, None, Network::Bitcoin, &[(0, 0), (0, 1)],
+                                        Amount::from_sat(50000), Duration::from_secs(3600)).unwrap();
+        assert!(!keys.is_unlocked());
+
+        let hot = master.get((0, 0)).unwrap().get_key(0).unwrap().script_pubkey.clone();
+        let cold = master.get((1, 0)).unwrap().get_key(0).unwrap().script_pubkey.clone();
+        let change = master.get((0, 1)).unwrap().get_key(0).unwrap().script_pubkey.clone();
+        let spend = |from: &ScriptBuf, pay: u64| {
+            let coin = TxOut { value: Amount::from_sat(100000), script_pubkey: from.clone() };
+            let transaction = Transaction {
+                version: Version::TWO, lock_time: LockTime::ZERO,
+                input: vec!(TxIn { previous_output: OutPoint { txid: Txid::all_zeros(), vout: 0 }, script_sig: ScriptBuf::new(), sequence: Sequence::MAX, witness: Witness::new() }),
+                output: vec!(TxOut { value: Amount::from_sat(pay), script_pubkey: ScriptBuf::new_op_return([0u8; 4]) },
+                             TxOut { value: Amount::from_sat(99000 - pay), script_pubkey: change.clone() })
+            };
+            (transaction, coin)
+        };
+
+        // small payment from a hot account
+        let (mut transaction, coin) = spend(&hot, 40000);
+        assert_eq!(HotColdKeys::spent(&master, &transaction, |_| Some(coin.clone())), Amount::from_sat(41000));
+        assert_eq!(keys.sign(&master, &mut transaction, EcdsaSighashType::All, |_| Some(coin.clone())).unwrap(), 1);
+        transaction.verify(|_| Some(coin.clone())).unwrap();
+        // large payment or from a cold account
+        let (mut transaction, coin) = spend(&hot, 60000);
+        assert!(keys.sign(&master, &mut transaction, EcdsaSighashType::All, |_| Some(coin.clone())).is_err());
+        let (mut cold_transaction, cold_coin) = spend(&cold, 10000);
+        assert!(keys.sign(&master, &mut cold_transaction, EcdsaSighashType::All, |_| Some(cold_coin.clone())).is_err());
+        // after unlocking
+        assert!(keys.unlock(&master, "wrong", None).is_err());
+        keys.unlock(&master, PASSPHRASE, None).unwrap();
+        assert_eq!(keys.sign(&master, &mut transaction, EcdsaSighashType::All, |_| Some(coin.clone())).unwrap(), 1);
+        transaction.verify(|_| Some(coin.clone())).unwrap();
+        keys.lock();
+        assert!(!keys.is_unlocked());
+        let mut expiring = HotColdKeys::new(&master, PASSPHRASE, None, Network::Bitcoin, &[], Amount::ZERO, Duration::from_secs(0)).unwrap();
+        expiring.unlock(&master, PASSPHRASE, None).unwrap();
+        assert!(!expiring.is_unlocked());
+    }
+}
@@ -0,0 +1,189 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # HWI
+//!
+//! Trezor, Ledger, Coldcard and other hardware wallets through the HWI command line tool
+//!
+
+use std::process::Command;
+use std::str::FromStr;
+
+use bitcoin::{Network, Psbt};
+use bitcoin::bip32::{ChildNumber, DerivationPath, Fingerprint, Xpub};
+use serde_json::Value;
+
+use account::{coin_type, AccountAddressType, MasterAccount};
+use error::WalletError;
+use psbt;
+use signer::{count_signatures, Signer};
+
+/// a device found by hwi enumerate
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Device {
+    /// e.g. trezor, ledger or coldcard
+    pub device_type: String,
+    /// model name
+    pub model: String,
+    /// USB path
+    pub path: String,
+    /// fingerprint of the master key, None if the device is locked
+    pub fingerprint: Option<Fingerprint>
+}
+
+/// list devices connected, command is the hwi executable
+pub fn enumerate(command: &str) -> Result<Vec<Device>, WalletError> {
+    let devices = run(command, &["enumerate"])?;
+    let devices = devices.as_array().ok_or_else(|| WalletError::Signer("unexpected enumerate result".to_string()))?;
+    let mut result = Vec::new();
+    for device in devices {
+        let field = |name: &str| device[name].as_str().unwrap_or_default().to_string();
+        let fingerprint = match device["fingerprint"].as_str() {
+            Some(fingerprint) => Some(Fingerprint::from_str(fingerprint).map_err(|e| WalletError::Signer(e.to_string()))?),
+            None => None
+        };
+        result.push(Device { device_type: field("type"), model: field("model"), path: field("path"), fingerprint });
+    }
+    Ok(result)
+}
+
+/// run hwi and parse its JSON output
+fn run(command: &str, args: &[&str]) -> Result<Value, WalletError> {
+    let output = Command::new(command).args(args).output()?;
+    let result: Value = serde_json::from_slice(&output.stdout)
+        .map_err(|_| WalletError::Signer(String::from_utf8_lossy(&output.stderr).trim().to_string()))?;
+    if let Some(error) = result.get("error") {
+        return Err(WalletError::Signer(error.as_str().map(|s| s.to_string()).unwrap_or_else(|| error.to_string())));
+    }
+    Ok(result)
+}
+
+/// a hardware wallet holding the master key of fingerprint
+pub struct Hwi {
+    command: String,
+    fingerprint: Fingerprint,
+    network: Network
+}
+
+impl Hwi {
+    /// talk to the device of fingerprint through the hwi executable at command
+    pub fn new(command: &str, fingerprint: Fingerprint, network: Network) -> Hwi {
+        Hwi { command: command.to_string(), fingerprint, network }
+    }
+
+    fn call(&self, args: &[&str]) -> Result<Value, WalletError> {
+        let fingerprint = self.fingerprint.to_string();
+        let chain = match self.network {
+            Network::Bitcoin => "main",
+            Network::Signet => "signet",
+            Network::Regtest => "regtest",
+            _ => "test"
+        };
+        let mut all = vec!("--fingerprint", fingerprint.as_str(), "--chain", chain);
+        all.extend_from_slice(args);
+        run(&self.command, all.as_slice())
+    }
+
+    /// the extended public key at path
+    pub fn get_xpub(&self, path: &DerivationPath) -> Result<Xpub, WalletError> {
+        let result = self.call(&["getxpub", format!("m/{}", path).as_str()])?;
+        let xpub = result["xpub"].as_str().ok_or_else(|| WalletError::Signer("no xpub".to_string()))?;
+        Ok(Xpub::from_str(xpub)?)
+    }
+
+    /// a watch only master of the account m / purpose' / coin_type' / account' of the device
+    pub fn watch_only_account(&self, address_type: AccountAddressType, account_number: u32, look_ahead: u32, birth: u64) -> Result<MasterAccount, WalletError> {
+        let path = DerivationPath::from(vec!(
+            ChildNumber::Hardened { index: address_type.as_u32() },
+            ChildNumber::Hardened { index: coin_type(self.network) },
+            ChildNumber::Hardened { index: account_number }));
+        let account_public = self.get_xpub(&path)?;
+        MasterAccount::watch_only_account(self.fingerprint, account_public, address_type, self.network, look_ahead, birth)
+    }
+}
+
+impl Signer for Hwi {
+    fn fingerprint(&mut self) -> Result<Fingerprint, WalletError> {
+        Ok(self.fingerprint)
+    }
+
+    /// the device shows the transaction and asks for confirmation
+    fn sign_psbt(&mut self, _master: &MasterAccount, psbt: &mut Psbt) -> Result<usize, WalletError> {
+        let before = count_signatures(psbt);
+        let result = self.call(&["signtx", psbt::to_base64(psbt).as_str()])?;
+        let signed = result["psbt"].as_str().ok_or_else(|| WalletError::Signer("no psbt".to_string()))?;
+        psbt.combine(psbt::from_base64(signed)?)?;
+        Ok(count_signatures(psbt) - before)
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use std::{env, fs};
+    use std::os::unix::fs::PermissionsExt;
+
+    use bitcoin::{Amount, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Txid, Witness};
+    use bitcoin::absolute::LockTime;
+    use bitcoin::transaction::Version;
+    use bitcoin_hashes::Hash;
+
+    use account::{MasterKeyEntropy, Unlocker};
+    use super::*;
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+
+    #[test]
+    fn test_hwi() {
+        // the device
+        let mut device = MasterAccount::new(MasterKeyEntropy::Low, Network::Regtest, PASSPHRASE, None).unwrap();
+        let mut unlocker = Unlocker::new(device.encrypted(), PASSPHRASE, None, Network::Regtest, None).unwrap();
+        let account_path = DerivationPath::from_str("m/84'/1'/0'").unwrap();
+        let account_public = Xpub::from_priv(&bitcoin::secp256k1::Secp256k1::new(), &unlocker.derive(&account_path).unwrap());
+        let script_pubkey = device.new_account(&mut unlocker, AccountAddressType::P2WPKH, 0, 0, 10).unwrap().get_key(0).unwrap().script_pubkey.clone();
+
+        let mut unsigned = Psbt::from_unsigned_tx(Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec!(TxIn { previous_output: OutPoint { txid: Txid::all_zeros(), vout: 0 }, script_sig: ScriptBuf::new(), sequence: Sequence::MAX, witness: Witness::new() }),
+            output: vec!(TxOut { value: Amount::from_sat(9000), script_pubkey: ScriptBuf::new_op_return([0u8; 4]) })
+        }).unwrap();
+        unsigned.inputs[0].witness_utxo = Some(TxOut { value: Amount::from_sat(10000), script_pubkey: script_pubkey.clone() });
+        let mut signed = unsigned.clone();
+        assert_eq!(psbt::sign(&device, &mut signed, &mut unlocker).unwrap(), 1);
+
+        let mut path = env::temp_dir();
+        path.push(format!("bitcoin-wallet-hwi-{}", std::process::id()));
+        fs::write(&path, format!("#!/bin/sh\ncase \"$*\" in\n\
+            enumerate) echo '[{{\"type\": \"trezor\", \"model\": \"trezor_t\", \"path\": \"webusb:001:1\", \"fingerprint\": \"{}\"}}]';;\n\
+            *getxpub*) echo '{{\"xpub\": \"{}\"}}';;\n\
+            *signtx*) echo '{{\"psbt\": \"{}\", \"signed\": true}}';;\n\
+            *) echo '{{\"error\": \"unknown command\", \"code\": -1}}';;\n\
+            esac\n", device.fingerprint(), account_public, psbt::to_base64(&signed))).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        let command = path.to_str().unwrap();
+
+        let devices = enumerate(command).unwrap();
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].fingerprint, Some(device.fingerprint()));
+
+        let mut hwi = Hwi::new(command, device.fingerprint(), Network::Regtest);
+        let master = hwi.watch_only_account(AccountAddressType::P2WPKH, 0, 10, 0).unwrap();
+        assert_eq!(master.get_key_for_script(&script_pubkey).unwrap().1.script_pubkey, script_pubkey);
+        assert_eq!(master.sign_psbt(&mut unsigned, &mut hwi).unwrap(), 1);
+        assert_eq!(unsigned.inputs[0].partial_sigs.len(), 1);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,219 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Key cache
+//!
+//! Child extended public keys and scripts derived once, for rescans that derive the same keys again and again
+//!
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::Range;
+
+use bitcoin::ScriptBuf;
+use bitcoin::bip32::{ChildNumber, Xpub};
+
+use account::{self, Account, AccountAddressType, MasterAccount};
+use context::SecpContext;
+use error::WalletError;
+
+/// entries kept by default, a few hundred bytes each
+pub const DEFAULT_CAPACITY: usize = 100_000;
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+enum Key {
+    Child(Xpub, ChildNumber),
+    Script(Xpub, AccountAddressType, u32)
+}
+
+impl Key {
+    fn parent(&self) -> &Xpub {
+        match self {
+            Key::Child(parent, _) | Key::Script(parent, _, _) => parent
+        }
+    }
+}
+
+enum Value {
+    Child(Xpub),
+    Script(ScriptBuf)
+}
+
+/// derived keys and scripts by parent key and child, the least recently used are dropped beyond capacity
+pub struct KeyCache {
+    capacity: usize,
+    entries: HashMap<Key, (Value, u64)>,
+    // use of entries in order
+    recent: BTreeMap<u64, Key>,
+    clock: u64,
+    context: SecpContext,
+    hits: u64,
+    misses: u64
+}
+
+impl Default for KeyCache {
+    fn default() -> KeyCache {
+        KeyCache::new(DEFAULT_CAPACITY)
+    }
+}
+
+impl KeyCache {
+    pub fn new(capacity: usize) -> KeyCache {
+        KeyCache { capacity, entries: HashMap::new(), recent: BTreeMap::new(), clock: 0, context: SecpContext::new(), hits: 0, misses: 0 }
+    }
+
+    /// the child of an extended public key, see `SecpContext::public_child`
+    pub fn child(&mut self, parent: &Xpub, child: ChildNumber) -> Result<Xpub, WalletError> {
+        let key = Key::Child(*parent, child);
+        if let Some(Value::Child(xpub)) = self.get(&key) {
+            return Ok(*xpub);
+        }
+        let xpub = self.context.public_child(parent, child)?;
+        self.insert(key, Value::Child(xpub));
+        Ok(xpub)
+    }
+
+    /// output scripts of a range of keys of a chain, see `account::derive_scripts`
+    pub fn scripts(&mut self, address_type: AccountAddressType, chain: &Xpub, range: Range<u32>) -> Result<Vec<(u32, ScriptBuf)>, WalletError> {
+        let mut scripts = Vec::with_capacity(range.len());
+        for index in range {
+            let key = Key::Script(*chain, address_type, index);
+            if let Some(Value::Script(script)) = self.get(&key) {
+                scripts.push((index, script.clone()));
+                continue;
+            }
+            let (_, script) = account::derive_scripts(&self.context, address_type, chain, index..index + 1)?.pop().expect("one script");
+            self.insert(key, Value::Script(script.clone()));
+            scripts.push((index, script));
+        }
+        Ok(scripts)
+    }
+
+    /// output scripts of a range of keys of an account
+    pub fn account_scripts(&mut self, account: &Account, range: Range<u32>) -> Result<Vec<(u32, ScriptBuf)>, WalletError> {
+        self.scripts(account.address_type(), account.master_public(), range)
+    }
+
+    /// drop what was derived from a key, e.g. of a removed account
+    pub fn invalidate(&mut self, parent: &Xpub) {
+        self.retain(|p| p != parent);
+    }
+
+    /// drop what was derived from keys of accounts the master no longer has
+    pub fn sync(&mut self, master: &MasterAccount) {
+        let chains = master.accounts().values().map(|a| *a.master_public()).collect::<HashSet<_>>();
+        self.retain(|p| chains.contains(p));
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.recent.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// lookups answered from the cache and those that derived
+    pub fn stats(&self) -> (u64, u64) {
+        (self.hits, self.misses)
+    }
+
+    fn get(&mut self, key: &Key) -> Option<&Value> {
+        self.clock += 1;
+        match self.entries.get_mut(key) {
+            Some((value, used)) => {
+                self.hits += 1;
+                self.recent.remove(used);
+                *used = self.clock;
+                self.recent.insert(self.clock, key.clone());
+                Some(value)
+            },
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    fn insert(&mut self, key: Key, value: Value) {
+        if self.capacity == 0 {
+            return;
+        }
+        while self.entries.len() >= self.capacity {
+            let (_, oldest) = self.recent.pop_first().expect("entries are in recent");
+            self.entries.remove(&oldest);
+        }
+        self.clock += 1;
+        self.recent.insert(self.clock, key.clone());
+        self.entries.insert(key, (value, self.clock));
+    }
+
+    fn retain<F: Fn(&Xpub) -> bool>(&mut self, keep: F) {
+        self.entries.retain(|key, _| keep(key.parent()));
+        self.recent.retain(|_, key| keep(key.parent()));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::Network;
+
+    use account::{MasterKeyEntropy, Unlocker};
+    use super::*;
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+
+    #[test]
+    fn test_key_cache() {
+        let mut master = MasterAccount::new(MasterKeyEntropy::Low, Network::Bitcoin, PASSPHRASE, None).unwrap();
+        let mut unlocker = Unlocker::new(master.encrypted(), PASSPHRASE, None, Network::Bitcoin, None).unwrap();
+        master.new_keychains(&mut unlocker, AccountAddressType::P2TR, 0, 5).unwrap();
+        let account = master.get((0, 0)).unwrap();
+
+        let mut cache = KeyCache::new(30);
+        assert_eq!(cache.account_scripts(account, 0..20).unwrap(), account.derive_scripts(0..20).unwrap());
+        assert_eq!(cache.account_scripts(account, 10..25).unwrap(), account.derive_scripts(10..25).unwrap());
+        assert_eq!(cache.stats(), (10, 25));
+        assert_eq!(cache.len(), 25);
+        let child = cache.child(account.master_public(), ChildNumber::Normal { index: 3 }).unwrap();
+        assert_eq!(child, SecpContext::new().public_child(account.master_public(), ChildNumber::Normal { index: 3 }).unwrap());
+        assert_eq!(cache.child(account.master_public(), ChildNumber::Normal { index: 3 }).unwrap(), child);
+        // least recently used are dropped
+        cache.account_scripts(account, 25..35).unwrap();
+        assert_eq!(cache.len(), 30);
+        cache.account_scripts(account, 10..11).unwrap();
+        assert_eq!(cache.stats().1, 36);
+        cache.account_scripts(account, 0..1).unwrap();
+        assert_eq!(cache.stats().1, 37);
+
+        let change = master.get((0, 1)).unwrap();
+        cache.account_scripts(change, 0..5).unwrap();
+        cache.invalidate(account.master_public());
+        assert_eq!(cache.len(), 5);
+        let mut other = MasterAccount::new(MasterKeyEntropy::Low, Network::Bitcoin, PASSPHRASE, None).unwrap();
+        cache.sync(&other);
+        assert!(cache.is_empty());
+        let mut unlocker = Unlocker::new(other.encrypted(), PASSPHRASE, None, Network::Bitcoin, None).unwrap();
+        other.new_keychains(&mut unlocker, AccountAddressType::P2WPKH, 0, 5).unwrap();
+        cache.account_scripts(other.get((0, 0)).unwrap(), 0..5).unwrap();
+        cache.sync(&other);
+        assert_eq!(cache.len(), 5);
+    }
+}
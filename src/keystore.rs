@@ -0,0 +1,292 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Keystore
+//!
+//! Authenticated encryption of wallet secrets in a versioned format
+//!
+//! format: magic | version | cipher | kdf | kdf parameters | salt | nonce | ciphertext and tag
+//! kdf parameters are iterations for PBKDF2 and memory, iterations, parallelism for Argon2id
+//! the header before the ciphertext is authenticated as associated data.
+//! blobs without the magic are of the legacy format: AES256(Sha256(passphrase)), ECB, PKCS padding,
+//! they are not decrypted on wasm32
+//!
+use std::num::NonZeroU32;
+
+#[cfg(not(target_arch = "wasm32"))]
+use crypto::aes;
+#[cfg(not(target_arch = "wasm32"))]
+use crypto::blockmodes;
+#[cfg(not(target_arch = "wasm32"))]
+use crypto::buffer;
+#[cfg(not(target_arch = "wasm32"))]
+use crypto::buffer::{BufferResult, ReadBuffer, WriteBuffer};
+#[cfg(not(target_arch = "wasm32"))]
+use crypto::digest::Digest;
+#[cfg(not(target_arch = "wasm32"))]
+use crypto::sha2::Sha256;
+use argon2;
+use ring::aead;
+use ring::pbkdf2;
+use secp256k1::rand::{thread_rng, RngCore};
+
+use error::WalletError;
+use secret::SecretBytes;
+use zeroize::Zeroize;
+
+/// first bytes of a versioned keystore
+pub const MAGIC: [u8; 4] = *b"BWKS";
+/// current version of the format
+pub const VERSION: u8 = 1;
+
+const KDF_PBKDF2_SHA256: u8 = 1;
+const KDF_ARGON2ID: u8 = 2;
+const SALT_LEN: usize = 16;
+// bounds of key derivations a keystore may ask for
+const MAX_PBKDF2_ITERATIONS: u32 = 6_000_000;
+const MAX_ARGON2_MEMORY: u32 = 1 << 21;
+const MAX_ARGON2_ITERATIONS: u32 = 64;
+const MAX_ARGON2_PARALLELISM: u32 = 64;
+const NONCE_LEN: usize = 12;
+
+/// authenticated cipher of a keystore
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum Cipher {
+    /// AES-256-GCM
+    Aes256Gcm,
+    /// ChaCha20-Poly1305
+    ChaCha20Poly1305
+}
+
+impl Cipher {
+    fn as_u8(self) -> u8 {
+        match self {
+            Cipher::Aes256Gcm => 1,
+            Cipher::ChaCha20Poly1305 => 2
+        }
+    }
+
+    fn from_u8(n: u8) -> Result<Cipher, WalletError> {
+        match n {
+            1 => Ok(Cipher::Aes256Gcm),
+            2 => Ok(Cipher::ChaCha20Poly1305),
+            _ => Err(WalletError::Unsupported("unknown keystore cipher"))
+        }
+    }
+
+    fn algorithm(self) -> &'static aead::Algorithm {
+        match self {
+            Cipher::Aes256Gcm => &aead::AES_256_GCM,
+            Cipher::ChaCha20Poly1305 => &aead::CHACHA20_POLY1305
+        }
+    }
+}
+
+/// derivation of the key from the passphrase
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum Kdf {
+    /// PBKDF2-HMAC-SHA256 with iterations
+    Pbkdf2Sha256(u32),
+    /// memory hard Argon2id
+    Argon2id(argon2::Params)
+}
+
+impl Default for Kdf {
+    fn default() -> Kdf {
+        Kdf::Argon2id(argon2::Params::default())
+    }
+}
+
+impl Kdf {
+    fn serialize(&self, header: &mut Vec<u8>) {
+        match self {
+            Kdf::Pbkdf2Sha256(iterations) => {
+                header.push(KDF_PBKDF2_SHA256);
+                header.extend(iterations.to_be_bytes().iter());
+            }
+            Kdf::Argon2id(params) => {
+                header.push(KDF_ARGON2ID);
+                for n in &[params.memory, params.iterations, params.parallelism] {
+                    header.extend(n.to_be_bytes().iter());
+                }
+            }
+        }
+    }
+
+    // kdf and the length of its serialization
+    // parameters of a malformed or hostile keystore could exhaust memory or take forever
+    fn deserialize(data: &[u8]) -> Result<(Kdf, usize), WalletError> {
+        let n = |i: usize, max: u32| match data.get(1 + 4 * i..5 + 4 * i).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]])) {
+            Some(n) if n > max => Err(WalletError::Unsupported("keystore key derivation is too expensive")),
+            Some(n) => Ok(n),
+            None => Err(WalletError::Unsupported("keystore is too short"))
+        };
+        match data.first() {
+            Some(&KDF_PBKDF2_SHA256) => Ok((Kdf::Pbkdf2Sha256(n(0, MAX_PBKDF2_ITERATIONS)?), 5)),
+            Some(&KDF_ARGON2ID) => Ok((Kdf::Argon2id(argon2::Params {
+                memory: n(0, MAX_ARGON2_MEMORY)?, iterations: n(1, MAX_ARGON2_ITERATIONS)?, parallelism: n(2, MAX_ARGON2_PARALLELISM)? }), 13)),
+            _ => Err(WalletError::Unsupported("unknown keystore key derivation"))
+        }
+    }
+
+    fn derive(&self, passphrase: &str, salt: &[u8]) -> Result<SecretBytes, WalletError> {
+        let mut key = SecretBytes::new(vec!(0u8; 32));
+        match self {
+            Kdf::Pbkdf2Sha256(iterations) => {
+                let iterations = NonZeroU32::new(*iterations).ok_or(WalletError::Unsupported("zero keystore iterations"))?;
+                pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, iterations, salt, passphrase.as_bytes(), key.as_mut_slice());
+            }
+            Kdf::Argon2id(params) => {
+                key = argon2::argon2id(passphrase.as_bytes(), salt, params, 32)?;
+            }
+        }
+        Ok(key)
+    }
+}
+
+/// encrypt data with a key derived from passphrase
+pub fn encrypt(data: &[u8], passphrase: &str, cipher: Cipher, kdf: Kdf) -> Result<Vec<u8>, WalletError> {
+    let mut rng = thread_rng();
+    let mut header = MAGIC.to_vec();
+    header.extend([VERSION, cipher.as_u8()].iter());
+    kdf.serialize(&mut header);
+    let mut salt = [0u8; SALT_LEN];
+    rng.fill_bytes(&mut salt);
+    header.extend(salt.iter());
+    let mut nonce = [0u8; NONCE_LEN];
+    rng.fill_bytes(&mut nonce);
+    header.extend(nonce.iter());
+
+    let key = key(cipher, &kdf.derive(passphrase, &salt)?)?;
+    let mut sealed = data.to_vec();
+    key.seal_in_place_append_tag(aead::Nonce::assume_unique_for_key(nonce), aead::Aad::from(&header), &mut sealed)
+        .map_err(|_| WalletError::Unsupported("keystore encryption failed"))?;
+    header.extend(sealed);
+    Ok(header)
+}
+
+/// decrypt a keystore of the current or the legacy format
+pub fn decrypt(encrypted: &[u8], passphrase: &str) -> Result<SecretBytes, WalletError> {
+    if is_legacy(encrypted) {
+        return decrypt_legacy(encrypted, passphrase);
+    }
+    let (kdf, kdf_len) = Kdf::deserialize(encrypted.get(6..).unwrap_or_default())?;
+    let salt_start = 6 + kdf_len;
+    let header_len = salt_start + SALT_LEN + NONCE_LEN;
+    if encrypted.len() < header_len {
+        return Err(WalletError::Unsupported("keystore is too short"));
+    }
+    if encrypted[4] != VERSION {
+        return Err(WalletError::Unsupported("unknown keystore version"));
+    }
+    let cipher = Cipher::from_u8(encrypted[5])?;
+    let salt = &encrypted[salt_start..salt_start + SALT_LEN];
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce.copy_from_slice(&encrypted[salt_start + SALT_LEN..header_len]);
+
+    let key = key(cipher, &kdf.derive(passphrase, salt)?)?;
+    let mut sealed = encrypted[header_len..].to_vec();
+    let plain = key.open_in_place(aead::Nonce::assume_unique_for_key(nonce), aead::Aad::from(&encrypted[..header_len]), &mut sealed)
+        .map(|plain| SecretBytes::from_slice(plain));
+    sealed.zeroize();
+    plain.map_err(|_| WalletError::Passphrase)
+}
+
+/// true if the keystore is of the legacy unauthenticated format
+pub fn is_legacy(encrypted: &[u8]) -> bool {
+    !encrypted.starts_with(&MAGIC)
+}
+
+/// re-encrypt a keystore of any known format into the current one
+pub fn migrate(encrypted: &[u8], passphrase: &str, cipher: Cipher, kdf: Kdf) -> Result<Vec<u8>, WalletError> {
+    encrypt(&decrypt(encrypted, passphrase)?, passphrase, cipher, kdf)
+}
+
+/// key derivation of a keystore of the current format
+pub fn kdf(encrypted: &[u8]) -> Result<Kdf, WalletError> {
+    if is_legacy(encrypted) {
+        return Err(WalletError::Unsupported("legacy keystore"));
+    }
+    Ok(Kdf::deserialize(encrypted.get(6..).unwrap_or_default())?.0)
+}
+
+fn key(cipher: Cipher, key: &SecretBytes) -> Result<aead::LessSafeKey, WalletError> {
+    let key = aead::UnboundKey::new(cipher.algorithm(), key.as_slice()).map_err(|_| WalletError::Unsupported("invalid keystore key"))?;
+    Ok(aead::LessSafeKey::new(key))
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn decrypt_legacy(encrypted: &[u8], passphrase: &str) -> Result<SecretBytes, WalletError> {
+    let mut key = [0u8; 32];
+    let mut sha2 = Sha256::new();
+    sha2.input(passphrase.as_bytes());
+    sha2.result(&mut key);
+
+    let mut decrypted = Vec::new();
+    let mut reader = buffer::RefReadBuffer::new(encrypted);
+    let mut buffer = [0u8; 1024];
+    let mut writer = buffer::RefWriteBuffer::new(&mut buffer);
+    let mut decryptor = aes::ecb_decryptor(aes::KeySize::KeySize256, &key, blockmodes::PkcsPadding{});
+    loop {
+        let result = decryptor.decrypt(&mut reader, &mut writer, true)?;
+        decrypted.extend(writer.take_read_buffer().take_remaining().iter().copied());
+        match result {
+            BufferResult::BufferUnderflow => break,
+            BufferResult::BufferOverflow => {}
+        }
+    }
+    key.zeroize();
+    buffer.zeroize();
+    Ok(SecretBytes::new(decrypted))
+}
+
+// the legacy ciphers are of rust-crypto, which does not build for wasm32
+#[cfg(target_arch = "wasm32")]
+fn decrypt_legacy(_encrypted: &[u8], _passphrase: &str) -> Result<SecretBytes, WalletError> {
+    Err(WalletError::Unsupported("legacy keystores are not decrypted on wasm32"))
+}
+
+#[cfg(test)]
+mod test {
+    use hex::decode;
+    use super::*;
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+    const WORDS: &str = "letter advice cage absurd amount doctor acoustic avoid letter advice cage above";
+
+    #[test]
+    fn test_keystore() {
+        let kdfs = [Kdf::Pbkdf2Sha256(1000), Kdf::Argon2id(argon2::Params { memory: 64, iterations: 1, parallelism: 2 })];
+        for (cipher, kdf) in [Cipher::Aes256Gcm, Cipher::ChaCha20Poly1305].iter().zip(kdfs.iter()) {
+            let encrypted = encrypt(WORDS.as_bytes(), PASSPHRASE, *cipher, *kdf).unwrap();
+            assert_eq!(super::kdf(&encrypted).unwrap(), *kdf);
+            assert!(!is_legacy(&encrypted));
+            assert_eq!(decrypt(&encrypted, PASSPHRASE).unwrap().as_slice(), WORDS.as_bytes());
+            assert!(decrypt(&encrypted, "wrong").is_err());
+            // the header is authenticated
+            let mut tampered = encrypted.clone();
+            tampered[10] ^= 1;
+            assert!(decrypt(&tampered, PASSPHRASE).is_err());
+        }
+
+        let legacy = decode("7d4772f962b12bdffefdef81b40422793c17d9c64ec2d4be049fde55da5b07c141eb2f841a9e82e1f68adec30923df4b4a3f7bc28f0aa5d879212c34203aa9734b1c3a9d7c7dbf44a134dfc18039226b").unwrap();
+        assert!(is_legacy(&legacy));
+        assert_eq!(decrypt(&legacy, PASSPHRASE).unwrap().as_slice(), WORDS.as_bytes());
+        let migrated = migrate(&legacy, PASSPHRASE, Cipher::Aes256Gcm, Kdf::Pbkdf2Sha256(1000)).unwrap();
+        assert!(!is_legacy(&migrated));
+        assert_eq!(decrypt(&migrated, PASSPHRASE).unwrap().as_slice(), WORDS.as_bytes());
+    }
+}
@@ -0,0 +1,176 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Labels
+//!
+//! Labels of addresses, transactions and outputs, exchanged with other wallets in BIP329 JSON lines
+//!
+
+use std::collections::BTreeMap;
+
+use bitcoin::{Address, OutPoint, Txid};
+
+use error::WalletError;
+
+/// what a label refers to
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LabelType {
+    /// a transaction id
+    Tx,
+    /// an address
+    Addr,
+    /// a hex public key
+    Pubkey,
+    /// an outpoint spent, as txid:vout
+    Input,
+    /// an outpoint created, as txid:vout
+    Output,
+    /// an extended public key
+    Xpub
+}
+
+/// a line of BIP329 export
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Label {
+    #[serde(rename = "type")]
+    pub label_type: LabelType,
+    #[serde(rename = "ref")]
+    pub reference: String,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub label: Option<String>,
+    /// descriptor of the wallet the label came from
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub origin: Option<String>,
+    /// outputs only, false if the coin should not be spent
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub spendable: Option<bool>
+}
+
+/// labels by type and reference
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Labels {
+    labels: BTreeMap<(LabelType, String), Label>
+}
+
+impl Labels {
+    pub fn new() -> Labels {
+        Labels::default()
+    }
+
+    /// add or replace a label
+    pub fn set(&mut self, label_type: LabelType, reference: String, label: &str) {
+        let entry = self.labels.entry((label_type, reference.clone()))
+            .or_insert_with(|| Label { label_type, reference, label: None, origin: None, spendable: None });
+        entry.label = Some(label.to_string());
+    }
+
+    pub fn get(&self, label_type: LabelType, reference: &str) -> Option<&Label> {
+        self.labels.get(&(label_type, reference.to_string()))
+    }
+
+    pub fn remove(&mut self, label_type: LabelType, reference: &str) -> Option<Label> {
+        self.labels.remove(&(label_type, reference.to_string()))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=&Label> {
+        self.labels.values()
+    }
+
+    pub fn set_address(&mut self, address: &Address, label: &str) {
+        self.set(LabelType::Addr, address.to_string(), label)
+    }
+
+    pub fn address(&self, address: &Address) -> Option<&str> {
+        self.get(LabelType::Addr, &address.to_string()).and_then(|l| l.label.as_deref())
+    }
+
+    pub fn set_transaction(&mut self, txid: &Txid, label: &str) {
+        self.set(LabelType::Tx, txid.to_string(), label)
+    }
+
+    pub fn transaction(&self, txid: &Txid) -> Option<&str> {
+        self.get(LabelType::Tx, &txid.to_string()).and_then(|l| l.label.as_deref())
+    }
+
+    pub fn set_output(&mut self, point: &OutPoint, label: &str) {
+        self.set(LabelType::Output, point.to_string(), label)
+    }
+
+    pub fn output(&self, point: &OutPoint) -> Option<&str> {
+        self.get(LabelType::Output, &point.to_string()).and_then(|l| l.label.as_deref())
+    }
+
+    /// BIP329 JSON lines, one label per line
+    pub fn export(&self) -> String {
+        self.labels.values().map(|label| serde_json::to_string(label).expect("labels serialize") + "\n").collect()
+    }
+
+    /// merge BIP329 JSON lines, imported labels replace those of the same type and reference.
+    /// Returns the number of labels imported, lines of unknown types are skipped.
+    pub fn import(&mut self, jsonl: &str) -> Result<usize, WalletError> {
+        let mut imported = 0;
+        for line in jsonl.lines().map(|l| l.trim()).filter(|l| !l.is_empty()) {
+            let value: serde_json::Value = serde_json::from_str(line).map_err(|_| WalletError::Unsupported("invalid BIP329 line"))?;
+            if let Ok(label) = serde_json::from_value::<Label>(value.clone()) {
+                self.labels.insert((label.label_type, label.reference.clone()), label);
+                imported += 1;
+            } else if value["type"].is_string() && value["ref"].is_string() && !value["type"].as_str().map(is_known_type).unwrap_or(false) {
+                continue;
+            } else {
+                return Err(WalletError::Unsupported("invalid BIP329 label"));
+            }
+        }
+        Ok(imported)
+    }
+}
+
+fn is_known_type(label_type: &str) -> bool {
+    ["tx", "addr", "pubkey", "input", "output", "xpub"].contains(&label_type)
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use super::*;
+
+    const EXPORT: &str = r#"{"type":"tx","ref":"f91d0a8a78462bc59398f2c5d7a84fcff491c26ba54c4833478b202796c8aafd","label":"Transaction","origin":"wpkh([d34db33f/84'/0'/0'])"}
+{"type":"addr","ref":"bc1q34aq5drpuwy3wgl9lhup9892qp6svr8ldzyy7c","label":"Address"}
+{"type":"output","ref":"f91d0a8a78462bc59398f2c5d7a84fcff491c26ba54c4833478b202796c8aafd:1","label":"Output","spendable":false}
+{"type":"future","ref":"whatever","label":"skipped"}
+"#;
+
+    #[test]
+    fn test_bip329() {
+        let mut labels = Labels::new();
+        assert_eq!(labels.import(EXPORT).unwrap(), 3);
+        let txid = Txid::from_str("f91d0a8a78462bc59398f2c5d7a84fcff491c26ba54c4833478b202796c8aafd").unwrap();
+        let address = Address::from_str("bc1q34aq5drpuwy3wgl9lhup9892qp6svr8ldzyy7c").unwrap().assume_checked();
+        assert_eq!(labels.transaction(&txid), Some("Transaction"));
+        assert_eq!(labels.address(&address), Some("Address"));
+        let point = OutPoint { txid, vout: 1 };
+        assert_eq!(labels.output(&point), Some("Output"));
+        assert_eq!(labels.get(LabelType::Output, &point.to_string()).unwrap().spendable, Some(false));
+
+        labels.set_output(&point, "Sentimental");
+        let mut restored = Labels::new();
+        assert_eq!(restored.import(&labels.export()).unwrap(), 3);
+        assert_eq!(restored, labels);
+        assert_eq!(restored.output(&point), Some("Sentimental"));
+        assert!(restored.import("{\"type\":\"tx\"}").is_err());
+    }
+}
@@ -0,0 +1,139 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # LDK keys
+//!
+//! Channel signers and on-chain scripts of a rust-lightning node from the master account
+//!
+
+use std::convert::TryFrom;
+
+use bitcoin::{Network, ScriptBuf};
+use bitcoin::bip32::Xpriv;
+use rust_lightning::io::Cursor;
+use rust_lightning::ln::msgs::DecodeError;
+use rust_lightning::ln::script::ShutdownScript;
+use rust_lightning::sign::{ChangeDestinationSource, EntropySource, InMemorySigner, SignerProvider};
+use rust_lightning::util::ser::ReadableArgs;
+use secp256k1::{All, PublicKey, Secp256k1};
+use secp256k1::rand::{thread_rng, RngCore};
+
+use account::{MasterAccount, Unlocker};
+use error::WalletError;
+use lightning::{self, ChannelKeys};
+
+/// keys of an LDK node, pass it as entropy source and signer provider to the ChannelManager.
+/// Channel keys follow the key families of `lightning`, the channel index is in the channel_keys_id LDK persists.
+pub struct LdkKeys {
+    master: Xpriv,
+    network: Network,
+    destination_script: ScriptBuf,
+    shutdown_script: ShutdownScript,
+    secp: Secp256k1<All>
+}
+
+impl LdkKeys {
+    /// keys of the master, claimed and closed channel funds go to change keys of its lowest account with a change chain
+    pub fn new(master: &mut MasterAccount, unlocker: &Unlocker, network: Network) -> Result<LdkKeys, WalletError> {
+        let change = master.default_change().ok_or(WalletError::Unsupported("no change account"))?;
+        let destination_script = master.next_key(change)?.0.script_pubkey;
+        let shutdown_script = ShutdownScript::try_from(master.next_key(change)?.0.script_pubkey)
+            .map_err(|_| WalletError::Unsupported("change account must be segwit"))?;
+        Ok(LdkKeys { master: *unlocker.master_private(), network, destination_script, shutdown_script, secp: Secp256k1::new() })
+    }
+
+    /// the node_id of `lightning::node_id`
+    pub fn node_id(&self) -> Result<PublicKey, WalletError> {
+        lightning::node_id(&self.master, self.network)
+    }
+
+    /// index of the channel keys of a channel_keys_id
+    pub fn channel_index(channel_keys_id: &[u8; 32]) -> u32 {
+        u32::from_be_bytes([channel_keys_id[0], channel_keys_id[1], channel_keys_id[2], channel_keys_id[3]]) & 0x7fff_ffff
+    }
+}
+
+impl EntropySource for LdkKeys {
+    fn get_secure_random_bytes(&self) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        thread_rng().fill_bytes(&mut bytes);
+        bytes
+    }
+}
+
+impl SignerProvider for LdkKeys {
+    type EcdsaSigner = InMemorySigner;
+
+    /// random, its first 31 bits are the channel index
+    fn generate_channel_keys_id(&self, _inbound: bool, _channel_value_satoshis: u64, _user_channel_id: u128) -> [u8; 32] {
+        self.get_secure_random_bytes()
+    }
+
+    fn derive_channel_signer(&self, channel_value_satoshis: u64, channel_keys_id: [u8; 32]) -> InMemorySigner {
+        let keys = ChannelKeys::new(&self.master, self.network, LdkKeys::channel_index(&channel_keys_id))
+            .expect("channel index is not hardened");
+        InMemorySigner::new(&self.secp, keys.funding_key, keys.revocation_base_key, keys.payment_key, keys.delayed_payment_base_key,
+                            keys.htlc_base_key, keys.commitment_seed, channel_value_satoshis, channel_keys_id, self.get_secure_random_bytes())
+    }
+
+    fn read_chan_signer(&self, reader: &[u8]) -> Result<InMemorySigner, DecodeError> {
+        InMemorySigner::read(&mut Cursor::new(reader), self)
+    }
+
+    fn get_destination_script(&self, _channel_keys_id: [u8; 32]) -> Result<ScriptBuf, ()> {
+        Ok(self.destination_script.clone())
+    }
+
+    fn get_shutdown_scriptpubkey(&self) -> Result<ShutdownScript, ()> {
+        Ok(self.shutdown_script.clone())
+    }
+}
+
+impl ChangeDestinationSource for LdkKeys {
+    fn get_change_destination_script(&self) -> Result<ScriptBuf, ()> {
+        Ok(self.destination_script.clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rust_lightning::sign::ChannelSigner;
+    use rust_lightning::util::ser::Writeable;
+
+    use account::{AccountAddressType, MasterKeyEntropy};
+    use super::*;
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+
+    #[test]
+    fn test_ldk() {
+        let mut master = MasterAccount::new(MasterKeyEntropy::Low, Network::Bitcoin, PASSPHRASE, None).unwrap();
+        let mut unlocker = Unlocker::new(master.encrypted(), PASSPHRASE, None, Network::Bitcoin, None).unwrap();
+        assert!(LdkKeys::new(&mut master, &unlocker, Network::Bitcoin).is_err());
+        master.new_keychains(&mut unlocker, AccountAddressType::P2WPKH, 0, 10).unwrap();
+        let keys = LdkKeys::new(&mut master, &unlocker, Network::Bitcoin).unwrap();
+        assert!(master.get_key_for_script(&keys.get_destination_script([0u8; 32]).unwrap()).is_some());
+        assert_eq!(keys.node_id().unwrap(), lightning::node_id(unlocker.master_private(), Network::Bitcoin).unwrap());
+
+        let id = keys.generate_channel_keys_id(false, 100000, 1);
+        assert_ne!(id, keys.generate_channel_keys_id(false, 100000, 1));
+        let signer = keys.derive_channel_signer(100000, id);
+        let expected = ChannelKeys::new(unlocker.master_private(), Network::Bitcoin, LdkKeys::channel_index(&id)).unwrap().basepoints();
+        assert_eq!(signer.pubkeys().funding_pubkey, expected.funding_pubkey);
+        assert_eq!(signer.pubkeys().htlc_basepoint.to_public_key(), expected.htlc_basepoint);
+        assert_eq!(keys.read_chan_signer(&signer.encode()).unwrap(), signer);
+    }
+}
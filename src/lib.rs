@@ -19,24 +19,134 @@
 #![deny(non_snake_case)]
 #![deny(unused_mut)]
 #![deny(unused_must_use)]
-#![forbid(unsafe_code)]
+#![cfg_attr(not(feature = "ffi"), forbid(unsafe_code))]
+#![cfg_attr(feature = "ffi", deny(unsafe_code))]
 
+#[cfg(not(target_arch = "wasm32"))]
 extern crate crypto;
+#[cfg(target_arch = "wasm32")]
+extern crate web_time;
 extern crate secp256k1;
+extern crate ring;
+extern crate zeroize;
+extern crate unicode_normalization;
 extern crate bitcoin;
 extern crate bitcoin_hashes;
 #[cfg(test)]
 extern crate hex;
-#[cfg(test)]
 extern crate serde_json;
+extern crate thiserror;
+#[cfg(all(any(feature = "electrum", feature = "esplora", feature = "mempool", feature = "payjoin"), not(feature = "offline")))]
+extern crate rustls;
+#[cfg(all(any(feature = "electrum", feature = "esplora", feature = "mempool", feature = "payjoin"), not(feature = "offline")))]
+extern crate webpki_roots;
+#[cfg(all(feature = "async", not(feature = "offline")))]
+extern crate tokio;
+#[cfg(all(feature = "async", not(feature = "offline")))]
+extern crate tokio_rustls;
+#[cfg(feature = "parallel")]
+extern crate rayon;
+#[cfg(feature = "ldk")]
+extern crate lightning as rust_lightning;
+#[cfg(feature = "policy")]
+extern crate miniscript;
+#[cfg(feature = "qr")]
+extern crate ur;
+#[cfg(feature = "sqlite")]
+extern crate rusqlite;
 
 extern crate serde;
 #[macro_use] extern crate serde_derive;
 
+pub mod secret;
 pub mod mnemonic;
+pub mod seedqr;
+pub mod argon2;
+pub mod keystore;
+pub mod slip39;
+pub mod bip85;
+pub mod lightning;
 pub mod error;
 pub mod context;
+pub mod events;
+pub mod network;
+pub mod offline;
 pub mod account;
+pub mod keycache;
+pub mod shared;
 pub mod coins;
+pub mod checkpoint;
+pub mod utxo;
+pub mod history;
+pub mod labels;
+pub mod coinselect;
+pub mod fees;
+pub mod dust;
+pub mod consolidate;
+pub mod sweep;
 pub mod proved;
+pub mod psbt;
+pub mod psbtv2;
+pub mod sighash;
+pub mod signer;
+pub mod hotcold;
+pub mod deniable;
+pub mod antiexfil;
+pub mod builder;
+pub mod privacy;
+pub mod broadcast;
+pub mod chain;
+pub mod filters;
+pub mod store;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod manager;
+pub mod backup;
+pub mod verification;
+pub mod discovery;
+pub mod watch;
+pub mod multisig;
+pub mod musig;
+pub mod address;
+pub mod uri;
+pub mod payjoin;
+pub mod message;
+pub mod descriptor;
+pub mod fuzz;
+pub mod slip132;
+pub mod electrumseed;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod bip38;
+pub mod paymentcode;
+pub mod vanity;
+pub mod silentpayment;
+#[cfg(all(any(feature = "electrum", feature = "p2p", feature = "bitcoind", feature = "esplora", feature = "mempool", feature = "payjoin"), not(feature = "offline")))]
+pub mod proxy;
+#[cfg(all(feature = "electrum", not(feature = "offline")))]
+pub mod electrum;
+#[cfg(all(feature = "esplora", not(feature = "offline")))]
+pub mod esplora;
+#[cfg(all(feature = "p2p", not(feature = "offline")))]
+pub mod p2p;
+#[cfg(all(feature = "bitcoind", not(feature = "offline")))]
+pub mod bitcoind;
+#[cfg(all(feature = "async", not(target_arch = "wasm32"), not(feature = "offline")))]
+pub mod nonblocking;
+#[cfg(feature = "hwi")]
+pub mod hwi;
+#[cfg(feature = "ldk")]
+pub mod ldk;
+#[cfg(feature = "policy")]
+pub mod policy;
+#[cfg(feature = "policy")]
+pub mod vault;
+#[cfg(feature = "qr")]
+pub mod qr;
+#[cfg(feature = "testutil")]
+pub mod testutil;
+#[cfg(feature = "ffi")]
+#[allow(unsafe_code)]
+pub mod ffi;
+#[cfg(feature = "bindings")]
+pub mod bindings;
 
@@ -0,0 +1,188 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Lightning keys
+//!
+//! Node and channel keys of a lightning node derived from the master key
+//!
+
+use bitcoin::Network;
+use bitcoin::bip32::{ChildNumber, Xpriv};
+use bitcoin_hashes::{sha256, Hash, HashEngine};
+use secp256k1::{PublicKey, Secp256k1, SecretKey};
+
+use account::coin_type;
+use bip85;
+use context::SecpContext;
+use error::WalletError;
+use secret::SecretBytes;
+
+/// BIP43 purpose of the key families of LND
+pub const PURPOSE: u32 = 1017;
+
+/// the first per commitment index of BOLT3, indexes count down
+pub const FIRST_COMMITMENT_INDEX: u64 = (1 << 48) - 1;
+
+/// key families of LND, keys are at m / 1017' / coin' / family' / 0 / index
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum KeyFamily {
+    MultiSig = 0,
+    RevocationBase = 1,
+    HtlcBase = 2,
+    PaymentBase = 3,
+    DelayBase = 4,
+    RevocationRoot = 5,
+    NodeKey = 6,
+    StaticBackup = 7,
+    TowerSession = 8,
+    TowerId = 9
+}
+
+/// a key of a family at index
+pub fn derive(master: &Xpriv, network: Network, family: KeyFamily, index: u32) -> Result<SecretKey, WalletError> {
+    let context = SecpContext::new();
+    let mut key = *master;
+    for child in &[ChildNumber::from_hardened_idx(PURPOSE)?, ChildNumber::from_hardened_idx(coin_type(network))?,
+                   ChildNumber::from_hardened_idx(family as u32)?, ChildNumber::from_normal_idx(0)?, ChildNumber::from_normal_idx(index)?] {
+        key = context.private_child(&key, *child)?;
+    }
+    Ok(key.private_key)
+}
+
+/// the key identifying the node, its public key is the node_id
+pub fn node_key(master: &Xpriv, network: Network) -> Result<SecretKey, WalletError> {
+    derive(master, network, KeyFamily::NodeKey, 0)
+}
+
+/// the node_id announced to the network
+pub fn node_id(master: &Xpriv, network: Network) -> Result<PublicKey, WalletError> {
+    Ok(node_key(master, network)?.public_key(&Secp256k1::signing_only()))
+}
+
+/// a 32 byte seed for the KeysManager of LDK, BIP85 hex entropy.
+/// LDK derives its node and channel keys from it with its own scheme.
+pub fn ldk_seed(master: &Xpriv, index: u32) -> Result<SecretBytes, WalletError> {
+    bip85::hex(master, 32, index)
+}
+
+/// the secret keys of a channel
+pub struct ChannelKeys {
+    /// key of the 2-of-2 funding output
+    pub funding_key: SecretKey,
+    pub revocation_base_key: SecretKey,
+    pub payment_key: SecretKey,
+    pub delayed_payment_base_key: SecretKey,
+    pub htlc_base_key: SecretKey,
+    /// seed of the per commitment secrets
+    pub commitment_seed: [u8; 32]
+}
+
+/// public keys of a channel sent in open_channel and accept_channel
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ChannelBasepoints {
+    pub funding_pubkey: PublicKey,
+    pub revocation_basepoint: PublicKey,
+    pub payment_point: PublicKey,
+    pub delayed_payment_basepoint: PublicKey,
+    pub htlc_basepoint: PublicKey
+}
+
+impl ChannelKeys {
+    /// keys of the channel at index, each from the key family of its kind.
+    /// The commitment seed is the hash of the revocation root key of the index.
+    pub fn new(master: &Xpriv, network: Network, index: u32) -> Result<ChannelKeys, WalletError> {
+        let revocation_root = derive(master, network, KeyFamily::RevocationRoot, index)?;
+        Ok(ChannelKeys {
+            funding_key: derive(master, network, KeyFamily::MultiSig, index)?,
+            revocation_base_key: derive(master, network, KeyFamily::RevocationBase, index)?,
+            payment_key: derive(master, network, KeyFamily::PaymentBase, index)?,
+            delayed_payment_base_key: derive(master, network, KeyFamily::DelayBase, index)?,
+            htlc_base_key: derive(master, network, KeyFamily::HtlcBase, index)?,
+            commitment_seed: sha256::Hash::hash(&revocation_root.secret_bytes()).to_byte_array()
+        })
+    }
+
+    /// the public keys of the channel
+    pub fn basepoints(&self) -> ChannelBasepoints {
+        let secp = Secp256k1::signing_only();
+        ChannelBasepoints {
+            funding_pubkey: self.funding_key.public_key(&secp),
+            revocation_basepoint: self.revocation_base_key.public_key(&secp),
+            payment_point: self.payment_key.public_key(&secp),
+            delayed_payment_basepoint: self.delayed_payment_base_key.public_key(&secp),
+            htlc_basepoint: self.htlc_base_key.public_key(&secp)
+        }
+    }
+
+    /// the per commitment secret of a commitment index, see `per_commitment_secret`
+    pub fn per_commitment_secret(&self, index: u64) -> [u8; 32] {
+        per_commitment_secret(&self.commitment_seed, index)
+    }
+
+    /// the per commitment point of a commitment index
+    pub fn per_commitment_point(&self, index: u64) -> Result<PublicKey, WalletError> {
+        Ok(SecretKey::from_slice(&self.per_commitment_secret(index))?.public_key(&Secp256k1::signing_only()))
+    }
+}
+
+/// the per commitment secret of BOLT3 for an index below 2^48
+pub fn per_commitment_secret(seed: &[u8; 32], index: u64) -> [u8; 32] {
+    let mut secret = *seed;
+    for bit in (0..48).rev() {
+        if index & (1 << bit) != 0 {
+            secret[bit / 8] ^= 1 << (bit & 7);
+            let mut engine = sha256::Hash::engine();
+            engine.input(&secret);
+            secret = sha256::Hash::from_engine(engine).to_byte_array();
+        }
+    }
+    secret
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use hex::decode;
+
+    use super::*;
+
+    #[test]
+    fn test_lightning() {
+        // generation tests of BOLT3
+        assert_eq!(per_commitment_secret(&[0u8; 32], FIRST_COMMITMENT_INDEX).to_vec(),
+                   decode("02a40c85b6f28da08dfdbe0926c53fab2de6d28c10301f8f7c4073d5e42e3148").unwrap());
+        assert_eq!(per_commitment_secret(&[0xffu8; 32], FIRST_COMMITMENT_INDEX).to_vec(),
+                   decode("7cc854b54e3e0dcdb010d7a3fee464a9687be6e8db3be6854c475621e007a5dc").unwrap());
+        assert_eq!(per_commitment_secret(&[0xffu8; 32], 0xaaaaaaaaaaa).to_vec(),
+                   decode("56f4008fb007ca9acf0e15b054d5c9fd12ee06cea347914ddbaed70d1c13a528").unwrap());
+        let mut seed = [0u8; 32];
+        seed.copy_from_slice(&decode("0101010101010101010101010101010101010101010101010101010101010101").unwrap());
+        assert_eq!(per_commitment_secret(&seed, 1).to_vec(),
+                   decode("915c75942a26bb3a433a8ce2cb0427c29ec6c1775cfc78328b57f6ba7bfeaa9c").unwrap());
+
+        let master = Xpriv::from_str("xprv9s21ZrQH143K2LBWUUQRFXhucrQqBpKdRRxNVq2zBqsx8HVqFk2uYo8kmbaLLHRdqtQpUm98uKfu3vca1LqdGhUtyoFnCNkfmXRyPXLjbKb").unwrap();
+        let node = node_id(&master, Network::Bitcoin).unwrap();
+        assert_ne!(node, node_id(&master, Network::Testnet).unwrap());
+        let channel = ChannelKeys::new(&master, Network::Bitcoin, 0).unwrap();
+        let basepoints = channel.basepoints();
+        assert_eq!(channel.funding_key, derive(&master, Network::Bitcoin, KeyFamily::MultiSig, 0).unwrap());
+        assert_ne!(basepoints, ChannelKeys::new(&master, Network::Bitcoin, 1).unwrap().basepoints());
+        assert_ne!(basepoints.funding_pubkey, node);
+        assert_ne!(channel.per_commitment_point(FIRST_COMMITMENT_INDEX).unwrap(), channel.per_commitment_point(FIRST_COMMITMENT_INDEX - 1).unwrap());
+        assert_eq!(ldk_seed(&master, 0).unwrap().len(), 32);
+    }
+}
@@ -0,0 +1,234 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Wallet manager
+//!
+//! Independent wallets of different seeds, e.g. of the users of a server, each in its own store
+//!
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use bitcoin::{Block, BlockHash};
+
+use account::MasterAccount;
+use coins::Coins;
+use error::WalletError;
+use store::WalletStore;
+
+/// a wallet of the manager, its master account and coins with the store they persist to
+pub struct ManagedWallet<S: WalletStore> {
+    master: MasterAccount,
+    coins: Coins,
+    store: S
+}
+
+impl<S: WalletStore> ManagedWallet<S> {
+    pub fn master(&self) -> &MasterAccount {
+        &self.master
+    }
+
+    pub fn master_mut(&mut self) -> &mut MasterAccount {
+        &mut self.master
+    }
+
+    pub fn coins(&self) -> &Coins {
+        &self.coins
+    }
+
+    pub fn coins_mut(&mut self) -> &mut Coins {
+        &mut self.coins
+    }
+
+    /// the store, e.g. for history
+    pub fn store(&mut self) -> &mut S {
+        &mut self.store
+    }
+
+    /// store master and coins
+    pub fn save(&mut self) -> Result<(), WalletError> {
+        self.store.save_master(&self.master)?;
+        self.store.save_coins(&self.coins)
+    }
+}
+
+/// wallets by id, no two of the same master key
+pub struct WalletManager<S: WalletStore> {
+    wallets: HashMap<String, ManagedWallet<S>>
+}
+
+impl<S: WalletStore> Default for WalletManager<S> {
+    fn default() -> WalletManager<S> {
+        WalletManager { wallets: HashMap::new() }
+    }
+}
+
+impl<S: WalletStore> WalletManager<S> {
+    pub fn new() -> WalletManager<S> {
+        WalletManager::default()
+    }
+
+    /// add a new wallet and save it to its store, that must not hold a wallet yet
+    pub fn create(&mut self, id: &str, master: MasterAccount, mut store: S) -> Result<&mut ManagedWallet<S>, WalletError> {
+        if store.load_master()?.is_some() {
+            return Err(WalletError::Unsupported("store holds a wallet"));
+        }
+        self.check(id, &master)?;
+        let mut wallet = ManagedWallet { master, coins: Coins::new(), store };
+        wallet.save()?;
+        Ok(self.wallets.entry(id.to_string()).or_insert(wallet))
+    }
+
+    /// add the wallet of a store
+    pub fn load(&mut self, id: &str, mut store: S) -> Result<&mut ManagedWallet<S>, WalletError> {
+        let master = store.load_master()?.ok_or(WalletError::Unsupported("store holds no wallet"))?;
+        self.check(id, &master)?;
+        let coins = store.load_coins()?;
+        Ok(self.wallets.entry(id.to_string()).or_insert(ManagedWallet { master, coins, store }))
+    }
+
+    fn check(&self, id: &str, master: &MasterAccount) -> Result<(), WalletError> {
+        if self.wallets.contains_key(id) {
+            return Err(WalletError::Unsupported("wallet id is taken"));
+        }
+        if self.wallets.values().any(|w| w.master.master_public() == master.master_public()) {
+            return Err(WalletError::Unsupported("wallet of the master key is managed already"));
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, id: &str) -> Option<&ManagedWallet<S>> {
+        self.wallets.get(id)
+    }
+
+    pub fn get_mut(&mut self, id: &str) -> Option<&mut ManagedWallet<S>> {
+        self.wallets.get_mut(id)
+    }
+
+    /// ids of the wallets in order
+    pub fn ids(&self) -> Vec<&str> {
+        let mut ids = self.wallets.keys().map(|id| id.as_str()).collect::<Vec<_>>();
+        ids.sort();
+        ids
+    }
+
+    /// take a wallet off the manager, its store is left as it is
+    pub fn remove(&mut self, id: &str) -> Option<ManagedWallet<S>> {
+        self.wallets.remove(id)
+    }
+
+    /// process a block for all wallets, see `Coins::process`
+    pub fn process(&mut self, block: &Block) -> Result<(), WalletError> {
+        for wallet in self.wallets.values_mut() {
+            wallet.coins.process(&mut wallet.master, block)?;
+        }
+        Ok(())
+    }
+
+    /// unwind the tip block for all wallets, see `Coins::unwind_tip`
+    pub fn unwind_tip(&mut self, block_hash: &BlockHash) {
+        for wallet in self.wallets.values_mut() {
+            wallet.coins.unwind_tip(block_hash);
+        }
+    }
+
+    /// save all wallets, stops at the first failing store
+    pub fn save_all(&mut self) -> Result<(), WalletError> {
+        self.wallets.values_mut().try_for_each(|w| w.save())
+    }
+}
+
+/// path of the store file of a wallet in a directory, e.g. for `FileStore::open`.
+/// Ids are letters, digits, '-' and '_' so that they name a file of the directory.
+pub fn store_path<P: AsRef<Path>>(directory: P, id: &str) -> Result<PathBuf, WalletError> {
+    if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err(WalletError::Unsupported("wallet id must be letters, digits, '-' and '_'"));
+    }
+    Ok(directory.as_ref().join(format!("{}.wallet", id)))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{env, fs};
+
+    use bitcoin::{Amount, CompactTarget, Network, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxMerkleNode, TxOut, Witness};
+    use bitcoin::absolute::LockTime;
+    use bitcoin::block::Header;
+    use bitcoin::transaction::Version;
+    use bitcoin_hashes::Hash;
+
+    use account::{AccountAddressType, MasterKeyEntropy, Unlocker};
+    use argon2;
+    use keystore::Kdf;
+    use store::FileStore;
+    use super::*;
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+
+    #[test]
+    fn test_manager() {
+        let mut directory = env::temp_dir();
+        directory.push(format!("bitcoin-wallet-manager-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let kdf = Kdf::Argon2id(argon2::Params { memory: 64, iterations: 1, parallelism: 1 });
+        let open = |id: &str| FileStore::open(store_path(&directory, id).unwrap(), PASSPHRASE, kdf).unwrap();
+        assert!(store_path(&directory, "../alice").is_err());
+
+        let mut manager = WalletManager::new();
+        let mut scripts = Vec::new();
+        for id in &["bob", "alice"] {
+            let mut master = MasterAccount::new(MasterKeyEntropy::Low, Network::Bitcoin, PASSPHRASE, None).unwrap();
+            let mut unlocker = Unlocker::new(master.encrypted(), PASSPHRASE, None, Network::Bitcoin, None).unwrap();
+            master.new_keychains(&mut unlocker, AccountAddressType::P2WPKH, 0, 10).unwrap();
+            scripts.push(master.next_receive_address(0).unwrap().script_pubkey());
+            let copy = MasterAccount::from_encrypted(master.encrypted(), *master.master_public(), master.birth());
+            manager.create(id, master, open(id)).unwrap();
+            assert!(manager.create("carol", copy, open("carol")).is_err());
+        }
+        assert!(!store_path(&directory, "carol").unwrap().exists());
+        assert_eq!(manager.ids(), vec!("alice", "bob"));
+        assert!(manager.create("bob", MasterAccount::new(MasterKeyEntropy::Low, Network::Bitcoin, PASSPHRASE, None).unwrap(), open("dave")).is_err());
+
+        // a payment to bob only
+        let transaction = Transaction {
+            version: Version::TWO, lock_time: LockTime::ZERO,
+            input: vec!(TxIn { previous_output: OutPoint::null(), script_sig: ScriptBuf::new(), sequence: Sequence::MAX, witness: Witness::new() }),
+            output: vec!(TxOut { value: Amount::from_sat(10000), script_pubkey: scripts[0].clone() })
+        };
+        let mut block = Block {
+            header: Header { version: bitcoin::block::Version::ONE, prev_blockhash: BlockHash::all_zeros(), merkle_root: TxMerkleNode::all_zeros(),
+                time: 0, bits: CompactTarget::from_consensus(0), nonce: 0 },
+            txdata: vec!(transaction)
+        };
+        block.header.merkle_root = block.compute_merkle_root().unwrap();
+        manager.process(&block).unwrap();
+        manager.save_all().unwrap();
+        assert_eq!(manager.get("bob").unwrap().coins().owned().len(), 1);
+        assert!(manager.get("alice").unwrap().coins().owned().is_empty());
+
+        // each wallet restores from its own file
+        let bob = manager.remove("bob").unwrap();
+        assert!(manager.get("bob").is_none());
+        let mut restored = WalletManager::new();
+        for id in &["alice", "bob"] {
+            restored.load(id, open(id)).unwrap();
+        }
+        assert_eq!(restored.get("bob").unwrap().master().master_public(), bob.master().master_public());
+        assert_eq!(restored.get("bob").unwrap().coins().owned().len(), 1);
+        assert!(restored.load("carol", open("carol")).is_err());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,194 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Signed messages
+//!
+//! Prove control of an address with BIP322 generic signed messages or legacy signmessage signatures
+//!
+
+use bitcoin::{Address, AddressType, Amount, EcdsaSighashType, OutPoint, Script, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Txid, Witness,
+              XOnlyPublicKey, absolute::LockTime, consensus, opcodes::all, script::Builder, sighash::{Prevouts, SighashCache},
+              taproot, transaction::Version};
+use bitcoin::base64::{Engine, engine::general_purpose::STANDARD};
+use bitcoin::sign_message::{signed_msg_hash, MessageSignature};
+use bitcoin_hashes::{sha256, Hash, HashEngine};
+use secp256k1::{Message, Secp256k1};
+
+use account::{Account, AccountAddressType, Unlocker};
+use error::WalletError;
+
+/// BIP322 tagged hash of a message
+pub fn message_hash(message: &[u8]) -> [u8; 32] {
+    let tag = sha256::Hash::hash(b"BIP0322-signed-message");
+    let mut engine = sha256::Hash::engine();
+    engine.input(tag.as_ref());
+    engine.input(tag.as_ref());
+    engine.input(message);
+    sha256::Hash::from_engine(engine).to_byte_array()
+}
+
+/// the virtual transaction that pays to the address signing the message
+pub fn to_spend(script_pubkey: &Script, message: &[u8]) -> Transaction {
+    Transaction {
+        version: Version(0),
+        lock_time: LockTime::ZERO,
+        input: vec!(TxIn {
+            previous_output: OutPoint { txid: Txid::all_zeros(), vout: 0xFFFFFFFF },
+            script_sig: Builder::new().push_opcode(all::OP_PUSHBYTES_0).push_slice(message_hash(message)).into_script(),
+            sequence: Sequence::ZERO,
+            witness: Witness::new()
+        }),
+        output: vec!(TxOut { value: Amount::ZERO, script_pubkey: script_pubkey.to_owned() })
+    }
+}
+
+/// the virtual transaction whose signature is the signature of the message
+pub fn to_sign(to_spend: &Transaction) -> Transaction {
+    Transaction {
+        version: Version(0),
+        lock_time: LockTime::ZERO,
+        input: vec!(TxIn {
+            previous_output: OutPoint { txid: to_spend.compute_txid(), vout: 0 },
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::ZERO,
+            witness: Witness::new()
+        }),
+        output: vec!(TxOut { value: Amount::ZERO, script_pubkey: Builder::new().push_opcode(all::OP_RETURN).into_script() })
+    }
+}
+
+/// sign a message with a key of the account
+/// P2PKH keys give legacy signmessage signatures, others BIP322 simple signatures
+/// or full signatures if a script_sig is needed
+pub fn sign(account: &Account, kix: u32, message: &str, unlocker: &mut Unlocker) -> Result<String, WalletError> {
+    let key = account.get_key(kix).ok_or(WalletError::Unsupported("unknown key"))?;
+    if account.address_type() == AccountAddressType::P2PKH {
+        let pk = account.unlock(unlocker, kix, key.tweak.clone())?;
+        let signature = unlocker.context().sign_recoverable(signed_msg_hash(message).as_byte_array(), &pk)?;
+        return Ok(MessageSignature::new(signature, pk.compressed).to_base64());
+    }
+    let spend = to_spend(&key.script_pubkey, message.as_bytes());
+    let spent = spend.output[0].clone();
+    let mut signed = to_sign(&spend);
+    if account.sign(&mut signed, EcdsaSighashType::All, |_: &OutPoint| Some(spent.clone()), unlocker)? != 1 {
+        return Err(WalletError::Unsupported("can not sign for this key"));
+    }
+    if signed.input[0].script_sig.is_empty() {
+        Ok(STANDARD.encode(consensus::serialize(&signed.input[0].witness)))
+    } else {
+        Ok(STANDARD.encode(consensus::serialize(&signed)))
+    }
+}
+
+/// true if the signature proves control of the address for the message
+/// accepts BIP322 simple and full signatures and legacy signatures of P2PKH addresses
+pub fn verify(address: &Address, message: &str, signature: &str) -> Result<bool, WalletError> {
+    let bytes = STANDARD.decode(signature).map_err(WalletError::Base64)?;
+    if address.address_type() == Some(AddressType::P2pkh) && bytes.len() == 65 {
+        return match MessageSignature::from_slice(&bytes) {
+            Ok(signature) => Ok(signature.is_signed_by_address(&Secp256k1::verification_only(), address, signed_msg_hash(message)).unwrap_or(false)),
+            Err(_) => Ok(false)
+        };
+    }
+    let spend = to_spend(&address.script_pubkey(), message.as_bytes());
+    let mut signed = to_sign(&spend);
+    if let Ok(witness) = consensus::deserialize::<Witness>(&bytes) {
+        signed.input[0].witness = witness;
+    } else if let Ok(full) = consensus::deserialize::<Transaction>(&bytes) {
+        if full.input.len() != 1 || full.input[0].previous_output != signed.input[0].previous_output || full.output != signed.output {
+            return Ok(false);
+        }
+        signed = full;
+    } else {
+        return Err(WalletError::Unsupported("malformed message signature"));
+    }
+    if address.address_type() == Some(AddressType::P2tr) {
+        return Ok(verify_key_spend(&signed, &spend.output[0]));
+    }
+    verify_script_spend(&signed, &spend)
+}
+
+// scripts are verified with libbitcoinconsensus, which does not build for wasm32
+#[cfg(not(target_arch = "wasm32"))]
+fn verify_script_spend(signed: &Transaction, spend: &Transaction) -> Result<bool, WalletError> {
+    let spent = OutPoint { txid: spend.compute_txid(), vout: 0 };
+    Ok(signed.verify(|point| if *point == spent { Some(spend.output[0].clone()) } else { None }).is_ok())
+}
+
+#[cfg(target_arch = "wasm32")]
+fn verify_script_spend(_signed: &Transaction, _spend: &Transaction) -> Result<bool, WalletError> {
+    Err(WalletError::Unsupported("BIP322 signatures of other than P2PKH and P2TR addresses are not verified on wasm32"))
+}
+
+/// libbitcoinconsensus does not validate taproot spends without all spent outputs,
+/// so key path signatures are checked here
+fn verify_key_spend(signed: &Transaction, spent: &TxOut) -> bool {
+    let witness = &signed.input[0].witness;
+    if witness.len() != 1 {
+        return false;
+    }
+    let signature = match taproot::Signature::from_slice(&witness[0]) {
+        Ok(signature) => signature,
+        Err(_) => return false
+    };
+    let output_key = match XOnlyPublicKey::from_slice(&spent.script_pubkey.as_bytes()[2..]) {
+        Ok(key) => key,
+        Err(_) => return false
+    };
+    match SighashCache::new(signed).taproot_key_spend_signature_hash(0, &Prevouts::All(std::slice::from_ref(spent)), signature.sighash_type) {
+        Ok(sighash) => Secp256k1::verification_only()
+            .verify_schnorr(&signature.signature, &Message::from_digest(sighash.to_byte_array()), &output_key).is_ok(),
+        Err(_) => false
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use bitcoin::Network;
+
+    use account::{MasterAccount, MasterKeyEntropy};
+    use super::*;
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+
+    #[test]
+    fn test_bip322() {
+        // test vectors of BIP322
+        assert_eq!(message_hash(b"")[..], hex::decode("c90c269c4f8fcbe6880f72a721ddfbf1914268a794cbb21cfafee13770ae19f1").unwrap()[..]);
+        assert_eq!(message_hash(b"Hello World")[..], hex::decode("f0eb03b1a75ac6d9847f55c624a99169b5dccba2a31f5b23bea77ba270de0a7a").unwrap()[..]);
+        let address = Address::from_str("bc1q9vza2e8x573nczrlzms0wvx3gsqjx7vavgkx0l").unwrap().assume_checked();
+        assert!(verify(&address, "", "AkcwRAIgM2gBAQqvZX15ZiysmKmQpDrG83avLIT492QBzLnQIxYCIBaTpOaD20qRlEylyxFSeEA2ba9YOixpX8z46TSDtS40ASECx/EgAxlkQpQ9hYjgGu6EBCPMVPwVIVJqO4XCsMvViHI=").unwrap());
+        assert!(verify(&address, "Hello World", "AkcwRAIgZRfIY3p7/DoVTty6YZbWS71bc5Vct9p9Fia83eRmw2QCICK/ENGfwLtptFluMGs2KsqoNSk89pO7F29zJLUx9a/sASECx/EgAxlkQpQ9hYjgGu6EBCPMVPwVIVJqO4XCsMvViHI=").unwrap());
+        assert!(!verify(&address, "Hello World", "AkcwRAIgM2gBAQqvZX15ZiysmKmQpDrG83avLIT492QBzLnQIxYCIBaTpOaD20qRlEylyxFSeEA2ba9YOixpX8z46TSDtS40ASECx/EgAxlkQpQ9hYjgGu6EBCPMVPwVIVJqO4XCsMvViHI=").unwrap());
+    }
+
+    #[test]
+    fn test_sign_verify() {
+        let mut master = MasterAccount::new(MasterKeyEntropy::Low, Network::Bitcoin, PASSPHRASE, None).unwrap();
+        let mut unlocker = Unlocker::new(master.encrypted(), PASSPHRASE, None, Network::Bitcoin, None).unwrap();
+        for (n, address_type) in [AccountAddressType::P2PKH, AccountAddressType::P2SHWPKH, AccountAddressType::P2WPKH, AccountAddressType::P2TR].iter().enumerate() {
+            let account = master.new_account(&mut unlocker, *address_type, n as u32, 0, 10).unwrap();
+            let signature = account.sign_message(3, "I control this address", &mut unlocker).unwrap();
+            assert!(account.verify_message(3, "I control this address", &signature).unwrap());
+            assert!(!account.verify_message(3, "I control an other address", &signature).unwrap());
+            assert!(!account.verify_message(4, "I control this address", &signature).unwrap());
+        }
+        let account = master.get((0, 0)).unwrap();
+        assert!(account.verify_message(0, "", "AAAA").is_err());
+    }
+}
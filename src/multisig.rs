@@ -0,0 +1,400 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Multisig
+//!
+//! M of N multisig accounts of BIP48 derived keys sorted as of BIP67, paying to P2WSH
+//!
+
+use std::sync::Arc;
+
+use bitcoin::{Address, Network, NetworkKind, PublicKey, Psbt, Script, ScriptBuf, ecdsa,
+              bip32::{ChildNumber, DerivationPath, Fingerprint, KeySource, Xpub},
+              opcodes::all, script::Builder, sighash::SighashCache};
+
+use account::{coin_type, Unlocker};
+use context::SecpContext;
+use error::WalletError;
+
+/// BIP48 purpose
+pub const PURPOSE: u32 = 48;
+
+/// BIP48 script type of native segwit multisig
+pub const P2WSH_SCRIPT_TYPE: u32 = 2;
+
+/// maximum number of keys of a P2WSH multisig script by standardness rules
+pub const MAX_COSIGNERS: usize = 20;
+
+/// a participant of a multisig account, known by its extended public key at
+/// m / 48' / coin_type' / account' / 2'
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Cosigner {
+    /// fingerprint of the cosigner's master key
+    pub fingerprint: Fingerprint,
+    /// path of account_public from the cosigner's master key
+    pub path: DerivationPath,
+    /// extended public key of the cosigner's account
+    pub account_public: Xpub
+}
+
+impl Cosigner {
+    /// the cosigner of the master unlocked, to be shared with the other participants
+    pub fn new(unlocker: &mut Unlocker, network: Network, account_number: u32) -> Result<Cosigner, WalletError> {
+        let path = account_path(network, account_number);
+        let context = SecpContext::new();
+        let account_public = context.extended_public_from_private(&unlocker.derive(&path)?);
+        Ok(Cosigner { fingerprint: unlocker.fingerprint(), path, account_public })
+    }
+}
+
+/// m / 48' / coin_type' / account' / 2'
+pub fn account_path(network: Network, account_number: u32) -> DerivationPath {
+    DerivationPath::from(vec!(
+        ChildNumber::Hardened { index: PURPOSE },
+        ChildNumber::Hardened { index: coin_type(network) },
+        ChildNumber::Hardened { index: account_number },
+        ChildNumber::Hardened { index: P2WSH_SCRIPT_TYPE }))
+}
+
+/// threshold OP_CHECKMULTISIG script of the keys in BIP67 order
+pub fn sorted_multisig(threshold: usize, keys: &[PublicKey]) -> Result<ScriptBuf, WalletError> {
+    if threshold == 0 || threshold > keys.len() || keys.len() > MAX_COSIGNERS {
+        return Err(WalletError::Unsupported("invalid multisig threshold or number of keys"));
+    }
+    if keys.iter().any(|k| !k.compressed) {
+        return Err(WalletError::Unsupported("segwit needs compressed keys"));
+    }
+    let mut keys = keys.to_vec();
+    keys.sort_by_key(|k| k.to_bytes());
+    let mut builder = Builder::new().push_int(threshold as i64);
+    for key in &keys {
+        builder = builder.push_key(key);
+    }
+    Ok(builder.push_int(keys.len() as i64).push_opcode(all::OP_CHECKMULTISIG).into_script())
+}
+
+/// instantiated key of a multisig account, the keys of all cosigners at the same index
+#[derive(Clone, Serialize, Deserialize)]
+pub struct MultisigKey {
+    pub index: u32,
+    /// in BIP67 order
+    pub keys: Vec<PublicKey>,
+    pub witness_script: ScriptBuf,
+    pub script_pubkey: ScriptBuf
+}
+
+/// an M of N multisig sub account
+pub struct MultisigAccount {
+    threshold: usize,
+    cosigners: Vec<Cosigner>,
+    sub_account_number: u32,
+    sub_account_publics: Vec<Xpub>,
+    context: Arc<SecpContext>,
+    instantiated: Vec<MultisigKey>,
+    next: u32,
+    look_ahead: u32,
+    network: Network
+}
+
+impl MultisigAccount {
+    /// a sub account of keys m / 48' / coin_type' / account' / 2' / sub_account of the cosigners
+    /// any threshold of them can spend, every participant builds the same account from the same cosigners
+    /// in any order
+    pub fn new(threshold: usize, cosigners: Vec<Cosigner>, sub_account_number: u32, look_ahead: u32, network: Network) -> Result<MultisigAccount, WalletError> {
+        if threshold == 0 || threshold > cosigners.len() || cosigners.len() > MAX_COSIGNERS {
+            return Err(WalletError::Unsupported("invalid multisig threshold or number of cosigners"));
+        }
+        if cosigners.iter().any(|c| c.account_public.network != NetworkKind::from(network)) {
+            return Err(WalletError::Network);
+        }
+        if cosigners.iter().enumerate().any(|(i, c)| cosigners[..i].iter().any(|o| o.account_public == c.account_public)) {
+            return Err(WalletError::Unsupported("duplicate cosigner"));
+        }
+        let context = Arc::new(SecpContext::new());
+        let sub_account_publics = cosigners.iter()
+            .map(|c| context.public_child(&c.account_public, ChildNumber::Normal { index: sub_account_number }))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut account = MultisigAccount {
+            threshold, cosigners, sub_account_number, sub_account_publics, context,
+            instantiated: Vec::new(), next: 0, look_ahead, network
+        };
+        account.do_look_ahead(0)?;
+        Ok(account)
+    }
+
+    pub fn threshold(&self) -> usize {
+        self.threshold
+    }
+
+    pub fn cosigners(&self) -> &Vec<Cosigner> {
+        &self.cosigners
+    }
+
+    pub fn sub_account_number(&self) -> u32 {
+        self.sub_account_number
+    }
+
+    pub fn next(&self) -> u32 {
+        self.next
+    }
+
+    pub fn look_ahead(&self) -> u32 {
+        self.look_ahead
+    }
+
+    pub fn network(&self) -> Network {
+        self.network
+    }
+
+    pub fn instantiated(&self) -> &Vec<MultisigKey> {
+        &self.instantiated
+    }
+
+    /// BIP32 path of the key of a cosigner
+    /// m / 48' / coin_type' / account' / 2' / sub / kix
+    pub fn key_path(&self, cosigner: &Cosigner, kix: u32) -> DerivationPath {
+        cosigner.path.child(ChildNumber::Normal { index: self.sub_account_number }).child(ChildNumber::Normal { index: kix })
+    }
+
+    /// look ahead from last seen
+    pub fn do_look_ahead(&mut self, seen: u32) -> Result<Vec<(u32, ScriptBuf)>, WalletError> {
+        use std::cmp::max;
+
+        let have = self.instantiated.len() as u32;
+        let need = max(seen + self.look_ahead, have) - have;
+        let mut new = Vec::new();
+        for i in 0..need {
+            new.push((have + i, self.instantiate_more()?.script_pubkey.clone()));
+        }
+        Ok(new)
+    }
+
+    fn instantiate_more(&mut self) -> Result<&MultisigKey, WalletError> {
+        let index = self.instantiated.len() as u32;
+        let mut keys = self.cosigner_keys(index)?.into_iter().map(|(k, _)| k).collect::<Vec<_>>();
+        let witness_script = sorted_multisig(self.threshold, &keys)?;
+        keys.sort_by_key(|k| k.to_bytes());
+        let script_pubkey = ScriptBuf::new_p2wsh(&witness_script.wscript_hash());
+        self.instantiated.push(MultisigKey { index, keys, witness_script, script_pubkey });
+        Ok(&self.instantiated[index as usize])
+    }
+
+    /// keys of the cosigners at an index, in the order of cosigners
+    fn cosigner_keys(&self, kix: u32) -> Result<Vec<(PublicKey, KeySource)>, WalletError> {
+        self.cosigners.iter().zip(self.sub_account_publics.iter()).map(|(c, s)| {
+            let public = PublicKey::new(self.context.public_child(s, ChildNumber::Normal { index: kix })?.public_key);
+            Ok((public, (c.fingerprint, self.key_path(c, kix))))
+        }).collect()
+    }
+
+    /// create a new key
+    pub fn next_key(&mut self) -> Result<&MultisigKey, WalletError> {
+        self.instantiate_more()?;
+        let key = &self.instantiated[self.next as usize];
+        self.next += 1;
+        Ok(key)
+    }
+
+    /// get a previously instantiated key
+    pub fn get_key(&self, kix: u32) -> Option<&MultisigKey> {
+        self.instantiated.get(kix as usize)
+    }
+
+    /// P2WSH address of a key
+    pub fn address(&self, kix: u32) -> Option<Address> {
+        self.get_key(kix).map(|k| Address::p2wsh(&k.witness_script, self.network))
+    }
+
+    /// move the next index forward, keys up to it and the look ahead are instantiated
+    pub fn set_next(&mut self, next: u32) -> Result<(), WalletError> {
+        if next > self.next {
+            self.next = next;
+            self.do_look_ahead(next)?;
+        }
+        Ok(())
+    }
+
+    // get all pubkey scripts of this account
+    pub fn get_scripts<'a>(&'a self) -> impl Iterator<Item=(u32, ScriptBuf)> + 'a {
+        self.instantiated.iter().map(|k| (k.index, k.script_pubkey.clone()))
+    }
+
+    /// find the key that controls a script
+    pub fn get_key_for_script(&self, script_pubkey: &Script) -> Option<&MultisigKey> {
+        self.instantiated.iter().find(|k| k.script_pubkey.as_script() == script_pubkey)
+    }
+
+    /// fill in witness scripts and derivation paths of all cosigners for inputs and outputs of this account
+    pub fn update(&self, psbt: &mut Psbt) -> Result<(), WalletError> {
+        for ix in 0..psbt.inputs.len() {
+            let spent = match psbt.spend_utxo(ix) {
+                Ok(spent) => spent.clone(),
+                Err(_) => continue
+            };
+            if let Some(key) = self.get_key_for_script(&spent.script_pubkey) {
+                let input = &mut psbt.inputs[ix];
+                input.witness_script = Some(key.witness_script.clone());
+                if input.witness_utxo.is_none() {
+                    input.witness_utxo = Some(spent);
+                }
+                for (public, source) in self.cosigner_keys(key.index)? {
+                    input.bip32_derivation.insert(public.inner, source);
+                }
+            }
+        }
+        for (ix, txout) in psbt.unsigned_tx.output.iter().enumerate() {
+            if let Some(key) = self.get_key_for_script(&txout.script_pubkey) {
+                let output = &mut psbt.outputs[ix];
+                output.witness_script = Some(key.witness_script.clone());
+                for (public, source) in self.cosigner_keys(key.index)? {
+                    output.bip32_derivation.insert(public.inner, source);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// add partial signatures of the unlocked master for all inputs of this account
+    /// returns the number of signatures added
+    pub fn sign(&self, psbt: &mut Psbt, unlocker: &mut Unlocker) -> Result<usize, WalletError> {
+        let fingerprint = unlocker.fingerprint();
+        let mut own = Vec::new();
+        for cosigner in self.cosigners.iter().filter(|c| c.fingerprint == fingerprint) {
+            if self.context.extended_public_from_private(&unlocker.derive(&cosigner.path)?) == cosigner.account_public {
+                own.push(cosigner);
+            }
+        }
+        if own.is_empty() {
+            return Err(WalletError::Unsupported("not a cosigner of this account"));
+        }
+        let transaction = psbt.unsigned_tx.clone();
+        let mut hasher = SighashCache::new(&transaction);
+        let mut signatures = Vec::new();
+        for ix in 0..psbt.inputs.len() {
+            let key = match psbt.spend_utxo(ix) {
+                Ok(spent) => match self.get_key_for_script(&spent.script_pubkey) {
+                    Some(key) => key,
+                    None => continue
+                },
+                Err(_) => continue
+            };
+            if psbt.inputs[ix].witness_script.is_none() {
+                psbt.inputs[ix].witness_script = Some(key.witness_script.clone());
+            }
+            let (message, sighash_type) = psbt.sighash_ecdsa(ix, &mut hasher)?;
+            for cosigner in &own {
+                let private = unlocker.derive(&self.key_path(cosigner, key.index))?.to_priv();
+                let public = self.context.public_from_private(&private);
+                let signature = unlocker.context().sign(message.as_ref(), &private)?;
+                signatures.push((ix, public, ecdsa::Signature { signature, sighash_type }));
+            }
+        }
+        let n_signatures = signatures.len();
+        for (ix, public, signature) in signatures {
+            psbt.inputs[ix].partial_sigs.insert(public, signature);
+        }
+        Ok(n_signatures)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use bitcoin::{Amount, OutPoint, Sequence, Transaction, TxIn, TxOut, Txid, Witness};
+    use bitcoin::absolute::LockTime;
+    use bitcoin::transaction::Version;
+    use bitcoin_hashes::Hash;
+
+    use account::{MasterAccount, MasterKeyEntropy};
+    use psbt;
+    use super::*;
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+
+    #[test]
+    fn test_sorted_multisig() {
+        // test vector of BIP67
+        let keys = [
+            PublicKey::from_str("02ff12471208c14bd580709cb2358d98975247d8765f92bc25eab3b2763ed605f8").unwrap(),
+            PublicKey::from_str("02fe6f0a5a297eb38c391581c4413e084773ea23954d93f7753db7dc0adc188b2f").unwrap()];
+        assert_eq!(sorted_multisig(2, &keys).unwrap().as_bytes(), &hex::decode(
+            "522102fe6f0a5a297eb38c391581c4413e084773ea23954d93f7753db7dc0adc188b2f2102ff12471208c14bd580709cb2358d98975247d8765f92bc25eab3b2763ed605f852ae").unwrap()[..]);
+        assert!(sorted_multisig(3, &keys).is_err());
+        assert!(sorted_multisig(0, &keys).is_err());
+    }
+
+    #[test]
+    fn test_multisig() {
+        let mut unlockers = (0..3).map(|_| {
+            let master = MasterAccount::new(MasterKeyEntropy::Low, Network::Bitcoin, PASSPHRASE, None).unwrap();
+            Unlocker::new(master.encrypted(), PASSPHRASE, None, Network::Bitcoin, None).unwrap()
+        }).collect::<Vec<_>>();
+        let cosigners = unlockers.iter_mut().map(|u| Cosigner::new(u, Network::Bitcoin, 0).unwrap()).collect::<Vec<_>>();
+        assert_eq!(cosigners[0].path.to_string(), "48'/0'/0'/2'");
+
+        let account = MultisigAccount::new(2, cosigners.clone(), 0, 10, Network::Bitcoin).unwrap();
+        let mut reversed = cosigners.clone();
+        reversed.reverse();
+        // every cosigner arrives at the same addresses
+        let other = MultisigAccount::new(2, reversed, 0, 10, Network::Bitcoin).unwrap();
+        assert_eq!(account.address(3), other.address(3));
+        assert!(account.address(3).unwrap().to_string().starts_with("bc1q"));
+        assert!(MultisigAccount::new(4, cosigners.clone(), 0, 10, Network::Bitcoin).is_err());
+        assert!(MultisigAccount::new(2, cosigners.clone(), 0, 10, Network::Testnet).is_err());
+        assert!(MultisigAccount::new(1, vec!(cosigners[0].clone(), cosigners[0].clone()), 0, 10, Network::Bitcoin).is_err());
+
+        let key = account.get_key(0).unwrap().clone();
+        let spent = TxOut { value: Amount::from_sat(100000), script_pubkey: key.script_pubkey.clone() };
+        let transaction = Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec!(TxIn { previous_output: OutPoint { txid: Txid::all_zeros(), vout: 0 }, script_sig: ScriptBuf::new(), sequence: Sequence::MAX, witness: Witness::new() }),
+            output: vec!(TxOut { value: Amount::from_sat(99000), script_pubkey: ScriptBuf::new_op_return([0u8; 4]) })
+        };
+        let mut psbt = Psbt::from_unsigned_tx(transaction).unwrap();
+        psbt.inputs[0].witness_utxo = Some(spent.clone());
+        account.update(&mut psbt).unwrap();
+        assert_eq!(psbt.inputs[0].witness_script, Some(key.witness_script.clone()));
+        assert_eq!(psbt.inputs[0].bip32_derivation.values().map(|(_, p)| p.to_string()).collect::<Vec<_>>(), vec!("48'/0'/0'/2'/0/0"; 3));
+
+        // two cosigners sign independently
+        let mut second = psbt.clone();
+        assert_eq!(account.sign(&mut psbt, &mut unlockers[0]).unwrap(), 1);
+        assert_eq!(other.sign(&mut second, &mut unlockers[2]).unwrap(), 1);
+        psbt.combine(second).unwrap();
+        assert_eq!(psbt.inputs[0].partial_sigs.len(), 2);
+
+        let mut signed = psbt.unsigned_tx.clone();
+        let mut sigs = psbt.inputs[0].partial_sigs.iter().collect::<Vec<_>>();
+        sigs.sort_by_key(|(k, _)| key.keys.iter().position(|o| o == *k));
+        let mut witness = Witness::new();
+        witness.push([0u8; 0]);
+        for (_, sig) in sigs {
+            witness.push(sig.serialize());
+        }
+        witness.push(key.witness_script.as_bytes());
+        signed.input[0].witness = witness;
+        signed.verify(|_| Some(spent.clone())).unwrap();
+        let mut finalized = psbt.clone();
+        psbt::finalize(&mut finalized).unwrap();
+        assert_eq!(psbt::extract(&finalized).unwrap(), signed);
+
+        let outsider = MasterAccount::new(MasterKeyEntropy::Low, Network::Bitcoin, PASSPHRASE, None).unwrap();
+        let mut outsider = Unlocker::new(outsider.encrypted(), PASSPHRASE, None, Network::Bitcoin, None).unwrap();
+        assert!(account.sign(&mut psbt, &mut outsider).is_err());
+    }
+}
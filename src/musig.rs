@@ -0,0 +1,430 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # MuSig2
+//!
+//! BIP327 multi-signatures, several signers jointly control a single taproot key
+//!
+
+use bitcoin::{TapNodeHash, TapTweakHash};
+use bitcoin_hashes::{sha256, Hash, HashEngine};
+use secp256k1::{Parity, PublicKey, Scalar, Secp256k1, SecretKey, XOnlyPublicKey, schnorr};
+use secp256k1::rand::{thread_rng, RngCore};
+
+use error::WalletError;
+
+/// order of the secp256k1 group
+const ORDER: [u8; 32] = [
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe,
+    0xba, 0xae, 0xdc, 0xe6, 0xaf, 0x48, 0xa0, 0x3b, 0xbf, 0xd2, 0x5e, 0x8c, 0xd0, 0x36, 0x41, 0x41];
+
+/// sort keys of the signers to aggregate them independent of their order
+pub fn key_sort(keys: &mut [PublicKey]) {
+    keys.sort_by_key(|k| k.serialize());
+}
+
+/// the aggregate key of the signers and tweaks applied to it
+#[derive(Clone)]
+pub struct KeyAggContext {
+    keys: Vec<PublicKey>,
+    list_hash: [u8; 32],
+    second: Option<PublicKey>,
+    aggregate: PublicKey,
+    // the accumulated sign of tweaks is negative
+    negated: bool,
+    // the accumulated tweak, None if zero
+    tweak: Option<SecretKey>
+}
+
+impl KeyAggContext {
+    /// aggregate keys of the signers in the order given, see key_sort
+    pub fn new(keys: &[PublicKey]) -> Result<KeyAggContext, WalletError> {
+        if keys.is_empty() {
+            return Err(WalletError::MuSig("no keys to aggregate"));
+        }
+        let secp = Secp256k1::verification_only();
+        let serialized = keys.iter().flat_map(|k| k.serialize().to_vec()).collect::<Vec<_>>();
+        let list_hash = tagged_hash("KeyAgg list", &[&serialized]);
+        let second = keys.iter().find(|k| **k != keys[0]).cloned();
+        let mut context = KeyAggContext { keys: keys.to_vec(), list_hash, second, aggregate: keys[0], negated: false, tweak: None };
+        let terms = keys.iter().map(|k| Ok(k.mul_tweak(&secp, &context.coefficient(k)?)?)).collect::<Result<Vec<_>, WalletError>>()?;
+        context.aggregate = PublicKey::combine_keys(&terms.iter().collect::<Vec<_>>())?;
+        Ok(context)
+    }
+
+    /// keys of the signers
+    pub fn keys(&self) -> &Vec<PublicKey> {
+        &self.keys
+    }
+
+    /// the aggregate key, tweaks included
+    pub fn aggregate_key(&self) -> XOnlyPublicKey {
+        self.aggregate.x_only_public_key().0
+    }
+
+    /// add a tweak to the aggregate key, x-only tweaks as used by taproot apply to the even key
+    pub fn apply_tweak(&mut self, tweak: &[u8; 32], x_only: bool) -> Result<(), WalletError> {
+        let secp = Secp256k1::verification_only();
+        let tweak = Scalar::from_be_bytes(*tweak).map_err(|_| WalletError::MuSig("tweak out of range"))?;
+        let negate = x_only && !has_even_y(&self.aggregate);
+        let aggregate = if negate { self.aggregate.negate(&secp) } else { self.aggregate };
+        self.aggregate = aggregate.add_exp_tweak(&secp, &tweak).map_err(|_| WalletError::MuSig("tweaked key is infinite"))?;
+        self.negated ^= negate;
+        // tweak = t + g * tweak
+        let accumulated = match self.tweak {
+            Some(current) => {
+                let current = if negate { current.negate() } else { current };
+                current.add_tweak(&tweak).ok()
+            }
+            None => SecretKey::from_slice(&tweak.to_be_bytes()).ok()
+        };
+        self.tweak = accumulated;
+        Ok(())
+    }
+
+    /// apply the BIP341 tweak with the merkle root of a script tree, or none for a key path only output
+    /// the aggregate key is then the output key of a P2TR output
+    pub fn tap_tweak(&mut self, merkle_root: Option<TapNodeHash>) -> Result<(), WalletError> {
+        let tweak = TapTweakHash::from_key_and_tweak(self.aggregate_key(), merkle_root).to_byte_array();
+        self.apply_tweak(&tweak, true)
+    }
+
+    fn coefficient(&self, key: &PublicKey) -> Result<Scalar, WalletError> {
+        if Some(*key) == self.second {
+            return Ok(Scalar::ONE);
+        }
+        reduce(tagged_hash("KeyAgg coefficient", &[&self.list_hash, &key.serialize()]))
+    }
+}
+
+/// secret nonce of a signer, consumed by signing so it can not be reused
+pub struct SecretNonce {
+    k1: SecretKey,
+    k2: SecretKey,
+    public: PublicKey
+}
+
+impl Drop for SecretNonce {
+    fn drop(&mut self) {
+        self.k1.non_secure_erase();
+        self.k2.non_secure_erase();
+    }
+}
+
+/// public nonce of a signer, shared with the other signers
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PublicNonce {
+    r1: PublicKey,
+    r2: PublicKey
+}
+
+impl PublicNonce {
+    /// the 66 bytes of serialized form
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut bytes = self.r1.serialize().to_vec();
+        bytes.extend_from_slice(&self.r2.serialize());
+        bytes
+    }
+
+    pub fn from_slice(bytes: &[u8]) -> Result<PublicNonce, WalletError> {
+        if bytes.len() != 66 {
+            return Err(WalletError::MuSig("public nonce must be 66 bytes"));
+        }
+        Ok(PublicNonce { r1: PublicKey::from_slice(&bytes[..33])?, r2: PublicKey::from_slice(&bytes[33..])? })
+    }
+}
+
+/// sum of the public nonces of all signers
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct AggregateNonce {
+    r1: Option<PublicKey>,
+    r2: Option<PublicKey>
+}
+
+impl AggregateNonce {
+    /// the 66 bytes of serialized form, an infinite point is serialized as zeros
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(66);
+        for r in &[self.r1, self.r2] {
+            match r {
+                Some(r) => bytes.extend_from_slice(&r.serialize()),
+                None => bytes.extend_from_slice(&[0u8; 33])
+            }
+        }
+        bytes
+    }
+}
+
+/// a signer's 32 byte share of the signature
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PartialSignature(pub [u8; 32]);
+
+/// create a nonce pair for a signing session, the optional arguments strengthen it against a bad random source
+pub fn nonce_gen(secret: Option<&SecretKey>, public: &PublicKey, aggregate_key: Option<&XOnlyPublicKey>, msg: Option<&[u8]>, extra: Option<&[u8]>) -> Result<(SecretNonce, PublicNonce), WalletError> {
+    let mut random = [0u8; 32];
+    thread_rng().fill_bytes(&mut random);
+    let result = nonce_gen_with(random, secret, public, aggregate_key, msg, extra);
+    random.iter_mut().for_each(|b| *b = 0);
+    result
+}
+
+fn nonce_gen_with(mut random: [u8; 32], secret: Option<&SecretKey>, public: &PublicKey, aggregate_key: Option<&XOnlyPublicKey>, msg: Option<&[u8]>, extra: Option<&[u8]>) -> Result<(SecretNonce, PublicNonce), WalletError> {
+    if let Some(secret) = secret {
+        let aux = tagged_hash("MuSig/aux", &[&random]);
+        for (r, (s, a)) in random.iter_mut().zip(secret.secret_bytes().iter().zip(aux.iter())) {
+            *r = s ^ a;
+        }
+    }
+    let public_bytes = public.serialize();
+    let aggregate_bytes = aggregate_key.map(|k| k.serialize().to_vec()).unwrap_or_default();
+    let mut msg_prefixed = Vec::new();
+    match msg {
+        Some(msg) => {
+            msg_prefixed.push(1u8);
+            msg_prefixed.extend_from_slice(&(msg.len() as u64).to_be_bytes());
+            msg_prefixed.extend_from_slice(msg);
+        }
+        None => msg_prefixed.push(0u8)
+    }
+    let extra = extra.unwrap_or(&[]);
+    let mut k = Vec::new();
+    for i in 0..2u8 {
+        let hash = tagged_hash("MuSig/nonce", &[
+            &random, &[public_bytes.len() as u8], &public_bytes, &[aggregate_bytes.len() as u8], &aggregate_bytes,
+            &msg_prefixed, &(extra.len() as u32).to_be_bytes(), extra, &[i]]);
+        k.push(SecretKey::from_slice(&reduce(hash)?.to_be_bytes()).map_err(|_| WalletError::MuSig("zero nonce"))?);
+    }
+    random.iter_mut().for_each(|b| *b = 0);
+    let secp = Secp256k1::signing_only();
+    let public_nonce = PublicNonce { r1: PublicKey::from_secret_key(&secp, &k[0]), r2: PublicKey::from_secret_key(&secp, &k[1]) };
+    Ok((SecretNonce { k1: k[0], k2: k[1], public: *public }, public_nonce))
+}
+
+/// aggregate the public nonces of all signers
+pub fn nonce_agg(nonces: &[PublicNonce]) -> AggregateNonce {
+    let r1 = nonces.iter().map(|n| n.r1).collect::<Vec<_>>();
+    let r2 = nonces.iter().map(|n| n.r2).collect::<Vec<_>>();
+    AggregateNonce {
+        r1: PublicKey::combine_keys(&r1.iter().collect::<Vec<_>>()).ok(),
+        r2: PublicKey::combine_keys(&r2.iter().collect::<Vec<_>>()).ok()
+    }
+}
+
+/// a signing session of a message with an aggregate nonce
+pub struct Session {
+    key_agg: KeyAggContext,
+    b: Scalar,
+    r: PublicKey,
+    e: Scalar
+}
+
+impl Session {
+    pub fn new(key_agg: &KeyAggContext, aggregate_nonce: &AggregateNonce, msg: &[u8]) -> Result<Session, WalletError> {
+        let secp = Secp256k1::verification_only();
+        let q = key_agg.aggregate_key().serialize();
+        let b = reduce(tagged_hash("MuSig/noncecoef", &[&aggregate_nonce.serialize(), &q, msg]))?;
+        let r2 = match aggregate_nonce.r2 {
+            Some(r2) => r2.mul_tweak(&secp, &b).ok(),
+            None => None
+        };
+        let r = match (aggregate_nonce.r1, r2) {
+            (Some(r1), Some(r2)) => r1.combine(&r2).ok(),
+            (r1, None) => r1,
+            (None, r2) => r2
+        };
+        // an infinite nonce is replaced by the generator
+        let r = match r {
+            Some(r) => r,
+            None => PublicKey::from_secret_key(&Secp256k1::signing_only(), &SecretKey::from_slice(&Scalar::ONE.to_be_bytes())?)
+        };
+        let e = reduce(tagged_hash("BIP0340/challenge", &[&r.x_only_public_key().0.serialize(), &q, msg]))?;
+        Ok(Session { key_agg: key_agg.clone(), b, r, e })
+    }
+
+    /// the partial signature of a signer with its secret key, the secret nonce is consumed
+    pub fn sign(&self, nonce: SecretNonce, secret: &SecretKey) -> Result<PartialSignature, WalletError> {
+        let secp = Secp256k1::new();
+        let public = PublicKey::from_secret_key(&secp, secret);
+        if public != nonce.public {
+            return Err(WalletError::MuSig("nonce was generated for an other key"));
+        }
+        let a = self.key_agg.coefficient(&public)?;
+        if !self.key_agg.keys.contains(&public) {
+            return Err(WalletError::MuSig("not a signer of the aggregate key"));
+        }
+        let (k1, k2) = if has_even_y(&self.r) { (nonce.k1, nonce.k2) } else { (nonce.k1.negate(), nonce.k2.negate()) };
+        let d = if has_even_y(&self.key_agg.aggregate) ^ self.key_agg.negated { *secret } else { secret.negate() };
+        // s = k1 + b * k2 + e * a * d
+        let s = k1.add_tweak(&Scalar::from(k2.mul_tweak(&self.b)?))?
+            .add_tweak(&Scalar::from(d.mul_tweak(&self.e)?.mul_tweak(&a)?))?;
+        let partial = PartialSignature(s.secret_bytes());
+        let public_nonce = PublicNonce { r1: PublicKey::from_secret_key(&secp, &nonce.k1), r2: PublicKey::from_secret_key(&secp, &nonce.k2) };
+        if !self.verify(&partial, &public_nonce, &public) {
+            return Err(WalletError::MuSig("partial signature does not verify"));
+        }
+        Ok(partial)
+    }
+
+    /// check the partial signature of a signer before aggregating it
+    pub fn verify(&self, partial: &PartialSignature, nonce: &PublicNonce, public: &PublicKey) -> bool {
+        let secp = Secp256k1::new();
+        let s = match SecretKey::from_slice(&partial.0) {
+            Ok(s) => s,
+            Err(_) => return false
+        };
+        let a = match self.key_agg.coefficient(public) {
+            Ok(a) if self.key_agg.keys.contains(public) => a,
+            _ => return false
+        };
+        // R = R1 + b * R2, negated if the session nonce is odd
+        let r = match nonce.r2.mul_tweak(&secp, &self.b).and_then(|r2| nonce.r1.combine(&r2)) {
+            Ok(r) => if has_even_y(&self.r) { r } else { r.negate(&secp) },
+            Err(_) => return false
+        };
+        // e * a * g' * P
+        let p = match public.mul_tweak(&secp, &self.e).and_then(|p| p.mul_tweak(&secp, &a)) {
+            Ok(p) => if has_even_y(&self.key_agg.aggregate) ^ self.key_agg.negated { p } else { p.negate(&secp) },
+            Err(_) => return false
+        };
+        match r.combine(&p) {
+            Ok(expected) => PublicKey::from_secret_key(&secp, &s) == expected,
+            Err(_) => false
+        }
+    }
+
+    /// the BIP340 signature of the aggregate key from partial signatures of all signers
+    pub fn aggregate(&self, partials: &[PartialSignature]) -> Result<schnorr::Signature, WalletError> {
+        let mut s: Option<SecretKey> = None;
+        let mut terms = partials.iter().map(|p| p.0).collect::<Vec<_>>();
+        // s += e * g * tweak
+        if let Some(tweak) = self.key_agg.tweak {
+            let t = tweak.mul_tweak(&self.e)?;
+            let t = if has_even_y(&self.key_agg.aggregate) { t } else { t.negate() };
+            terms.push(t.secret_bytes());
+        }
+        for term in terms {
+            let term = Scalar::from_be_bytes(term).map_err(|_| WalletError::MuSig("partial signature out of range"))?;
+            s = match s {
+                Some(s) => s.add_tweak(&term).ok(),
+                None => SecretKey::from_slice(&term.to_be_bytes()).ok()
+            };
+        }
+        let s = s.map(|s| s.secret_bytes()).unwrap_or([0u8; 32]);
+        let mut signature = self.r.x_only_public_key().0.serialize().to_vec();
+        signature.extend_from_slice(&s);
+        Ok(schnorr::Signature::from_slice(&signature)?)
+    }
+}
+
+fn has_even_y(point: &PublicKey) -> bool {
+    point.x_only_public_key().1 == Parity::Even
+}
+
+fn tagged_hash(tag: &str, data: &[&[u8]]) -> [u8; 32] {
+    let tag = sha256::Hash::hash(tag.as_bytes());
+    let mut engine = sha256::Hash::engine();
+    engine.input(tag.as_ref());
+    engine.input(tag.as_ref());
+    for d in data {
+        engine.input(d);
+    }
+    sha256::Hash::from_engine(engine).to_byte_array()
+}
+
+/// a hash as scalar modulo the group order
+fn reduce(mut hash: [u8; 32]) -> Result<Scalar, WalletError> {
+    if hash >= ORDER {
+        let mut borrow = 0i16;
+        for i in (0..32).rev() {
+            let d = hash[i] as i16 - ORDER[i] as i16 - borrow;
+            borrow = if d < 0 { 1 } else { 0 };
+            hash[i] = (d + 256 * borrow) as u8;
+        }
+    }
+    Ok(Scalar::from_be_bytes(hash).expect("reduced below the order"))
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use bitcoin::{Amount, OutPoint, ScriptBuf, Sequence, TapSighashType, Transaction, TxIn, TxOut, Txid, Witness};
+    use bitcoin::absolute::LockTime;
+    use bitcoin::key::TweakedPublicKey;
+    use bitcoin::sighash::{Prevouts, SighashCache};
+    use bitcoin::transaction::Version;
+
+    use super::*;
+
+    #[test]
+    fn test_key_agg() {
+        // test vectors of BIP327
+        let keys = [
+            "02F9308A019258C31049344F85F89D5229B531C845836F99B08601F113BCE036F9",
+            "03DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA659",
+            "023590A94E768F8E1815C2F24B4D80A8E3149316C3518CE7B7AD338368D038CA66"
+        ].iter().map(|k| PublicKey::from_str(k).unwrap()).collect::<Vec<_>>();
+        for (indices, expected) in &[
+            (vec!(0, 1, 2), "90539EEDE565F5D054F32CC0C220126889ED1E5D193BAF15AEF344FE59D4610C"),
+            (vec!(2, 1, 0), "6204DE8B083426DC6EAF9502D27024D53FC826BF7D2012148A0575435DF54B2B"),
+            (vec!(0, 0, 0), "B436E3BAD62B8CD409969A224731C193D051162D8C5AE8B109306127DA3AA935"),
+            (vec!(0, 0, 1, 1), "69BC22BFA5D106306E48A20679DE1D7389386124D07571D0D872686028C26A3E")] {
+            let selected = indices.iter().map(|i| keys[*i]).collect::<Vec<_>>();
+            let context = KeyAggContext::new(&selected).unwrap();
+            assert_eq!(context.aggregate_key(), XOnlyPublicKey::from_str(expected).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_taproot_spend() {
+        let secp = Secp256k1::new();
+        let secrets = (0..3u8).map(|i| SecretKey::from_slice(&[i + 1; 32]).unwrap()).collect::<Vec<_>>();
+        let mut keys = secrets.iter().map(|s| PublicKey::from_secret_key(&secp, s)).collect::<Vec<_>>();
+        key_sort(&mut keys);
+        let mut key_agg = KeyAggContext::new(&keys).unwrap();
+        key_agg.tap_tweak(None).unwrap();
+        let output_key = TweakedPublicKey::dangerous_assume_tweaked(key_agg.aggregate_key());
+
+        let spent = TxOut { value: Amount::from_sat(100000), script_pubkey: ScriptBuf::new_p2tr_tweaked(output_key) };
+        let mut spending = Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec!(TxIn { previous_output: OutPoint { txid: Txid::all_zeros(), vout: 0 }, script_sig: ScriptBuf::new(), sequence: Sequence::MAX, witness: Witness::new() }),
+            output: vec!(TxOut { value: Amount::from_sat(99000), script_pubkey: ScriptBuf::new_op_return([0u8; 4]) })
+        };
+        let msg = SighashCache::new(&spending).taproot_key_spend_signature_hash(0, &Prevouts::All(std::slice::from_ref(&spent)), TapSighashType::Default)
+            .unwrap().to_byte_array();
+
+        let (secret_nonces, public_nonces): (Vec<_>, Vec<_>) = secrets.iter().map(|s|
+            nonce_gen(Some(s), &PublicKey::from_secret_key(&secp, s), Some(&key_agg.aggregate_key()), Some(&msg), None).unwrap()).unzip();
+        let aggregate_nonce = nonce_agg(&public_nonces);
+        let session = Session::new(&key_agg, &aggregate_nonce, &msg).unwrap();
+        let partials = secret_nonces.into_iter().zip(secrets.iter())
+            .map(|(n, s)| session.sign(n, s).unwrap()).collect::<Vec<_>>();
+        for ((partial, nonce), secret) in partials.iter().zip(public_nonces.iter()).zip(secrets.iter()) {
+            assert!(session.verify(partial, nonce, &PublicKey::from_secret_key(&secp, secret)));
+        }
+        assert!(!session.verify(&partials[0], &public_nonces[1], &PublicKey::from_secret_key(&secp, &secrets[0])));
+
+        let signature = session.aggregate(&partials).unwrap();
+        secp.verify_schnorr(&signature, &secp256k1::Message::from_digest(msg), &key_agg.aggregate_key()).unwrap();
+        spending.input[0].witness.push(signature.as_ref());
+        spending.verify(|_| Some(spent.clone())).unwrap();
+
+        let (nonce, _) = nonce_gen(None, &keys[0], None, None, None).unwrap();
+        assert!(session.sign(nonce, &SecretKey::from_slice(&[9u8; 32]).unwrap()).is_err());
+    }
+}
@@ -0,0 +1,191 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Custom networks
+//!
+//! Parameters of forks and test networks registered at runtime
+//!
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::str::FromStr;
+use std::sync::{OnceLock, RwLock};
+
+use bitcoin::{base58, Network, PubkeyHash, Script, ScriptBuf, ScriptHash, WitnessProgram, WitnessVersion};
+use bitcoin::bech32::{segwit, Hrp};
+use bitcoin::bip32::Xpub;
+use bitcoin::p2p::Magic;
+use bitcoin_hashes::Hash;
+
+use account::{coin_type, Unlocker};
+use error::WalletError;
+
+/// parameters that tell a network apart
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct NetworkParams {
+    /// name of the network in the registry
+    pub name: String,
+    /// consensus rules and extended key versions follow this network
+    pub base: Network,
+    /// message start bytes of p2p messages
+    pub magic: [u8; 4],
+    /// human readable part of segwit addresses
+    pub hrp: String,
+    /// base58 version byte of P2PKH addresses
+    pub p2pkh_prefix: u8,
+    /// base58 version byte of P2SH addresses
+    pub p2sh_prefix: u8,
+    /// BIP44 coin type of account key paths
+    pub coin_type: u32
+}
+
+impl NetworkParams {
+    /// parameters of a network known to the bitcoin crate
+    pub fn of(network: Network) -> NetworkParams {
+        let (hrp, p2pkh_prefix, p2sh_prefix) = match network {
+            Network::Bitcoin => ("bc", 0x00, 0x05),
+            Network::Regtest => ("bcrt", 0x6f, 0xc4),
+            _ => ("tb", 0x6f, 0xc4)
+        };
+        NetworkParams {
+            name: network.to_string(), base: network, magic: Magic::from(network).to_bytes(),
+            hrp: hrp.to_string(), p2pkh_prefix, p2sh_prefix, coin_type: coin_type(network)
+        }
+    }
+
+    /// message start of p2p connections, see `p2p::Peer::connect_with_magic`
+    pub fn magic(&self) -> Magic {
+        Magic::from_bytes(self.magic)
+    }
+
+    /// decrypt the seed of a master account to derive keys on the coin type of this network
+    pub fn unlocker(&self, encrypted: &[u8], passphrase: &str, pd_passphrase: Option<&str>, master_public: Option<&Xpub>) -> Result<Unlocker, WalletError> {
+        Ok(Unlocker::new(encrypted, passphrase, pd_passphrase, self.base, master_public)?.with_coin_type(self.coin_type))
+    }
+
+    /// address of an output script on this network
+    pub fn address(&self, script: &Script) -> Result<String, WalletError> {
+        let bytes = script.as_bytes();
+        if script.is_p2pkh() {
+            Ok(base58::encode_check(&[&[self.p2pkh_prefix], &bytes[3..23]].concat()))
+        } else if script.is_p2sh() {
+            Ok(base58::encode_check(&[&[self.p2sh_prefix], &bytes[2..22]].concat()))
+        } else if let Some(version) = script.witness_version() {
+            segwit::encode(self.hrp()?, version.to_fe(), &bytes[2..]).map_err(|_| WalletError::Unsupported("invalid witness program"))
+        } else {
+            Err(WalletError::Unsupported("script has no address"))
+        }
+    }
+
+    /// output script of an address, fails with WalletError::Network if it is of an other network
+    pub fn script_pubkey(&self, address: &str) -> Result<ScriptBuf, WalletError> {
+        if let Ok((hrp, version, program)) = segwit::decode(address) {
+            if hrp != self.hrp()? {
+                return Err(WalletError::Network);
+            }
+            let version = WitnessVersion::try_from(version).map_err(|e| WalletError::InvalidAddress(e.to_string()))?;
+            let program = WitnessProgram::new(version, &program).map_err(|e| WalletError::InvalidAddress(e.to_string()))?;
+            return Ok(ScriptBuf::new_witness_program(&program));
+        }
+        let data = base58::decode_check(address).map_err(|e| WalletError::InvalidAddress(e.to_string()))?;
+        if data.len() != 21 {
+            return Err(WalletError::InvalidAddress(format!("{} bytes", data.len())));
+        }
+        if data[0] == self.p2pkh_prefix {
+            Ok(ScriptBuf::new_p2pkh(&PubkeyHash::from_slice(&data[1..]).expect("20 bytes")))
+        } else if data[0] == self.p2sh_prefix {
+            Ok(ScriptBuf::new_p2sh(&ScriptHash::from_slice(&data[1..]).expect("20 bytes")))
+        } else {
+            Err(WalletError::Network)
+        }
+    }
+
+    fn hrp(&self) -> Result<Hrp, WalletError> {
+        Hrp::parse(&self.hrp).map_err(|_| WalletError::Unsupported("invalid human readable part"))
+    }
+}
+
+fn registry() -> &'static RwLock<HashMap<String, NetworkParams>> {
+    static REGISTRY: OnceLock<RwLock<HashMap<String, NetworkParams>>> = OnceLock::new();
+    REGISTRY.get_or_init(|| RwLock::new(HashMap::new()))
+}
+
+/// register a custom network, it replaces an earlier one of the same name
+/// networks known to the bitcoin crate can not be replaced
+pub fn register(params: NetworkParams) -> Result<(), WalletError> {
+    if Network::from_str(&params.name).is_ok() {
+        return Err(WalletError::Unsupported("can not replace a built in network"));
+    }
+    params.hrp()?;
+    registry().write().expect("network registry poisoned").insert(params.name.clone(), params);
+    Ok(())
+}
+
+/// parameters of a built in or registered network
+pub fn lookup(name: &str) -> Option<NetworkParams> {
+    if let Ok(network) = Network::from_str(name) {
+        return Some(NetworkParams::of(network));
+    }
+    registry().read().expect("network registry poisoned").get(name).cloned()
+}
+
+/// all registered custom networks
+pub fn registered() -> Vec<NetworkParams> {
+    registry().read().expect("network registry poisoned").values().cloned().collect()
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::Address;
+
+    use account::{AccountAddressType, MasterAccount, MasterKeyEntropy};
+    use super::*;
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+
+    #[test]
+    fn test_custom_network() {
+        let fork = NetworkParams {
+            name: "fork".to_string(), base: Network::Bitcoin, magic: [0xf9, 0xbe, 0xb4, 0xd8],
+            hrp: "fk".to_string(), p2pkh_prefix: 0x23, p2sh_prefix: 0x24, coin_type: 1234
+        };
+        register(fork.clone()).unwrap();
+        assert_eq!(lookup("fork"), Some(fork.clone()));
+        assert_eq!(lookup("regtest").unwrap().hrp, "bcrt");
+        assert!(register(NetworkParams { name: "bitcoin".to_string(), ..fork.clone() }).is_err());
+        assert!(register(NetworkParams { name: "bad".to_string(), hrp: "not a hrp".to_string(), ..fork.clone() }).is_err());
+
+        let mut master = MasterAccount::new(MasterKeyEntropy::Low, fork.base, PASSPHRASE, None).unwrap();
+        let mut unlocker = fork.unlocker(master.encrypted(), PASSPHRASE, None, Some(master.master_public())).unwrap();
+        let account = master.new_account(&mut unlocker, AccountAddressType::P2WPKH, 0, 0, 10).unwrap();
+        assert_eq!(account.key_path(0).to_string(), "84'/1234'/0'/0/0");
+        let script = account.next_key().unwrap().script_pubkey.clone();
+        let address = fork.address(&script).unwrap();
+        assert!(address.starts_with("fk1q"));
+        assert_eq!(fork.script_pubkey(&address).unwrap(), script);
+
+        let bitcoin = NetworkParams::of(Network::Bitcoin);
+        for address in ["1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2", "3J98t1WpEZ73CNmQviecrnyiWrnqRhWNLy", "bc1p5d7rjq7g6rdk2yhzks9smlaqtedr4dekq08ge8ztwac72sfr9rusxg3297"].iter() {
+            let script = Address::from_str(address).unwrap().assume_checked().script_pubkey();
+            assert_eq!(bitcoin.script_pubkey(address).unwrap(), script);
+            assert_eq!(bitcoin.address(&script).unwrap(), *address);
+            match fork.script_pubkey(address) {
+                Err(WalletError::Network) => {},
+                _ => panic!("address of an other network")
+            }
+        }
+    }
+}
@@ -0,0 +1,821 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Async
+//!
+//! Electrum sync and broadcast on tokio sockets and a wallet file store saved with tokio, for async servers
+//!
+//! The crate is edition 2015, so its futures are written out as state machines instead of async fns.
+//! They await in async code of any edition within a tokio runtime, e.g.
+//! `let mut client = nonblocking::Client::connect("electrum.example.com:50001").await?;`
+//! `let history = client.sync(&mut master, &mut coins).await?;`
+//! No thread is spawned: the requests of a sync are sent in batches and their responses read as the socket
+//! becomes readable. Hashing and the encryption of the store run in the polling task. tokio reads and writes files
+//! on its own blocking pool, as operating systems do not signal readiness of files.
+//! The module is not built for wasm32 and with the offline feature, which leaves out the Electrum client.
+//!
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::TryFrom;
+use std::future::Future;
+use std::io;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::str;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use bitcoin::{consensus, OutPoint, Script, ScriptBuf, Transaction, Txid};
+use rustls::{ClientConfig, RootCertStore};
+use rustls::pki_types::ServerName;
+use serde_json::{json, Value};
+use tokio::fs;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+use tokio_rustls::client::TlsStream;
+
+use account::{KeyDerivation, MasterAccount};
+use coins::{Coin, Coins};
+use electrum::{self, script_hash, PROTOCOL_VERSION};
+use error::WalletError;
+use keystore::Kdf;
+use offline;
+use proved::ProvedTransaction;
+use secret::SecretBytes;
+use store::{self, HistoryEntry, WalletStore};
+
+type Pending<T> = Pin<Box<dyn Future<Output = io::Result<T>> + Send>>;
+
+/// a client talking the Electrum protocol over a tokio stream
+/// requests are sent ahead of reading responses, those of requests of other futures are kept until asked for
+pub struct Client<S> {
+    stream: S,
+    next_id: u64,
+    outgoing: Vec<u8>,
+    unflushed: bool,
+    incoming: Vec<u8>,
+    responses: HashMap<u64, Result<Value, WalletError>>,
+    // requests of dropped futures, their responses are discarded
+    abandoned: HashSet<u64>,
+    notifications: VecDeque<Value>
+}
+
+impl Client<TcpStream> {
+    /// connect over plain TCP
+    pub fn connect(address: &str) -> Connect<TcpStream> {
+        Connect::new(|| Box::pin(TcpStream::connect(address.to_string())))
+    }
+}
+
+impl Client<TlsStream<TcpStream>> {
+    /// connect over SSL, the server certificate is checked against the webpki roots for domain
+    pub fn connect_ssl(address: &str, domain: &str) -> Connect<TlsStream<TcpStream>> {
+        let roots = RootCertStore { roots: webpki_roots::TLS_SERVER_ROOTS.to_vec() };
+        let config = match ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions() {
+            Ok(builder) => builder.with_root_certificates(roots).with_no_client_auth(),
+            Err(e) => return Connect::failed(electrum::invalid(e))
+        };
+        let name = match ServerName::try_from(domain.to_string()) {
+            Ok(name) => name,
+            Err(e) => return Connect::failed(electrum::invalid(e))
+        };
+        let tls = (TlsConnector::from(Arc::new(config)), name);
+        Connect::new(|| Box::pin(TlsConnecting { tcp: Box::pin(TcpStream::connect(address.to_string())), tls: Some(tls), handshake: None }))
+    }
+}
+
+impl<S> Client<S> {
+    /// queue a request, it is sent with those of other requests once a response is awaited
+    fn send(&mut self, method: &str, params: Vec<Value>) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.outgoing.extend_from_slice(electrum::request_line(id, method, params).as_bytes());
+        id
+    }
+
+    /// the response of a request that is ready
+    fn take(&mut self, id: u64) -> Result<Value, WalletError> {
+        self.responses.remove(&id).expect("response is ready")
+    }
+
+    /// forget a request, its response is discarded
+    fn abandon(&mut self, id: u64) {
+        if self.responses.remove(&id).is_none() {
+            self.abandoned.insert(id);
+        }
+    }
+
+    fn receive(&mut self, line: &[u8]) -> Result<(), WalletError> {
+        let response: Value = serde_json::from_slice(line).map_err(electrum::invalid)?;
+        if response["id"].is_null() && response["method"].is_string() {
+            self.notifications.push_back(response);
+        } else if let Some(id) = response["id"].as_u64() {
+            if !self.abandoned.remove(&id) {
+                self.responses.insert(id, electrum::result(response));
+            }
+        }
+        Ok(())
+    }
+
+    /// notifications of subscriptions received so far
+    pub fn notifications(&mut self) -> impl Iterator<Item=Value> + '_ {
+        self.notifications.drain(..)
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Client<S> {
+    /// start a session on a connected stream, e.g. one through a proxy
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(stream: S) -> Handshake<S> {
+        let mut client = Client {
+            stream, next_id: 0, outgoing: Vec::new(), unflushed: false, incoming: Vec::new(),
+            responses: HashMap::new(), abandoned: HashSet::new(), notifications: VecDeque::new()
+        };
+        let id = client.send("server.version", vec!(json!("bitcoin-wallet"), json!(PROTOCOL_VERSION)));
+        Handshake { client: Some(client), id }
+    }
+
+    /// write queued requests and read responses until that of request id is there.
+    /// Reads go on while a write waits, so that a server answering a large batch does not stall it.
+    fn poll_ready(&mut self, cx: &mut Context, id: u64) -> Poll<Result<(), WalletError>> {
+        loop {
+            if self.responses.contains_key(&id) {
+                return Poll::Ready(Ok(()));
+            }
+            if let Some(end) = self.incoming.iter().position(|b| *b == b'\n') {
+                let line = self.incoming.drain(..=end).collect::<Vec<_>>();
+                self.receive(&line)?;
+                continue;
+            }
+            let mut progress = false;
+            if !self.outgoing.is_empty() {
+                match Pin::new(&mut self.stream).poll_write(cx, &self.outgoing) {
+                    Poll::Ready(Ok(0)) => return Poll::Ready(Err(closed())),
+                    Poll::Ready(Ok(n)) => {
+                        self.outgoing.drain(..n);
+                        self.unflushed = true;
+                        progress = true;
+                    }
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e.into())),
+                    Poll::Pending => {}
+                }
+            }
+            if self.outgoing.is_empty() && self.unflushed {
+                match Pin::new(&mut self.stream).poll_flush(cx) {
+                    Poll::Ready(Ok(())) => {
+                        self.unflushed = false;
+                        progress = true;
+                    }
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e.into())),
+                    Poll::Pending => {}
+                }
+            }
+            let mut buffer = [0u8; 4096];
+            let mut read = ReadBuf::new(&mut buffer);
+            match Pin::new(&mut self.stream).poll_read(cx, &mut read) {
+                Poll::Ready(Ok(())) if read.filled().is_empty() => return Poll::Ready(Err(closed())),
+                Poll::Ready(Ok(())) => {
+                    self.incoming.extend_from_slice(read.filled());
+                    progress = true;
+                }
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e.into())),
+                Poll::Pending => {}
+            }
+            if !progress {
+                return Poll::Pending;
+            }
+        }
+    }
+
+    fn call<T>(&mut self, method: &str, params: Vec<Value>, parse: Box<dyn Fn(Value) -> Result<T, WalletError> + Send>) -> Call<'_, S, T> {
+        let id = self.send(method, params);
+        Call { client: self, id: Some(id), parse }
+    }
+
+    /// send a request, the future completes with its result
+    pub fn request(&mut self, method: &str, params: Vec<Value>) -> Call<'_, S, Value> {
+        self.call(method, params, Box::new(Ok))
+    }
+
+    /// transactions spending from or paying to a script
+    pub fn get_history(&mut self, script_pubkey: &Script) -> Call<'_, S, Vec<HistoryEntry>> {
+        self.call("blockchain.scripthash.get_history", vec!(json!(script_hash(script_pubkey))), Box::new(|history| electrum::history(&history)))
+    }
+
+    pub fn get_transaction(&mut self, txid: &Txid) -> Call<'_, S, Transaction> {
+        let txid = *txid;
+        self.call("blockchain.transaction.get", vec!(json!(txid.to_string())), Box::new(move |raw| electrum::transaction(&raw, &txid)))
+    }
+
+    /// send a transaction to the network
+    pub fn broadcast(&mut self, transaction: &Transaction) -> Call<'_, S, Txid> {
+        self.call("blockchain.transaction.broadcast", vec!(json!(consensus::encode::serialize_hex(transaction))), Box::new(|txid| electrum::txid(&txid)))
+    }
+
+    /// subscribe to all scripts of the master account, extending look ahead of accounts as
+    /// used keys are found, then process confirmed transactions into coins, see `electrum::Client::sync`.
+    /// The future completes with the history of the master account including unconfirmed transactions
+    pub fn sync<'a>(&'a mut self, master: &'a mut MasterAccount, coins: &'a mut Coins) -> Synchronize<'a, S> {
+        Synchronize { client: self, master, coins, queried: HashSet::new(), history: HashMap::new(), step: Step::Scripts }
+    }
+}
+
+fn closed() -> WalletError {
+    WalletError::Electrum("connection closed".to_string())
+}
+
+/// the future of a connection and its session
+pub struct Connect<S> {
+    state: Connecting<S>
+}
+
+enum Connecting<S> {
+    Failed(Option<WalletError>),
+    Stream(Pending<S>),
+    Handshake(Handshake<S>)
+}
+
+impl<S> Connect<S> {
+    /// connect unless offline
+    fn new<F: FnOnce() -> Pending<S>>(connect: F) -> Connect<S> {
+        match offline::check() {
+            Ok(()) => Connect { state: Connecting::Stream(connect()) },
+            Err(e) => Connect::failed(e)
+        }
+    }
+
+    fn failed(error: WalletError) -> Connect<S> {
+        Connect { state: Connecting::Failed(Some(error)) }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Future for Connect<S> {
+    type Output = Result<Client<S>, WalletError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<Client<S>, WalletError>> {
+        let this = self.get_mut();
+        loop {
+            this.state = match this.state {
+                Connecting::Failed(ref mut error) => return Poll::Ready(Err(error.take().expect("connect is not polled after completion"))),
+                Connecting::Stream(ref mut stream) => match stream.as_mut().poll(cx) {
+                    Poll::Ready(Ok(stream)) => Connecting::Handshake(Client::new(stream)),
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e.into())),
+                    Poll::Pending => return Poll::Pending
+                },
+                Connecting::Handshake(ref mut handshake) => return Pin::new(handshake).poll(cx)
+            };
+        }
+    }
+}
+
+/// TCP connection and TLS handshake
+struct TlsConnecting {
+    tcp: Pending<TcpStream>,
+    tls: Option<(TlsConnector, ServerName<'static>)>,
+    handshake: Option<tokio_rustls::Connect<TcpStream>>
+}
+
+impl Future for TlsConnecting {
+    type Output = io::Result<TlsStream<TcpStream>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<TlsStream<TcpStream>>> {
+        let this = self.get_mut();
+        if this.handshake.is_none() {
+            let tcp = match this.tcp.as_mut().poll(cx) {
+                Poll::Ready(tcp) => tcp?,
+                Poll::Pending => return Poll::Pending
+            };
+            let (connector, name) = this.tls.take().expect("connected once");
+            this.handshake = Some(connector.connect(name, tcp));
+        }
+        Pin::new(this.handshake.as_mut().expect("handshake started")).poll(cx)
+    }
+}
+
+/// the future of the version negotiation that starts a session
+pub struct Handshake<S> {
+    client: Option<Client<S>>,
+    id: u64
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Future for Handshake<S> {
+    type Output = Result<Client<S>, WalletError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<Client<S>, WalletError>> {
+        let this = self.get_mut();
+        let id = this.id;
+        let client = this.client.as_mut().expect("handshake is not polled after completion");
+        match client.poll_ready(cx, id) {
+            Poll::Ready(Ok(())) => {
+                client.take(id)?;
+                Poll::Ready(Ok(this.client.take().expect("client is there")))
+            }
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending
+        }
+    }
+}
+
+/// the future of a request, the response is discarded if dropped before
+pub struct Call<'a, S: 'a, T> {
+    client: &'a mut Client<S>,
+    id: Option<u64>,
+    parse: Box<dyn Fn(Value) -> Result<T, WalletError> + Send>
+}
+
+impl<'a, S: AsyncRead + AsyncWrite + Unpin, T> Future for Call<'a, S, T> {
+    type Output = Result<T, WalletError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<T, WalletError>> {
+        let this = self.get_mut();
+        let id = this.id.expect("call is not polled after completion");
+        match this.client.poll_ready(cx, id) {
+            Poll::Ready(Ok(())) => {
+                this.id = None;
+                Poll::Ready(this.client.take(id).and_then(|value| (this.parse)(value)))
+            }
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending
+        }
+    }
+}
+
+impl<'a, S: 'a, T> Drop for Call<'a, S, T> {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            self.client.abandon(id);
+        }
+    }
+}
+
+/// the future of `Client::sync`
+pub struct Synchronize<'a, S: 'a> {
+    client: &'a mut Client<S>,
+    master: &'a mut MasterAccount,
+    coins: &'a mut Coins,
+    queried: HashSet<ScriptBuf>,
+    history: HashMap<Txid, i32>,
+    step: Step
+}
+
+enum Step {
+    /// subscribe to the scripts not yet queried, or fetch proofs if there are none
+    Scripts,
+    /// subscriptions in flight and the histories requested for scripts that have one
+    Subscriptions(VecDeque<(u64, ScriptBuf, KeyDerivation)>, Vec<u64>),
+    Histories(VecDeque<u64>),
+    /// transaction, merkle branch and header requests of confirmed transactions, and those proved
+    Proofs(VecDeque<(u64, u64, u64, Txid, i32)>, Vec<(i32, usize, ProvedTransaction)>),
+    Done
+}
+
+impl<'a, S: AsyncRead + AsyncWrite + Unpin> Synchronize<'a, S> {
+    /// the next step, the history once done
+    fn advance(&mut self, cx: &mut Context) -> Poll<Result<Option<Vec<HistoryEntry>>, WalletError>> {
+        match self.step {
+            Step::Scripts => {
+                let scripts = self.master.get_scripts().filter(|(s, _)| !self.queried.contains(s)).collect::<Vec<_>>();
+                if scripts.is_empty() {
+                    let client = &mut *self.client;
+                    let confirmed = self.history.iter().filter(|(_, height)| **height > 0).map(|(txid, height)| (
+                        client.send("blockchain.transaction.get", vec!(json!(txid.to_string()))),
+                        client.send("blockchain.transaction.get_merkle", vec!(json!(txid.to_string()), json!(height))),
+                        client.send("blockchain.block.header", vec!(json!(height))),
+                        *txid, *height)).collect();
+                    self.step = Step::Proofs(confirmed, Vec::new());
+                } else {
+                    let subscriptions = scripts.into_iter().map(|(script_pubkey, derivation)|
+                        (self.client.send("blockchain.scripthash.subscribe", vec!(json!(script_hash(&script_pubkey)))), script_pubkey, derivation))
+                        .collect();
+                    self.step = Step::Subscriptions(subscriptions, Vec::new());
+                }
+            }
+            Step::Subscriptions(ref mut pending, ref mut histories) => {
+                while let Some(&(id, _, _)) = pending.front() {
+                    match self.client.poll_ready(cx, id) {
+                        Poll::Ready(ready) => ready?,
+                        Poll::Pending => return Poll::Pending
+                    }
+                    let (_, script_pubkey, derivation) = pending.pop_front().expect("front is there");
+                    // a script has a status if and only if it has history
+                    if !self.client.take(id)?.is_null() {
+                        histories.push(self.client.send("blockchain.scripthash.get_history", vec!(json!(script_hash(&script_pubkey)))));
+                        if let Some(account) = self.master.get_mut((derivation.account, derivation.sub)) {
+                            account.do_look_ahead(derivation.kix)?;
+                        }
+                    }
+                    self.queried.insert(script_pubkey);
+                }
+                let histories = mem::take(histories);
+                self.step = Step::Histories(histories.into());
+            }
+            Step::Histories(ref mut pending) => {
+                while let Some(&id) = pending.front() {
+                    match self.client.poll_ready(cx, id) {
+                        Poll::Ready(ready) => ready?,
+                        Poll::Pending => return Poll::Pending
+                    }
+                    pending.pop_front();
+                    self.history.extend(electrum::history(&self.client.take(id)?)?);
+                }
+                self.step = Step::Scripts;
+            }
+            Step::Proofs(ref mut pending, ref mut confirmed) => {
+                while let Some(&(transaction, merkle, header, txid, height)) = pending.front() {
+                    for id in &[transaction, merkle, header] {
+                        match self.client.poll_ready(cx, *id) {
+                            Poll::Ready(ready) => ready?,
+                            Poll::Pending => return Poll::Pending
+                        }
+                    }
+                    pending.pop_front();
+                    let (transaction, merkle, header) = (self.client.take(transaction), self.client.take(merkle), self.client.take(header));
+                    let transaction = electrum::transaction(&transaction?, &txid)?;
+                    let (proved, position) = electrum::proved(transaction, &merkle?, &electrum::header(&header?)?)?;
+                    confirmed.push((height, position, proved));
+                }
+                let confirmed = mem::take(confirmed);
+                let history = mem::take(&mut self.history);
+                self.step = Step::Done;
+                return Poll::Ready(electrum::process(self.master, self.coins, history, confirmed).map(Some));
+            }
+            Step::Done => panic!("sync is not polled after completion")
+        }
+        Poll::Ready(Ok(None))
+    }
+}
+
+impl<'a, S: AsyncRead + AsyncWrite + Unpin> Future for Synchronize<'a, S> {
+    type Output = Result<Vec<HistoryEntry>, WalletError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<Vec<HistoryEntry>, WalletError>> {
+        let this = self.get_mut();
+        loop {
+            match this.advance(cx) {
+                Poll::Ready(Ok(Some(history))) => return Poll::Ready(Ok(history)),
+                Poll::Ready(Ok(None)) => continue,
+                Poll::Ready(Err(e)) => {
+                    this.abandon();
+                    return Poll::Ready(Err(e));
+                }
+                Poll::Pending => return Poll::Pending
+            }
+        }
+    }
+}
+
+impl<'a, S: 'a> Synchronize<'a, S> {
+    /// forget the requests in flight
+    fn abandon(&mut self) {
+        let ids = match mem::replace(&mut self.step, Step::Done) {
+            Step::Subscriptions(pending, histories) => pending.into_iter().map(|(id, _, _)| id).chain(histories).collect(),
+            Step::Histories(pending) => pending.into_iter().collect(),
+            Step::Proofs(pending, _) => pending.into_iter().flat_map(|(transaction, merkle, header, _, _)| vec!(transaction, merkle, header)).collect(),
+            Step::Scripts | Step::Done => Vec::new()
+        };
+        for id in ids {
+            self.client.abandon(id);
+        }
+    }
+}
+
+impl<'a, S: 'a> Drop for Synchronize<'a, S> {
+    fn drop(&mut self) {
+        self.abandon();
+    }
+}
+
+/// a wallet store in a single encrypted file, see `store::FileStore`, loads answer from memory and saves are futures.
+/// A save should complete before the next one starts, as they write the same temporary file
+pub struct FileStore {
+    store: store::FileStore
+}
+
+impl FileStore {
+    /// open or create the store at path
+    /// kdf is used for a new file, an existing file keeps its key derivation
+    pub fn open<P: AsRef<Path>>(path: P, passphrase: &str, kdf: Kdf) -> Open {
+        let path = path.as_ref().to_path_buf();
+        Open { read: Box::pin(fs::read(path.clone())), path, passphrase: SecretBytes::from_slice(passphrase.as_bytes()), kdf }
+    }
+
+    fn save(&self) -> Save {
+        match self.store.encrypt() {
+            Ok((encrypted, temporary, path)) => Save { state: Saving::Write(Box::pin(fs::write(temporary.clone(), encrypted)), temporary, path) },
+            Err(e) => Save { state: Saving::Failed(Some(e)) }
+        }
+    }
+
+    /// see `WalletStore::load_master`
+    pub fn load_master(&mut self) -> Result<Option<MasterAccount>, WalletError> {
+        self.store.load_master()
+    }
+
+    /// see `WalletStore::save_master`
+    pub fn save_master(&mut self, master: &MasterAccount) -> Save {
+        self.store.set_master(master);
+        self.save()
+    }
+
+    /// see `WalletStore::load_coins`
+    pub fn load_coins(&mut self) -> Result<Coins, WalletError> {
+        self.store.load_coins()
+    }
+
+    /// see `WalletStore::save_coins`
+    pub fn save_coins(&mut self, coins: &Coins) -> Save {
+        self.store.set_coins(coins);
+        self.save()
+    }
+
+    /// see `WalletStore::load_history`
+    pub fn load_history(&mut self) -> Result<Vec<HistoryEntry>, WalletError> {
+        self.store.load_history()
+    }
+
+    /// see `WalletStore::save_history`
+    pub fn save_history(&mut self, history: &[HistoryEntry]) -> Save {
+        self.store.set_history(history);
+        self.save()
+    }
+
+    /// see `WalletStore::coin`
+    pub fn coin(&mut self, point: &OutPoint) -> Result<Option<Coin>, WalletError> {
+        self.store.coin(point)
+    }
+
+    /// see `WalletStore::history_since`
+    pub fn history_since(&mut self, height: i32) -> Result<Vec<HistoryEntry>, WalletError> {
+        self.store.history_since(height)
+    }
+}
+
+/// the future of `FileStore::open`
+pub struct Open {
+    read: Pending<Vec<u8>>,
+    path: PathBuf,
+    passphrase: SecretBytes,
+    kdf: Kdf
+}
+
+impl Future for Open {
+    type Output = Result<FileStore, WalletError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<FileStore, WalletError>> {
+        let this = self.get_mut();
+        let encrypted = match this.read.as_mut().poll(cx) {
+            Poll::Ready(Ok(encrypted)) => Some(encrypted),
+            Poll::Ready(Err(ref e)) if e.kind() == io::ErrorKind::NotFound => None,
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(e.into())),
+            Poll::Pending => return Poll::Pending
+        };
+        let passphrase = str::from_utf8(&this.passphrase).expect("passphrase was a str");
+        Poll::Ready(store::FileStore::decrypt(this.path.clone(), passphrase, this.kdf, encrypted).map(|store| FileStore { store }))
+    }
+}
+
+/// the future of a save, the file is replaced once the temporary file is written
+pub struct Save {
+    state: Saving
+}
+
+enum Saving {
+    Failed(Option<WalletError>),
+    Write(Pending<()>, PathBuf, PathBuf),
+    Rename(Pending<()>)
+}
+
+impl Future for Save {
+    type Output = Result<(), WalletError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), WalletError>> {
+        let this = self.get_mut();
+        loop {
+            this.state = match this.state {
+                Saving::Failed(ref mut error) => return Poll::Ready(Err(error.take().expect("save is not polled after completion"))),
+                Saving::Write(ref mut write, ref temporary, ref path) => match write.as_mut().poll(cx) {
+                    Poll::Ready(Ok(())) => Saving::Rename(Box::pin(fs::rename(temporary.clone(), path.clone()))),
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e.into())),
+                    Poll::Pending => return Poll::Pending
+                },
+                Saving::Rename(ref mut rename) => return rename.as_mut().poll(cx).map_err(WalletError::from)
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::VecDeque;
+    use std::env;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use bitcoin::{Amount, Block, BlockHash, CompactTarget, Network, Sequence, TxIn, TxMerkleNode, TxOut, Witness};
+    use bitcoin::absolute::LockTime;
+    use bitcoin::block::Header;
+    use bitcoin::hex::FromHex;
+    use bitcoin::transaction::Version;
+    use bitcoin_hashes::Hash;
+    use tokio::runtime::{Builder, Runtime};
+
+    use account::{AccountAddressType, MasterKeyEntropy, Unlocker};
+    use argon2;
+    use super::*;
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+
+    fn runtime() -> Runtime {
+        Builder::new_current_thread().enable_all().build().unwrap()
+    }
+
+    /// answers requests from a single block chain, the whole batch at once
+    struct MockServer {
+        block: Block,
+        request: Vec<u8>,
+        response: VecDeque<u8>
+    }
+
+    impl MockServer {
+        fn answer(&self, request: &Value) -> Value {
+            let params = request["params"].as_array().unwrap();
+            let tx = &self.block.txdata[0];
+            let hashes = tx.output.iter().map(|o| script_hash(&o.script_pubkey)).collect::<Vec<_>>();
+            match request["method"].as_str().unwrap() {
+                "server.version" => json!(["mock", PROTOCOL_VERSION]),
+                "blockchain.scripthash.subscribe" =>
+                    if hashes.iter().any(|h| h == params[0].as_str().unwrap()) { json!("status") } else { Value::Null },
+                "blockchain.scripthash.get_history" => json!([{"tx_hash": tx.compute_txid().to_string(), "height": 1}]),
+                "blockchain.transaction.get" => json!(consensus::encode::serialize_hex(tx)),
+                "blockchain.transaction.get_merkle" => json!({"block_height": 1, "merkle": [], "pos": 0}),
+                "blockchain.block.header" => json!(consensus::encode::serialize_hex(&self.block.header)),
+                "blockchain.transaction.broadcast" => {
+                    let raw = Vec::<u8>::from_hex(params[0].as_str().unwrap()).unwrap();
+                    json!(consensus::deserialize::<Transaction>(&raw).unwrap().compute_txid().to_string())
+                }
+                _ => Value::Null
+            }
+        }
+
+        fn process(&mut self, buf: &[u8]) {
+            self.request.extend_from_slice(buf);
+            while let Some(end) = self.request.iter().position(|b| *b == b'\n') {
+                let line = self.request.drain(..=end).collect::<Vec<_>>();
+                let request: Value = serde_json::from_slice(&line).unwrap();
+                // a notification first, the client has to keep it aside
+                self.response.extend(json!({"jsonrpc": "2.0", "method": "blockchain.headers.subscribe", "params": []}).to_string().bytes());
+                self.response.push_back(b'\n');
+                let response = json!({"jsonrpc": "2.0", "id": request["id"], "result": self.answer(&request)});
+                self.response.extend(response.to_string().bytes());
+                self.response.push_back(b'\n');
+            }
+        }
+    }
+
+    impl AsyncWrite for MockServer {
+        fn poll_write(self: Pin<&mut Self>, _cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+            self.get_mut().process(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl AsyncRead for MockServer {
+        // nothing to read is the end of the stream, as answers are there once asked
+        fn poll_read(self: Pin<&mut Self>, _cx: &mut Context, buf: &mut ReadBuf) -> Poll<io::Result<()>> {
+            let response = &mut self.get_mut().response;
+            let n = response.len().min(buf.remaining());
+            buf.put_slice(&response.drain(..n).collect::<Vec<_>>());
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// a master with the last key of the look ahead window of its account used in a block
+    fn funded() -> (MasterAccount, Block) {
+        let mut master = MasterAccount::new(MasterKeyEntropy::Low, Network::Bitcoin, PASSPHRASE, None).unwrap();
+        let mut unlocker = Unlocker::new(master.encrypted(), PASSPHRASE, None, Network::Bitcoin, None).unwrap();
+        let used = master.new_account(&mut unlocker, AccountAddressType::P2WPKH, 0, 0, 5).unwrap().get_key(4).unwrap().script_pubkey.clone();
+        let funding = Transaction {
+            version: Version::ONE,
+            lock_time: LockTime::ZERO,
+            input: vec!(TxIn { previous_output: OutPoint::null(), script_sig: ScriptBuf::new(), sequence: Sequence::MAX, witness: Witness::new() }),
+            output: vec!(TxOut { value: Amount::from_sat(100000), script_pubkey: used })
+        };
+        let block = Block {
+            header: Header {
+                version: bitcoin::block::Version::ONE, prev_blockhash: BlockHash::all_zeros(),
+                merkle_root: TxMerkleNode::from_raw_hash(funding.compute_txid().to_raw_hash()),
+                time: 0, bits: CompactTarget::from_consensus(0), nonce: 0
+            },
+            txdata: vec!(funding)
+        };
+        (master, block)
+    }
+
+    #[test]
+    fn test_sync() {
+        let (mut master, block) = funded();
+        let funding = block.txdata[0].clone();
+        let runtime = runtime();
+        let mut client = runtime.block_on(Client::new(MockServer { block, request: Vec::new(), response: VecDeque::new() })).unwrap();
+        let mut coins = Coins::new();
+        let history = runtime.block_on(client.sync(&mut master, &mut coins)).unwrap();
+        assert_eq!(history, vec!((funding.compute_txid(), 1)));
+        assert_eq!(coins.owned().len(), 1);
+        assert_eq!(coins.proofs().len(), 1);
+        // found use extended the look ahead
+        assert_eq!(master.get((0, 0)).unwrap().instantiated().len(), 9);
+        assert!(client.notifications().count() > 0);
+
+        // a second sync does not add anything
+        assert_eq!(runtime.block_on(client.sync(&mut master, &mut coins)).unwrap().len(), 1);
+        assert_eq!(coins.owned().len(), 1);
+
+        assert_eq!(runtime.block_on(client.get_transaction(&funding.compute_txid())).unwrap(), funding);
+        assert!(runtime.block_on(client.get_transaction(&Txid::all_zeros())).is_err());
+        assert_eq!(runtime.block_on(client.broadcast(&funding)).unwrap(), funding.compute_txid());
+        // the response of a dropped call is discarded
+        drop(client.get_history(&funding.output[0].script_pubkey));
+        assert_eq!(runtime.block_on(client.get_history(&funding.output[0].script_pubkey)).unwrap(), vec!((funding.compute_txid(), 1)));
+        assert!(client.responses.is_empty() && client.abandoned.is_empty());
+    }
+
+    #[test]
+    fn test_connect() {
+        let (mut master, block) = funded();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+            let mut mock = MockServer { block, request: Vec::new(), response: VecDeque::new() };
+            let mut buffer = [0u8; 4096];
+            loop {
+                let n = socket.read(&mut buffer).unwrap();
+                if n == 0 {
+                    return;
+                }
+                mock.process(&buffer[..n]);
+                let response = mock.response.drain(..).collect::<Vec<_>>();
+                socket.write_all(&response).unwrap();
+            }
+        });
+        let runtime = runtime();
+        let connect = Client::connect(&address.to_string());
+        // futures of a TCP client can be spawned on threads of a runtime
+        fn is_send<T: Send>(_: &T) {}
+        is_send(&connect);
+        let mut client = runtime.block_on(connect).unwrap();
+        let mut coins = Coins::new();
+        is_send(&client.sync(&mut master, &mut coins));
+        assert_eq!(runtime.block_on(client.sync(&mut master, &mut coins)).unwrap().len(), 1);
+        assert_eq!(coins.owned().len(), 1);
+        drop(client);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_file_store() {
+        let mut path = env::temp_dir();
+        path.push(format!("bitcoin-wallet-nonblocking-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let kdf = Kdf::Argon2id(argon2::Params { memory: 64, iterations: 1, parallelism: 1 });
+        let runtime = runtime();
+
+        let mut store = runtime.block_on(FileStore::open(&path, PASSPHRASE, kdf)).unwrap();
+        assert!(store.load_master().unwrap().is_none());
+        let (master, _) = funded();
+        runtime.block_on(store.save_master(&master)).unwrap();
+        runtime.block_on(store.save_history(&[(Txid::all_zeros(), 5)])).unwrap();
+
+        let mut store = runtime.block_on(FileStore::open(&path, PASSPHRASE, kdf)).unwrap();
+        assert_eq!(store.load_master().unwrap().unwrap().master_public(), master.master_public());
+        assert_eq!(store.history_since(6).unwrap(), vec!());
+        assert_eq!(store.load_history().unwrap(), vec!((Txid::all_zeros(), 5)));
+        assert!(runtime.block_on(FileStore::open(&path, "wrong", kdf)).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,93 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Offline mode
+//!
+//! Refuse to open sockets on cold signing machines. The `offline` feature does not compile networking
+//! code at all, even if networking features are enabled too, the flag of this module stops the connections
+//! of a build that has them.
+//!
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use error::WalletError;
+
+static OFFLINE: AtomicBool = AtomicBool::new(cfg!(feature = "offline"));
+
+/// refuse connections from now on, or allow them again unless built with the offline feature
+pub fn set_offline(offline: bool) {
+    OFFLINE.store(offline || cfg!(feature = "offline"), Ordering::SeqCst);
+}
+
+pub fn is_offline() -> bool {
+    OFFLINE.load(Ordering::SeqCst)
+}
+
+/// fails if offline, connections check this before they open a socket
+pub fn check() -> Result<(), WalletError> {
+    if is_offline() {
+        return Err(WalletError::Offline);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    #[cfg(all(any(feature = "electrum", feature = "p2p", feature = "bitcoind", feature = "esplora", feature = "mempool", feature = "payjoin"), not(feature = "offline")))]
+    use std::{env, net::TcpListener, process::Command};
+
+    use super::*;
+
+    #[test]
+    fn test_offline() {
+        // other tests connect to local servers, the flag is not changed here
+        assert_eq!(is_offline(), cfg!(feature = "offline"));
+        assert_eq!(check().is_err(), cfg!(feature = "offline"));
+        assert_eq!(WalletError::Offline.to_string(), "offline, networking is disabled");
+    }
+
+    /// other tests connect to local servers, so this one sets the flag in a process of its own
+    #[test]
+    #[cfg(all(any(feature = "electrum", feature = "p2p", feature = "bitcoind", feature = "esplora", feature = "mempool", feature = "payjoin"), not(feature = "offline")))]
+    fn test_offline_connect() {
+        const CHILD: &str = "WALLET_OFFLINE_TEST";
+        if env::var_os(CHILD).is_none() {
+            let status = Command::new(env::current_exe().unwrap())
+                .args(["offline::test::test_offline_connect", "--exact", "--nocapture"])
+                .env(CHILD, "1").status().unwrap();
+            assert!(status.success());
+            return;
+        }
+
+        use proxy::{self, Socks5Proxy};
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        set_offline(true);
+        assert!(matches!(proxy::connect(&address.to_string(), None), Err(WalletError::Offline)));
+        assert!(matches!(Socks5Proxy::new(&address.to_string()).connect("example.onion:80"), Err(WalletError::Offline)));
+        #[cfg(feature = "p2p")]
+        {
+            use bitcoin::Network;
+            use p2p::Peer;
+            assert!(matches!(Peer::connect(&address, Network::Bitcoin), Err(WalletError::Offline)));
+        }
+
+        set_offline(false);
+        assert!(!is_offline());
+        assert!(proxy::connect(&address.to_string(), None).is_ok());
+    }
+}
@@ -0,0 +1,227 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Peer
+//!
+//! A minimal connection to a BIP157 serving peer of the bitcoin network
+//!
+
+use std::io::{BufReader, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bitcoin::{consensus, Block, BlockHash, Network, Transaction, Txid};
+use bitcoin::bip158::BlockFilter;
+use bitcoin::block::Header;
+use bitcoin::p2p::{Address, Magic, ServiceFlags};
+use bitcoin::p2p::message::{NetworkMessage, RawNetworkMessage};
+use bitcoin::p2p::message_blockdata::{GetHeadersMessage, Inventory};
+use bitcoin::p2p::message_filter::GetCFilters;
+use bitcoin::p2p::message_network::VersionMessage;
+use bitcoin_hashes::Hash;
+use secp256k1::rand::{thread_rng, RngCore};
+
+use broadcast::Broadcaster;
+use error::WalletError;
+use offline;
+use proxy::{self, Socks5Proxy};
+use watch::{MempoolEvent, MempoolWatcher};
+use filters::{FilterSource, BASIC_FILTER};
+
+/// a connected peer that serves compact block filters
+pub struct Peer {
+    stream: BufReader<TcpStream>,
+    magic: Magic,
+    services: ServiceFlags,
+    height: i32
+}
+
+impl Peer {
+    /// connect and complete the version handshake
+    /// fails if the peer does not serve compact filters
+    pub fn connect(address: &SocketAddr, network: Network) -> Result<Peer, WalletError> {
+        Peer::connect_with_magic(address, Magic::from(network))
+    }
+
+    /// connect to a peer of a network with other message start bytes, e.g. a custom network
+    pub fn connect_with_magic(address: &SocketAddr, magic: Magic) -> Result<Peer, WalletError> {
+        offline::check()?;
+        Peer::handshake(TcpStream::connect(address)?, address, magic, false)
+    }
+
+    /// connect to target, a host:port, through the proxy if given, e.g. to an onion peer through Tor
+    pub fn connect_with_proxy(target: &str, magic: Magic, proxy: Option<&Socks5Proxy>) -> Result<Peer, WalletError> {
+        let stream = proxy::connect(target, proxy)?;
+        // the address of an onion peer has no socket address, the peer does not rely on it
+        let address = target.parse().unwrap_or_else(|_| SocketAddr::from(([0, 0, 0, 0], 0)));
+        Peer::handshake(stream, &address, magic, false)
+    }
+
+    /// connect like `connect_with_proxy` and ask the peer to announce transactions entering its mempool, see `wait_for_events`
+    pub fn connect_relaying(target: &str, magic: Magic, proxy: Option<&Socks5Proxy>) -> Result<Peer, WalletError> {
+        let stream = proxy::connect(target, proxy)?;
+        let address = target.parse().unwrap_or_else(|_| SocketAddr::from(([0, 0, 0, 0], 0)));
+        Peer::handshake(stream, &address, magic, true)
+    }
+
+    fn handshake(stream: TcpStream, address: &SocketAddr, magic: Magic, relay: bool) -> Result<Peer, WalletError> {
+        let local = stream.local_addr()?;
+        let mut peer = Peer { stream: BufReader::new(stream), magic, services: ServiceFlags::NONE, height: 0 };
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map_err(|e| WalletError::P2P(e.to_string()))?.as_secs() as i64;
+        let mut version = VersionMessage::new(ServiceFlags::NONE, timestamp,
+                                              Address::new(address, ServiceFlags::NONE), Address::new(&local, ServiceFlags::NONE),
+                                              thread_rng().next_u64(), "/bitcoin-wallet/".to_string(), 0);
+        version.relay = relay;
+        peer.send(NetworkMessage::Version(version))?;
+        let mut verack = false;
+        let mut remote = None;
+        while !verack || remote.is_none() {
+            match peer.receive()? {
+                NetworkMessage::Version(v) => {
+                    peer.send(NetworkMessage::Verack)?;
+                    remote = Some(v);
+                }
+                NetworkMessage::Verack => verack = true,
+                _ => {}
+            }
+        }
+        let remote = remote.expect("loop ends with a version");
+        if !remote.services.has(ServiceFlags::COMPACT_FILTERS) {
+            return Err(WalletError::P2P("peer does not serve compact filters".to_string()));
+        }
+        peer.services = remote.services;
+        peer.height = remote.start_height;
+        Ok(peer)
+    }
+
+    /// services the peer announced
+    pub fn services(&self) -> ServiceFlags {
+        self.services
+    }
+
+    /// height of the peer's chain at connection
+    pub fn height(&self) -> i32 {
+        self.height
+    }
+
+    /// headers following the first known block of the locator, at most 2000
+    pub fn get_headers(&mut self, locator: Vec<BlockHash>) -> Result<Vec<Header>, WalletError> {
+        self.send(NetworkMessage::GetHeaders(GetHeadersMessage::new(locator, BlockHash::all_zeros())))?;
+        loop {
+            if let NetworkMessage::Headers(headers) = self.receive()? {
+                return Ok(headers);
+            }
+        }
+    }
+
+    /// relay a transaction to the peer, returns once the peer processed it
+    /// a peer does not tell whether it accepted the transaction into its mempool
+    pub fn send_transaction(&mut self, transaction: &Transaction) -> Result<Txid, WalletError> {
+        self.send(NetworkMessage::Tx(transaction.clone()))?;
+        // messages are processed in order, the pong follows processing of the transaction
+        let nonce = thread_rng().next_u64();
+        self.send(NetworkMessage::Ping(nonce))?;
+        loop {
+            if let NetworkMessage::Pong(n) = self.receive()? {
+                if n == nonce {
+                    return Ok(transaction.compute_txid());
+                }
+            }
+        }
+    }
+
+    /// wait until the peer relays transactions paying to the watcher's scripts
+    /// the peer announces transactions only if connected with `connect_relaying`
+    pub fn wait_for_events(&mut self, watcher: &mut MempoolWatcher) -> Result<Vec<MempoolEvent>, WalletError> {
+        loop {
+            match self.receive()? {
+                NetworkMessage::Inv(inventory) => {
+                    let wanted = inventory.iter().filter_map(|i| match i {
+                        Inventory::Transaction(txid) | Inventory::WitnessTransaction(txid) if !watcher.is_pending(txid) =>
+                            Some(Inventory::WitnessTransaction(*txid)),
+                        _ => None
+                    }).collect::<Vec<_>>();
+                    if !wanted.is_empty() {
+                        self.send(NetworkMessage::GetData(wanted))?;
+                    }
+                }
+                NetworkMessage::Tx(transaction) => {
+                    if let Some(event) = watcher.process(&transaction) {
+                        return Ok(vec!(event));
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn send(&mut self, payload: NetworkMessage) -> Result<(), WalletError> {
+        let message = RawNetworkMessage::new(self.magic, payload);
+        self.stream.get_mut().write_all(&consensus::serialize(&message))?;
+        Ok(())
+    }
+
+    /// next message of the peer, pings are answered on the way
+    fn receive(&mut self) -> Result<NetworkMessage, WalletError> {
+        loop {
+            let message: RawNetworkMessage = consensus::Decodable::consensus_decode(&mut self.stream)
+                .map_err(|e| WalletError::P2P(e.to_string()))?;
+            if *message.magic() != self.magic {
+                return Err(WalletError::P2P("wrong network magic".to_string()));
+            }
+            match message.into_payload() {
+                NetworkMessage::Ping(nonce) => self.send(NetworkMessage::Pong(nonce))?,
+                payload => return Ok(payload)
+            }
+        }
+    }
+}
+
+impl Broadcaster for Peer {
+    fn broadcast(&mut self, transaction: &Transaction) -> Result<Txid, WalletError> {
+        self.send_transaction(transaction)
+    }
+}
+
+impl FilterSource for Peer {
+    fn get_filters(&mut self, start_height: u32, stop_hash: &BlockHash) -> Result<Vec<(BlockHash, BlockFilter)>, WalletError> {
+        self.send(NetworkMessage::GetCFilters(GetCFilters { filter_type: BASIC_FILTER, start_height, stop_hash: *stop_hash }))?;
+        let mut filters = Vec::new();
+        loop {
+            if let NetworkMessage::CFilter(filter) = self.receive()? {
+                if filter.filter_type != BASIC_FILTER {
+                    continue;
+                }
+                let last = filter.block_hash == *stop_hash;
+                filters.push((filter.block_hash, BlockFilter::new(&filter.filter)));
+                if last {
+                    return Ok(filters);
+                }
+            }
+        }
+    }
+
+    fn get_block(&mut self, block_hash: &BlockHash) -> Result<Block, WalletError> {
+        self.send(NetworkMessage::GetData(vec!(Inventory::WitnessBlock(*block_hash))))?;
+        loop {
+            match self.receive()? {
+                NetworkMessage::Block(block) if block.block_hash() == *block_hash => return Ok(block),
+                NetworkMessage::NotFound(_) => return Err(WalletError::P2P(format!("block {} not found", block_hash))),
+                _ => {}
+            }
+        }
+    }
+}
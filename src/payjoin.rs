@@ -0,0 +1,723 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Payjoin
+//!
+//! Sender and receiver of BIP78 payjoin, the receiver adds inputs to a payment
+//!
+
+use std::collections::{HashMap, HashSet};
+
+use bitcoin::{Amount, EcdsaSighashType, FeeRate, OutPoint, Psbt, Script, ScriptBuf, Transaction, TxOut, Witness};
+#[cfg(not(target_arch = "wasm32"))]
+use bitcoin::TxIn;
+use bitcoin::psbt::Output;
+#[cfg(not(target_arch = "wasm32"))]
+use secp256k1::rand::{thread_rng, Rng, seq::SliceRandom};
+
+use account::{MasterAccount, Unlocker};
+use coins::Coins;
+use coinselect;
+#[cfg(not(target_arch = "wasm32"))]
+use dust;
+use error::WalletError;
+use psbt;
+#[cfg(all(feature = "payjoin", not(feature = "offline")))]
+use proxy::{self, Socks5Proxy};
+use uri::PaymentRequest;
+
+/// the payjoin endpoint of a payment request, its pj parameter
+pub fn endpoint(request: &PaymentRequest) -> Option<&str> {
+    request.extras.iter().find(|(key, _)| key == "pj").map(|(_, value)| value.as_str())
+}
+
+/// a payjoin of a signed original transaction paying a payment request
+/// the original is the fallback to broadcast if the receiver fails or proposes an invalid payjoin.
+/// Output substitution is always disabled, the receiver may only add to its payment.
+pub struct Sender {
+    endpoint: String,
+    original: Transaction,
+    psbt: Psbt,
+    prevouts: HashMap<OutPoint, TxOut>,
+    payee: ScriptBuf,
+    fee_output: Option<usize>,
+    max_additional_fee: Amount,
+    /// fee of an input of the type the sender spends at the fee rate of the original
+    input_fee: Amount,
+    min_fee_rate: Option<FeeRate>
+}
+
+impl Sender {
+    /// a payjoin of original, signed and spending owned coins, to the endpoint of the request
+    /// change of the original may pay the fee of one more input of the type the sender spends
+    pub fn new(master: &MasterAccount, coins: &Coins, request: &PaymentRequest, original: Transaction) -> Result<Sender, WalletError> {
+        let endpoint = endpoint(request).ok_or_else(|| invalid("no payjoin endpoint in the request"))?;
+        let onion = endpoint.starts_with("http://") && host(endpoint).ends_with(".onion");
+        if !(endpoint.starts_with("https://") || onion) {
+            return Err(invalid("payjoin endpoint must be https or onion"));
+        }
+        let payee = request.address.script_pubkey();
+        let paid = original.output.iter().filter(|o| o.script_pubkey == payee).map(|o| o.value).sum::<Amount>();
+        if paid == Amount::ZERO || request.amount.is_some_and(|amount| paid < amount) {
+            return Err(invalid("original does not pay the request"));
+        }
+        let mut prevouts = HashMap::new();
+        for input in &original.input {
+            let coin = coins.owned().get(&input.previous_output).ok_or(WalletError::UnknownCoin(input.previous_output))?;
+            if input.script_sig.is_empty() && input.witness.is_empty() {
+                return Err(invalid("original is not signed"));
+            }
+            prevouts.insert(input.previous_output, coin.output.clone());
+        }
+
+        let mut unsigned = original.clone();
+        for input in unsigned.input.iter_mut() {
+            input.script_sig = ScriptBuf::new();
+            input.witness = Witness::new();
+        }
+        let mut original_psbt = Psbt::from_unsigned_tx(unsigned)?;
+        psbt::update(master, coins, &mut original_psbt);
+        // the receiver learns spent outputs and final scripts, but no key origins
+        for (input, txin) in original_psbt.inputs.iter_mut().zip(original.input.iter()) {
+            input.bip32_derivation.clear();
+            input.tap_key_origins.clear();
+            input.tap_internal_key = None;
+            input.redeem_script = None;
+            input.witness_script = None;
+            if !txin.script_sig.is_empty() {
+                input.final_script_sig = Some(txin.script_sig.clone());
+            }
+            if !txin.witness.is_empty() {
+                input.final_script_witness = Some(txin.witness.clone());
+            }
+        }
+        original_psbt.outputs = vec!(Output::default(); original.output.len());
+
+        // the fee of one more input is taken from change
+        let fee_rate = rate(fee(&prevouts, &original)?, original.weight().to_wu());
+        let fee_output = original.output.iter().position(|o| o.script_pubkey != payee && master.get_key_for_script(&o.script_pubkey).is_some());
+        let input_fee = match prevouts.values().next().and_then(|o| master.get_key_for_script(&o.script_pubkey)) {
+            Some((account, key)) => Amount::from_sat(coinselect::fee(fee_rate, coinselect::input_weight(account.address_type(), &key.script_code))),
+            None => Amount::ZERO
+        };
+        let max_additional_fee = if fee_output.is_some() { input_fee } else { Amount::ZERO };
+        Ok(Sender {
+            endpoint: endpoint.to_string(), original, psbt: original_psbt, prevouts, payee,
+            fee_output, max_additional_fee, input_fee, min_fee_rate: None
+        })
+    }
+
+    /// the most the change of the original may pay for the inputs of the receiver,
+    /// never more than the fee of the added inputs at the fee rate of the original
+    pub fn max_additional_fee(mut self, amount: Amount) -> Sender {
+        if self.fee_output.is_some() {
+            self.max_additional_fee = amount;
+        }
+        self
+    }
+
+    /// reject a payjoin below the fee rate
+    pub fn min_fee_rate(mut self, fee_rate: FeeRate) -> Sender {
+        self.min_fee_rate = Some(fee_rate);
+        self
+    }
+
+    /// the signed original, broadcast it if the payjoin fails
+    pub fn fallback(&self) -> &Transaction {
+        &self.original
+    }
+
+    /// the original PSBT sent to the receiver
+    pub fn original_psbt(&self) -> &Psbt {
+        &self.psbt
+    }
+
+    /// endpoint with the parameters of BIP78
+    pub fn url(&self) -> String {
+        let mut url = self.endpoint.clone();
+        url.push(if url.contains('?') { '&' } else { '?' });
+        url.push_str("v=1&disableoutputsubstitution=true");
+        if let Some(vout) = self.fee_output {
+            url.push_str(&format!("&additionalfeeoutputindex={}&maxadditionalfeecontribution={}", vout, self.max_additional_fee.to_sat()));
+        }
+        if let Some(fee_rate) = self.min_fee_rate {
+            url.push_str(&format!("&minfeerate={}", fee_rate.to_sat_per_kwu() as f64 / 250.0));
+        }
+        url
+    }
+
+    /// validate the payjoin proposed by the receiver and sign the inputs of the sender
+    pub fn process_proposal(&self, master: &MasterAccount, proposal: &Psbt, unlocker: &mut Unlocker) -> Result<Transaction, WalletError> {
+        let original = &self.psbt.unsigned_tx;
+        let proposed = &proposal.unsigned_tx;
+        if proposed.version != original.version || proposed.lock_time != original.lock_time {
+            return Err(invalid("version or lock time changed"));
+        }
+        if !proposal.xpub.is_empty() || proposal.outputs.iter().any(|o| !o.bip32_derivation.is_empty() || !o.tap_key_origins.is_empty()) {
+            return Err(invalid("proposal has key origins"));
+        }
+        // receiver inputs must look like those of the sender
+        let kinds = self.prevouts.values().map(|o| kind(&o.script_pubkey)).collect::<HashSet<_>>();
+        let sequences = original.input.iter().map(|i| i.sequence).collect::<HashSet<_>>();
+        let mut prevouts = self.prevouts.clone();
+        let mut seen = HashSet::new();
+        let mut sender_inputs = 0;
+        for (ix, (txin, input)) in proposed.input.iter().zip(proposal.inputs.iter()).enumerate() {
+            if !seen.insert(txin.previous_output) {
+                return Err(invalid("an input is spent twice"));
+            }
+            if !input.bip32_derivation.is_empty() || !input.tap_key_origins.is_empty() {
+                return Err(invalid("proposal has key origins"));
+            }
+            let finalized = input.final_script_sig.is_some() || input.final_script_witness.is_some();
+            if let Some(sender) = original.input.iter().find(|i| i.previous_output == txin.previous_output) {
+                if txin.sequence != sender.sequence {
+                    return Err(invalid("sequence of a sender input changed"));
+                }
+                if finalized {
+                    return Err(invalid("sender input is finalized"));
+                }
+                sender_inputs += 1;
+            } else {
+                if !finalized {
+                    return Err(invalid("receiver input is not finalized"));
+                }
+                let spent = proposal.spend_utxo(ix).map_err(|_| invalid("receiver input without spent output"))?;
+                if kinds.len() == 1 && !kinds.contains(kind(&spent.script_pubkey)) {
+                    return Err(invalid("receiver input of an other script type"));
+                }
+                if sequences.len() == 1 && !sequences.contains(&txin.sequence) {
+                    return Err(invalid("receiver input of an other sequence"));
+                }
+                prevouts.insert(txin.previous_output, spent.clone());
+            }
+        }
+        if sender_inputs != original.input.len() {
+            return Err(invalid("sender input missing"));
+        }
+        if proposed.input.len() == original.input.len() {
+            return Err(invalid("receiver added no input"));
+        }
+
+        let mut contribution = Amount::ZERO;
+        for (vout, txout) in original.output.iter().enumerate() {
+            let proposed_output = proposed.output.iter().find(|o| o.script_pubkey == txout.script_pubkey)
+                .ok_or_else(|| invalid("sender output missing"))?;
+            if Some(vout) == self.fee_output {
+                contribution = txout.value.checked_sub(proposed_output.value).unwrap_or(Amount::ZERO);
+                if contribution > self.max_additional_fee {
+                    return Err(invalid("fee contribution above the maximum"));
+                }
+                // BIP78: the sender pays at most its original fee rate for each input the receiver adds
+                let added = (proposed.input.len() - original.input.len()) as u64;
+                if contribution > self.input_fee.checked_mul(added).unwrap_or(Amount::MAX_MONEY) {
+                    return Err(invalid("fee contribution above the original fee rate of the added inputs"));
+                }
+            } else if txout.script_pubkey == self.payee {
+                if proposed_output.value < txout.value {
+                    return Err(invalid("payment reduced"));
+                }
+            } else if proposed_output.value != txout.value {
+                return Err(invalid("sender output changed"));
+            }
+        }
+        let original_fee = fee(&self.prevouts, original)?;
+        let proposed_fee = fee(&prevouts, proposed)?;
+        if proposed_fee < original_fee + contribution {
+            return Err(invalid("fee contribution pays the receiver"));
+        }
+
+        let mut transaction = proposed.clone();
+        for txin in transaction.input.iter_mut() {
+            txin.script_sig = ScriptBuf::new();
+            txin.witness = Witness::new();
+        }
+        let resolver = |point: &OutPoint| prevouts.get(point).cloned();
+        if master.sign(&mut transaction, EcdsaSighashType::All, &resolver, unlocker)? < sender_inputs {
+            return Err(invalid("can not sign all sender inputs"));
+        }
+        for (txin, input) in transaction.input.iter_mut().zip(proposal.inputs.iter()) {
+            if !self.prevouts.contains_key(&txin.previous_output) {
+                txin.script_sig = input.final_script_sig.clone().unwrap_or_default();
+                txin.witness = input.final_script_witness.clone().unwrap_or_default();
+            }
+        }
+        if let Some(fee_rate) = self.min_fee_rate {
+            if proposed_fee.to_sat() < coinselect::fee(fee_rate, transaction.weight().to_wu()) {
+                return Err(invalid("fee rate below the minimum"));
+            }
+        }
+        Ok(transaction)
+    }
+
+    /// post the original to the receiver and sign its proposal, optionally through a SOCKS5 proxy, e.g. Tor for onion endpoints
+    #[cfg(all(feature = "payjoin", not(feature = "offline")))]
+    pub fn send(&self, master: &MasterAccount, unlocker: &mut Unlocker, proxy: Option<&Socks5Proxy>) -> Result<Transaction, WalletError> {
+        let response = post(&self.url(), &psbt::to_base64(&self.psbt), proxy)?;
+        let proposal = psbt::from_base64(response.trim())?;
+        self.process_proposal(master, &proposal, unlocker)
+    }
+}
+
+/// parameters of the sender, the query of the payjoin request
+#[derive(Clone, Debug, PartialEq)]
+pub struct Parameters {
+    /// output of the sender that may pay for the inputs of the receiver
+    pub additional_fee_output: Option<usize>,
+    /// the most that output pays
+    pub max_additional_fee: Amount,
+    /// lowest fee rate of the proposal the sender accepts
+    pub min_fee_rate: Option<FeeRate>,
+    /// the receiver must not change its outputs other than adding to them
+    pub disable_output_substitution: bool
+}
+
+impl Parameters {
+    /// parse a query like v=1&additionalfeeoutputindex=1&maxadditionalfeecontribution=300, unknown parameters are ignored
+    pub fn parse(query: &str) -> Result<Parameters, WalletError> {
+        let mut parameters = Parameters { additional_fee_output: None, max_additional_fee: Amount::ZERO, min_fee_rate: None, disable_output_substitution: false };
+        let mut version = None;
+        for (key, value) in query.split('&').filter_map(|p| p.split_once('=')) {
+            let number = || value.parse::<u64>().map_err(|_| invalid(&format!("invalid {}", key)));
+            match key {
+                "v" => version = Some(value),
+                "additionalfeeoutputindex" => parameters.additional_fee_output = Some(number()? as usize),
+                "maxadditionalfeecontribution" => parameters.max_additional_fee = Amount::from_sat(number()?),
+                "minfeerate" => {
+                    let sat_per_vb = value.parse::<f64>().ok().filter(|r| r.is_finite() && *r >= 0.0).ok_or_else(|| invalid("invalid minfeerate"))?;
+                    parameters.min_fee_rate = Some(FeeRate::from_sat_per_kwu((sat_per_vb * 250.0) as u64));
+                }
+                "disableoutputsubstitution" => parameters.disable_output_substitution = value == "true",
+                _ => {}
+            }
+        }
+        if version != Some("1") {
+            return Err(invalid("version-unsupported"));
+        }
+        Ok(parameters)
+    }
+}
+
+/// a checked original PSBT posted to the payjoin endpoint of the wallet
+/// broadcast the fallback if the sender does not broadcast the payjoin
+/// not on wasm32, where the scripts of originals can not be verified
+#[cfg(not(target_arch = "wasm32"))]
+pub struct Receiver {
+    original: Psbt,
+    fallback: Transaction,
+    prevouts: HashMap<OutPoint, TxOut>,
+    parameters: Parameters,
+    payment: usize
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Receiver {
+    /// check the original of a sender, query is that of the request URL
+    /// the original must be final and valid, pay to the wallet and not spend its coins.
+    /// seen remembers the inputs of originals and tells if one was seen before, e.g. `|point| !known.insert(*point)`
+    /// with a persistent set, so that a sender can not learn coins of the wallet by posting originals of the same coins
+    pub fn new<F>(master: &MasterAccount, original: Psbt, query: &str, mut seen: F) -> Result<Receiver, WalletError>
+        where F: FnMut(&OutPoint) -> bool {
+        let parameters = Parameters::parse(query)?;
+        let mut fallback = original.unsigned_tx.clone();
+        let mut prevouts = HashMap::new();
+        let mut kinds = HashSet::new();
+        for (ix, (txin, input)) in fallback.input.iter_mut().zip(original.inputs.iter()).enumerate() {
+            if input.final_script_sig.is_none() && input.final_script_witness.is_none() {
+                return Err(invalid("original input is not finalized"));
+            }
+            let spent = original.spend_utxo(ix).map_err(|_| invalid("original input without spent output"))?;
+            if master.get_key_for_script(&spent.script_pubkey).is_some() {
+                return Err(invalid("original spends a coin of the receiver"));
+            }
+            kinds.insert(kind(&spent.script_pubkey));
+            prevouts.insert(txin.previous_output, spent.clone());
+            txin.script_sig = input.final_script_sig.clone().unwrap_or_default();
+            txin.witness = input.final_script_witness.clone().unwrap_or_default();
+        }
+        if kinds.len() != 1 {
+            return Err(invalid("original spends mixed script types"));
+        }
+        let payment = fallback.output.iter().position(|o| master.get_key_for_script(&o.script_pubkey).is_some())
+            .ok_or_else(|| invalid("original does not pay the wallet"))?;
+        if let Some(vout) = parameters.additional_fee_output {
+            if vout >= fallback.output.len() || vout == payment {
+                return Err(invalid("invalid additionalfeeoutputindex"));
+            }
+        }
+        verify(&fallback, &prevouts)?;
+        fee(&prevouts, &fallback)?;
+        // all inputs are remembered, even if one was seen before
+        if fallback.input.iter().filter(|i| seen(&i.previous_output)).count() > 0 {
+            return Err(invalid("original-psbt-rejected: an input was seen in an earlier original"));
+        }
+        Ok(Receiver { original, fallback, prevouts, parameters, payment })
+    }
+
+    /// the original as signed by the sender
+    pub fn fallback(&self) -> &Transaction {
+        &self.fallback
+    }
+
+    /// parameters of the sender
+    pub fn parameters(&self) -> &Parameters {
+        &self.parameters
+    }
+
+    /// a proposal adding an owned coin of the script type the sender spends
+    pub fn proposal(&self, master: &MasterAccount, coins: &Coins, unlocker: &mut Unlocker) -> Result<Psbt, WalletError> {
+        let sender_kind = kind(&self.prevouts.values().next().expect("original has inputs").script_pubkey);
+        let candidates = coins.owned().iter()
+            .filter(|(point, coin)| !coins.is_frozen(point) && !self.prevouts.contains_key(point) && kind(&coin.output.script_pubkey) == sender_kind)
+            .map(|(point, _)| *point)
+            .collect::<Vec<_>>();
+        let point = candidates.choose(&mut thread_rng()).ok_or_else(|| invalid("unavailable"))?;
+        self.proposal_with(master, coins, &[*point], unlocker)
+    }
+
+    /// a proposal adding the owned coins to the payment, at random positions
+    /// their fee is paid by the additional fee output up to its maximum, the rest by the payment
+    pub fn proposal_with(&self, master: &MasterAccount, coins: &Coins, inputs: &[OutPoint], unlocker: &mut Unlocker) -> Result<Psbt, WalletError> {
+        if inputs.is_empty() {
+            return Err(invalid("no inputs to contribute"));
+        }
+        let mut prevouts = self.prevouts.clone();
+        let mut weight = 0;
+        let mut contributed = Amount::ZERO;
+        for point in inputs {
+            let coin = coins.owned().get(point).ok_or(WalletError::UnknownCoin(*point))?;
+            let (account, key) = master.get_key_for_script(&coin.output.script_pubkey).ok_or(WalletError::UnknownCoin(*point))?;
+            if prevouts.insert(*point, coin.output.clone()).is_some() {
+                return Err(invalid("coin is spent by the original"));
+            }
+            weight += coinselect::input_weight(account.address_type(), &key.script_code);
+            contributed += coin.output.value;
+        }
+        let original_rate = rate(fee(&self.prevouts, &self.fallback)?, self.fallback.weight().to_wu());
+        let fee_rate = self.parameters.min_fee_rate.map_or(original_rate, |r| r.max(original_rate));
+        let needed = Amount::from_sat(coinselect::fee(fee_rate, weight));
+        let from_sender = if self.parameters.additional_fee_output.is_some() { needed.min(self.parameters.max_additional_fee) } else { Amount::ZERO };
+
+        let mut proposal = self.original.clone();
+        proposal.xpub.clear();
+        // the sender fills in and signs its inputs again
+        for input in proposal.inputs.iter_mut() {
+            *input = Default::default();
+        }
+        proposal.outputs = vec!(Output::default(); proposal.unsigned_tx.output.len());
+        let sequence = proposal.unsigned_tx.input[0].sequence;
+        let mut rng = thread_rng();
+        for point in inputs {
+            let position = rng.gen_range(0..=proposal.unsigned_tx.input.len());
+            proposal.unsigned_tx.input.insert(position, TxIn { previous_output: *point, script_sig: ScriptBuf::new(), sequence, witness: Witness::new() });
+            proposal.inputs.insert(position, Default::default());
+        }
+        let payment = &mut proposal.unsigned_tx.output[self.payment];
+        payment.value = (payment.value + contributed).checked_sub(needed - from_sender).ok_or(WalletError::InsufficientFunds)?;
+        if let Some(vout) = self.parameters.additional_fee_output {
+            let output = &mut proposal.unsigned_tx.output[vout];
+            output.value = output.value.checked_sub(from_sender).ok_or(WalletError::InsufficientFunds)?;
+            if from_sender > Amount::ZERO && dust::is_dust(output) {
+                return Err(WalletError::Dust(output.value));
+            }
+        }
+
+        let mut transaction = proposal.unsigned_tx.clone();
+        let resolver = |point: &OutPoint| prevouts.get(point).cloned();
+        if master.sign(&mut transaction, EcdsaSighashType::All, &resolver, unlocker)? < inputs.len() {
+            return Err(invalid("can not sign the contributed inputs"));
+        }
+        for (txin, input) in transaction.input.iter().zip(proposal.inputs.iter_mut()) {
+            if inputs.contains(&txin.previous_output) {
+                let spent = prevouts[&txin.previous_output].clone();
+                if spent.script_pubkey.is_p2pkh() {
+                    input.non_witness_utxo = coins.proofs().get(&txin.previous_output.txid).map(|p| p.get_transaction());
+                } else {
+                    input.witness_utxo = Some(spent);
+                }
+                if !txin.script_sig.is_empty() {
+                    input.final_script_sig = Some(txin.script_sig.clone());
+                }
+                if !txin.witness.is_empty() {
+                    input.final_script_witness = Some(txin.witness.clone());
+                }
+            }
+        }
+        Ok(proposal)
+    }
+}
+
+/// body of an error response, code is one of unavailable, not-enough-money, version-unsupported or original-psbt-rejected
+pub fn error_response(code: &str, message: &str) -> String {
+    serde_json::json!({"errorCode": code, "message": message}).to_string()
+}
+
+fn invalid(reason: &str) -> WalletError {
+    WalletError::Payjoin(reason.to_string())
+}
+
+/// check the scripts of a transaction spending the prevouts with libbitcoinconsensus, which does not build for wasm32
+#[cfg(not(target_arch = "wasm32"))]
+fn verify(transaction: &Transaction, prevouts: &HashMap<OutPoint, TxOut>) -> Result<(), WalletError> {
+    transaction.verify(|point| prevouts.get(point).cloned()).map_err(|_| invalid("original is not valid"))
+}
+
+/// fee of a transaction spending the prevouts
+fn fee(prevouts: &HashMap<OutPoint, TxOut>, transaction: &Transaction) -> Result<Amount, WalletError> {
+    let spent = transaction.input.iter()
+        .map(|i| prevouts.get(&i.previous_output).map(|o| o.value))
+        .sum::<Option<Amount>>().ok_or_else(|| invalid("spent output unknown"))?;
+    spent.checked_sub(transaction.output.iter().map(|o| o.value).sum()).ok_or_else(|| invalid("outputs exceed inputs"))
+}
+
+/// fee rate of a fee for weight, rounded down
+fn rate(fee: Amount, weight: u64) -> FeeRate {
+    FeeRate::from_sat_per_kwu(fee.to_sat() * 1000 / weight.max(1))
+}
+
+fn kind(script_pubkey: &Script) -> &'static str {
+    if script_pubkey.is_p2pkh() {
+        "p2pkh"
+    } else if script_pubkey.is_p2sh() {
+        "p2sh"
+    } else if script_pubkey.is_p2wpkh() {
+        "p2wpkh"
+    } else if script_pubkey.is_p2wsh() {
+        "p2wsh"
+    } else if script_pubkey.is_p2tr() {
+        "p2tr"
+    } else {
+        "other"
+    }
+}
+
+/// host of an URL
+fn host(url: &str) -> &str {
+    let rest = url.split_once("://").map(|(_, rest)| rest).unwrap_or(url);
+    let authority = rest.split(['/', '?', '#']).next().unwrap_or_default();
+    authority.rsplit_once(':').map(|(host, _)| host).unwrap_or(authority)
+}
+
+/// POST the body as text to an https or http URL, returns the body of a 200 response
+#[cfg(all(feature = "payjoin", not(feature = "offline")))]
+fn post(url: &str, body: &str, proxy: Option<&Socks5Proxy>) -> Result<String, WalletError> {
+    use std::convert::TryFrom;
+    use std::io::{Read, Write};
+    use std::sync::Arc;
+    use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+    use rustls::pki_types::ServerName;
+
+    fn exchange<S: Read + Write>(mut stream: S, request: &str) -> Result<Vec<u8>, WalletError> {
+        stream.write_all(request.as_bytes())?;
+        stream.flush()?;
+        let mut response = Vec::new();
+        // servers may close without TLS close_notify
+        if let Err(e) = stream.read_to_end(&mut response) {
+            if e.kind() != std::io::ErrorKind::UnexpectedEof {
+                return Err(e.into());
+            }
+        }
+        Ok(response)
+    }
+
+    let tls = url.starts_with("https://");
+    let host = host(url);
+    let rest = url.split_once("://").map(|(_, rest)| rest).unwrap_or(url);
+    let (authority, path) = match rest.find(['/', '?']) {
+        Some(ix) => (&rest[..ix], &rest[ix..]),
+        None => (rest, "/")
+    };
+    let path = if path.starts_with('?') { format!("/{}", path) } else { path.to_string() };
+    let target = if authority.contains(':') { authority.to_string() } else { format!("{}:{}", host, if tls { 443 } else { 80 }) };
+    // HTTP/1.0 responses are not chunked
+    let request = format!("POST {} HTTP/1.0\r\nHost: {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n{}", path, host, body.len(), body);
+    let stream = proxy::connect(&target, proxy)?;
+    let response = if tls {
+        let roots = RootCertStore { roots: webpki_roots::TLS_SERVER_ROOTS.to_vec() };
+        let config = ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions().map_err(|e| invalid(&e.to_string()))?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let name = ServerName::try_from(host.to_string()).map_err(|e| invalid(&e.to_string()))?;
+        let connection = ClientConnection::new(Arc::new(config), name).map_err(|e| invalid(&e.to_string()))?;
+        exchange(StreamOwned::new(connection, stream), &request)?
+    } else {
+        exchange(stream, &request)?
+    };
+    let response = String::from_utf8_lossy(&response);
+    let (head, body) = response.split_once("\r\n\r\n").ok_or_else(|| invalid("invalid HTTP response"))?;
+    if head.split_whitespace().nth(1) == Some("200") {
+        return Ok(body.to_string());
+    }
+    // errors of BIP78 are JSON with errorCode and message
+    match serde_json::from_str::<serde_json::Value>(body) {
+        Ok(json) => Err(WalletError::Payjoin(format!("{}: {}",
+            json["errorCode"].as_str().unwrap_or("unknown"), json["message"].as_str().unwrap_or_default()))),
+        Err(_) => Err(WalletError::Payjoin(head.lines().next().unwrap_or_default().to_string()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{Block, BlockHash, CompactTarget, Network, Sequence, TxMerkleNode, Txid};
+    use bitcoin::absolute::LockTime;
+    use bitcoin::block::Header;
+    use bitcoin::transaction::Version;
+    use bitcoin_hashes::Hash;
+
+    use account::{AccountAddressType, MasterKeyEntropy};
+    use builder::TxBuilder;
+    use super::*;
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+
+    fn funded(value: u64) -> (MasterAccount, Unlocker, Coins, Transaction) {
+        let mut master = MasterAccount::new(MasterKeyEntropy::Low, Network::Bitcoin, PASSPHRASE, None).unwrap();
+        let mut unlocker = Unlocker::new(master.encrypted(), PASSPHRASE, None, Network::Bitcoin, None).unwrap();
+        master.new_keychains(&mut unlocker, AccountAddressType::P2WPKH, 0, 10).unwrap();
+        let script_pubkey = master.next_receive_address(0).unwrap().script_pubkey();
+        let funding = Transaction {
+            version: Version::TWO, lock_time: LockTime::ZERO,
+            input: vec!(TxIn { previous_output: OutPoint { txid: Txid::from_byte_array([value as u8; 32]), vout: 0 }, script_sig: ScriptBuf::new(), sequence: Sequence::MAX, witness: Witness::new() }),
+            output: vec!(TxOut { value: Amount::from_sat(value), script_pubkey })
+        };
+        let block = Block {
+            header: Header { version: bitcoin::block::Version::ONE, prev_blockhash: BlockHash::all_zeros(), merkle_root: TxMerkleNode::all_zeros(), time: 0, bits: CompactTarget::from_consensus(0), nonce: 0 },
+            txdata: vec!(funding.clone())
+        };
+        let mut coins = Coins::new();
+        coins.process(&mut master, &block).unwrap();
+        (master, unlocker, coins, funding)
+    }
+
+    #[test]
+    fn test_payjoin() {
+        let (mut sender, mut sender_unlocker, sender_coins, sender_funding) = funded(100000);
+        let (mut receiver, mut receiver_unlocker, _, receiver_funding) = funded(70000);
+        let address = receiver.next_receive_address(0).unwrap();
+        let request = PaymentRequest::parse(&format!("bitcoin:{}?amount=0.0003&pj=https://example.com/pj", address), Network::Bitcoin).unwrap();
+        assert_eq!(endpoint(&request), Some("https://example.com/pj"));
+        assert!(Sender::new(&sender, &sender_coins, &PaymentRequest::new(address.clone()), Transaction {
+            version: Version::TWO, lock_time: LockTime::ZERO, input: vec!(), output: vec!() }).is_err());
+
+        let original = TxBuilder::new().add_recipient(address.script_pubkey(), 30000).fee_rate(5)
+            .finish_signed(&mut sender, &sender_coins, &mut sender_unlocker).unwrap();
+        let payjoin = Sender::new(&sender, &sender_coins, &request, original.clone()).unwrap();
+        assert_eq!(payjoin.fallback(), &original);
+        let change = original.output.iter().position(|o| o.script_pubkey != address.script_pubkey()).unwrap();
+        let url = payjoin.url();
+        assert!(url.starts_with("https://example.com/pj?v=1&disableoutputsubstitution=true"));
+        assert!(url.contains(&format!("additionalfeeoutputindex={}", change)));
+        let original_psbt = payjoin.original_psbt();
+        assert!(original_psbt.inputs[0].final_script_witness.is_some() && original_psbt.inputs[0].bip32_derivation.is_empty());
+
+        // the receiver adds its coin to the payment, the sender pays the fee of that input from change
+        let mut proposal = original_psbt.clone();
+        proposal.inputs[0].final_script_witness = None;
+        proposal.inputs[0].witness_utxo = None;
+        let point = OutPoint { txid: receiver_funding.compute_txid(), vout: 0 };
+        proposal.unsigned_tx.input.push(TxIn { previous_output: point, script_sig: ScriptBuf::new(), sequence: original.input[0].sequence, witness: Witness::new() });
+        proposal.inputs.push(Default::default());
+        proposal.inputs[1].witness_utxo = Some(receiver_funding.output[0].clone());
+        let payee = 1 - change;
+        proposal.unsigned_tx.output[payee].value += Amount::from_sat(70000);
+        proposal.unsigned_tx.output[change].value -= Amount::from_sat(300);
+        let mut signed = proposal.unsigned_tx.clone();
+        let funding_output = receiver_funding.output[0].clone();
+        receiver.sign(&mut signed, EcdsaSighashType::All, &|p: &OutPoint| if *p == point { Some(funding_output.clone()) } else { None }, &mut receiver_unlocker).unwrap();
+        proposal.inputs[1].final_script_witness = Some(signed.input[1].witness.clone());
+
+        let payjoin_tx = payjoin.process_proposal(&sender, &proposal, &mut sender_unlocker).unwrap();
+        let mut spent = HashMap::new();
+        spent.insert(sender_funding.compute_txid(), sender_funding.clone());
+        spent.insert(receiver_funding.compute_txid(), receiver_funding.clone());
+        payjoin_tx.verify(|p| spent.get(&p.txid).map(|t| t.output[p.vout as usize].clone())).unwrap();
+
+        // invalid proposals
+        let mut reduced = proposal.clone();
+        reduced.unsigned_tx.output[payee].value = Amount::from_sat(20000);
+        assert!(payjoin.process_proposal(&sender, &reduced, &mut sender_unlocker).is_err());
+        let mut greedy = proposal.clone();
+        greedy.unsigned_tx.output[change].value -= Amount::from_sat(5000);
+        greedy.unsigned_tx.output[payee].value += Amount::from_sat(5000);
+        assert!(payjoin.process_proposal(&sender, &greedy, &mut sender_unlocker).is_err());
+        // a generous maximum does not let the receiver charge more than the original fee rate for its input
+        let generous = Sender::new(&sender, &sender_coins, &request, original.clone()).unwrap().max_additional_fee(Amount::from_sat(5000));
+        generous.process_proposal(&sender, &proposal, &mut sender_unlocker).unwrap();
+        let mut overcharging = proposal.clone();
+        overcharging.unsigned_tx.output[change].value -= Amount::from_sat(1000);
+        assert!(matches!(generous.process_proposal(&sender, &overcharging, &mut sender_unlocker),
+            Err(WalletError::Payjoin(ref reason)) if reason.contains("original fee rate")));
+        assert!(payjoin.process_proposal(&sender, original_psbt, &mut sender_unlocker).is_err());
+        let mut unsigned = proposal.clone();
+        unsigned.inputs[1].final_script_witness = None;
+        assert!(payjoin.process_proposal(&sender, &unsigned, &mut sender_unlocker).is_err());
+        let demanding = Sender::new(&sender, &sender_coins, &request, original).unwrap().min_fee_rate(FeeRate::from_sat_per_vb(50).unwrap());
+        assert!(demanding.url().ends_with("&minfeerate=50"));
+        assert!(demanding.process_proposal(&sender, &proposal, &mut sender_unlocker).is_err());
+    }
+
+    #[test]
+    fn test_receiver() {
+        let (mut sender, mut sender_unlocker, sender_coins, sender_funding) = funded(100000);
+        let (mut receiver, mut receiver_unlocker, receiver_coins, receiver_funding) = funded(70000);
+        let address = receiver.next_receive_address(0).unwrap();
+        let request = PaymentRequest::parse(&format!("bitcoin:{}?pj=https://example.com/pj", address), Network::Bitcoin).unwrap();
+        let original = TxBuilder::new().add_recipient(address.script_pubkey(), 30000).fee_rate(5)
+            .finish_signed(&mut sender, &sender_coins, &mut sender_unlocker).unwrap();
+        let payjoin = Sender::new(&sender, &sender_coins, &request, original.clone()).unwrap();
+        let url = payjoin.url();
+        let query = url.split_once('?').unwrap().1;
+
+        let parameters = Parameters::parse(query).unwrap();
+        assert!(parameters.disable_output_substitution && parameters.additional_fee_output.is_some());
+        assert!(Parameters::parse("v=2").is_err());
+        let mut known = HashSet::new();
+        assert!(Receiver::new(&receiver, payjoin.original_psbt().clone(), "v=2", |p| !known.insert(*p)).is_err());
+        let mut unsigned = payjoin.original_psbt().clone();
+        unsigned.inputs[0].final_script_witness = None;
+        assert!(Receiver::new(&receiver, unsigned, query, |p| !known.insert(*p)).is_err());
+        // the sender pays no one but itself
+        assert!(Receiver::new(&sender, payjoin.original_psbt().clone(), query, |p| !known.insert(*p)).is_err());
+
+        assert!(known.is_empty());
+        let checked = Receiver::new(&receiver, payjoin.original_psbt().clone(), query, |p| !known.insert(*p)).unwrap();
+        // a sender probing for coins of the wallet with the same inputs again is rejected
+        assert!(matches!(Receiver::new(&receiver, payjoin.original_psbt().clone(), query, |p| !known.insert(*p)),
+            Err(WalletError::Payjoin(ref reason)) if reason.starts_with("original-psbt-rejected")));
+        assert_eq!(checked.fallback(), &original);
+        let proposal = checked.proposal(&receiver, &receiver_coins, &mut receiver_unlocker).unwrap();
+        assert_eq!(proposal.unsigned_tx.input.len(), 2);
+        let payjoin_tx = payjoin.process_proposal(&sender, &psbt::from_base64(&psbt::to_base64(&proposal)).unwrap(), &mut sender_unlocker).unwrap();
+        let mut spent = HashMap::new();
+        spent.insert(sender_funding.compute_txid(), sender_funding.clone());
+        spent.insert(receiver_funding.compute_txid(), receiver_funding.clone());
+        payjoin_tx.verify(|p| spent.get(&p.txid).map(|t| t.output[p.vout as usize].clone())).unwrap();
+        let paid = payjoin_tx.output.iter().find(|o| o.script_pubkey == address.script_pubkey()).unwrap().value;
+        assert!(paid > Amount::from_sat(30000 + 70000 - 300));
+        assert!(error_response("unavailable", "no coins").contains("\"errorCode\":\"unavailable\""));
+
+        // the fee output of the sender is not cut below the dust limit
+        let original = TxBuilder::new().add_recipient(address.script_pubkey(), 98850).fee_rate(5)
+            .finish_signed(&mut sender, &sender_coins, &mut sender_unlocker).unwrap();
+        let change = original.output.iter().find(|o| o.script_pubkey != address.script_pubkey()).unwrap();
+        assert!(change.value > Amount::from_sat(294) && change.value < Amount::from_sat(500));
+        let payjoin = Sender::new(&sender, &sender_coins, &request, original.clone()).unwrap();
+        let url = payjoin.url();
+        let checked = Receiver::new(&receiver, payjoin.original_psbt().clone(), url.split_once('?').unwrap().1, |_| false).unwrap();
+        assert!(matches!(checked.proposal(&receiver, &receiver_coins, &mut receiver_unlocker), Err(WalletError::Dust(_))));
+    }
+}
@@ -0,0 +1,451 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Payment codes
+//!
+//! BIP47 reusable payment codes, notification transactions and per counterparty address chains
+//!
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+use bitcoin::{base58, consensus, ecdsa, Address, CompressedPublicKey, EcdsaSighashType, Network, NetworkKind, OutPoint,
+              PrivateKey, PublicKey, ScriptBuf, Transaction, TxOut,
+              bip32::{ChainCode, ChildNumber, DerivationPath, Fingerprint, Xpub},
+              script::{Builder, Instruction, PushBytesBuf}, sighash::SighashCache};
+use bitcoin_hashes::{hmac, sha256, sha512, Hash, HashEngine};
+use secp256k1::{Scalar, Secp256k1, SecretKey};
+
+use account::{coin_type, AccountAddressType, MasterAccount, Unlocker};
+use builder::{TxBuilder, TxOrdering};
+use coins::Coins;
+use context::SecpContext;
+use error::WalletError;
+
+/// BIP47 purpose
+pub const PURPOSE: u32 = 47;
+
+/// version of payment codes created here
+pub const VERSION: u8 = 1;
+
+/// value paid to notification addresses
+pub const NOTIFICATION_AMOUNT: u64 = 546;
+
+const PREFIX: u8 = 0x47;
+const LENGTH: usize = 80;
+
+/// a payment code, public key and chain code of m / 47' / coin_type' / account'
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct PaymentCode {
+    public: secp256k1::PublicKey,
+    chain_code: ChainCode
+}
+
+impl PaymentCode {
+    /// the payment code of an account of the master unlocked
+    pub fn new(unlocker: &Unlocker, network: Network, account_number: u32) -> Result<PaymentCode, WalletError> {
+        let key = unlocker.derive(&account_path(network, account_number))?;
+        let public = SecpContext::new().extended_public_from_private(&key);
+        Ok(PaymentCode { public: public.public_key, chain_code: public.chain_code })
+    }
+
+    /// the 80 bytes of a version 1 payment code
+    pub fn from_bytes(bytes: &[u8]) -> Result<PaymentCode, WalletError> {
+        if bytes.len() != LENGTH || bytes[0] != VERSION {
+            return Err(WalletError::PaymentCode("not a version 1 payment code"));
+        }
+        let public = secp256k1::PublicKey::from_slice(&bytes[2..35]).map_err(|_| WalletError::PaymentCode("invalid public key"))?;
+        let chain_code = ChainCode::from(<[u8; 32]>::try_from(&bytes[35..67]).expect("32 bytes"));
+        Ok(PaymentCode { public, chain_code })
+    }
+
+    pub fn to_bytes(&self) -> [u8; LENGTH] {
+        let mut bytes = [0u8; LENGTH];
+        bytes[0] = VERSION;
+        bytes[2..35].copy_from_slice(&self.public.serialize());
+        bytes[35..67].copy_from_slice(self.chain_code.as_bytes());
+        bytes
+    }
+
+    /// the public key of child i
+    pub fn derive(&self, i: u32) -> Result<PublicKey, WalletError> {
+        let xpub = Xpub {
+            network: NetworkKind::Main,
+            depth: 3,
+            parent_fingerprint: Fingerprint::default(),
+            child_number: ChildNumber::Hardened { index: 0 },
+            public_key: self.public,
+            chain_code: self.chain_code
+        };
+        Ok(PublicKey::new(SecpContext::new().public_child(&xpub, ChildNumber::Normal { index: i })?.public_key))
+    }
+
+    /// the key of child 0 that others notify
+    pub fn notification_key(&self) -> Result<PublicKey, WalletError> {
+        self.derive(0)
+    }
+
+    pub fn notification_address(&self, network: Network) -> Result<Address, WalletError> {
+        Ok(Address::p2pkh(self.notification_key()?, network))
+    }
+}
+
+impl fmt::Display for PaymentCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut data = vec!(PREFIX);
+        data.extend_from_slice(&self.to_bytes());
+        write!(f, "{}", base58::encode_check(&data))
+    }
+}
+
+impl FromStr for PaymentCode {
+    type Err = WalletError;
+
+    fn from_str(s: &str) -> Result<PaymentCode, WalletError> {
+        let data = base58::decode_check(s).map_err(|_| WalletError::PaymentCode("invalid base58"))?;
+        if data.first() != Some(&PREFIX) {
+            return Err(WalletError::PaymentCode("not a payment code"));
+        }
+        PaymentCode::from_bytes(&data[1..])
+    }
+}
+
+/// m / 47' / coin_type' / account'
+pub fn account_path(network: Network, account_number: u32) -> DerivationPath {
+    DerivationPath::from(vec!(
+        ChildNumber::Hardened { index: PURPOSE },
+        ChildNumber::Hardened { index: coin_type(network) },
+        ChildNumber::Hardened { index: account_number }))
+}
+
+/// the tweak of a payment key, SHA256 of the x coordinate of the shared point
+pub fn shared_secret(secret: &SecretKey, public: &PublicKey) -> Result<SecretKey, WalletError> {
+    let point = public.inner.mul_tweak(&Secp256k1::verification_only(), &Scalar::from(*secret))?;
+    SecretKey::from_slice(sha256::Hash::hash(&point.serialize()[1..33]).as_ref())
+        .map_err(|_| WalletError::PaymentCode("shared secret is not a valid key"))
+}
+
+fn blinding_factor(secret: &SecretKey, public: &PublicKey, outpoint: &OutPoint) -> Result<[u8; 64], WalletError> {
+    let point = public.inner.mul_tweak(&Secp256k1::verification_only(), &Scalar::from(*secret))?;
+    let mut engine = hmac::HmacEngine::<sha512::Hash>::new(&consensus::serialize(outpoint));
+    engine.input(&point.serialize()[1..33]);
+    Ok(hmac::Hmac::<sha512::Hash>::from_engine(engine).to_byte_array())
+}
+
+fn blind(code: &mut [u8; LENGTH], factor: &[u8; 64]) {
+    for (b, f) in code[3..67].iter_mut().zip(factor.iter()) {
+        *b ^= f;
+    }
+}
+
+/// the first public key an input of the transaction exposes, with the outpoint it spends
+fn designated(transaction: &Transaction) -> Option<(OutPoint, PublicKey)> {
+    transaction.input.iter().find_map(|input| {
+        let key = if input.witness.len() == 2 {
+            input.witness.nth(1).map(|k| k.to_vec())
+        } else {
+            input.script_sig.instructions().last().and_then(|i| match i {
+                Ok(Instruction::PushBytes(push)) => Some(push.as_bytes().to_vec()),
+                _ => None
+            })
+        };
+        key.and_then(|k| PublicKey::from_slice(&k).ok()).map(|k| (input.previous_output, k))
+    })
+}
+
+/// a key receiving payments of a counterparty
+#[derive(Clone, Debug)]
+pub struct ReceiveKey {
+    pub index: u32,
+    /// the shared secret added to child index of the own payment code
+    pub tweak: Vec<u8>,
+    pub public: PublicKey,
+    pub address: Address,
+    pub script_pubkey: ScriptBuf
+}
+
+/// payments with a counterparty
+#[derive(Clone, Debug)]
+pub struct Channel {
+    pub code: PaymentCode,
+    /// keys of payments the counterparty makes, known once it notified
+    pub receive: Vec<ReceiveKey>,
+    /// number of addresses handed out to pay the counterparty
+    pub sent: u32,
+    /// the counterparty was sent a notification
+    pub notified: bool
+}
+
+/// the reusable payment code of an account and its channels
+pub struct PaymentCodeAccount {
+    account_number: u32,
+    address_type: AccountAddressType,
+    network: Network,
+    look_ahead: u32,
+    code: PaymentCode,
+    channels: Vec<Channel>,
+    context: SecpContext
+}
+
+impl PaymentCodeAccount {
+    /// address_type is the type of the payment addresses, P2PKH as specified, or the
+    /// P2SHWPKH or P2WPKH addresses of the same keys some wallets use
+    pub fn new(unlocker: &Unlocker, address_type: AccountAddressType, account_number: u32, look_ahead: u32, network: Network) -> Result<PaymentCodeAccount, WalletError> {
+        match address_type {
+            AccountAddressType::P2PKH | AccountAddressType::P2SHWPKH | AccountAddressType::P2WPKH => {}
+            _ => return Err(WalletError::Unsupported("payment codes pay to P2PKH, P2SHWPKH or P2WPKH"))
+        }
+        let code = PaymentCode::new(unlocker, network, account_number)?;
+        Ok(PaymentCodeAccount { account_number, address_type, network, look_ahead, code, channels: Vec::new(), context: SecpContext::new() })
+    }
+
+    pub fn payment_code(&self) -> &PaymentCode {
+        &self.code
+    }
+
+    pub fn notification_address(&self) -> Result<Address, WalletError> {
+        self.code.notification_address(self.network)
+    }
+
+    pub fn channels(&self) -> &Vec<Channel> {
+        &self.channels
+    }
+
+    pub fn channel(&self, code: &PaymentCode) -> Option<&Channel> {
+        self.channels.iter().find(|c| c.code == *code)
+    }
+
+    fn channel_mut(&mut self, code: &PaymentCode) -> &mut Channel {
+        if let Some(pos) = self.channels.iter().position(|c| c.code == *code) {
+            return &mut self.channels[pos];
+        }
+        self.channels.push(Channel { code: *code, receive: Vec::new(), sent: 0, notified: false });
+        self.channels.last_mut().expect("just pushed")
+    }
+
+    /// the private key of child i of the own payment code
+    fn private(&self, unlocker: &Unlocker, i: u32) -> Result<PrivateKey, WalletError> {
+        let path = account_path(self.network, self.account_number).child(ChildNumber::Normal { index: i });
+        Ok(unlocker.derive(&path)?.to_priv())
+    }
+
+    fn address(&self, public: &PublicKey) -> Result<Address, WalletError> {
+        let compressed = CompressedPublicKey::try_from(*public).map_err(|_| WalletError::Unsupported("segwit needs compressed keys"))?;
+        Ok(match self.address_type {
+            AccountAddressType::P2SHWPKH => Address::p2shwpkh(&compressed, self.network),
+            AccountAddressType::P2WPKH => Address::p2wpkh(&compressed, self.network),
+            _ => Address::p2pkh(public, self.network)
+        })
+    }
+
+    /// the next address to pay the owner of a payment code, after it was notified
+    pub fn next_send_address(&mut self, to: &PaymentCode, unlocker: &Unlocker) -> Result<Address, WalletError> {
+        let secret = self.private(unlocker, 0)?;
+        let i = self.channel(to).map(|c| c.sent).unwrap_or(0);
+        let mut public = to.derive(i)?;
+        let tweak = shared_secret(&secret.inner, &public)?;
+        self.context.tweak_exp_add(&mut public, &tweak.secret_bytes())?;
+        let address = self.address(&public)?;
+        self.channel_mut(to).sent = i + 1;
+        Ok(address)
+    }
+
+    /// a transaction that pays the notification address of a payment code and tells its owner the own payment code,
+    /// blinded with the key of the designated input, an owned coin that is spent first.
+    /// builder sets fee rate and change of the transaction.
+    pub fn notification_transaction(&mut self, to: &PaymentCode, designated: OutPoint, builder: TxBuilder,
+                                    master: &mut MasterAccount, coins: &Coins, unlocker: &mut Unlocker) -> Result<Transaction, WalletError> {
+        let coin = coins.owned().get(&designated).ok_or(WalletError::UnknownCoin(designated))?;
+        let d = &coin.derivation;
+        let account = master.get((d.account, d.sub)).ok_or(WalletError::Unsupported("no account of the designated coin"))?;
+        let key = account.unlock(unlocker, d.kix, d.tweak.clone())?;
+
+        let mut code = self.code.to_bytes();
+        blind(&mut code, &blinding_factor(&key.inner, &to.notification_key()?, &designated)?);
+        let payload = Builder::new().push_opcode(bitcoin::opcodes::all::OP_RETURN)
+            .push_slice(PushBytesBuf::try_from(code.to_vec()).expect("80 bytes")).into_script();
+        let transaction = builder.ordering(TxOrdering::Untouched).add_utxo(designated)
+            .add_recipient(to.notification_address(self.network)?.script_pubkey(), NOTIFICATION_AMOUNT)
+            .add_recipient(payload, 0)
+            .finish_signed(master, coins, unlocker)?;
+        if transaction.input[0].previous_output != designated {
+            return Err(WalletError::PaymentCode("designated input is not spent first"));
+        }
+        self.channel_mut(to).notified = true;
+        Ok(transaction)
+    }
+
+    /// read a notification transaction paying the own notification address and
+    /// instantiate the keys the sender will pay to. returns the payment code of the sender
+    pub fn process_notification(&mut self, transaction: &Transaction, unlocker: &Unlocker) -> Result<Option<PaymentCode>, WalletError> {
+        let notification = self.notification_address()?.script_pubkey();
+        if !transaction.output.iter().any(|o| o.script_pubkey == notification) {
+            return Ok(None);
+        }
+        let mut code = match transaction.output.iter().find_map(|o| {
+            let mut instructions = o.script_pubkey.instructions();
+            match (instructions.next(), instructions.next()) {
+                (Some(Ok(Instruction::Op(bitcoin::opcodes::all::OP_RETURN))), Some(Ok(Instruction::PushBytes(push)))) if push.len() == LENGTH =>
+                    Some(<[u8; LENGTH]>::try_from(push.as_bytes()).expect("80 bytes")),
+                _ => None
+            }
+        }) {
+            Some(code) => code,
+            None => return Ok(None)
+        };
+        let (outpoint, public) = designated(transaction).ok_or(WalletError::PaymentCode("notification exposes no public key"))?;
+        let secret = self.private(unlocker, 0)?;
+        blind(&mut code, &blinding_factor(&secret.inner, &public, &outpoint)?);
+        let from = PaymentCode::from_bytes(&code)?;
+        let have = self.channel(&from).map(|c| c.receive.len() as u32).unwrap_or(0);
+        self.look_ahead(&from, have + self.look_ahead, unlocker)?;
+        Ok(Some(from))
+    }
+
+    /// have at least n keys to receive from the owner of a payment code
+    pub fn look_ahead(&mut self, from: &PaymentCode, n: u32, unlocker: &Unlocker) -> Result<(), WalletError> {
+        let their = from.notification_key()?;
+        let mut new = Vec::new();
+        for index in self.channel(from).map(|c| c.receive.len() as u32).unwrap_or(0)..n {
+            let tweak = shared_secret(&self.private(unlocker, index)?.inner, &their)?.secret_bytes().to_vec();
+            let mut public = self.code.derive(index)?;
+            self.context.tweak_exp_add(&mut public, &tweak)?;
+            let address = self.address(&public)?;
+            new.push(ReceiveKey { index, tweak, public, script_pubkey: address.script_pubkey(), address });
+        }
+        self.channel_mut(from).receive.extend(new);
+        Ok(())
+    }
+
+    /// scripts of all keys receiving from counterparties
+    pub fn get_scripts<'a>(&'a self) -> impl Iterator<Item=(&'a PaymentCode, &'a ReceiveKey)> + 'a {
+        self.channels.iter().flat_map(|c| c.receive.iter().map(move |k| (&c.code, k)))
+    }
+
+    /// sign inputs spending coins received from counterparties
+    pub fn sign<R>(&self, transaction: &mut Transaction, hash_type: EcdsaSighashType, resolver: R, unlocker: &Unlocker) -> Result<usize, WalletError>
+        where R: Fn(&OutPoint) -> Option<TxOut> {
+        let mut signed = 0;
+        let txclone = transaction.clone();
+        let mut hasher = SighashCache::new(&txclone);
+        for (ix, input) in transaction.input.iter_mut().enumerate() {
+            let spend = match resolver(&input.previous_output) {
+                Some(spend) => spend,
+                None => continue
+            };
+            let key = match self.get_scripts().find(|(_, k)| k.script_pubkey == spend.script_pubkey) {
+                Some((_, key)) => key,
+                None => continue
+            };
+            let mut pk = self.private(unlocker, key.index)?;
+            self.context.tweak_add(&mut pk, &key.tweak)?;
+            let script_code = ScriptBuf::new_p2pkh(&key.public.pubkey_hash());
+            if self.address_type == AccountAddressType::P2PKH {
+                let sighash = hasher.legacy_signature_hash(ix, &key.script_pubkey, hash_type.to_u32()).expect("input index is in range");
+                let signature = ecdsa::Signature { signature: unlocker.context().sign(&sighash[..], &pk)?, sighash_type: hash_type };
+                input.script_sig = Builder::new().push_slice(signature.serialize()).push_key(&key.public).into_script();
+            } else {
+                let sighash = hasher.p2wsh_signature_hash(ix, &script_code, spend.value, hash_type).expect("input index is in range");
+                let signature = ecdsa::Signature { signature: unlocker.context().sign(&sighash[..], &pk)?, sighash_type: hash_type };
+                input.script_sig = if self.address_type == AccountAddressType::P2SHWPKH {
+                    let redeem_script = ScriptBuf::new_p2wpkh(&key.public.wpubkey_hash().expect("compressed key"));
+                    Builder::new().push_slice(PushBytesBuf::try_from(redeem_script.into_bytes()).expect("short script")).into_script()
+                } else {
+                    ScriptBuf::new()
+                };
+                input.witness.push(signature.serialize());
+                input.witness.push(key.public.to_bytes());
+            }
+            signed += 1;
+        }
+        Ok(signed)
+    }
+
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{Amount, Block, BlockHash, CompactTarget, Sequence, TxIn, TxMerkleNode, Txid, Witness};
+    use bitcoin::absolute::LockTime;
+    use bitcoin::block::Header;
+    use bitcoin::transaction::Version;
+
+    use mnemonic::Mnemonic;
+    use super::*;
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+
+    fn unlocker(words: &str) -> (MasterAccount, Unlocker) {
+        let master = MasterAccount::from_mnemonic(&Mnemonic::from_str(words).unwrap(), 0, Network::Bitcoin, PASSPHRASE, None).unwrap();
+        let unlocker = Unlocker::new(master.encrypted(), PASSPHRASE, None, Network::Bitcoin, None).unwrap();
+        (master, unlocker)
+    }
+
+    #[test]
+    fn test_bip47() {
+        // test vectors of BIP47
+        let (mut alice_master, mut alice_unlocker) = unlocker("response seminar brave tip suit recall often sound stick owner lottery motion");
+        let (_, bob_unlocker) = unlocker("reward upper indicate eight swift arch injury crystal super wrestle already dentist");
+        let mut alice = PaymentCodeAccount::new(&alice_unlocker, AccountAddressType::P2PKH, 0, 10, Network::Bitcoin).unwrap();
+        let mut bob = PaymentCodeAccount::new(&bob_unlocker, AccountAddressType::P2PKH, 0, 10, Network::Bitcoin).unwrap();
+        assert_eq!(alice.payment_code().to_string(), "PM8TJTLJbPRGxSbc8EJi42Wrr6QbNSaSSVJ5Y3E4pbCYiTHUskHg13935Ubb7q8tx9GVbh2UuRnBc3WSyJHhUrw8KhprKnn9eDznYGieTzFcwQRya4GA");
+        assert_eq!(alice.notification_address().unwrap().to_string(), "1JDdmqFLhpzcUwPeinhJbUPw4Co3aWLyzW");
+        assert_eq!(bob.notification_address().unwrap().to_string(), "1ChvUUvht2hUQufHBXF8NgLhW8SwE2ecGV");
+        let bob_code = PaymentCode::from_str(&bob.payment_code().to_string()).unwrap();
+        assert_eq!(bob_code, *bob.payment_code());
+
+        let first = alice.next_send_address(&bob_code, &alice_unlocker).unwrap();
+        assert_eq!(first.to_string(), "141fi7TY3h936vRUKh1qfUZr8rSBuYbVBK");
+        assert_eq!(alice.next_send_address(&bob_code, &alice_unlocker).unwrap().to_string(), "12u3Uued2fuko2nY4SoSFGCoGLCBUGPkk6");
+
+        // alice notifies bob spending a coin of hers
+        let account = alice_master.new_account(&mut alice_unlocker, AccountAddressType::P2WPKH, 0, 0, 10).unwrap();
+        let funding = Transaction {
+            version: Version::ONE,
+            lock_time: LockTime::ZERO,
+            input: vec!(TxIn { previous_output: OutPoint { txid: Txid::all_zeros(), vout: 0 }, script_sig: ScriptBuf::new(), sequence: Sequence::MAX, witness: Witness::new() }),
+            output: vec!(TxOut { value: Amount::from_sat(100000), script_pubkey: account.get_key(0).unwrap().script_pubkey.clone() })
+        };
+        let block = Block {
+            header: Header { version: bitcoin::block::Version::ONE, prev_blockhash: BlockHash::all_zeros(), merkle_root: TxMerkleNode::all_zeros(),
+                time: 0, bits: CompactTarget::from_consensus(0), nonce: 0 },
+            txdata: vec!(funding.clone())
+        };
+        let mut coins = Coins::new();
+        coins.process(&mut alice_master, &block).unwrap();
+        let designated = OutPoint { txid: funding.compute_txid(), vout: 0 };
+        let notification = alice.notification_transaction(&bob_code, designated, TxBuilder::new().change_account(0, 0),
+                                                          &mut alice_master, &coins, &mut alice_unlocker).unwrap();
+        assert!(alice.channel(&bob_code).unwrap().notified);
+        assert_eq!(bob.process_notification(&notification, &bob_unlocker).unwrap(), Some(*alice.payment_code()));
+        assert_eq!(bob.process_notification(&funding, &bob_unlocker).unwrap(), None);
+
+        // bob watches and spends what alice pays to him
+        let channel = bob.channel(alice.payment_code()).unwrap();
+        assert_eq!(channel.receive.len(), 10);
+        assert_eq!(channel.receive[0].address, first);
+        let payment = TxOut { value: Amount::from_sat(50000), script_pubkey: first.script_pubkey() };
+        let mut spend = Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec!(TxIn { previous_output: OutPoint { txid: Txid::all_zeros(), vout: 1 }, script_sig: ScriptBuf::new(), sequence: Sequence::MAX, witness: Witness::new() }),
+            output: vec!(TxOut { value: Amount::from_sat(49000), script_pubkey: ScriptBuf::new_op_return([0u8; 4]) })
+        };
+        assert_eq!(bob.sign(&mut spend, EcdsaSighashType::All, |_| Some(payment.clone()), &bob_unlocker).unwrap(), 1);
+        spend.verify(|_| Some(payment.clone())).unwrap();
+    }
+}
@@ -0,0 +1,173 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Spending policies
+//!
+//! Compile policies to Miniscript descriptors, derive their addresses and satisfy them
+//!
+
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use bitcoin::{Address, EcdsaSighashType, Network, PrivateKey, ScriptBuf, Transaction, TxOut, ecdsa};
+use bitcoin::bip32::Xpriv;
+use bitcoin::sighash::SighashCache;
+use bitcoin_hashes::Hash;
+use miniscript::{Descriptor, DescriptorPublicKey, ForEachKey, Segwitv0};
+use miniscript::policy::Concrete;
+use secp256k1::Secp256k1;
+
+use context::SecpContext;
+use error::WalletError;
+
+/// a P2WSH descriptor of a spending policy, keys are extended public keys ending in a wildcard
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CompiledPolicy {
+    descriptor: Descriptor<DescriptorPublicKey>
+}
+
+impl CompiledPolicy {
+    /// compile a policy of the Miniscript policy language, older counts blocks, 144 a day.
+    /// e.g. 2 of 3 keys, or 1 key after 90 days: or(thresh(2,pk(A),pk(B),pk(C)),and(pk(D),older(12960)))
+    pub fn compile(policy: &str) -> Result<CompiledPolicy, WalletError> {
+        let policy = Concrete::<DescriptorPublicKey>::from_str(policy).map_err(|e| WalletError::Policy(e.to_string()))?;
+        let miniscript = policy.compile::<Segwitv0>().map_err(|e| WalletError::Policy(e.to_string()))?;
+        Ok(CompiledPolicy { descriptor: Descriptor::new_wsh(miniscript).map_err(|e| WalletError::Policy(e.to_string()))? })
+    }
+
+    /// a descriptor compiled earlier
+    pub fn from_descriptor(descriptor: &str) -> Result<CompiledPolicy, WalletError> {
+        let descriptor = Descriptor::<DescriptorPublicKey>::from_str(descriptor).map_err(|e| WalletError::Policy(e.to_string()))?;
+        match descriptor {
+            Descriptor::Wsh(_) => Ok(CompiledPolicy { descriptor }),
+            _ => Err(WalletError::Policy("only wsh descriptors are supported".to_string()))
+        }
+    }
+
+    pub fn descriptor(&self) -> &Descriptor<DescriptorPublicKey> {
+        &self.descriptor
+    }
+
+    /// script at derivation index
+    pub fn script_pubkey(&self, index: u32) -> Result<ScriptBuf, WalletError> {
+        Ok(self.derived(index)?.script_pubkey())
+    }
+
+    /// address at derivation index
+    pub fn address(&self, index: u32, network: Network) -> Result<Address, WalletError> {
+        self.derived(index)?.address(network).map_err(|e| WalletError::Policy(e.to_string()))
+    }
+
+    /// weight of the largest witness satisfying the policy, for fee estimation
+    pub fn max_satisfaction_weight(&self) -> Result<u64, WalletError> {
+        Ok(self.descriptor.max_weight_to_satisfy().map_err(|e| WalletError::Policy(e.to_string()))?.to_wu())
+    }
+
+    /// sign an input spending the output at derivation index with the keys the master keys derive,
+    /// then satisfy the policy with those signatures, the input's sequence and the lock time of the transaction.
+    /// Fails if they do not satisfy the policy. Returns the number of signatures.
+    pub fn sign(&self, transaction: &mut Transaction, input: usize, spent: &TxOut, index: u32, keys: &[Xpriv]) -> Result<usize, WalletError> {
+        let secp = Secp256k1::new();
+        let context = SecpContext::new();
+        let definite = self.descriptor.at_derivation_index(index).map_err(|e| WalletError::Policy(e.to_string()))?;
+        let derived = self.derived(index)?;
+        if derived.script_pubkey() != spent.script_pubkey {
+            return Err(WalletError::Policy("spent output is not of the policy at index".to_string()));
+        }
+        let script_code = derived.script_code().map_err(|e| WalletError::Policy(e.to_string()))?;
+        let sighash = SighashCache::new(&*transaction).p2wsh_signature_hash(input, &script_code, spent.value, EcdsaSighashType::All)
+            .map_err(|e| WalletError::Policy(e.to_string()))?;
+
+        let mut privates = Vec::new();
+        definite.for_each_key(|key| {
+            if let (Some(path), Ok(public)) = (key.full_derivation_path(), key.derive_public_key(&secp)) {
+                for master in keys.iter().filter(|k| k.fingerprint(&secp) == key.master_fingerprint()) {
+                    if let Ok(private) = master.derive_priv(&secp, &path) {
+                        if private.private_key.public_key(&secp) == public.inner {
+                            privates.push((public, PrivateKey::new(private.private_key, Network::Bitcoin)));
+                        }
+                    }
+                }
+            }
+            true
+        });
+        let mut signatures = HashMap::new();
+        for (public, private) in privates {
+            let signature = context.sign(&sighash.to_byte_array(), &private)?;
+            signatures.insert(public, ecdsa::Signature { signature, sighash_type: EcdsaSighashType::All });
+        }
+
+        let mut txin = transaction.input.get(input).cloned().ok_or(WalletError::Unsupported("no such input"))?;
+        let sequence = txin.sequence;
+        derived.satisfy(&mut txin, (&signatures, sequence, transaction.lock_time))
+            .map_err(|e| WalletError::Policy(format!("can not satisfy the policy: {}", e)))?;
+        transaction.input[input] = txin;
+        Ok(signatures.len())
+    }
+
+    fn derived(&self, index: u32) -> Result<Descriptor<bitcoin::PublicKey>, WalletError> {
+        self.descriptor.derived_descriptor(&Secp256k1::verification_only(), index).map_err(|e| WalletError::Policy(e.to_string()))
+    }
+}
+
+impl fmt::Display for CompiledPolicy {
+    /// the descriptor with checksum
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.descriptor)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{Amount, OutPoint, Sequence, TxIn, Txid, Witness};
+    use bitcoin::absolute::LockTime;
+    use bitcoin::bip32::Xpub;
+    use bitcoin::transaction::Version;
+
+    use super::*;
+
+    #[test]
+    fn test_policy() {
+        let secp = Secp256k1::new();
+        let masters = (1u8..5).map(|i| Xpriv::new_master(Network::Bitcoin, &[i; 32]).unwrap()).collect::<Vec<_>>();
+        let keys = masters.iter().map(|m| format!("{}/0/*", Xpub::from_priv(&secp, m))).collect::<Vec<_>>();
+        let policy = CompiledPolicy::compile(&format!("or(99@thresh(2,pk({}),pk({}),pk({})),1@and(pk({}),older(12960)))", keys[0], keys[1], keys[2], keys[3])).unwrap();
+        assert_eq!(CompiledPolicy::from_descriptor(&policy.to_string()).unwrap(), policy);
+        assert!(CompiledPolicy::compile("thresh(2,pk(A)").is_err());
+        assert_ne!(policy.address(0, Network::Bitcoin).unwrap(), policy.address(1, Network::Bitcoin).unwrap());
+        assert!(policy.max_satisfaction_weight().unwrap() > 0);
+
+        let spent = TxOut { value: Amount::from_sat(100000), script_pubkey: policy.script_pubkey(5).unwrap() };
+        let unsigned = Transaction {
+            version: Version::TWO, lock_time: LockTime::ZERO,
+            input: vec!(TxIn { previous_output: OutPoint { txid: Txid::all_zeros(), vout: 0 }, script_sig: ScriptBuf::new(), sequence: Sequence::ENABLE_RBF_NO_LOCKTIME, witness: Witness::new() }),
+            output: vec!(TxOut { value: Amount::from_sat(99000), script_pubkey: ScriptBuf::new_op_return([0u8; 4]) })
+        };
+        // two of the three keys
+        let mut transaction = unsigned.clone();
+        assert_eq!(policy.sign(&mut transaction, 0, &spent, 5, &masters[..2]).unwrap(), 2);
+        transaction.verify(|_| Some(spent.clone())).unwrap();
+        let mut transaction = unsigned.clone();
+        assert!(policy.sign(&mut transaction, 0, &spent, 4, &masters[..2]).is_err());
+        assert!(policy.sign(&mut transaction, 0, &spent, 5, &masters[..1]).is_err());
+        // the recovery key only after 90 days
+        assert!(policy.sign(&mut transaction, 0, &spent, 5, &masters[3..]).is_err());
+        transaction.input[0].sequence = Sequence::from_height(12960);
+        assert_eq!(policy.sign(&mut transaction, 0, &spent, 5, &masters[3..]).unwrap(), 1);
+        transaction.verify(|_| Some(spent.clone())).unwrap();
+    }
+}
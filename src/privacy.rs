@@ -0,0 +1,281 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Privacy report
+//!
+//! Score a draft transaction for what it tells observers before it is broadcast
+//!
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use bitcoin::{OutPoint, Psbt, Script, Transaction, TxOut};
+
+use account::{AddressStatus, MasterAccount};
+
+/// amounts that are multiples of this many satoshis look like payments
+pub const ROUND_AMOUNT: u64 = 10_000;
+
+/// how much a warning costs the score
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Severity {
+    Low,
+    Medium,
+    High
+}
+
+impl Severity {
+    fn penalty(self) -> u8 {
+        match self {
+            Severity::Low => 10,
+            Severity::Medium => 20,
+            Severity::High => 30
+        }
+    }
+}
+
+/// why an observer would take an output for change
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ChangeHint {
+    /// the only output of the script type of the inputs
+    ScriptType,
+    /// the only amount that is not round, payments are
+    RoundPayment,
+    /// smaller than the smallest input while the payments are not, were a payment change an input was not needed
+    UnnecessaryInput
+}
+
+/// a privacy leak of a transaction
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Warning {
+    /// an output pays an own address that already received funds
+    ReusedOutput { vout: usize },
+    /// several inputs spend from the same script, or from one that received several times
+    ReusedInput { input: usize },
+    /// a payment of a round amount, other outputs are likely change
+    RoundAmount { vout: usize },
+    /// inputs of several script types, likely a wallet holding all of them
+    MixedInputTypes,
+    /// an own output an observer takes for change
+    ObviousChange { vout: usize, hint: ChangeHint }
+}
+
+impl Warning {
+    pub fn severity(&self) -> Severity {
+        match self {
+            Warning::ReusedOutput { .. } | Warning::ReusedInput { .. } => Severity::High,
+            Warning::MixedInputTypes | Warning::ObviousChange { .. } => Severity::Medium,
+            Warning::RoundAmount { .. } => Severity::Low
+        }
+    }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Warning::ReusedOutput { vout } => write!(f, "output {} pays an address that already received funds", vout),
+            Warning::ReusedInput { input } => write!(f, "input {} spends from a reused address", input),
+            Warning::RoundAmount { vout } => write!(f, "output {} pays a round amount", vout),
+            Warning::MixedInputTypes => write!(f, "inputs of several address types link them to one wallet"),
+            Warning::ObviousChange { vout, hint } => write!(f, "output {} is recognizable as change: {}", vout, match hint {
+                ChangeHint::ScriptType => "the only one of the address type of the inputs",
+                ChangeHint::RoundPayment => "the only amount that is not round",
+                ChangeHint::UnnecessaryInput => "an input was not needed for the payments"
+            })
+        }
+    }
+}
+
+/// warnings and a score of a transaction, 100 for none of them
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Report {
+    pub warnings: Vec<Warning>,
+    pub score: u8
+}
+
+impl Report {
+    /// warnings of at least a severity
+    pub fn at_least(&self, severity: Severity) -> impl Iterator<Item=&Warning> {
+        self.warnings.iter().filter(move |w| w.severity() >= severity)
+    }
+}
+
+/// analyze a transaction spending outputs the resolver knows, e.g. owned coins.
+/// Outputs to scripts of the master are change, others payments.
+pub fn analyze<R>(master: &MasterAccount, transaction: &Transaction, resolver: R) -> Report
+    where R: Fn(&OutPoint) -> Option<TxOut> {
+    let mut warnings = Vec::new();
+    let spent = transaction.input.iter().map(|i| resolver(&i.previous_output)).collect::<Vec<_>>();
+
+    let mut scripts = HashSet::new();
+    for (input, output) in spent.iter().enumerate() {
+        if let Some(output) = output {
+            if !scripts.insert(output.script_pubkey.clone()) || master.address_status(&output.script_pubkey) == Some(AddressStatus::Reused) {
+                warnings.push(Warning::ReusedInput { input });
+            }
+        }
+    }
+    let input_types = spent.iter().flatten().map(|o| script_type(&o.script_pubkey)).collect::<HashSet<_>>();
+    if input_types.len() > 1 {
+        warnings.push(Warning::MixedInputTypes);
+    }
+
+    let mut own = Vec::new();
+    let mut payments = Vec::new();
+    for (vout, output) in transaction.output.iter().enumerate() {
+        if output.script_pubkey.is_op_return() {
+            continue;
+        }
+        match master.address_status(&output.script_pubkey) {
+            Some(status) => {
+                if status != AddressStatus::Fresh {
+                    warnings.push(Warning::ReusedOutput { vout });
+                }
+                own.push(vout);
+            }
+            None => payments.push(vout)
+        }
+    }
+    let round = |vout: &usize| transaction.output[*vout].value.to_sat().is_multiple_of(ROUND_AMOUNT);
+    for vout in payments.iter().filter(|v| round(v)) {
+        warnings.push(Warning::RoundAmount { vout: *vout });
+    }
+
+    if !payments.is_empty() {
+        let of_input_type = |vout: &usize| input_types.len() == 1 && input_types.contains(&script_type(&transaction.output[*vout].script_pubkey));
+        let smallest_input = spent.iter().flatten().map(|o| o.value.to_sat()).min();
+        let below_inputs = |vout: &usize| smallest_input.is_some_and(|s| transaction.output[*vout].value.to_sat() < s);
+        let mut hints = HashMap::new();
+        for vout in own.iter() {
+            let mut add = |hint| hints.entry(*vout).or_insert_with(Vec::new).push(hint);
+            if of_input_type(vout) && !payments.iter().any(of_input_type) && own.iter().filter(|v| of_input_type(v)).count() == 1 {
+                add(ChangeHint::ScriptType);
+            }
+            if payments.iter().all(round) && !round(vout) && own.iter().filter(|v| !round(v)).count() == 1 {
+                add(ChangeHint::RoundPayment);
+            }
+            if spent.len() > 1 && below_inputs(vout) && !payments.iter().any(below_inputs) {
+                add(ChangeHint::UnnecessaryInput);
+            }
+        }
+        for vout in own.iter() {
+            for hint in hints.remove(vout).unwrap_or_default() {
+                warnings.push(Warning::ObviousChange { vout: *vout, hint });
+            }
+        }
+    }
+
+    let penalty = warnings.iter().map(|w| w.severity().penalty() as u32).sum::<u32>();
+    Report { score: 100u32.saturating_sub(penalty) as u8, warnings }
+}
+
+/// analyze the unsigned transaction of a PSBT with the spent outputs it carries
+pub fn analyze_psbt(master: &MasterAccount, psbt: &Psbt) -> Report {
+    let spent = psbt.unsigned_tx.input.iter().zip(psbt.inputs.iter()).filter_map(|(txin, input)| {
+        let point = txin.previous_output;
+        input.witness_utxo.clone()
+            .or_else(|| input.non_witness_utxo.as_ref().and_then(|t| t.output.get(point.vout as usize).cloned()))
+            .map(|output| (point, output))
+    }).collect::<HashMap<_, _>>();
+    analyze(master, &psbt.unsigned_tx, |point| spent.get(point).cloned())
+}
+
+/// the kind of a script observers tell apart
+fn script_type(script: &Script) -> &'static str {
+    if script.is_p2pkh() {
+        "p2pkh"
+    } else if script.is_p2sh() {
+        "p2sh"
+    } else if script.is_p2wpkh() {
+        "p2wpkh"
+    } else if script.is_p2wsh() {
+        "p2wsh"
+    } else if script.is_p2tr() {
+        "p2tr"
+    } else {
+        "other"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{Amount, Network, ScriptBuf, Sequence, TxIn, Txid, WPubkeyHash, Witness};
+    use bitcoin::absolute::LockTime;
+    use bitcoin::transaction::Version;
+    use bitcoin_hashes::Hash;
+
+    use account::{AccountAddressType, MasterKeyEntropy, Unlocker, EXTERNAL, INTERNAL};
+    use super::*;
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+
+    fn spend(inputs: u32, output: Vec<TxOut>) -> Transaction {
+        Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: (0..inputs).map(|vout| TxIn {
+                previous_output: OutPoint { txid: Txid::all_zeros(), vout },
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::new()
+            }).collect(),
+            output
+        }
+    }
+
+    #[test]
+    fn test_privacy() {
+        let mut master = MasterAccount::new(MasterKeyEntropy::Low, Network::Bitcoin, PASSPHRASE, None).unwrap();
+        let mut unlocker = Unlocker::new(master.encrypted(), PASSPHRASE, None, Network::Bitcoin, None).unwrap();
+        master.new_account(&mut unlocker, AccountAddressType::P2WPKH, 0, EXTERNAL, 10).unwrap();
+        master.new_account(&mut unlocker, AccountAddressType::P2WPKH, 0, INTERNAL, 10).unwrap();
+        let receive = master.next_key((0, EXTERNAL)).unwrap().0.script_pubkey;
+        let change = master.next_key((0, INTERNAL)).unwrap().0.script_pubkey;
+        let payee = ScriptBuf::new_p2wpkh(&WPubkeyHash::from_byte_array([1u8; 20]));
+        let legacy = ScriptBuf::new_p2pkh(&bitcoin::PubkeyHash::from_byte_array([2u8; 20]));
+        let coin = |value, script_pubkey: &ScriptBuf| TxOut { value: Amount::from_sat(value), script_pubkey: script_pubkey.clone() };
+
+        // an odd payment of the input type to a fresh change key of the same type leaks nothing
+        let transaction = spend(1, vec!(coin(12345, &payee), coin(87001, &change)));
+        let report = analyze(&master, &transaction, |_| Some(coin(100000, &receive)));
+        assert_eq!(report, Report { warnings: Vec::new(), score: 100 });
+
+        // a round payment gives the change away
+        let transaction = spend(1, vec!(coin(20000, &payee), coin(79001, &change)));
+        let report = analyze(&master, &transaction, |_| Some(coin(100000, &receive)));
+        assert_eq!(report.warnings, vec!(
+            Warning::RoundAmount { vout: 0 },
+            Warning::ObviousChange { vout: 1, hint: ChangeHint::RoundPayment }));
+        assert_eq!(report.score, 70);
+        assert_eq!(report.at_least(Severity::Medium).count(), 1);
+
+        // inputs of one script, of two types, one of them not needed for the payment
+        let transaction = spend(2, vec!(coin(30001, &payee), coin(1000, &change)));
+        let report = analyze(&master, &transaction, |point| Some(if point.vout == 0 { coin(20000, &receive) } else { coin(20000, &legacy) }));
+        assert_eq!(report.warnings, vec!(
+            Warning::MixedInputTypes,
+            Warning::ObviousChange { vout: 1, hint: ChangeHint::UnnecessaryInput }));
+        let report = analyze(&master, &transaction, |_| Some(coin(20000, &receive)));
+        assert_eq!(report.warnings[0], Warning::ReusedInput { input: 1 });
+
+        // paying an address that already received
+        master.get_mut((0, INTERNAL)).unwrap().record_receipt(0, Txid::all_zeros());
+        let transaction = spend(1, vec!(coin(12345, &payee), coin(87001, &change)));
+        let report = analyze(&master, &transaction, |_| Some(coin(100000, &receive)));
+        assert_eq!(report.warnings, vec!(Warning::ReusedOutput { vout: 1 }));
+        assert_eq!(report.warnings[0].to_string(), "output 1 pays an address that already received funds");
+    }
+}
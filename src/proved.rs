@@ -18,7 +18,7 @@
 //!
 //!
 
-use bitcoin::{Block, Transaction, BitcoinHash};
+use bitcoin::{Block, BlockHash, Transaction, TxMerkleNode};
 use bitcoin_hashes::{sha256d, HashEngine, Hash};
 
 /// A confirmed transaction with its SPV proof
@@ -26,31 +26,42 @@ use bitcoin_hashes::{sha256d, HashEngine, Hash};
 pub struct ProvedTransaction {
     transaction: Transaction,
     merkle_path: Vec<(bool, sha256d::Hash)>,
-    block_hash: sha256d::Hash
+    block_hash: BlockHash
 }
 
 impl ProvedTransaction {
     pub fn new (block: &Block, txnr: usize) -> ProvedTransaction {
         let transaction = block.txdata[txnr].clone();
         ProvedTransaction {
-            block_hash: block.header.bitcoin_hash(),
+            block_hash: block.header.block_hash(),
             merkle_path: Self::compute_proof(txnr, block),
             transaction
         }
     }
+    /// a proof assembled from the merkle branch of a transaction at a position of a block
+    /// as served by Electrum servers, hashes of the branch are ordered from the leaf to the root
+    pub fn from_merkle_branch (transaction: Transaction, block_hash: BlockHash, mut position: usize, branch: &[sha256d::Hash]) -> ProvedTransaction {
+        let mut merkle_path = Vec::new();
+        for hash in branch {
+            merkle_path.push((position & 1 == 1, *hash));
+            position >>= 1;
+        }
+        ProvedTransaction { transaction, merkle_path, block_hash }
+    }
+
     /// get a copy of the transaction
     pub fn get_transaction (&self) -> Transaction {
         self.transaction.clone()
     }
 
-    pub fn get_block_hash (&self) -> &sha256d::Hash {
+    pub fn get_block_hash (&self) -> &BlockHash {
         &self.block_hash
     }
 
     /// compute the merkle root implied by the SPV proof
-    pub fn merkle_root(&self) -> sha256d::Hash {
-        self.merkle_path.iter()
-            .fold(self.transaction.txid(), |a, (left, h)| {
+    pub fn merkle_root(&self) -> TxMerkleNode {
+        TxMerkleNode::from_raw_hash(self.merkle_path.iter()
+            .fold(self.transaction.compute_txid().to_raw_hash(), |a, (left, h)| {
                 let mut engine = sha256d::Hash::engine();
                 if *left {
                     engine.input(&h[..]);
@@ -61,7 +72,7 @@ impl ProvedTransaction {
                     engine.input(&h[..]);
                 }
                 sha256d::Hash::from_engine(engine)
-            })
+            }))
     }
 
     /// compute a proof for a transaction in a block
@@ -103,7 +114,7 @@ impl ProvedTransaction {
             (result, op)
         }
 
-        let mut ids = block.txdata.iter().map(|t| t.txid()).collect::<Vec<_>>();
+        let mut ids = block.txdata.iter().map(|t| t.compute_txid().to_raw_hash()).collect::<Vec<_>>();
         let mut proof = Vec::new();
         while let (i, Some((t, left, hash))) = binhash (ids.as_slice(), track) {
             proof.push((left, hash));
@@ -128,9 +139,12 @@ mod test {
             let pt = ProvedTransaction {
                 transaction: tx.clone(),
                 merkle_path: proof,
-                block_hash: block.header.bitcoin_hash()
+                block_hash: block.header.block_hash()
             };
             assert_eq!(pt.merkle_root(), block.header.merkle_root);
+            let branch = pt.merkle_path.iter().map(|(_, h)| *h).collect::<Vec<_>>();
+            let from_branch = ProvedTransaction::from_merkle_branch(tx.clone(), block.header.block_hash(), track, &branch);
+            assert_eq!(from_branch, pt);
         }
     }
 }
\ No newline at end of file
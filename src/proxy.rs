@@ -0,0 +1,210 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # SOCKS5 proxy
+//!
+//! Connections of the network clients through a SOCKS5 proxy, e.g. Tor
+//!
+
+use std::io::{Read, Write};
+use std::net::{IpAddr, TcpStream};
+
+use error::WalletError;
+use offline;
+
+/// a SOCKS5 proxy (RFC 1928), names are resolved by the proxy so onion addresses work with Tor
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Socks5Proxy {
+    address: String,
+    credentials: Option<(String, String)>
+}
+
+impl Socks5Proxy {
+    /// the proxy listening at address, e.g. 127.0.0.1:9050
+    pub fn new(address: &str) -> Socks5Proxy {
+        Socks5Proxy { address: address.to_string(), credentials: None }
+    }
+
+    /// the SOCKS port of a Tor daemon on this machine
+    pub fn tor() -> Socks5Proxy {
+        Socks5Proxy::new("127.0.0.1:9050")
+    }
+
+    /// authenticate with username and password (RFC 1929)
+    /// Tor uses distinct circuits for distinct credentials
+    pub fn with_credentials(mut self, user: &str, password: &str) -> Socks5Proxy {
+        self.credentials = Some((user.to_string(), password.to_string()));
+        self
+    }
+
+    /// address of the proxy
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    /// open a stream to target, a host:port, through the proxy
+    pub fn connect(&self, target: &str) -> Result<TcpStream, WalletError> {
+        let (host, port) = split_target(target)?;
+        offline::check()?;
+        let mut stream = TcpStream::connect(&self.address)?;
+
+        let method = if self.credentials.is_some() { 0x02 } else { 0x00 };
+        stream.write_all(&[0x05, 0x01, method])?;
+        let mut reply = [0u8; 2];
+        stream.read_exact(&mut reply)?;
+        if reply[0] != 0x05 || reply[1] != method {
+            return Err(WalletError::Proxy("proxy refused the authentication method".to_string()));
+        }
+        if let Some((ref user, ref password)) = self.credentials {
+            if user.is_empty() || user.len() > 255 || password.len() > 255 {
+                return Err(WalletError::Proxy("credentials must be 1 to 255 bytes".to_string()));
+            }
+            let mut auth = vec!(0x01, user.len() as u8);
+            auth.extend_from_slice(user.as_bytes());
+            auth.push(password.len() as u8);
+            auth.extend_from_slice(password.as_bytes());
+            stream.write_all(&auth)?;
+            stream.read_exact(&mut reply)?;
+            if reply[1] != 0x00 {
+                return Err(WalletError::Proxy("proxy rejected the credentials".to_string()));
+            }
+        }
+
+        let mut request = vec!(0x05, 0x01, 0x00);
+        match host.parse::<IpAddr>() {
+            Ok(IpAddr::V4(ip)) => {
+                request.push(0x01);
+                request.extend_from_slice(&ip.octets());
+            }
+            Ok(IpAddr::V6(ip)) => {
+                request.push(0x04);
+                request.extend_from_slice(&ip.octets());
+            }
+            Err(_) => {
+                if host.is_empty() || host.len() > 255 {
+                    return Err(WalletError::Proxy("host name must be 1 to 255 bytes".to_string()));
+                }
+                request.push(0x03);
+                request.push(host.len() as u8);
+                request.extend_from_slice(host.as_bytes());
+            }
+        }
+        request.extend_from_slice(&port.to_be_bytes());
+        stream.write_all(&request)?;
+
+        let mut reply = [0u8; 4];
+        stream.read_exact(&mut reply)?;
+        if reply[0] != 0x05 {
+            return Err(WalletError::Proxy("not a SOCKS5 proxy".to_string()));
+        }
+        if reply[1] != 0x00 {
+            return Err(WalletError::Proxy(reply_error(reply[1]).to_string()));
+        }
+        // skip the bound address the proxy reports
+        let bound = match reply[3] {
+            0x01 => 4,
+            0x04 => 16,
+            0x03 => {
+                let mut len = [0u8; 1];
+                stream.read_exact(&mut len)?;
+                len[0] as usize
+            }
+            _ => return Err(WalletError::Proxy("invalid address type in reply".to_string()))
+        };
+        let mut skip = vec!(0u8; bound + 2);
+        stream.read_exact(&mut skip)?;
+        Ok(stream)
+    }
+}
+
+/// connect to target, a host:port, directly or through the proxy
+pub fn connect(target: &str, proxy: Option<&Socks5Proxy>) -> Result<TcpStream, WalletError> {
+    match proxy {
+        Some(proxy) => proxy.connect(target),
+        None => {
+            offline::check()?;
+            Ok(TcpStream::connect(target)?)
+        }
+    }
+}
+
+/// host and port of host:port, IPv6 hosts are in brackets
+fn split_target(target: &str) -> Result<(&str, u16), WalletError> {
+    let (host, port) = target.rsplit_once(':').ok_or_else(|| WalletError::Proxy(format!("no port in {}", target)))?;
+    let port = port.parse::<u16>().map_err(|_| WalletError::Proxy(format!("invalid port in {}", target)))?;
+    Ok((host.trim_start_matches('[').trim_end_matches(']'), port))
+}
+
+fn reply_error(code: u8) -> &'static str {
+    match code {
+        0x01 => "general SOCKS server failure",
+        0x02 => "connection not allowed by ruleset",
+        0x03 => "network unreachable",
+        0x04 => "host unreachable",
+        0x05 => "connection refused",
+        0x06 => "TTL expired",
+        0x07 => "command not supported",
+        0x08 => "address type not supported",
+        _ => "unknown SOCKS5 error"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn test_socks5() {
+        assert_eq!(split_target("[::1]:8333").unwrap(), ("::1", 8333));
+        assert_eq!(split_target("example.onion:50001").unwrap(), ("example.onion", 50001));
+        assert!(split_target("example.onion").is_err());
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut greeting = [0u8; 3];
+            stream.read_exact(&mut greeting).unwrap();
+            assert_eq!(greeting, [0x05, 0x01, 0x02]);
+            stream.write_all(&[0x05, 0x02]).unwrap();
+            let mut auth = [0u8; 11];
+            stream.read_exact(&mut auth).unwrap();
+            assert_eq!(&auth, b"\x01\x04user\x04pass");
+            stream.write_all(&[0x01, 0x00]).unwrap();
+            let mut request = [0u8; 5 + 13 + 2];
+            stream.read_exact(&mut request).unwrap();
+            assert_eq!(&request[..5], &[0x05, 0x01, 0x00, 0x03, 13]);
+            assert_eq!(&request[5..18], b"example.onion");
+            assert_eq!(&request[18..], &50001u16.to_be_bytes());
+            stream.write_all(&[0x05, 0x00, 0x00, 0x01, 127, 0, 0, 1, 0x1f, 0x90]).unwrap();
+            // echo through the tunnel
+            let mut data = [0u8; 5];
+            stream.read_exact(&mut data).unwrap();
+            stream.write_all(&data).unwrap();
+        });
+
+        let proxy = Socks5Proxy::new(&address).with_credentials("user", "pass");
+        let mut stream = connect("example.onion:50001", Some(&proxy)).unwrap();
+        stream.write_all(b"hello").unwrap();
+        let mut echo = [0u8; 5];
+        stream.read_exact(&mut echo).unwrap();
+        assert_eq!(&echo, b"hello");
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,542 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Partially signed transactions
+//!
+//! Create, update and sign BIP174 transactions with keys of a master account
+//!
+
+use std::convert::TryFrom;
+use std::str::FromStr;
+
+use bitcoin::{Amount, EcdsaSighashType, FeeRate, OutPoint, Psbt, PublicKey, ScriptBuf, Sequence, TapSighashType, Transaction, TxIn, TxOut, Witness,
+              XOnlyPublicKey, ecdsa, taproot, absolute::LockTime, transaction::Version, sighash::{Prevouts, SighashCache}};
+use bitcoin::opcodes::all::OP_CHECKMULTISIG;
+use bitcoin::psbt::Input;
+use bitcoin::script::{Builder, Instruction, PushBytesBuf, Script};
+use bitcoin_hashes::Hash;
+
+use account::{MasterAccount, Unlocker, AccountAddressType, Account, InstantiatedKey};
+use coins::Coins;
+use coinselect::{self, CoinSelector, Target};
+use context::{AnySignature, SigningHash};
+use error::WalletError;
+use psbtv2::PsbtV2;
+
+/// create an unsigned PSBT spending owned coins to the outputs, inputs signal opt-in RBF
+/// inputs and outputs are completed with what the master account knows of them
+pub fn create(master: &MasterAccount, coins: &Coins, inputs: &[OutPoint], outputs: Vec<TxOut>, lock_time: LockTime) -> Result<Psbt, WalletError> {
+    if let Some(point) = inputs.iter().find(|p| !coins.owned().contains_key(p)) {
+        return Err(WalletError::UnknownCoin(*point));
+    }
+    let transaction = Transaction {
+        version: Version::TWO,
+        lock_time,
+        input: inputs.iter().map(|point| TxIn {
+            previous_output: *point,
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+            witness: Witness::new()
+        }).collect(),
+        output: outputs
+    };
+    let mut psbt = Psbt::from_unsigned_tx(transaction)?;
+    update(master, coins, &mut psbt);
+    Ok(psbt)
+}
+
+/// create an unsigned PSBT paying the outputs with owned coins chosen by the selector
+/// change is paid to change_script unless it would be dust
+pub fn create_funded<S: CoinSelector>(master: &MasterAccount, coins: &Coins, selector: &S, mut outputs: Vec<TxOut>,
+                                      fee_rate: FeeRate, change_script: ScriptBuf, lock_time: LockTime) -> Result<Psbt, WalletError> {
+    let candidates = coins.owned().iter().filter_map(|(point, coin)| coinselect::candidate(master, point, coin)).collect::<Vec<_>>();
+    let target = Target {
+        amount: outputs.iter().map(|o| o.value.to_sat()).sum(),
+        fee_rate,
+        base_weight: coinselect::TRANSACTION_OVERHEAD_WEIGHT + outputs.iter().map(|o| coinselect::output_weight(&o.script_pubkey)).sum::<u64>(),
+        change_weight: coinselect::output_weight(&change_script),
+        min_change: change_script.minimal_non_dust().to_sat()
+    };
+    let selection = selector.select(&candidates, &target)?;
+    if selection.change > 0 {
+        outputs.push(TxOut { value: Amount::from_sat(selection.change), script_pubkey: change_script });
+    }
+    let inputs = selection.coins.iter().map(|(point, _)| *point).collect::<Vec<_>>();
+    create(master, coins, &inputs, outputs, lock_time)
+}
+
+/// fill in spent outputs, scripts and derivation paths for inputs and outputs of the master account
+/// missing information of inputs not spending owned coins is left untouched
+pub fn update(master: &MasterAccount, coins: &Coins, psbt: &mut Psbt) {
+    for (ix, txin) in psbt.unsigned_tx.input.iter().enumerate() {
+        let input = &mut psbt.inputs[ix];
+        if let Some(proof) = coins.proofs().get(&txin.previous_output.txid) {
+            if input.non_witness_utxo.is_none() {
+                input.non_witness_utxo = Some(proof.get_transaction());
+            }
+        }
+        let spent = if let Some(coin) = coins.owned().get(&txin.previous_output) {
+            coin.output.clone()
+        } else if let Some(ref utxo) = input.witness_utxo {
+            utxo.clone()
+        } else if let Some(ref tx) = input.non_witness_utxo {
+            match tx.output.get(txin.previous_output.vout as usize) {
+                Some(output) => output.clone(),
+                None => continue
+            }
+        } else {
+            continue;
+        };
+        if let Some((account, key)) = master.get_key_for_script(&spent.script_pubkey) {
+            match account.address_type() {
+                AccountAddressType::P2PKH => {}
+                AccountAddressType::P2SHWPKH => {
+                    input.redeem_script = Some(redeem_script(key));
+                    input.witness_utxo = Some(spent);
+                }
+                AccountAddressType::P2WPKH => {
+                    input.witness_utxo = Some(spent);
+                }
+                AccountAddressType::P2WSH(_) => {
+                    input.witness_script = Some(key.script_code.clone());
+                    input.witness_utxo = Some(spent);
+                }
+                AccountAddressType::P2TR => {
+                    input.tap_internal_key = Some(XOnlyPublicKey::from(key.public.inner));
+                    input.witness_utxo = Some(spent);
+                }
+            }
+            if let Some(origin) = master.key_origin((account.account_number(), account.sub_account_number()), key.index) {
+                if account.address_type() == AccountAddressType::P2TR {
+                    input.tap_key_origins.insert(XOnlyPublicKey::from(key.public.inner), (Vec::new(), origin));
+                } else {
+                    input.bip32_derivation.insert(key.public.inner, origin);
+                }
+            }
+        }
+    }
+    for (ix, txout) in psbt.unsigned_tx.output.iter().enumerate() {
+        let output = &mut psbt.outputs[ix];
+        if let Some((account, key)) = master.get_key_for_script(&txout.script_pubkey) {
+            match account.address_type() {
+                AccountAddressType::P2SHWPKH => output.redeem_script = Some(redeem_script(key)),
+                AccountAddressType::P2WSH(_) => output.witness_script = Some(key.script_code.clone()),
+                AccountAddressType::P2TR => output.tap_internal_key = Some(XOnlyPublicKey::from(key.public.inner)),
+                _ => {}
+            }
+            if let Some(origin) = master.key_origin((account.account_number(), account.sub_account_number()), key.index) {
+                if account.address_type() == AccountAddressType::P2TR {
+                    output.tap_key_origins.insert(XOnlyPublicKey::from(key.public.inner), (Vec::new(), origin));
+                } else {
+                    output.bip32_derivation.insert(key.public.inner, origin);
+                }
+            }
+        }
+    }
+}
+
+/// add partial signatures for all inputs the master account controls
+/// returns the number of signatures added
+/// hashes of all inputs are computed before any is signed, the PSBT is unchanged if one of them fails
+pub fn sign(master: &MasterAccount, psbt: &mut Psbt, unlocker: &mut Unlocker) -> Result<usize, WalletError> {
+    if master.is_watch_only() {
+        return Err(WalletError::Unsupported("watch only master can not sign"));
+    }
+    let transaction = psbt.unsigned_tx.clone();
+    let mut hasher = SighashCache::new(&transaction);
+    // taproot signatures commit to all spent outputs
+    let prevouts = (0..psbt.inputs.len()).map(|ix| psbt.spend_utxo(ix).ok().cloned()).collect::<Option<Vec<_>>>();
+    let mut work = Vec::new();
+    let mut signing = Vec::new();
+    for ix in 0..psbt.inputs.len() {
+        let script_pubkey = match psbt.spend_utxo(ix) {
+            Ok(spent) => spent.script_pubkey.clone(),
+            Err(_) => continue
+        };
+        if let Some((account, key)) = master.get_key_for_script(&script_pubkey) {
+            let pk = unlock(account, key, unlocker)?;
+            if account.address_type() == AccountAddressType::P2TR {
+                let prevouts = prevouts.as_ref()
+                    .ok_or(WalletError::Unsupported("taproot signing needs all spent outputs"))?;
+                let sighash_type = match psbt.inputs[ix].sighash_type {
+                    Some(t) => t.taproot_hash_ty().map_err(|_| WalletError::Unsupported("invalid taproot sighash type"))?,
+                    None => TapSighashType::Default
+                };
+                let sighash = hasher.taproot_key_spend_signature_hash(ix, &Prevouts::All(prevouts), sighash_type)?;
+                work.push((SigningHash::Schnorr(sighash.to_byte_array()), pk));
+                signing.push((ix, key.public, Sighash::Taproot(sighash_type)));
+            } else {
+                let (message, sighash_type) = psbt.sighash_ecdsa(ix, &mut hasher)?;
+                work.push((SigningHash::Ecdsa(*message.as_ref()), pk));
+                signing.push((ix, key.public, Sighash::Ecdsa(sighash_type)));
+            }
+        }
+    }
+    let signatures = unlocker.context().sign_all(&work)?;
+    for ((ix, public, sighash_type), signature) in signing.iter().zip(signatures) {
+        match (*sighash_type, signature) {
+            (Sighash::Ecdsa(sighash_type), AnySignature::Ecdsa(signature)) => {
+                psbt.inputs[*ix].partial_sigs.insert(*public, ecdsa::Signature { signature, sighash_type });
+            }
+            (Sighash::Taproot(sighash_type), AnySignature::Schnorr(signature)) => {
+                psbt.inputs[*ix].tap_key_sig = Some(taproot::Signature { signature, sighash_type });
+            }
+            _ => unreachable!("signatures follow the kind of their hash")
+        }
+    }
+    Ok(signing.len())
+}
+
+/// the Combiner of BIP174: merge the signatures and other fields of PSBTs of the same transaction,
+/// e.g. those cosigners signed independently
+pub fn combine(psbts: Vec<Psbt>) -> Result<Psbt, WalletError> {
+    let mut psbts = psbts.into_iter();
+    let mut combined = psbts.next().ok_or(WalletError::Unsupported("no PSBT to combine"))?;
+    for psbt in psbts {
+        combined.combine(psbt)?;
+    }
+    Ok(combined)
+}
+
+/// the Finalizer of BIP174: build the final script signature and witness of each input with enough signatures,
+/// P2PKH, P2SH-P2WPKH, P2WPKH, taproot key path, P2WSH of a single signature and sorted or unsorted multisig.
+/// Fields the final scripts replace are cleared. Fails if an input could not be finalized, others are finalized nonetheless.
+pub fn finalize(psbt: &mut Psbt) -> Result<(), WalletError> {
+    let mut complete = true;
+    for ix in 0..psbt.inputs.len() {
+        let input = &psbt.inputs[ix];
+        if input.final_script_sig.is_some() || input.final_script_witness.is_some() {
+            continue;
+        }
+        let script_pubkey = match psbt.spend_utxo(ix) {
+            Ok(spent) => spent.script_pubkey.clone(),
+            Err(_) => {
+                complete = false;
+                continue;
+            }
+        };
+        match final_scripts(input, &script_pubkey) {
+            Some((script_sig, witness)) => {
+                let input = &mut psbt.inputs[ix];
+                *input = Input {
+                    non_witness_utxo: input.non_witness_utxo.take(),
+                    witness_utxo: input.witness_utxo.take(),
+                    final_script_sig: if script_sig.is_empty() { None } else { Some(script_sig) },
+                    final_script_witness: if witness.is_empty() { None } else { Some(witness) },
+                    proprietary: std::mem::take(&mut input.proprietary),
+                    unknown: std::mem::take(&mut input.unknown),
+                    ..Default::default()
+                };
+            }
+            None => complete = false
+        }
+    }
+    if complete { Ok(()) } else { Err(WalletError::Unsupported("not all inputs have the signatures to finalize")) }
+}
+
+/// the Extractor of BIP174: the signed transaction of a finalized PSBT, its fee is not checked
+pub fn extract(psbt: &Psbt) -> Result<Transaction, WalletError> {
+    if psbt.inputs.iter().any(|i| i.final_script_sig.is_none() && i.final_script_witness.is_none()) {
+        return Err(WalletError::Unsupported("PSBT is not finalized"));
+    }
+    Ok(psbt.clone().extract_tx_unchecked_fee_rate())
+}
+
+/// script signature and witness of an input spending the script, none without the signatures
+fn final_scripts(input: &Input, script_pubkey: &Script) -> Option<(ScriptBuf, Witness)> {
+    let single = || if input.partial_sigs.len() == 1 { input.partial_sigs.iter().next() } else { None };
+    if script_pubkey.is_p2tr() {
+        let signature = input.tap_key_sig?;
+        return Some((ScriptBuf::new(), Witness::from_slice(&[signature.to_vec()])));
+    }
+    if script_pubkey.is_p2pkh() {
+        let (public, signature) = single()?;
+        let script_sig = Builder::new().push_slice(signature.serialize()).push_key(public).into_script();
+        return Some((script_sig, Witness::new()));
+    }
+    if script_pubkey.is_p2wpkh() {
+        let (public, signature) = single()?;
+        return Some((ScriptBuf::new(), Witness::p2wpkh(signature, &public.inner)));
+    }
+    if script_pubkey.is_p2sh() {
+        let redeem_script = input.redeem_script.as_ref().filter(|r| r.is_p2wpkh() && ScriptBuf::new_p2sh(&r.script_hash()) == *script_pubkey)?;
+        let (public, signature) = single()?;
+        let push = PushBytesBuf::try_from(redeem_script.to_bytes()).ok()?;
+        return Some((Builder::new().push_slice(push).into_script(), Witness::p2wpkh(signature, &public.inner)));
+    }
+    if script_pubkey.is_p2wsh() {
+        let witness_script = input.witness_script.as_ref().filter(|w| ScriptBuf::new_p2wsh(&w.wscript_hash()) == *script_pubkey)?;
+        let mut witness = Witness::new();
+        if let Some((threshold, keys)) = multisig_keys(witness_script) {
+            // signatures in the order of their keys, after the extra element OP_CHECKMULTISIG pops
+            let signatures = keys.iter().filter_map(|k| input.partial_sigs.get(k)).take(threshold).collect::<Vec<_>>();
+            if signatures.len() < threshold {
+                return None;
+            }
+            witness.push([0u8; 0]);
+            for signature in signatures {
+                witness.push(signature.serialize());
+            }
+        } else {
+            witness.push(single()?.1.serialize());
+        }
+        witness.push(witness_script.as_bytes());
+        return Some((ScriptBuf::new(), witness));
+    }
+    None
+}
+
+/// threshold and keys of a threshold OP_CHECKMULTISIG script
+fn multisig_keys(script: &Script) -> Option<(usize, Vec<PublicKey>)> {
+    let instructions = script.instructions().collect::<Result<Vec<_>, _>>().ok()?;
+    if instructions.len() < 4 || instructions.last() != Some(&Instruction::Op(OP_CHECKMULTISIG)) {
+        return None;
+    }
+    let number = |instruction: &Instruction| match instruction {
+        Instruction::Op(op) if (0x51..=0x60).contains(&op.to_u8()) => Some((op.to_u8() - 0x50) as usize),
+        _ => None
+    };
+    let threshold = number(&instructions[0])?;
+    let keys = instructions[1..instructions.len() - 2].iter().map(|i| match i {
+        Instruction::PushBytes(bytes) => PublicKey::from_slice(bytes.as_bytes()).ok(),
+        _ => None
+    }).collect::<Option<Vec<_>>>()?;
+    if number(&instructions[instructions.len() - 2])? != keys.len() || threshold > keys.len() {
+        return None;
+    }
+    Some((threshold, keys))
+}
+
+/// sighash type of a signature to add
+#[derive(Clone, Copy)]
+enum Sighash {
+    Ecdsa(EcdsaSighashType),
+    Taproot(TapSighashType)
+}
+
+/// serialize to the base64 form used by Core and most other wallets
+pub fn to_base64(psbt: &Psbt) -> String {
+    psbt.to_string()
+}
+
+/// deserialize from base64, a version 2 PSBT converts to version 0
+pub fn from_base64(s: &str) -> Result<Psbt, WalletError> {
+    match Psbt::from_str(s) {
+        Ok(psbt) => Ok(psbt),
+        Err(e) => PsbtV2::from_base64(s).map(|v2| v2.to_v0()).map_err(|_| e.into())
+    }
+}
+
+fn unlock(account: &Account, key: &InstantiatedKey, unlocker: &mut Unlocker) -> Result<bitcoin::PrivateKey, WalletError> {
+    account.unlock(unlocker, key.index, key.tweak.clone())
+}
+
+fn redeem_script(key: &InstantiatedKey) -> ScriptBuf {
+    ScriptBuf::new_p2wpkh(&key.public.wpubkey_hash().expect("compressed key"))
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{Amount, Block, Network, Txid, CompactTarget, TxMerkleNode, BlockHash};
+    use bitcoin::block::Header;
+    use bitcoin_hashes::Hash;
+
+    use account::MasterKeyEntropy;
+    use super::*;
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+
+    #[test]
+    fn test_finalize() {
+        let mut master = MasterAccount::new(MasterKeyEntropy::Low, Network::Bitcoin, PASSPHRASE, None).unwrap();
+        let mut unlocker = Unlocker::new(master.encrypted(), PASSPHRASE, None, Network::Bitcoin, None).unwrap();
+        let nested = master.new_account(&mut unlocker, AccountAddressType::P2SHWPKH, 0, 0, 10).unwrap().next_key().unwrap().address.clone();
+        let taproot = master.new_account(&mut unlocker, AccountAddressType::P2TR, 1, 0, 10).unwrap().next_key().unwrap().address.clone();
+        let funding = Transaction {
+            version: Version::ONE,
+            lock_time: LockTime::ZERO,
+            input: vec!(TxIn { previous_output: OutPoint::null(), script_sig: ScriptBuf::new(), sequence: Sequence::MAX, witness: Witness::new() }),
+            output: vec!(
+                TxOut { value: Amount::from_sat(100000), script_pubkey: nested.script_pubkey() },
+                TxOut { value: Amount::from_sat(200000), script_pubkey: taproot.script_pubkey() })
+        };
+        let mut coins = Coins::new();
+        coins.process(&mut master, &Block { header: Header {
+            version: bitcoin::block::Version::ONE, prev_blockhash: BlockHash::all_zeros(), merkle_root: TxMerkleNode::all_zeros(),
+            time: 0, bits: CompactTarget::from_consensus(0), nonce: 0 }, txdata: vec!(funding.clone()) }).unwrap();
+        let txid = funding.compute_txid();
+        let outputs = vec!(TxOut { value: Amount::from_sat(299000), script_pubkey: ScriptBuf::new_op_return([0u8; 4]) });
+        let unsigned = create(&master, &coins, &[OutPoint { txid, vout: 0 }, OutPoint { txid, vout: 1 }], outputs, LockTime::ZERO).unwrap();
+        // each input signed by an other signer
+        let mut first = unsigned.clone();
+        let mut second = unsigned.clone();
+        sign(&master, &mut first, &mut unlocker).unwrap();
+        first.inputs[1].tap_key_sig = None;
+        sign(&master, &mut second, &mut unlocker).unwrap();
+        second.inputs[0].partial_sigs.clear();
+        assert!(finalize(&mut first.clone()).is_err());
+        let mut combined = combine(vec!(unsigned, first, second)).unwrap();
+        finalize(&mut combined).unwrap();
+        assert!(combined.inputs[0].final_script_sig.is_some() && combined.inputs[0].redeem_script.is_none());
+        assert_eq!(combined.inputs[1].final_script_witness.as_ref().unwrap().len(), 1);
+        extract(&combined).unwrap().verify(|point| funding.output.get(point.vout as usize).cloned()).unwrap();
+    }
+
+    #[test]
+    fn test_sign_single_without_output() {
+        let mut master = MasterAccount::new(MasterKeyEntropy::Low, Network::Bitcoin, PASSPHRASE, None).unwrap();
+        let mut unlocker = Unlocker::new(master.encrypted(), PASSPHRASE, None, Network::Bitcoin, None).unwrap();
+        let taproot = master.new_account(&mut unlocker, AccountAddressType::P2TR, 0, 0, 10).unwrap().next_key().unwrap().address.clone();
+        let funding = Transaction {
+            version: Version::ONE,
+            lock_time: LockTime::ZERO,
+            input: vec!(TxIn { previous_output: OutPoint::null(), script_sig: ScriptBuf::new(), sequence: Sequence::MAX, witness: Witness::new() }),
+            output: vec!(
+                TxOut { value: Amount::from_sat(100000), script_pubkey: taproot.script_pubkey() },
+                TxOut { value: Amount::from_sat(200000), script_pubkey: taproot.script_pubkey() })
+        };
+        let mut coins = Coins::new();
+        coins.process(&mut master, &Block { header: Header {
+            version: bitcoin::block::Version::ONE, prev_blockhash: BlockHash::all_zeros(), merkle_root: TxMerkleNode::all_zeros(),
+            time: 0, bits: CompactTarget::from_consensus(0), nonce: 0 }, txdata: vec!(funding.clone()) }).unwrap();
+        let txid = funding.compute_txid();
+        let outputs = vec!(TxOut { value: Amount::from_sat(299000), script_pubkey: ScriptBuf::new_op_return([0u8; 4]) });
+        let mut psbt = create(&master, &coins, &[OutPoint { txid, vout: 0 }, OutPoint { txid, vout: 1 }], outputs, LockTime::ZERO).unwrap();
+        // SIGHASH_SINGLE of the second input has no output to commit to
+        psbt.inputs[1].sighash_type = Some(TapSighashType::Single.into());
+        let unsigned = psbt.clone();
+        match sign(&master, &mut psbt, &mut unlocker) {
+            Err(WalletError::TaprootSighash(_)) => {}
+            other => panic!("expected a taproot sighash error, got {:?}", other)
+        }
+        assert_eq!(psbt, unsigned);
+        psbt.inputs[1].sighash_type = None;
+        assert_eq!(sign(&master, &mut psbt, &mut unlocker).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_sign_consolidation() {
+        let mut master = MasterAccount::new(MasterKeyEntropy::Low, Network::Bitcoin, PASSPHRASE, None).unwrap();
+        let mut unlocker = Unlocker::new(master.encrypted(), PASSPHRASE, None, Network::Bitcoin, None).unwrap();
+        let segwit = master.new_account(&mut unlocker, AccountAddressType::P2WPKH, 0, 0, 10).unwrap().next_key().unwrap().address.clone();
+        let taproot = master.new_account(&mut unlocker, AccountAddressType::P2TR, 1, 0, 10).unwrap().next_key().unwrap().address.clone();
+        let funding = Transaction {
+            version: Version::ONE,
+            lock_time: LockTime::ZERO,
+            input: vec!(TxIn { previous_output: OutPoint::null(), script_sig: ScriptBuf::new(), sequence: Sequence::MAX, witness: Witness::new() }),
+            output: (0..100).map(|n| TxOut {
+                value: Amount::from_sat(10000),
+                script_pubkey: if n % 2 == 0 { segwit.script_pubkey() } else { taproot.script_pubkey() }
+            }).collect()
+        };
+        let mut coins = Coins::new();
+        coins.process(&mut master, &Block { header: Header {
+            version: bitcoin::block::Version::ONE, prev_blockhash: BlockHash::all_zeros(), merkle_root: TxMerkleNode::all_zeros(),
+            time: 0, bits: CompactTarget::from_consensus(0), nonce: 0 }, txdata: vec!(funding.clone()) }).unwrap();
+        let txid = funding.compute_txid();
+        let inputs = (0..100).map(|vout| OutPoint { txid, vout }).collect::<Vec<_>>();
+        let outputs = vec!(TxOut { value: Amount::from_sat(990000), script_pubkey: segwit.script_pubkey() });
+        let mut psbt = create(&master, &coins, &inputs, outputs, LockTime::ZERO).unwrap();
+        // the hash of the last input fails after all others were computed
+        psbt.inputs[99].sighash_type = Some(TapSighashType::Single.into());
+        let unsigned = psbt.clone();
+        assert!(sign(&master, &mut psbt, &mut unlocker).is_err());
+        assert_eq!(psbt, unsigned);
+        psbt.inputs[99].sighash_type = None;
+        assert_eq!(sign(&master, &mut psbt, &mut unlocker).unwrap(), 100);
+        finalize(&mut psbt).unwrap();
+        extract(&psbt).unwrap().verify(|point| funding.output.get(point.vout as usize).cloned()).unwrap();
+    }
+
+    #[test]
+    fn test_create_sign() {
+        let mut master = MasterAccount::new(MasterKeyEntropy::Low, Network::Bitcoin, PASSPHRASE, None).unwrap();
+        let mut unlocker = Unlocker::new(master.encrypted(), PASSPHRASE, None, Network::Bitcoin, None).unwrap();
+        let legacy = master.new_account(&mut unlocker, AccountAddressType::P2PKH, 0, 0, 10).unwrap().next_key().unwrap().address.clone();
+        let segwit = master.new_account(&mut unlocker, AccountAddressType::P2WPKH, 1, 0, 10).unwrap().next_key().unwrap().address.clone();
+        let change = master.new_account(&mut unlocker, AccountAddressType::P2SHWPKH, 2, 0, 10).unwrap().next_key().unwrap().address.clone();
+
+        let funding = Transaction {
+            version: Version::ONE,
+            lock_time: LockTime::ZERO,
+            input: vec!(TxIn {
+                previous_output: OutPoint { txid: Txid::all_zeros(), vout: 0 },
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::new()
+            }),
+            output: vec!(
+                TxOut { value: Amount::from_sat(100000), script_pubkey: legacy.script_pubkey() },
+                TxOut { value: Amount::from_sat(200000), script_pubkey: segwit.script_pubkey() })
+        };
+        let block = Block {
+            header: Header {
+                version: bitcoin::block::Version::ONE,
+                prev_blockhash: BlockHash::all_zeros(),
+                merkle_root: TxMerkleNode::all_zeros(),
+                time: 0,
+                bits: CompactTarget::from_consensus(0),
+                nonce: 0
+            },
+            txdata: vec!(funding.clone())
+        };
+        let mut coins = Coins::new();
+        coins.process(&mut master, &block).unwrap();
+        assert_eq!(coins.owned().len(), 2);
+
+        let txid = funding.compute_txid();
+        let inputs = [OutPoint { txid, vout: 0 }, OutPoint { txid, vout: 1 }];
+        let outputs = vec!(TxOut { value: Amount::from_sat(290000), script_pubkey: change.script_pubkey() });
+        let mut psbt = create(&master, &coins, &inputs, outputs, LockTime::ZERO).unwrap();
+        assert!(psbt.inputs[0].non_witness_utxo.is_some());
+        assert!(psbt.inputs[0].witness_utxo.is_none());
+        assert!(psbt.inputs[1].witness_utxo.is_some());
+        assert_eq!(psbt.inputs[1].bip32_derivation.values().next().unwrap().1.to_string(), "84'/0'/1'/0/0");
+        assert!(psbt.outputs[0].redeem_script.is_some());
+        assert_eq!(psbt.outputs[0].bip32_derivation.len(), 1);
+
+        assert_eq!(sign(&master, &mut psbt, &mut unlocker).unwrap(), 2);
+        assert_eq!(psbt.inputs[0].partial_sigs.len(), 1);
+        assert_eq!(psbt.inputs[1].partial_sigs.len(), 1);
+
+        let encoded = to_base64(&psbt);
+        assert_eq!(from_base64(&encoded).unwrap(), psbt);
+        assert!(from_base64("cHNidP8=").is_err());
+
+        // finalize and extract the signed transaction
+        let mut finalized = psbt.clone();
+        assert!(extract(&finalized).is_err());
+        finalize(&mut finalized).unwrap();
+        assert!(finalized.inputs.iter().all(|i| i.partial_sigs.is_empty() && i.bip32_derivation.is_empty()));
+        assert!(finalized.inputs[0].final_script_sig.is_some() && finalized.inputs[1].final_script_witness.is_some());
+        extract(&finalized).unwrap().verify(|point| funding.output.get(point.vout as usize).cloned()).unwrap();
+        let mut unsigned = create(&master, &coins, &inputs, psbt.unsigned_tx.output.clone(), LockTime::ZERO).unwrap();
+        assert!(finalize(&mut unsigned).is_err());
+        assert!(combine(Vec::new()).is_err());
+
+        let foreign = [OutPoint { txid, vout: 2 }];
+        assert!(create(&master, &coins, &foreign, Vec::new(), LockTime::ZERO).is_err());
+
+        let fee_rate = FeeRate::from_sat_per_vb(2).unwrap();
+        let payment = vec!(TxOut { value: Amount::from_sat(150000), script_pubkey: legacy.script_pubkey() });
+        let psbt = create_funded(&master, &coins, &coinselect::LargestFirst, payment, fee_rate, change.script_pubkey(), LockTime::ZERO).unwrap();
+        assert_eq!(psbt.unsigned_tx.input.len(), 1);
+        assert_eq!(psbt.unsigned_tx.output.len(), 2);
+        assert!(psbt.fee().unwrap().to_sat() >= coinselect::fee(fee_rate, psbt.unsigned_tx.weight().to_wu()));
+        let payment = vec!(TxOut { value: Amount::from_sat(300000), script_pubkey: legacy.script_pubkey() });
+        assert!(create_funded(&master, &coins, &coinselect::LargestFirst, payment, fee_rate, change.script_pubkey(), LockTime::ZERO).is_err());
+    }
+}
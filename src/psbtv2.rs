@@ -0,0 +1,428 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # PSBT version 2
+//!
+//! BIP370 PSBTs, inputs and outputs are added independently until the transaction is signed
+//!
+
+use std::convert::TryFrom;
+
+use bitcoin::{Amount, OutPoint, Psbt, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Txid, Witness};
+use bitcoin::absolute::LockTime;
+use bitcoin::base64::{engine::general_purpose::STANDARD, Engine};
+use bitcoin::consensus::encode::{serialize, VarInt};
+use bitcoin::psbt::{Input, Output};
+use bitcoin::transaction::Version;
+use bitcoin_hashes::Hash;
+
+use account::{MasterAccount, Unlocker};
+use error::WalletError;
+use psbt;
+
+/// inputs may be added
+pub const INPUTS_MODIFIABLE: u8 = 1;
+/// outputs may be added
+pub const OUTPUTS_MODIFIABLE: u8 = 2;
+/// an input is signed with SIGHASH_SINGLE, its output must stay at its index
+pub const HAS_SIGHASH_SINGLE: u8 = 4;
+
+const MAGIC: &[u8] = b"psbt\xff";
+const GLOBAL_UNSIGNED_TX: u8 = 0x00;
+const GLOBAL_TX_VERSION: u8 = 0x02;
+const GLOBAL_FALLBACK_LOCKTIME: u8 = 0x03;
+const GLOBAL_INPUT_COUNT: u8 = 0x04;
+const GLOBAL_OUTPUT_COUNT: u8 = 0x05;
+const GLOBAL_TX_MODIFIABLE: u8 = 0x06;
+const GLOBAL_VERSION: u8 = 0xfb;
+const IN_PREVIOUS_TXID: u8 = 0x0e;
+const IN_OUTPUT_INDEX: u8 = 0x0f;
+const IN_SEQUENCE: u8 = 0x10;
+const IN_REQUIRED_TIME_LOCKTIME: u8 = 0x11;
+const IN_REQUIRED_HEIGHT_LOCKTIME: u8 = 0x12;
+const OUT_AMOUNT: u8 = 0x03;
+const OUT_SCRIPT: u8 = 0x04;
+
+/// a key value pair of a PSBT map
+type Pair = (Vec<u8>, Vec<u8>);
+/// a map of key value pairs
+type Map = Vec<Pair>;
+
+/// a version 2 PSBT, kept as the version 0 PSBT of the transaction it currently describes
+#[derive(Clone, Debug, PartialEq)]
+pub struct PsbtV2 {
+    psbt: Psbt,
+    fallback_lock_time: Option<LockTime>,
+    modifiable: u8,
+    /// required time and height lock times of each input
+    required: Vec<(Option<u32>, Option<u32>)>
+}
+
+impl PsbtV2 {
+    /// an empty PSBT to add inputs and outputs to, version 2 transactions or later
+    pub fn new(version: Version, fallback_lock_time: Option<LockTime>) -> Result<PsbtV2, WalletError> {
+        if version < Version::TWO {
+            return Err(WalletError::Unsupported("PSBTv2 need version 2 transactions"));
+        }
+        let transaction = Transaction { version, lock_time: fallback_lock_time.unwrap_or(LockTime::ZERO), input: Vec::new(), output: Vec::new() };
+        Ok(PsbtV2 {
+            psbt: Psbt::from_unsigned_tx(transaction)?,
+            fallback_lock_time,
+            modifiable: INPUTS_MODIFIABLE | OUTPUTS_MODIFIABLE,
+            required: Vec::new()
+        })
+    }
+
+    /// a version 2 PSBT of a version 0 one, neither inputs nor outputs are modifiable
+    pub fn from_v0(psbt: Psbt) -> Result<PsbtV2, WalletError> {
+        if psbt.unsigned_tx.version < Version::TWO {
+            return Err(WalletError::Unsupported("PSBTv2 need version 2 transactions"));
+        }
+        let required = vec!((None, None); psbt.inputs.len());
+        Ok(PsbtV2 { fallback_lock_time: Some(psbt.unsigned_tx.lock_time), psbt, modifiable: 0, required })
+    }
+
+    /// the version 0 PSBT of the transaction, to sign, finalize or pass on to version 0 signers
+    pub fn to_v0(&self) -> Psbt {
+        self.psbt.clone()
+    }
+
+    pub fn psbt(&self) -> &Psbt {
+        &self.psbt
+    }
+
+    /// flags of INPUTS_MODIFIABLE, OUTPUTS_MODIFIABLE and HAS_SIGHASH_SINGLE
+    pub fn modifiable(&self) -> u8 {
+        self.modifiable
+    }
+
+    pub fn set_modifiable(&mut self, flags: u8) {
+        self.modifiable = flags;
+    }
+
+    /// add an input, optionally with the lock time it requires, e.g. of a CLTV script
+    pub fn add_input(&mut self, txin: TxIn, input: Input, required: Option<LockTime>) -> Result<(), WalletError> {
+        if self.modifiable & INPUTS_MODIFIABLE == 0 {
+            return Err(WalletError::Unsupported("inputs are not modifiable"));
+        }
+        if self.psbt.unsigned_tx.input.iter().any(|i| i.previous_output == txin.previous_output) {
+            return Err(WalletError::Unsupported("input is already spent"));
+        }
+        let mut requirements = self.required.clone();
+        requirements.push(match required {
+            Some(LockTime::Seconds(time)) => (Some(time.to_consensus_u32()), None),
+            Some(LockTime::Blocks(height)) => (None, Some(height.to_consensus_u32())),
+            None => (None, None)
+        });
+        let lock_time = lock_time(&requirements, self.fallback_lock_time)?;
+        self.psbt.unsigned_tx.input.push(TxIn { script_sig: ScriptBuf::new(), witness: Witness::new(), ..txin });
+        self.psbt.unsigned_tx.lock_time = lock_time;
+        self.psbt.inputs.push(input);
+        self.required = requirements;
+        Ok(())
+    }
+
+    /// add an output
+    pub fn add_output(&mut self, txout: TxOut, output: Output) -> Result<(), WalletError> {
+        if self.modifiable & OUTPUTS_MODIFIABLE == 0 {
+            return Err(WalletError::Unsupported("outputs are not modifiable"));
+        }
+        self.psbt.unsigned_tx.output.push(txout);
+        self.psbt.outputs.push(output);
+        Ok(())
+    }
+
+    /// the lock time of the transaction, see `add_input`
+    pub fn lock_time(&self) -> LockTime {
+        self.psbt.unsigned_tx.lock_time
+    }
+
+    /// add signatures of the master account, then inputs and outputs are no longer modifiable
+    pub fn sign(&mut self, master: &MasterAccount, unlocker: &mut Unlocker) -> Result<usize, WalletError> {
+        let signed = psbt::sign(master, &mut self.psbt, unlocker)?;
+        if signed > 0 {
+            self.modifiable &= !(INPUTS_MODIFIABLE | OUTPUTS_MODIFIABLE);
+        }
+        Ok(signed)
+    }
+
+    /// the BIP370 serialization
+    pub fn serialize(&self) -> Vec<u8> {
+        let (mut global, inputs, outputs) = maps(&self.psbt.serialize(), self.psbt.inputs.len(), self.psbt.outputs.len())
+            .expect("serialized PSBT of the library");
+        let transaction = &self.psbt.unsigned_tx;
+        global.retain(|(key, _)| key[..] != [GLOBAL_UNSIGNED_TX] && key[..] != [GLOBAL_VERSION]);
+        global.push((vec!(GLOBAL_TX_VERSION), serialize(&transaction.version)));
+        if let Some(lock_time) = self.fallback_lock_time {
+            global.push((vec!(GLOBAL_FALLBACK_LOCKTIME), serialize(&lock_time)));
+        }
+        global.push((vec!(GLOBAL_INPUT_COUNT), serialize(&VarInt(transaction.input.len() as u64))));
+        global.push((vec!(GLOBAL_OUTPUT_COUNT), serialize(&VarInt(transaction.output.len() as u64))));
+        global.push((vec!(GLOBAL_TX_MODIFIABLE), vec!(self.modifiable)));
+        global.push((vec!(GLOBAL_VERSION), 2u32.to_le_bytes().to_vec()));
+        global.sort();
+
+        let mut bytes = MAGIC.to_vec();
+        write_map(&mut bytes, &global);
+        for ((mut map, txin), (time, height)) in inputs.into_iter().zip(transaction.input.iter()).zip(self.required.iter()) {
+            map.push((vec!(IN_PREVIOUS_TXID), txin.previous_output.txid.to_byte_array().to_vec()));
+            map.push((vec!(IN_OUTPUT_INDEX), txin.previous_output.vout.to_le_bytes().to_vec()));
+            map.push((vec!(IN_SEQUENCE), txin.sequence.to_consensus_u32().to_le_bytes().to_vec()));
+            if let Some(time) = time {
+                map.push((vec!(IN_REQUIRED_TIME_LOCKTIME), time.to_le_bytes().to_vec()));
+            }
+            if let Some(height) = height {
+                map.push((vec!(IN_REQUIRED_HEIGHT_LOCKTIME), height.to_le_bytes().to_vec()));
+            }
+            map.sort();
+            write_map(&mut bytes, &map);
+        }
+        for (mut map, txout) in outputs.into_iter().zip(transaction.output.iter()) {
+            map.push((vec!(OUT_AMOUNT), txout.value.to_sat().to_le_bytes().to_vec()));
+            map.push((vec!(OUT_SCRIPT), txout.script_pubkey.to_bytes()));
+            map.sort();
+            write_map(&mut bytes, &map);
+        }
+        bytes
+    }
+
+    /// parse the BIP370 serialization
+    pub fn deserialize(bytes: &[u8]) -> Result<PsbtV2, WalletError> {
+        let mut pos = MAGIC.len();
+        if !bytes.starts_with(MAGIC) {
+            return Err(WalletError::Unsupported("not a PSBT"));
+        }
+        let mut global = read_map(bytes, &mut pos)?;
+        if field(&global, GLOBAL_VERSION).map(u32_field).transpose()? != Some(2) {
+            return Err(WalletError::Unsupported("not a version 2 PSBT"));
+        }
+        let version = Version(u32_field(field(&global, GLOBAL_TX_VERSION).ok_or(WalletError::Unsupported("PSBTv2 without transaction version"))?)? as i32);
+        let fallback_lock_time = field(&global, GLOBAL_FALLBACK_LOCKTIME).map(|v| u32_field(v).map(LockTime::from_consensus)).transpose()?;
+        let n_inputs = count_field(field(&global, GLOBAL_INPUT_COUNT))?;
+        let n_outputs = count_field(field(&global, GLOBAL_OUTPUT_COUNT))?;
+        let modifiable = match field(&global, GLOBAL_TX_MODIFIABLE) {
+            Some([flags]) => *flags,
+            Some(_) => return Err(WalletError::Unsupported("invalid PSBTv2 modifiable flags")),
+            None => 0
+        };
+
+        let mut inputs = Vec::new();
+        let mut txins = Vec::new();
+        let mut required = Vec::new();
+        for _ in 0..n_inputs {
+            let mut map = read_map(bytes, &mut pos)?;
+            let txid = field(&map, IN_PREVIOUS_TXID).and_then(|v| Txid::from_slice(v).ok()).ok_or(WalletError::Unsupported("PSBTv2 input without previous txid"))?;
+            let vout = u32_field(field(&map, IN_OUTPUT_INDEX).ok_or(WalletError::Unsupported("PSBTv2 input without output index"))?)?;
+            let sequence = field(&map, IN_SEQUENCE).map(u32_field).transpose()?.map(Sequence).unwrap_or(Sequence::MAX);
+            let time = field(&map, IN_REQUIRED_TIME_LOCKTIME).map(u32_field).transpose()?;
+            let height = field(&map, IN_REQUIRED_HEIGHT_LOCKTIME).map(u32_field).transpose()?;
+            if time.is_some_and(|t| t < 500_000_000) || height.is_some_and(|h| h >= 500_000_000) {
+                return Err(WalletError::Unsupported("invalid PSBTv2 required lock time"));
+            }
+            map.retain(|(key, _)| !(IN_PREVIOUS_TXID..=IN_REQUIRED_HEIGHT_LOCKTIME).any(|t| key[..] == [t]));
+            txins.push(TxIn { previous_output: OutPoint { txid, vout }, script_sig: ScriptBuf::new(), sequence, witness: Witness::new() });
+            required.push((time, height));
+            inputs.push(map);
+        }
+        let mut outputs = Vec::new();
+        let mut txouts = Vec::new();
+        for _ in 0..n_outputs {
+            let mut map = read_map(bytes, &mut pos)?;
+            let amount = field(&map, OUT_AMOUNT).and_then(|v| <[u8; 8]>::try_from(v).ok()).map(i64::from_le_bytes)
+                .filter(|a| *a >= 0).ok_or(WalletError::Unsupported("PSBTv2 output without amount"))?;
+            let script_pubkey = ScriptBuf::from(field(&map, OUT_SCRIPT).ok_or(WalletError::Unsupported("PSBTv2 output without script"))?.to_vec());
+            map.retain(|(key, _)| key[..] != [OUT_AMOUNT] && key[..] != [OUT_SCRIPT]);
+            txouts.push(TxOut { value: Amount::from_sat(amount as u64), script_pubkey });
+            outputs.push(map);
+        }
+        if pos != bytes.len() {
+            return Err(WalletError::Unsupported("data after the PSBT"));
+        }
+
+        let transaction = Transaction { version, lock_time: lock_time(&required, fallback_lock_time)?, input: txins, output: txouts };
+        // the version 0 serialization of the same maps
+        global.retain(|(key, _)| !(GLOBAL_TX_VERSION..=GLOBAL_TX_MODIFIABLE).any(|t| key[..] == [t]) && key[..] != [GLOBAL_VERSION]);
+        let mut unsigned = serialize(&transaction.version);
+        unsigned.extend(serialize(&transaction.input));
+        unsigned.extend(serialize(&transaction.output));
+        unsigned.extend(serialize(&transaction.lock_time));
+        global.insert(0, (vec!(GLOBAL_UNSIGNED_TX), unsigned));
+        let mut v0 = MAGIC.to_vec();
+        for map in Some(global).iter().chain(inputs.iter()).chain(outputs.iter()) {
+            write_map(&mut v0, map);
+        }
+        Ok(PsbtV2 { psbt: Psbt::deserialize(&v0)?, fallback_lock_time, modifiable, required })
+    }
+
+    pub fn to_base64(&self) -> String {
+        STANDARD.encode(self.serialize())
+    }
+
+    pub fn from_base64(s: &str) -> Result<PsbtV2, WalletError> {
+        PsbtV2::deserialize(&STANDARD.decode(s).map_err(WalletError::Base64)?)
+    }
+}
+
+/// the lock time of BIP370: the highest required height if all inputs allow one, or else the highest required time,
+/// the fallback if no input requires a lock time
+fn lock_time(required: &[(Option<u32>, Option<u32>)], fallback: Option<LockTime>) -> Result<LockTime, WalletError> {
+    let constrained = required.iter().filter(|(time, height)| time.is_some() || height.is_some()).collect::<Vec<_>>();
+    if constrained.is_empty() {
+        return Ok(fallback.unwrap_or(LockTime::ZERO));
+    }
+    if constrained.iter().all(|(_, height)| height.is_some()) {
+        return Ok(LockTime::from_consensus(constrained.iter().filter_map(|(_, h)| *h).max().expect("constrained")));
+    }
+    if constrained.iter().all(|(time, _)| time.is_some()) {
+        return Ok(LockTime::from_consensus(constrained.iter().filter_map(|(t, _)| *t).max().expect("constrained")));
+    }
+    Err(WalletError::Unsupported("inputs require lock times of height and of time"))
+}
+
+/// the global, input and output maps of a PSBT
+fn maps(bytes: &[u8], inputs: usize, outputs: usize) -> Result<(Map, Vec<Map>, Vec<Map>), WalletError> {
+    let mut pos = MAGIC.len();
+    let global = read_map(bytes, &mut pos)?;
+    let inputs = (0..inputs).map(|_| read_map(bytes, &mut pos)).collect::<Result<Vec<_>, _>>()?;
+    let outputs = (0..outputs).map(|_| read_map(bytes, &mut pos)).collect::<Result<Vec<_>, _>>()?;
+    Ok((global, inputs, outputs))
+}
+
+fn field(map: &[Pair], key_type: u8) -> Option<&[u8]> {
+    map.iter().find(|(key, _)| key[..] == [key_type]).map(|(_, value)| &value[..])
+}
+
+fn u32_field(value: &[u8]) -> Result<u32, WalletError> {
+    Ok(u32::from_le_bytes(<[u8; 4]>::try_from(value).map_err(|_| WalletError::Unsupported("invalid PSBTv2 field"))?))
+}
+
+fn count_field(value: Option<&[u8]>) -> Result<u64, WalletError> {
+    let mut pos = 0;
+    let value = value.ok_or(WalletError::Unsupported("PSBTv2 without input or output count"))?;
+    let count = read_compact_size(value, &mut pos)?;
+    if pos != value.len() {
+        return Err(WalletError::Unsupported("invalid PSBTv2 count"));
+    }
+    Ok(count)
+}
+
+fn read_map(bytes: &[u8], pos: &mut usize) -> Result<Map, WalletError> {
+    let mut map = Vec::new();
+    loop {
+        let key_length = read_compact_size(bytes, pos)? as usize;
+        if key_length == 0 {
+            return Ok(map);
+        }
+        let key = read_bytes(bytes, pos, key_length)?;
+        let value_length = read_compact_size(bytes, pos)? as usize;
+        let value = read_bytes(bytes, pos, value_length)?;
+        if map.iter().any(|(k, _)| *k == key) {
+            return Err(WalletError::Unsupported("duplicate PSBT key"));
+        }
+        map.push((key, value));
+    }
+}
+
+fn read_bytes(bytes: &[u8], pos: &mut usize, length: usize) -> Result<Vec<u8>, WalletError> {
+    let end = pos.checked_add(length).filter(|end| *end <= bytes.len()).ok_or(WalletError::Unsupported("truncated PSBT"))?;
+    let data = bytes[*pos..end].to_vec();
+    *pos = end;
+    Ok(data)
+}
+
+fn read_compact_size(bytes: &[u8], pos: &mut usize) -> Result<u64, WalletError> {
+    let first = read_bytes(bytes, pos, 1)?[0];
+    let length = match first {
+        0xfd => 2,
+        0xfe => 4,
+        0xff => 8,
+        n => return Ok(n as u64)
+    };
+    let mut value = [0u8; 8];
+    value[..length].copy_from_slice(&read_bytes(bytes, pos, length)?);
+    Ok(u64::from_le_bytes(value))
+}
+
+fn write_map(bytes: &mut Vec<u8>, map: &[Pair]) {
+    for (key, value) in map {
+        bytes.extend(serialize(&VarInt(key.len() as u64)));
+        bytes.extend_from_slice(key);
+        bytes.extend(serialize(&VarInt(value.len() as u64)));
+        bytes.extend_from_slice(value);
+    }
+    bytes.push(0);
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::Network;
+
+    use account::{AccountAddressType, MasterKeyEntropy};
+    use super::*;
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+
+    #[test]
+    fn test_psbtv2() {
+        let mut master = MasterAccount::new(MasterKeyEntropy::Low, Network::Bitcoin, PASSPHRASE, None).unwrap();
+        let mut unlocker = Unlocker::new(master.encrypted(), PASSPHRASE, None, Network::Bitcoin, None).unwrap();
+        let script = master.new_account(&mut unlocker, AccountAddressType::P2WPKH, 0, 0, 10).unwrap().next_key().unwrap().script_pubkey.clone();
+        let spent = |vout: u32| (TxIn { previous_output: OutPoint { txid: Txid::all_zeros(), vout }, sequence: Sequence::ENABLE_LOCKTIME_NO_RBF, ..TxIn::default() },
+                                 Input { witness_utxo: Some(TxOut { value: Amount::from_sat(50000), script_pubkey: script.clone() }), ..Input::default() });
+        assert!(PsbtV2::new(Version::ONE, None).is_err());
+
+        // constructors add inputs and outputs independently
+        let mut psbt = PsbtV2::new(Version::TWO, Some(LockTime::from_height(100).unwrap())).unwrap();
+        assert_eq!(PsbtV2::deserialize(&psbt.serialize()).unwrap(), psbt);
+        let (txin, input) = spent(0);
+        psbt.add_input(txin.clone(), input.clone(), None).unwrap();
+        assert!(psbt.add_input(txin, input, None).is_err());
+        assert_eq!(psbt.lock_time(), LockTime::from_height(100).unwrap());
+        psbt.add_output(TxOut { value: Amount::from_sat(40000), script_pubkey: ScriptBuf::new_op_return([0u8; 4]) }, Output::default()).unwrap();
+        let (txin, input) = spent(1);
+        psbt.add_input(txin, input, Some(LockTime::from_height(800000).unwrap())).unwrap();
+        assert_eq!(psbt.lock_time(), LockTime::from_height(800000).unwrap());
+        let (txin, input) = spent(2);
+        assert!(psbt.add_input(txin, input, Some(LockTime::from_time(1700000000).unwrap())).is_err());
+        psbt.add_output(TxOut { value: Amount::from_sat(50000), script_pubkey: script.clone() }, Output::default()).unwrap();
+        assert_eq!(psbt.psbt().unsigned_tx.input.len(), 2);
+
+        let encoded = psbt.to_base64();
+        let decoded = PsbtV2::from_base64(&encoded).unwrap();
+        assert_eq!(decoded, psbt);
+        assert_eq!(decoded.to_base64(), encoded);
+        // version 0 parsers get the transaction it describes
+        assert_eq!(psbt::from_base64(&encoded).unwrap(), psbt.to_v0());
+        assert!(Psbt::deserialize(&psbt.serialize()).is_err());
+        assert_eq!(PsbtV2::from_v0(psbt.to_v0()).unwrap().modifiable(), 0);
+        assert!(PsbtV2::deserialize(&psbt.to_v0().serialize()).is_err());
+        let bytes = psbt.serialize();
+        assert!(PsbtV2::deserialize(&bytes[..bytes.len() - 1]).is_err());
+
+        // signatures commit to inputs and outputs
+        assert_eq!(psbt.sign(&master, &mut unlocker).unwrap(), 2);
+        assert_eq!(psbt.modifiable(), 0);
+        let (txin, input) = spent(3);
+        assert!(psbt.add_input(txin, input, None).is_err());
+        assert!(psbt.add_output(TxOut { value: Amount::from_sat(1000), script_pubkey: script.clone() }, Output::default()).is_err());
+        let decoded = PsbtV2::from_base64(&psbt.to_base64()).unwrap();
+        assert_eq!(decoded.psbt().inputs[1].partial_sigs.len(), 1);
+        let mut signed = decoded.to_v0();
+        psbt::finalize(&mut signed).unwrap();
+        let spent = TxOut { value: Amount::from_sat(50000), script_pubkey: script };
+        psbt::extract(&signed).unwrap().verify(|_| Some(spent.clone())).unwrap();
+    }
+}
@@ -0,0 +1,565 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # QR codes
+//!
+//! PSBTs and descriptors as UR and BBQr, in one or animated multi-part QR codes of air-gapped signers
+//!
+
+use std::convert::TryFrom;
+
+use bitcoin::Psbt;
+use ur::ur::{self as uniform, Kind, Type};
+
+use error::WalletError;
+use psbtv2::PsbtV2;
+
+/// UR type of PSBTs, as SeedSigner, Keystone and Sparrow read and write them
+pub const UR_PSBT: &str = "crypto-psbt";
+/// UR type of PSBTs in the current registry
+pub const UR_PSBT_V2: &str = "psbt";
+/// UR type of a descriptor in text form
+pub const UR_OUTPUT_DESCRIPTOR: &str = "output-descriptor";
+
+// CBOR major types
+const CBOR_UNSIGNED: u8 = 0;
+const CBOR_BYTES: u8 = 2;
+const CBOR_TEXT: u8 = 3;
+const CBOR_ARRAY: u8 = 4;
+const CBOR_MAP: u8 = 5;
+const CBOR_TAG: u8 = 6;
+
+/// the payload of a UR
+#[derive(Clone, Debug, PartialEq)]
+pub enum UrPayload {
+    Psbt(Psbt),
+    Descriptor(String)
+}
+
+/// encodes the parts of a UR, a single part if the payload fits the fragment length.
+/// Parts beyond the fragment count mix fragments, a scanner decodes any set of enough parts.
+pub struct UrEncoder {
+    encoder: uniform::Encoder<'static>,
+    single: Option<String>
+}
+
+impl UrEncoder {
+    /// a PSBT with up to max_fragment_length bytes per part
+    pub fn psbt(psbt: &Psbt, max_fragment_length: usize) -> Result<UrEncoder, WalletError> {
+        UrEncoder::new(&cbor_bytes(&psbt.serialize()), max_fragment_length, UR_PSBT)
+    }
+
+    /// a descriptor with up to max_fragment_length bytes per part
+    pub fn descriptor(descriptor: &str, max_fragment_length: usize) -> Result<UrEncoder, WalletError> {
+        UrEncoder::new(&cbor_descriptor(descriptor), max_fragment_length, UR_OUTPUT_DESCRIPTOR)
+    }
+
+    fn new(message: &[u8], max_fragment_length: usize, ur_type: &'static str) -> Result<UrEncoder, WalletError> {
+        let encoder = uniform::Encoder::new(message, max_fragment_length, ur_type).map_err(ur_error)?;
+        let single = if encoder.fragment_count() == 1 { Some(uniform::encode(message, &Type::Custom(ur_type))) } else { None };
+        Ok(UrEncoder { encoder, single })
+    }
+
+    /// number of parts that carry the payload once
+    pub fn fragment_count(&self) -> usize {
+        self.encoder.fragment_count()
+    }
+
+    /// the next part to show, loop through parts for an animated QR code
+    pub fn next_part(&mut self) -> Result<String, WalletError> {
+        match self.single {
+            Some(ref single) => Ok(single.clone()),
+            None => self.encoder.next_part().map_err(ur_error)
+        }
+    }
+}
+
+/// a PSBT as a single part UR
+pub fn psbt_to_ur(psbt: &Psbt) -> String {
+    uniform::encode(&cbor_bytes(&psbt.serialize()), &Type::Custom(UR_PSBT))
+}
+
+/// a descriptor as a single part UR
+pub fn descriptor_to_ur(descriptor: &str) -> String {
+    uniform::encode(&cbor_descriptor(descriptor), &Type::Custom(UR_OUTPUT_DESCRIPTOR))
+}
+
+/// collects scanned parts of a UR until the payload is complete
+#[derive(Default)]
+pub struct UrDecoder {
+    decoder: uniform::Decoder,
+    ur_type: Option<String>,
+    single: Option<Vec<u8>>
+}
+
+impl UrDecoder {
+    pub fn new() -> UrDecoder {
+        UrDecoder::default()
+    }
+
+    /// receive a scanned part, upper case of QR alphanumeric mode is accepted.
+    /// Returns true once the payload is complete.
+    pub fn receive(&mut self, part: &str) -> Result<bool, WalletError> {
+        let part = part.trim().to_lowercase();
+        let ur_type = part.strip_prefix("ur:").and_then(|p| p.split('/').next())
+            .ok_or_else(|| WalletError::Qr("not a UR".to_string()))?.to_string();
+        match self.ur_type {
+            Some(ref known) if *known != ur_type => return Err(WalletError::Qr(format!("part of a {} UR while decoding {}", ur_type, known))),
+            _ => self.ur_type = Some(ur_type)
+        }
+        if self.complete() {
+            return Ok(true);
+        }
+        match uniform::decode(&part).map_err(ur_error)? {
+            (Kind::SinglePart, message) => self.single = Some(message),
+            (Kind::MultiPart, _) => self.decoder.receive(&part).map_err(ur_error)?
+        }
+        Ok(self.complete())
+    }
+
+    /// received enough parts
+    pub fn complete(&self) -> bool {
+        self.single.is_some() || self.decoder.complete()
+    }
+
+    /// the payload once complete
+    pub fn payload(&self) -> Result<Option<UrPayload>, WalletError> {
+        let message = match self.single {
+            Some(ref message) => message.clone(),
+            None => match self.decoder.message().map_err(ur_error)? {
+                Some(message) => message,
+                None => return Ok(None)
+            }
+        };
+        match self.ur_type.as_deref() {
+            Some(UR_PSBT) | Some(UR_PSBT_V2) => Ok(Some(UrPayload::Psbt(decode_psbt(&decode_cbor_bytes(&message)?)?))),
+            Some(UR_OUTPUT_DESCRIPTOR) => Ok(Some(UrPayload::Descriptor(decode_cbor_descriptor(&message)?))),
+            Some(other) => Err(WalletError::Qr(format!("unsupported UR type {}", other))),
+            None => Ok(None)
+        }
+    }
+}
+
+/// decode a single part UR
+pub fn from_ur(ur: &str) -> Result<UrPayload, WalletError> {
+    let mut decoder = UrDecoder::new();
+    if !decoder.receive(ur)? {
+        return Err(WalletError::Qr("part of a multi-part UR".to_string()));
+    }
+    decoder.payload()?.ok_or_else(|| WalletError::Qr("incomplete UR".to_string()))
+}
+
+fn decode_psbt(bytes: &[u8]) -> Result<Psbt, WalletError> {
+    match Psbt::deserialize(bytes) {
+        Ok(psbt) => Ok(psbt),
+        Err(e) => PsbtV2::deserialize(bytes).map(|v2| v2.to_v0()).map_err(|_| e.into())
+    }
+}
+
+fn ur_error(e: uniform::Error) -> WalletError {
+    WalletError::Qr(e.to_string())
+}
+
+fn cbor_header(major: u8, value: u64, out: &mut Vec<u8>) {
+    let major = major << 5;
+    if value < 24 {
+        out.push(major | value as u8);
+    } else if value <= 0xff {
+        out.push(major | 24);
+        out.push(value as u8);
+    } else if value <= 0xffff {
+        out.push(major | 25);
+        out.extend_from_slice(&(value as u16).to_be_bytes());
+    } else if value <= 0xffff_ffff {
+        out.push(major | 26);
+        out.extend_from_slice(&(value as u32).to_be_bytes());
+    } else {
+        out.push(major | 27);
+        out.extend_from_slice(&value.to_be_bytes());
+    }
+}
+
+fn cbor_bytes(bytes: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(bytes.len() + 9);
+    cbor_header(CBOR_BYTES, bytes.len() as u64, &mut out);
+    out.extend_from_slice(bytes);
+    out
+}
+
+// BCR-2023-010 output-descriptor, a map of the descriptor text at key 1
+fn cbor_descriptor(descriptor: &str) -> Vec<u8> {
+    let mut out = Vec::with_capacity(descriptor.len() + 11);
+    cbor_header(CBOR_MAP, 1, &mut out);
+    cbor_header(CBOR_UNSIGNED, 1, &mut out);
+    cbor_header(CBOR_TEXT, descriptor.len() as u64, &mut out);
+    out.extend_from_slice(descriptor.as_bytes());
+    out
+}
+
+struct CborReader<'a> {
+    data: &'a [u8],
+    pos: usize
+}
+
+impl<'a> CborReader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], WalletError> {
+        let end = self.pos.checked_add(n).filter(|end| *end <= self.data.len())
+            .ok_or_else(|| WalletError::Qr("truncated CBOR".to_string()))?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn header(&mut self) -> Result<(u8, u64), WalletError> {
+        let first = self.take(1)?[0];
+        let value = match first & 0x1f {
+            n if n < 24 => n as u64,
+            24 => self.take(1)?[0] as u64,
+            25 => self.take(2)?.iter().fold(0u64, |v, b| v << 8 | *b as u64),
+            26 => self.take(4)?.iter().fold(0u64, |v, b| v << 8 | *b as u64),
+            27 => self.take(8)?.iter().fold(0u64, |v, b| v << 8 | *b as u64),
+            _ => return Err(WalletError::Qr("indefinite length CBOR is not supported".to_string()))
+        };
+        Ok((first >> 5, value))
+    }
+
+    fn string(&mut self, major: u8, len: u64) -> Result<&'a [u8], WalletError> {
+        if major != CBOR_BYTES && major != CBOR_TEXT {
+            return Err(WalletError::Qr("expected a CBOR string".to_string()));
+        }
+        self.take(usize::try_from(len).map_err(|_| WalletError::Qr("CBOR string too long".to_string()))?)
+    }
+
+    // skip an item whose header was read
+    fn skip(&mut self, major: u8, value: u64) -> Result<(), WalletError> {
+        let items = match major {
+            CBOR_BYTES | CBOR_TEXT => { self.string(major, value)?; 0 },
+            CBOR_ARRAY => value,
+            CBOR_MAP => value.checked_mul(2).ok_or_else(|| WalletError::Qr("CBOR map too long".to_string()))?,
+            CBOR_TAG => 1,
+            _ => 0
+        };
+        for _ in 0..items {
+            let (major, value) = self.header()?;
+            self.skip(major, value)?;
+        }
+        Ok(())
+    }
+
+    // the header of the next item that is not a tag
+    fn untagged(&mut self) -> Result<(u8, u64), WalletError> {
+        loop {
+            match self.header()? {
+                (CBOR_TAG, _) => continue,
+                header => return Ok(header)
+            }
+        }
+    }
+}
+
+fn decode_cbor_bytes(data: &[u8]) -> Result<Vec<u8>, WalletError> {
+    let mut reader = CborReader { data, pos: 0 };
+    match reader.untagged()? {
+        (CBOR_BYTES, len) => Ok(reader.string(CBOR_BYTES, len)?.to_vec()),
+        _ => Err(WalletError::Qr("expected CBOR bytes".to_string()))
+    }
+}
+
+fn decode_cbor_descriptor(data: &[u8]) -> Result<String, WalletError> {
+    let mut reader = CborReader { data, pos: 0 };
+    let entries = match reader.untagged()? {
+        (CBOR_MAP, entries) => entries,
+        _ => return Err(WalletError::Qr("expected a CBOR map".to_string()))
+    };
+    let mut descriptor = None;
+    for _ in 0..entries {
+        let (major, key) = reader.header()?;
+        let (value_major, value) = reader.header()?;
+        if major == CBOR_UNSIGNED && key == 1 && value_major == CBOR_TEXT {
+            let text = reader.string(CBOR_TEXT, value)?;
+            descriptor = Some(String::from_utf8(text.to_vec()).map_err(|_| WalletError::Qr("descriptor is not UTF-8".to_string()))?);
+        } else {
+            reader.skip(value_major, value)?;
+        }
+    }
+    descriptor.ok_or_else(|| WalletError::Qr("no descriptor in output-descriptor".to_string()))
+}
+
+const BASE32: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+const BASE36: &[u8; 36] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+const BBQR_HEADER: usize = 8;
+
+/// file type of a BBQr
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BbqrType {
+    Psbt,
+    Transaction,
+    Json,
+    Cbor,
+    /// text, e.g. a descriptor
+    Unicode
+}
+
+impl BbqrType {
+    fn code(self) -> u8 {
+        match self {
+            BbqrType::Psbt => b'P',
+            BbqrType::Transaction => b'T',
+            BbqrType::Json => b'J',
+            BbqrType::Cbor => b'C',
+            BbqrType::Unicode => b'U'
+        }
+    }
+
+    fn from_code(code: u8) -> Result<BbqrType, WalletError> {
+        match code {
+            b'P' => Ok(BbqrType::Psbt),
+            b'T' => Ok(BbqrType::Transaction),
+            b'J' => Ok(BbqrType::Json),
+            b'C' => Ok(BbqrType::Cbor),
+            b'U' => Ok(BbqrType::Unicode),
+            _ => Err(WalletError::Qr(format!("unsupported BBQr file type {}", code as char)))
+        }
+    }
+}
+
+/// split data into BBQr parts of base32 encoding with up to max_chars characters each, header included
+pub fn to_bbqr(data: &[u8], file_type: BbqrType, max_chars: usize) -> Result<Vec<String>, WalletError> {
+    // base32 parts other than the last are whole groups of 8 characters
+    let per_part = max_chars.saturating_sub(BBQR_HEADER) / 8 * 8;
+    if per_part == 0 {
+        return Err(WalletError::Qr("BBQr parts must hold more than their header".to_string()));
+    }
+    let encoded = base32_encode(data);
+    let chunks = encoded.as_bytes().chunks(per_part).collect::<Vec<_>>();
+    let total = chunks.len().max(1);
+    if total > 36 * 36 - 1 {
+        return Err(WalletError::Qr("too many BBQr parts".to_string()));
+    }
+    let mut parts = Vec::with_capacity(total);
+    for index in 0..total {
+        let mut part = format!("B$2{}{}{}", file_type.code() as char, base36(total), base36(index));
+        part.push_str(chunks.get(index).map(|c| std::str::from_utf8(c).expect("base32 is ascii")).unwrap_or(""));
+        parts.push(part);
+    }
+    Ok(parts)
+}
+
+/// a PSBT as BBQr parts
+pub fn psbt_to_bbqr(psbt: &Psbt, max_chars: usize) -> Result<Vec<String>, WalletError> {
+    to_bbqr(&psbt.serialize(), BbqrType::Psbt, max_chars)
+}
+
+/// a descriptor as BBQr parts
+pub fn descriptor_to_bbqr(descriptor: &str, max_chars: usize) -> Result<Vec<String>, WalletError> {
+    to_bbqr(descriptor.as_bytes(), BbqrType::Unicode, max_chars)
+}
+
+/// collects scanned BBQr parts in any order until all arrived.
+/// Hex and base32 encodings are decoded, zlib compressed parts are not supported.
+#[derive(Default)]
+pub struct BbqrDecoder {
+    encoding: u8,
+    file_type: Option<BbqrType>,
+    parts: Vec<Option<Vec<u8>>>
+}
+
+impl BbqrDecoder {
+    pub fn new() -> BbqrDecoder {
+        BbqrDecoder::default()
+    }
+
+    /// receive a scanned part, returns true once all parts arrived
+    pub fn receive(&mut self, part: &str) -> Result<bool, WalletError> {
+        let part = part.trim().as_bytes();
+        if part.len() < BBQR_HEADER || &part[..2] != b"B$" {
+            return Err(WalletError::Qr("not a BBQr".to_string()));
+        }
+        let encoding = part[2];
+        if encoding == b'Z' {
+            return Err(WalletError::Qr("zlib compressed BBQr is not supported".to_string()));
+        }
+        if encoding != b'2' && encoding != b'H' {
+            return Err(WalletError::Qr(format!("unknown BBQr encoding {}", encoding as char)));
+        }
+        let file_type = BbqrType::from_code(part[3])?;
+        let total = from_base36(&part[4..6])?;
+        let index = from_base36(&part[6..8])?;
+        if total == 0 || index >= total {
+            return Err(WalletError::Qr("invalid BBQr part index".to_string()));
+        }
+        match self.file_type {
+            None => {
+                self.encoding = encoding;
+                self.file_type = Some(file_type);
+                self.parts = vec![None; total];
+            },
+            Some(known) if known != file_type || self.encoding != encoding || self.parts.len() != total =>
+                return Err(WalletError::Qr("part of another BBQr".to_string())),
+            _ => {}
+        }
+        let payload = std::str::from_utf8(&part[BBQR_HEADER..]).map_err(|_| WalletError::Qr("BBQr is not ascii".to_string()))?;
+        self.parts[index] = Some(if encoding == b'H' { hex_decode(payload)? } else { base32_decode(payload)? });
+        Ok(self.complete())
+    }
+
+    /// received all parts
+    pub fn complete(&self) -> bool {
+        !self.parts.is_empty() && self.parts.iter().all(|p| p.is_some())
+    }
+
+    /// the file type and data once complete
+    pub fn data(&self) -> Option<(BbqrType, Vec<u8>)> {
+        if !self.complete() {
+            return None;
+        }
+        Some((self.file_type?, self.parts.iter().flat_map(|p| p.iter().flatten().cloned()).collect()))
+    }
+
+    /// the PSBT once complete
+    pub fn psbt(&self) -> Result<Option<Psbt>, WalletError> {
+        match self.data() {
+            Some((BbqrType::Psbt, data)) => Ok(Some(decode_psbt(&data)?)),
+            Some(_) => Err(WalletError::Qr("BBQr is not a PSBT".to_string())),
+            None => Ok(None)
+        }
+    }
+
+    /// the text, e.g. a descriptor, once complete
+    pub fn text(&self) -> Result<Option<String>, WalletError> {
+        match self.data() {
+            Some((BbqrType::Unicode, data)) | Some((BbqrType::Json, data)) =>
+                String::from_utf8(data).map(Some).map_err(|_| WalletError::Qr("BBQr text is not UTF-8".to_string())),
+            Some(_) => Err(WalletError::Qr("BBQr is not text".to_string())),
+            None => Ok(None)
+        }
+    }
+}
+
+fn base36(n: usize) -> String {
+    [BASE36[n / 36] as char, BASE36[n % 36] as char].iter().collect()
+}
+
+fn from_base36(digits: &[u8]) -> Result<usize, WalletError> {
+    digits.iter().try_fold(0usize, |n, d| {
+        BASE36.iter().position(|c| c == d).map(|v| n * 36 + v)
+            .ok_or_else(|| WalletError::Qr("invalid BBQr header".to_string()))
+    })
+}
+
+// RFC 4648 without padding
+fn base32_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(5) * 8);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for byte in data {
+        buffer = buffer << 8 | *byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32[(buffer >> bits) as usize & 31] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32[(buffer << (5 - bits)) as usize & 31] as char);
+    }
+    out
+}
+
+fn base32_decode(s: &str) -> Result<Vec<u8>, WalletError> {
+    let mut out = Vec::with_capacity(s.len() * 5 / 8);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for c in s.trim_end_matches('=').bytes() {
+        let value = BASE32.iter().position(|b| *b == c).ok_or_else(|| WalletError::Qr("invalid base32".to_string()))?;
+        buffer = buffer << 5 | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    Ok(out)
+}
+
+fn hex_decode(s: &str) -> Result<Vec<u8>, WalletError> {
+    if !s.len().is_multiple_of(2) {
+        return Err(WalletError::Qr("odd length hex".to_string()));
+    }
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).map_err(|_| WalletError::Qr("invalid hex".to_string()))).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{Amount, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Txid, Witness};
+    use bitcoin::absolute::LockTime;
+    use bitcoin::transaction::Version;
+    use bitcoin_hashes::Hash;
+
+    use super::*;
+
+    const DESCRIPTOR: &str = "wpkh([d34db33f/84'/0'/0']xpub6CatWdiZiodmUeTDp8LT5or8nmbKNcuyvz7WyksVFkKB4RHwCD3XyuvPEbvqAQY3rAPshWcMLoP2fMFMKHPJ4ZeZXYVUhLv1VMrjPC7PW6V/0/*)";
+
+    #[test]
+    fn test_qr() {
+        let transaction = Transaction {
+            version: Version::TWO, lock_time: LockTime::ZERO,
+            input: (0..20u8).map(|i| TxIn { previous_output: OutPoint { txid: Txid::from_byte_array([i; 32]), vout: 0 }, script_sig: ScriptBuf::new(), sequence: Sequence::MAX, witness: Witness::new() }).collect(),
+            output: vec!(TxOut { value: Amount::from_sat(10000), script_pubkey: ScriptBuf::new_op_return([0u8; 4]) })
+        };
+        let psbt = Psbt::from_unsigned_tx(transaction).unwrap();
+
+        let ur = psbt_to_ur(&psbt);
+        assert!(ur.starts_with("ur:crypto-psbt/"));
+        assert_eq!(from_ur(&ur.to_uppercase()).unwrap(), UrPayload::Psbt(psbt.clone()));
+        assert_eq!(from_ur(&descriptor_to_ur(DESCRIPTOR)).unwrap(), UrPayload::Descriptor(DESCRIPTOR.to_string()));
+        assert!(from_ur("ur:bytes/iehsjyhspmwfwfia").is_err());
+
+        // animated, skipping parts the fountain code recovers
+        let mut encoder = UrEncoder::psbt(&psbt, 100).unwrap();
+        assert!(encoder.fragment_count() > 1);
+        let mut decoder = UrDecoder::new();
+        let mut complete = false;
+        for i in 0..10 * encoder.fragment_count() {
+            let part = encoder.next_part().unwrap();
+            if i % 3 != 1 && !complete {
+                complete = decoder.receive(&part).unwrap();
+            }
+        }
+        assert!(complete);
+        assert_eq!(decoder.payload().unwrap(), Some(UrPayload::Psbt(psbt.clone())));
+        assert_eq!(UrEncoder::descriptor(DESCRIPTOR, 1000).unwrap().next_part().unwrap(), descriptor_to_ur(DESCRIPTOR));
+
+        // BBQr parts in any order
+        let parts = psbt_to_bbqr(&psbt, 300).unwrap();
+        assert!(parts.len() > 1 && parts.iter().all(|p| p.len() <= 300 && p.starts_with("B$2P")));
+        let mut decoder = BbqrDecoder::new();
+        for part in parts.iter().rev() {
+            assert_eq!(decoder.psbt().unwrap(), None);
+            decoder.receive(part).unwrap();
+        }
+        assert_eq!(decoder.psbt().unwrap(), Some(psbt));
+        let mut decoder = BbqrDecoder::new();
+        assert!(decoder.receive(&descriptor_to_bbqr(DESCRIPTOR, 1000).unwrap()[0]).unwrap());
+        assert_eq!(decoder.text().unwrap().unwrap(), DESCRIPTOR);
+        let mut decoder = BbqrDecoder::new();
+        assert!(decoder.receive("B$HU010068656c6c6f").unwrap());
+        assert_eq!(decoder.text().unwrap().unwrap(), "hello");
+        assert!(BbqrDecoder::new().receive("B$ZP0100ABCD").is_err());
+        assert_eq!(base32_decode(&base32_encode(b"hello world")).unwrap(), b"hello world");
+        assert_eq!(base32_encode(b"foobar"), "MZXW6YTBOI");
+    }
+}
@@ -0,0 +1,95 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # SeedQR
+//!
+//! English 12 and 24 word mnemonics as the SeedQR and CompactSeedQR codes of SeedSigner
+//!
+
+use error::WalletError;
+use mnemonic::{Language, Mnemonic};
+use secret::SecretBytes;
+
+/// the digits of a SeedQR, the wordlist index of each word in four digits.
+/// Encode them in the numeric mode of a QR code.
+pub fn encode(mnemonic: &Mnemonic) -> Result<String, WalletError> {
+    check(mnemonic)?;
+    Ok(mnemonic.indices().iter().map(|i| format!("{:04}", i)).collect())
+}
+
+/// the mnemonic of the digits of a SeedQR
+pub fn decode(digits: &str) -> Result<Mnemonic, WalletError> {
+    let digits = digits.trim().as_bytes();
+    if (digits.len() != 48 && digits.len() != 96) || !digits.iter().all(|d| d.is_ascii_digit()) {
+        return Err(WalletError::Mnemonic("SeedQR must have 48 or 96 digits"));
+    }
+    let indices = digits.chunks(4).map(|c| c.iter().fold(0usize, |i, d| i * 10 + (d - b'0') as usize)).collect::<Vec<_>>();
+    let mnemonic = Mnemonic::from_indices(&indices, Language::English)?;
+    mnemonic.entropy()?;
+    Ok(mnemonic)
+}
+
+/// the bytes of a CompactSeedQR, the 16 or 32 bytes of entropy without checksum.
+/// Encode them in the byte mode of a QR code.
+pub fn encode_compact(mnemonic: &Mnemonic) -> Result<SecretBytes, WalletError> {
+    check(mnemonic)?;
+    mnemonic.entropy()
+}
+
+/// the mnemonic of the bytes of a CompactSeedQR
+pub fn decode_compact(bytes: &[u8]) -> Result<Mnemonic, WalletError> {
+    if bytes.len() != 16 && bytes.len() != 32 {
+        return Err(WalletError::Mnemonic("CompactSeedQR must have 16 or 32 bytes"));
+    }
+    Mnemonic::new_in(bytes, Language::English)
+}
+
+fn check(mnemonic: &Mnemonic) -> Result<(), WalletError> {
+    if mnemonic.language() != Language::English {
+        return Err(WalletError::Mnemonic("SeedQR encodes english mnemonics only"));
+    }
+    match mnemonic.iter().count() {
+        12 | 24 => Ok(()),
+        _ => Err(WalletError::Mnemonic("SeedQR encodes 12 or 24 word mnemonics only"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_seedqr() {
+        // example of the SeedQR specification
+        let mnemonic = Mnemonic::from_str("attack pizza motion avocado network gather crop fresh patrol unusual wild holiday candy pony ranch winter theme error hybrid van cereal salon goddess expire").unwrap();
+        let digits = "011513251154012711900771041507421289190620080870026613431420201617920614089619290300152408010643";
+        assert_eq!(encode(&mnemonic).unwrap(), digits);
+        assert_eq!(decode(digits).unwrap(), mnemonic);
+        let compact = encode_compact(&mnemonic).unwrap();
+        assert_eq!(compact.len(), 32);
+        assert_eq!(decode_compact(&compact).unwrap(), mnemonic);
+
+        let short = Mnemonic::from_str("letter advice cage absurd amount doctor acoustic avoid letter advice cage above").unwrap();
+        assert_eq!(decode(&encode(&short).unwrap()).unwrap(), short);
+        assert_eq!(decode_compact(&encode_compact(&short).unwrap()).unwrap(), short);
+        // wrong checksum, length or index
+        assert!(decode(&digits.replace("0643", "0644")).is_err());
+        assert!(decode(&digits[..92]).is_err());
+        assert!(decode(&format!("2048{}", &digits[4..48])).is_err());
+        assert!(decode_compact(&[0u8; 20]).is_err());
+        assert!(encode(&Mnemonic::new(&[0u8; 20]).unwrap()).is_err());
+    }
+}
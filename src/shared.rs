@@ -0,0 +1,110 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Shared master
+//!
+//! A master account shared among threads, e.g. the request handlers of a server
+//!
+
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use bitcoin::{Address, Script};
+use bitcoin::bip32::KeySource;
+
+use account::{AddressStatus, InstantiatedKey, MasterAccount};
+use error::WalletError;
+
+/// a master account behind a lock, clones share it. Address cursors move under the write lock,
+/// so that threads deriving addresses at the same time never get the same key.
+/// Master accounts, accounts and coins are Send and Sync, the lock only orders their changes.
+#[derive(Clone)]
+pub struct SharedMaster {
+    master: Arc<RwLock<MasterAccount>>
+}
+
+impl SharedMaster {
+    pub fn new(master: MasterAccount) -> SharedMaster {
+        SharedMaster { master: Arc::new(RwLock::new(master)) }
+    }
+
+    /// read the master, any number of threads at once
+    pub fn read(&self) -> RwLockReadGuard<'_, MasterAccount> {
+        self.master.read().expect("master lock poisoned")
+    }
+
+    /// change the master, e.g. add accounts
+    pub fn write(&self) -> RwLockWriteGuard<'_, MasterAccount> {
+        self.master.write().expect("master lock poisoned")
+    }
+
+    /// a new key of an account with its origin, see `MasterAccount::next_key`
+    pub fn next_key(&self, account: (u32, u32)) -> Result<(InstantiatedKey, KeySource), WalletError> {
+        self.write().next_key(account)
+    }
+
+    /// next address of the external chain of an account
+    pub fn next_receive_address(&self, account_number: u32) -> Result<Address, WalletError> {
+        self.write().next_receive_address(account_number)
+    }
+
+    /// next address of the internal chain of an account
+    pub fn next_change_address(&self, account_number: u32) -> Result<Address, WalletError> {
+        self.write().next_change_address(account_number)
+    }
+
+    /// usage of an address of the master, none if the script is not of its keys
+    pub fn address_status(&self, script_pubkey: &Script) -> Option<AddressStatus> {
+        self.read().address_status(script_pubkey)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+    use std::thread;
+
+    use bitcoin::Network;
+
+    use account::{Account, AccountAddressType, MasterKeyEntropy, Unlocker};
+    use coins::Coins;
+    use super::*;
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+
+    fn send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn test_shared() {
+        send_sync::<MasterAccount>();
+        send_sync::<Account>();
+        send_sync::<Coins>();
+        send_sync::<SharedMaster>();
+
+        let mut master = MasterAccount::new(MasterKeyEntropy::Low, Network::Bitcoin, PASSPHRASE, None).unwrap();
+        let mut unlocker = Unlocker::new(master.encrypted(), PASSPHRASE, None, Network::Bitcoin, None).unwrap();
+        master.new_keychains(&mut unlocker, AccountAddressType::P2WPKH, 0, 5).unwrap();
+        let shared = SharedMaster::new(master);
+        let threads = (0..8).map(|_| {
+            let shared = shared.clone();
+            thread::spawn(move || (0..10).map(|_| shared.next_receive_address(0).unwrap()).collect::<Vec<_>>())
+        }).collect::<Vec<_>>();
+        let addresses = threads.into_iter().flat_map(|t| t.join().unwrap()).collect::<HashSet<_>>();
+        assert_eq!(addresses.len(), 80);
+        assert_eq!(shared.read().get((0, 0)).unwrap().next(), 80);
+        assert!(addresses.iter().all(|a| shared.address_status(&a.script_pubkey()) == Some(AddressStatus::Fresh)));
+        assert!(shared.next_change_address(1).is_err());
+    }
+}
@@ -0,0 +1,220 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Signature hashes
+//!
+//! Signature hashes of all inputs of a transaction computed from shared midstates
+//!
+
+use bitcoin::{EcdsaSighashType, PublicKey, Script, ScriptBuf, Transaction, TxOut, XOnlyPublicKey, ecdsa, taproot};
+use bitcoin::script::Instruction;
+use bitcoin::sighash::{Prevouts, SighashCache};
+use bitcoin_hashes::Hash;
+
+use account::{tap_sighash_type, AccountAddressType};
+use context::{SecpContext, SigningHash};
+use error::WalletError;
+
+/// signature hashes of the inputs of one transaction
+/// the legacy serialization, the BIP143 and the BIP341 midstates are computed once and shared by all inputs
+pub struct TxSighashes<'a> {
+    transaction: &'a Transaction,
+    cache: SighashCache<&'a Transaction>,
+    prevouts: Option<Vec<TxOut>>
+}
+
+impl<'a> TxSighashes<'a> {
+    /// prevouts are the outputs spent by all inputs in order, taproot inputs can not be hashed without them
+    pub fn new(transaction: &'a Transaction, prevouts: Option<Vec<TxOut>>) -> TxSighashes<'a> {
+        TxSighashes { transaction, cache: SighashCache::new(transaction), prevouts }
+    }
+
+    /// the hash a key of the address type signs to spend input ix
+    /// script code is the P2PKH script of segwit v0 keys or the witness script of P2WSH
+    pub fn signing_hash(&mut self, ix: usize, address_type: AccountAddressType, script_code: &Script, spent: &TxOut, hash_type: EcdsaSighashType) -> Result<SigningHash, WalletError> {
+        if ix >= self.transaction.input.len() {
+            return Err(WalletError::Unsupported("input index out of range"));
+        }
+        Ok(match address_type {
+            AccountAddressType::P2PKH =>
+                SigningHash::Ecdsa(self.cache.legacy_signature_hash(ix, &spent.script_pubkey, hash_type.to_u32())
+                    .expect("input index is in range").to_byte_array()),
+            AccountAddressType::P2WPKH | AccountAddressType::P2SHWPKH | AccountAddressType::P2WSH(_) =>
+                SigningHash::Ecdsa(self.cache.p2wsh_signature_hash(ix, script_code, spent.value, hash_type)
+                    .expect("input index is in range").to_byte_array()),
+            AccountAddressType::P2TR => {
+                let prevouts = self.prevouts.as_ref()
+                    .ok_or(WalletError::Unsupported("taproot signing needs all spent outputs"))?;
+                // fails for SIGHASH_SINGLE without an output at ix or if prevouts do not match the inputs
+                SigningHash::Schnorr(self.cache.taproot_key_spend_signature_hash(ix, &Prevouts::All(prevouts), tap_sighash_type(hash_type))?
+                    .to_byte_array())
+            }
+        })
+    }
+
+    /// true if input ix carries a valid signature spending the output
+    /// P2PKH, P2WPKH, P2SH-P2WPKH and taproot key path spends are understood
+    pub fn verify(&mut self, context: &SecpContext, ix: usize, spent: &TxOut) -> Result<bool, WalletError> {
+        let input = self.transaction.input.get(ix).ok_or(WalletError::Unsupported("input index out of range"))?;
+        let script_pubkey = &spent.script_pubkey;
+        if script_pubkey.is_p2pkh() {
+            let pushes = pushes(&input.script_sig);
+            if pushes.len() != 2 {
+                return Ok(false);
+            }
+            return Ok(match key_and_signature(&pushes[1], &pushes[0]) {
+                Some((public, signature)) if ScriptBuf::new_p2pkh(&public.pubkey_hash()) == *script_pubkey => {
+                    let hash = self.cache.legacy_signature_hash(ix, script_pubkey, signature.sighash_type.to_u32())
+                        .expect("input index is in range");
+                    context.verify(hash.as_byte_array(), &signature.signature, &public)
+                }
+                _ => false
+            });
+        }
+        let witness_script = if script_pubkey.is_p2sh() {
+            match pushes(&input.script_sig).as_slice() {
+                [redeem] if ScriptBuf::new_p2sh(&Script::from_bytes(redeem).script_hash()) == *script_pubkey => ScriptBuf::from_bytes(redeem.clone()),
+                _ => return Ok(false)
+            }
+        } else {
+            script_pubkey.clone()
+        };
+        if witness_script.is_p2wpkh() {
+            if input.witness.len() != 2 {
+                return Ok(false);
+            }
+            return Ok(match key_and_signature(&input.witness[1], &input.witness[0]) {
+                Some((public, signature)) if public.wpubkey_hash().map(|h| ScriptBuf::new_p2wpkh(&h)).ok() == Some(witness_script.clone()) => {
+                    let script_code = ScriptBuf::new_p2pkh(&public.pubkey_hash());
+                    let hash = self.cache.p2wsh_signature_hash(ix, &script_code, spent.value, signature.sighash_type)
+                        .expect("input index is in range");
+                    context.verify(hash.as_byte_array(), &signature.signature, &public)
+                }
+                _ => false
+            });
+        }
+        if script_pubkey.is_p2tr() {
+            let prevouts = self.prevouts.as_ref()
+                .ok_or(WalletError::Unsupported("taproot verification needs all spent outputs"))?;
+            if input.witness.len() != 1 {
+                return Ok(false);
+            }
+            let (signature, output_key) = match (taproot::Signature::from_slice(&input.witness[0]), XOnlyPublicKey::from_slice(&script_pubkey.as_bytes()[2..])) {
+                (Ok(signature), Ok(output_key)) => (signature, output_key),
+                _ => return Ok(false)
+            };
+            return Ok(match self.cache.taproot_key_spend_signature_hash(ix, &Prevouts::All(prevouts), signature.sighash_type) {
+                Ok(hash) => context.verify_schnorr(hash.as_byte_array(), &signature.signature, &output_key),
+                Err(_) => false
+            });
+        }
+        Err(WalletError::Unsupported("can not verify spends of this script type"))
+    }
+}
+
+/// data pushed by a script, empty if it does anything else
+fn pushes(script: &Script) -> Vec<Vec<u8>> {
+    let mut pushes = Vec::new();
+    for instruction in script.instructions() {
+        match instruction {
+            Ok(Instruction::PushBytes(bytes)) => pushes.push(bytes.as_bytes().to_vec()),
+            _ => return Vec::new()
+        }
+    }
+    pushes
+}
+
+fn key_and_signature(public: &[u8], signature: &[u8]) -> Option<(PublicKey, ecdsa::Signature)> {
+    Some((PublicKey::from_slice(public).ok()?, ecdsa::Signature::from_slice(signature).ok()?))
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{Amount, Network, OutPoint, Sequence, TxIn, Txid, Witness};
+    use bitcoin::absolute::LockTime;
+    use bitcoin::transaction::Version;
+
+    use account::{MasterAccount, MasterKeyEntropy, Unlocker};
+    use super::*;
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+
+    #[test]
+    fn test_sign_verify() {
+        let mut master = MasterAccount::new(MasterKeyEntropy::Low, Network::Bitcoin, PASSPHRASE, None).unwrap();
+        let mut unlocker = Unlocker::new(master.encrypted(), PASSPHRASE, None, Network::Bitcoin, None).unwrap();
+        let mut spent = Vec::new();
+        for (n, address_type) in [AccountAddressType::P2PKH, AccountAddressType::P2SHWPKH, AccountAddressType::P2WPKH, AccountAddressType::P2TR].iter().enumerate() {
+            let account = master.new_account(&mut unlocker, *address_type, n as u32, 0, 10).unwrap();
+            spent.push(TxOut { value: Amount::from_sat(10000 * (n as u64 + 1)), script_pubkey: account.next_key().unwrap().script_pubkey.clone() });
+        }
+        let mut transaction = Transaction {
+            version: Version::TWO, lock_time: LockTime::ZERO,
+            input: (0..spent.len() as u32).map(|vout| TxIn {
+                previous_output: OutPoint { txid: Txid::all_zeros(), vout },
+                script_sig: ScriptBuf::new(), sequence: Sequence::MAX, witness: Witness::new()
+            }).collect(),
+            output: vec!(TxOut { value: Amount::from_sat(90000), script_pubkey: spent[2].script_pubkey.clone() })
+        };
+        let resolver = |point: &OutPoint| spent.get(point.vout as usize).cloned();
+        assert_eq!(master.sign(&mut transaction, EcdsaSighashType::All, &resolver, &mut unlocker).unwrap(), 4);
+
+        let context = SecpContext::new();
+        let mut sighashes = TxSighashes::new(&transaction, Some(spent.clone()));
+        for (ix, output) in spent.iter().enumerate() {
+            assert!(sighashes.verify(&context, ix, output).unwrap());
+        }
+        // signatures do not verify once the transaction changed
+        let mut changed = transaction.clone();
+        changed.output[0].value = Amount::from_sat(80000);
+        let mut sighashes = TxSighashes::new(&changed, Some(spent.clone()));
+        for (ix, output) in spent.iter().enumerate() {
+            assert!(!sighashes.verify(&context, ix, output).unwrap());
+        }
+        assert!(TxSighashes::new(&transaction, None).verify(&context, 3, &spent[3]).is_err());
+    }
+
+    #[test]
+    fn test_taproot_hash_errors() {
+        let mut master = MasterAccount::new(MasterKeyEntropy::Low, Network::Bitcoin, PASSPHRASE, None).unwrap();
+        let mut unlocker = Unlocker::new(master.encrypted(), PASSPHRASE, None, Network::Bitcoin, None).unwrap();
+        let account = master.new_account(&mut unlocker, AccountAddressType::P2TR, 0, 0, 10).unwrap();
+        let script_pubkey = account.next_key().unwrap().script_pubkey.clone();
+        let spent = vec!(TxOut { value: Amount::from_sat(10000), script_pubkey: script_pubkey.clone() },
+                         TxOut { value: Amount::from_sat(20000), script_pubkey: script_pubkey.clone() });
+        let mut transaction = Transaction {
+            version: Version::TWO, lock_time: LockTime::ZERO,
+            input: (0..2).map(|vout| TxIn {
+                previous_output: OutPoint { txid: Txid::all_zeros(), vout },
+                script_sig: ScriptBuf::new(), sequence: Sequence::MAX, witness: Witness::new()
+            }).collect(),
+            output: vec!(TxOut { value: Amount::from_sat(25000), script_pubkey })
+        };
+        let mut sighashes = TxSighashes::new(&transaction, Some(spent.clone()));
+        assert!(sighashes.signing_hash(0, AccountAddressType::P2TR, Script::new(), &spent[0], EcdsaSighashType::Single).is_ok());
+        // no output at the index of the second input
+        assert!(matches!(sighashes.signing_hash(1, AccountAddressType::P2TR, Script::new(), &spent[1], EcdsaSighashType::Single),
+                         Err(WalletError::TaprootSighash(_))));
+        // fewer prevouts than inputs
+        let mut sighashes = TxSighashes::new(&transaction, Some(spent[..1].to_vec()));
+        assert!(matches!(sighashes.signing_hash(0, AccountAddressType::P2TR, Script::new(), &spent[0], EcdsaSighashType::All),
+                         Err(WalletError::TaprootSighash(_))));
+
+        let resolver = |point: &OutPoint| spent.get(point.vout as usize).cloned();
+        assert!(master.sign(&mut transaction, EcdsaSighashType::Single, &resolver, &mut unlocker).is_err());
+        assert!(transaction.input.iter().all(|input| input.witness.is_empty()));
+    }
+}
@@ -0,0 +1,106 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Signers
+//!
+//! Holders of the master key that add signatures to PSBTs of a master account
+//!
+
+use bitcoin::{Psbt, PublicKey};
+use bitcoin::bip32::Fingerprint;
+
+use account::{MasterAccount, Unlocker};
+use antiexfil;
+use error::WalletError;
+use psbt;
+
+/// holds the master key of a master account and signs for its keys,
+/// the master account itself may be watch only
+pub trait Signer {
+    /// fingerprint of the master key
+    fn fingerprint(&mut self) -> Result<Fingerprint, WalletError>;
+
+    /// add signatures for inputs spending coins of the master account, returns the number added
+    fn sign_psbt(&mut self, master: &MasterAccount, psbt: &mut Psbt) -> Result<usize, WalletError>;
+
+    /// anti-exfil first round: nonce points of ECDSA inputs for the host's commitments, see `antiexfil::sign_psbt`
+    fn commit_nonces(&mut self, _master: &MasterAccount, _psbt: &Psbt, _commitments: &[(usize, [u8; 32])]) -> Result<Vec<(usize, PublicKey)>, WalletError> {
+        Err(WalletError::Signer("anti-exfil signing is not supported".to_string()))
+    }
+
+    /// anti-exfil second round: sign the committed inputs with nonces tweaked by the host's data
+    fn sign_psbt_anti_exfil(&mut self, _master: &MasterAccount, _psbt: &mut Psbt, _host_data: &[(usize, [u8; 32])]) -> Result<usize, WalletError> {
+        Err(WalletError::Signer("anti-exfil signing is not supported".to_string()))
+    }
+}
+
+/// the key decrypted in memory
+impl Signer for Unlocker {
+    fn fingerprint(&mut self) -> Result<Fingerprint, WalletError> {
+        Ok(Unlocker::fingerprint(self))
+    }
+
+    fn sign_psbt(&mut self, master: &MasterAccount, psbt: &mut Psbt) -> Result<usize, WalletError> {
+        psbt::sign(master, psbt, self)
+    }
+
+    fn commit_nonces(&mut self, master: &MasterAccount, psbt: &Psbt, commitments: &[(usize, [u8; 32])]) -> Result<Vec<(usize, PublicKey)>, WalletError> {
+        antiexfil::commit_nonces(self, master, psbt, commitments)
+    }
+
+    fn sign_psbt_anti_exfil(&mut self, master: &MasterAccount, psbt: &mut Psbt, host_data: &[(usize, [u8; 32])]) -> Result<usize, WalletError> {
+        antiexfil::sign_inputs(self, master, psbt, host_data)
+    }
+}
+
+/// number of signatures in a PSBT
+pub fn count_signatures(psbt: &Psbt) -> usize {
+    psbt.inputs.iter().map(|i| i.partial_sigs.len() + i.tap_key_sig.iter().count() + i.tap_script_sigs.len()).sum()
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{Amount, Network, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Txid, Witness};
+    use bitcoin::absolute::LockTime;
+    use bitcoin::transaction::Version;
+    use bitcoin_hashes::Hash;
+
+    use account::{AccountAddressType, MasterKeyEntropy};
+    use super::*;
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+
+    #[test]
+    fn test_signer() {
+        let mut master = MasterAccount::new(MasterKeyEntropy::Low, Network::Bitcoin, PASSPHRASE, None).unwrap();
+        let mut unlocker = Unlocker::new(master.encrypted(), PASSPHRASE, None, Network::Bitcoin, None).unwrap();
+        let spent = TxOut { value: Amount::from_sat(10000), script_pubkey: master.new_account(&mut unlocker, AccountAddressType::P2WPKH, 0, 0, 10).unwrap().get_key(0).unwrap().script_pubkey.clone() };
+        let mut psbt = Psbt::from_unsigned_tx(Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec!(TxIn { previous_output: OutPoint { txid: Txid::all_zeros(), vout: 0 }, script_sig: ScriptBuf::new(), sequence: Sequence::MAX, witness: Witness::new() }),
+            output: vec!(TxOut { value: Amount::from_sat(9000), script_pubkey: ScriptBuf::new_op_return([0u8; 4]) })
+        }).unwrap();
+        psbt.inputs[0].witness_utxo = Some(spent);
+        assert_eq!(master.sign_psbt(&mut psbt, &mut unlocker).unwrap(), 1);
+        assert_eq!(count_signatures(&psbt), 1);
+
+        // the key of an other master does not sign
+        let other = MasterAccount::new(MasterKeyEntropy::Low, Network::Bitcoin, PASSPHRASE, None).unwrap();
+        let mut other = Unlocker::new(other.encrypted(), PASSPHRASE, None, Network::Bitcoin, None).unwrap();
+        assert!(master.sign_psbt(&mut psbt, &mut other).is_err());
+    }
+}
@@ -0,0 +1,430 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Silent payments
+//!
+//! BIP352 silent payment addresses, outputs to them and scanning for received ones
+//!
+
+use std::fmt;
+use std::str::FromStr;
+
+use bitcoin::{consensus, Network, OutPoint, Script, ScriptBuf, TapSighashType, Transaction, TxOut, Witness, XOnlyPublicKey,
+              bip32::{ChildNumber, DerivationPath}, key::TweakedPublicKey, script::Instruction,
+              sighash::{Prevouts, SighashCache}, taproot};
+use bitcoin_hashes::{hash160, sha256, Hash, HashEngine};
+use secp256k1::{Keypair, Message, Parity, PublicKey, Scalar, Secp256k1, SecretKey};
+
+use account::{coin_type, Unlocker};
+use error::WalletError;
+
+/// BIP352 purpose
+pub const PURPOSE: u32 = 352;
+
+const CHARSET: &[u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+const BECH32M_CONST: u32 = 0x2bc830a3;
+const MAX_LENGTH: usize = 1023;
+
+/// a silent payment address, the public scan and spend keys of the receiver
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SilentPaymentAddress {
+    pub scan: PublicKey,
+    pub spend: PublicKey,
+    pub network: Network
+}
+
+impl SilentPaymentAddress {
+    /// a taproot output of the same size as the outputs to this address, to estimate fees
+    pub fn placeholder(&self) -> ScriptBuf {
+        ScriptBuf::new_p2tr_tweaked(TweakedPublicKey::dangerous_assume_tweaked(self.spend.x_only_public_key().0))
+    }
+}
+
+fn hrp(network: Network) -> &'static str {
+    match network {
+        Network::Bitcoin => "sp",
+        Network::Regtest => "sprt",
+        _ => "tsp"
+    }
+}
+
+impl fmt::Display for SilentPaymentAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut bytes = self.scan.serialize().to_vec();
+        bytes.extend_from_slice(&self.spend.serialize());
+        let mut data = vec!(0u8);
+        data.extend(convert_bits(&bytes, 8, 5, true).expect("padding allowed"));
+        let hrp = hrp(self.network);
+        data.extend(checksum(hrp, &data));
+        write!(f, "{}1{}", hrp, data.iter().map(|d| CHARSET[*d as usize] as char).collect::<String>())
+    }
+}
+
+impl FromStr for SilentPaymentAddress {
+    type Err = WalletError;
+
+    fn from_str(s: &str) -> Result<SilentPaymentAddress, WalletError> {
+        if s.len() > MAX_LENGTH || (s.to_lowercase() != s && s.to_uppercase() != s) {
+            return Err(WalletError::Unsupported("invalid silent payment address"));
+        }
+        let s = s.to_lowercase();
+        let separator = s.rfind('1').ok_or(WalletError::Unsupported("invalid silent payment address"))?;
+        let (hrp, rest) = (&s[..separator], &s[separator + 1..]);
+        let network = match hrp {
+            "sp" => Network::Bitcoin,
+            "tsp" => Network::Testnet,
+            "sprt" => Network::Regtest,
+            _ => return Err(WalletError::Unsupported("not a silent payment address"))
+        };
+        let data = rest.bytes().map(|c| CHARSET.iter().position(|x| *x == c).map(|p| p as u8))
+            .collect::<Option<Vec<u8>>>().ok_or(WalletError::Unsupported("invalid silent payment address"))?;
+        if data.len() < 7 || checksum(hrp, &data[..data.len() - 6]) != data[data.len() - 6..] {
+            return Err(WalletError::Unsupported("invalid silent payment address checksum"));
+        }
+        // version 0 has 66 bytes of keys, later versions may append data
+        let version = data[0];
+        let bytes = convert_bits(&data[1..data.len() - 6], 5, 8, false).ok_or(WalletError::Unsupported("invalid silent payment address"))?;
+        if version == 31 || (version == 0 && bytes.len() != 66) || bytes.len() < 66 {
+            return Err(WalletError::Unsupported("unsupported silent payment address version"));
+        }
+        let scan = PublicKey::from_slice(&bytes[..33])?;
+        let spend = PublicKey::from_slice(&bytes[33..66])?;
+        Ok(SilentPaymentAddress { scan, spend, network })
+    }
+}
+
+fn polymod(values: &[u8]) -> u32 {
+    let mut chk = 1u32;
+    for v in values {
+        let b = chk >> 25;
+        chk = ((chk & 0x1ffffff) << 5) ^ *v as u32;
+        for (i, g) in [0x3b6a57b2u32, 0x26508e6d, 0x1ea119fa, 0x3d4233dd, 0x2a1462b3].iter().enumerate() {
+            if (b >> i) & 1 == 1 {
+                chk ^= g;
+            }
+        }
+    }
+    chk
+}
+
+fn checksum(hrp: &str, data: &[u8]) -> Vec<u8> {
+    let mut values = hrp.bytes().map(|b| b >> 5).collect::<Vec<_>>();
+    values.push(0);
+    values.extend(hrp.bytes().map(|b| b & 31));
+    values.extend_from_slice(data);
+    values.extend_from_slice(&[0u8; 6]);
+    let m = polymod(&values) ^ BECH32M_CONST;
+    (0..6).map(|i| ((m >> (5 * (5 - i))) & 31) as u8).collect()
+}
+
+fn convert_bits(data: &[u8], from: u32, to: u32, pad: bool) -> Option<Vec<u8>> {
+    let (mut acc, mut bits) = (0u32, 0u32);
+    let max = (1u32 << to) - 1;
+    let mut result = Vec::new();
+    for value in data {
+        acc = (acc << from) | *value as u32;
+        bits += from;
+        while bits >= to {
+            bits -= to;
+            result.push(((acc >> bits) & max) as u8);
+        }
+    }
+    if pad {
+        if bits > 0 {
+            result.push(((acc << (to - bits)) & max) as u8);
+        }
+    } else if bits >= from || ((acc << (to - bits)) & max) != 0 {
+        return None;
+    }
+    Some(result)
+}
+
+fn tagged_hash(tag: &str, data: &[&[u8]]) -> [u8; 32] {
+    let tag = sha256::Hash::hash(tag.as_bytes());
+    let mut engine = sha256::Hash::engine();
+    engine.input(tag.as_ref());
+    engine.input(tag.as_ref());
+    for d in data {
+        engine.input(d);
+    }
+    sha256::Hash::from_engine(engine).to_byte_array()
+}
+
+fn input_hash(smallest: &OutPoint, sum: &PublicKey) -> Result<Scalar, WalletError> {
+    Ok(Scalar::from(SecretKey::from_slice(&tagged_hash("BIP0352/Inputs", &[&consensus::serialize(smallest), &sum.serialize()]))?))
+}
+
+fn output_tweak(shared: &PublicKey, k: u32) -> Result<SecretKey, WalletError> {
+    Ok(SecretKey::from_slice(&tagged_hash("BIP0352/SharedSecret", &[&shared.serialize(), &k.to_be_bytes()]))?)
+}
+
+/// m / 352' / coin_type' / account' / 0' / 0 spend and m / 352' / coin_type' / account' / 1' / 0 scan key
+pub fn key_path(network: Network, account_number: u32, scan: bool) -> DerivationPath {
+    DerivationPath::from(vec!(
+        ChildNumber::Hardened { index: PURPOSE },
+        ChildNumber::Hardened { index: coin_type(network) },
+        ChildNumber::Hardened { index: account_number },
+        ChildNumber::Hardened { index: if scan { 1 } else { 0 } },
+        ChildNumber::Normal { index: 0 }))
+}
+
+/// the private key of an input the sender spends, taproot keys are the tweaked output keys
+pub struct InputKey {
+    pub point: OutPoint,
+    pub secret: SecretKey,
+    pub taproot: bool
+}
+
+/// scripts of outputs paying the recipients, in their order, from a transaction spending the inputs
+pub fn sender_outputs(inputs: &[InputKey], recipients: &[SilentPaymentAddress]) -> Result<Vec<ScriptBuf>, WalletError> {
+    let secp = Secp256k1::new();
+    let mut sum: Option<SecretKey> = None;
+    for input in inputs {
+        let mut secret = input.secret;
+        if input.taproot && secret.x_only_public_key(&secp).1 == Parity::Odd {
+            secret = secret.negate();
+        }
+        sum = Some(match sum {
+            Some(s) => s.add_tweak(&Scalar::from(secret))?,
+            None => secret
+        });
+    }
+    let sum = sum.ok_or(WalletError::Unsupported("silent payments need inputs"))?;
+    let smallest = inputs.iter().map(|i| consensus::serialize(&i.point)).min().expect("inputs are not empty");
+    let smallest: OutPoint = consensus::deserialize(&smallest).expect("serialized outpoint");
+    let tweaked = sum.mul_tweak(&input_hash(&smallest, &sum.public_key(&secp))?)?;
+
+    let mut scripts = Vec::new();
+    let mut counts: Vec<(PublicKey, u32)> = Vec::new();
+    for recipient in recipients {
+        let k = match counts.iter_mut().find(|(scan, _)| *scan == recipient.scan) {
+            Some((_, n)) => { *n += 1; *n - 1 }
+            None => { counts.push((recipient.scan, 1)); 0 }
+        };
+        let shared = recipient.scan.mul_tweak(&secp, &Scalar::from(tweaked))?;
+        let output = recipient.spend.add_exp_tweak(&secp, &Scalar::from(output_tweak(&shared, k)?))?;
+        scripts.push(ScriptBuf::new_p2tr_tweaked(TweakedPublicKey::dangerous_assume_tweaked(output.x_only_public_key().0)));
+    }
+    Ok(scripts)
+}
+
+/// the public key an input exposes that silent payments use, none for inputs of other types
+fn input_public(input: &bitcoin::TxIn, spent: &Script) -> Option<PublicKey> {
+    if spent.is_p2tr() {
+        // script path spends revealing the NUMS point of BIP341 are not eligible, not checked here
+        return XOnlyPublicKey::from_slice(&spent.as_bytes()[2..]).ok().map(|x| x.public_key(Parity::Even));
+    }
+    let compressed = |bytes: &[u8]| if bytes.len() == 33 { PublicKey::from_slice(bytes).ok() } else { None };
+    let nested = spent.is_p2sh() && input.script_sig.len() == 23 && input.script_sig.as_bytes()[1..3] == [0, 20];
+    if spent.is_p2wpkh() || nested {
+        return if input.witness.len() == 2 { input.witness.nth(1).and_then(compressed) } else { None };
+    }
+    if spent.is_p2pkh() {
+        let hash = &spent.as_bytes()[3..23];
+        return input.script_sig.instructions().filter_map(|i| match i {
+            Ok(Instruction::PushBytes(push)) => compressed(push.as_bytes()),
+            _ => None
+        }).find(|k| hash160::Hash::hash(&k.serialize())[..] == *hash);
+    }
+    None
+}
+
+/// an output received to the silent payment address
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SilentCoin {
+    pub point: OutPoint,
+    pub output: TxOut,
+    /// added to the spend key to get the key of the output
+    pub tweak: [u8; 32]
+}
+
+/// scan and spend keys of a silent payment address and the coins found
+pub struct SilentPaymentAccount {
+    account_number: u32,
+    network: Network,
+    scan: SecretKey,
+    spend: PublicKey,
+    owned: Vec<SilentCoin>
+}
+
+impl SilentPaymentAccount {
+    /// the scan key stays in memory to find payments, the spend key is unlocked to sign
+    pub fn new(unlocker: &Unlocker, account_number: u32, network: Network) -> Result<SilentPaymentAccount, WalletError> {
+        let scan = unlocker.derive(&key_path(network, account_number, true))?.private_key;
+        let spend = unlocker.derive(&key_path(network, account_number, false))?.private_key.public_key(&Secp256k1::signing_only());
+        Ok(SilentPaymentAccount { account_number, network, scan, spend, owned: Vec::new() })
+    }
+
+    pub fn address(&self) -> SilentPaymentAddress {
+        SilentPaymentAddress { scan: self.scan.public_key(&Secp256k1::signing_only()), spend: self.spend, network: self.network }
+    }
+
+    pub fn owned(&self) -> &Vec<SilentCoin> {
+        &self.owned
+    }
+
+    /// find outputs paying to the address, resolver gives the outputs the transaction spends.
+    /// returns the coins found
+    pub fn scan<R>(&mut self, transaction: &Transaction, resolver: R) -> Result<Vec<SilentCoin>, WalletError>
+        where R: Fn(&OutPoint) -> Option<TxOut> {
+        if transaction.is_coinbase() || !transaction.output.iter().any(|o| o.script_pubkey.is_p2tr()) {
+            return Ok(Vec::new());
+        }
+        let secp = Secp256k1::new();
+        let mut keys = Vec::new();
+        for input in transaction.input.iter() {
+            let spent = resolver(&input.previous_output).ok_or(WalletError::Unsupported("scanning needs the spent outputs"))?;
+            if let Some(key) = input_public(input, &spent.script_pubkey) {
+                keys.push(key);
+            }
+        }
+        if keys.is_empty() {
+            return Ok(Vec::new());
+        }
+        let sum = PublicKey::combine_keys(&keys.iter().collect::<Vec<_>>())
+            .map_err(|_| WalletError::Unsupported("input keys sum to infinity"))?;
+        let smallest = transaction.input.iter().map(|i| consensus::serialize(&i.previous_output)).min().expect("not coinbase");
+        let smallest: OutPoint = consensus::deserialize(&smallest).expect("serialized outpoint");
+        let shared = sum.mul_tweak(&secp, &Scalar::from(self.scan.mul_tweak(&input_hash(&smallest, &sum)?)?))?;
+
+        let txid = transaction.compute_txid();
+        let mut found = Vec::new();
+        let mut k = 0;
+        loop {
+            let tweak = output_tweak(&shared, k)?;
+            let output_key = self.spend.add_exp_tweak(&secp, &Scalar::from(tweak))?.x_only_public_key().0;
+            let script = ScriptBuf::new_p2tr_tweaked(TweakedPublicKey::dangerous_assume_tweaked(output_key));
+            match transaction.output.iter().position(|o| o.script_pubkey == script) {
+                Some(vout) => {
+                    let coin = SilentCoin { point: OutPoint { txid, vout: vout as u32 }, output: transaction.output[vout].clone(), tweak: tweak.secret_bytes() };
+                    if !self.owned.contains(&coin) {
+                        self.owned.push(coin.clone());
+                    }
+                    found.push(coin);
+                    k += 1;
+                }
+                None => break
+            }
+        }
+        Ok(found)
+    }
+
+    /// forget coins spent by a transaction
+    pub fn spent(&mut self, transaction: &Transaction) {
+        self.owned.retain(|c| !transaction.input.iter().any(|i| i.previous_output == c.point));
+    }
+
+    /// sign inputs spending received coins on the key path, resolver gives all spent outputs
+    pub fn sign<R>(&self, transaction: &mut Transaction, resolver: R, unlocker: &Unlocker) -> Result<usize, WalletError>
+        where R: Fn(&OutPoint) -> Option<TxOut> {
+        let secp = Secp256k1::new();
+        let prevouts = transaction.input.iter().map(|i| resolver(&i.previous_output)).collect::<Option<Vec<_>>>()
+            .ok_or(WalletError::Unsupported("taproot signing needs all spent outputs"))?;
+        let spend = unlocker.derive(&key_path(self.network, self.account_number, false))?.private_key;
+        let txclone = transaction.clone();
+        let mut hasher = SighashCache::new(&txclone);
+        let mut signed = 0;
+        for (ix, input) in transaction.input.iter_mut().enumerate() {
+            if let Some(coin) = self.owned.iter().find(|c| c.point == input.previous_output) {
+                let keypair = Keypair::from_secret_key(&secp, &spend.add_tweak(&Scalar::from_be_bytes(coin.tweak).map_err(|_| WalletError::Unsupported("invalid tweak"))?)?);
+                let sighash = hasher.taproot_key_spend_signature_hash(ix, &Prevouts::All(&prevouts), TapSighashType::Default)
+                    .expect("input index is in range");
+                let signature = secp.sign_schnorr(&Message::from_digest(sighash.to_byte_array()), &keypair);
+                input.script_sig = ScriptBuf::new();
+                input.witness = Witness::from_slice(&[taproot::Signature { signature, sighash_type: TapSighashType::Default }.to_vec()]);
+                signed += 1;
+            }
+        }
+        Ok(signed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{Amount, Block, BlockHash, CompactTarget, Sequence, TxIn, TxMerkleNode, Txid};
+    use bitcoin::absolute::LockTime;
+    use bitcoin::block::Header;
+    use bitcoin::transaction::Version;
+
+    use account::{AccountAddressType, MasterAccount, MasterKeyEntropy};
+    use builder::TxBuilder;
+    use coins::Coins;
+    use super::*;
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+
+    #[test]
+    fn test_address() {
+        let address = SilentPaymentAddress::from_str("sp1qqgste7k9hx0qftg6qmwlkqtwuy6cycyavzmzj85c6qdfhjdpdjtdgqjuexzk6murw56suy3e0rd2cgqvycxttddwsvgxe2usfpxumr70xc9pkqwv").unwrap();
+        assert_eq!(address.network, Network::Bitcoin);
+        assert_eq!(address.to_string(), "sp1qqgste7k9hx0qftg6qmwlkqtwuy6cycyavzmzj85c6qdfhjdpdjtdgqjuexzk6murw56suy3e0rd2cgqvycxttddwsvgxe2usfpxumr70xc9pkqwv");
+        assert!(SilentPaymentAddress::from_str("sp1qqgste7k9hx0qftg6qmwlkqtwuy6cycyavzmzj85c6qdfhjdpdjtdgqjuexzk6murw56suy3e0rd2cgqvycxttddwsvgxe2usfpxumr70xc9pkqwu").is_err());
+    }
+
+    #[test]
+    fn test_silent_payment() {
+        let mut sender = MasterAccount::new(MasterKeyEntropy::Low, Network::Regtest, PASSPHRASE, None).unwrap();
+        let mut sender_unlocker = Unlocker::new(sender.encrypted(), PASSPHRASE, None, Network::Regtest, None).unwrap();
+        let receiver = MasterAccount::new(MasterKeyEntropy::Low, Network::Regtest, PASSPHRASE, None).unwrap();
+        let receiver_unlocker = Unlocker::new(receiver.encrypted(), PASSPHRASE, None, Network::Regtest, None).unwrap();
+        let mut account = SilentPaymentAccount::new(&receiver_unlocker, 0, Network::Regtest).unwrap();
+        let address = SilentPaymentAddress::from_str(&account.address().to_string()).unwrap();
+        assert_eq!(address, account.address());
+
+        // the sender has a taproot and a segwit coin
+        let taproot = sender.new_account(&mut sender_unlocker, AccountAddressType::P2TR, 0, 0, 10).unwrap().get_key(0).unwrap().script_pubkey.clone();
+        let segwit = sender.new_account(&mut sender_unlocker, AccountAddressType::P2WPKH, 1, 0, 10).unwrap().get_key(0).unwrap().script_pubkey.clone();
+        let funding = Transaction {
+            version: Version::ONE,
+            lock_time: LockTime::ZERO,
+            input: vec!(TxIn { previous_output: OutPoint { txid: Txid::all_zeros(), vout: 0 }, script_sig: ScriptBuf::new(), sequence: Sequence::MAX, witness: Witness::new() }),
+            output: vec!(TxOut { value: Amount::from_sat(60000), script_pubkey: taproot }, TxOut { value: Amount::from_sat(60000), script_pubkey: segwit })
+        };
+        let block = Block {
+            header: Header { version: bitcoin::block::Version::ONE, prev_blockhash: BlockHash::all_zeros(), merkle_root: TxMerkleNode::all_zeros(),
+                time: 0, bits: CompactTarget::from_consensus(0), nonce: 0 },
+            txdata: vec!(funding.clone())
+        };
+        let mut coins = Coins::new();
+        coins.process(&mut sender, &block).unwrap();
+
+        let builder = TxBuilder::new().add_silent_payment(&address, 100000).change_account(1, 0);
+        assert!(builder.finish(&mut sender, &coins).is_err());
+        let payment = builder.finish_signed(&mut sender, &coins, &mut sender_unlocker).unwrap();
+        assert_eq!(payment.input.len(), 2);
+        let resolver = |point: &OutPoint| if point.txid == funding.compute_txid() { funding.output.get(point.vout as usize).cloned() } else { None };
+        let found = account.scan(&payment, resolver).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].output.value.to_sat(), 100000);
+        assert!(account.scan(&funding, |_| None).is_err());
+
+        // the receiver spends it
+        let coin = found[0].clone();
+        let mut spend = Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec!(TxIn { previous_output: coin.point, script_sig: ScriptBuf::new(), sequence: Sequence::MAX, witness: Witness::new() }),
+            output: vec!(TxOut { value: Amount::from_sat(99000), script_pubkey: ScriptBuf::new_op_return([0u8; 4]) })
+        };
+        assert_eq!(account.sign(&mut spend, |_| Some(coin.output.clone()), &receiver_unlocker).unwrap(), 1);
+        let signature = taproot::Signature::from_slice(&spend.input[0].witness[0]).unwrap();
+        let sighash = SighashCache::new(&spend).taproot_key_spend_signature_hash(0, &Prevouts::All(std::slice::from_ref(&coin.output)), TapSighashType::Default).unwrap();
+        let output_key = XOnlyPublicKey::from_slice(&coin.output.script_pubkey.as_bytes()[2..]).unwrap();
+        assert!(Secp256k1::verification_only().verify_schnorr(&signature.signature, &Message::from_digest(sighash.to_byte_array()), &output_key).is_ok());
+        account.spent(&spend);
+        assert!(account.owned().is_empty());
+    }
+}
@@ -0,0 +1,135 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # SLIP-132
+//!
+//! Extended keys with version bytes telling the script type, xpub, ypub, zpub and their testnet forms
+//!
+
+use bitcoin::{base58, Network, NetworkKind};
+use bitcoin::bip32::{Fingerprint, Xpriv, Xpub};
+
+use account::{AccountAddressType, MasterAccount, Unlocker};
+use context::SecpContext;
+use error::WalletError;
+
+/// version bytes of public and private keys by network kind and script type
+const VERSIONS: [(NetworkKind, AccountAddressType, [u8; 4], [u8; 4]); 6] = [
+    (NetworkKind::Main, AccountAddressType::P2PKH, [0x04, 0x88, 0xb2, 0x1e], [0x04, 0x88, 0xad, 0xe4]),
+    (NetworkKind::Main, AccountAddressType::P2SHWPKH, [0x04, 0x9d, 0x7c, 0xb2], [0x04, 0x9d, 0x78, 0x78]),
+    (NetworkKind::Main, AccountAddressType::P2WPKH, [0x04, 0xb2, 0x47, 0x46], [0x04, 0xb2, 0x43, 0x0c]),
+    (NetworkKind::Test, AccountAddressType::P2PKH, [0x04, 0x35, 0x87, 0xcf], [0x04, 0x35, 0x83, 0x94]),
+    (NetworkKind::Test, AccountAddressType::P2SHWPKH, [0x04, 0x4a, 0x52, 0x62], [0x04, 0x4a, 0x4e, 0x28]),
+    (NetworkKind::Test, AccountAddressType::P2WPKH, [0x04, 0x5f, 0x1c, 0xf6], [0x04, 0x5f, 0x18, 0xbc])
+];
+
+/// script types without own prefix use those of xpub and tpub
+fn versions(network: NetworkKind, address_type: AccountAddressType) -> ([u8; 4], [u8; 4]) {
+    let address_type = match address_type {
+        AccountAddressType::P2SHWPKH | AccountAddressType::P2WPKH => address_type,
+        _ => AccountAddressType::P2PKH
+    };
+    VERSIONS.iter().find(|(n, a, _, _)| *n == network && *a == address_type).map(|(_, _, public, private)| (*public, *private))
+        .expect("all network kinds have versions")
+}
+
+/// xpub, ypub or zpub (tpub, upub or vpub on test networks) for the script type
+pub fn encode_xpub(xpub: &Xpub, address_type: AccountAddressType) -> String {
+    let mut data = xpub.encode();
+    data[0..4].copy_from_slice(&versions(xpub.network, address_type).0);
+    base58::encode_check(&data)
+}
+
+/// xprv, yprv or zprv (tprv, uprv or vprv on test networks) for the script type
+pub fn encode_xprv(xprv: &Xpriv, address_type: AccountAddressType) -> String {
+    let mut data = xprv.encode();
+    data[0..4].copy_from_slice(&versions(xprv.network, address_type).1);
+    base58::encode_check(&data)
+}
+
+/// the network kind and script type of version bytes, the script type is None for xpub and tpub
+/// as those are used for any script
+fn lookup(version: &[u8], private: bool) -> Result<(NetworkKind, Option<AccountAddressType>), WalletError> {
+    VERSIONS.iter().find(|(_, _, public_version, private_version)| version == if private { private_version } else { public_version })
+        .map(|(network, address_type, _, _)| (*network, if *address_type == AccountAddressType::P2PKH { None } else { Some(*address_type) }))
+        .ok_or(WalletError::Unsupported("unknown extended key version"))
+}
+
+fn normalize(encoded: &str, private: bool) -> Result<(Vec<u8>, Option<AccountAddressType>), WalletError> {
+    let mut data = base58::decode_check(encoded).map_err(|_| WalletError::Unsupported("invalid extended key encoding"))?;
+    if data.len() != 78 {
+        return Err(WalletError::Unsupported("invalid extended key length"));
+    }
+    let (network, address_type) = lookup(&data[0..4], private)?;
+    let (public_version, private_version) = versions(network, AccountAddressType::P2PKH);
+    data[0..4].copy_from_slice(if private { &private_version } else { &public_version });
+    Ok((data, address_type))
+}
+
+/// an extended public key in any of the SLIP-132 forms with the script type its prefix tells
+pub fn decode_xpub(encoded: &str) -> Result<(Xpub, Option<AccountAddressType>), WalletError> {
+    let (data, address_type) = normalize(encoded, false)?;
+    Ok((Xpub::decode(&data)?, address_type))
+}
+
+/// an extended private key in any of the SLIP-132 forms with the script type its prefix tells
+pub fn decode_xprv(encoded: &str) -> Result<(Xpriv, Option<AccountAddressType>), WalletError> {
+    let (data, address_type) = normalize(encoded, true)?;
+    Ok((Xpriv::decode(&data)?, address_type))
+}
+
+/// the key at m / purpose' / coin_type' / account' as other wallets expect it
+pub fn export_account(unlocker: &mut Unlocker, address_type: AccountAddressType, account_number: u32) -> Result<String, WalletError> {
+    let account_key = unlocker.account_key(address_type, account_number)?;
+    Ok(encode_xpub(&SecpContext::new().extended_public_from_private(&account_key), address_type))
+}
+
+/// a watch only master of an account key exported by an other wallet, address_type is used
+/// if the prefix does not tell the script type
+pub fn import_account(fingerprint: Fingerprint, encoded: &str, address_type: AccountAddressType, network: Network, look_ahead: u32, birth: u64) -> Result<MasterAccount, WalletError> {
+    let (account_public, prefix_type) = decode_xpub(encoded)?;
+    MasterAccount::watch_only_account(fingerprint, account_public, prefix_type.unwrap_or(address_type), network, look_ahead, birth)
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use super::*;
+
+    #[test]
+    fn test_slip132() {
+        // SLIP-132 test vector, m/84'/0'/0' of abandon ... about
+        let zpub = "zpub6rFR7y4Q2AijBEqTUquhVz398htDFrtymD9xYYfG1m4wAcvPhXNfE3EfH1r1ADqtfSdVCToUG868RvUUkgDKf31mGDtKsAYz2oz2AGutZYs";
+        let (xpub, address_type) = decode_xpub(zpub).unwrap();
+        assert_eq!(address_type, Some(AccountAddressType::P2WPKH));
+        assert_eq!(xpub.to_string(), "xpub6CatWdiZiodmUeTDp8LT5or8nmbKNcuyvz7WyksVFkKB4RHwCD3XyuvPEbvqAQY3rAPshWcMLoP2fMFMKHPJ4ZeZXYVUhLv1VMrjPC7PW6V");
+        assert_eq!(encode_xpub(&xpub, AccountAddressType::P2WPKH), zpub);
+        assert_eq!(decode_xpub(&xpub.to_string()).unwrap(), (xpub, None));
+
+        let ypub = encode_xpub(&xpub, AccountAddressType::P2SHWPKH);
+        assert!(ypub.starts_with("ypub"));
+        assert_eq!(decode_xpub(&ypub).unwrap(), (xpub, Some(AccountAddressType::P2SHWPKH)));
+        assert!(decode_xprv(zpub).is_err());
+
+        let xprv = Xpriv::from_str("tprv8ZgxMBicQKsPd7Uf69XL1XwhmjHopUGep8GuEiJDZmbQz6o58LninorQAfcKZWARbtRtfnLcJ5MQ2AtHcQJCCRUcMRvmDUjyEmNUWwx8UbK").unwrap();
+        let vprv = encode_xprv(&xprv, AccountAddressType::P2WPKH);
+        assert!(vprv.starts_with("vprv"));
+        assert_eq!(decode_xprv(&vprv).unwrap(), (xprv, Some(AccountAddressType::P2WPKH)));
+        let upub = encode_xpub(&Xpub::from_priv(&bitcoin::secp256k1::Secp256k1::new(), &xprv), AccountAddressType::P2SHWPKH);
+        assert!(upub.starts_with("upub"));
+    }
+}
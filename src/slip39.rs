@@ -0,0 +1,1424 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # SLIP-39
+//!
+//! Shamir's secret sharing of a master secret into mnemonic shares
+//!
+use std::collections::HashMap;
+use std::fmt;
+use std::num::NonZeroU32;
+
+use ring::{hmac, pbkdf2};
+use secp256k1::rand::{thread_rng, RngCore};
+
+use error::WalletError;
+use secret::SecretBytes;
+
+/// minimal length of a master secret in bytes
+pub const MIN_SECRET_LENGTH: usize = 16;
+/// maximal number of groups and of members in a group
+pub const MAX_SHARE_COUNT: u8 = 16;
+
+const RADIX_BITS: usize = 10;
+const CHECKSUM_WORDS: usize = 3;
+// identifier, extendable flag, iteration exponent and group and member parameters
+const HEADER_WORDS: usize = 4;
+const MIN_MNEMONIC_WORDS: usize = 20;
+const BASE_ITERATION_COUNT: u32 = 10000;
+const ROUND_COUNT: u8 = 4;
+const SECRET_INDEX: u8 = 255;
+const DIGEST_INDEX: u8 = 254;
+const DIGEST_LENGTH: usize = 4;
+
+/// a share of a master secret
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct Share {
+    /// random identifier common to all shares of a secret
+    pub identifier: u16,
+    /// the identifier is not part of the encryption salt
+    pub extendable: bool,
+    /// PBKDF2 iterations are 10000 * 2^iteration_exponent
+    pub iteration_exponent: u8,
+    /// index of the group of this share
+    pub group_index: u8,
+    /// number of groups needed to recover the secret
+    pub group_threshold: u8,
+    /// number of groups
+    pub group_count: u8,
+    /// index of this share within its group
+    pub member_index: u8,
+    /// number of shares of the group needed to recover the group secret
+    pub member_threshold: u8,
+    /// share of the group secret
+    pub value: Vec<u8>
+}
+
+impl Share {
+    /// parse a share from its mnemonic
+    pub fn from_mnemonic(s: &str) -> Result<Share, WalletError> {
+        let mut words = Vec::new();
+        for word in s.split_whitespace() {
+            match WORDS.binary_search(&word) {
+                Ok(idx) => words.push(idx as u16),
+                Err(_) => return Err(WalletError::Slip39("share contains an unknown word"))
+            }
+        }
+        if words.len() < MIN_MNEMONIC_WORDS {
+            return Err(WalletError::Slip39("share is too short"));
+        }
+        let value_words = words.len() - HEADER_WORDS - CHECKSUM_WORDS;
+        let padding = (RADIX_BITS * value_words) % 16;
+        if padding > 8 {
+            return Err(WalletError::Slip39("share has an invalid length"));
+        }
+        let extendable = words[1] & 0x10 != 0;
+        if checksum(customization(extendable), &words) != 1 {
+            return Err(WalletError::Slip39("share checksum mismatch"));
+        }
+        let group_threshold = ((words[2] >> 2) & 0xf) as u8 + 1;
+        let group_count = (((words[2] & 3) << 2) | (words[3] >> 8)) as u8 + 1;
+        if group_threshold > group_count {
+            return Err(WalletError::Slip39("group threshold exceeds the number of groups"));
+        }
+        let value = from_words(&words[HEADER_WORDS..HEADER_WORDS + value_words], (RADIX_BITS * value_words - padding) / 8)?;
+        Ok(Share {
+            identifier: (words[0] << 5) | (words[1] >> 5),
+            extendable,
+            iteration_exponent: (words[1] & 0xf) as u8,
+            group_index: (words[2] >> 6) as u8,
+            group_threshold,
+            group_count,
+            member_index: ((words[3] >> 4) & 0xf) as u8,
+            member_threshold: (words[3] & 0xf) as u8 + 1,
+            value
+        })
+    }
+
+    /// the mnemonic of the share
+    pub fn to_mnemonic(&self) -> String {
+        self.to_string()
+    }
+
+    fn words(&self) -> Vec<u16> {
+        let mut words = vec!(
+            self.identifier >> 5,
+            ((self.identifier & 0x1f) << 5) | ((self.extendable as u16) << 4) | self.iteration_exponent as u16,
+            ((self.group_index as u16) << 6) | (((self.group_threshold - 1) as u16) << 2) | ((self.group_count - 1) as u16 >> 2),
+            ((((self.group_count - 1) & 3) as u16) << 8) | ((self.member_index as u16) << 4) | (self.member_threshold - 1) as u16
+        );
+        words.extend(to_words(&self.value));
+        let mut data = words.clone();
+        data.extend([0u16; CHECKSUM_WORDS].iter());
+        let check = checksum(customization(self.extendable), &data) ^ 1;
+        words.extend((0..CHECKSUM_WORDS).rev().map(|i| ((check >> (RADIX_BITS * i)) & 0x3ff) as u16));
+        words
+    }
+}
+
+impl fmt::Display for Share {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.words().iter().map(|w| WORDS[*w as usize]).collect::<Vec<_>>().join(" "))
+    }
+}
+
+/// split a master secret into count shares, any threshold of them recover it
+pub fn split(master_secret: &[u8], passphrase: &str, threshold: u8, count: u8, iteration_exponent: u8) -> Result<Vec<Share>, WalletError> {
+    Ok(split_groups(master_secret, passphrase, 1, &[(threshold, count)], iteration_exponent)?.remove(0))
+}
+
+/// split a master secret into groups of shares given as (member threshold, member count),
+/// the secret is recovered from member threshold shares of each of group_threshold groups
+pub fn split_groups(master_secret: &[u8], passphrase: &str, group_threshold: u8, groups: &[(u8, u8)], iteration_exponent: u8) -> Result<Vec<Vec<Share>>, WalletError> {
+    if master_secret.len() < MIN_SECRET_LENGTH || !master_secret.len().is_multiple_of(2) {
+        return Err(WalletError::Slip39("master secret must be at least 16 bytes and of even length"));
+    }
+    if group_threshold == 0 || group_threshold as usize > groups.len() || groups.len() > MAX_SHARE_COUNT as usize {
+        return Err(WalletError::Slip39("invalid group threshold or group count"));
+    }
+    if iteration_exponent > 15 {
+        return Err(WalletError::Slip39("iteration exponent is too large"));
+    }
+    for (threshold, count) in groups {
+        if *threshold == 0 || threshold > count || *count > MAX_SHARE_COUNT {
+            return Err(WalletError::Slip39("invalid member threshold or member count"));
+        }
+        if *threshold == 1 && *count > 1 {
+            return Err(WalletError::Slip39("multiple shares with member threshold 1 are not allowed"));
+        }
+    }
+    let identifier = (thread_rng().next_u32() & 0x7fff) as u16;
+    let extendable = true;
+    let encrypted = encrypt(master_secret, passphrase, iteration_exponent, identifier, extendable)?;
+    let group_secrets = split_secret(group_threshold, groups.len() as u8, &encrypted);
+    Ok(groups.iter().zip(group_secrets).enumerate().map(|(group_index, ((threshold, count), (_, group_secret)))|
+        split_secret(*threshold, *count, &group_secret).into_iter().map(|(member_index, value)| Share {
+            identifier,
+            extendable,
+            iteration_exponent,
+            group_index: group_index as u8,
+            group_threshold,
+            group_count: groups.len() as u8,
+            member_index,
+            member_threshold: *threshold,
+            value
+        }).collect()).collect())
+}
+
+/// recover the master secret from shares
+pub fn combine(shares: &[Share], passphrase: &str) -> Result<SecretBytes, WalletError> {
+    let first = shares.first().ok_or(WalletError::Slip39("no shares"))?;
+    if shares.iter().any(|s| s.identifier != first.identifier || s.extendable != first.extendable ||
+        s.iteration_exponent != first.iteration_exponent || s.group_threshold != first.group_threshold ||
+        s.group_count != first.group_count || s.value.len() != first.value.len()) {
+        return Err(WalletError::Slip39("shares do not belong to the same secret"));
+    }
+    let mut groups: HashMap<u8, (u8, HashMap<u8, Vec<u8>>)> = HashMap::new();
+    for share in shares {
+        let group = groups.entry(share.group_index).or_insert((share.member_threshold, HashMap::new()));
+        if group.0 != share.member_threshold {
+            return Err(WalletError::Slip39("shares of a group have different member thresholds"));
+        }
+        group.1.insert(share.member_index, share.value.clone());
+    }
+    let mut group_secrets = Vec::new();
+    let mut indices = groups.keys().copied().collect::<Vec<_>>();
+    indices.sort_unstable();
+    for index in indices {
+        let (threshold, ref members) = groups[&index];
+        if members.len() >= threshold as usize && group_secrets.len() < first.group_threshold as usize {
+            let mut members = members.iter().map(|(i, v)| (*i, v.clone())).collect::<Vec<_>>();
+            members.sort_unstable();
+            members.truncate(threshold as usize);
+            group_secrets.push((index, recover_secret(threshold, &members)?));
+        }
+    }
+    if group_secrets.len() < first.group_threshold as usize {
+        return Err(WalletError::Slip39("not enough shares to recover the secret"));
+    }
+    let encrypted = recover_secret(first.group_threshold, &group_secrets)?;
+    decrypt(&encrypted, passphrase, first.iteration_exponent, first.identifier, first.extendable).map(SecretBytes::new)
+}
+
+fn customization(extendable: bool) -> &'static [u8] {
+    if extendable { b"shamir_extendable" } else { b"shamir" }
+}
+
+// Reed-Solomon code over GF(1024) of the share and customization string
+fn checksum(customization: &[u8], words: &[u16]) -> u32 {
+    const GEN: [u32; 10] = [0xE0_E040, 0x1C1_C080, 0x383_8100, 0x707_0200, 0xE0E_0009, 0x1C0C_2412, 0x3808_6C24, 0x3090_FC48, 0x21B1_F890, 0x3F3_F120];
+    let mut chk = 1u32;
+    for v in customization.iter().map(|b| *b as u32).chain(words.iter().map(|w| *w as u32)) {
+        let b = chk >> 20;
+        chk = ((chk & 0xf_ffff) << 10) ^ v;
+        for (i, g) in GEN.iter().enumerate() {
+            if (b >> i) & 1 != 0 {
+                chk ^= g;
+            }
+        }
+    }
+    chk
+}
+
+// big endian bits of data left padded with zeros to a multiple of 10
+fn to_words(data: &[u8]) -> Vec<u16> {
+    let count = (data.len() * 8).div_ceil(RADIX_BITS);
+    let padding = count * RADIX_BITS - data.len() * 8;
+    let bit = |i: usize| if i < padding { 0 } else { let i = i - padding; ((data[i / 8] >> (7 - i % 8)) & 1) as u16 };
+    (0..count).map(|w| (0..RADIX_BITS).fold(0u16, |acc, b| (acc << 1) | bit(w * RADIX_BITS + b))).collect()
+}
+
+fn from_words(words: &[u16], length: usize) -> Result<Vec<u8>, WalletError> {
+    let padding = words.len() * RADIX_BITS - length * 8;
+    let bit = |i: usize| (words[i / RADIX_BITS] >> (RADIX_BITS - 1 - i % RADIX_BITS)) & 1;
+    if (0..padding).any(|i| bit(i) != 0) {
+        return Err(WalletError::Slip39("share has invalid padding"));
+    }
+    Ok((0..length).map(|b| (0..8).fold(0u8, |acc, i| (acc << 1) | bit(padding + b * 8 + i) as u8)).collect())
+}
+
+// Feistel network with PBKDF2-HMAC-SHA256 round function
+fn feistel(data: &[u8], passphrase: &str, iteration_exponent: u8, identifier: u16, extendable: bool, rounds: &[u8]) -> Result<Vec<u8>, WalletError> {
+    if !passphrase.bytes().all(|b| (32..=126).contains(&b)) {
+        return Err(WalletError::Slip39("passphrase must be printable ASCII"));
+    }
+    let mut salt = Vec::new();
+    if !extendable {
+        salt.extend(customization(false));
+        salt.extend(identifier.to_be_bytes().iter());
+    }
+    let iterations = NonZeroU32::new((BASE_ITERATION_COUNT << iteration_exponent) / ROUND_COUNT as u32).expect("at least 2500 iterations");
+    let (mut l, mut r) = (data[..data.len() / 2].to_vec(), data[data.len() / 2..].to_vec());
+    for round in rounds {
+        let mut password = vec!(*round);
+        password.extend(passphrase.as_bytes());
+        let mut round_salt = salt.clone();
+        round_salt.extend(r.iter());
+        let mut f = vec!(0u8; r.len());
+        pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, iterations, &round_salt, &password, &mut f);
+        let next = l.iter().zip(f.iter()).map(|(a, b)| a ^ b).collect::<Vec<_>>();
+        l = r;
+        r = next;
+    }
+    r.extend(l);
+    Ok(r)
+}
+
+fn encrypt(master_secret: &[u8], passphrase: &str, iteration_exponent: u8, identifier: u16, extendable: bool) -> Result<Vec<u8>, WalletError> {
+    feistel(master_secret, passphrase, iteration_exponent, identifier, extendable, &[0, 1, 2, 3])
+}
+
+fn decrypt(encrypted: &[u8], passphrase: &str, iteration_exponent: u8, identifier: u16, extendable: bool) -> Result<Vec<u8>, WalletError> {
+    feistel(encrypted, passphrase, iteration_exponent, identifier, extendable, &[3, 2, 1, 0])
+}
+
+// exponent and logarithm tables of GF(256) with the Rijndael polynomial and generator 3
+fn tables() -> ([u8; 255], [u8; 256]) {
+    let mut exp = [0u8; 255];
+    let mut log = [0u8; 256];
+    let mut poly = 1u16;
+    for (i, e) in exp.iter_mut().enumerate() {
+        *e = poly as u8;
+        log[poly as usize] = i as u8;
+        poly ^= poly << 1;
+        if poly & 0x100 != 0 {
+            poly ^= 0x11b;
+        }
+    }
+    (exp, log)
+}
+
+// Lagrange interpolation of the shares at x
+fn interpolate(shares: &[(u8, Vec<u8>)], x: u8) -> Vec<u8> {
+    if let Some((_, value)) = shares.iter().find(|(i, _)| *i == x) {
+        return value.clone();
+    }
+    let (exp, log) = tables();
+    let log_product = shares.iter().map(|(i, _)| log[(i ^ x) as usize] as u32).sum::<u32>();
+    let mut result = vec!(0u8; shares[0].1.len());
+    for (i, value) in shares {
+        let others = shares.iter().filter(|(j, _)| j != i).map(|(j, _)| log[(i ^ j) as usize] as u32).sum::<u32>();
+        let log_basis = (log_product + 255 * 255 - log[(i ^ x) as usize] as u32 - others) % 255;
+        for (r, v) in result.iter_mut().zip(value.iter()) {
+            if *v != 0 {
+                *r ^= exp[(log[*v as usize] as u32 + log_basis) as usize % 255];
+            }
+        }
+    }
+    result
+}
+
+fn digest(random: &[u8], secret: &[u8]) -> Vec<u8> {
+    hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, random), secret).as_ref()[..DIGEST_LENGTH].to_vec()
+}
+
+fn split_secret(threshold: u8, count: u8, secret: &[u8]) -> Vec<(u8, Vec<u8>)> {
+    if threshold == 1 {
+        return (0..count).map(|i| (i, secret.to_vec())).collect();
+    }
+    let mut rng = thread_rng();
+    let mut random = |n: usize| { let mut r = vec!(0u8; n); rng.fill_bytes(&mut r); r };
+    let mut shares = (0..threshold - 2).map(|i| (i, random(secret.len()))).collect::<Vec<_>>();
+    let random_part = random(secret.len() - DIGEST_LENGTH);
+    let mut digest_share = digest(&random_part, secret);
+    digest_share.extend(random_part);
+    let mut base = shares.clone();
+    base.push((DIGEST_INDEX, digest_share));
+    base.push((SECRET_INDEX, secret.to_vec()));
+    shares.extend((threshold - 2..count).map(|i| (i, interpolate(&base, i))));
+    shares
+}
+
+fn recover_secret(threshold: u8, shares: &[(u8, Vec<u8>)]) -> Result<Vec<u8>, WalletError> {
+    if threshold == 1 {
+        return Ok(shares[0].1.clone());
+    }
+    let secret = interpolate(shares, SECRET_INDEX);
+    let digest_share = interpolate(shares, DIGEST_INDEX);
+    if digest(&digest_share[DIGEST_LENGTH..], &secret) != digest_share[..DIGEST_LENGTH] {
+        return Err(WalletError::Slip39("share digest mismatch"));
+    }
+    Ok(secret)
+}
+
+static WORDS: [&str; 1024] = [
+    "academic",
+    "acid",
+    "acne",
+    "acquire",
+    "acrobat",
+    "activity",
+    "actress",
+    "adapt",
+    "adequate",
+    "adjust",
+    "admit",
+    "adorn",
+    "adult",
+    "advance",
+    "advocate",
+    "afraid",
+    "again",
+    "agency",
+    "agree",
+    "aide",
+    "aircraft",
+    "airline",
+    "airport",
+    "ajar",
+    "alarm",
+    "album",
+    "alcohol",
+    "alien",
+    "alive",
+    "alpha",
+    "already",
+    "alto",
+    "aluminum",
+    "always",
+    "amazing",
+    "ambition",
+    "amount",
+    "amuse",
+    "analysis",
+    "anatomy",
+    "ancestor",
+    "ancient",
+    "angel",
+    "angry",
+    "animal",
+    "answer",
+    "antenna",
+    "anxiety",
+    "apart",
+    "aquatic",
+    "arcade",
+    "arena",
+    "argue",
+    "armed",
+    "artist",
+    "artwork",
+    "aspect",
+    "auction",
+    "august",
+    "aunt",
+    "average",
+    "aviation",
+    "avoid",
+    "award",
+    "away",
+    "axis",
+    "axle",
+    "beam",
+    "beard",
+    "beaver",
+    "become",
+    "bedroom",
+    "behavior",
+    "being",
+    "believe",
+    "belong",
+    "benefit",
+    "best",
+    "beyond",
+    "bike",
+    "biology",
+    "birthday",
+    "bishop",
+    "black",
+    "blanket",
+    "blessing",
+    "blimp",
+    "blind",
+    "blue",
+    "body",
+    "bolt",
+    "boring",
+    "born",
+    "both",
+    "boundary",
+    "bracelet",
+    "branch",
+    "brave",
+    "breathe",
+    "briefing",
+    "broken",
+    "brother",
+    "browser",
+    "bucket",
+    "budget",
+    "building",
+    "bulb",
+    "bulge",
+    "bumpy",
+    "bundle",
+    "burden",
+    "burning",
+    "busy",
+    "buyer",
+    "cage",
+    "calcium",
+    "camera",
+    "campus",
+    "canyon",
+    "capacity",
+    "capital",
+    "capture",
+    "carbon",
+    "cards",
+    "careful",
+    "cargo",
+    "carpet",
+    "carve",
+    "category",
+    "cause",
+    "ceiling",
+    "center",
+    "ceramic",
+    "champion",
+    "change",
+    "charity",
+    "check",
+    "chemical",
+    "chest",
+    "chew",
+    "chubby",
+    "cinema",
+    "civil",
+    "class",
+    "clay",
+    "cleanup",
+    "client",
+    "climate",
+    "clinic",
+    "clock",
+    "clogs",
+    "closet",
+    "clothes",
+    "club",
+    "cluster",
+    "coal",
+    "coastal",
+    "coding",
+    "column",
+    "company",
+    "corner",
+    "costume",
+    "counter",
+    "course",
+    "cover",
+    "cowboy",
+    "cradle",
+    "craft",
+    "crazy",
+    "credit",
+    "cricket",
+    "criminal",
+    "crisis",
+    "critical",
+    "crowd",
+    "crucial",
+    "crunch",
+    "crush",
+    "crystal",
+    "cubic",
+    "cultural",
+    "curious",
+    "curly",
+    "custody",
+    "cylinder",
+    "daisy",
+    "damage",
+    "dance",
+    "darkness",
+    "database",
+    "daughter",
+    "deadline",
+    "deal",
+    "debris",
+    "debut",
+    "decent",
+    "decision",
+    "declare",
+    "decorate",
+    "decrease",
+    "deliver",
+    "demand",
+    "density",
+    "deny",
+    "depart",
+    "depend",
+    "depict",
+    "deploy",
+    "describe",
+    "desert",
+    "desire",
+    "desktop",
+    "destroy",
+    "detailed",
+    "detect",
+    "device",
+    "devote",
+    "diagnose",
+    "dictate",
+    "diet",
+    "dilemma",
+    "diminish",
+    "dining",
+    "diploma",
+    "disaster",
+    "discuss",
+    "disease",
+    "dish",
+    "dismiss",
+    "display",
+    "distance",
+    "dive",
+    "divorce",
+    "document",
+    "domain",
+    "domestic",
+    "dominant",
+    "dough",
+    "downtown",
+    "dragon",
+    "dramatic",
+    "dream",
+    "dress",
+    "drift",
+    "drink",
+    "drove",
+    "drug",
+    "dryer",
+    "duckling",
+    "duke",
+    "duration",
+    "dwarf",
+    "dynamic",
+    "early",
+    "earth",
+    "easel",
+    "easy",
+    "echo",
+    "eclipse",
+    "ecology",
+    "edge",
+    "editor",
+    "educate",
+    "either",
+    "elbow",
+    "elder",
+    "election",
+    "elegant",
+    "element",
+    "elephant",
+    "elevator",
+    "elite",
+    "else",
+    "email",
+    "emerald",
+    "emission",
+    "emperor",
+    "emphasis",
+    "employer",
+    "empty",
+    "ending",
+    "endless",
+    "endorse",
+    "enemy",
+    "energy",
+    "enforce",
+    "engage",
+    "enjoy",
+    "enlarge",
+    "entrance",
+    "envelope",
+    "envy",
+    "epidemic",
+    "episode",
+    "equation",
+    "equip",
+    "eraser",
+    "erode",
+    "escape",
+    "estate",
+    "estimate",
+    "evaluate",
+    "evening",
+    "evidence",
+    "evil",
+    "evoke",
+    "exact",
+    "example",
+    "exceed",
+    "exchange",
+    "exclude",
+    "excuse",
+    "execute",
+    "exercise",
+    "exhaust",
+    "exotic",
+    "expand",
+    "expect",
+    "explain",
+    "express",
+    "extend",
+    "extra",
+    "eyebrow",
+    "facility",
+    "fact",
+    "failure",
+    "faint",
+    "fake",
+    "false",
+    "family",
+    "famous",
+    "fancy",
+    "fangs",
+    "fantasy",
+    "fatal",
+    "fatigue",
+    "favorite",
+    "fawn",
+    "fiber",
+    "fiction",
+    "filter",
+    "finance",
+    "findings",
+    "finger",
+    "firefly",
+    "firm",
+    "fiscal",
+    "fishing",
+    "fitness",
+    "flame",
+    "flash",
+    "flavor",
+    "flea",
+    "flexible",
+    "flip",
+    "float",
+    "floral",
+    "fluff",
+    "focus",
+    "forbid",
+    "force",
+    "forecast",
+    "forget",
+    "formal",
+    "fortune",
+    "forward",
+    "founder",
+    "fraction",
+    "fragment",
+    "frequent",
+    "freshman",
+    "friar",
+    "fridge",
+    "friendly",
+    "frost",
+    "froth",
+    "frozen",
+    "fumes",
+    "funding",
+    "furl",
+    "fused",
+    "galaxy",
+    "game",
+    "garbage",
+    "garden",
+    "garlic",
+    "gasoline",
+    "gather",
+    "general",
+    "genius",
+    "genre",
+    "genuine",
+    "geology",
+    "gesture",
+    "glad",
+    "glance",
+    "glasses",
+    "glen",
+    "glimpse",
+    "goat",
+    "golden",
+    "graduate",
+    "grant",
+    "grasp",
+    "gravity",
+    "gray",
+    "greatest",
+    "grief",
+    "grill",
+    "grin",
+    "grocery",
+    "gross",
+    "group",
+    "grownup",
+    "grumpy",
+    "guard",
+    "guest",
+    "guilt",
+    "guitar",
+    "gums",
+    "hairy",
+    "hamster",
+    "hand",
+    "hanger",
+    "harvest",
+    "have",
+    "havoc",
+    "hawk",
+    "hazard",
+    "headset",
+    "health",
+    "hearing",
+    "heat",
+    "helpful",
+    "herald",
+    "herd",
+    "hesitate",
+    "hobo",
+    "holiday",
+    "holy",
+    "home",
+    "hormone",
+    "hospital",
+    "hour",
+    "huge",
+    "human",
+    "humidity",
+    "hunting",
+    "husband",
+    "hush",
+    "husky",
+    "hybrid",
+    "idea",
+    "identify",
+    "idle",
+    "image",
+    "impact",
+    "imply",
+    "improve",
+    "impulse",
+    "include",
+    "income",
+    "increase",
+    "index",
+    "indicate",
+    "industry",
+    "infant",
+    "inform",
+    "inherit",
+    "injury",
+    "inmate",
+    "insect",
+    "inside",
+    "install",
+    "intend",
+    "intimate",
+    "invasion",
+    "involve",
+    "iris",
+    "island",
+    "isolate",
+    "item",
+    "ivory",
+    "jacket",
+    "jerky",
+    "jewelry",
+    "join",
+    "judicial",
+    "juice",
+    "jump",
+    "junction",
+    "junior",
+    "junk",
+    "jury",
+    "justice",
+    "kernel",
+    "keyboard",
+    "kidney",
+    "kind",
+    "kitchen",
+    "knife",
+    "knit",
+    "laden",
+    "ladle",
+    "ladybug",
+    "lair",
+    "lamp",
+    "language",
+    "large",
+    "laser",
+    "laundry",
+    "lawsuit",
+    "leader",
+    "leaf",
+    "learn",
+    "leaves",
+    "lecture",
+    "legal",
+    "legend",
+    "legs",
+    "lend",
+    "length",
+    "level",
+    "liberty",
+    "library",
+    "license",
+    "lift",
+    "likely",
+    "lilac",
+    "lily",
+    "lips",
+    "liquid",
+    "listen",
+    "literary",
+    "living",
+    "lizard",
+    "loan",
+    "lobe",
+    "location",
+    "losing",
+    "loud",
+    "loyalty",
+    "luck",
+    "lunar",
+    "lunch",
+    "lungs",
+    "luxury",
+    "lying",
+    "lyrics",
+    "machine",
+    "magazine",
+    "maiden",
+    "mailman",
+    "main",
+    "makeup",
+    "making",
+    "mama",
+    "manager",
+    "mandate",
+    "mansion",
+    "manual",
+    "marathon",
+    "march",
+    "market",
+    "marvel",
+    "mason",
+    "material",
+    "math",
+    "maximum",
+    "mayor",
+    "meaning",
+    "medal",
+    "medical",
+    "member",
+    "memory",
+    "mental",
+    "merchant",
+    "merit",
+    "method",
+    "metric",
+    "midst",
+    "mild",
+    "military",
+    "mineral",
+    "minister",
+    "miracle",
+    "mixed",
+    "mixture",
+    "mobile",
+    "modern",
+    "modify",
+    "moisture",
+    "moment",
+    "morning",
+    "mortgage",
+    "mother",
+    "mountain",
+    "mouse",
+    "move",
+    "much",
+    "mule",
+    "multiple",
+    "muscle",
+    "museum",
+    "music",
+    "mustang",
+    "nail",
+    "national",
+    "necklace",
+    "negative",
+    "nervous",
+    "network",
+    "news",
+    "nuclear",
+    "numb",
+    "numerous",
+    "nylon",
+    "oasis",
+    "obesity",
+    "object",
+    "observe",
+    "obtain",
+    "ocean",
+    "often",
+    "olympic",
+    "omit",
+    "oral",
+    "orange",
+    "orbit",
+    "order",
+    "ordinary",
+    "organize",
+    "ounce",
+    "oven",
+    "overall",
+    "owner",
+    "paces",
+    "pacific",
+    "package",
+    "paid",
+    "painting",
+    "pajamas",
+    "pancake",
+    "pants",
+    "papa",
+    "paper",
+    "parcel",
+    "parking",
+    "party",
+    "patent",
+    "patrol",
+    "payment",
+    "payroll",
+    "peaceful",
+    "peanut",
+    "peasant",
+    "pecan",
+    "penalty",
+    "pencil",
+    "percent",
+    "perfect",
+    "permit",
+    "petition",
+    "phantom",
+    "pharmacy",
+    "photo",
+    "phrase",
+    "physics",
+    "pickup",
+    "picture",
+    "piece",
+    "pile",
+    "pink",
+    "pipeline",
+    "pistol",
+    "pitch",
+    "plains",
+    "plan",
+    "plastic",
+    "platform",
+    "playoff",
+    "pleasure",
+    "plot",
+    "plunge",
+    "practice",
+    "prayer",
+    "preach",
+    "predator",
+    "pregnant",
+    "premium",
+    "prepare",
+    "presence",
+    "prevent",
+    "priest",
+    "primary",
+    "priority",
+    "prisoner",
+    "privacy",
+    "prize",
+    "problem",
+    "process",
+    "profile",
+    "program",
+    "promise",
+    "prospect",
+    "provide",
+    "prune",
+    "public",
+    "pulse",
+    "pumps",
+    "punish",
+    "puny",
+    "pupal",
+    "purchase",
+    "purple",
+    "python",
+    "quantity",
+    "quarter",
+    "quick",
+    "quiet",
+    "race",
+    "racism",
+    "radar",
+    "railroad",
+    "rainbow",
+    "raisin",
+    "random",
+    "ranked",
+    "rapids",
+    "raspy",
+    "reaction",
+    "realize",
+    "rebound",
+    "rebuild",
+    "recall",
+    "receiver",
+    "recover",
+    "regret",
+    "regular",
+    "reject",
+    "relate",
+    "remember",
+    "remind",
+    "remove",
+    "render",
+    "repair",
+    "repeat",
+    "replace",
+    "require",
+    "rescue",
+    "research",
+    "resident",
+    "response",
+    "result",
+    "retailer",
+    "retreat",
+    "reunion",
+    "revenue",
+    "review",
+    "reward",
+    "rhyme",
+    "rhythm",
+    "rich",
+    "rival",
+    "river",
+    "robin",
+    "rocky",
+    "romantic",
+    "romp",
+    "roster",
+    "round",
+    "royal",
+    "ruin",
+    "ruler",
+    "rumor",
+    "sack",
+    "safari",
+    "salary",
+    "salon",
+    "salt",
+    "satisfy",
+    "satoshi",
+    "saver",
+    "says",
+    "scandal",
+    "scared",
+    "scatter",
+    "scene",
+    "scholar",
+    "science",
+    "scout",
+    "scramble",
+    "screw",
+    "script",
+    "scroll",
+    "seafood",
+    "season",
+    "secret",
+    "security",
+    "segment",
+    "senior",
+    "shadow",
+    "shaft",
+    "shame",
+    "shaped",
+    "sharp",
+    "shelter",
+    "sheriff",
+    "short",
+    "should",
+    "shrimp",
+    "sidewalk",
+    "silent",
+    "silver",
+    "similar",
+    "simple",
+    "single",
+    "sister",
+    "skin",
+    "skunk",
+    "slap",
+    "slavery",
+    "sled",
+    "slice",
+    "slim",
+    "slow",
+    "slush",
+    "smart",
+    "smear",
+    "smell",
+    "smirk",
+    "smith",
+    "smoking",
+    "smug",
+    "snake",
+    "snapshot",
+    "sniff",
+    "society",
+    "software",
+    "soldier",
+    "solution",
+    "soul",
+    "source",
+    "space",
+    "spark",
+    "speak",
+    "species",
+    "spelling",
+    "spend",
+    "spew",
+    "spider",
+    "spill",
+    "spine",
+    "spirit",
+    "spit",
+    "spray",
+    "sprinkle",
+    "square",
+    "squeeze",
+    "stadium",
+    "staff",
+    "standard",
+    "starting",
+    "station",
+    "stay",
+    "steady",
+    "step",
+    "stick",
+    "stilt",
+    "story",
+    "strategy",
+    "strike",
+    "style",
+    "subject",
+    "submit",
+    "sugar",
+    "suitable",
+    "sunlight",
+    "superior",
+    "surface",
+    "surprise",
+    "survive",
+    "sweater",
+    "swimming",
+    "swing",
+    "switch",
+    "symbolic",
+    "sympathy",
+    "syndrome",
+    "system",
+    "tackle",
+    "tactics",
+    "tadpole",
+    "talent",
+    "task",
+    "taste",
+    "taught",
+    "taxi",
+    "teacher",
+    "teammate",
+    "teaspoon",
+    "temple",
+    "tenant",
+    "tendency",
+    "tension",
+    "terminal",
+    "testify",
+    "texture",
+    "thank",
+    "that",
+    "theater",
+    "theory",
+    "therapy",
+    "thorn",
+    "threaten",
+    "thumb",
+    "thunder",
+    "ticket",
+    "tidy",
+    "timber",
+    "timely",
+    "ting",
+    "tofu",
+    "together",
+    "tolerate",
+    "total",
+    "toxic",
+    "tracks",
+    "traffic",
+    "training",
+    "transfer",
+    "trash",
+    "traveler",
+    "treat",
+    "trend",
+    "trial",
+    "tricycle",
+    "trip",
+    "triumph",
+    "trouble",
+    "true",
+    "trust",
+    "twice",
+    "twin",
+    "type",
+    "typical",
+    "ugly",
+    "ultimate",
+    "umbrella",
+    "uncover",
+    "undergo",
+    "unfair",
+    "unfold",
+    "unhappy",
+    "union",
+    "universe",
+    "unkind",
+    "unknown",
+    "unusual",
+    "unwrap",
+    "upgrade",
+    "upstairs",
+    "username",
+    "usher",
+    "usual",
+    "valid",
+    "valuable",
+    "vampire",
+    "vanish",
+    "various",
+    "vegan",
+    "velvet",
+    "venture",
+    "verdict",
+    "verify",
+    "very",
+    "veteran",
+    "vexed",
+    "victim",
+    "video",
+    "view",
+    "vintage",
+    "violence",
+    "viral",
+    "visitor",
+    "visual",
+    "vitamins",
+    "vocal",
+    "voice",
+    "volume",
+    "voter",
+    "voting",
+    "walnut",
+    "warmth",
+    "warn",
+    "watch",
+    "wavy",
+    "wealthy",
+    "weapon",
+    "webcam",
+    "welcome",
+    "welfare",
+    "western",
+    "width",
+    "wildlife",
+    "window",
+    "wine",
+    "wireless",
+    "wisdom",
+    "withdraw",
+    "wits",
+    "wolf",
+    "woman",
+    "work",
+    "worthy",
+    "wrap",
+    "wrist",
+    "writing",
+    "wrote",
+    "year",
+    "yelp",
+    "yield",
+    "yoga",
+    "zero"
+];
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hex::decode;
+
+    #[test]
+    fn test_slip39() {
+        let single = "duckling enlarge academic academic agency result length solution fridge kidney coal piece deal husband erode duke ajar critical decision keyboard";
+        let share = Share::from_mnemonic(single).unwrap();
+        assert_eq!(share.to_mnemonic(), single);
+        assert_eq!(combine(&[share], "TREZOR").unwrap().to_vec(), decode("bb54aac4b89dc868ba37d9cc21b2cece").unwrap());
+
+        let shares = [
+            "shadow pistol academic always adequate wildlife fancy gross oasis cylinder mustang wrist rescue view short owner flip making coding armed",
+            "shadow pistol academic acid actress prayer class unknown daughter sweater depict flip twice unkind craft early superior advocate guest smoking"
+        ].iter().map(|s| Share::from_mnemonic(s).unwrap()).collect::<Vec<_>>();
+        assert_eq!(combine(&shares, "TREZOR").unwrap().to_vec(), decode("b43ceb7e57a0ea8766221624d01b0864").unwrap());
+        assert!(combine(&shares[..1], "TREZOR").is_err());
+        assert!(Share::from_mnemonic("shadow pistol academic always adequate wildlife fancy gross oasis cylinder mustang wrist rescue view short owner flip making coding coding").is_err());
+
+        let secret = decode("00112233445566778899aabbccddeeff00112233445566778899aabbccddeeff").unwrap();
+        let shares = split(&secret, "", 3, 5, 0).unwrap();
+        assert_eq!(shares.len(), 5);
+        let parsed = shares.iter().map(|s| Share::from_mnemonic(&s.to_mnemonic()).unwrap()).collect::<Vec<_>>();
+        assert_eq!(parsed, shares);
+        assert_eq!(combine(&parsed[1..4], "").unwrap().to_vec(), secret);
+        assert_eq!(combine(&[parsed[4].clone(), parsed[0].clone(), parsed[2].clone()], "").unwrap().to_vec(), secret);
+        assert!(combine(&parsed[..2], "").is_err());
+        assert_ne!(combine(&parsed[..3], "other").unwrap().to_vec(), secret);
+
+        let groups = split_groups(&secret[..16], "TREZOR", 2, &[(1, 1), (2, 3), (3, 5)], 0).unwrap();
+        assert_eq!(groups.iter().map(|g| g.len()).collect::<Vec<_>>(), vec!(1, 3, 5));
+        let mut shares = groups[0].clone();
+        shares.extend(groups[2][1..4].iter().cloned());
+        assert_eq!(combine(&shares, "TREZOR").unwrap().to_vec(), secret[..16].to_vec());
+        assert!(combine(&groups[2][1..4], "TREZOR").is_err());
+
+        assert!(split(&secret[..14], "", 2, 3, 0).is_err());
+        assert!(split(&secret, "", 1, 3, 0).is_err());
+    }
+}
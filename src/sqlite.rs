@@ -0,0 +1,267 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # SQLite store
+//!
+//! A wallet store in an SQLite database, coins and history are queried without loading all of them
+//!
+
+use std::path::Path;
+use std::str::FromStr;
+
+use bitcoin::{OutPoint, TxOut, Txid};
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use account::{KeyDerivation, MasterAccount};
+use coins::{Coin, Coins};
+use error::WalletError;
+use proved::ProvedTransaction;
+use store::{CoinsRecord, HistoryEntry, MasterRecord, WalletStore};
+
+/// schema changes of each version in order, a database of version n had the first n applied
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE master (id INTEGER PRIMARY KEY CHECK (id = 0), record TEXT NOT NULL);
+     CREATE TABLE coins (txid TEXT NOT NULL, vout INTEGER NOT NULL, output TEXT NOT NULL, derivation TEXT NOT NULL, PRIMARY KEY (txid, vout));
+     CREATE TABLE proofs (txid TEXT PRIMARY KEY, proof TEXT NOT NULL);
+     CREATE TABLE frozen (txid TEXT NOT NULL, vout INTEGER NOT NULL, PRIMARY KEY (txid, vout));
+     CREATE TABLE history (txid TEXT NOT NULL, height INTEGER NOT NULL);
+     CREATE INDEX history_height ON history (height);"
+];
+
+/// a wallet store in an SQLite database
+/// the mnemonic is encrypted in the master record as in every store, coins and history are not
+pub struct SqliteStore {
+    connection: Connection
+}
+
+impl SqliteStore {
+    /// open or create the database at path and migrate its schema to the current version
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<SqliteStore, WalletError> {
+        SqliteStore::new(Connection::open(path).map_err(sqlite_error)?)
+    }
+
+    /// a store of an open connection, e.g. of an in-memory database
+    pub fn new(mut connection: Connection) -> Result<SqliteStore, WalletError> {
+        migrate(&mut connection)?;
+        Ok(SqliteStore { connection })
+    }
+
+    /// schema version of the database
+    pub fn version(&self) -> Result<u32, WalletError> {
+        self.connection.query_row("PRAGMA user_version", [], |row| row.get(0)).map_err(sqlite_error)
+    }
+
+    fn transaction(&mut self) -> Result<Transaction<'_>, WalletError> {
+        self.connection.transaction().map_err(sqlite_error)
+    }
+}
+
+impl WalletStore for SqliteStore {
+    fn load_master(&mut self) -> Result<Option<MasterAccount>, WalletError> {
+        let record = self.connection.query_row("SELECT record FROM master WHERE id = 0", [], |row| row.get::<_, String>(0))
+            .optional().map_err(sqlite_error)?;
+        record.map(|r| from_json::<MasterRecord>(&r).map(MasterRecord::restore)).transpose()
+    }
+
+    fn save_master(&mut self, master: &MasterAccount) -> Result<(), WalletError> {
+        let record = to_json(&MasterRecord::new(master))?;
+        self.connection.execute("INSERT OR REPLACE INTO master (id, record) VALUES (0, ?1)", params![record]).map_err(sqlite_error)?;
+        Ok(())
+    }
+
+    fn load_coins(&mut self) -> Result<Coins, WalletError> {
+        let mut record = CoinsRecord::default();
+        let mut statement = self.connection.prepare("SELECT txid, vout, output, derivation FROM coins").map_err(sqlite_error)?;
+        let mut rows = statement.query([]).map_err(sqlite_error)?;
+        while let Some(row) = rows.next().map_err(sqlite_error)? {
+            let point = outpoint(&row.get::<_, String>(0).map_err(sqlite_error)?, row.get(1).map_err(sqlite_error)?)?;
+            let output = from_json::<TxOut>(&row.get::<_, String>(2).map_err(sqlite_error)?)?;
+            let derivation = from_json::<KeyDerivation>(&row.get::<_, String>(3).map_err(sqlite_error)?)?;
+            record.owned.push((point, output, derivation));
+        }
+        let mut statement = self.connection.prepare("SELECT proof FROM proofs").map_err(sqlite_error)?;
+        let mut rows = statement.query([]).map_err(sqlite_error)?;
+        while let Some(row) = rows.next().map_err(sqlite_error)? {
+            record.proofs.push(from_json::<ProvedTransaction>(&row.get::<_, String>(0).map_err(sqlite_error)?)?);
+        }
+        let mut statement = self.connection.prepare("SELECT txid, vout FROM frozen").map_err(sqlite_error)?;
+        let mut rows = statement.query([]).map_err(sqlite_error)?;
+        while let Some(row) = rows.next().map_err(sqlite_error)? {
+            record.frozen.push(outpoint(&row.get::<_, String>(0).map_err(sqlite_error)?, row.get(1).map_err(sqlite_error)?)?);
+        }
+        record.restore()
+    }
+
+    fn save_coins(&mut self, coins: &Coins) -> Result<(), WalletError> {
+        let record = CoinsRecord::new(coins);
+        let transaction = self.transaction()?;
+        transaction.execute_batch("DELETE FROM coins; DELETE FROM proofs; DELETE FROM frozen;").map_err(sqlite_error)?;
+        for (point, output, derivation) in &record.owned {
+            transaction.execute("INSERT INTO coins (txid, vout, output, derivation) VALUES (?1, ?2, ?3, ?4)",
+                                params![point.txid.to_string(), point.vout, to_json(output)?, to_json(derivation)?]).map_err(sqlite_error)?;
+        }
+        for proof in &record.proofs {
+            transaction.execute("INSERT INTO proofs (txid, proof) VALUES (?1, ?2)",
+                                params![proof.get_transaction().compute_txid().to_string(), to_json(proof)?]).map_err(sqlite_error)?;
+        }
+        for point in &record.frozen {
+            transaction.execute("INSERT INTO frozen (txid, vout) VALUES (?1, ?2)", params![point.txid.to_string(), point.vout]).map_err(sqlite_error)?;
+        }
+        transaction.commit().map_err(sqlite_error)
+    }
+
+    fn load_history(&mut self) -> Result<Vec<HistoryEntry>, WalletError> {
+        history(&self.connection, "SELECT txid, height FROM history ORDER BY rowid", params![])
+    }
+
+    fn save_history(&mut self, history: &[HistoryEntry]) -> Result<(), WalletError> {
+        let transaction = self.transaction()?;
+        transaction.execute("DELETE FROM history", []).map_err(sqlite_error)?;
+        for (txid, height) in history {
+            transaction.execute("INSERT INTO history (txid, height) VALUES (?1, ?2)", params![txid.to_string(), height]).map_err(sqlite_error)?;
+        }
+        transaction.commit().map_err(sqlite_error)
+    }
+
+    fn coin(&mut self, point: &OutPoint) -> Result<Option<Coin>, WalletError> {
+        let row = self.connection.query_row("SELECT output, derivation FROM coins WHERE txid = ?1 AND vout = ?2",
+                                            params![point.txid.to_string(), point.vout],
+                                            |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))
+            .optional().map_err(sqlite_error)?;
+        match row {
+            Some((output, derivation)) => Ok(Some(Coin { output: from_json(&output)?, derivation: from_json(&derivation)? })),
+            None => Ok(None)
+        }
+    }
+
+    fn history_since(&mut self, height: i32) -> Result<Vec<HistoryEntry>, WalletError> {
+        history(&self.connection, "SELECT txid, height FROM history WHERE height <= 0 OR height >= ?1 ORDER BY rowid", params![height])
+    }
+}
+
+// apply the migrations the database is missing in one transaction
+fn migrate(connection: &mut Connection) -> Result<(), WalletError> {
+    let transaction = connection.transaction().map_err(sqlite_error)?;
+    let version = transaction.query_row("PRAGMA user_version", [], |row| row.get::<_, u32>(0)).map_err(sqlite_error)? as usize;
+    if version > MIGRATIONS.len() {
+        return Err(WalletError::Unsupported("wallet store of a newer version"));
+    }
+    for migration in &MIGRATIONS[version..] {
+        transaction.execute_batch(migration).map_err(sqlite_error)?;
+    }
+    transaction.pragma_update(None, "user_version", MIGRATIONS.len() as u32).map_err(sqlite_error)?;
+    transaction.commit().map_err(sqlite_error)
+}
+
+fn history(connection: &Connection, query: &str, params: &[&dyn rusqlite::ToSql]) -> Result<Vec<HistoryEntry>, WalletError> {
+    let mut statement = connection.prepare(query).map_err(sqlite_error)?;
+    let mut rows = statement.query(params).map_err(sqlite_error)?;
+    let mut history = Vec::new();
+    while let Some(row) = rows.next().map_err(sqlite_error)? {
+        let txid = Txid::from_str(&row.get::<_, String>(0).map_err(sqlite_error)?).map_err(|e| WalletError::StoreCorrupt(e.to_string()))?;
+        history.push((txid, row.get(1).map_err(sqlite_error)?));
+    }
+    Ok(history)
+}
+
+fn outpoint(txid: &str, vout: u32) -> Result<OutPoint, WalletError> {
+    Ok(OutPoint { txid: Txid::from_str(txid).map_err(|e| WalletError::StoreCorrupt(e.to_string()))?, vout })
+}
+
+fn to_json<T: Serialize>(value: &T) -> Result<String, WalletError> {
+    serde_json::to_string(value).map_err(|e| WalletError::StoreCorrupt(e.to_string()))
+}
+
+fn from_json<T: DeserializeOwned>(json: &str) -> Result<T, WalletError> {
+    serde_json::from_str(json).map_err(|e| WalletError::StoreCorrupt(e.to_string()))
+}
+
+fn sqlite_error(error: rusqlite::Error) -> WalletError {
+    WalletError::Sqlite(error.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{Amount, Block, BlockHash, CompactTarget, Network, ScriptBuf, Sequence, TxIn, TxMerkleNode, Witness};
+    use bitcoin::absolute::LockTime;
+    use bitcoin::block::Header;
+    use bitcoin::transaction::Version;
+    use bitcoin_hashes::Hash;
+
+    use account::{AccountAddressType, MasterKeyEntropy, Unlocker};
+    use super::*;
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+
+    #[test]
+    fn test_sqlite_store() {
+        let mut master = MasterAccount::new(MasterKeyEntropy::Low, Network::Bitcoin, PASSPHRASE, None).unwrap();
+        let mut unlocker = Unlocker::new(master.encrypted(), PASSPHRASE, None, Network::Bitcoin, None).unwrap();
+        let script_pubkey = master.new_account(&mut unlocker, AccountAddressType::P2WPKH, 0, 0, 10).unwrap().next_key().unwrap().script_pubkey.clone();
+        let transaction = bitcoin::Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec!(TxIn { previous_output: OutPoint::null(), script_sig: ScriptBuf::new(), sequence: Sequence::MAX, witness: Witness::new() }),
+            output: vec!(TxOut { value: Amount::from_sat(10000), script_pubkey: script_pubkey.clone() },
+                         TxOut { value: Amount::from_sat(20000), script_pubkey })
+        };
+        let mut block = Block {
+            header: Header { version: bitcoin::block::Version::ONE, prev_blockhash: BlockHash::all_zeros(), merkle_root: TxMerkleNode::all_zeros(),
+                time: 0, bits: CompactTarget::from_consensus(0), nonce: 0 },
+            txdata: vec!(transaction.clone())
+        };
+        block.header.merkle_root = block.compute_merkle_root().unwrap();
+        let mut coins = Coins::new();
+        coins.process(&mut master, &block).unwrap();
+        let txid = transaction.compute_txid();
+        coins.freeze(OutPoint { txid, vout: 1 });
+        let history = vec!((txid, 5), (Txid::all_zeros(), 0), (Txid::from_byte_array([1; 32]), 2));
+
+        let mut store = SqliteStore::new(Connection::open_in_memory().unwrap()).unwrap();
+        assert_eq!(store.version().unwrap(), MIGRATIONS.len() as u32);
+        assert!(store.load_master().unwrap().is_none());
+        assert!(store.load_coins().unwrap().owned().is_empty());
+        store.save_master(&master).unwrap();
+        store.save_coins(&coins).unwrap();
+        store.save_history(&history).unwrap();
+
+        let restored = store.load_master().unwrap().unwrap();
+        assert_eq!(restored.master_public(), master.master_public());
+        assert_eq!(restored.get((0, 0)).unwrap().next(), master.get((0, 0)).unwrap().next());
+        assert!(store.load_coins().unwrap() == coins);
+        assert_eq!(store.coin(&OutPoint { txid, vout: 1 }).unwrap().unwrap().output.value, Amount::from_sat(20000));
+        assert!(store.coin(&OutPoint { txid, vout: 2 }).unwrap().is_none());
+        assert_eq!(store.load_history().unwrap(), history);
+        assert_eq!(store.history_since(3).unwrap(), vec!(history[0], history[1]));
+        // saves replace what was stored
+        store.save_history(&history[..1]).unwrap();
+        assert_eq!(store.load_history().unwrap(), &history[..1]);
+        store.save_coins(&Coins::new()).unwrap();
+        assert!(store.coin(&OutPoint { txid, vout: 1 }).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_migrate() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        migrate(&mut connection).unwrap();
+        // migrating again leaves the schema alone
+        migrate(&mut connection).unwrap();
+        connection.pragma_update(None, "user_version", 99).unwrap();
+        assert!(SqliteStore::new(connection).is_err());
+    }
+}
@@ -0,0 +1,459 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Wallet store
+//!
+//! Persistence of master account, accounts, coins and history,
+//! the file store is not built for wasm32 where hosts keep the records themselves
+//!
+#[cfg(not(target_arch = "wasm32"))]
+use std::fs;
+#[cfg(not(target_arch = "wasm32"))]
+use std::io;
+#[cfg(not(target_arch = "wasm32"))]
+use std::path::{Path, PathBuf};
+#[cfg(not(target_arch = "wasm32"))]
+use std::str;
+
+use bitcoin::{Network, OutPoint, TxOut, Txid};
+use bitcoin::bip32::{DerivationPath, Fingerprint, Xpub};
+
+use account::{Account, AccountAddressType, AccountId, InstantiatedKey, KeyDerivation, MasterAccount};
+use coins::{Coin, Coins};
+use error::WalletError;
+#[cfg(not(target_arch = "wasm32"))]
+use keystore;
+#[cfg(not(target_arch = "wasm32"))]
+use keystore::{Cipher, Kdf};
+use proved::ProvedTransaction;
+#[cfg(not(target_arch = "wasm32"))]
+use secret::SecretBytes;
+
+/// a transaction of the wallet, height is zero or negative for unconfirmed transactions
+pub type HistoryEntry = (Txid, i32);
+
+/// storage of wallet state
+pub trait WalletStore {
+    /// the master account with its accounts and their address cursors, None if not yet saved
+    fn load_master(&mut self) -> Result<Option<MasterAccount>, WalletError>;
+
+    /// store the master account with its accounts and their address cursors
+    fn save_master(&mut self, master: &MasterAccount) -> Result<(), WalletError>;
+
+    /// owned coins and their proofs
+    fn load_coins(&mut self) -> Result<Coins, WalletError>;
+
+    /// store owned coins and their proofs
+    fn save_coins(&mut self, coins: &Coins) -> Result<(), WalletError>;
+
+    /// transaction history
+    fn load_history(&mut self) -> Result<Vec<HistoryEntry>, WalletError>;
+
+    /// store transaction history
+    fn save_history(&mut self, history: &[HistoryEntry]) -> Result<(), WalletError>;
+
+    /// an owned coin, stores backed by a database should answer without loading all coins, see `sqlite::SqliteStore`
+    fn coin(&mut self, point: &OutPoint) -> Result<Option<Coin>, WalletError> {
+        Ok(self.load_coins()?.owned().get(point).cloned())
+    }
+
+    /// confirmed history from height on and unconfirmed transactions,
+    /// stores backed by a database should answer without loading all history
+    fn history_since(&mut self, height: i32) -> Result<Vec<HistoryEntry>, WalletError> {
+        Ok(self.load_history()?.into_iter().filter(|(_, h)| *h <= 0 || *h >= height).collect())
+    }
+}
+
+/// version of the stored record format, stores migrate older versions on load
+pub const STORE_VERSION: u32 = 1;
+
+/// stored form of a master account
+#[derive(Clone, Serialize, Deserialize)]
+pub struct MasterRecord {
+    pub master_public: Xpub,
+    pub fingerprint: Fingerprint,
+    pub encrypted: Vec<u8>,
+    pub birth: u64,
+    pub accounts: Vec<AccountRecord>,
+    /// ids of archived accounts
+    #[serde(default)]
+    pub archived: Vec<AccountId>
+}
+
+impl MasterRecord {
+    pub fn new(master: &MasterAccount) -> MasterRecord {
+        let mut accounts = master.accounts().values().map(AccountRecord::new).collect::<Vec<_>>();
+        accounts.sort_by_key(|a| (a.account_number, a.sub_account_number));
+        let archived = master.account_ids().into_iter().filter(|id| master.is_archived(*id)).collect();
+        MasterRecord {
+            master_public: *master.master_public(),
+            fingerprint: master.fingerprint(),
+            encrypted: master.encrypted().clone(),
+            birth: master.birth(),
+            accounts,
+            archived
+        }
+    }
+
+    pub fn restore(self) -> MasterAccount {
+        let mut master = MasterAccount::new_from_storage(self.master_public, self.fingerprint, &self.encrypted, self.birth);
+        for account in self.accounts {
+            master.add_account(account.restore());
+        }
+        for id in self.archived {
+            // ids of accounts no longer present are dropped
+            master.archive_account(id, true).ok();
+        }
+        master
+    }
+}
+
+/// stored form of an account, next is the address cursor
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AccountRecord {
+    pub address_type: u32,
+    pub account_number: u32,
+    pub sub_account_number: u32,
+    pub master_public: Xpub,
+    pub instantiated: Vec<InstantiatedKey>,
+    pub next: u32,
+    pub look_ahead: u32,
+    pub network: Network,
+    /// none if the BIP44 coin type of the network
+    #[serde(default)]
+    pub coin_type: Option<u32>,
+    /// chain path of custom accounts
+    #[serde(default)]
+    pub path: Option<DerivationPath>,
+    /// transactions that paid to keys, by key index
+    #[serde(default)]
+    pub receipts: Vec<(u32, Vec<Txid>)>
+}
+
+impl AccountRecord {
+    pub fn new(account: &Account) -> AccountRecord {
+        let mut receipts = account.receipts().iter().map(|(kix, txids)| {
+            let mut txids = txids.iter().cloned().collect::<Vec<_>>();
+            txids.sort();
+            (*kix, txids)
+        }).collect::<Vec<_>>();
+        receipts.sort();
+        AccountRecord {
+            address_type: account.address_type().as_u32(),
+            account_number: account.account_number(),
+            sub_account_number: account.sub_account_number(),
+            master_public: *account.master_public(),
+            instantiated: account.instantiated().clone(),
+            next: account.next(),
+            look_ahead: account.look_ahead(),
+            network: account.network(),
+            coin_type: Some(account.coin_type()),
+            path: account.path().cloned(),
+            receipts
+        }
+    }
+
+    pub fn restore(self) -> Account {
+        let account = Account::new_from_storage(AccountAddressType::from_u32(self.address_type), self.account_number, self.sub_account_number,
+                                  self.master_public, self.instantiated, self.next, self.look_ahead, self.network)
+            .with_path(self.path)
+            .with_receipts(self.receipts.into_iter().map(|(kix, txids)| (kix, txids.into_iter().collect())).collect());
+        match self.coin_type {
+            Some(coin_type) => account.with_coin_type(coin_type),
+            None => account
+        }
+    }
+}
+
+/// stored form of owned coins
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct CoinsRecord {
+    pub owned: Vec<(OutPoint, TxOut, KeyDerivation)>,
+    pub proofs: Vec<ProvedTransaction>,
+    /// coins not to spend
+    #[serde(default)]
+    pub frozen: Vec<OutPoint>
+}
+
+impl CoinsRecord {
+    pub fn new(coins: &Coins) -> CoinsRecord {
+        let mut owned = coins.owned().iter().map(|(p, c)| (*p, c.output.clone(), c.derivation.clone())).collect::<Vec<_>>();
+        owned.sort_by_key(|(p, _, _)| *p);
+        let mut proofs = coins.proofs().values().cloned().collect::<Vec<_>>();
+        proofs.sort_by_key(|p| p.get_transaction().compute_txid());
+        let mut frozen = coins.frozen().iter().cloned().collect::<Vec<_>>();
+        frozen.sort();
+        CoinsRecord { owned, proofs, frozen }
+    }
+
+    pub fn restore(self) -> Result<Coins, WalletError> {
+        let mut coins = Coins::new();
+        for (point, output, derivation) in self.owned {
+            let proof = self.proofs.iter().find(|p| p.get_transaction().compute_txid() == point.txid)
+                .ok_or(WalletError::StoreCorrupt("stored coin without proof".to_string()))?;
+            coins.add_from_storage(point, Coin { output, derivation }, proof.clone());
+        }
+        for point in self.frozen {
+            coins.freeze(point);
+        }
+        Ok(coins)
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Serialize, Deserialize)]
+struct WalletRecord {
+    /// files written before versioning are of version 0
+    #[serde(default)]
+    version: u32,
+    master: Option<MasterRecord>,
+    coins: CoinsRecord,
+    history: Vec<HistoryEntry>
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Default for WalletRecord {
+    fn default() -> WalletRecord {
+        WalletRecord { version: STORE_VERSION, master: None, coins: CoinsRecord::default(), history: Vec::new() }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl WalletRecord {
+    // bring a record of an earlier version to the current one
+    fn migrate(mut self) -> Result<WalletRecord, WalletError> {
+        if self.version > STORE_VERSION {
+            return Err(WalletError::Unsupported("wallet store of a newer version"));
+        }
+        // version 0 differs only in the missing version field
+        self.version = STORE_VERSION;
+        Ok(self)
+    }
+}
+
+/// a wallet store in a single file encrypted as a keystore
+/// the file is replaced on every save
+#[cfg(not(target_arch = "wasm32"))]
+pub struct FileStore {
+    path: PathBuf,
+    passphrase: SecretBytes,
+    kdf: Kdf,
+    record: WalletRecord
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl FileStore {
+    /// open or create the store at path
+    /// kdf is used for a new file, an existing file keeps its key derivation
+    pub fn open<P: AsRef<Path>>(path: P, passphrase: &str, kdf: Kdf) -> Result<FileStore, WalletError> {
+        let path = path.as_ref().to_path_buf();
+        let encrypted = if path.exists() { Some(fs::read(&path)?) } else { None };
+        FileStore::decrypt(path, passphrase, kdf, encrypted)
+    }
+
+    /// the store of the encrypted content of its file, None if there is no file yet
+    pub(crate) fn decrypt(path: PathBuf, passphrase: &str, kdf: Kdf, encrypted: Option<Vec<u8>>) -> Result<FileStore, WalletError> {
+        let passphrase = SecretBytes::from_slice(passphrase.as_bytes());
+        let encrypted = match encrypted {
+            Some(encrypted) => encrypted,
+            None => return Ok(FileStore { path, passphrase, kdf, record: WalletRecord::default() })
+        };
+        let kdf = keystore::kdf(&encrypted)?;
+        let decrypted = keystore::decrypt(&encrypted, str::from_utf8(&passphrase).expect("passphrase was a str"))?;
+        let record = serde_json::from_slice::<WalletRecord>(&decrypted).map_err(|e| WalletError::StoreCorrupt(e.to_string()))?.migrate()?;
+        Ok(FileStore { path, passphrase, kdf, record })
+    }
+
+    /// encrypt the file with a new passphrase, old must be the one it was opened with.
+    /// The new file replaces the old once written.
+    pub fn change_passphrase(&mut self, old: &str, new: &str) -> Result<(), WalletError> {
+        if old.as_bytes() != self.passphrase.as_slice() {
+            return Err(WalletError::Passphrase);
+        }
+        let previous = std::mem::replace(&mut self.passphrase, SecretBytes::from_slice(new.as_bytes()));
+        if let Err(e) = self.write() {
+            self.passphrase = previous;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn write(&self) -> Result<(), WalletError> {
+        let (encrypted, temporary, path) = self.encrypt()?;
+        fs::write(&temporary, encrypted)?;
+        fs::rename(&temporary, path)?;
+        Ok(())
+    }
+
+    /// the content of the file, the temporary file it is written to and the file that one then replaces
+    pub(crate) fn encrypt(&self) -> Result<(Vec<u8>, PathBuf, PathBuf), WalletError> {
+        let json = SecretBytes::new(serde_json::to_vec(&self.record).map_err(io::Error::from)?);
+        let encrypted = keystore::encrypt(&json, str::from_utf8(&self.passphrase).expect("passphrase was a str"), Cipher::Aes256Gcm, self.kdf)?;
+        let mut temporary = self.path.clone().into_os_string();
+        temporary.push(".tmp");
+        Ok((encrypted, PathBuf::from(temporary), self.path.clone()))
+    }
+
+    pub(crate) fn set_master(&mut self, master: &MasterAccount) {
+        self.record.master = Some(MasterRecord::new(master));
+    }
+
+    pub(crate) fn set_coins(&mut self, coins: &Coins) {
+        self.record.coins = CoinsRecord::new(coins);
+    }
+
+    pub(crate) fn set_history(&mut self, history: &[HistoryEntry]) {
+        self.record.history = history.to_vec();
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl WalletStore for FileStore {
+    fn load_master(&mut self) -> Result<Option<MasterAccount>, WalletError> {
+        Ok(self.record.master.clone().map(MasterRecord::restore))
+    }
+
+    fn save_master(&mut self, master: &MasterAccount) -> Result<(), WalletError> {
+        self.set_master(master);
+        self.write()
+    }
+
+    fn load_coins(&mut self) -> Result<Coins, WalletError> {
+        self.record.coins.clone().restore()
+    }
+
+    fn save_coins(&mut self, coins: &Coins) -> Result<(), WalletError> {
+        self.set_coins(coins);
+        self.write()
+    }
+
+    fn load_history(&mut self) -> Result<Vec<HistoryEntry>, WalletError> {
+        Ok(self.record.history.clone())
+    }
+
+    fn save_history(&mut self, history: &[HistoryEntry]) -> Result<(), WalletError> {
+        self.set_history(history);
+        self.write()
+    }
+
+    fn coin(&mut self, point: &OutPoint) -> Result<Option<Coin>, WalletError> {
+        Ok(self.record.coins.owned.iter().find(|(p, _, _)| p == point)
+            .map(|(_, output, derivation)| Coin { output: output.clone(), derivation: derivation.clone() }))
+    }
+
+    fn history_since(&mut self, height: i32) -> Result<Vec<HistoryEntry>, WalletError> {
+        Ok(self.record.history.iter().filter(|(_, h)| *h <= 0 || *h >= height).cloned().collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+
+    use bitcoin::{Amount, Block, BlockHash, CompactTarget, ScriptBuf, Sequence, Transaction, TxIn, TxMerkleNode, Witness};
+    use bitcoin::absolute::LockTime;
+    use bitcoin::block::Header;
+    use bitcoin::transaction::Version;
+    use bitcoin_hashes::Hash;
+
+    use account::{MasterKeyEntropy, Unlocker};
+    use argon2;
+    use super::*;
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+
+    #[test]
+    fn test_file_store() {
+        let mut path = env::temp_dir();
+        path.push(format!("bitcoin-wallet-store-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let kdf = Kdf::Argon2id(argon2::Params { memory: 64, iterations: 1, parallelism: 1 });
+
+        let mut master = MasterAccount::new(MasterKeyEntropy::Low, Network::Bitcoin, PASSPHRASE, None).unwrap();
+        let mut unlocker = Unlocker::new(master.encrypted(), PASSPHRASE, None, Network::Bitcoin, None).unwrap();
+        let script_pubkey = master.new_account(&mut unlocker, AccountAddressType::P2WPKH, 0, 0, 10).unwrap().next_key().unwrap().script_pubkey.clone();
+        master.new_account(&mut unlocker, AccountAddressType::P2WPKH, 0, 1, 10).unwrap();
+        master.archive_account((84, 0, 0), true).unwrap();
+
+        let transaction = Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec!(TxIn { previous_output: OutPoint::null(), script_sig: ScriptBuf::new(), sequence: Sequence::MAX, witness: Witness::new() }),
+            output: vec!(TxOut { value: Amount::from_sat(10000), script_pubkey })
+        };
+        let mut block = Block {
+            header: Header { version: bitcoin::block::Version::ONE, prev_blockhash: BlockHash::all_zeros(), merkle_root: TxMerkleNode::all_zeros(),
+                time: 0, bits: CompactTarget::from_consensus(0), nonce: 0 },
+            txdata: vec!(transaction.clone())
+        };
+        block.header.merkle_root = block.compute_merkle_root().unwrap();
+        let mut coins = Coins::new();
+        coins.process(&mut master, &block).unwrap();
+        assert_eq!(coins.owned().len(), 1);
+        let history = vec!((transaction.compute_txid(), 1));
+
+        {
+            let mut store = FileStore::open(&path, PASSPHRASE, kdf).unwrap();
+            assert!(store.load_master().unwrap().is_none());
+            store.save_master(&master).unwrap();
+            store.save_coins(&coins).unwrap();
+            store.save_history(&history).unwrap();
+        }
+        assert!(FileStore::open(&path, "wrong", kdf).is_err());
+        let mut corrupt = path.clone().into_os_string();
+        corrupt.push(".corrupt");
+        fs::write(&corrupt, keystore::encrypt(b"{\"master\":", PASSPHRASE, Cipher::Aes256Gcm, kdf).unwrap()).unwrap();
+        match FileStore::open(&corrupt, PASSPHRASE, kdf) {
+            Err(WalletError::StoreCorrupt(_)) => {},
+            _ => panic!("corrupt store")
+        }
+        fs::remove_file(&corrupt).unwrap();
+
+        let mut store = FileStore::open(&path, PASSPHRASE, Kdf::default()).unwrap();
+        let restored = store.load_master().unwrap().unwrap();
+        assert_eq!(restored.master_public(), master.master_public());
+        assert_eq!(restored.fingerprint(), master.fingerprint());
+        assert_eq!(restored.encrypted(), master.encrypted());
+        assert_eq!(restored.accounts().len(), 2);
+        assert!(restored.is_archived((84, 0, 0)));
+        let account = restored.get((0, 0)).unwrap();
+        assert_eq!(account.next(), master.get((0, 0)).unwrap().next());
+        assert_eq!(account.instantiated().len(), master.get((0, 0)).unwrap().instantiated().len());
+        assert!(store.load_coins().unwrap() == coins);
+        assert_eq!(store.load_history().unwrap(), history);
+        let point = *coins.owned().keys().next().unwrap();
+        assert_eq!(store.coin(&point).unwrap().unwrap().output.value.to_sat(), 10000);
+        assert_eq!(store.history_since(1).unwrap(), history);
+        assert!(store.history_since(2).unwrap().is_empty());
+        // the key derivation of the file is kept
+        store.save_history(&[]).unwrap();
+        assert_eq!(keystore::kdf(&fs::read(&path).unwrap()).unwrap(), kdf);
+        assert!(store.change_passphrase("wrong", "new passphrase").is_err());
+        store.change_passphrase(PASSPHRASE, "new passphrase").unwrap();
+        assert!(FileStore::open(&path, PASSPHRASE, kdf).is_err());
+        assert!(FileStore::open(&path, "new passphrase", kdf).unwrap().load_master().unwrap().is_some());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_migrate() {
+        let record: WalletRecord = serde_json::from_str(r#"{"master":null,"coins":{"owned":[],"proofs":[]},"history":[]}"#).unwrap();
+        assert_eq!(record.version, 0);
+        assert_eq!(record.migrate().unwrap().version, STORE_VERSION);
+        let record: WalletRecord = serde_json::from_str(r#"{"version":99,"master":null,"coins":{"owned":[],"proofs":[]},"history":[]}"#).unwrap();
+        assert!(record.migrate().is_err());
+    }
+}
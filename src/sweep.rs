@@ -0,0 +1,176 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Sweep
+//!
+//! Move all coins of an external private key, e.g. of a paper wallet, into the wallet
+//!
+
+use std::convert::TryFrom;
+
+use bitcoin::{Amount, CompressedPublicKey, EcdsaSighashType, FeeRate, Network, OutPoint, PrivateKey, Script, ScriptBuf,
+              Sequence, Transaction, TxIn, TxOut, Witness, ecdsa};
+use bitcoin::absolute::LockTime;
+use bitcoin::script::{Builder, PushBytesBuf};
+use bitcoin::sighash::SighashCache;
+use bitcoin::transaction::Version;
+
+use account::AccountAddressType;
+use coinselect;
+use context::SecpContext;
+use error::WalletError;
+
+/// a chain backend that knows the unspent outputs of a script
+pub trait UtxoSource {
+    fn unspent(&mut self, script_pubkey: &Script) -> Result<Vec<(OutPoint, TxOut)>, WalletError>;
+}
+
+/// the scripts a private key controls with their address types
+fn scripts(key: &PrivateKey) -> Vec<(AccountAddressType, ScriptBuf)> {
+    let public = key.public_key(&secp256k1::Secp256k1::signing_only());
+    let mut scripts = vec!((AccountAddressType::P2PKH, ScriptBuf::new_p2pkh(&public.pubkey_hash())));
+    if let Ok(compressed) = CompressedPublicKey::try_from(public) {
+        let p2wpkh = ScriptBuf::new_p2wpkh(&compressed.wpubkey_hash());
+        scripts.push((AccountAddressType::P2SHWPKH, ScriptBuf::new_p2sh(&p2wpkh.script_hash())));
+        scripts.push((AccountAddressType::P2WPKH, p2wpkh));
+    }
+    scripts
+}
+
+/// a signed transaction paying all coins of the private key in WIF to destination at the fee rate.
+/// P2PKH, P2WPKH and P2SH-P2WPKH outputs of the key are looked up at the source.
+pub fn sweep(wif: &str, network: Network, fee_rate: FeeRate, source: &mut dyn UtxoSource, destination: ScriptBuf) -> Result<Transaction, WalletError> {
+    let key = PrivateKey::from_wif(wif).map_err(|_| WalletError::Unsupported("invalid WIF private key"))?;
+    if bitcoin::NetworkKind::from(network) != key.network {
+        return Err(WalletError::Network);
+    }
+    let keys = scripts(&key).into_iter().map(|(address_type, _)| (key, address_type)).collect::<Vec<_>>();
+    sweep_keys(&keys, fee_rate, source, destination)
+}
+
+/// a signed transaction paying all coins of the keys to destination at the fee rate,
+/// only outputs of the given address type of each key are looked up at the source
+pub fn sweep_keys(keys: &[(PrivateKey, AccountAddressType)], fee_rate: FeeRate, source: &mut dyn UtxoSource, destination: ScriptBuf) -> Result<Transaction, WalletError> {
+    let context = SecpContext::new();
+    let secp = secp256k1::Secp256k1::signing_only();
+
+    let mut spent = Vec::new();
+    for (key, address_type) in keys {
+        let script_pubkey = scripts(key).into_iter().find(|(a, _)| a == address_type).map(|(_, s)| s)
+            .ok_or(WalletError::Unsupported("segwit needs a compressed key"))?;
+        for (point, output) in source.unspent(&script_pubkey)? {
+            spent.push((*key, key.public_key(&secp), *address_type, point, output));
+        }
+    }
+    if spent.is_empty() {
+        return Err(WalletError::InsufficientFunds);
+    }
+    // uncompressed keys are 32 bytes longer, signatures with high R one byte
+    let weight = coinselect::TRANSACTION_OVERHEAD_WEIGHT + coinselect::output_weight(&destination)
+        + spent.iter().map(|(_, public, address_type, _, _)| {
+            let extra = match address_type {
+                AccountAddressType::P2PKH => 4 * if public.compressed { 1 } else { 33 },
+                _ => 1
+            };
+            coinselect::input_weight(*address_type, &ScriptBuf::new()) + extra
+        }).sum::<u64>();
+    let value = spent.iter().map(|(_, _, _, _, o)| o.value.to_sat()).sum::<u64>();
+    let remaining = value.checked_sub(coinselect::fee(fee_rate, weight)).ok_or(WalletError::InsufficientFunds)?;
+    if remaining < destination.minimal_non_dust().to_sat() {
+        return Err(WalletError::InsufficientFunds);
+    }
+
+    let mut transaction = Transaction {
+        version: Version::TWO,
+        lock_time: LockTime::ZERO,
+        input: spent.iter().map(|(_, _, _, point, _)| TxIn { previous_output: *point, script_sig: ScriptBuf::new(), sequence: Sequence::ENABLE_RBF_NO_LOCKTIME, witness: Witness::new() }).collect(),
+        output: vec!(TxOut { value: Amount::from_sat(remaining), script_pubkey: destination })
+    };
+    let unsigned = transaction.clone();
+    let mut hasher = SighashCache::new(&unsigned);
+    let hash_type = EcdsaSighashType::All;
+    for (ix, (key, public, address_type, _, output)) in spent.iter().enumerate() {
+        let input = &mut transaction.input[ix];
+        if *address_type == AccountAddressType::P2PKH {
+            let sighash = hasher.legacy_signature_hash(ix, &output.script_pubkey, hash_type.to_u32()).expect("input index is in range");
+            let signature = ecdsa::Signature { signature: context.sign(&sighash[..], key)?, sighash_type: hash_type };
+            input.script_sig = Builder::new().push_slice(signature.serialize()).push_key(public).into_script();
+        } else {
+            if *address_type == AccountAddressType::P2SHWPKH {
+                let redeem_script = ScriptBuf::new_p2wpkh(&public.wpubkey_hash().expect("compressed key"));
+                input.script_sig = Builder::new()
+                    .push_slice(PushBytesBuf::try_from(redeem_script.into_bytes()).expect("short script"))
+                    .into_script();
+            }
+            let script_code = ScriptBuf::new_p2pkh(&public.pubkey_hash());
+            let sighash = hasher.p2wsh_signature_hash(ix, &script_code, output.value, hash_type).expect("input index is in range");
+            let signature = ecdsa::Signature { signature: context.sign(&sighash[..], key)?, sighash_type: hash_type };
+            input.witness.push(signature.serialize());
+            input.witness.push(public.to_bytes());
+        }
+    }
+    Ok(transaction)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use bitcoin::Txid;
+    use bitcoin_hashes::Hash;
+
+    use account::{MasterAccount, MasterKeyEntropy, Unlocker};
+    use super::*;
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+
+    struct Source(HashMap<ScriptBuf, Vec<(OutPoint, TxOut)>>);
+
+    impl UtxoSource for Source {
+        fn unspent(&mut self, script_pubkey: &Script) -> Result<Vec<(OutPoint, TxOut)>, WalletError> {
+            Ok(self.0.get(script_pubkey).cloned().unwrap_or_default())
+        }
+    }
+
+    #[test]
+    fn test_sweep() {
+        let mut master = MasterAccount::new(MasterKeyEntropy::Low, Network::Bitcoin, PASSPHRASE, None).unwrap();
+        let mut unlocker = Unlocker::new(master.encrypted(), PASSPHRASE, None, Network::Bitcoin, None).unwrap();
+        master.new_account(&mut unlocker, AccountAddressType::P2WPKH, 0, 0, 10).unwrap();
+
+        let key = PrivateKey::from_slice(&[7u8; 32], Network::Bitcoin).unwrap();
+        let mut source = Source(HashMap::new());
+        let mut spent = HashMap::new();
+        for (vout, (_, script_pubkey)) in scripts(&key).into_iter().enumerate() {
+            let point = OutPoint { txid: Txid::all_zeros(), vout: vout as u32 };
+            let output = TxOut { value: Amount::from_sat(10000), script_pubkey: script_pubkey.clone() };
+            spent.insert(point, output.clone());
+            source.0.insert(script_pubkey, vec!((point, output)));
+        }
+
+        let fee_rate = FeeRate::from_sat_per_vb(2).unwrap();
+        let account = master.get_mut((0, 0)).unwrap();
+        let transaction = account.sweep_wif(&key.to_wif(), fee_rate, &mut source).unwrap();
+        assert_eq!(transaction.input.len(), 3);
+        assert_eq!(transaction.output[0].script_pubkey, account.get_key(0).unwrap().script_pubkey);
+        transaction.verify(|point| spent.get(point).cloned()).unwrap();
+        let fee = 30000 - transaction.output[0].value.to_sat();
+        assert!(fee >= coinselect::fee(fee_rate, transaction.weight().to_wu()));
+
+        assert!(account.sweep_wif(&PrivateKey::from_slice(&[8u8; 32], Network::Bitcoin).unwrap().to_wif(), fee_rate, &mut source).is_err());
+        assert!(account.sweep_wif(&PrivateKey::from_slice(&[7u8; 32], Network::Testnet).unwrap().to_wif(), fee_rate, &mut source).is_err());
+    }
+}
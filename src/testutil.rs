@@ -0,0 +1,303 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Test utilities
+//!
+//! Deterministic wallets, a chain in memory and funded coins to test integrations without network
+//!
+
+use std::collections::HashSet;
+
+use bitcoin::{Amount, Block, BlockHash, CompactTarget, Network, OutPoint, Script, ScriptBuf, Sequence, Transaction, TxIn, TxMerkleNode, TxOut, Txid, Witness};
+use bitcoin::absolute::LockTime;
+use bitcoin::bip158::{self, BlockFilter};
+use bitcoin::block::Header;
+use bitcoin::opcodes::OP_TRUE;
+use bitcoin::script::Builder;
+use bitcoin::transaction::Version;
+use bitcoin_hashes::Hash;
+
+use account::{AccountAddressType, MasterAccount, Unlocker, EXTERNAL};
+use broadcast::Broadcaster;
+use coins::Coins;
+use discovery::UsageSource;
+use error::WalletError;
+use filters::FilterSource;
+use sweep::UtxoSource;
+
+/// passphrase of test wallets
+pub const PASSPHRASE: &str = "correct horse battery staple";
+
+/// entropy of test wallets unless chosen
+pub const ENTROPY: [u8; 16] = [0x5a; 16];
+
+/// builder of a wallet with a fixed seed, by default P2WPKH receive and change keys of account 0 on regtest
+pub struct TestWallet {
+    entropy: Vec<u8>,
+    network: Network,
+    pd_passphrase: Option<String>,
+    accounts: Vec<(AccountAddressType, u32)>,
+    look_ahead: u32
+}
+
+impl TestWallet {
+    pub fn new() -> TestWallet {
+        TestWallet { entropy: ENTROPY.to_vec(), network: Network::Regtest, pd_passphrase: None, accounts: Vec::new(), look_ahead: 10 }
+    }
+
+    /// an other seed, 16 to 32 bytes
+    pub fn entropy(mut self, entropy: &[u8]) -> TestWallet {
+        self.entropy = entropy.to_vec();
+        self
+    }
+
+    pub fn network(mut self, network: Network) -> TestWallet {
+        self.network = network;
+        self
+    }
+
+    pub fn pd_passphrase(mut self, pd_passphrase: &str) -> TestWallet {
+        self.pd_passphrase = Some(pd_passphrase.to_string());
+        self
+    }
+
+    /// receive and change keys of an account instead of the default
+    pub fn account(mut self, address_type: AccountAddressType, account_number: u32) -> TestWallet {
+        self.accounts.push((address_type, account_number));
+        self
+    }
+
+    pub fn look_ahead(mut self, look_ahead: u32) -> TestWallet {
+        self.look_ahead = look_ahead;
+        self
+    }
+
+    /// the master born at time 0 and its unlocker
+    pub fn build(self) -> Result<(MasterAccount, Unlocker), WalletError> {
+        let pd_passphrase = self.pd_passphrase.as_deref();
+        let mut master = MasterAccount::from_entropy(&self.entropy, 0, self.network, PASSPHRASE, pd_passphrase)?;
+        let mut unlocker = Unlocker::new(master.encrypted(), PASSPHRASE, pd_passphrase, self.network, Some(master.master_public()))?;
+        let accounts = if self.accounts.is_empty() { vec!((AccountAddressType::P2WPKH, 0)) } else { self.accounts };
+        for (address_type, account_number) in accounts {
+            master.new_keychains(&mut unlocker, address_type, account_number, self.look_ahead)?;
+        }
+        Ok((master, unlocker))
+    }
+}
+
+impl Default for TestWallet {
+    fn default() -> TestWallet {
+        TestWallet::new()
+    }
+}
+
+/// blocks in memory, each mined on request with a coinbase of its height.
+/// Serves filters, blocks, unspent outputs and usage of scripts, broadcasts wait in the mempool for the next block.
+pub struct FakeChain {
+    blocks: Vec<Block>,
+    mempool: Vec<Transaction>
+}
+
+impl FakeChain {
+    /// a chain of a genesis block
+    pub fn new() -> FakeChain {
+        let mut chain = FakeChain { blocks: Vec::new(), mempool: Vec::new() };
+        chain.mine(Vec::new());
+        chain
+    }
+
+    /// height of the tip, the genesis block is at 0
+    pub fn height(&self) -> u32 {
+        self.blocks.len() as u32 - 1
+    }
+
+    pub fn tip(&self) -> BlockHash {
+        self.blocks.last().expect("genesis").block_hash()
+    }
+
+    pub fn blocks(&self) -> &[Block] {
+        &self.blocks
+    }
+
+    /// hashes of blocks from start_height, as `filters::scan` takes them
+    pub fn block_hashes(&self, start_height: u32) -> Vec<BlockHash> {
+        self.blocks.iter().skip(start_height as usize).map(|b| b.block_hash()).collect()
+    }
+
+    /// broadcast transactions not yet mined
+    pub fn mempool(&self) -> &[Transaction] {
+        &self.mempool
+    }
+
+    /// mine a block of the mempool and transactions, a coinbase to anyone first
+    pub fn mine(&mut self, transactions: Vec<Transaction>) -> &Block {
+        let coinbase = FakeChain::coinbase(self.blocks.len() as u32, Amount::from_int_btc(50));
+        self.mine_with(coinbase, transactions)
+    }
+
+    /// mine a block paying amounts to next receive keys of an account of the master.
+    /// Returns the funding transaction, process the block with `Coins::process` to own them.
+    pub fn fund(&mut self, master: &mut MasterAccount, account: u32, amounts: &[u64]) -> Result<Transaction, WalletError> {
+        let mut output = Vec::new();
+        for amount in amounts {
+            output.push(TxOut { value: Amount::from_sat(*amount), script_pubkey: master.next_key((account, EXTERNAL))?.0.script_pubkey });
+        }
+        let total = output.iter().map(|o| o.value).sum::<Amount>();
+        let coinbase = FakeChain::coinbase(self.blocks.len() as u32, total);
+        let funding = Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec!(TxIn { previous_output: OutPoint { txid: coinbase.compute_txid(), vout: 0 }, script_sig: ScriptBuf::new(), sequence: Sequence::MAX, witness: Witness::new() }),
+            output
+        };
+        self.mine_with(coinbase, vec!(funding.clone()));
+        Ok(funding)
+    }
+
+    /// an output of a transaction on the chain or in the mempool
+    pub fn output(&self, point: &OutPoint) -> Option<TxOut> {
+        self.transactions().find(|t| t.compute_txid() == point.txid).and_then(|t| t.output.get(point.vout as usize).cloned())
+    }
+
+    fn transactions(&self) -> impl Iterator<Item=&Transaction> {
+        self.blocks.iter().flat_map(|b| b.txdata.iter()).chain(self.mempool.iter())
+    }
+
+    fn coinbase(height: u32, value: Amount) -> Transaction {
+        Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec!(TxIn { previous_output: OutPoint::null(), script_sig: Builder::new().push_int(height as i64).push_opcode(OP_TRUE).into_script(),
+                               sequence: Sequence::MAX, witness: Witness::new() }),
+            output: vec!(TxOut { value, script_pubkey: Builder::new().push_opcode(OP_TRUE).into_script() })
+        }
+    }
+
+    fn mine_with(&mut self, coinbase: Transaction, transactions: Vec<Transaction>) -> &Block {
+        let mut txdata = vec!(coinbase);
+        txdata.append(&mut self.mempool);
+        txdata.extend(transactions);
+        let prev_blockhash = self.blocks.last().map(|b| b.block_hash()).unwrap_or_else(BlockHash::all_zeros);
+        let mut block = Block {
+            header: Header { version: bitcoin::block::Version::ONE, prev_blockhash, merkle_root: TxMerkleNode::all_zeros(),
+                             time: self.blocks.len() as u32, bits: CompactTarget::from_consensus(0x207fffff), nonce: 0 },
+            txdata
+        };
+        block.header.merkle_root = block.compute_merkle_root().expect("block has a coinbase");
+        self.blocks.push(block);
+        self.blocks.last().expect("just mined")
+    }
+}
+
+impl Default for FakeChain {
+    fn default() -> FakeChain {
+        FakeChain::new()
+    }
+}
+
+impl FilterSource for FakeChain {
+    fn get_filters(&mut self, start_height: u32, stop_hash: &BlockHash) -> Result<Vec<(BlockHash, BlockFilter)>, WalletError> {
+        let stop = self.blocks.iter().position(|b| b.block_hash() == *stop_hash).ok_or(WalletError::P2P("unknown stop hash".to_string()))?;
+        self.blocks.get(start_height as usize..=stop).unwrap_or_default().iter().map(|block| {
+            let filter = BlockFilter::new_script_filter(block, |point| self.output(point).map(|o| o.script_pubkey).ok_or(bip158::Error::UtxoMissing(*point)))?;
+            Ok((block.block_hash(), filter))
+        }).collect()
+    }
+
+    fn get_block(&mut self, block_hash: &BlockHash) -> Result<Block, WalletError> {
+        self.blocks.iter().find(|b| b.block_hash() == *block_hash).cloned().ok_or(WalletError::P2P("unknown block".to_string()))
+    }
+}
+
+impl UtxoSource for FakeChain {
+    fn unspent(&mut self, script_pubkey: &Script) -> Result<Vec<(OutPoint, TxOut)>, WalletError> {
+        let spent = self.transactions().flat_map(|t| t.input.iter().map(|i| i.previous_output)).collect::<HashSet<_>>();
+        Ok(self.transactions().flat_map(|t| {
+            let txid = t.compute_txid();
+            t.output.iter().enumerate().map(move |(vout, o)| (OutPoint { txid, vout: vout as u32 }, o.clone()))
+        }).filter(|(point, output)| output.script_pubkey == *script_pubkey && !spent.contains(point)).collect())
+    }
+}
+
+impl UsageSource for FakeChain {
+    fn used(&mut self, scripts: &[ScriptBuf]) -> Result<Vec<bool>, WalletError> {
+        let paid = self.transactions().flat_map(|t| t.output.iter().map(|o| o.script_pubkey.clone())).collect::<HashSet<_>>();
+        Ok(scripts.iter().map(|s| paid.contains(s)).collect())
+    }
+}
+
+impl Broadcaster for FakeChain {
+    /// accepts transactions spending known outputs into the mempool
+    fn broadcast(&mut self, transaction: &Transaction) -> Result<Txid, WalletError> {
+        if transaction.input.iter().any(|i| self.output(&i.previous_output).is_none()) {
+            return Err(WalletError::Broadcast("spends unknown outputs".to_string()));
+        }
+        self.mempool.push(transaction.clone());
+        Ok(transaction.compute_txid())
+    }
+}
+
+/// a test wallet owning coins of amounts on a fake chain
+pub fn funded(amounts: &[u64]) -> Result<(MasterAccount, Unlocker, Coins, FakeChain), WalletError> {
+    let (mut master, unlocker) = TestWallet::new().build()?;
+    let mut chain = FakeChain::new();
+    chain.fund(&mut master, 0, amounts)?;
+    let mut coins = Coins::new();
+    let block = chain.blocks().last().expect("funded").clone();
+    coins.process(&mut master, &block)?;
+    Ok((master, unlocker, coins, chain))
+}
+
+#[cfg(test)]
+mod test {
+    use builder::TxBuilder;
+    use filters;
+    use super::*;
+
+    #[test]
+    fn test_testutil() {
+        let (master, _) = TestWallet::new().build().unwrap();
+        let (same, _) = TestWallet::new().build().unwrap();
+        assert_eq!(master.master_public(), same.master_public());
+        assert_ne!(TestWallet::new().pd_passphrase("other").build().unwrap().0.fingerprint(), master.fingerprint());
+        let (taproot, _) = TestWallet::new().account(AccountAddressType::P2TR, 1).network(Network::Testnet).build().unwrap();
+        assert_eq!(taproot.accounts().len(), 2);
+
+        let (mut master, mut unlocker, coins, mut chain) = funded(&[100000, 50000]).unwrap();
+        assert_eq!(coins.owned().len(), 2);
+        assert_eq!(chain.height(), 1);
+        let receive = master.get((0, 0)).unwrap().get_key(0).unwrap().script_pubkey.clone();
+        assert_eq!(chain.unspent(&receive).unwrap().len(), 1);
+        assert_eq!(chain.used(&[receive.clone(), ScriptBuf::new()]).unwrap(), vec!(true, false));
+
+        // rescan from the filters of the chain
+        let (mut restored, _) = TestWallet::new().build().unwrap();
+        let mut rescanned = Coins::new();
+        let block_hashes = chain.block_hashes(0);
+        filters::scan(&mut chain, &mut restored, &mut rescanned, 0, &block_hashes).unwrap();
+        assert_eq!(rescanned.owned().len(), 2);
+
+        // spend and mine
+        let transaction = TxBuilder::new().add_recipient(ScriptBuf::new_op_return([0u8; 4]), 30000).fee_rate(2)
+            .finish_signed(&mut master, &coins, &mut unlocker).unwrap();
+        transaction.verify(|point| chain.output(point)).unwrap();
+        chain.broadcast(&transaction).unwrap();
+        assert_eq!(chain.mempool().len(), 1);
+        chain.mine(Vec::new());
+        assert!(chain.mempool().is_empty());
+        assert_eq!(chain.unspent(&receive).unwrap().len() + chain.unspent(&master.get((0, 0)).unwrap().get_key(1).unwrap().script_pubkey).unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,212 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Payment URI
+//!
+//! Generate and parse BIP21 bitcoin: URIs
+//!
+
+use std::fmt;
+
+use bitcoin::{Address, Amount, Denomination, Network};
+
+use address;
+use error::WalletError;
+
+/// scheme of BIP21 URIs
+pub const SCHEME: &str = "bitcoin";
+
+/// a payment request as encoded in a BIP21 URI
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PaymentRequest {
+    pub address: Address,
+    pub amount: Option<Amount>,
+    /// label of the address, e.g. name of the recipient
+    pub label: Option<String>,
+    /// message that describes the payment to the user
+    pub message: Option<String>,
+    /// other parameters in their order of appearance, req- parameters were understood by the creator
+    pub extras: Vec<(String, String)>
+}
+
+impl PaymentRequest {
+    /// a request to pay to an address of the wallet
+    pub fn new(address: Address) -> PaymentRequest {
+        PaymentRequest { address, amount: None, label: None, message: None, extras: Vec::new() }
+    }
+
+    pub fn with_amount(mut self, amount: Amount) -> PaymentRequest {
+        self.amount = Some(amount);
+        self
+    }
+
+    pub fn with_label(mut self, label: &str) -> PaymentRequest {
+        self.label = Some(label.to_string());
+        self
+    }
+
+    pub fn with_message(mut self, message: &str) -> PaymentRequest {
+        self.message = Some(message.to_string());
+        self
+    }
+
+    /// the bitcoin: URI of this request
+    pub fn to_uri(&self) -> String {
+        self.to_string()
+    }
+
+    /// parse a bitcoin: URI, fails for addresses of an other network
+    /// and for required (req-) parameters this library does not understand
+    pub fn parse(uri: &str, network: Network) -> Result<PaymentRequest, WalletError> {
+        let colon = uri.find(':').ok_or(WalletError::Uri("missing scheme"))?;
+        if !uri[..colon].eq_ignore_ascii_case(SCHEME) {
+            return Err(WalletError::Uri("not a bitcoin URI"));
+        }
+        let rest = &uri[colon + 1..];
+        let (address, query) = match rest.find('?') {
+            Some(q) => (&rest[..q], Some(&rest[q + 1..])),
+            None => (rest, None)
+        };
+        let address = address::validate(address, network)?.address;
+        let mut request = PaymentRequest::new(address);
+        let mut seen = Vec::new();
+        for parameter in query.into_iter().flat_map(|q| q.split('&')).filter(|p| !p.is_empty()) {
+            let (key, value) = match parameter.find('=') {
+                Some(e) => (&parameter[..e], &parameter[e + 1..]),
+                None => (parameter, "")
+            };
+            let key = percent_decode(key)?;
+            let value = percent_decode(value)?;
+            if seen.contains(&key) {
+                return Err(WalletError::Uri("duplicate parameter"));
+            }
+            seen.push(key.clone());
+            match key.as_str() {
+                "amount" => request.amount = Some(parse_amount(&value)?),
+                "label" => request.label = Some(value),
+                "message" => request.message = Some(value),
+                _ if key.starts_with("req-") => return Err(WalletError::Uri("required parameter not understood")),
+                _ => request.extras.push((key, value))
+            }
+        }
+        Ok(request)
+    }
+}
+
+impl fmt::Display for PaymentRequest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", SCHEME, self.address)?;
+        let mut parameters = Vec::new();
+        if let Some(amount) = self.amount {
+            parameters.push(("amount".to_string(), amount.to_string_in(Denomination::Bitcoin)));
+        }
+        if let Some(ref label) = self.label {
+            parameters.push(("label".to_string(), percent_encode(label)));
+        }
+        if let Some(ref message) = self.message {
+            parameters.push(("message".to_string(), percent_encode(message)));
+        }
+        for (key, value) in &self.extras {
+            parameters.push((percent_encode(key), percent_encode(value)));
+        }
+        for (i, (key, value)) in parameters.iter().enumerate() {
+            write!(f, "{}{}={}", if i == 0 { '?' } else { '&' }, key, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// decimal amount in BTC without grouping or exponent
+fn parse_amount(value: &str) -> Result<Amount, WalletError> {
+    if value.is_empty() || !value.chars().all(|c| c.is_ascii_digit() || c == '.') {
+        return Err(WalletError::Uri("invalid amount"));
+    }
+    Amount::from_str_in(value, Denomination::Bitcoin).map_err(|_| WalletError::Uri("invalid amount"))
+}
+
+/// percent encode all but unreserved characters of RFC 3986
+fn percent_encode(s: &str) -> String {
+    let mut encoded = String::new();
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+    encoded
+}
+
+fn percent_decode(s: &str) -> Result<String, WalletError> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = s.get(i + 1..i + 3).ok_or(WalletError::Uri("invalid percent encoding"))?;
+            decoded.push(u8::from_str_radix(hex, 16).map_err(|_| WalletError::Uri("invalid percent encoding"))?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).map_err(|_| WalletError::Uri("parameter is not UTF-8"))
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use super::*;
+
+    #[test]
+    fn test_uri() {
+        // examples of BIP21 with a valid address
+        let request = PaymentRequest::parse("bitcoin:1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2?amount=50&label=Luke-Jr&message=Donation%20for%20project%20xyz", Network::Bitcoin).unwrap();
+        assert_eq!(request.address.to_string(), "1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2");
+        assert_eq!(request.amount, Some(Amount::from_sat(5000000000)));
+        assert_eq!(request.label.as_ref().unwrap(), "Luke-Jr");
+        assert_eq!(request.message.as_ref().unwrap(), "Donation for project xyz");
+        assert_eq!(PaymentRequest::parse(&request.to_uri(), Network::Bitcoin).unwrap(), request);
+
+        assert!(PaymentRequest::parse("bitcoin:1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2?req-somethingyoudontunderstand=50&req-somethingelseyoudontget=999", Network::Bitcoin).is_err());
+        let request = PaymentRequest::parse("bitcoin:1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2?somethingyoudontunderstand=50&somethingelseyoudontget=999", Network::Bitcoin).unwrap();
+        assert_eq!(request.extras.len(), 2);
+
+        // wrong network
+        assert!(PaymentRequest::parse("bitcoin:1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2", Network::Testnet).is_err());
+        // upper case as in QR codes
+        let request = PaymentRequest::parse("BITCOIN:BC1QLZ2H9SCGALMQJ43D36F58DCXRRL7UDU999GCP2?amount=0.0001", Network::Bitcoin).unwrap();
+        assert_eq!(request.amount, Some(Amount::from_sat(10000)));
+        assert_eq!(request.to_uri(), "bitcoin:bc1qlz2h9scgalmqj43d36f58dcxrrl7udu999gcp2?amount=0.0001");
+
+        for invalid in &["bitcoin:1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2?amount=1,5", "bitcoin:1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2?amount=0.000000001",
+                         "bitcoin:1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2?label=a&label=b", "bitcoin:1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN3",
+                         "litecoin:1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2", "bitcoin:1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2?message=%E2%8"] {
+            assert!(PaymentRequest::parse(invalid, Network::Bitcoin).is_err());
+        }
+        match PaymentRequest::parse("bitcoin:1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN3", Network::Bitcoin) {
+            Err(WalletError::InvalidAddress(_)) => {},
+            _ => panic!("invalid address")
+        }
+
+        let address = Address::from_str("bc1qlz2h9scgalmqj43d36f58dcxrrl7udu999gcp2").unwrap().assume_checked();
+        let request = PaymentRequest::new(address).with_amount(Amount::from_sat(123456)).with_label("Café & Co").with_message("50% off");
+        assert_eq!(request.to_uri(), "bitcoin:bc1qlz2h9scgalmqj43d36f58dcxrrl7udu999gcp2?amount=0.00123456&label=Caf%C3%A9%20%26%20Co&message=50%25%20off");
+        assert_eq!(PaymentRequest::parse(&request.to_uri(), Network::Bitcoin).unwrap(), request);
+    }
+}
@@ -0,0 +1,418 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # UTXO set
+//!
+//! Outputs of the wallet through their unconfirmed, confirmed, spent and reorged states
+//!
+
+use std::collections::HashMap;
+
+use bitcoin::{Amount, Block, BlockHash, OutPoint, ScriptBuf, Transaction, TxOut, Txid};
+use bitcoin::bip32::DerivationPath;
+use bitcoin::block::Header;
+
+use account::{AccountAddressType, KeyDerivation, MasterAccount};
+use checkpoint::Checkpoints;
+use events::{EventBus, WalletEvent};
+
+/// where an output stands
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum UtxoState {
+    /// in the mempool, trusted if created by a transaction spending only own outputs
+    Unconfirmed { trusted: bool },
+    /// in a block of the trunk
+    Confirmed { height: u32, block_hash: BlockHash },
+    /// spent by a transaction, confirmed in a block or still in the mempool
+    Spent { txid: Txid, block_hash: Option<BlockHash> },
+    /// the block that confirmed it was unwound, it may confirm again or never
+    Reorged
+}
+
+/// an output of the wallet
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Utxo {
+    pub output: TxOut,
+    pub derivation: KeyDerivation,
+    /// the script type of the account
+    pub address_type: AccountAddressType,
+    /// m / purpose' / coin_type' / account' / sub / kix
+    pub path: DerivationPath,
+    /// created by a transaction spending only own outputs
+    pub trusted: bool,
+    /// height and hash of the block confirming it
+    pub confirmed: Option<(u32, BlockHash)>,
+    /// a block confirming it was unwound
+    pub reorged: bool,
+    /// the spending transaction and the block confirming that
+    pub spent_by: Option<(Txid, Option<BlockHash>)>
+}
+
+impl Utxo {
+    pub fn state(&self) -> UtxoState {
+        if let Some((txid, block_hash)) = self.spent_by {
+            UtxoState::Spent { txid, block_hash }
+        } else if let Some((height, block_hash)) = self.confirmed {
+            UtxoState::Confirmed { height, block_hash }
+        } else if self.reorged {
+            UtxoState::Reorged
+        } else {
+            UtxoState::Unconfirmed { trusted: self.trusted }
+        }
+    }
+}
+
+/// balance of unspent outputs
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Balance {
+    /// outputs confirmed in the trunk
+    pub confirmed: Amount,
+    /// unconfirmed outputs of own transactions, e.g. change
+    pub trusted_pending: Amount,
+    /// unconfirmed or reorged outputs paid by others
+    pub untrusted_pending: Amount
+}
+
+impl Balance {
+    /// confirmed and pending together
+    pub fn total(&self) -> Amount {
+        self.confirmed + self.trusted_pending + self.untrusted_pending
+    }
+
+    /// what coin selection should consider safe to spend
+    pub fn spendable(&self) -> Amount {
+        self.confirmed + self.trusted_pending
+    }
+}
+
+/// a transaction creating or spending outputs of the wallet
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct WalletTransaction {
+    pub transaction: Transaction,
+    /// height and hash of the block confirming it
+    pub confirmed: Option<(u32, BlockHash)>,
+    /// time of the block confirming it
+    pub timestamp: Option<u32>
+}
+
+/// outputs of a master account by their state
+#[derive(Default)]
+pub struct UtxoSet {
+    utxos: HashMap<OutPoint, Utxo>,
+    transactions: HashMap<Txid, WalletTransaction>,
+    /// recent blocks processed, to unwind those a reorg removed
+    checkpoints: Checkpoints,
+    events: Option<EventBus>
+}
+
+impl UtxoSet {
+    pub fn new() -> UtxoSet {
+        UtxoSet::default()
+    }
+
+    pub fn get(&self, point: &OutPoint) -> Option<&Utxo> {
+        self.utxos.get(point)
+    }
+
+    /// all tracked outputs, spent or not
+    pub fn iter(&self) -> impl Iterator<Item=(&OutPoint, &Utxo)> {
+        self.utxos.iter()
+    }
+
+    /// transactions creating or spending outputs of the wallet
+    pub fn transactions(&self) -> &HashMap<Txid, WalletTransaction> {
+        &self.transactions
+    }
+
+    /// outputs not spent by any known transaction
+    pub fn unspent(&self) -> impl Iterator<Item=(&OutPoint, &Utxo)> {
+        self.utxos.iter().filter(|(_, u)| u.spent_by.is_none())
+    }
+
+    /// balance of outputs not spent
+    pub fn balance(&self) -> Balance {
+        let mut balance = Balance::default();
+        for (_, utxo) in self.unspent() {
+            match utxo.state() {
+                UtxoState::Confirmed { .. } => balance.confirmed += utxo.output.value,
+                UtxoState::Unconfirmed { trusted: true } => balance.trusted_pending += utxo.output.value,
+                _ => balance.untrusted_pending += utxo.output.value
+            }
+        }
+        balance
+    }
+
+    /// process a transaction seen in the mempool
+    pub fn process_unconfirmed(&mut self, master: &mut MasterAccount, transaction: &Transaction) {
+        self.process_transaction(master, transaction, None, None);
+    }
+
+    /// process a transaction a chain source reports confirmed in the block at height,
+    /// without the block its position and time are not known
+    pub fn process_confirmed(&mut self, master: &mut MasterAccount, transaction: &Transaction, height: u32, block_hash: BlockHash) {
+        self.process_transaction(master, transaction, Some((height, block_hash)), None);
+    }
+
+    /// publish received and confirmed payments and reorgs to the bus
+    pub fn set_events(&mut self, events: EventBus) {
+        self.events = Some(events);
+    }
+
+    pub fn checkpoints(&self) -> &Checkpoints {
+        &self.checkpoints
+    }
+
+    /// a block without wallet transactions joined the trunk at height, e.g. one a filter did not match.
+    /// Blocks no longer in the trunk are unwound, their hashes are returned highest first.
+    pub fn connect_header(&mut self, height: u32, header: &Header) -> Vec<BlockHash> {
+        let disconnected = self.checkpoints.connect(height, header.block_hash(), header.prev_blockhash);
+        self.unwind(&disconnected);
+        disconnected
+    }
+
+    /// the chain backend reports a reorg, unwind blocks above the fork height.
+    /// Returns their hashes highest first.
+    pub fn reorg(&mut self, fork_height: u32) -> Vec<BlockHash> {
+        let disconnected = self.checkpoints.disconnect_above(fork_height);
+        self.unwind(&disconnected);
+        disconnected
+    }
+
+    /// process a block of the trunk at height, blocks should be processed in ascending height order.
+    /// A block replacing one processed before unwinds that first.
+    pub fn process_block(&mut self, master: &mut MasterAccount, block: &Block, height: u32) {
+        self.connect_header(height, &block.header);
+        let block_hash = block.block_hash();
+        for transaction in block.txdata.iter() {
+            self.process_transaction(master, transaction, Some((height, block_hash)), Some(block.header.time));
+        }
+    }
+
+    fn unwind(&mut self, disconnected: &[BlockHash]) {
+        for block_hash in disconnected.iter() {
+            self.unwind_tip(block_hash);
+        }
+        if let (false, Some(events)) = (disconnected.is_empty(), self.events.as_ref()) {
+            events.publish(WalletEvent::ReorgDetected { disconnected: disconnected.to_vec() });
+        }
+    }
+
+    fn process_transaction(&mut self, master: &mut MasterAccount, transaction: &Transaction, confirmed: Option<(u32, BlockHash)>, timestamp: Option<u32>) {
+        let txid = transaction.compute_txid();
+        let mut trusted = !transaction.is_coinbase();
+        let mut relevant = false;
+        let mut spends = false;
+        let mut received = Vec::new();
+        if !transaction.is_coinbase() {
+            for input in transaction.input.iter() {
+                match self.utxos.get_mut(&input.previous_output) {
+                    Some(utxo) => {
+                        utxo.spent_by = Some((txid, confirmed.map(|(_, block_hash)| block_hash)));
+                        relevant = true;
+                        spends = true;
+                    }
+                    None => trusted = false
+                }
+            }
+        }
+        let mut scripts: HashMap<ScriptBuf, KeyDerivation> = master.get_scripts().collect();
+        for (vout, output) in transaction.output.iter().enumerate() {
+            let derivation = match scripts.get(&output.script_pubkey) {
+                Some(derivation) => derivation.clone(),
+                None => continue
+            };
+            relevant = true;
+            if let Some(account) = master.get_mut((derivation.account, derivation.sub)) {
+                account.record_receipt(derivation.kix, txid);
+            }
+            let point = OutPoint { txid, vout: vout as u32 };
+            if let Some(utxo) = self.utxos.get_mut(&point) {
+                if confirmed.is_some() {
+                    utxo.confirmed = confirmed;
+                    utxo.reorged = false;
+                }
+                continue;
+            }
+            let account = match master.get_mut((derivation.account, derivation.sub)) {
+                Some(account) => account,
+                None => continue
+            };
+            let address_type = account.address_type();
+            let path = account.key_path(derivation.kix);
+            if let Ok(more) = account.do_look_ahead(derivation.kix) {
+                for (kix, script) in more {
+                    scripts.insert(script, KeyDerivation { kix, ..derivation.clone() });
+                }
+            }
+            received.push((vout as u32, output.clone(), derivation.clone()));
+            self.utxos.insert(point, Utxo { output: output.clone(), derivation, address_type, path, trusted, confirmed, reorged: false, spent_by: None });
+        }
+        if relevant {
+            let record = self.transactions.entry(txid).or_insert_with(|| WalletTransaction { transaction: transaction.clone(), confirmed: None, timestamp: None });
+            let newly_confirmed = record.confirmed.is_none() && confirmed.is_some();
+            if confirmed.is_some() {
+                record.confirmed = confirmed;
+                record.timestamp = timestamp;
+            }
+            if let Some(ref events) = self.events {
+                if !spends && !received.is_empty() {
+                    events.publish(WalletEvent::PaymentReceived { txid, outputs: received });
+                }
+                if let (true, Some((height, _))) = (newly_confirmed, confirmed) {
+                    events.publish(WalletEvent::PaymentConfirmed { txid, height });
+                }
+            }
+        }
+    }
+
+    /// unwind the tip of the trunk, outputs it confirmed are reorged, spends it confirmed return to the mempool
+    pub fn unwind_tip(&mut self, block_hash: &BlockHash) {
+        for record in self.transactions.values_mut() {
+            if record.confirmed.map(|(_, confirmed)| confirmed == *block_hash).unwrap_or(false) {
+                record.confirmed = None;
+                record.timestamp = None;
+            }
+        }
+        for utxo in self.utxos.values_mut() {
+            if utxo.confirmed.map(|(_, confirmed)| confirmed == *block_hash).unwrap_or(false) {
+                utxo.confirmed = None;
+                utxo.reorged = true;
+            }
+            if let Some((_, ref mut spent)) = utxo.spent_by {
+                if *spent == Some(*block_hash) {
+                    *spent = None;
+                }
+            }
+        }
+    }
+
+    /// forget an unconfirmed transaction dropped from the mempool, outputs it spent are unspent again
+    pub fn evict(&mut self, txid: &Txid) {
+        if self.transactions.get(txid).map(|record| record.confirmed.is_none()).unwrap_or(false) {
+            self.transactions.remove(txid);
+        }
+        self.utxos.retain(|point, utxo| point.txid != *txid || utxo.confirmed.is_some());
+        for utxo in self.utxos.values_mut() {
+            if utxo.spent_by == Some((*txid, None)) {
+                utxo.spent_by = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{Network, Sequence, TxIn, Witness, CompactTarget, TxMerkleNode};
+    use bitcoin::absolute::LockTime;
+    use bitcoin::block::Version as BlockVersion;
+    use bitcoin::transaction::Version;
+    use bitcoin_hashes::Hash;
+
+    use account::{MasterKeyEntropy, Unlocker};
+    use super::*;
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+
+    fn transaction(inputs: Vec<OutPoint>, outputs: Vec<TxOut>) -> Transaction {
+        Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: inputs.into_iter().map(|previous_output| TxIn { previous_output, script_sig: ScriptBuf::new(), sequence: Sequence::MAX, witness: Witness::new() }).collect(),
+            output: outputs
+        }
+    }
+
+    fn block(prev_blockhash: BlockHash, txdata: Vec<Transaction>) -> Block {
+        let mut block = Block {
+            header: Header { version: BlockVersion::ONE, prev_blockhash, merkle_root: TxMerkleNode::all_zeros(), time: 0, bits: CompactTarget::from_consensus(0), nonce: 0 },
+            txdata
+        };
+        block.header.merkle_root = block.compute_merkle_root().unwrap_or_else(TxMerkleNode::all_zeros);
+        block
+    }
+
+    #[test]
+    fn test_utxo_states() {
+        let mut master = MasterAccount::new(MasterKeyEntropy::Low, Network::Bitcoin, PASSPHRASE, None).unwrap();
+        let mut unlocker = Unlocker::new(master.encrypted(), PASSPHRASE, None, Network::Bitcoin, None).unwrap();
+        let receive = master.new_account(&mut unlocker, AccountAddressType::P2WPKH, 0, 0, 10).unwrap().next_key().unwrap().script_pubkey.clone();
+        let change = master.new_account(&mut unlocker, AccountAddressType::P2WPKH, 0, 1, 10).unwrap().next_key().unwrap().script_pubkey.clone();
+        let mut utxos = UtxoSet::new();
+
+        // paid by someone else
+        let foreign = OutPoint { txid: Txid::all_zeros(), vout: 0 };
+        let funding = transaction(vec!(foreign), vec!(TxOut { value: Amount::from_sat(50000), script_pubkey: receive }));
+        let funded = OutPoint { txid: funding.compute_txid(), vout: 0 };
+        utxos.process_unconfirmed(&mut master, &funding);
+        assert_eq!(utxos.get(&funded).unwrap().state(), UtxoState::Unconfirmed { trusted: false });
+        assert_eq!(utxos.get(&funded).unwrap().path.to_string(), "84'/0'/0'/0/0");
+        assert_eq!(utxos.balance().untrusted_pending, Amount::from_sat(50000));
+
+        let block = block(BlockHash::all_zeros(), vec!(funding));
+        utxos.process_block(&mut master, &block, 100);
+        assert_eq!(utxos.balance(), Balance { confirmed: Amount::from_sat(50000), ..Balance::default() });
+
+        // spend to self
+        let spend = transaction(vec!(funded), vec!(TxOut { value: Amount::from_sat(49000), script_pubkey: change }));
+        utxos.process_unconfirmed(&mut master, &spend);
+        assert_eq!(utxos.get(&funded).unwrap().state(), UtxoState::Spent { txid: spend.compute_txid(), block_hash: None });
+        assert_eq!(utxos.balance(), Balance { trusted_pending: Amount::from_sat(49000), ..Balance::default() });
+        assert_eq!(utxos.unspent().count(), 1);
+
+        utxos.unwind_tip(&block.block_hash());
+        let balance = utxos.balance();
+        assert_eq!(balance.spendable(), Amount::from_sat(49000));
+        assert_eq!(balance.total(), Amount::from_sat(49000));
+
+        // spend dropped from the mempool, the reorged output is back
+        utxos.evict(&spend.compute_txid());
+        assert_eq!(utxos.get(&funded).unwrap().state(), UtxoState::Reorged);
+        assert_eq!(utxos.balance(), Balance { untrusted_pending: Amount::from_sat(50000), ..Balance::default() });
+    }
+
+    #[test]
+    fn test_reorg() {
+        let mut master = MasterAccount::new(MasterKeyEntropy::Low, Network::Bitcoin, PASSPHRASE, None).unwrap();
+        let mut unlocker = Unlocker::new(master.encrypted(), PASSPHRASE, None, Network::Bitcoin, None).unwrap();
+        let receive = master.new_account(&mut unlocker, AccountAddressType::P2WPKH, 0, 0, 10).unwrap().next_key().unwrap().script_pubkey.clone();
+        let mut utxos = UtxoSet::new();
+
+        let funding = transaction(vec!(OutPoint { txid: Txid::all_zeros(), vout: 0 }), vec!(TxOut { value: Amount::from_sat(50000), script_pubkey: receive }));
+        let funded = OutPoint { txid: funding.compute_txid(), vout: 0 };
+        let spend = transaction(vec!(funded), vec!(TxOut { value: Amount::from_sat(49000), script_pubkey: ScriptBuf::new_op_return([0u8; 4]) }));
+        let genesis = BlockHash::all_zeros();
+        let first = block(genesis, vec!(funding.clone()));
+        let second = block(first.block_hash(), vec!(spend.clone()));
+        utxos.process_block(&mut master, &first, 1);
+        utxos.process_block(&mut master, &second, 2);
+        assert_eq!(utxos.balance(), Balance::default());
+
+        // a competing block without the spend, the spend is back to the mempool
+        let competing = block(first.block_hash(), Vec::new());
+        utxos.process_block(&mut master, &competing, 2);
+        assert_eq!(utxos.get(&funded).unwrap().state(), UtxoState::Spent { txid: spend.compute_txid(), block_hash: None });
+        utxos.evict(&spend.compute_txid());
+        assert_eq!(utxos.balance().confirmed, Amount::from_sat(50000));
+
+        // the backend reports a fork below the funding, it is not double counted when confirmed again
+        assert_eq!(utxos.reorg(0), vec!(competing.block_hash(), first.block_hash()));
+        assert_eq!(utxos.balance(), Balance { untrusted_pending: Amount::from_sat(50000), ..Balance::default() });
+        let replacement = block(genesis, vec!(transaction(Vec::new(), Vec::new()), funding));
+        utxos.process_block(&mut master, &replacement, 1);
+        assert_eq!(utxos.balance(), Balance { confirmed: Amount::from_sat(50000), ..Balance::default() });
+        assert_eq!(utxos.checkpoints().tip(), Some((1, replacement.block_hash())));
+    }
+}
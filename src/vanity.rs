@@ -0,0 +1,117 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Vanity addresses
+//!
+//! Search the keys of an account for an address with a chosen prefix
+//!
+
+use std::ops::Range;
+
+use bitcoin::Address;
+use bitcoin::bip32::DerivationPath;
+
+use account::{Account, AccountAddressType};
+use error::WalletError;
+
+/// keys derived at once by a thread
+const BATCH: u32 = 256;
+
+const BECH32_CHARSET: &str = "qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+/// an address of an account with a vanity prefix, the seed recovers it with the key index
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct VanityAddress {
+    pub index: u32,
+    pub path: DerivationPath,
+    pub address: Address
+}
+
+/// the first key in range of a segwit or taproot account whose address starts with prefix, e.g. bc1qcafe.
+/// Search is spread across threads with the parallel feature, each expected character multiplies the work by 32.
+/// Keys beyond the gap limit are only found by a wallet looking that far ahead.
+pub fn search(account: &Account, prefix: &str, range: Range<u32>) -> Result<Option<VanityAddress>, WalletError> {
+    match account.address_type() {
+        AccountAddressType::P2WPKH | AccountAddressType::P2TR => {},
+        _ => return Err(WalletError::Unsupported("vanity addresses are bech32 addresses of segwit or taproot accounts"))
+    }
+    let prefix = prefix.to_lowercase();
+    let sample = address(account, range.start)?.to_string();
+    // human readable part, separator and witness version
+    let fixed = sample.find('1').expect("bech32 address has a separator") + 2;
+    if !prefix.starts_with(&sample[..fixed]) || !prefix[fixed..].chars().all(|c| BECH32_CHARSET.contains(c)) {
+        return Err(WalletError::Unsupported("prefix is not a bech32 address prefix of the account"));
+    }
+    if prefix.len() > sample.len() {
+        return Err(WalletError::Unsupported("prefix is longer than an address"));
+    }
+
+    let batches = (range.start..range.end).step_by(BATCH as usize).map(|start| start..range.end.min(start.saturating_add(BATCH)));
+    let matching = |batch: Range<u32>| -> Option<Result<u32, WalletError>> {
+        match account.derive_scripts(batch) {
+            Ok(scripts) => scripts.into_iter().find(|(_, script)|
+                Address::from_script(script, account.network()).is_ok_and(|a| a.to_string().starts_with(&prefix))).map(|(index, _)| Ok(index)),
+            Err(e) => Some(Err(e))
+        }
+    };
+    #[cfg(feature = "parallel")]
+    let found = {
+        use rayon::prelude::*;
+        batches.collect::<Vec<_>>().into_par_iter().find_map_first(matching)
+    };
+    #[cfg(not(feature = "parallel"))]
+    let found = batches.into_iter().find_map(matching);
+    match found {
+        Some(index) => {
+            let index = index?;
+            Ok(Some(VanityAddress { index, path: account.key_path(index), address: address(account, index)? }))
+        },
+        None => Ok(None)
+    }
+}
+
+fn address(account: &Account, index: u32) -> Result<Address, WalletError> {
+    let (_, script) = account.derive_scripts(index..index + 1)?.pop().ok_or(WalletError::Unsupported("no key at index"))?;
+    Address::from_script(&script, account.network()).map_err(|_| WalletError::Unsupported("script has no address"))
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::Network;
+
+    use account::{MasterAccount, MasterKeyEntropy, Unlocker};
+    use super::*;
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+
+    #[test]
+    fn test_vanity() {
+        let mut master = MasterAccount::new(MasterKeyEntropy::Low, Network::Bitcoin, PASSPHRASE, None).unwrap();
+        let mut unlocker = Unlocker::new(master.encrypted(), PASSPHRASE, None, Network::Bitcoin, None).unwrap();
+        let account = master.new_account(&mut unlocker, AccountAddressType::P2WPKH, 0, 0, 10).unwrap();
+        let found = search(account, "bc1qq", 0..2000).unwrap().unwrap();
+        assert!(found.address.to_string().starts_with("bc1qq"));
+        assert_eq!(found.path, account.key_path(found.index));
+        assert_eq!(account.derive_scripts(found.index..found.index + 1).unwrap()[0].1, found.address.script_pubkey());
+        // the first match
+        assert!(search(account, "bc1qq", 0..found.index).unwrap().is_none());
+        assert_eq!(search(account, "BC1QQ", found.index..found.index + 1).unwrap().unwrap().index, found.index);
+
+        assert!(search(account, "bc1qb", 0..10).is_err());
+        assert!(search(account, "tb1qq", 0..10).is_err());
+        assert!(search(account, "bc1p", 0..10).is_err());
+    }
+}
@@ -0,0 +1,147 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Vaults
+//!
+//! Inheritance and recovery templates of spending policies with a timeout
+//!
+
+use bitcoin::{Address, Amount, FeeRate, Network, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Witness};
+use bitcoin::absolute::LockTime;
+use bitcoin::bip32::Xpriv;
+use bitcoin::transaction::Version;
+
+use coinselect;
+use error::WalletError;
+use policy::CompiledPolicy;
+
+/// coins of a vault are spent by its owners any time, or by the heir or recovery key once they are timeout blocks old.
+/// Keys are extended public keys ending in a wildcard, e.g. [d34db33f/48'/0'/0'/2']xpub.../0/*
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Vault {
+    policy: CompiledPolicy,
+    timeout: u16
+}
+
+impl Vault {
+    /// the owner key now, the heir key after timeout blocks, 144 a day up to 65535
+    pub fn inheritance(owner: &str, heir: &str, timeout: u16) -> Result<Vault, WalletError> {
+        Vault::compile(&format!("or(99@pk({}),1@and(pk({}),older({})))", owner, heir, timeout), timeout)
+    }
+
+    /// threshold of the owner keys now, the recovery key after timeout blocks
+    pub fn recovery(owners: &[&str], threshold: usize, recovery: &str, timeout: u16) -> Result<Vault, WalletError> {
+        if threshold == 0 || threshold > owners.len() {
+            return Err(WalletError::Policy("threshold must be 1 to the number of owner keys".to_string()));
+        }
+        let owners = owners.iter().map(|k| format!("pk({})", k)).collect::<Vec<_>>().join(",");
+        Vault::compile(&format!("or(99@thresh({},{}),1@and(pk({}),older({})))", threshold, owners, recovery, timeout), timeout)
+    }
+
+    fn compile(policy: &str, timeout: u16) -> Result<Vault, WalletError> {
+        if timeout == 0 {
+            return Err(WalletError::Policy("timeout must be at least a block".to_string()));
+        }
+        Ok(Vault { policy: CompiledPolicy::compile(policy)?, timeout })
+    }
+
+    pub fn policy(&self) -> &CompiledPolicy {
+        &self.policy
+    }
+
+    /// the descriptor with checksum, to watch the vault with other wallets
+    pub fn descriptor(&self) -> String {
+        self.policy.to_string()
+    }
+
+    /// blocks a coin must be old before the heir or recovery key spends it
+    pub fn timeout(&self) -> u16 {
+        self.timeout
+    }
+
+    /// address at derivation index
+    pub fn address(&self, index: u32, network: Network) -> Result<Address, WalletError> {
+        self.policy.address(index, network)
+    }
+
+    /// spend vault coins, each with its output and derivation index, to destination with the heir or recovery key.
+    /// The transaction is valid once all coins are timeout blocks old.
+    pub fn claim_after_timeout(&self, coins: &[(OutPoint, TxOut, u32)], destination: ScriptBuf, fee_rate: FeeRate, key: &Xpriv) -> Result<Transaction, WalletError> {
+        if coins.is_empty() {
+            return Err(WalletError::Unsupported("no coins to claim"));
+        }
+        let sequence = Sequence::from_height(self.timeout);
+        // outpoint, sequence, empty script and the number of witness elements
+        let input_weight = 4 * (32 + 4 + 4 + 1) + 1 + self.policy.max_satisfaction_weight()?;
+        let weight = coinselect::TRANSACTION_OVERHEAD_WEIGHT + coinselect::output_weight(&destination) + input_weight * coins.len() as u64;
+        let value = coins.iter().map(|(_, output, _)| output.value).sum::<Amount>();
+        let remaining = value.checked_sub(Amount::from_sat(coinselect::fee(fee_rate, weight))).ok_or(WalletError::InsufficientFunds)?;
+        if remaining < destination.minimal_non_dust() {
+            return Err(WalletError::InsufficientFunds);
+        }
+        let mut transaction = Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: coins.iter().map(|(point, _, _)| TxIn { previous_output: *point, script_sig: ScriptBuf::new(), sequence, witness: Witness::new() }).collect(),
+            output: vec!(TxOut { value: remaining, script_pubkey: destination })
+        };
+        for (ix, (_, spent, index)) in coins.iter().enumerate() {
+            self.policy.sign(&mut transaction, ix, spent, *index, &[*key])?;
+        }
+        Ok(transaction)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use bitcoin::Txid;
+    use bitcoin::bip32::Xpub;
+    use bitcoin_hashes::Hash;
+    use secp256k1::Secp256k1;
+
+    use super::*;
+
+    #[test]
+    fn test_vault() {
+        let secp = Secp256k1::new();
+        let owner = Xpriv::new_master(Network::Bitcoin, &[1u8; 32]).unwrap();
+        let heir = Xpriv::new_master(Network::Bitcoin, &[2u8; 32]).unwrap();
+        let key = |k: &Xpriv| format!("{}/0/*", Xpub::from_priv(&secp, k));
+        let vault = Vault::inheritance(&key(&owner), &key(&heir), 52560).unwrap();
+        assert!(vault.descriptor().starts_with("wsh("));
+        assert!(Vault::inheritance(&key(&owner), &key(&heir), 0).is_err());
+        let cosigner = key(&Xpriv::new_master(Network::Bitcoin, &[3u8; 32]).unwrap());
+        assert!(Vault::recovery(&[&key(&owner), &cosigner], 3, &key(&heir), 100).is_err());
+        assert!(Vault::recovery(&[&key(&owner), &cosigner], 2, &key(&heir), 100).is_ok());
+
+        let coins = (0..2u32).map(|i| (OutPoint { txid: Txid::from_byte_array([i as u8; 32]), vout: i },
+                                          TxOut { value: Amount::from_sat(50000), script_pubkey: vault.address(i, Network::Bitcoin).unwrap().script_pubkey() }, i))
+            .collect::<Vec<_>>();
+        let destination = ScriptBuf::new_op_return([0u8; 4]);
+        let claim = vault.claim_after_timeout(&coins, destination.clone(), FeeRate::from_sat_per_vb(2).unwrap(), &heir).unwrap();
+        assert!(claim.input.iter().all(|i| i.sequence == Sequence::from_height(52560)));
+        let spent = coins.iter().map(|(point, output, _)| (*point, output.clone())).collect::<HashMap<_, _>>();
+        claim.verify(|point| spent.get(point).cloned()).unwrap();
+        assert!(claim.output[0].value.to_sat() + coinselect::fee(FeeRate::from_sat_per_vb(2).unwrap(), claim.weight().to_wu()) <= 100000);
+        // the heir waits, the owner does not
+        let mut early = claim.clone();
+        early.input[0].sequence = Sequence::ENABLE_RBF_NO_LOCKTIME;
+        assert!(vault.policy().sign(&mut early, 0, &coins[0].1, 0, &[heir]).is_err());
+        assert_eq!(vault.policy().sign(&mut early, 0, &coins[0].1, 0, &[owner]).unwrap(), 1);
+    }
+}
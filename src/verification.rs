@@ -0,0 +1,153 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Backup verification
+//!
+//! Challenges that check a written down mnemonic against the encrypted seed of a master
+//!
+
+use std::str::FromStr;
+
+use bitcoin::{Address, ScriptBuf};
+use secp256k1::rand::thread_rng;
+use secp256k1::rand::seq::{index, SliceRandom};
+
+use account::{AccountAddressType, MasterAccount, EXTERNAL};
+use error::WalletError;
+use secret::SecretBytes;
+
+/// receive addresses of challenges are among the first of an account, those other wallets show after restore
+pub const ADDRESS_INDEXES: u32 = 20;
+
+/// a question about a mnemonic backup
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Challenge {
+    /// the word at a position, counting from 1
+    Word(usize),
+    /// the receive address at an index of an account, as another wallet restored from the mnemonic derives it
+    Address { account: u32, address_type: AccountAddressType, index: u32 }
+}
+
+/// challenges of a backup verification ceremony, expected answers are zeroized on drop
+pub struct Verification {
+    challenges: Vec<(Challenge, SecretBytes)>,
+    passed: Vec<bool>
+}
+
+impl Verification {
+    /// challenges of words at distinct random positions of the mnemonic the encrypted seed decrypts to,
+    /// and of random receive addresses of random accounts
+    pub fn new(master: &MasterAccount, passphrase: &str, words: usize, addresses: usize) -> Result<Verification, WalletError> {
+        let mnemonic = master.mnemonic(passphrase)?;
+        let mnemonic = mnemonic.iter().collect::<Vec<_>>();
+        if words > mnemonic.len() {
+            return Err(WalletError::Unsupported("more word challenges than words"));
+        }
+        let mut rng = thread_rng();
+        let mut challenges = index::sample(&mut rng, mnemonic.len(), words).into_iter()
+            .map(|i| (Challenge::Word(i + 1), SecretBytes::from_slice(mnemonic[i].as_bytes())))
+            .collect::<Vec<_>>();
+
+        let accounts = master.accounts().values()
+            .filter(|a| a.sub_account_number() == EXTERNAL && !matches!(a.address_type(), AccountAddressType::P2WSH(_)))
+            .collect::<Vec<_>>();
+        if addresses > 0 && accounts.is_empty() {
+            return Err(WalletError::Unsupported("no receive account to derive addresses of"));
+        }
+        for _ in 0..addresses {
+            let account = accounts.choose(&mut rng).expect("some account");
+            let index = secp256k1::rand::Rng::gen_range(&mut rng, 0..ADDRESS_INDEXES);
+            let (_, script) = account.derive_scripts(index..index + 1)?.pop().expect("one script");
+            challenges.push((Challenge::Address { account: account.account_number(), address_type: account.address_type(), index },
+                             SecretBytes::new(script.into_bytes())));
+        }
+        let passed = vec!(false; challenges.len());
+        Ok(Verification { challenges, passed })
+    }
+
+    /// the challenges to ask in order
+    pub fn challenges(&self) -> impl Iterator<Item=&Challenge> {
+        self.challenges.iter().map(|(c, _)| c)
+    }
+
+    /// answer a challenge, a word or its four letter prefix, or an address. Returns if it is right.
+    pub fn answer(&mut self, challenge: usize, answer: &str) -> Result<bool, WalletError> {
+        let (kind, expected) = self.challenges.get(challenge).ok_or(WalletError::Unsupported("no such challenge"))?;
+        let answer = answer.trim();
+        let right = match kind {
+            Challenge::Word(_) => {
+                let answer = answer.to_lowercase();
+                let expected = std::str::from_utf8(expected.as_slice()).expect("words are ascii");
+                answer == expected || (answer.len() >= 4 && expected.starts_with(&answer))
+            },
+            Challenge::Address { .. } => Address::from_str(answer).ok()
+                .map(|a| a.assume_checked().script_pubkey()) == Some(ScriptBuf::from_bytes(expected.as_slice().to_vec()))
+        };
+        self.passed[challenge] = right;
+        Ok(right)
+    }
+
+    /// all challenges were answered right
+    pub fn passed(&self) -> bool {
+        self.passed.iter().all(|p| *p)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::Network;
+
+    use account::{MasterKeyEntropy, Unlocker};
+    use super::*;
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+
+    #[test]
+    fn test_verification() {
+        let mut master = MasterAccount::new(MasterKeyEntropy::Low, Network::Bitcoin, PASSPHRASE, None).unwrap();
+        assert!(Verification::new(&master, PASSPHRASE, 3, 1).is_err());
+        let mut unlocker = Unlocker::new(master.encrypted(), PASSPHRASE, None, Network::Bitcoin, None).unwrap();
+        master.new_keychains(&mut unlocker, AccountAddressType::P2WPKH, 0, 10).unwrap();
+        assert!(Verification::new(&master, "wrong", 3, 1).is_err());
+        assert!(Verification::new(&master, PASSPHRASE, 25, 0).is_err());
+
+        let mnemonic = master.mnemonic(PASSPHRASE).unwrap();
+        let words = mnemonic.iter().collect::<Vec<_>>();
+        let mut verification = Verification::new(&master, PASSPHRASE, 3, 2).unwrap();
+        let challenges = verification.challenges().cloned().collect::<Vec<_>>();
+        assert_eq!(challenges.len(), 5);
+        for (i, challenge) in challenges.iter().enumerate() {
+            match *challenge {
+                Challenge::Word(position) => {
+                    let word = words[position - 1];
+                    let other = words.iter().find(|w| **w != word).unwrap();
+                    assert!(!verification.answer(i, other).unwrap());
+                    assert!(verification.answer(i, &word[..4.min(word.len())].to_uppercase()).unwrap());
+                },
+                Challenge::Address { account, address_type, index } => {
+                    assert_eq!((account, address_type), (0, AccountAddressType::P2WPKH));
+                    assert!(index < ADDRESS_INDEXES);
+                    let script = master.get((account, EXTERNAL)).unwrap().derive_scripts(index..index + 1).unwrap()[0].1.clone();
+                    assert!(!verification.answer(i, "bc1qnotanaddress").unwrap());
+                    assert!(!verification.passed());
+                    assert!(verification.answer(i, &Address::from_script(&script, Network::Bitcoin).unwrap().to_string()).unwrap());
+                }
+            }
+        }
+        assert!(verification.passed());
+        assert!(verification.answer(5, "abandon").is_err());
+    }
+}
@@ -0,0 +1,188 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Mempool watcher
+//!
+//! Events for unconfirmed transactions paying to the wallet
+//!
+
+use std::collections::{HashMap, HashSet};
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+use bitcoin::{Amount, Script, ScriptBuf, Transaction, TxOut, Txid};
+
+use account::{KeyDerivation, MasterAccount};
+use events::{EventBus, WalletEvent};
+
+/// an unconfirmed transaction paying to scripts of the wallet
+#[derive(Clone, Debug, PartialEq)]
+pub struct IncomingPayment {
+    /// the transaction as seen in the mempool
+    pub transaction: Transaction,
+    /// vout, output and key of outputs paying to the wallet
+    pub outputs: Vec<(u32, TxOut, KeyDerivation)>
+}
+
+impl IncomingPayment {
+    /// id of the transaction
+    pub fn txid(&self) -> Txid {
+        self.transaction.compute_txid()
+    }
+
+    /// sum of the outputs paying to the wallet
+    pub fn amount(&self) -> Amount {
+        self.outputs.iter().map(|(_, output, _)| output.value).sum()
+    }
+}
+
+/// an event of the mempool watcher
+#[derive(Clone, Debug, PartialEq)]
+pub enum MempoolEvent {
+    /// a payment entered the mempool
+    Incoming(IncomingPayment),
+    /// a payment reported earlier was confirmed at height
+    Confirmed { txid: Txid, height: u32 }
+}
+
+/// turns unconfirmed transactions into events for its subscribers
+/// transactions come from `electrum::Client::wait_for_events` or `p2p::Peer::wait_for_events`
+pub struct MempoolWatcher {
+    scripts: HashMap<ScriptBuf, KeyDerivation>,
+    pending: HashSet<Txid>,
+    subscribers: Vec<Sender<MempoolEvent>>,
+    events: Option<EventBus>
+}
+
+impl MempoolWatcher {
+    /// watch all scripts of the master account
+    pub fn new(master: &MasterAccount) -> MempoolWatcher {
+        let mut watcher = MempoolWatcher { scripts: HashMap::new(), pending: HashSet::new(), subscribers: Vec::new(), events: None };
+        watcher.update(master);
+        watcher
+    }
+
+    /// also watch scripts added to the master since, e.g. by look ahead
+    pub fn update(&mut self, master: &MasterAccount) {
+        self.scripts.extend(master.get_scripts());
+    }
+
+    /// scripts watched
+    pub fn scripts(&self) -> impl Iterator<Item=&Script> {
+        self.scripts.keys().map(|s| s.as_script())
+    }
+
+    /// a receiver of all future events, it stops receiving once dropped
+    pub fn subscribe(&mut self) -> Receiver<MempoolEvent> {
+        let (sender, receiver) = channel();
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    /// also publish events to the wallet event bus
+    pub fn set_events(&mut self, events: EventBus) {
+        self.events = Some(events);
+    }
+
+    /// true if the transaction was reported and is not yet confirmed
+    pub fn is_pending(&self, txid: &Txid) -> bool {
+        self.pending.contains(txid)
+    }
+
+    /// check an unconfirmed transaction, a payment to the wallet is reported once
+    pub fn process(&mut self, transaction: &Transaction) -> Option<MempoolEvent> {
+        let txid = transaction.compute_txid();
+        if self.pending.contains(&txid) {
+            return None;
+        }
+        let outputs = transaction.output.iter().enumerate()
+            .filter_map(|(vout, output)| self.scripts.get(&output.script_pubkey).map(|d| (vout as u32, output.clone(), d.clone())))
+            .collect::<Vec<_>>();
+        if outputs.is_empty() {
+            return None;
+        }
+        self.pending.insert(txid);
+        Some(self.emit(MempoolEvent::Incoming(IncomingPayment { transaction: transaction.clone(), outputs })))
+    }
+
+    /// a transaction confirmed, reported if it was pending
+    pub fn confirmed(&mut self, txid: &Txid, height: u32) -> Option<MempoolEvent> {
+        if !self.pending.remove(txid) {
+            return None;
+        }
+        Some(self.emit(MempoolEvent::Confirmed { txid: *txid, height }))
+    }
+
+    fn emit(&mut self, event: MempoolEvent) -> MempoolEvent {
+        self.subscribers.retain(|s| s.send(event.clone()).is_ok());
+        if let Some(ref events) = self.events {
+            events.publish(WalletEvent::from(event.clone()));
+        }
+        event
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{Network, OutPoint, Sequence, TxIn, Witness};
+    use bitcoin::absolute::LockTime;
+    use bitcoin::transaction::Version;
+    use bitcoin_hashes::Hash;
+
+    use account::{AccountAddressType, MasterKeyEntropy, Unlocker};
+    use super::*;
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+
+    #[test]
+    fn test_watcher() {
+        let mut master = MasterAccount::new(MasterKeyEntropy::Low, Network::Bitcoin, PASSPHRASE, None).unwrap();
+        let mut unlocker = Unlocker::new(master.encrypted(), PASSPHRASE, None, Network::Bitcoin, None).unwrap();
+        let script = master.new_account(&mut unlocker, AccountAddressType::P2WPKH, 0, 0, 10).unwrap()
+            .next_key().unwrap().script_pubkey.clone();
+        let mut watcher = MempoolWatcher::new(&master);
+        assert_eq!(watcher.scripts().count(), master.get_scripts().count());
+        let events = watcher.subscribe();
+
+        let mut transaction = Transaction {
+            version: Version::TWO, lock_time: LockTime::ZERO,
+            input: vec!(TxIn { previous_output: OutPoint { txid: Txid::all_zeros(), vout: 0 }, script_sig: ScriptBuf::new(), sequence: Sequence::MAX, witness: Witness::new() }),
+            output: vec!(TxOut { value: Amount::from_sat(1000), script_pubkey: ScriptBuf::new_op_return([0u8; 4]) })
+        };
+        assert!(watcher.process(&transaction).is_none());
+        transaction.output.push(TxOut { value: Amount::from_sat(5000), script_pubkey: script });
+        let txid = transaction.compute_txid();
+        match watcher.process(&transaction) {
+            Some(MempoolEvent::Incoming(payment)) => {
+                assert_eq!(payment.txid(), txid);
+                assert_eq!(payment.amount(), Amount::from_sat(5000));
+                assert_eq!(payment.outputs[0].0, 1);
+                assert_eq!(payment.outputs[0].2.kix, 0);
+            }
+            _ => panic!("payment to the wallet")
+        }
+        assert!(watcher.process(&transaction).is_none());
+        assert!(watcher.is_pending(&txid));
+        assert!(watcher.confirmed(&txid, 100).is_some());
+        assert!(watcher.confirmed(&txid, 100).is_none());
+
+        let received = events.try_iter().collect::<Vec<_>>();
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[1], MempoolEvent::Confirmed { txid, height: 100 });
+        drop(events);
+        transaction.lock_time = LockTime::from_height(1).unwrap();
+        assert!(watcher.process(&transaction).is_some());
+    }
+}